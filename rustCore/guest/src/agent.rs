@@ -0,0 +1,302 @@
+// A guest's connection to the hypervisor over its agent channel: the HELLO
+// handshake, request/reply matching, and events that arrive in between.
+
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::protocol::{
+    decode_files, decode_formats, ClipboardFormat, DecodeError, ErrorCode, FileInfo, Frame, FrameDecoder, Hello,
+    Reader, MAX_PAYLOAD, MSG_CLIPBOARD_CHANGED, MSG_DND_CANCEL, MSG_DND_DROP, MSG_DND_PULL, MSG_DND_READY, MSG_HELLO,
+    MSG_WELCOME, PROTOCOL_VERSION,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelError {
+    /// The hypervisor end went away.
+    Closed,
+    Io,
+}
+
+/// The guest end of the agent channel, such as the virtio console port
+/// opened in raw mode.
+pub trait Channel {
+    fn send(&mut self, data: &[u8]) -> Result<(), ChannelError>;
+
+    /// Wait for bytes from the hypervisor, returning how many were read.
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgentError {
+    Channel(ChannelError),
+    Decode(DecodeError),
+    /// The hypervisor answered with ERROR.
+    Refused(ErrorCode),
+    /// A reply of the wrong kind.
+    Unexpected(u16),
+    /// The hypervisor did not grant the service.
+    NotGranted(u32),
+    /// The request is over the hypervisor's payload limit.
+    TooLarge,
+}
+
+impl From<ChannelError> for AgentError {
+    fn from(err: ChannelError) -> Self {
+        AgentError::Channel(err)
+    }
+}
+
+impl From<DecodeError> for AgentError {
+    fn from(err: DecodeError) -> Self {
+        AgentError::Decode(err)
+    }
+}
+
+/// Something the hypervisor told the guest without being asked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Another VM copied; its clipboard is available in `formats`.
+    ClipboardChanged { serial: u64, formats: Vec<ClipboardFormat> },
+    /// Files dragged from another VM were dropped on this one's window;
+    /// accept some or none of them.
+    DndDrop { transfer: u64, files: Vec<FileInfo> },
+    /// Send `len` bytes of an offered file, starting at `offset`.
+    DndPull { transfer: u64, file: u16, offset: u64, len: u32 },
+    /// An accepted file is staged and may be read.
+    DndReady { transfer: u64, file: u16 },
+    DndCancelled { transfer: u64, code: ErrorCode },
+    /// An event this library does not know.
+    Other(Frame),
+}
+
+impl Event {
+    fn decode(frame: Frame) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(&frame.payload);
+        let event = match frame.kind {
+            MSG_CLIPBOARD_CHANGED => {
+                let serial = reader.u64()?;
+                Event::ClipboardChanged { serial, formats: decode_formats(&mut reader)? }
+            }
+            MSG_DND_DROP => Event::DndDrop { transfer: reader.u64()?, files: decode_files(&mut reader)? },
+            MSG_DND_PULL => {
+                let (transfer, file) = (reader.u64()?, reader.u16()?);
+                Event::DndPull { transfer, file, offset: reader.u64()?, len: reader.u32()? }
+            }
+            MSG_DND_READY => Event::DndReady { transfer: reader.u64()?, file: reader.u16()? },
+            MSG_DND_CANCEL => {
+                Event::DndCancelled { transfer: reader.u64()?, code: ErrorCode::from_code(reader.u16()?) }
+            }
+            _ => return Ok(Event::Other(frame)),
+        };
+        reader.finish()?;
+        Ok(event)
+    }
+}
+
+pub struct Agent<C: Channel> {
+    channel: C,
+    decoder: FrameDecoder,
+    last_id: u32,
+    /// Services the hypervisor granted.
+    services: u32,
+    max_payload: u32,
+    /// Events that arrived while waiting for a reply.
+    events: VecDeque<Event>,
+}
+
+impl<C: Channel> Agent<C> {
+    /// Say HELLO, asking for `services`, and wait for the WELCOME.
+    pub fn connect(channel: C, services: u32) -> Result<Self, AgentError> {
+        let mut agent = Agent {
+            channel,
+            decoder: FrameDecoder::new(MAX_PAYLOAD),
+            last_id: 0,
+            services: 0,
+            max_payload: MAX_PAYLOAD,
+            events: VecDeque::new(),
+        };
+        let hello = Hello { version: PROTOCOL_VERSION, services, max_payload: MAX_PAYLOAD };
+        let welcome = agent.request(MSG_HELLO, hello.encode())?;
+        if welcome.kind != MSG_WELCOME {
+            return Err(AgentError::Unexpected(welcome.kind));
+        }
+        let welcome = Hello::decode(&welcome.payload)?;
+        agent.services = welcome.services & services;
+        agent.max_payload = welcome.max_payload;
+        Ok(agent)
+    }
+
+    pub fn services(&self) -> u32 {
+        self.services
+    }
+
+    /// The largest request payload the hypervisor accepts.
+    pub fn max_payload(&self) -> u32 {
+        self.max_payload
+    }
+
+    pub fn into_channel(self) -> C {
+        self.channel
+    }
+
+    /// Fail unless the hypervisor granted `service`.
+    pub fn require(&self, service: u32) -> Result<(), AgentError> {
+        if self.services & service == service {
+            Ok(())
+        } else {
+            Err(AgentError::NotGranted(service))
+        }
+    }
+
+    /// Send a request and wait for its reply, turning ERROR replies into
+    /// errors.
+    pub fn request(&mut self, kind: u16, payload: Vec<u8>) -> Result<Frame, AgentError> {
+        if payload.len() > self.max_payload as usize {
+            return Err(AgentError::TooLarge);
+        }
+        self.last_id = match self.last_id.wrapping_add(1) {
+            0 => 1,
+            id => id,
+        };
+        let id = self.last_id;
+        self.channel.send(&Frame::new(kind, id, payload).encode())?;
+        loop {
+            let frame = self.read_frame()?;
+            if frame.id == 0 {
+                self.events.push_back(Event::decode(frame)?);
+            } else if frame.id == id {
+                return match frame.error_code() {
+                    Some(code) => Err(AgentError::Refused(code)),
+                    None => Ok(frame),
+                };
+            }
+            // Anything else answers a request given up on.
+        }
+    }
+
+    /// Send a message that gets no reply.
+    pub fn notify(&mut self, kind: u16, payload: Vec<u8>) -> Result<(), AgentError> {
+        if payload.len() > self.max_payload as usize {
+            return Err(AgentError::TooLarge);
+        }
+        self.channel.send(&Frame::new(kind, 0, payload).encode())?;
+        Ok(())
+    }
+
+    /// Wait for the next event.
+    pub fn next_event(&mut self) -> Result<Event, AgentError> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let frame = self.read_frame()?;
+            if frame.id == 0 {
+                return Ok(Event::decode(frame)?);
+            }
+        }
+    }
+
+    /// An event that arrived while waiting for a reply, if any.
+    pub fn queued_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, AgentError> {
+        let mut buf = vec![0u8; 4096];
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(frame);
+            }
+            let len = self.channel.recv(&mut buf)?;
+            if len == 0 {
+                return Err(AgentError::Channel(ChannelError::Closed));
+            }
+            self.decoder.push(&buf[..len]);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::protocol::{MSG_OK, SERVICE_CLIPBOARD};
+    use alloc::collections::VecDeque;
+
+    /// A hypervisor that answers each request with the next scripted
+    /// reply, preceded by any scripted events.
+    pub(crate) struct Scripted {
+        pub sent: Vec<Frame>,
+        pub replies: VecDeque<(Vec<Frame>, Frame)>,
+        pending: Vec<u8>,
+        decoder: FrameDecoder,
+    }
+
+    impl Scripted {
+        pub(crate) fn new(replies: Vec<(Vec<Frame>, Frame)>) -> Self {
+            let decoder = FrameDecoder::new(MAX_PAYLOAD);
+            Scripted { sent: Vec::new(), replies: replies.into(), pending: Vec::new(), decoder }
+        }
+
+        /// A channel whose first reply welcomes the guest with `services`.
+        pub(crate) fn welcoming(services: u32, mut replies: Vec<(Vec<Frame>, Frame)>) -> Self {
+            let welcome = Hello { version: PROTOCOL_VERSION, services, max_payload: 64 };
+            replies.insert(0, (Vec::new(), Frame::new(MSG_WELCOME, 0, welcome.encode())));
+            Self::new(replies)
+        }
+    }
+
+    impl Channel for Scripted {
+        fn send(&mut self, data: &[u8]) -> Result<(), ChannelError> {
+            self.decoder.push(data);
+            while let Some(request) = self.decoder.next_frame().unwrap() {
+                if request.id == 0 {
+                    // Events get no reply.
+                    self.sent.push(request);
+                    continue;
+                }
+                let (events, mut reply) = self.replies.pop_front().ok_or(ChannelError::Closed)?;
+                reply.id = request.id;
+                self.sent.push(request);
+                for event in events {
+                    self.pending.extend(event.encode());
+                }
+                self.pending.extend(reply.encode());
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+            // Dribble the bytes out to exercise reassembly.
+            let len = core::cmp::min(5, self.pending.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_handshake_and_requests() {
+        let changed = Frame::new(MSG_CLIPBOARD_CHANGED, 0, [&5u64.to_le_bytes()[..], &[1, 0, 1, 0]].concat());
+        let script = vec![(vec![changed], Frame::ok(0)), (Vec::new(), Frame::error(0, ErrorCode::Denied))];
+        let mut agent = Agent::connect(Scripted::welcoming(SERVICE_CLIPBOARD | 2, script), SERVICE_CLIPBOARD).unwrap();
+        // Only what was both asked for and granted counts.
+        assert_eq!(agent.services(), SERVICE_CLIPBOARD);
+        assert_eq!(agent.max_payload(), 64);
+        assert_eq!(agent.require(2), Err(AgentError::NotGranted(2)));
+
+        assert_eq!(agent.request(0x0150, vec![1]).unwrap().kind, MSG_OK);
+        assert_eq!(agent.request(0x0150, vec![2]), Err(AgentError::Refused(ErrorCode::Denied)));
+        assert_eq!(agent.request(0x0150, vec![0; 65]), Err(AgentError::TooLarge));
+        let expected = Event::ClipboardChanged { serial: 5, formats: vec![ClipboardFormat::Text] };
+        assert_eq!(agent.next_event(), Ok(expected));
+
+        let channel = agent.into_channel();
+        let ids: Vec<u32> = channel.sent.iter().map(|frame| frame.id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        assert_eq!(Hello::decode(&channel.sent[0].payload).unwrap().services, SERVICE_CLIPBOARD);
+        // Nothing left to read means the hypervisor hung up.
+        let mut agent = Agent::connect(Scripted::welcoming(0, Vec::new()), 0).unwrap();
+        assert_eq!(agent.next_event(), Err(AgentError::Channel(ChannelError::Closed)));
+    }
+}
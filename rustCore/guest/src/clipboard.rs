@@ -0,0 +1,84 @@
+// The clipboard service from the guest's side: publish what the user copied,
+// fetch what another VM copied when the user pastes.
+
+use alloc::vec::Vec;
+
+use crate::agent::{Agent, AgentError, Channel};
+use crate::protocol::{
+    decode_items, encode_formats, encode_items, ClipboardFormat, ClipboardItem, Reader, MSG_CLIPBOARD_DATA,
+    MSG_CLIPBOARD_GET, MSG_CLIPBOARD_SET, MSG_OK, SERVICE_CLIPBOARD,
+};
+
+/// The shared clipboard as fetched, with the serial of the copy it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clipboard {
+    pub serial: u64,
+    pub items: Vec<ClipboardItem>,
+}
+
+impl Clipboard {
+    pub fn get(&self, format: ClipboardFormat) -> Option<&ClipboardItem> {
+        self.items.iter().find(|item| item.format() == format)
+    }
+}
+
+impl<C: Channel> Agent<C> {
+    /// Share the guest's clipboard, one item per format, with other VMs.
+    pub fn set_clipboard(&mut self, items: &[ClipboardItem]) -> Result<(), AgentError> {
+        self.require(SERVICE_CLIPBOARD)?;
+        let reply = self.request(MSG_CLIPBOARD_SET, encode_items(items))?;
+        match reply.kind {
+            MSG_OK => Ok(()),
+            kind => Err(AgentError::Unexpected(kind)),
+        }
+    }
+
+    /// Fetch the shared clipboard in `formats`, or in every format it has
+    /// if `formats` is empty.
+    pub fn get_clipboard(&mut self, formats: &[ClipboardFormat]) -> Result<Clipboard, AgentError> {
+        self.require(SERVICE_CLIPBOARD)?;
+        let reply = self.request(MSG_CLIPBOARD_GET, encode_formats(formats))?;
+        if reply.kind != MSG_CLIPBOARD_DATA {
+            return Err(AgentError::Unexpected(reply.kind));
+        }
+        let mut reader = Reader::new(&reply.payload);
+        let serial = reader.u64()?;
+        let items = decode_items(&mut reader)?;
+        reader.finish()?;
+        Ok(Clipboard { serial, items })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::tests::Scripted;
+    use crate::protocol::{ErrorCode, Frame, Writer};
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn test_copy_and_paste() {
+        let text = ClipboardItem::Text("copied".to_string());
+        let data = [&Writer::new().u64(3).finish()[..], &encode_items(core::slice::from_ref(&text))].concat();
+        let script = vec![
+            (Vec::new(), Frame::ok(0)),
+            (Vec::new(), Frame::new(MSG_CLIPBOARD_DATA, 0, data)),
+            (Vec::new(), Frame::error(0, ErrorCode::Empty)),
+        ];
+        let mut agent = Agent::connect(Scripted::welcoming(SERVICE_CLIPBOARD, script), SERVICE_CLIPBOARD).unwrap();
+        agent.set_clipboard(core::slice::from_ref(&text)).unwrap();
+        let clipboard = agent.get_clipboard(&[ClipboardFormat::Text]).unwrap();
+        assert_eq!(clipboard.serial, 3);
+        assert_eq!(clipboard.get(ClipboardFormat::Text), Some(&text));
+        assert_eq!(clipboard.get(ClipboardFormat::Html), None);
+        assert_eq!(agent.get_clipboard(&[]), Err(AgentError::Refused(ErrorCode::Empty)));
+
+        let sent = agent.into_channel().sent;
+        assert_eq!(sent[1].kind, MSG_CLIPBOARD_SET);
+        assert_eq!(sent[2].payload, encode_formats(&[ClipboardFormat::Text]));
+
+        let mut agent = Agent::connect(Scripted::welcoming(0, Vec::new()), SERVICE_CLIPBOARD).unwrap();
+        assert_eq!(agent.set_clipboard(&[text]), Err(AgentError::NotGranted(SERVICE_CLIPBOARD)));
+    }
+}
@@ -0,0 +1,112 @@
+// Drag-and-drop between VMs from the guest's side. The source agent offers
+// the files being dragged and sends their contents when the hypervisor pulls
+// them; the target agent accepts a drop and reads the staged files back,
+// from any offset, so either side can pick up where it left off.
+
+use alloc::vec::Vec;
+
+use crate::agent::{Agent, AgentError, Channel};
+use crate::protocol::{
+    encode_files, ErrorCode, FileInfo, Reader, Writer, MSG_DND_ACCEPT, MSG_DND_CANCEL, MSG_DND_CHUNK, MSG_DND_DONE,
+    MSG_DND_OFFER, MSG_DND_OFFERED, MSG_DND_READ, MSG_OK, SERVICE_DND,
+};
+
+impl<C: Channel> Agent<C> {
+    /// Offer the files the user started dragging, replacing any earlier
+    /// offer not yet dropped. Returns the transfer ID.
+    pub fn offer_files(&mut self, files: &[FileInfo]) -> Result<u64, AgentError> {
+        self.require(SERVICE_DND)?;
+        let reply = self.request(MSG_DND_OFFER, encode_files(files))?;
+        if reply.kind != MSG_DND_OFFERED {
+            return Err(AgentError::Unexpected(reply.kind));
+        }
+        let mut reader = Reader::new(&reply.payload);
+        let transfer = reader.u64()?;
+        reader.finish()?;
+        Ok(transfer)
+    }
+
+    /// Answer a pull with `data` read from `offset` of the file.
+    pub fn send_chunk(&mut self, transfer: u64, file: u16, offset: u64, data: &[u8]) -> Result<(), AgentError> {
+        self.require(SERVICE_DND)?;
+        self.notify(MSG_DND_CHUNK, Writer::new().u64(transfer).u16(file).u64(offset).bytes(data).finish())
+    }
+
+    /// Accept the dropped files at `files`, as indices into the drop's
+    /// list; accepting none declines the drop.
+    pub fn accept_drop(&mut self, transfer: u64, files: &[u16]) -> Result<(), AgentError> {
+        self.require(SERVICE_DND)?;
+        let payload = files.iter().fold(Writer::new().u64(transfer).u16(files.len() as u16), |w, &file| w.u16(file));
+        self.expect_ok(MSG_DND_ACCEPT, payload.finish())
+    }
+
+    /// Read up to `len` bytes of a staged file from `offset`. An empty
+    /// result is the end of the file.
+    pub fn read_chunk(&mut self, transfer: u64, file: u16, offset: u64, len: u32) -> Result<Vec<u8>, AgentError> {
+        self.require(SERVICE_DND)?;
+        let request = Writer::new().u64(transfer).u16(file).u64(offset).u32(len).finish();
+        let reply = self.request(MSG_DND_READ, request)?;
+        if reply.kind != MSG_DND_CHUNK {
+            return Err(AgentError::Unexpected(reply.kind));
+        }
+        let mut reader = Reader::new(&reply.payload);
+        let (_transfer, _file, _offset) = (reader.u64()?, reader.u16()?, reader.u64()?);
+        let data = reader.bytes()?.to_vec();
+        reader.finish()?;
+        Ok(data)
+    }
+
+    /// Tell the hypervisor a file arrived intact, so it can drop its copy.
+    pub fn finish_file(&mut self, transfer: u64, file: u16) -> Result<(), AgentError> {
+        self.require(SERVICE_DND)?;
+        self.expect_ok(MSG_DND_DONE, Writer::new().u64(transfer).u16(file).finish())
+    }
+
+    /// Call off a transfer from either end.
+    pub fn cancel_transfer(&mut self, transfer: u64, code: ErrorCode) -> Result<(), AgentError> {
+        self.require(SERVICE_DND)?;
+        self.notify(MSG_DND_CANCEL, Writer::new().u64(transfer).u16(code.code()).finish())
+    }
+
+    fn expect_ok(&mut self, kind: u16, payload: Vec<u8>) -> Result<(), AgentError> {
+        match self.request(kind, payload)?.kind {
+            MSG_OK => Ok(()),
+            kind => Err(AgentError::Unexpected(kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::tests::Scripted;
+    use crate::agent::Event;
+    use crate::protocol::{Crc32, Frame, MSG_DND_PULL};
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn test_offer_and_read() {
+        let file = FileInfo { name: "a.txt".to_string(), size: 5, checksum: Crc32::checksum(b"hello") };
+        let pull = Frame::new(MSG_DND_PULL, 0, Writer::new().u64(7).u16(0).u64(2).u32(3).finish());
+        let chunk = Writer::new().u64(7).u16(0).u64(2).bytes(b"llo").finish();
+        let script = vec![
+            (Vec::new(), Frame::new(MSG_DND_OFFERED, 0, 7u64.to_le_bytes().to_vec())),
+            (vec![pull], Frame::ok(0)),
+            (Vec::new(), Frame::new(MSG_DND_CHUNK, 0, chunk)),
+            (Vec::new(), Frame::error(0, ErrorCode::NotFound)),
+        ];
+        let mut agent = Agent::connect(Scripted::welcoming(SERVICE_DND, script), SERVICE_DND).unwrap();
+        assert_eq!(agent.offer_files(core::slice::from_ref(&file)), Ok(7));
+        agent.accept_drop(7, &[0]).unwrap();
+        assert_eq!(agent.next_event(), Ok(Event::DndPull { transfer: 7, file: 0, offset: 2, len: 3 }));
+        agent.send_chunk(7, 0, 2, b"llo").unwrap();
+        assert_eq!(agent.read_chunk(7, 0, 2, 3), Ok(b"llo".to_vec()));
+        assert_eq!(agent.finish_file(8, 0), Err(AgentError::Refused(ErrorCode::NotFound)));
+
+        let sent = agent.into_channel().sent;
+        assert_eq!(sent[2].payload, [&7u64.to_le_bytes()[..], &[1, 0, 0, 0]].concat());
+        // Chunks are events, so the next request got the next ID.
+        assert_eq!((sent[3].kind, sent[3].id, sent[4].id), (MSG_DND_CHUNK, 0, 4));
+    }
+}
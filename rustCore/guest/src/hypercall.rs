@@ -0,0 +1,307 @@
+// The hypercall ABI, as specified in HYPERCALL.md: guests call into the
+// hypervisor directly with VMCALL, without an agent. The hypervisor takes its
+// function numbers, status codes and CPUID leaves from here, so both ends
+// always agree on them.
+//
+// Everything goes through the `Vmcall` trait, so the calls can be tested
+// without a hypervisor; `Native` is the real instruction.
+
+/// Highest version of the ABI described here.
+pub const HYPERCALL_VERSION: u32 = 1;
+
+/// Highest hypervisor leaf in EAX, signature in EBX, ECX and EDX.
+pub const CPUID_SIGNATURE_LEAF: u32 = 0x4000_0000;
+pub const SIGNATURE: &[u8; 12] = b"Hypercore\0\0\0";
+/// ABI version in EAX, 0 without hypercalls; the functions available in it
+/// as a bitmap in EBX (functions 0-31) and ECX (32-63).
+pub const CPUID_FEATURES_LEAF: u32 = 0x4000_0001;
+
+// Function numbers, passed in RAX.
+pub const HC_VERSION: u32 = 0;
+pub const HC_TIME: u32 = 1;
+pub const HC_CLIPBOARD_SET: u32 = 2;
+pub const HC_CLIPBOARD_GET: u32 = 3;
+pub const HC_VOLUME_NOTIFY: u32 = 4;
+
+/// The ABI version that introduced `function`, None if no version has it.
+pub fn introduced_in(function: u32) -> Option<u32> {
+    match function {
+        HC_VERSION | HC_TIME | HC_CLIPBOARD_SET | HC_CLIPBOARD_GET | HC_VOLUME_NOTIFY => Some(1),
+        _ => None,
+    }
+}
+
+/// Why a hypercall failed, returned negated in RAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HypercallError {
+    /// Unknown function, or one the VM does not get.
+    NotSupported,
+    /// Called from outside ring 0, or the VM's policy forbids it.
+    Denied,
+    /// A bad argument, or a buffer outside guest RAM.
+    Invalid,
+    NotFound,
+    /// The guest's buffer cannot hold the result.
+    TooSmall,
+    /// There is nothing to return, such as an empty clipboard.
+    Empty,
+    TooLarge,
+    Other(i64),
+}
+
+impl HypercallError {
+    pub fn code(self) -> i64 {
+        match self {
+            HypercallError::NotSupported => -1,
+            HypercallError::Denied => -2,
+            HypercallError::Invalid => -3,
+            HypercallError::NotFound => -4,
+            HypercallError::TooSmall => -5,
+            HypercallError::Empty => -6,
+            HypercallError::TooLarge => -7,
+            HypercallError::Other(code) => code,
+        }
+    }
+
+    pub fn from_code(code: i64) -> Self {
+        match code {
+            -1 => HypercallError::NotSupported,
+            -2 => HypercallError::Denied,
+            -3 => HypercallError::Invalid,
+            -4 => HypercallError::NotFound,
+            -5 => HypercallError::TooSmall,
+            -6 => HypercallError::Empty,
+            -7 => HypercallError::TooLarge,
+            code => HypercallError::Other(code),
+        }
+    }
+}
+
+/// The instructions hypercalls are made with.
+pub trait Vmcall {
+    /// VMCALL with `function` in RAX and `args` in RBX, RCX, RDX and RSI.
+    /// Returns RAX, RBX, RCX and RDX afterwards.
+    fn vmcall(&mut self, function: u64, args: [u64; 4]) -> [u64; 4];
+
+    /// CPUID `leaf`, subleaf 0, as EAX, EBX, ECX and EDX.
+    fn cpuid(&mut self, leaf: u32) -> [u32; 4];
+}
+
+/// The CPU's own VMCALL and CPUID.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Native;
+
+#[cfg(target_arch = "x86_64")]
+impl Vmcall for Native {
+    fn vmcall(&mut self, function: u64, args: [u64; 4]) -> [u64; 4] {
+        let (rax, rbx, rcx, rdx);
+        // RBX belongs to LLVM, so it is swapped in and out around the call.
+        // The hypervisor may write guest memory the arguments point at.
+        unsafe {
+            core::arch::asm!(
+                "xchg {b}, rbx",
+                "vmcall",
+                "xchg {b}, rbx",
+                b = inout(reg) args[0] => rbx,
+                inout("rax") function => rax,
+                inout("rcx") args[1] => rcx,
+                inout("rdx") args[2] => rdx,
+                in("rsi") args[3],
+                options(nostack),
+            );
+        }
+        [rax, rbx, rcx, rdx]
+    }
+
+    fn cpuid(&mut self, leaf: u32) -> [u32; 4] {
+        let result = core::arch::x86_64::__cpuid_count(leaf, 0);
+        [result.eax, result.ebx, result.ecx, result.edx]
+    }
+}
+
+/// Host wall-clock time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WallClock {
+    /// Seconds since the Unix epoch.
+    pub secs: u64,
+    pub nanos: u32,
+}
+
+/// The guest's copy of the shared clipboard text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClipboardText {
+    /// Length of the text in bytes.
+    pub len: usize,
+    /// Serial of the copy it came from, as the agent protocol counts them.
+    pub serial: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeAccess {
+    Read,
+    Write,
+}
+
+/// Where a shared volume is at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeState {
+    /// Goes up whenever the volume, or who may use it, changes.
+    pub generation: u64,
+    pub access: VolumeAccess,
+}
+
+/// Hypercalls to Hypercore, once it has been found and the ABI version
+/// agreed.
+pub struct Hypercalls<V: Vmcall> {
+    vmcall: V,
+    version: u32,
+    functions: u64,
+}
+
+impl<V: Vmcall> Hypercalls<V> {
+    /// Look for Hypercore's CPUID leaves and agree on an ABI version. None
+    /// when not running on Hypercore, or on one without hypercalls.
+    pub fn detect(mut vmcall: V) -> Option<Self> {
+        let [max_leaf, ebx, ecx, edx] = vmcall.cpuid(CPUID_SIGNATURE_LEAF);
+        let signature = [ebx.to_le_bytes(), ecx.to_le_bytes(), edx.to_le_bytes()].concat();
+        if signature != SIGNATURE || max_leaf < CPUID_FEATURES_LEAF || vmcall.cpuid(CPUID_FEATURES_LEAF)[0] == 0 {
+            return None;
+        }
+        let mut calls = Hypercalls { vmcall, version: 0, functions: 1 << HC_VERSION };
+        let [version, functions, _] = calls.call(HC_VERSION, [HYPERCALL_VERSION as u64, 0, 0, 0]).ok()?;
+        calls.version = version as u32;
+        calls.functions = functions;
+        Some(calls)
+    }
+
+    /// The ABI version in use.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Whether the hypervisor offers `function` to this VM.
+    pub fn supports(&self, function: u32) -> bool {
+        function < 64 && self.functions & (1 << function) != 0
+    }
+
+    /// Make hypercall `function`, returning RBX, RCX and RDX.
+    pub fn call(&mut self, function: u32, args: [u64; 4]) -> Result<[u64; 3], HypercallError> {
+        if !self.supports(function) {
+            return Err(HypercallError::NotSupported);
+        }
+        let [status, rbx, rcx, rdx] = self.vmcall.vmcall(function as u64, args);
+        match status as i64 {
+            0 => Ok([rbx, rcx, rdx]),
+            code => Err(HypercallError::from_code(code)),
+        }
+    }
+
+    pub fn time(&mut self) -> Result<WallClock, HypercallError> {
+        let [secs, nanos, _] = self.call(HC_TIME, [0; 4])?;
+        Ok(WallClock { secs, nanos: nanos as u32 })
+    }
+
+    /// Share `len` bytes of UTF-8 text at guest-physical `gpa` as the
+    /// clipboard, or clear it if `len` is 0. Returns the copy's serial.
+    ///
+    /// # Safety
+    ///
+    /// The text must be in guest RAM at `gpa`.
+    pub unsafe fn clipboard_set(&mut self, gpa: u64, len: usize) -> Result<u64, HypercallError> {
+        Ok(self.call(HC_CLIPBOARD_SET, [gpa, len as u64, 0, 0])?[0])
+    }
+
+    /// The shared clipboard's text length and serial, without copying it.
+    pub fn clipboard_len(&mut self) -> Result<ClipboardText, HypercallError> {
+        let [len, serial, _] = self.call(HC_CLIPBOARD_GET, [0; 4])?;
+        Ok(ClipboardText { len: len as usize, serial })
+    }
+
+    /// Copy the shared clipboard's text to guest-physical `gpa`, failing with
+    /// `TooSmall` if it is longer than `size`.
+    ///
+    /// # Safety
+    ///
+    /// `size` bytes at `gpa` must be guest RAM the hypervisor may overwrite.
+    pub unsafe fn clipboard_get(&mut self, gpa: u64, size: usize) -> Result<ClipboardText, HypercallError> {
+        let [len, serial, _] = self.call(HC_CLIPBOARD_GET, [gpa, size as u64, 0, 0])?;
+        Ok(ClipboardText { len: len as usize, serial })
+    }
+
+    /// The state of the shared volume whose name is the `len` bytes at
+    /// guest-physical `gpa`. Polling it tells the guest when another VM
+    /// changed the volume, so it can drop what it cached.
+    ///
+    /// # Safety
+    ///
+    /// The name must be in guest RAM at `gpa`.
+    pub unsafe fn volume_state(&mut self, gpa: u64, len: usize) -> Result<VolumeState, HypercallError> {
+        let [generation, access, _] = self.call(HC_VOLUME_NOTIFY, [gpa, len as u64, 0, 0])?;
+        let access = match access {
+            1 => VolumeAccess::Read,
+            2 => VolumeAccess::Write,
+            _ => return Err(HypercallError::Other(access as i64)),
+        };
+        Ok(VolumeState { generation, access })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// A hypervisor with hypercall version `version` that records the calls
+    /// it gets and answers them from `replies`.
+    struct MockHypervisor {
+        version: u32,
+        calls: Vec<(u64, [u64; 4])>,
+        replies: Vec<[u64; 4]>,
+    }
+
+    impl Vmcall for MockHypervisor {
+        fn vmcall(&mut self, function: u64, args: [u64; 4]) -> [u64; 4] {
+            self.calls.push((function, args));
+            self.replies.remove(0)
+        }
+
+        fn cpuid(&mut self, leaf: u32) -> [u32; 4] {
+            let word = |i: usize| u32::from_le_bytes(SIGNATURE[i..i + 4].try_into().unwrap());
+            match leaf {
+                CPUID_SIGNATURE_LEAF => [CPUID_FEATURES_LEAF, word(0), word(4), word(8)],
+                CPUID_FEATURES_LEAF => [self.version, 0b11111, 0, 0],
+                _ => [0; 4],
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_and_call() {
+        let none = MockHypervisor { version: 0, calls: Vec::new(), replies: Vec::new() };
+        assert!(Hypercalls::detect(none).is_none());
+
+        let replies = Vec::from([
+            [0, 1, 0b1011, 0],
+            [0, 1_700_000_000, 5, 0],
+            [HypercallError::Denied.code() as u64, 0, 0, 0],
+            [0, 12, 7, 0],
+        ]);
+        let mock = MockHypervisor { version: 1, calls: Vec::new(), replies };
+        let mut calls = Hypercalls::detect(mock).unwrap();
+        assert_eq!(calls.version(), 1);
+        assert!(calls.supports(HC_CLIPBOARD_GET) && !calls.supports(HC_CLIPBOARD_SET));
+
+        assert_eq!(calls.time(), Ok(WallClock { secs: 1_700_000_000, nanos: 5 }));
+        // Functions the hypervisor did not offer are not called at all.
+        assert_eq!(unsafe { calls.clipboard_set(0x1000, 4) }, Err(HypercallError::NotSupported));
+        assert_eq!(calls.clipboard_len(), Err(HypercallError::Denied));
+        assert_eq!(unsafe { calls.clipboard_get(0x2000, 64) }, Ok(ClipboardText { len: 12, serial: 7 }));
+        let state = unsafe { calls.volume_state(0x3000, 4) };
+        assert_eq!(state, Err(HypercallError::NotSupported));
+
+        let made: Vec<u64> = calls.vmcall.calls.iter().map(|&(function, _)| function).collect();
+        assert_eq!(made, [HC_VERSION, HC_TIME, HC_CLIPBOARD_GET, HC_CLIPBOARD_GET].map(u64::from));
+        assert_eq!(calls.vmcall.calls[3].1, [0x2000, 64, 0, 0]);
+    }
+}
@@ -0,0 +1,20 @@
+// Guest-side library for Hypercore's paravirtual services: the agent
+// protocol and clients for the services reached over the agent channel, and
+// the hypercall ABI for calling the hypervisor directly.
+// It only needs `alloc`, so guest kernels can use it as well as user-space
+// agents.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod agent;
+pub mod clipboard;
+pub mod dnd;
+pub mod hypercall;
+pub mod protocol;
+
+pub use agent::{Agent, AgentError, Channel, ChannelError, Event};
+pub use clipboard::Clipboard;
+pub use hypercall::{HypercallError, Hypercalls, Vmcall};
+pub use protocol::{ClipboardFormat, ClipboardItem, Crc32, ErrorCode, FileInfo, Frame};
@@ -0,0 +1,586 @@
+// The agent protocol spoken between a guest agent and the hypervisor over the
+// agent channel, as specified in PROTOCOL.md. The hypervisor uses this codec
+// too, so both ends always agree on the wire format.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Length, kind, flags and request ID.
+pub const HEADER_LEN: usize = 12;
+/// Largest payload a peer may ever be asked to accept.
+pub const MAX_PAYLOAD: u32 = 16 << 20;
+
+// Message kinds. The high byte names the service, 0 being the channel.
+pub const MSG_HELLO: u16 = 0x0001;
+pub const MSG_WELCOME: u16 = 0x0002;
+pub const MSG_OK: u16 = 0x0003;
+pub const MSG_ERROR: u16 = 0x0004;
+pub const MSG_CLIPBOARD_SET: u16 = 0x0101;
+pub const MSG_CLIPBOARD_GET: u16 = 0x0102;
+pub const MSG_CLIPBOARD_DATA: u16 = 0x0103;
+pub const MSG_CLIPBOARD_CHANGED: u16 = 0x0104;
+pub const MSG_DND_OFFER: u16 = 0x0201;
+pub const MSG_DND_OFFERED: u16 = 0x0202;
+pub const MSG_DND_DROP: u16 = 0x0203;
+pub const MSG_DND_ACCEPT: u16 = 0x0204;
+pub const MSG_DND_PULL: u16 = 0x0205;
+pub const MSG_DND_CHUNK: u16 = 0x0206;
+pub const MSG_DND_READY: u16 = 0x0207;
+pub const MSG_DND_READ: u16 = 0x0208;
+pub const MSG_DND_DONE: u16 = 0x0209;
+pub const MSG_DND_CANCEL: u16 = 0x020A;
+
+// Services, as bits of the masks exchanged in HELLO and WELCOME.
+pub const SERVICE_CLIPBOARD: u32 = 1 << 0;
+pub const SERVICE_DND: u32 = 1 << 1;
+
+/// The service bit a message kind belongs to, 0 for channel messages.
+pub fn service_of(kind: u16) -> u32 {
+    match kind >> 8 {
+        0 => 0,
+        n => 1u32.checked_shl(n as u32 - 1).unwrap_or(0),
+    }
+}
+
+/// Why a request was refused, carried by an ERROR reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Malformed,
+    /// Unknown message kind, or a service the guest was not granted.
+    Unsupported,
+    /// The VM's policy forbids it.
+    Denied,
+    TooLarge,
+    /// There is nothing to return, such as an empty clipboard.
+    Empty,
+    /// The guest has not sent HELLO yet.
+    NoSession,
+    /// No such transfer, or not one the VM takes part in.
+    NotFound,
+    /// Data failed checksum verification.
+    Corrupt,
+    /// The user or an agent called it off.
+    Cancelled,
+    Other(u16),
+}
+
+impl ErrorCode {
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorCode::Malformed => 1,
+            ErrorCode::Unsupported => 2,
+            ErrorCode::Denied => 3,
+            ErrorCode::TooLarge => 4,
+            ErrorCode::Empty => 5,
+            ErrorCode::NoSession => 6,
+            ErrorCode::NotFound => 7,
+            ErrorCode::Corrupt => 8,
+            ErrorCode::Cancelled => 9,
+            ErrorCode::Other(code) => code,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::Malformed,
+            2 => ErrorCode::Unsupported,
+            3 => ErrorCode::Denied,
+            4 => ErrorCode::TooLarge,
+            5 => ErrorCode::Empty,
+            6 => ErrorCode::NoSession,
+            7 => ErrorCode::NotFound,
+            8 => ErrorCode::Corrupt,
+            9 => ErrorCode::Cancelled,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A payload ended early, had bytes left over or held bad UTF-8.
+    Malformed,
+    /// A frame announced a payload over the limit. Its payload is skipped
+    /// as it arrives; the frame's kind and ID allow an ERROR reply.
+    TooLarge { kind: u16, id: u32, len: u32 },
+}
+
+/// One message on the channel. Replies carry the ID of their request;
+/// events, which answer nothing, carry 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: u16,
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: u16, id: u32, payload: Vec<u8>) -> Self {
+        Frame { kind, id, payload }
+    }
+
+    pub fn ok(id: u32) -> Self {
+        Frame::new(MSG_OK, id, Vec::new())
+    }
+
+    pub fn error(id: u32, code: ErrorCode) -> Self {
+        Frame::new(MSG_ERROR, id, Writer::new().u16(code.code()).finish())
+    }
+
+    /// The code of an ERROR frame.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        if self.kind != MSG_ERROR {
+            return None;
+        }
+        let mut reader = Reader::new(&self.payload);
+        Some(ErrorCode::from_code(reader.u16().unwrap_or(0)))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.kind.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&self.id.to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Splits the byte stream from a channel back into frames.
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    max_payload: u32,
+    /// Bytes still to discard of an oversized frame.
+    skip: usize,
+}
+
+impl FrameDecoder {
+    pub fn new(max_payload: u32) -> Self {
+        FrameDecoder { buf: Vec::new(), max_payload, skip: 0 }
+    }
+
+    pub fn push(&mut self, mut bytes: &[u8]) {
+        let skipped = core::cmp::min(self.skip, bytes.len());
+        self.skip -= skipped;
+        bytes = &bytes[skipped..];
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The next complete frame, if one has arrived.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, DecodeError> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let mut header = Reader::new(&self.buf[..HEADER_LEN]);
+        let (len, kind, _flags, id) = (header.u32()?, header.u16()?, header.u16()?, header.u32()?);
+        if len > self.max_payload {
+            let buffered = core::cmp::min(self.buf.len() - HEADER_LEN, len as usize);
+            self.buf.drain(..HEADER_LEN + buffered);
+            self.skip = len as usize - buffered;
+            return Err(DecodeError::TooLarge { kind, id, len });
+        }
+        let end = HEADER_LEN + len as usize;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+        let payload = self.buf[HEADER_LEN..end].to_vec();
+        self.buf.drain(..end);
+        Ok(Some(Frame { kind, id, payload }))
+    }
+}
+
+/// Builds a payload out of little-endian fields.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn u16(mut self, value: u16) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// A byte string behind its u32 length.
+    pub fn bytes(self, data: &[u8]) -> Self {
+        let mut writer = self.u32(data.len() as u32);
+        writer.buf.extend_from_slice(data);
+        writer
+    }
+
+    pub fn str(self, text: &str) -> Self {
+        self.bytes(text.as_bytes())
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Takes the fields of a payload apart again.
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < len {
+            return Err(DecodeError::Malformed);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn string(&mut self) -> Result<String, DecodeError> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::Malformed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Check that the whole payload was consumed.
+    pub fn finish(self) -> Result<(), DecodeError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::Malformed)
+        }
+    }
+}
+
+/// HELLO from the guest, and the hypervisor's WELCOME in reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    /// Services wanted by the guest, or granted by the hypervisor.
+    pub services: u32,
+    /// In WELCOME only: the largest payload the hypervisor accepts.
+    pub max_payload: u32,
+}
+
+impl Hello {
+    pub fn encode(&self) -> Vec<u8> {
+        Writer::new().u16(self.version).u32(self.services).u32(self.max_payload).finish()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(payload);
+        let hello = Hello { version: reader.u16()?, services: reader.u32()?, max_payload: reader.u32()? };
+        reader.finish()?;
+        Ok(hello)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClipboardFormat {
+    /// UTF-8 text.
+    Text,
+    /// UTF-8 HTML fragment.
+    Html,
+    /// A PNG image.
+    Image,
+    /// A list of file paths.
+    Files,
+}
+
+impl ClipboardFormat {
+    pub fn code(self) -> u16 {
+        match self {
+            ClipboardFormat::Text => 1,
+            ClipboardFormat::Html => 2,
+            ClipboardFormat::Image => 3,
+            ClipboardFormat::Files => 4,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            1 => Some(ClipboardFormat::Text),
+            2 => Some(ClipboardFormat::Html),
+            3 => Some(ClipboardFormat::Image),
+            4 => Some(ClipboardFormat::Files),
+            _ => None,
+        }
+    }
+}
+
+/// The clipboard in one format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClipboardItem {
+    Text(String),
+    Html(String),
+    Image(Vec<u8>),
+    Files(Vec<String>),
+}
+
+impl ClipboardItem {
+    pub fn format(&self) -> ClipboardFormat {
+        match self {
+            ClipboardItem::Text(_) => ClipboardFormat::Text,
+            ClipboardItem::Html(_) => ClipboardFormat::Html,
+            ClipboardItem::Image(_) => ClipboardFormat::Image,
+            ClipboardItem::Files(_) => ClipboardFormat::Files,
+        }
+    }
+
+    fn body(&self) -> Vec<u8> {
+        match self {
+            ClipboardItem::Text(text) | ClipboardItem::Html(text) => text.as_bytes().to_vec(),
+            ClipboardItem::Image(png) => png.clone(),
+            ClipboardItem::Files(paths) => {
+                paths.iter().fold(Writer::new().u16(paths.len() as u16), |writer, path| writer.str(path)).finish()
+            }
+        }
+    }
+
+    fn decode(format: ClipboardFormat, body: &[u8]) -> Result<Self, DecodeError> {
+        let text = || String::from_utf8(body.to_vec()).map_err(|_| DecodeError::Malformed);
+        Ok(match format {
+            ClipboardFormat::Text => ClipboardItem::Text(text()?),
+            ClipboardFormat::Html => ClipboardItem::Html(text()?),
+            ClipboardFormat::Image => ClipboardItem::Image(body.to_vec()),
+            ClipboardFormat::Files => {
+                let mut reader = Reader::new(body);
+                let count = reader.u16()?;
+                let paths = (0..count).map(|_| reader.string()).collect::<Result<_, _>>()?;
+                reader.finish()?;
+                ClipboardItem::Files(paths)
+            }
+        })
+    }
+}
+
+/// Encode clipboard items: a u16 count, then each item's format code and
+/// its body as a byte string.
+pub fn encode_items(items: &[ClipboardItem]) -> Vec<u8> {
+    items.iter().fold(Writer::new().u16(items.len() as u16), |writer, item| {
+        writer.u16(item.format().code()).bytes(&item.body())
+    })
+    .finish()
+}
+
+/// Decode clipboard items, skipping formats this side does not know.
+pub fn decode_items(reader: &mut Reader) -> Result<Vec<ClipboardItem>, DecodeError> {
+    let count = reader.u16()?;
+    let mut items = Vec::new();
+    for _ in 0..count {
+        let code = reader.u16()?;
+        let body = reader.bytes()?;
+        if let Some(format) = ClipboardFormat::from_code(code) {
+            items.push(ClipboardItem::decode(format, body)?);
+        }
+    }
+    Ok(items)
+}
+
+/// Encode a list of formats: a u16 count, then each format code.
+pub fn encode_formats(formats: &[ClipboardFormat]) -> Vec<u8> {
+    formats.iter().fold(Writer::new().u16(formats.len() as u16), |writer, format| writer.u16(format.code())).finish()
+}
+
+/// Decode a list of formats, skipping ones this side does not know.
+pub fn decode_formats(reader: &mut Reader) -> Result<Vec<ClipboardFormat>, DecodeError> {
+    let count = reader.u16()?;
+    let mut formats = Vec::new();
+    for _ in 0..count {
+        if let Some(format) = ClipboardFormat::from_code(reader.u16()?) {
+            formats.push(format);
+        }
+    }
+    Ok(formats)
+}
+
+/// A file offered for drag-and-drop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// The file's name, without any directory.
+    pub name: String,
+    pub size: u64,
+    /// CRC-32 of the contents, see [`Crc32`].
+    pub checksum: u32,
+}
+
+/// Encode a file list: a u16 count, then each file's name, size and
+/// checksum.
+pub fn encode_files(files: &[FileInfo]) -> Vec<u8> {
+    files.iter().fold(Writer::new().u16(files.len() as u16), |writer, file| {
+        writer.str(&file.name).u64(file.size).u32(file.checksum)
+    })
+    .finish()
+}
+
+pub fn decode_files(reader: &mut Reader) -> Result<Vec<FileInfo>, DecodeError> {
+    let count = reader.u16()?;
+    (0..count).map(|_| Ok(FileInfo { name: reader.string()?, size: reader.u64()?, checksum: reader.u32()? })).collect()
+}
+
+/// The CRC-32 used by zlib and Ethernet, computed a piece at a time.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state ^= byte as u32;
+            for _ in 0..8 {
+                self.state = (self.state >> 1) ^ (0xEDB8_8320 & (self.state & 1).wrapping_neg());
+            }
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.state
+    }
+
+    pub fn checksum(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn test_frame_round_trip_in_pieces() {
+        let frames = [Frame::new(MSG_CLIPBOARD_GET, 7, vec![1, 2, 3]), Frame::ok(7), Frame::error(9, ErrorCode::Denied)];
+        let stream: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+        assert_eq!(stream[..HEADER_LEN], [3, 0, 0, 0, 0x02, 0x01, 0, 0, 7, 0, 0, 0]);
+
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD);
+        let mut decoded = Vec::new();
+        for byte in stream {
+            decoder.push(&[byte]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames);
+        assert_eq!(decoded[2].error_code(), Some(ErrorCode::Denied));
+        assert_eq!(decoded[1].error_code(), None);
+    }
+
+    #[test]
+    fn test_oversized_frame_is_skipped() {
+        let mut decoder = FrameDecoder::new(8);
+        let big = Frame::new(MSG_CLIPBOARD_SET, 3, vec![0xAA; 20]).encode();
+        decoder.push(&big[..16]);
+        assert_eq!(decoder.next_frame(), Err(DecodeError::TooLarge { kind: MSG_CLIPBOARD_SET, id: 3, len: 20 }));
+        // The rest of its payload is dropped; the next frame is intact.
+        let mut rest = big[16..].to_vec();
+        rest.extend(Frame::ok(4).encode());
+        decoder.push(&rest);
+        assert_eq!(decoder.next_frame(), Ok(Some(Frame::ok(4))));
+        assert_eq!(decoder.next_frame(), Ok(None));
+    }
+
+    #[test]
+    fn test_clipboard_items() {
+        let items = vec![
+            ClipboardItem::Text("héllo".to_string()),
+            ClipboardItem::Html("<b>hi</b>".to_string()),
+            ClipboardItem::Image(vec![0x89, b'P', b'N', b'G']),
+            ClipboardItem::Files(vec!["/home/a.txt".to_string(), "/tmp/b".to_string()]),
+        ];
+        let encoded = encode_items(&items);
+        let mut reader = Reader::new(&encoded);
+        assert_eq!(decode_items(&mut reader).unwrap(), items);
+        reader.finish().unwrap();
+
+        // An unknown format is skipped, bad UTF-8 and short bodies are not.
+        let unknown = Writer::new().u16(2).u16(99).bytes(b"??").u16(1).bytes(b"ok").finish();
+        assert_eq!(decode_items(&mut Reader::new(&unknown)).unwrap(), [ClipboardItem::Text("ok".to_string())]);
+        let bad_text = Writer::new().u16(1).u16(1).bytes(&[0xFF]).finish();
+        assert_eq!(decode_items(&mut Reader::new(&bad_text)), Err(DecodeError::Malformed));
+        let short = Writer::new().u16(1).u16(4).bytes(&[3, 0]).finish();
+        assert_eq!(decode_items(&mut Reader::new(&short)), Err(DecodeError::Malformed));
+
+        let formats = encode_formats(&[ClipboardFormat::Html, ClipboardFormat::Files]);
+        assert_eq!(formats, [2, 0, 2, 0, 4, 0]);
+        assert_eq!(decode_formats(&mut Reader::new(&formats)).unwrap(), [ClipboardFormat::Html, ClipboardFormat::Files]);
+    }
+
+    #[test]
+    fn test_files_and_checksum() {
+        let files = vec![
+            FileInfo { name: "a.txt".to_string(), size: 9, checksum: 0xCBF4_3926 },
+            FileInfo { name: "empty".to_string(), size: 0, checksum: 0 },
+        ];
+        let encoded = encode_files(&files);
+        let mut reader = Reader::new(&encoded);
+        assert_eq!(decode_files(&mut reader).unwrap(), files);
+        reader.finish().unwrap();
+
+        assert_eq!(Crc32::checksum(b"123456789"), 0xCBF4_3926);
+        assert_eq!(Crc32::checksum(b""), 0);
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_service_of() {
+        assert_eq!(service_of(MSG_HELLO), 0);
+        assert_eq!(service_of(MSG_CLIPBOARD_CHANGED), SERVICE_CLIPBOARD);
+        assert_eq!(service_of(MSG_DND_CANCEL), SERVICE_DND);
+        assert_eq!(service_of(0x2001), 1 << 31);
+        assert_eq!(service_of(0x2101), 0);
+    }
+}
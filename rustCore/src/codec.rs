@@ -0,0 +1,139 @@
+// Little-endian encoding for the byte formats the hypervisor keeps and
+// sends: vCPU state, snapshot records, migration messages and 9P.
+//
+// A `Reader` fails every read past its end with the error it was made
+// with, so each format reports truncation in its own error type.
+
+use alloc::vec::Vec;
+
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    /// Overwrite the u32 at `offset`, for lengths known only once what
+    /// they cover is written.
+    pub fn patch_u32(&mut self, offset: usize, value: u32) {
+        self.buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+pub struct Reader<'a, E> {
+    data: &'a [u8],
+    pos: usize,
+    error: E,
+}
+
+impl<'a, E: Copy> Reader<'a, E> {
+    /// A reader of `data` whose reads past the end fail with `error`.
+    pub fn new(data: &'a [u8], error: E) -> Self {
+        Reader { data, pos: 0, error }
+    }
+
+    /// The error reads past the end fail with, for callers that find the
+    /// data malformed in other ways.
+    pub fn error(&self) -> E {
+        self.error
+    }
+
+    /// Whether everything has been read.
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], E> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len()).ok_or(self.error)?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Everything not read yet.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let bytes = &self.data[self.pos..];
+        self.pos = self.data.len();
+        bytes
+    }
+
+    pub fn u8(&mut self) -> Result<u8, E> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, E> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, E> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, E> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_truncation() {
+        let mut w = Writer::new();
+        w.u32(0).u8(1).u16(0x0203).u64(u64::MAX).bytes(b"xy");
+        let len = w.len() as u32;
+        w.patch_u32(0, len);
+        let data = w.into_bytes();
+        assert_eq!(data.len(), 17);
+
+        let mut r = Reader::new(&data, "truncated");
+        assert_eq!((r.u32(), r.u8(), r.u16(), r.u64()), (Ok(17), Ok(1), Ok(0x0203), Ok(u64::MAX)));
+        assert_eq!(r.take(3), Err("truncated"));
+        assert_eq!(r.rest(), b"xy");
+        assert!(r.is_empty());
+        assert_eq!(r.u8(), Err("truncated"));
+        assert_eq!(Reader::new(&data, ()).take(usize::MAX), Err(()));
+    }
+}
@@ -1,13 +1,94 @@
+// The VM manager on the framebuffer: a menu of what can be done to a VM, what
+// became of the last choice, and the file transfers between VMs.
+//
+// The GUI owns the VMs it shows, through a `VmManager`, and the agent hub
+// with the drag-and-drop broker that moves files between their windows.
+// `render` gives the native VMs a turn and serves their agents before it
+// draws.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
 use crate::graphics::FramebufferWriter;
-use crate::shell::{list_vms, create_vm, boot_vm, stop_vm, delete_vm, snapshot_vm, restore_vm, list_snapshots, print_help};
+use crate::hypervisor::SnapshotError;
+use crate::manager::{vmx_backend, ManagerError, VmManager};
+use crate::services::{AgentHub, DndBroker, DndPolicy, Outbox, StagingArea, TransferProgress, TransferState};
+use crate::storage::volume::SharedVolumeStore;
+use crate::storage::StorageBackend;
+use crate::vdev::channel::SharedChannel;
+use crate::vmx::backend::VmxBackend;
 
-pub struct Gui<'a> {
+/// The VM the menu acts on, until it can prompt for one.
+const DEMO_VM: &str = "demo";
+const DEMO_SNAPSHOT: &str = "snap1";
+/// Characters of the status line, so a short status covers a long one.
+const STATUS_WIDTH: usize = 78;
+
+pub struct Gui<'a, S: StorageBackend> {
     fb: &'a mut FramebufferWriter,
+    manager: VmManager<VmxBackend, S, S>,
+    hub: AgentHub,
+    dnd: DndBroker<S>,
+    /// Storage for a VM's snapshots, made with its first one.
+    snapshot_storage: fn(&str) -> Result<S, SnapshotError>,
+    /// The bzImage VMs boot.
+    kernel: Option<Vec<u8>>,
+    /// Drives the LAPIC timers of the VMs.
+    clock: fn() -> u64,
+    /// What became of the last menu choice.
+    status: String,
 }
 
-impl<'a> Gui<'a> {
-    pub fn new(fb: &'a mut FramebufferWriter) -> Self {
-        Gui { fb }
+impl<'a, S: StorageBackend> Gui<'a, S> {
+    /// A GUI sharing the volumes in `volumes` with its VMs and staging
+    /// files dragged between them in `staging`. Until `with_kernel` and
+    /// `with_snapshot_storage` say otherwise, VMs cannot boot and have
+    /// nowhere to keep snapshots.
+    pub fn new(fb: &'a mut FramebufferWriter, volumes: SharedVolumeStore<S>, staging: StagingArea<S>) -> Self {
+        Gui {
+            fb,
+            manager: VmManager::new(volumes),
+            hub: AgentHub::new(),
+            dnd: DndBroker::new(staging),
+            snapshot_storage: |_| Err(SnapshotError::Storage),
+            kernel: None,
+            clock: || 0,
+            status: String::new(),
+        }
+    }
+
+    pub fn with_kernel(mut self, kernel: Vec<u8>) -> Self {
+        self.kernel = Some(kernel);
+        self
+    }
+
+    pub fn with_snapshot_storage(mut self, snapshot_storage: fn(&str) -> Result<S, SnapshotError>) -> Self {
+        self.snapshot_storage = snapshot_storage;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Terminate `vm`'s agent channel here, so it can share files by drag
+    /// and drop.
+    pub fn attach_agent(&mut self, vm: &str, channel: SharedChannel) {
+        self.hub.attach(vm, channel);
+    }
+
+    /// Give the native VMs a turn, serve their agents and redraw.
+    pub fn render(&mut self) {
+        for stopped in self.manager.run_native_vms() {
+            self.status = format!("VM '{}' stopped: vCPU {}: {:?}", stopped.vm, stopped.vcpu, stopped.cause);
+        }
+        self.hub.poll(&mut [&mut self.dnd]);
+        self.draw_menu();
+        let transfers = self.dnd.progress();
+        self.draw_transfers(&transfers);
     }
 
     pub fn draw_menu(&mut self) {
@@ -24,22 +105,109 @@ impl<'a> Gui<'a> {
         self.fb.draw_string(10, 170, "8. List Snapshots", fg, bg);
         self.fb.draw_string(10, 190, "9. Help", fg, bg);
         self.fb.draw_string(10, 210, "0. Exit", fg, bg);
+        let status = format!("{:<width$.width$}", self.status, width = STATUS_WIDTH);
+        self.fb.draw_string(10, 230, &status, fg, bg);
+    }
+
+    /// Show each drag-and-drop transfer between VMs on a line under the menu.
+    pub fn draw_transfers(&mut self, transfers: &[TransferProgress]) {
+        let fg = 0xFFFFFFFF; // white
+        let bg = 0x00000000; // black
+        self.fb.draw_string(10, 260, "File transfers", fg, bg);
+        for (i, transfer) in transfers.iter().enumerate() {
+            let percent = |bytes: u64| (bytes * 100).checked_div(transfer.total).unwrap_or(100);
+            let status = match transfer.state {
+                TransferState::Offered => String::from("dragging"),
+                TransferState::Dropped => String::from("waiting to be accepted"),
+                TransferState::Copying => {
+                    format!("staged {}%, delivered {}%", percent(transfer.staged), percent(transfer.delivered))
+                }
+                TransferState::Complete => String::from("done"),
+                TransferState::Failed(code) => format!("failed ({:?})", code),
+            };
+            let target = transfer.target.as_deref().unwrap_or("?");
+            let line = format!(
+                "{} -> {}: {} files, {} bytes, {}",
+                transfer.source, target, transfer.files, transfer.total, status
+            );
+            self.fb.draw_string(10, 280 + 20 * i, &line, fg, bg);
+        }
     }
 
     pub fn handle_input(&mut self, input: u8) {
-        // For now, just call the functions directly as a stub
-        match input {
-            b'1' => list_vms(),
-            b'2' => create_vm("demo", 128, 1), // TODO: prompt for real input
-            b'3' => boot_vm("demo"),
-            b'4' => stop_vm("demo"),
-            b'5' => delete_vm("demo"),
-            b'6' => snapshot_vm("demo", "snap1"),
-            b'7' => restore_vm("demo", "snap1"),
-            b'8' => list_snapshots("demo"),
-            b'9' => print_help(),
-            b'0' => {/* exit GUI loop */},
-            _ => {},
+        let result = match input {
+            b'1' => Ok(self.list_vms()),
+            b'2' => self.create_vm(),
+            b'3' => self.boot_vm(),
+            b'4' => self.manager.stop_native(DEMO_VM).map(|()| format!("Stopped VM '{}'.", DEMO_VM)),
+            b'5' => self.delete_vm(),
+            b'6' => {
+                let storage = self.snapshot_storage;
+                self.manager
+                    .take_snapshot(DEMO_VM, DEMO_SNAPSHOT, || storage(DEMO_VM))
+                    .map(|_| format!("Snapshot '{}' created for VM '{}'.", DEMO_SNAPSHOT, DEMO_VM))
+            }
+            b'7' => self
+                .manager
+                .restore_snapshot(DEMO_VM, DEMO_SNAPSHOT)
+                .map(|()| format!("Restored VM '{}' from snapshot '{}'.", DEMO_VM, DEMO_SNAPSHOT)),
+            b'8' => self.list_snapshots(),
+            b'9' => Ok(String::from("Choose an action by its number; it applies to VM 'demo'.")),
+            b'0' => return, // exit GUI loop
+            _ => return,
+        };
+        self.status = match result {
+            Ok(status) => status,
+            Err(e) => format!("VM '{}': {:?}", DEMO_VM, e),
+        };
+    }
+
+    fn list_vms(&self) -> String {
+        let vms: Vec<String> = self
+            .manager
+            .vms()
+            .iter()
+            .map(|vm| format!("{} ({}MB, {} CPUs)", vm.name(), vm.ram_mb(), vm.cpus()))
+            .collect();
+        if vms.is_empty() {
+            String::from("No VMs found.")
+        } else {
+            format!("VMs: {}", vms.join(", "))
+        }
+    }
+
+    /// Create the demo VM, which may drag files to and from other VMs.
+    fn create_vm(&mut self) -> Result<String, ManagerError> {
+        self.manager.create_vm(DEMO_VM, 128, 1, "", None)?;
+        self.dnd.set_policy(DEMO_VM, DndPolicy::shared());
+        Ok(format!("Created VM '{}'.", DEMO_VM))
+    }
+
+    fn boot_vm(&mut self) -> Result<String, ManagerError> {
+        let Some(kernel) = self.kernel.as_deref() else {
+            return Ok(String::from("There is no kernel image to boot VMs with."));
+        };
+        self.manager.boot_native(DEMO_VM, kernel, |vm| vmx_backend(vm.ram_mb(), vm.cpus()), Box::new(self.clock))?;
+        Ok(format!("VM '{}' started natively.", DEMO_VM))
+    }
+
+    /// Delete the demo VM, calling off the files it was dragging or being
+    /// dropped.
+    fn delete_vm(&mut self) -> Result<String, ManagerError> {
+        self.manager.delete_vm(DEMO_VM)?;
+        self.hub.detach(DEMO_VM, &mut [&mut self.dnd]);
+        let mut outbox = Outbox::default();
+        self.dnd.remove_vm(DEMO_VM, &mut outbox);
+        self.hub.deliver(outbox);
+        Ok(format!("Deleted VM '{}'.", DEMO_VM))
+    }
+
+    fn list_snapshots(&self) -> Result<String, ManagerError> {
+        let snapshots = self.manager.vm(DEMO_VM)?.snapshots()?;
+        if snapshots.is_empty() {
+            return Ok(format!("No snapshots for VM '{}'.", DEMO_VM));
         }
+        let names: Vec<&str> = snapshots.iter().map(|s| s.name.as_str()).collect();
+        Ok(format!("Snapshots for VM '{}': {}", DEMO_VM, names.join(", ")))
     }
-} 
\ No newline at end of file
+}
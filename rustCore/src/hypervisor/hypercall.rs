@@ -0,0 +1,263 @@
+// Hypercalls: guests calling into Hypercore with VMCALL. The ABI (registers,
+// function numbers, error codes and the CPUID leaf advertising them) is in
+// the hypercore-guest crate and its HYPERCALL.md; this is the host end.
+//
+// A VM's `HypercallTable` maps function numbers to handlers. `Vm::run_vcpu`
+// passes it every VMCALL exit; the table checks the caller is in ring 0,
+// masks registers to the guest's operand size, answers VERSION itself and
+// hands anything else to the registered handler.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use hypercore_guest::hypercall::{introduced_in, HypercallError, HC_VERSION, HYPERCALL_VERSION};
+
+use super::{HvError, HypervisorBackend, SpecialRegisters, VcpuId, VcpuRegisters, EFER_LMA};
+use crate::vmx::CpuidEntry;
+
+/// Guest RAM as a hypercall handler sees it. Unlike the memory virtio
+/// devices share between threads, this is borrowed from the backend for the
+/// length of one call.
+pub trait HypercallMemory {
+    fn read(&self, gpa: u64, buf: &mut [u8]) -> Result<(), HvError>;
+    fn write(&mut self, gpa: u64, data: &[u8]) -> Result<(), HvError>;
+}
+
+impl<B: HypervisorBackend> HypercallMemory for B {
+    fn read(&self, gpa: u64, buf: &mut [u8]) -> Result<(), HvError> {
+        self.read_guest(gpa, buf)
+    }
+
+    fn write(&mut self, gpa: u64, data: &[u8]) -> Result<(), HvError> {
+        self.write_guest(gpa, data)
+    }
+}
+
+/// One call as a handler sees it.
+pub struct HypercallContext<'a> {
+    pub vcpu: VcpuId,
+    pub function: u32,
+    /// RBX, RCX, RDX and RSI.
+    pub args: [u64; 4],
+    /// RBX, RCX and RDX to return, zero unless the handler sets them. They
+    /// reach the guest even if the call fails.
+    pub results: [u64; 3],
+    pub memory: &'a mut dyn HypercallMemory,
+}
+
+impl HypercallContext<'_> {
+    /// `len` bytes of guest memory at `gpa`.
+    pub fn read(&self, gpa: u64, len: usize) -> Result<Vec<u8>, HypercallError> {
+        let mut buf = vec![0; len];
+        self.memory.read(gpa, &mut buf).map_err(|_| HypercallError::Invalid)?;
+        Ok(buf)
+    }
+
+    pub fn write(&mut self, gpa: u64, data: &[u8]) -> Result<(), HypercallError> {
+        self.memory.write(gpa, data).map_err(|_| HypercallError::Invalid)
+    }
+}
+
+pub trait Hypercall: Send {
+    fn call(&mut self, ctx: &mut HypercallContext) -> Result<(), HypercallError>;
+}
+
+/// The hypercalls one VM may make.
+pub struct HypercallTable {
+    version: u32,
+    handlers: BTreeMap<u32, Box<dyn Hypercall>>,
+}
+
+impl HypercallTable {
+    /// A table offering the newest ABI version, with only VERSION in it.
+    pub fn new() -> Self {
+        HypercallTable { version: HYPERCALL_VERSION, handlers: BTreeMap::new() }
+    }
+
+    /// Offer ABI `version` at most, so guests see what an older Hypercore
+    /// gave them; functions from later versions are not supported.
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version.clamp(1, HYPERCALL_VERSION);
+        self
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Handle `function` with `handler`, returning the handler it replaces.
+    pub fn register(&mut self, function: u32, handler: Box<dyn Hypercall>) -> Option<Box<dyn Hypercall>> {
+        self.handlers.insert(function, handler)
+    }
+
+    pub fn unregister(&mut self, function: u32) -> Option<Box<dyn Hypercall>> {
+        self.handlers.remove(&function)
+    }
+
+    fn available(&self, function: u32, version: u32) -> bool {
+        let since = introduced_in(function).unwrap_or(u32::MAX);
+        since <= version && (function == HC_VERSION || self.handlers.contains_key(&function))
+    }
+
+    /// Functions 0-63 available in ABI `version`, as a bitmap.
+    pub fn functions(&self, version: u32) -> u64 {
+        (0..64).filter(|&function| self.available(function, version)).fold(0, |bits, function| bits | 1 << function)
+    }
+
+    /// CPUID leaf 0x4000_0001 advertising the table, for the VM's CPU model.
+    pub fn cpuid(&self) -> CpuidEntry {
+        let functions = self.functions(self.version);
+        CpuidEntry::new(self.version, functions as u32, (functions >> 32) as u32, 0)
+    }
+
+    /// Make the hypercall in `regs`, leaving the status and results there.
+    pub fn dispatch(
+        &mut self,
+        vcpu: VcpuId,
+        regs: &mut VcpuRegisters,
+        sregs: &SpecialRegisters,
+        memory: &mut dyn HypercallMemory,
+    ) {
+        let long_mode = sregs.efer & EFER_LMA != 0 && sregs.cs.long_mode();
+        let mask = if long_mode { u64::MAX } else { u32::MAX as u64 };
+        // Function numbers are 32 bits, so a long-mode caller setting the
+        // upper half of RAX asked for one that does not exist.
+        let function = u32::try_from(regs.rax & mask).unwrap_or(u32::MAX);
+        let args = [regs.rbx, regs.rcx, regs.rdx, regs.rsi].map(|arg| arg & mask);
+        let mut ctx = HypercallContext { vcpu, function, args, results: [0; 3], memory };
+        // SS.DPL is the CPL.
+        let status = if (sregs.ss.access_rights >> 5) & 3 != 0 {
+            Err(HypercallError::Denied)
+        } else if !self.available(function, self.version) {
+            Err(HypercallError::NotSupported)
+        } else if function == HC_VERSION {
+            self.negotiate(&mut ctx)
+        } else {
+            match self.handlers.get_mut(&function) {
+                Some(handler) => handler.call(&mut ctx),
+                None => Err(HypercallError::NotSupported),
+            }
+        };
+        regs.rax = status.map_or_else(|err| err.code() as u64, |()| 0) & mask;
+        regs.rbx = ctx.results[0] & mask;
+        regs.rcx = ctx.results[1] & mask;
+        regs.rdx = ctx.results[2] & mask;
+    }
+
+    fn negotiate(&self, ctx: &mut HypercallContext) -> Result<(), HypercallError> {
+        let version = match ctx.args[0] {
+            0 => return Err(HypercallError::Invalid),
+            guest => core::cmp::min(guest, self.version as u64) as u32,
+        };
+        ctx.results = [version as u64, self.functions(version), 0];
+        Ok(())
+    }
+}
+
+impl Default for HypercallTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::{ExitResponse, MockBackend, Segment, VmExit};
+    use hypercore_guest::hypercall::HC_TIME;
+
+    /// Adds RBX and RCX, or fails with RDX as the error code if it is set.
+    struct Add;
+
+    impl Hypercall for Add {
+        fn call(&mut self, ctx: &mut HypercallContext) -> Result<(), HypercallError> {
+            ctx.results[0] = ctx.args[0].wrapping_add(ctx.args[1]);
+            match ctx.args[2] {
+                0 => Ok(()),
+                code => Err(HypercallError::from_code(-(code as i64))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut table = HypercallTable::new();
+        assert!(table.register(HC_TIME, Box::new(Add)).is_none());
+        // A function no ABI version has is never offered.
+        table.register(40, Box::new(Add));
+        assert_eq!(table.functions(1), 0b11);
+        assert_eq!(table.cpuid(), CpuidEntry::new(1, 0b11, 0, 0));
+
+        let mut memory = MockBackend::new();
+        let mut sregs = SpecialRegisters::long_mode(0);
+        let mut call = |table: &mut HypercallTable, sregs: &SpecialRegisters, rax: u64, args: [u64; 4]| {
+            let mut regs =
+                VcpuRegisters { rax, rbx: args[0], rcx: args[1], rdx: args[2], rsi: args[3], ..Default::default() };
+            table.dispatch(0, &mut regs, sregs, &mut memory);
+            (regs.rax as i64, regs.rbx, regs.rcx, regs.rdx, regs.rsi)
+        };
+
+        assert_eq!(call(&mut table, &sregs, 0, [7, 0, 0, 0]), (0, 1, 0b11, 0, 0));
+        assert_eq!(call(&mut table, &sregs, 0, [0; 4]), (-3, 0, 0, 0, 0));
+        assert_eq!(call(&mut table, &sregs, 1, [1 << 40, 2, 0, 9]), (0, (1 << 40) + 2, 0, 0, 9));
+        assert_eq!(call(&mut table, &sregs, 1, [1, 2, 4, 0]), (-4, 3, 0, 0, 0));
+        assert_eq!(call(&mut table, &sregs, 40, [1, 2, 0, 0]).0, -1);
+        assert_eq!(call(&mut table, &sregs, 5, [0; 4]).0, -1);
+        assert_eq!(call(&mut table, &sregs, 1 << 32 | 1, [1, 2, 0, 0]), (-1, 0, 0, 0, 0));
+
+        // Outside long mode the upper halves are ignored and cleared.
+        sregs.efer = 0;
+        sregs.cs = Segment::flat_code(8);
+        assert_eq!(call(&mut table, &sregs, 1 << 32 | 1, [1 << 32 | 1, 2, 0, 0]), (0, 3, 0, 0, 0));
+        assert_eq!(call(&mut table, &sregs, 1, [1, 2, 2, 0]).0, 0xFFFF_FFFE);
+
+        sregs.ss.access_rights |= 3 << 5;
+        assert_eq!(call(&mut table, &sregs, 1, [1, 2, 0, 0]).0, 0xFFFF_FFFE);
+        assert!(table.unregister(HC_TIME).is_some());
+        assert_eq!(table.functions(1), 0b1);
+    }
+
+    #[test]
+    fn test_vmcall_exit() {
+        use crate::hypervisor::{Disposition, Vm, VmExitHandler};
+
+        struct Stop(Vec<VmExit>);
+        impl VmExitHandler for Stop {
+            fn handle(&mut self, _vcpu: VcpuId, exit: &VmExit) -> Disposition {
+                self.0.push(*exit);
+                match exit {
+                    VmExit::Hlt => Disposition::Stop,
+                    _ => Disposition::Resume(ExitResponse::None),
+                }
+            }
+        }
+
+        let code = [
+            0xB8, 0x01, 0x00, // mov ax, 1
+            0xBB, 0x28, 0x00, // mov bx, 40
+            0xB9, 0x02, 0x00, // mov cx, 2
+            0x0F, 0x01, 0xC1, // vmcall
+            0x89, 0xDF, // mov di, bx
+            0xF4, // hlt
+        ];
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        backend.create_vcpu(0).unwrap();
+        backend.write_guest(0x1000, &code).unwrap();
+        backend.set_special_registers(0, &SpecialRegisters::real_mode(0x100)).unwrap();
+
+        let mut vm = Vm::new(backend);
+        let mut table = HypercallTable::new();
+        table.register(HC_TIME, Box::new(Add));
+        vm.set_hypercalls(table);
+        let mut handler = Stop(Vec::new());
+        assert_eq!(vm.run_vcpu(0, &mut handler).unwrap(), VmExit::Hlt);
+        // The VMCALL never reached the handler.
+        assert_eq!(handler.0, [VmExit::Hlt]);
+        let regs = vm.backend().get_registers(0).unwrap();
+        assert_eq!((regs.rax, regs.rdi), (0, 42));
+        assert_eq!(vm.hypercalls().unwrap().cpuid(), CpuidEntry::new(1, 0b11, 0, 0));
+    }
+}
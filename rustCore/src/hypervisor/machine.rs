@@ -0,0 +1,155 @@
+// A native VM together with what runs it: the `Vm` with its interrupt
+// controllers, the port I/O devices its exits go to with a 16550 at COM1
+// among them, and one scheduler thread per vCPU.
+//
+// The threads are created with the machine and end with it. In between,
+// `run_vcpu` mirrors each vCPU's run state into its thread, so a vCPU
+// halted in the guest or held by a pause is blocked rather than scheduled.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{HvError, HypervisorBackend, VcpuId, VcpuRunState, Vm, VmExit};
+use crate::process::{ProcessManager, PROCESS_MANAGER};
+use crate::vdev::serial::register_com1;
+use crate::vdev::{IoApicPin, IrqChip, PortIoBus, SharedConsole};
+
+/// The IOAPIC pin COM1 interrupts on, as on a PC.
+const COM1_IRQ: usize = 4;
+
+pub struct Machine<B: HypervisorBackend> {
+    id: u64,
+    vm: Vm<B>,
+    ports: PortIoBus,
+    processes: &'static Mutex<ProcessManager>,
+    threads: Vec<u64>,
+}
+
+impl<B: HypervisorBackend> Machine<B> {
+    /// A machine with `cpus` vCPUs on `backend`, which has its RAM mapped
+    /// already. vCPU 0 starts; the others wait for a startup IPI. `id`
+    /// identifies the machine to the scheduler, `clock` drives the LAPIC
+    /// timers and COM1 writes to `console`.
+    pub fn new(
+        id: u64,
+        backend: B,
+        cpus: usize,
+        clock: Box<dyn Fn() -> u64 + Send>,
+        console: SharedConsole,
+    ) -> Result<Self, HvError> {
+        Self::with_processes(id, backend, cpus, clock, console, &PROCESS_MANAGER)
+    }
+
+    /// Like `new`, with the vCPU threads created by `processes` rather than
+    /// the global process manager.
+    pub fn with_processes(
+        id: u64,
+        backend: B,
+        cpus: usize,
+        clock: Box<dyn Fn() -> u64 + Send>,
+        console: SharedConsole,
+        processes: &'static Mutex<ProcessManager>,
+    ) -> Result<Self, HvError> {
+        let mut vm = Vm::new(backend);
+        vm.add_vcpus(cpus)?;
+        let chip = IrqChip::shared(cpus, clock);
+        vm.set_irqchip(chip.clone());
+        let mut ports = PortIoBus::new();
+        register_com1(&mut ports, console, Box::new(IoApicPin::new(chip, COM1_IRQ)))
+            .map_err(|_| HvError::Backend("COM1 ports are taken"))?;
+        let threads = processes.lock().create_vcpu_threads(id, cpus);
+        Ok(Machine { id, vm, ports, processes, threads })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn vm(&self) -> &Vm<B> {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut Vm<B> {
+        &mut self.vm
+    }
+
+    pub fn ports_mut(&mut self) -> &mut PortIoBus {
+        &mut self.ports
+    }
+
+    /// PIDs of the vCPU threads, in vCPU order.
+    pub fn threads(&self) -> &[u64] {
+        &self.threads
+    }
+
+    /// Run `vcpu` until an exit no device handles, with its thread marked
+    /// running until then.
+    pub fn run_vcpu(&mut self, vcpu: VcpuId) -> Result<VmExit, HvError> {
+        self.processes.lock().update_vcpu_state(self.id, vcpu, VcpuRunState::Running);
+        let result = self.vm.run_vcpu(vcpu, &mut self.ports);
+        let state = self.vm.coordinator().state(vcpu);
+        self.processes.lock().update_vcpu_state(self.id, vcpu, state);
+        result
+    }
+
+    /// Bring every thread up to date with its vCPU, after wakeups and
+    /// pauses that happened outside `run_vcpu`.
+    pub fn sync_threads(&self) {
+        let mut manager = self.processes.lock();
+        for &vcpu in self.vm.vcpus() {
+            manager.update_vcpu_state(self.id, vcpu, self.vm.coordinator().state(vcpu));
+        }
+    }
+}
+
+impl<B: HypervisorBackend> Drop for Machine<B> {
+    fn drop(&mut self) {
+        self.processes.lock().terminate_vm(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::{MockBackend, SpecialRegisters};
+    use crate::process::{MultiFeedbackQueue, ProcessState};
+    use crate::vdev::ConsoleBuffer;
+
+    #[test]
+    fn test_vcpu_threads() {
+        const ID: u64 = 7;
+        let scheduler: &'static Mutex<MultiFeedbackQueue> = Box::leak(Box::new(Mutex::new(MultiFeedbackQueue::new())));
+        let processes = Box::leak(Box::new(Mutex::new(ProcessManager::with_scheduler(scheduler))));
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        let code = [
+            0xBA, 0xF8, 0x03, // mov dx, 0x3F8
+            0xB0, b'A', // mov al, 'A'
+            0xEE, // out dx, al
+            0xF4, // hlt
+        ];
+        backend.write_guest(0x1000, &code).unwrap();
+        let console = ConsoleBuffer::shared(64);
+        let mut machine = Machine::with_processes(ID, backend, 2, Box::new(|| 0), console.clone(), processes).unwrap();
+        machine.vm_mut().backend_mut().set_special_registers(0, &SpecialRegisters::real_mode(0x100)).unwrap();
+        let threads = machine.threads().to_vec();
+        assert_eq!(scheduler.lock().vcpu_threads(ID), [(0, threads[0]), (1, threads[1])]);
+        let state = |pid| scheduler.lock().get_process_state(pid);
+        assert_eq!((state(threads[0]), state(threads[1])), (Some(ProcessState::Ready), Some(ProcessState::Ready)));
+
+        // A halted vCPU is blocked until it is woken, as is one waiting for
+        // its startup IPI.
+        assert_eq!(machine.run_vcpu(0), Ok(VmExit::Hlt));
+        assert_eq!(console.lock().text(), "A");
+        assert_eq!((state(threads[0]), state(threads[1])), (Some(ProcessState::Blocked), Some(ProcessState::Ready)));
+        assert_eq!(machine.run_vcpu(1), Ok(VmExit::Hlt));
+        assert_eq!(state(threads[1]), Some(ProcessState::Blocked));
+        machine.vm().coordinator().wake(0);
+        machine.sync_threads();
+        assert_eq!(state(threads[0]), Some(ProcessState::Ready));
+
+        drop(machine);
+        assert_eq!(scheduler.lock().vcpu_threads(ID), []);
+    }
+}
@@ -0,0 +1,550 @@
+// Live migration: moving a running VM to another host over a byte stream.
+//
+// The sender copies all of guest RAM while the VM keeps running, then
+// re-sends the pages the dirty log reports, round after round, until the
+// `ConvergencePolicy` judges what is left small enough to send with the VM
+// stopped. That final stop-and-copy carries the last dirty pages, vCPU
+// state and device state, and the receiver acknowledges it so the source
+// knows it may discard its copy.
+//
+// Stream format: messages of a one-byte type, a u32 body length and the
+// body, all little-endian. The stream opens with `HELLO`, describing the
+// VM, and closes with `DONE`, answered by `ACK`.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{HvError, HypervisorBackend, StateError, VcpuId, VcpuState, Vm};
+use crate::codec::{self, Writer};
+use crate::vmx::ept::RegionKind;
+
+const MAGIC: [u8; 4] = *b"HCMG";
+const VERSION: u16 = 1;
+const PAGE_SIZE: usize = 0x1000;
+/// Largest message body accepted, well above any vCPU or device state.
+const MAX_MESSAGE: usize = 16 << 20;
+
+const MSG_HELLO: u8 = 1;
+const MSG_PAGE: u8 = 2;
+const MSG_ZERO_PAGE: u8 = 3;
+const MSG_ROUND_END: u8 = 4;
+const MSG_VCPU: u8 = 5;
+const MSG_DEVICE: u8 = 6;
+const MSG_DONE: u8 = 7;
+const MSG_ACK: u8 = 8;
+
+/// The connection failed; the transport has no more to say about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransportError;
+
+/// A reliable, ordered byte stream to the other host, such as a TCP
+/// connection.
+pub trait MigrationTransport {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError>;
+    /// Fill `buf` completely.
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(), TransportError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationError {
+    Transport,
+    /// The peer sent something this end does not understand.
+    Protocol(&'static str),
+    /// The destination VM's RAM regions or vCPU count differ from the source's.
+    LayoutMismatch,
+    Guest(HvError),
+    State(StateError),
+}
+
+impl From<TransportError> for MigrationError {
+    fn from(_: TransportError) -> Self {
+        MigrationError::Transport
+    }
+}
+
+impl From<HvError> for MigrationError {
+    fn from(err: HvError) -> Self {
+        MigrationError::Guest(err)
+    }
+}
+
+impl From<StateError> for MigrationError {
+    fn from(err: StateError) -> Self {
+        MigrationError::State(err)
+    }
+}
+
+/// One pre-copy round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoundStats {
+    /// Round 0 is the full copy.
+    pub round: u32,
+    pub pages: usize,
+    /// Time taken to send the pages, in nanoseconds.
+    pub duration: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The remaining dirty pages fit in the allowed downtime.
+    Converged,
+    RoundLimit,
+    /// The guest dirties pages as fast as they are sent.
+    NotConverging,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Continue,
+    StopAndCopy(StopReason),
+}
+
+/// When to stop pre-copying, based on how fast the guest dirties pages
+/// compared to how fast they are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConvergencePolicy {
+    /// Longest acceptable stop-and-copy, in nanoseconds.
+    pub max_downtime: u64,
+    /// Pre-copy rounds after which the VM is stopped however much is left.
+    pub max_rounds: u32,
+    /// Consecutive rounds the dirty rate may match or beat the transfer
+    /// rate before pre-copy is given up.
+    pub max_stalled_rounds: u32,
+}
+
+impl Default for ConvergencePolicy {
+    fn default() -> Self {
+        ConvergencePolicy { max_downtime: 300_000_000, max_rounds: 30, max_stalled_rounds: 3 }
+    }
+}
+
+impl ConvergencePolicy {
+    /// Decide what to do with `dirty` pages written while `last` was being
+    /// sent, `stalled` being how many rounds before it already failed to
+    /// outpace the guest.
+    pub fn decide(&self, last: &RoundStats, dirty: usize, stalled: u32) -> Decision {
+        // At the last round's transfer rate, sending what is dirty now takes:
+        let downtime = dirty as u128 * last.duration as u128 / core::cmp::max(last.pages, 1) as u128;
+        if downtime <= self.max_downtime as u128 {
+            Decision::StopAndCopy(StopReason::Converged)
+        } else if last.round + 1 >= self.max_rounds {
+            Decision::StopAndCopy(StopReason::RoundLimit)
+        } else if dirty >= last.pages && stalled + 1 >= self.max_stalled_rounds {
+            Decision::StopAndCopy(StopReason::NotConverging)
+        } else {
+            Decision::Continue
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub rounds: Vec<RoundStats>,
+    /// Pages sent by the stop-and-copy.
+    pub final_pages: usize,
+    /// Time from the start of the stop-and-copy to the receiver's ack, in
+    /// nanoseconds.
+    pub downtime: u64,
+}
+
+/// The source side of a migration.
+///
+/// Call [`begin`](Self::begin), then [`iterate`](Self::iterate) while the
+/// VM keeps running until it returns [`Decision::StopAndCopy`], then stop
+/// the vCPUs and call [`complete`](Self::complete).
+pub struct MigrationSender<T: MigrationTransport> {
+    transport: T,
+    policy: ConvergencePolicy,
+    /// Monotonic time in nanoseconds.
+    clock: Box<dyn Fn() -> u64 + Send>,
+    rounds: Vec<RoundStats>,
+    /// Rounds in a row whose dirty pages outnumbered the pages sent.
+    stalled: u32,
+    /// Dirty pages not yet sent.
+    pending: BTreeSet<u64>,
+}
+
+impl<T: MigrationTransport> MigrationSender<T> {
+    pub fn new(transport: T, policy: ConvergencePolicy, clock: Box<dyn Fn() -> u64 + Send>) -> Self {
+        MigrationSender { transport, policy, clock, rounds: Vec::new(), stalled: 0, pending: BTreeSet::new() }
+    }
+
+    pub fn rounds(&self) -> &[RoundStats] {
+        &self.rounds
+    }
+
+    /// Describe the VM to the receiver and send all of guest RAM, logging
+    /// the pages written meanwhile.
+    pub fn begin<B: HypervisorBackend>(&mut self, vm: &mut Vm<B>) -> Result<(), MigrationError> {
+        let regions = ram_regions(vm);
+        let mut hello = Writer::new();
+        hello.bytes(&MAGIC);
+        hello.u16(VERSION);
+        hello.u32(vm.vcpus().len() as u32);
+        hello.u32(regions.len() as u32);
+        for &(start, size) in &regions {
+            hello.u64(start);
+            hello.u64(size);
+        }
+        send_message(&mut self.transport, MSG_HELLO, hello.as_bytes())?;
+
+        vm.enable_dirty_log()?;
+        self.take_dirty(vm)?;
+        self.pending.clear();
+        let pages = regions.iter().flat_map(|&(start, size)| (start..start + size).step_by(PAGE_SIZE));
+        self.send_round(vm, pages.collect())
+    }
+
+    /// Pick up the pages dirtied during the last round and, unless the
+    /// policy says to stop and copy instead, send them as the next round.
+    pub fn iterate<B: HypervisorBackend>(&mut self, vm: &mut Vm<B>) -> Result<Decision, MigrationError> {
+        let last = *self.rounds.last().ok_or(MigrationError::Protocol("migration not started"))?;
+        let dirty = self.take_dirty(vm)?;
+        let decision = self.policy.decide(&last, dirty, self.stalled);
+        if decision != Decision::Continue {
+            return Ok(decision);
+        }
+        self.stalled = if dirty >= last.pages { self.stalled + 1 } else { 0 };
+        let pages = core::mem::take(&mut self.pending);
+        self.send_round(vm, pages.into_iter().collect())?;
+        Ok(Decision::Continue)
+    }
+
+    /// Stop-and-copy, with every vCPU stopped: send the remaining dirty
+    /// pages, then the state of each vCPU and of `devices` (name and saved
+    /// state), and wait for the receiver to take over.
+    pub fn complete<B: HypervisorBackend>(
+        mut self,
+        vm: &mut Vm<B>,
+        devices: &[(&str, &[u8])],
+    ) -> Result<MigrationReport, MigrationError> {
+        let start = (self.clock)();
+        self.take_dirty(vm)?;
+        let pages: Vec<u64> = core::mem::take(&mut self.pending).into_iter().collect();
+        let final_pages = pages.len();
+        self.send_pages(vm, &pages)?;
+        for vcpu in vm.vcpus().to_vec() {
+            let mut body = Writer::new();
+            body.u32(vcpu);
+            body.bytes(&vm.save_vcpu(vcpu)?.encode());
+            send_message(&mut self.transport, MSG_VCPU, body.as_bytes())?;
+        }
+        for &(name, state) in devices {
+            let mut body = Writer::new();
+            body.u16(name.len() as u16);
+            body.bytes(name.as_bytes());
+            body.bytes(state);
+            send_message(&mut self.transport, MSG_DEVICE, body.as_bytes())?;
+        }
+        send_message(&mut self.transport, MSG_DONE, &[])?;
+        let (kind, _) = recv_message(&mut self.transport)?;
+        if kind != MSG_ACK {
+            return Err(MigrationError::Protocol("expected ACK"));
+        }
+        Ok(MigrationReport { rounds: self.rounds, final_pages, downtime: (self.clock)() - start })
+    }
+
+    /// Add the dirty log to the pending pages, returning how many it held.
+    /// Running vCPUs are kicked to flush their TLBs, and the pages are only
+    /// read once they have, so no write slips between the log and the copy.
+    fn take_dirty<B: HypervisorBackend>(&mut self, vm: &mut Vm<B>) -> Result<usize, MigrationError> {
+        let dirty = vm.take_dirty_pages()?;
+        while vm.vcpus().iter().any(|&vcpu| vm.coordinator().flush_pending(vcpu)) {
+            core::hint::spin_loop();
+        }
+        let count = dirty.len();
+        self.pending.extend(dirty);
+        Ok(count)
+    }
+
+    fn send_round<B: HypervisorBackend>(&mut self, vm: &Vm<B>, pages: Vec<u64>) -> Result<(), MigrationError> {
+        let start = (self.clock)();
+        self.send_pages(vm, &pages)?;
+        let round = self.rounds.len() as u32;
+        send_message(&mut self.transport, MSG_ROUND_END, &round.to_le_bytes())?;
+        self.rounds.push(RoundStats { round, pages: pages.len(), duration: (self.clock)() - start });
+        Ok(())
+    }
+
+    fn send_pages<B: HypervisorBackend>(&mut self, vm: &Vm<B>, pages: &[u64]) -> Result<(), MigrationError> {
+        let mut body = vec![0u8; 8 + PAGE_SIZE];
+        for &gpa in pages {
+            body[..8].copy_from_slice(&gpa.to_le_bytes());
+            vm.backend().read_guest(gpa, &mut body[8..])?;
+            if body[8..].iter().all(|&byte| byte == 0) {
+                send_message(&mut self.transport, MSG_ZERO_PAGE, &body[..8])?;
+            } else {
+                send_message(&mut self.transport, MSG_PAGE, &body)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the receiver got besides RAM and vCPU state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncomingMigration {
+    /// Device states by name, for the caller to load into its devices.
+    pub devices: Vec<(String, Vec<u8>)>,
+    /// Pre-copy rounds, including the full copy.
+    pub rounds: u32,
+    /// Page messages received, counting pages sent more than once.
+    pub pages: usize,
+}
+
+/// The destination side: fill `vm`, which must have the source's RAM
+/// regions and vCPU count and must not be running, from `transport`. On
+/// success the source has been told it may discard its copy and `vm` is
+/// ready to run.
+pub fn receive<B: HypervisorBackend, T: MigrationTransport>(
+    transport: &mut T,
+    vm: &mut Vm<B>,
+) -> Result<IncomingMigration, MigrationError> {
+    let (kind, hello) = recv_message(transport)?;
+    if kind != MSG_HELLO {
+        return Err(MigrationError::Protocol("expected HELLO"));
+    }
+    let mut r = reader(&hello);
+    if r.take(4)? != MAGIC {
+        return Err(MigrationError::Protocol("not a migration stream"));
+    }
+    if r.u16()? != VERSION {
+        return Err(MigrationError::Protocol("unsupported stream version"));
+    }
+    let vcpus = r.u32()? as usize;
+    let regions = (0..r.u32()?).map(|_| Ok((r.u64()?, r.u64()?))).collect::<Result<Vec<_>, MigrationError>>()?;
+    if vcpus != vm.vcpus().len() || regions != ram_regions(vm) {
+        return Err(MigrationError::LayoutMismatch);
+    }
+
+    let mut incoming = IncomingMigration { devices: Vec::new(), rounds: 0, pages: 0 };
+    let zero = vec![0u8; PAGE_SIZE];
+    loop {
+        let (kind, body) = recv_message(transport)?;
+        let mut r = reader(&body);
+        match kind {
+            MSG_PAGE | MSG_ZERO_PAGE => {
+                let gpa = r.u64()?;
+                let data = if kind == MSG_PAGE { r.take(PAGE_SIZE)? } else { &zero[..] };
+                if !gpa.is_multiple_of(PAGE_SIZE as u64) {
+                    return Err(MigrationError::Protocol("unaligned page"));
+                }
+                let end = gpa.checked_add(PAGE_SIZE as u64);
+                if !end.is_some_and(|end| regions.iter().any(|&(start, size)| gpa >= start && end <= start + size)) {
+                    return Err(MigrationError::Protocol("page outside guest RAM"));
+                }
+                vm.backend_mut().write_guest(gpa, data)?;
+                incoming.pages += 1;
+            }
+            MSG_ROUND_END => incoming.rounds += 1,
+            MSG_VCPU => {
+                let vcpu: VcpuId = r.u32()?;
+                if !vm.vcpus().contains(&vcpu) {
+                    return Err(MigrationError::Protocol("unknown vCPU"));
+                }
+                let state = VcpuState::decode(r.rest())?;
+                vm.restore_vcpu(vcpu, &state)?;
+            }
+            MSG_DEVICE => {
+                let len = r.u16()? as usize;
+                let name = String::from_utf8(r.take(len)?.to_vec());
+                let name = name.map_err(|_| MigrationError::Protocol("bad device name"))?;
+                incoming.devices.push((name, r.rest().to_vec()));
+            }
+            MSG_DONE => break,
+            _ => return Err(MigrationError::Protocol("unknown message")),
+        }
+    }
+    send_message(transport, MSG_ACK, &[])?;
+    Ok(incoming)
+}
+
+/// The VM's RAM regions as (guest-physical start, size).
+fn ram_regions<B: HypervisorBackend>(vm: &Vm<B>) -> Vec<(u64, u64)> {
+    let layout = vm.backend().memory_layout();
+    layout
+        .regions()
+        .iter()
+        .filter(|region| matches!(region.kind, RegionKind::Ram { .. }))
+        .map(|region| (region.guest_phys, region.size))
+        .collect()
+}
+
+fn send_message<T: MigrationTransport>(transport: &mut T, kind: u8, body: &[u8]) -> Result<(), MigrationError> {
+    let mut header = [kind, 0, 0, 0, 0];
+    header[1..].copy_from_slice(&(body.len() as u32).to_le_bytes());
+    transport.send(&header)?;
+    transport.send(body)?;
+    Ok(())
+}
+
+fn recv_message<T: MigrationTransport>(transport: &mut T) -> Result<(u8, Vec<u8>), MigrationError> {
+    let mut header = [0u8; 5];
+    transport.recv(&mut header)?;
+    let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_MESSAGE {
+        return Err(MigrationError::Protocol("message too long"));
+    }
+    let mut body = vec![0u8; len];
+    transport.recv(&mut body)?;
+    Ok((header[0], body))
+}
+
+fn reader(data: &[u8]) -> codec::Reader<'_, MigrationError> {
+    codec::Reader::new(data, MigrationError::Protocol("truncated message"))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::hypervisor::mock::MOCK_STEP_LIMIT;
+    use crate::hypervisor::{Disposition, MockBackend, SpecialRegisters, VmExit, VmExitHandler};
+    use crate::vdev::channel::tests::Loopback;
+
+    impl MigrationTransport for TcpStream {
+        fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+            self.write_all(data).map_err(|_| TransportError)
+        }
+
+        fn recv(&mut self, buf: &mut [u8]) -> Result<(), TransportError> {
+            self.read_exact(buf).map_err(|_| TransportError)
+        }
+    }
+
+    struct StopAtStepLimit;
+
+    impl VmExitHandler for StopAtStepLimit {
+        fn handle(&mut self, _vcpu: VcpuId, exit: &VmExit) -> Disposition {
+            assert!(matches!(exit, VmExit::Unhandled { reason: MOCK_STEP_LIMIT, .. }), "unexpected exit {:?}", exit);
+            Disposition::Stop
+        }
+    }
+
+    /// 64 KiB of RAM and a guest forever incrementing words at 0x3000 and
+    /// 0x4000, `steps` instructions per run.
+    fn vm(steps: u64) -> Vm<MockBackend> {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        backend.set_step_limit(steps);
+        // l: inc word [0x3000]; inc word [0x4000]; jmp l
+        backend.write_guest(0x1000, &[0xFF, 0x06, 0x00, 0x30, 0xFF, 0x06, 0x00, 0x40, 0xEB, 0xF6]).unwrap();
+        let mut vm = Vm::new(backend);
+        vm.add_vcpu().unwrap();
+        vm.backend_mut().set_special_registers(0, &SpecialRegisters::real_mode(0x100)).unwrap();
+        vm
+    }
+
+    fn ram(vm: &Vm<MockBackend>) -> Vec<u8> {
+        let mut data = vec![0u8; 0x10000];
+        vm.backend().read_guest(0, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_convergence_policy() {
+        let policy = ConvergencePolicy { max_downtime: 1_000, max_rounds: 5, max_stalled_rounds: 2 };
+        // 100 pages in 10 us: 10 pages fit in the 1 us budget.
+        let last = RoundStats { round: 1, pages: 100, duration: 10_000 };
+        assert_eq!(policy.decide(&last, 10, 0), Decision::StopAndCopy(StopReason::Converged));
+        assert_eq!(policy.decide(&last, 50, 0), Decision::Continue);
+        assert_eq!(policy.decide(&last, 150, 0), Decision::Continue);
+        assert_eq!(policy.decide(&last, 150, 1), Decision::StopAndCopy(StopReason::NotConverging));
+        let late = RoundStats { round: 4, ..last };
+        assert_eq!(policy.decide(&late, 50, 0), Decision::StopAndCopy(StopReason::RoundLimit));
+    }
+
+    #[test]
+    fn test_migration_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let destination = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut vm = vm(100);
+            let incoming = receive(&mut stream, &mut vm).unwrap();
+            vm.run_vcpu(0, &mut StopAtStepLimit).unwrap();
+            (incoming, ram(&vm), vm.save_vcpu(0).unwrap())
+        });
+
+        let mut source = vm(100);
+        let time = Arc::new(AtomicU64::new(0));
+        let clock = time.clone();
+        let policy = ConvergencePolicy { max_downtime: 0, max_rounds: 10, max_stalled_rounds: 2 };
+        let mut sender = MigrationSender::new(
+            TcpStream::connect(addr).unwrap(),
+            policy,
+            Box::new(move || clock.fetch_add(100, Ordering::Relaxed)),
+        );
+        sender.begin(&mut source).unwrap();
+        assert_eq!(sender.rounds()[0].pages, 16);
+        // The guest keeps running between rounds and rewrites its two pages
+        // every time, so with no downtime allowed pre-copy never converges.
+        let mut decision = Decision::Continue;
+        while decision == Decision::Continue {
+            source.run_vcpu(0, &mut StopAtStepLimit).unwrap();
+            decision = sender.iterate(&mut source).unwrap();
+        }
+        assert_eq!(decision, Decision::StopAndCopy(StopReason::NotConverging));
+        let pages: Vec<usize> = sender.rounds().iter().map(|round| round.pages).collect();
+        assert_eq!(pages, [16, 2, 2]);
+
+        source.run_vcpu(0, &mut StopAtStepLimit).unwrap();
+        let report = sender.complete(&mut source, &[("serial0", b"\x01\x02")]).unwrap();
+        assert_eq!(report.final_pages, 2);
+
+        // Both copies carry on identically.
+        source.run_vcpu(0, &mut StopAtStepLimit).unwrap();
+        let (incoming, dest_ram, dest_state) = destination.join().unwrap();
+        assert_eq!(incoming.devices, vec![(String::from("serial0"), vec![1, 2])]);
+        assert_eq!(incoming.rounds as usize, report.rounds.len());
+        assert_eq!(dest_ram, ram(&source));
+        assert_ne!(dest_ram[0x3000], 0);
+        assert_eq!(dest_state, source.save_vcpu(0).unwrap());
+    }
+
+    #[test]
+    fn test_layout_mismatch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let destination = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut vm = vm(100);
+            vm.add_vcpu().unwrap();
+            receive(&mut stream, &mut vm)
+        });
+        let mut source = vm(100);
+        let mut sender =
+            MigrationSender::new(TcpStream::connect(addr).unwrap(), ConvergencePolicy::default(), Box::new(|| 0));
+        // The receiver hangs up after the hello; the full copy may or may
+        // not get out before it does.
+        let _ = sender.begin(&mut source);
+        assert_eq!(destination.join().unwrap(), Err(MigrationError::LayoutMismatch));
+    }
+
+    #[test]
+    fn test_bad_page_address() {
+        for (gpa, error) in [(0x1800, "unaligned page"), (!0xFFFu64, "page outside guest RAM")] {
+            let mut vm = vm(100);
+            let mut transport = Loopback::echo();
+            let mut hello = Writer::new();
+            hello.bytes(&MAGIC);
+            hello.u16(VERSION);
+            hello.u32(1);
+            hello.u32(1);
+            hello.u64(0);
+            hello.u64(0x10000);
+            send_message(&mut transport, MSG_HELLO, hello.as_bytes()).unwrap();
+            send_message(&mut transport, MSG_ZERO_PAGE, &gpa.to_le_bytes()).unwrap();
+            assert_eq!(receive(&mut transport, &mut vm), Err(MigrationError::Protocol(error)));
+        }
+    }
+}
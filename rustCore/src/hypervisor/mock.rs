@@ -0,0 +1,1392 @@
+// Software backend: a small, deterministic x86 interpreter.
+//
+// It understands just enough of the instruction set (MOV, basic ALU ops,
+// jumps, stack, port and string I/O, CPUID, RDMSR/WRMSR, HLT, VMCALL, IRET) to run
+// hand-assembled real-mode and long-mode test guests, and produces the same
+// `VmExit`s the VMX backend does, so exit handling and device models can be
+// tested on any host.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{
+    operand_mask, sign_extend, translate_linear, EventKind, ExitResponse, HvError, HypervisorBackend, IoDirection, PendingEvent,
+    SpecialRegisters, VcpuEvents, VcpuId, VcpuRegisters, VcpuState, VmExit,
+};
+use crate::vmx::ept::{GuestMemoryLayout, MemoryRegion, RegionKind};
+
+/// Reason codes reported in [`VmExit::Unhandled`] by the mock backend.
+pub const MOCK_INVALID_OPCODE: u32 = 0x1000_0000;
+pub const MOCK_STEP_LIMIT: u32 = 0x1000_0001;
+pub const MOCK_PAGE_FAULT: u32 = 0x1000_0002;
+pub const MOCK_UNSUPPORTED_MMIO: u32 = 0x1000_0003;
+/// Interrupt injection outside real mode and long mode.
+pub const MOCK_UNSUPPORTED_INTERRUPT: u32 = 0x1000_0004;
+
+const FLAG_CF: u64 = 1 << 0;
+const FLAG_FIXED: u64 = 1 << 1;
+const FLAG_PF: u64 = 1 << 2;
+const FLAG_ZF: u64 = 1 << 6;
+const FLAG_SF: u64 = 1 << 7;
+const FLAG_IF: u64 = 1 << 9;
+const FLAG_DF: u64 = 1 << 10;
+const FLAG_OF: u64 = 1 << 11;
+const ARITH_FLAGS: u64 = FLAG_CF | FLAG_PF | FLAG_ZF | FLAG_SF | FLAG_OF;
+
+const DEFAULT_STEP_LIMIT: u64 = 1_000_000;
+const PAGE_SIZE: usize = 0x1000;
+
+/// How to finish an instruction that exited, once the exit is completed.
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    Advance { next_rip: u64 },
+    IoIn { size: u8, next_rip: u64 },
+    MmioRead { reg: u8, dest_size: u8, src_size: u8, sign_extend: bool, rex: bool, next_rip: u64 },
+    Cpuid { next_rip: u64 },
+    Rdmsr { next_rip: u64 },
+    Hypercall { next_rip: u64 },
+    StringIo { direction: IoDirection, size: u8, rep: bool, addr_size: u8, next_rip: u64 },
+}
+
+struct MockVcpu {
+    regs: VcpuRegisters,
+    sregs: SpecialRegisters,
+    pending: Option<Pending>,
+    response: ExitResponse,
+    injected: Option<u8>,
+    interrupt_window: bool,
+    /// The last instruction was an STI that set IF.
+    interrupt_shadow: bool,
+    tlb_flushes: u32,
+    /// MSR values carried through save and restore. The interpreter itself
+    /// exits on every RDMSR and WRMSR.
+    msrs: BTreeMap<u32, u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MemFault {
+    Mmio(u64),
+    PageFault(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Seg {
+    Es,
+    Cs,
+    Ss,
+    Ds,
+    Fs,
+    Gs,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Reg(u8),
+    Mem(u64),
+}
+
+struct Decoded {
+    opcode: u16,
+    len: u64,
+    opsize: u8,
+    addrsize: u8,
+    rex: u8,
+    rep: bool,
+    seg: Option<Seg>,
+    reg: u8,
+    rm: Option<Operand>,
+    imm: u64,
+}
+
+/// Deterministic interpreter backend for tests.
+pub struct MockBackend {
+    layout: GuestMemoryLayout,
+    owned_ram: Vec<Box<[u8]>>,
+    vcpus: BTreeMap<VcpuId, MockVcpu>,
+    step_limit: u64,
+    steps: u64,
+    /// Copy of each RAM region, by guest-physical start, as of the last
+    /// time dirty pages were taken. The interpreter has no EPT, so pages
+    /// are dirty when their contents differ from the copy.
+    dirty_log: Option<BTreeMap<u64, Vec<u8>>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        MockBackend {
+            layout: GuestMemoryLayout::new(),
+            owned_ram: Vec::new(),
+            vcpus: BTreeMap::new(),
+            step_limit: DEFAULT_STEP_LIMIT,
+            steps: 0,
+            dirty_log: None,
+        }
+    }
+
+    /// Allocate `size` bytes of zeroed host memory and map it as guest RAM.
+    pub fn alloc_ram(&mut self, guest_phys: u64, size: u64) -> Result<(), HvError> {
+        let mut ram = vec![0u8; size as usize].into_boxed_slice();
+        let host = ram.as_mut_ptr() as u64;
+        self.layout.add(MemoryRegion::ram(guest_phys, size, host))?;
+        self.owned_ram.push(ram);
+        Ok(())
+    }
+
+    /// Maximum number of instructions a single `run` may execute.
+    pub fn set_step_limit(&mut self, limit: u64) {
+        self.step_limit = limit;
+    }
+
+    /// Total instructions executed so far, across all vCPUs.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// How many times `vcpu`'s TLB was flushed. The interpreter has no
+    /// TLB, so this only records the requests.
+    pub fn tlb_flushes(&self, vcpu: VcpuId) -> u32 {
+        self.vcpus.get(&vcpu).map_or(0, |vcpu| vcpu.tlb_flushes)
+    }
+
+    /// Current contents of every RAM region, by guest-physical start.
+    fn ram_copy(&self) -> Result<BTreeMap<u64, Vec<u8>>, HvError> {
+        let mut copy = BTreeMap::new();
+        for region in self.layout.regions().iter().filter(|region| matches!(region.kind, RegionKind::Ram { .. })) {
+            let mut data = vec![0u8; region.size as usize];
+            self.read_guest(region.guest_phys, &mut data)?;
+            copy.insert(region.guest_phys, data);
+        }
+        Ok(copy)
+    }
+
+    fn vcpu(&self, id: VcpuId) -> Result<&MockVcpu, HvError> {
+        self.vcpus.get(&id).ok_or(HvError::NoSuchVcpu(id))
+    }
+
+    fn vcpu_mut(&mut self, id: VcpuId) -> Result<&mut MockVcpu, HvError> {
+        self.vcpus.get_mut(&id).ok_or(HvError::NoSuchVcpu(id))
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HypervisorBackend for MockBackend {
+    fn create_vcpu(&mut self, id: VcpuId) -> Result<(), HvError> {
+        if self.vcpus.contains_key(&id) {
+            return Err(HvError::VcpuExists(id));
+        }
+        self.vcpus.insert(id, MockVcpu {
+            regs: VcpuRegisters { rflags: FLAG_FIXED, ..Default::default() },
+            sregs: SpecialRegisters::real_mode(0),
+            pending: None,
+            response: ExitResponse::None,
+            injected: None,
+            interrupt_window: false,
+            interrupt_shadow: false,
+            tlb_flushes: 0,
+            msrs: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    fn get_registers(&self, vcpu: VcpuId) -> Result<VcpuRegisters, HvError> {
+        Ok(self.vcpu(vcpu)?.regs)
+    }
+
+    fn set_registers(&mut self, vcpu: VcpuId, regs: &VcpuRegisters) -> Result<(), HvError> {
+        self.vcpu_mut(vcpu)?.regs = *regs;
+        Ok(())
+    }
+
+    fn get_special_registers(&self, vcpu: VcpuId) -> Result<SpecialRegisters, HvError> {
+        Ok(self.vcpu(vcpu)?.sregs)
+    }
+
+    fn set_special_registers(&mut self, vcpu: VcpuId, sregs: &SpecialRegisters) -> Result<(), HvError> {
+        self.vcpu_mut(vcpu)?.sregs = *sregs;
+        Ok(())
+    }
+
+    /// RAM regions must point at host memory that outlives the backend.
+    fn map_memory(&mut self, region: MemoryRegion) -> Result<(), HvError> {
+        self.layout.add(region)?;
+        Ok(())
+    }
+
+    fn memory_layout(&self) -> &GuestMemoryLayout {
+        &self.layout
+    }
+
+    fn read_guest(&self, gpa: u64, buf: &mut [u8]) -> Result<(), HvError> {
+        read_phys(&self.layout, gpa, buf).map_err(HvError::BadGuestAddress)
+    }
+
+    fn write_guest(&mut self, gpa: u64, data: &[u8]) -> Result<(), HvError> {
+        write_phys(&self.layout, gpa, data).map_err(HvError::BadGuestAddress)
+    }
+
+    fn run(&mut self, id: VcpuId) -> Result<VmExit, HvError> {
+        let layout = &self.layout;
+        let vcpu = self.vcpus.get_mut(&id).ok_or(HvError::NoSuchVcpu(id))?;
+        if let Some(pending) = vcpu.pending.take() {
+            let response = core::mem::replace(&mut vcpu.response, ExitResponse::None);
+            finish_pending(vcpu, layout, pending, response);
+        }
+        if let Some(vector) = vcpu.injected.take() {
+            if let Some(exit) = deliver_interrupt(vcpu, layout, vector) {
+                return Ok(exit);
+            }
+        }
+        let mut steps = 0;
+        loop {
+            let shadow = core::mem::take(&mut vcpu.interrupt_shadow);
+            if vcpu.interrupt_window && !shadow && vcpu.regs.rflags & FLAG_IF != 0 {
+                self.steps += steps;
+                return Ok(VmExit::InterruptWindow);
+            }
+            if steps >= self.step_limit {
+                self.steps += steps;
+                return Ok(VmExit::Unhandled { reason: MOCK_STEP_LIMIT, qualification: vcpu.regs.rip });
+            }
+            steps += 1;
+            if let Some(exit) = step(vcpu, layout) {
+                self.steps += steps;
+                return Ok(exit);
+            }
+        }
+    }
+
+    fn complete(&mut self, vcpu: VcpuId, response: ExitResponse) -> Result<(), HvError> {
+        self.vcpu_mut(vcpu)?.response = response;
+        Ok(())
+    }
+
+    fn interrupt_ready(&self, id: VcpuId) -> Result<bool, HvError> {
+        let vcpu = self.vcpu(id)?;
+        Ok(vcpu.regs.rflags & FLAG_IF != 0 && !vcpu.interrupt_shadow && vcpu.injected.is_none())
+    }
+
+    fn inject_interrupt(&mut self, id: VcpuId, vector: u8) -> Result<(), HvError> {
+        self.vcpu_mut(id)?.injected = Some(vector);
+        Ok(())
+    }
+
+    fn set_interrupt_window(&mut self, id: VcpuId, enabled: bool) -> Result<(), HvError> {
+        self.vcpu_mut(id)?.interrupt_window = enabled;
+        Ok(())
+    }
+
+    fn flush_tlb(&mut self, id: VcpuId) -> Result<(), HvError> {
+        self.vcpu_mut(id)?.tlb_flushes += 1;
+        Ok(())
+    }
+
+    fn save_state(&mut self, id: VcpuId) -> Result<VcpuState, HvError> {
+        let layout = &self.layout;
+        let vcpu = self.vcpus.get_mut(&id).ok_or(HvError::NoSuchVcpu(id))?;
+        if let Some(pending) = vcpu.pending.take() {
+            let response = core::mem::replace(&mut vcpu.response, ExitResponse::None);
+            finish_pending(vcpu, layout, pending, response);
+        }
+        let pending = vcpu.injected.map(|vector| PendingEvent {
+            kind: EventKind::ExternalInterrupt,
+            vector,
+            error_code: None,
+            instruction_length: 0,
+        });
+        Ok(VcpuState {
+            regs: vcpu.regs,
+            sregs: vcpu.sregs,
+            msrs: vcpu.msrs.iter().map(|(&msr, &value)| (msr, value)).collect(),
+            events: VcpuEvents {
+                pending,
+                interrupt_shadow: vcpu.interrupt_shadow as u8,
+                nmi_blocked: false,
+                interrupt_window: vcpu.interrupt_window,
+            },
+            lapic: None,
+        })
+    }
+
+    fn restore_state(&mut self, id: VcpuId, state: &VcpuState) -> Result<(), HvError> {
+        let injected = match state.events.pending {
+            Some(PendingEvent { kind: EventKind::ExternalInterrupt, vector, .. }) => Some(vector),
+            Some(_) => return Err(HvError::Backend("only external interrupts can be injected")),
+            None => None,
+        };
+        let vcpu = self.vcpu_mut(id)?;
+        vcpu.regs = state.regs;
+        vcpu.sregs = state.sregs;
+        vcpu.msrs = state.msrs.iter().copied().collect();
+        vcpu.pending = None;
+        vcpu.response = ExitResponse::None;
+        vcpu.injected = injected;
+        vcpu.interrupt_shadow = state.events.interrupt_shadow & 1 != 0;
+        vcpu.interrupt_window = state.events.interrupt_window;
+        Ok(())
+    }
+
+    fn enable_dirty_log(&mut self) -> Result<(), HvError> {
+        if self.dirty_log.is_none() {
+            self.dirty_log = Some(self.ram_copy()?);
+        }
+        Ok(())
+    }
+
+    fn take_dirty_pages(&mut self) -> Result<Vec<u64>, HvError> {
+        let current = self.ram_copy()?;
+        let previous = self.dirty_log.as_ref().ok_or(HvError::Backend("dirty logging is not enabled"))?;
+        let mut pages = Vec::new();
+        for (&start, data) in &current {
+            let old = previous.get(&start);
+            for (i, page) in data.chunks(PAGE_SIZE).enumerate() {
+                if old.and_then(|old| old.get(i * PAGE_SIZE..i * PAGE_SIZE + page.len())) != Some(page) {
+                    pages.push(start + (i * PAGE_SIZE) as u64);
+                }
+            }
+        }
+        self.dirty_log = Some(current);
+        Ok(pages)
+    }
+}
+
+fn ram_ptr(layout: &GuestMemoryLayout, gpa: u64) -> Option<(*mut u8, u64)> {
+    let region = layout.find(gpa)?;
+    match region.kind {
+        RegionKind::Ram { host_phys } => {
+            let offset = gpa - region.guest_phys;
+            Some(((host_phys + offset) as *mut u8, region.size - offset))
+        }
+        RegionKind::Mmio => None,
+    }
+}
+
+/// Copy guest-physical memory out; on failure returns the first bad address.
+fn read_phys(layout: &GuestMemoryLayout, gpa: u64, buf: &mut [u8]) -> Result<(), u64> {
+    let mut done = 0;
+    while done < buf.len() {
+        let addr = gpa + done as u64;
+        let (ptr, avail) = ram_ptr(layout, addr).ok_or(addr)?;
+        let n = core::cmp::min(avail as usize, buf.len() - done);
+        unsafe { core::ptr::copy_nonoverlapping(ptr, buf[done..].as_mut_ptr(), n) };
+        done += n;
+    }
+    Ok(())
+}
+
+fn write_phys(layout: &GuestMemoryLayout, gpa: u64, data: &[u8]) -> Result<(), u64> {
+    let mut done = 0;
+    while done < data.len() {
+        let addr = gpa + done as u64;
+        let (ptr, avail) = ram_ptr(layout, addr).ok_or(addr)?;
+        let n = core::cmp::min(avail as usize, data.len() - done);
+        unsafe { core::ptr::copy_nonoverlapping(data[done..].as_ptr(), ptr, n) };
+        done += n;
+    }
+    Ok(())
+}
+
+fn translate(layout: &GuestMemoryLayout, sregs: &SpecialRegisters, linear: u64) -> Result<u64, MemFault> {
+    let read_entry = |gpa: u64| {
+        let mut raw = [0u8; 8];
+        read_phys(layout, gpa, &mut raw).ok().map(|_| u64::from_le_bytes(raw))
+    };
+    translate_linear(sregs, linear, read_entry).ok_or(MemFault::PageFault(linear))
+}
+
+fn read_linear(layout: &GuestMemoryLayout, sregs: &SpecialRegisters, linear: u64, size: u8) -> Result<u64, MemFault> {
+    let mut bytes = [0u8; 8];
+    for i in 0..size as u64 {
+        let gpa = translate(layout, sregs, linear.wrapping_add(i))?;
+        read_phys(layout, gpa, &mut bytes[i as usize..i as usize + 1]).map_err(MemFault::Mmio)?;
+    }
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn write_linear(layout: &GuestMemoryLayout, sregs: &SpecialRegisters, linear: u64, size: u8, value: u64) -> Result<(), MemFault> {
+    let bytes = value.to_le_bytes();
+    // Translate everything first so a fault leaves memory untouched.
+    let mut gpas = [0u64; 8];
+    for (i, gpa) in gpas.iter_mut().take(size as usize).enumerate() {
+        *gpa = translate(layout, sregs, linear.wrapping_add(i as u64))?;
+        if ram_ptr(layout, *gpa).is_none() {
+            return Err(MemFault::Mmio(*gpa));
+        }
+    }
+    for i in 0..size as usize {
+        write_phys(layout, gpas[i], &bytes[i..i + 1]).map_err(MemFault::Mmio)?;
+    }
+    Ok(())
+}
+
+fn sign_bit(size: u8) -> u64 {
+    1u64 << (size as u32 * 8 - 1)
+}
+
+fn segment_base(sregs: &SpecialRegisters, seg: Seg) -> u64 {
+    let long = sregs.is_long_mode();
+    match seg {
+        Seg::Fs => sregs.fs.base,
+        Seg::Gs => sregs.gs.base,
+        _ if long => 0,
+        Seg::Es => sregs.es.base,
+        Seg::Cs => sregs.cs.base,
+        Seg::Ss => sregs.ss.base,
+        Seg::Ds => sregs.ds.base,
+    }
+}
+
+fn parity(value: u64) -> bool {
+    (value as u8).count_ones() & 1 == 0
+}
+
+fn set_result_flags(regs: &mut VcpuRegisters, result: u64, size: u8) {
+    let result = result & operand_mask(size);
+    regs.rflags &= !(FLAG_ZF | FLAG_SF | FLAG_PF);
+    if result == 0 { regs.rflags |= FLAG_ZF; }
+    if result & sign_bit(size) != 0 { regs.rflags |= FLAG_SF; }
+    if parity(result) { regs.rflags |= FLAG_PF; }
+}
+
+/// ALU operation selected by the /digit of group-1 opcodes.
+fn alu(regs: &mut VcpuRegisters, op: u8, a: u64, b: u64, size: u8) -> Option<u64> {
+    let m = operand_mask(size);
+    let (a, b) = (a & m, b & m);
+    let sb = sign_bit(size);
+    let (result, cf, of) = match op {
+        0 => {
+            let r = a.wrapping_add(b) & m;
+            (r, r < a, (a ^ r) & (b ^ r) & sb != 0)
+        }
+        1 => (a | b, false, false),
+        4 => (a & b, false, false),
+        5 | 7 => {
+            let r = a.wrapping_sub(b) & m;
+            (r, a < b, (a ^ b) & (a ^ r) & sb != 0)
+        }
+        6 => (a ^ b, false, false),
+        _ => return None,
+    };
+    regs.rflags &= !ARITH_FLAGS;
+    if cf { regs.rflags |= FLAG_CF; }
+    if of { regs.rflags |= FLAG_OF; }
+    set_result_flags(regs, result, size);
+    Some(result)
+}
+
+fn condition(rflags: u64, cc: u8) -> bool {
+    let cf = rflags & FLAG_CF != 0;
+    let zf = rflags & FLAG_ZF != 0;
+    let sf = rflags & FLAG_SF != 0;
+    let of = rflags & FLAG_OF != 0;
+    let pf = rflags & FLAG_PF != 0;
+    let result = match cc >> 1 {
+        0 => of,
+        1 => cf,
+        2 => zf,
+        3 => cf || zf,
+        4 => sf,
+        5 => pf,
+        6 => sf != of,
+        _ => zf || (sf != of),
+    };
+    if cc & 1 != 0 { !result } else { result }
+}
+
+fn fetch(vcpu: &MockVcpu, layout: &GuestMemoryLayout) -> Result<([u8; 15], usize), MemFault> {
+    let mut bytes = [0u8; 15];
+    let linear = vcpu.sregs.cs.base.wrapping_add(vcpu.regs.rip);
+    let mut n = 0;
+    while n < bytes.len() {
+        let gpa = match translate(layout, &vcpu.sregs, linear + n as u64) {
+            Ok(gpa) => gpa,
+            Err(fault) if n == 0 => return Err(fault),
+            Err(_) => break,
+        };
+        if read_phys(layout, gpa, &mut bytes[n..n + 1]).is_err() {
+            if n == 0 {
+                return Err(MemFault::Mmio(gpa));
+            }
+            break;
+        }
+        n += 1;
+    }
+    Ok((bytes, n))
+}
+
+fn has_modrm(opcode: u16) -> bool {
+    match opcode {
+        0x00..=0x3F => opcode & 0x7 < 4,
+        0x80..=0x8B | 0x8D | 0xC6 | 0xC7 | 0xF6 | 0xF7 | 0xFE | 0xFF => true,
+        0x0FB6 | 0x0FB7 | 0x0FBE | 0x0FBF => true,
+        _ => false,
+    }
+}
+
+fn decode(bytes: &[u8], avail: usize, vcpu: &MockVcpu) -> Option<Decoded> {
+    let long = vcpu.sregs.is_long_mode();
+    let regs = &vcpu.regs;
+    let mut i = 0;
+    let mut op16 = false;
+    let mut addr_override = false;
+    let mut rep = false;
+    let mut seg = None;
+    let byte = |i: usize| if i < avail { Some(bytes[i]) } else { None };
+    loop {
+        match byte(i)? {
+            0x66 => op16 = true,
+            0x67 => addr_override = true,
+            0xF2 | 0xF3 => rep = true,
+            0xF0 => {}
+            0x26 => seg = Some(Seg::Es),
+            0x2E => seg = Some(Seg::Cs),
+            0x36 => seg = Some(Seg::Ss),
+            0x3E => seg = Some(Seg::Ds),
+            0x64 => seg = Some(Seg::Fs),
+            0x65 => seg = Some(Seg::Gs),
+            _ => break,
+        }
+        i += 1;
+    }
+    let mut rex = 0;
+    if long && byte(i)? & 0xF0 == 0x40 {
+        rex = byte(i)?;
+        i += 1;
+    }
+    let opsize = if long {
+        if rex & 0x8 != 0 { 8 } else if op16 { 2 } else { 4 }
+    } else if op16 { 4 } else { 2 };
+    let addrsize = if long {
+        if addr_override { 4 } else { 8 }
+    } else if addr_override { 4 } else { 2 };
+
+    let mut opcode = byte(i)? as u16;
+    i += 1;
+    if opcode == 0x0F {
+        opcode = 0x0F00 | byte(i)? as u16;
+        i += 1;
+        if opcode == 0x0F01 {
+            // Only VMCALL (0F 01 C1) is modelled.
+            if byte(i)? != 0xC1 {
+                return None;
+            }
+            i += 1;
+        }
+    }
+
+    let mut reg = 0;
+    let mut rm = None;
+    let mut rip_relative = None;
+    let mut mod_reg = 0;
+    if has_modrm(opcode) {
+        let modrm = byte(i)?;
+        i += 1;
+        let md = modrm >> 6;
+        mod_reg = (modrm >> 3) & 7;
+        reg = mod_reg | ((rex & 0x4) << 1);
+        let low = modrm & 7;
+        if md == 3 {
+            rm = Some(Operand::Reg(low | ((rex & 0x1) << 3)));
+        } else if addrsize == 2 {
+            let (base, default_ss) = match low {
+                0 => (regs.rbx.wrapping_add(regs.rsi), false),
+                1 => (regs.rbx.wrapping_add(regs.rdi), false),
+                2 => (regs.rbp.wrapping_add(regs.rsi), true),
+                3 => (regs.rbp.wrapping_add(regs.rdi), true),
+                4 => (regs.rsi, false),
+                5 => (regs.rdi, false),
+                6 if md == 0 => (0, false),
+                6 => (regs.rbp, true),
+                _ => (regs.rbx, false),
+            };
+            let disp = if md == 0 && low == 6 {
+                let d = u16::from_le_bytes([byte(i)?, byte(i + 1)?]) as u64;
+                i += 2;
+                d
+            } else if md == 1 {
+                let d = sign_extend(byte(i)? as u64, 1);
+                i += 1;
+                d
+            } else if md == 2 {
+                let d = sign_extend(u16::from_le_bytes([byte(i)?, byte(i + 1)?]) as u64, 2);
+                i += 2;
+                d
+            } else {
+                0
+            };
+            let ea = base.wrapping_add(disp) & 0xFFFF;
+            let segment = seg.unwrap_or(if default_ss { Seg::Ss } else { Seg::Ds });
+            rm = Some(Operand::Mem(segment_base(&vcpu.sregs, segment).wrapping_add(ea)));
+        } else {
+            let mut default_ss = false;
+            let mut base_val = 0u64;
+            let mut disp32_only = false;
+            if low == 4 {
+                let sib = byte(i)?;
+                i += 1;
+                let scale = 1u64 << (sib >> 6);
+                let index = ((sib >> 3) & 7) | ((rex & 0x2) << 2);
+                let base = (sib & 7) | ((rex & 0x1) << 3);
+                if index != 4 {
+                    base_val = regs.gpr(index).wrapping_mul(scale);
+                }
+                if sib & 7 == 5 && md == 0 {
+                    disp32_only = true;
+                } else {
+                    base_val = base_val.wrapping_add(regs.gpr(base));
+                    default_ss = base & 7 == 4 || base & 7 == 5;
+                }
+            } else if low == 5 && md == 0 {
+                disp32_only = true;
+                if long {
+                    rip_relative = Some(0);
+                }
+            } else {
+                let base = low | ((rex & 0x1) << 3);
+                base_val = regs.gpr(base);
+                default_ss = low == 5;
+            }
+            let disp = if md == 1 {
+                let d = sign_extend(byte(i)? as u64, 1);
+                i += 1;
+                d
+            } else if md == 2 || disp32_only {
+                let d = sign_extend(u32::from_le_bytes([byte(i)?, byte(i + 1)?, byte(i + 2)?, byte(i + 3)?]) as u64, 4);
+                i += 4;
+                d
+            } else {
+                0
+            };
+            let mut ea = base_val.wrapping_add(disp);
+            if addrsize == 4 {
+                ea &= 0xFFFF_FFFF;
+            }
+            let segment = seg.unwrap_or(if default_ss { Seg::Ss } else { Seg::Ds });
+            if rip_relative.is_some() {
+                rip_relative = Some(disp);
+            } else {
+                rm = Some(Operand::Mem(segment_base(&vcpu.sregs, segment).wrapping_add(ea)));
+            }
+        }
+    }
+
+    let branch_size = if long { 4 } else { opsize };
+    let imm_size = match opcode {
+        0x04 | 0x0C | 0x24 | 0x2C | 0x34 | 0x3C | 0xA8 | 0xB0..=0xB7 | 0x80 | 0x83 | 0xC6 => 1,
+        0xE4..=0xE7 | 0xEB | 0x70..=0x7F | 0xE2 | 0x6A => 1,
+        0xF6 if mod_reg == 0 => 1,
+        0x05 | 0x0D | 0x25 | 0x2D | 0x35 | 0x3D | 0xA9 | 0x81 | 0xC7 | 0x68 => core::cmp::min(opsize, 4),
+        0xF7 if mod_reg == 0 => core::cmp::min(opsize, 4),
+        0xB8..=0xBF => opsize,
+        0xE8 | 0xE9 | 0x0F80..=0x0F8F => branch_size,
+        _ => 0,
+    };
+    let mut imm = 0u64;
+    for k in 0..imm_size as usize {
+        imm |= (byte(i + k)? as u64) << (8 * k);
+    }
+    i += imm_size as usize;
+    if imm_size > 0 && !(0xB8..=0xBF).contains(&opcode) {
+        imm = sign_extend(imm, imm_size);
+    }
+
+    if let Some(disp) = rip_relative {
+        let next_rip = vcpu.regs.rip.wrapping_add(i as u64);
+        let segment = seg.unwrap_or(Seg::Ds);
+        rm = Some(Operand::Mem(segment_base(&vcpu.sregs, segment).wrapping_add(next_rip.wrapping_add(disp))));
+    }
+
+    Some(Decoded { opcode, len: i as u64, opsize, addrsize, rex, rep, seg, reg, rm, imm })
+}
+
+/// Apply the result of a completed exit and move past the instruction.
+fn finish_pending(vcpu: &mut MockVcpu, layout: &GuestMemoryLayout, pending: Pending, response: ExitResponse) {
+    let long = vcpu.sregs.is_long_mode();
+    let data = match response {
+        ExitResponse::Data(value) => Some(value),
+        _ => None,
+    };
+    match pending {
+        Pending::Advance { next_rip } => vcpu.regs.rip = next_rip,
+        Pending::IoIn { size, next_rip } => {
+            vcpu.regs.write_operand(0, size, true, long, data.unwrap_or(u64::MAX));
+            vcpu.regs.rip = next_rip;
+        }
+        Pending::MmioRead { reg, dest_size, src_size, sign_extend: sx, rex, next_rip } => {
+            let mut value = data.unwrap_or(u64::MAX) & operand_mask(src_size);
+            if sx {
+                value = sign_extend(value, src_size);
+            }
+            vcpu.regs.write_operand(reg, dest_size, rex, long, value);
+            vcpu.regs.rip = next_rip;
+        }
+        Pending::Cpuid { next_rip } => {
+            let (a, b, c, d) = match response {
+                ExitResponse::Cpuid { eax, ebx, ecx, edx } => (eax, ebx, ecx, edx),
+                _ => (0, 0, 0, 0),
+            };
+            vcpu.regs.rax = a as u64;
+            vcpu.regs.rbx = b as u64;
+            vcpu.regs.rcx = c as u64;
+            vcpu.regs.rdx = d as u64;
+            vcpu.regs.rip = next_rip;
+        }
+        Pending::Rdmsr { next_rip } => {
+            let value = data.unwrap_or(0);
+            vcpu.regs.rax = value & 0xFFFF_FFFF;
+            vcpu.regs.rdx = value >> 32;
+            vcpu.regs.rip = next_rip;
+        }
+        Pending::Hypercall { next_rip } => {
+            if let Some(value) = data {
+                vcpu.regs.rax = value;
+            }
+            vcpu.regs.rip = next_rip;
+        }
+        Pending::StringIo { direction, size, rep, addr_size, next_rip } => {
+            let step = if vcpu.regs.rflags & FLAG_DF != 0 { (size as u64).wrapping_neg() } else { size as u64 };
+            let amask = operand_mask(addr_size);
+            match direction {
+                IoDirection::In => {
+                    let linear = segment_base(&vcpu.sregs, Seg::Es).wrapping_add(vcpu.regs.rdi & amask);
+                    // A fault here drops the element, like a device that lost the data.
+                    let _ = write_linear(layout, &vcpu.sregs, linear, size, data.unwrap_or(u64::MAX));
+                    let rdi = vcpu.regs.rdi;
+                    vcpu.regs.rdi = (rdi & !amask) | (rdi.wrapping_add(step) & amask);
+                }
+                IoDirection::Out => {
+                    let rsi = vcpu.regs.rsi;
+                    vcpu.regs.rsi = (rsi & !amask) | (rsi.wrapping_add(step) & amask);
+                }
+            }
+            if rep {
+                let rcx = vcpu.regs.rcx;
+                let count = (rcx & amask).wrapping_sub(1) & amask;
+                vcpu.regs.rcx = (rcx & !amask) | count;
+                if count != 0 {
+                    // Re-execute the instruction for the next element.
+                    return;
+                }
+            }
+            vcpu.regs.rip = next_rip;
+        }
+    }
+}
+
+fn unhandled(reason: u32, qualification: u64) -> Option<VmExit> {
+    Some(VmExit::Unhandled { reason, qualification })
+}
+
+fn fault_exit(fault: MemFault) -> Option<VmExit> {
+    match fault {
+        MemFault::PageFault(addr) => unhandled(MOCK_PAGE_FAULT, addr),
+        MemFault::Mmio(gpa) => unhandled(MOCK_UNSUPPORTED_MMIO, gpa),
+    }
+}
+
+/// Execute one instruction. Returns `Some` if it caused an exit.
+fn step(vcpu: &mut MockVcpu, layout: &GuestMemoryLayout) -> Option<VmExit> {
+    let (bytes, avail) = match fetch(vcpu, layout) {
+        Ok(fetched) => fetched,
+        Err(fault) => return fault_exit(fault),
+    };
+    let insn = match decode(&bytes, avail, vcpu) {
+        Some(insn) => insn,
+        None => return unhandled(MOCK_INVALID_OPCODE, vcpu.regs.rip),
+    };
+    let long = vcpu.sregs.is_long_mode();
+    let ip_mask = if long { u64::MAX } else { 0xFFFF };
+    let next_rip = vcpu.regs.rip.wrapping_add(insn.len) & ip_mask;
+    let rex = insn.rex != 0;
+    let opsize = insn.opsize;
+    let stack_size = if long { if opsize == 2 { 2 } else { 8 } } else { opsize };
+    let sregs = vcpu.sregs;
+
+    macro_rules! read_rm {
+        ($size:expr) => {
+            match insn.rm {
+                Some(Operand::Reg(r)) => vcpu.regs.read_operand(r, $size, rex),
+                Some(Operand::Mem(addr)) => match read_linear(layout, &sregs, addr, $size) {
+                    Ok(v) => v,
+                    Err(fault) => return fault_exit(fault),
+                },
+                None => return unhandled(MOCK_INVALID_OPCODE, vcpu.regs.rip),
+            }
+        };
+    }
+    macro_rules! write_rm {
+        ($size:expr, $value:expr) => {
+            match insn.rm {
+                Some(Operand::Reg(r)) => vcpu.regs.write_operand(r, $size, rex, long, $value),
+                Some(Operand::Mem(addr)) => {
+                    if let Err(fault) = write_linear(layout, &sregs, addr, $size, $value) {
+                        return fault_exit(fault);
+                    }
+                }
+                None => return unhandled(MOCK_INVALID_OPCODE, vcpu.regs.rip),
+            }
+        };
+    }
+
+    match insn.opcode {
+        // ALU r/m, reg / reg, r/m / acc, imm
+        op @ 0x00..=0x3D if op & 7 < 6 && op >> 3 != 2 && op >> 3 != 3 => {
+            let alu_op = (op >> 3) as u8;
+            let size = if op & 1 == 0 { 1 } else { opsize };
+            let (a, b) = match op & 7 {
+                0 | 1 => (read_rm!(size), vcpu.regs.read_operand(insn.reg, size, rex)),
+                2 | 3 => (vcpu.regs.read_operand(insn.reg, size, rex), read_rm!(size)),
+                _ => (vcpu.regs.read_operand(0, size, rex), insn.imm),
+            };
+            let result = alu(&mut vcpu.regs, alu_op, a, b, size)?;
+            if alu_op != 7 {
+                match op & 7 {
+                    0 | 1 => write_rm!(size, result),
+                    2 | 3 => vcpu.regs.write_operand(insn.reg, size, rex, long, result),
+                    _ => vcpu.regs.write_operand(0, size, rex, long, result),
+                }
+            }
+        }
+        // INC/DEC r16/r32 (not REX in long mode)
+        op @ 0x40..=0x4F if !long => {
+            let r = (op & 7) as u8;
+            let cf = vcpu.regs.rflags & FLAG_CF;
+            let a = vcpu.regs.read_operand(r, opsize, rex);
+            let result = alu(&mut vcpu.regs, if op < 0x48 { 0 } else { 5 }, a, 1, opsize)?;
+            vcpu.regs.rflags = (vcpu.regs.rflags & !FLAG_CF) | cf;
+            vcpu.regs.write_operand(r, opsize, rex, long, result);
+        }
+        op @ 0x50..=0x57 => {
+            let r = (op & 7) as u8 | ((insn.rex & 1) << 3);
+            let value = vcpu.regs.read_operand(r, stack_size, rex);
+            if let Err(fault) = push(vcpu, layout, stack_size, value) {
+                return fault_exit(fault);
+            }
+        }
+        op @ 0x58..=0x5F => {
+            let r = (op & 7) as u8 | ((insn.rex & 1) << 3);
+            match pop(vcpu, layout, stack_size) {
+                Ok(value) => vcpu.regs.write_operand(r, stack_size, rex, long, value),
+                Err(fault) => return fault_exit(fault),
+            }
+        }
+        0x68 | 0x6A => {
+            if let Err(fault) = push(vcpu, layout, stack_size, insn.imm) {
+                return fault_exit(fault);
+            }
+        }
+        op @ (0x6C..=0x6F) => {
+            let size = if op & 1 == 0 { 1 } else { core::cmp::min(opsize, 4) };
+            let amask = operand_mask(insn.addrsize);
+            if insn.rep && vcpu.regs.rcx & amask == 0 {
+                vcpu.regs.rip = next_rip;
+                return None;
+            }
+            let port = vcpu.regs.rdx as u16;
+            let pending = |direction| Pending::StringIo { direction, size, rep: insn.rep, addr_size: insn.addrsize, next_rip };
+            if op < 0x6E {
+                vcpu.pending = Some(pending(IoDirection::In));
+                return Some(VmExit::Io { port, size, direction: IoDirection::In, data: 0 });
+            }
+            let segment = insn.seg.unwrap_or(Seg::Ds);
+            let linear = segment_base(&sregs, segment).wrapping_add(vcpu.regs.rsi & amask);
+            let data = match read_linear(layout, &sregs, linear, size) {
+                Ok(v) => v as u32,
+                Err(fault) => return fault_exit(fault),
+            };
+            vcpu.pending = Some(pending(IoDirection::Out));
+            return Some(VmExit::Io { port, size, direction: IoDirection::Out, data });
+        }
+        op @ 0x70..=0x7F => {
+            if condition(vcpu.regs.rflags, (op & 0xF) as u8) {
+                vcpu.regs.rip = next_rip.wrapping_add(insn.imm) & ip_mask;
+                return None;
+            }
+        }
+        op @ 0x0F80..=0x0F8F => {
+            if condition(vcpu.regs.rflags, (op & 0xF) as u8) {
+                vcpu.regs.rip = next_rip.wrapping_add(insn.imm) & ip_mask;
+                return None;
+            }
+        }
+        op @ (0x80 | 0x81 | 0x83) => {
+            let size = if op == 0x80 { 1 } else { opsize };
+            let alu_op = insn.reg & 7;
+            let a = read_rm!(size);
+            let result = match alu(&mut vcpu.regs, alu_op, a, insn.imm, size) {
+                Some(result) => result,
+                None => return unhandled(MOCK_INVALID_OPCODE, vcpu.regs.rip),
+            };
+            if alu_op != 7 {
+                write_rm!(size, result);
+            }
+        }
+        op @ (0x84 | 0x85 | 0xA8 | 0xA9) => {
+            let size = if op & 1 == 0 { 1 } else { opsize };
+            let (a, b) = if op < 0xA8 {
+                (read_rm!(size), vcpu.regs.read_operand(insn.reg, size, rex))
+            } else {
+                (vcpu.regs.read_operand(0, size, rex), insn.imm)
+            };
+            alu(&mut vcpu.regs, 4, a, b, size);
+        }
+        op @ (0x88 | 0x89) => {
+            let size = if op == 0x88 { 1 } else { opsize };
+            let value = vcpu.regs.read_operand(insn.reg, size, rex);
+            if let Some(Operand::Mem(addr)) = insn.rm {
+                match write_linear(layout, &sregs, addr, size, value) {
+                    Ok(()) => {}
+                    Err(MemFault::Mmio(gpa)) => {
+                        vcpu.pending = Some(Pending::Advance { next_rip });
+                        return Some(VmExit::Mmio { gpa, size, write: true, data: value });
+                    }
+                    Err(fault) => return fault_exit(fault),
+                }
+            } else {
+                write_rm!(size, value);
+            }
+        }
+        op @ (0x8A | 0x8B | 0x0FB6 | 0x0FB7 | 0x0FBE | 0x0FBF) => {
+            let (src_size, sx) = match op {
+                0x8A => (1, false),
+                0x8B => (opsize, false),
+                0x0FB6 => (1, false),
+                0x0FB7 => (2, false),
+                0x0FBE => (1, true),
+                _ => (2, true),
+            };
+            let dest_size = if op == 0x8A { 1 } else { opsize };
+            let value = match insn.rm {
+                Some(Operand::Mem(addr)) => match read_linear(layout, &sregs, addr, src_size) {
+                    Ok(v) => v,
+                    Err(MemFault::Mmio(gpa)) => {
+                        vcpu.pending = Some(Pending::MmioRead { reg: insn.reg, dest_size, src_size, sign_extend: sx, rex, next_rip });
+                        return Some(VmExit::Mmio { gpa, size: src_size, write: false, data: 0 });
+                    }
+                    Err(fault) => return fault_exit(fault),
+                },
+                _ => read_rm!(src_size),
+            };
+            let value = if sx { sign_extend(value, src_size) } else { value };
+            vcpu.regs.write_operand(insn.reg, dest_size, rex, long, value);
+        }
+        0x8D => match insn.rm {
+            Some(Operand::Mem(addr)) => {
+                // Segment bases are zero wherever LEA is used in test guests.
+                vcpu.regs.write_operand(insn.reg, opsize, rex, long, addr);
+            }
+            _ => return unhandled(MOCK_INVALID_OPCODE, vcpu.regs.rip),
+        },
+        0x90 => {}
+        op @ 0xB0..=0xB7 => {
+            let r = (op & 7) as u8 | ((insn.rex & 1) << 3);
+            vcpu.regs.write_operand(r, 1, rex, long, insn.imm);
+        }
+        op @ 0xB8..=0xBF => {
+            let r = (op & 7) as u8 | ((insn.rex & 1) << 3);
+            vcpu.regs.write_operand(r, opsize, rex, long, insn.imm);
+        }
+        0xC3 => match pop(vcpu, layout, stack_size) {
+            Ok(target) => {
+                vcpu.regs.rip = target & ip_mask;
+                return None;
+            }
+            Err(fault) => return fault_exit(fault),
+        },
+        0xCF if opsize == stack_size && (opsize == 8 || !sregs.is_protected()) => {
+            return match iret(vcpu, layout, opsize) {
+                Ok(()) => None,
+                Err(fault) => fault_exit(fault),
+            };
+        }
+        op @ (0xC6 | 0xC7) => {
+            let size = if op == 0xC6 { 1 } else { opsize };
+            if let Some(Operand::Mem(addr)) = insn.rm {
+                match write_linear(layout, &sregs, addr, size, insn.imm) {
+                    Ok(()) => {}
+                    Err(MemFault::Mmio(gpa)) => {
+                        vcpu.pending = Some(Pending::Advance { next_rip });
+                        return Some(VmExit::Mmio { gpa, size, write: true, data: insn.imm & operand_mask(size) });
+                    }
+                    Err(fault) => return fault_exit(fault),
+                }
+            } else {
+                write_rm!(size, insn.imm);
+            }
+        }
+        0xE2 => {
+            let amask = operand_mask(insn.addrsize);
+            let count = (vcpu.regs.rcx & amask).wrapping_sub(1) & amask;
+            vcpu.regs.rcx = (vcpu.regs.rcx & !amask) | count;
+            if count != 0 {
+                vcpu.regs.rip = next_rip.wrapping_add(insn.imm) & ip_mask;
+                return None;
+            }
+        }
+        op @ (0xE4..=0xE7 | 0xEC..=0xEF) => {
+            let size = if op & 1 == 0 { 1 } else { core::cmp::min(opsize, 4) };
+            let port = if op < 0xE8 { insn.imm as u8 as u16 } else { vcpu.regs.rdx as u16 };
+            if op & 2 == 0 {
+                vcpu.pending = Some(Pending::IoIn { size, next_rip });
+                return Some(VmExit::Io { port, size, direction: IoDirection::In, data: 0 });
+            }
+            let data = (vcpu.regs.rax & operand_mask(size)) as u32;
+            vcpu.pending = Some(Pending::Advance { next_rip });
+            return Some(VmExit::Io { port, size, direction: IoDirection::Out, data });
+        }
+        0xE8 => {
+            if let Err(fault) = push(vcpu, layout, stack_size, next_rip) {
+                return fault_exit(fault);
+            }
+            vcpu.regs.rip = next_rip.wrapping_add(insn.imm) & ip_mask;
+            return None;
+        }
+        0xE9 | 0xEB => {
+            vcpu.regs.rip = next_rip.wrapping_add(insn.imm) & ip_mask;
+            return None;
+        }
+        0xF4 => {
+            vcpu.pending = Some(Pending::Advance { next_rip });
+            return Some(VmExit::Hlt);
+        }
+        op @ (0xF6 | 0xF7) if insn.reg & 7 == 0 => {
+            let size = if op == 0xF6 { 1 } else { opsize };
+            let a = read_rm!(size);
+            alu(&mut vcpu.regs, 4, a, insn.imm, size);
+        }
+        0xFA => vcpu.regs.rflags &= !FLAG_IF,
+        0xFB => {
+            // Interrupts stay blocked for one more instruction, so `sti; hlt` cannot miss a wakeup.
+            vcpu.interrupt_shadow = vcpu.regs.rflags & FLAG_IF == 0;
+            vcpu.regs.rflags |= FLAG_IF;
+        }
+        0xFC => vcpu.regs.rflags &= !FLAG_DF,
+        0xFD => vcpu.regs.rflags |= FLAG_DF,
+        op @ (0xFE | 0xFF) if insn.reg & 7 < 2 => {
+            let size = if op == 0xFE { 1 } else { opsize };
+            let cf = vcpu.regs.rflags & FLAG_CF;
+            let a = read_rm!(size);
+            let result = alu(&mut vcpu.regs, if insn.reg & 7 == 0 { 0 } else { 5 }, a, 1, size)?;
+            vcpu.regs.rflags = (vcpu.regs.rflags & !FLAG_CF) | cf;
+            write_rm!(size, result);
+        }
+        0x0F01 => {
+            vcpu.pending = Some(Pending::Hypercall { next_rip });
+            return Some(VmExit::Hypercall);
+        }
+        0x0F30 => {
+            let msr = vcpu.regs.rcx as u32;
+            let value = (vcpu.regs.rdx << 32) | (vcpu.regs.rax & 0xFFFF_FFFF);
+            vcpu.pending = Some(Pending::Advance { next_rip });
+            return Some(VmExit::MsrWrite { msr, value });
+        }
+        0x0F32 => {
+            vcpu.pending = Some(Pending::Rdmsr { next_rip });
+            return Some(VmExit::MsrRead { msr: vcpu.regs.rcx as u32 });
+        }
+        0x0FA2 => {
+            vcpu.pending = Some(Pending::Cpuid { next_rip });
+            return Some(VmExit::Cpuid { leaf: vcpu.regs.rax as u32, subleaf: vcpu.regs.rcx as u32 });
+        }
+        _ => return unhandled(MOCK_INVALID_OPCODE, vcpu.regs.rip),
+    }
+    vcpu.regs.rip = next_rip;
+    None
+}
+
+/// Deliver an external interrupt through the IVT (real mode) or a 64-bit
+/// IDT gate. Returns the exit for a fault or an unsupported mode.
+fn deliver_interrupt(vcpu: &mut MockVcpu, layout: &GuestMemoryLayout, vector: u8) -> Option<VmExit> {
+    let sregs = vcpu.sregs;
+    let result = if !sregs.is_protected() {
+        deliver_real_mode(vcpu, layout, vector)
+    } else if sregs.is_long_mode() {
+        deliver_long_mode(vcpu, layout, vector)
+    } else {
+        return unhandled(MOCK_UNSUPPORTED_INTERRUPT, vector as u64);
+    };
+    result.err().and_then(fault_exit)
+}
+
+fn deliver_real_mode(vcpu: &mut MockVcpu, layout: &GuestMemoryLayout, vector: u8) -> Result<(), MemFault> {
+    let entry = read_linear(layout, &vcpu.sregs, vcpu.sregs.idt.base + vector as u64 * 4, 4)?;
+    push(vcpu, layout, 2, vcpu.regs.rflags)?;
+    push(vcpu, layout, 2, vcpu.sregs.cs.selector as u64)?;
+    push(vcpu, layout, 2, vcpu.regs.rip)?;
+    vcpu.regs.rflags &= !FLAG_IF;
+    vcpu.sregs.cs.selector = (entry >> 16) as u16;
+    vcpu.sregs.cs.base = (entry >> 16) << 4;
+    vcpu.regs.rip = entry & 0xFFFF;
+    Ok(())
+}
+
+/// Ring 0 only: no stack switch and no IST.
+fn deliver_long_mode(vcpu: &mut MockVcpu, layout: &GuestMemoryLayout, vector: u8) -> Result<(), MemFault> {
+    let gate = vcpu.sregs.idt.base + vector as u64 * 16;
+    let low = read_linear(layout, &vcpu.sregs, gate, 8)?;
+    let high = read_linear(layout, &vcpu.sregs, gate + 8, 8)?;
+    let target = (low & 0xFFFF) | ((low >> 48) << 16) | ((high & 0xFFFF_FFFF) << 32);
+    let interrupt_gate = (low >> 40) & 0xF == 0xE;
+    let rsp = vcpu.regs.rsp;
+    vcpu.regs.rsp &= !0xF;
+    push(vcpu, layout, 8, vcpu.sregs.ss.selector as u64)?;
+    push(vcpu, layout, 8, rsp)?;
+    push(vcpu, layout, 8, vcpu.regs.rflags)?;
+    push(vcpu, layout, 8, vcpu.sregs.cs.selector as u64)?;
+    push(vcpu, layout, 8, vcpu.regs.rip)?;
+    if interrupt_gate {
+        vcpu.regs.rflags &= !FLAG_IF;
+    }
+    vcpu.sregs.cs.selector = (low >> 16) as u16;
+    vcpu.regs.rip = target;
+    Ok(())
+}
+
+/// IRET in real mode or IRETQ in long mode, back to the same privilege level.
+fn iret(vcpu: &mut MockVcpu, layout: &GuestMemoryLayout, size: u8) -> Result<(), MemFault> {
+    let rip = pop(vcpu, layout, size)?;
+    let cs = pop(vcpu, layout, size)? as u16;
+    let rflags = pop(vcpu, layout, size)?;
+    if size == 8 {
+        let rsp = pop(vcpu, layout, 8)?;
+        vcpu.sregs.ss.selector = pop(vcpu, layout, 8)? as u16;
+        vcpu.regs.rsp = rsp;
+    } else {
+        vcpu.sregs.cs.base = (cs as u64) << 4;
+    }
+    vcpu.sregs.cs.selector = cs;
+    vcpu.regs.rip = rip;
+    let mask = operand_mask(size);
+    vcpu.regs.rflags = ((vcpu.regs.rflags & !mask) | (rflags & mask)) | FLAG_FIXED;
+    Ok(())
+}
+
+fn stack_mask(vcpu: &MockVcpu) -> u64 {
+    if vcpu.sregs.is_long_mode() { u64::MAX } else { 0xFFFF }
+}
+
+fn push(vcpu: &mut MockVcpu, layout: &GuestMemoryLayout, size: u8, value: u64) -> Result<(), MemFault> {
+    let smask = stack_mask(vcpu);
+    let rsp = vcpu.regs.rsp.wrapping_sub(size as u64) & smask;
+    let linear = segment_base(&vcpu.sregs, Seg::Ss).wrapping_add(rsp);
+    write_linear(layout, &vcpu.sregs, linear, size, value)?;
+    vcpu.regs.rsp = (vcpu.regs.rsp & !smask) | rsp;
+    Ok(())
+}
+
+fn pop(vcpu: &mut MockVcpu, layout: &GuestMemoryLayout, size: u8) -> Result<u64, MemFault> {
+    let smask = stack_mask(vcpu);
+    let rsp = vcpu.regs.rsp & smask;
+    let linear = segment_base(&vcpu.sregs, Seg::Ss).wrapping_add(rsp);
+    let value = read_linear(layout, &vcpu.sregs, linear, size)?;
+    vcpu.regs.rsp = (vcpu.regs.rsp & !smask) | (rsp.wrapping_add(size as u64) & smask);
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::{Disposition, Vm, VmExitHandler};
+    use crate::vmx::ept::MemoryRegion;
+
+    fn real_mode_vm(code: &[u8]) -> MockBackend {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        backend.create_vcpu(0).unwrap();
+        backend.write_guest(0x1000, code).unwrap();
+        let sregs = SpecialRegisters::real_mode(0x100);
+        backend.set_special_registers(0, &sregs).unwrap();
+        let mut regs = backend.get_registers(0).unwrap();
+        regs.rip = 0;
+        regs.rsp = 0x8000;
+        backend.set_registers(0, &regs).unwrap();
+        backend
+    }
+
+    /// Identity-map the low 4 GiB with 1 GiB pages, tables at 0x10000.
+    fn long_mode_vm(code: &[u8]) -> MockBackend {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x40_0000).unwrap();
+        backend.create_vcpu(0).unwrap();
+        backend.write_guest(0x10000, &(0x11000u64 | 3).to_le_bytes()).unwrap();
+        for i in 0..4u64 {
+            backend.write_guest(0x11000 + i * 8, &((i << 30) | 0x83).to_le_bytes()).unwrap();
+        }
+        backend.write_guest(0x20_0000, code).unwrap();
+        backend.set_special_registers(0, &SpecialRegisters::long_mode(0x10000)).unwrap();
+        let mut regs = backend.get_registers(0).unwrap();
+        regs.rip = 0x20_0000;
+        regs.rsp = 0x30_0000;
+        backend.set_registers(0, &regs).unwrap();
+        backend
+    }
+
+    #[test]
+    fn test_real_mode_serial_output() {
+        // mov dx, 0x3f8; mov si, msg; mov cx, 2; cld; rep outsb; mov al, '!'; out dx, al; hlt; msg: "Hi"
+        let code = [
+            0xBA, 0xF8, 0x03, 0xBE, 0x11, 0x00, 0xB9, 0x02, 0x00, 0xFC, 0xF3, 0x6E, 0xB0, b'!', 0xEE, 0xF4,
+            0x90, b'H', b'i',
+        ];
+        let mut backend = real_mode_vm(&code);
+        // DS = 0 but the code lives at 0x1000, so point DS at it.
+        let mut sregs = backend.get_special_registers(0).unwrap();
+        sregs.ds = crate::hypervisor::Segment::real_mode(0x100, false);
+        backend.set_special_registers(0, &sregs).unwrap();
+
+        let mut out = Vec::new();
+        loop {
+            match backend.run(0).unwrap() {
+                VmExit::Io { port: 0x3F8, size: 1, direction: IoDirection::Out, data } => out.push(data as u8),
+                VmExit::Hlt => break,
+                other => panic!("unexpected exit {:?}", other),
+            }
+        }
+        assert_eq!(out, b"Hi!");
+        let regs = backend.get_registers(0).unwrap();
+        assert_eq!(regs.rcx & 0xFFFF, 0);
+        // HLT completes on the next run.
+        assert_eq!(regs.rip, 0xF);
+    }
+
+    #[test]
+    fn test_real_mode_in_and_loop() {
+        // xor bx, bx; mov cx, 3; l: in al, 0x60; add bl, al; loop l; hlt
+        let code = [0x31, 0xDB, 0xB9, 0x03, 0x00, 0xE4, 0x60, 0x00, 0xC3, 0xE2, 0xFA, 0xF4];
+        let mut backend = real_mode_vm(&code);
+        let mut reads = 0;
+        loop {
+            match backend.run(0).unwrap() {
+                VmExit::Io { port: 0x60, direction: IoDirection::In, .. } => {
+                    reads += 1;
+                    backend.complete(0, ExitResponse::Data(reads)).unwrap();
+                }
+                VmExit::Hlt => break,
+                other => panic!("unexpected exit {:?}", other),
+            }
+        }
+        assert_eq!(reads, 3);
+        assert_eq!(backend.get_registers(0).unwrap().rbx & 0xFF, 6);
+    }
+
+    #[test]
+    fn test_long_mode_cpuid_msr_and_mmio() {
+        let code = [
+            0x31, 0xC0, // xor eax, eax
+            0x0F, 0xA2, // cpuid
+            0x89, 0xDF, // mov edi, ebx
+            0xB9, 0x10, 0x00, 0x00, 0x00, // mov ecx, 0x10
+            0x0F, 0x32, // rdmsr
+            0x48, 0xBE, 0x00, 0x00, 0xE0, 0xFE, 0x00, 0x00, 0x00, 0x00, // mov rsi, 0xfee00000
+            0x8B, 0x5E, 0x20, // mov ebx, [rsi + 0x20]
+            0xC7, 0x46, 0x30, 0x78, 0x56, 0x34, 0x12, // mov dword [rsi + 0x30], 0x12345678
+            0x0F, 0x01, 0xC1, // vmcall
+            0xF4, // hlt
+        ];
+        let mut backend = long_mode_vm(&code);
+        backend.map_memory(MemoryRegion::mmio(0xFEE0_0000, 0x1000)).unwrap();
+
+        assert_eq!(backend.run(0).unwrap(), VmExit::Cpuid { leaf: 0, subleaf: 0 });
+        backend.complete(0, ExitResponse::Cpuid { eax: 0xd, ebx: 0x4879_7065, ecx: 0, edx: 0 }).unwrap();
+        assert_eq!(backend.run(0).unwrap(), VmExit::MsrRead { msr: 0x10 });
+        backend.complete(0, ExitResponse::Data(0x1_0000_0002)).unwrap();
+        assert_eq!(backend.run(0).unwrap(), VmExit::Mmio { gpa: 0xFEE0_0020, size: 4, write: false, data: 0 });
+        backend.complete(0, ExitResponse::Data(0x1400_0000)).unwrap();
+        assert_eq!(backend.run(0).unwrap(), VmExit::Mmio { gpa: 0xFEE0_0030, size: 4, write: true, data: 0x1234_5678 });
+        assert_eq!(backend.run(0).unwrap(), VmExit::Hypercall);
+        backend.complete(0, ExitResponse::Data(0)).unwrap();
+        assert_eq!(backend.run(0).unwrap(), VmExit::Hlt);
+
+        let regs = backend.get_registers(0).unwrap();
+        assert_eq!(regs.rdi, 0x4879_7065);
+        assert_eq!(regs.rax, 0);
+        assert_eq!(regs.rdx, 1);
+        assert_eq!(regs.rbx, 0x1400_0000);
+    }
+
+    #[test]
+    fn test_long_mode_stack_and_call() {
+        let code = [
+            0xE8, 0x02, 0x00, 0x00, 0x00, // call +2
+            0xF4, // hlt
+            0x90, // nop
+            0x48, 0xC7, 0xC0, 0x2A, 0x00, 0x00, 0x00, // mov rax, 42
+            0x50, // push rax
+            0x5B, // pop rbx
+            0xC3, // ret
+        ];
+        let mut backend = long_mode_vm(&code);
+        assert_eq!(backend.run(0).unwrap(), VmExit::Hlt);
+        let regs = backend.get_registers(0).unwrap();
+        assert_eq!(regs.rbx, 42);
+        assert_eq!(regs.rsp, 0x30_0000);
+        assert_eq!(regs.rip, 0x20_0005);
+    }
+
+    #[test]
+    fn test_invalid_opcode_and_step_limit() {
+        let mut backend = real_mode_vm(&[0x0F, 0x0B]);
+        assert_eq!(backend.run(0).unwrap(), VmExit::Unhandled { reason: MOCK_INVALID_OPCODE, qualification: 0 });
+
+        let mut backend = real_mode_vm(&[0xEB, 0xFE]);
+        backend.set_step_limit(100);
+        assert_eq!(backend.run(0).unwrap(), VmExit::Unhandled { reason: MOCK_STEP_LIMIT, qualification: 0 });
+        assert_eq!(backend.steps(), 100);
+    }
+
+    struct CountingHandler {
+        outs: usize,
+    }
+
+    impl VmExitHandler for CountingHandler {
+        fn handle(&mut self, _vcpu: VcpuId, exit: &VmExit) -> Disposition {
+            match exit {
+                VmExit::Io { direction: IoDirection::Out, .. } => {
+                    self.outs += 1;
+                    Disposition::Resume(ExitResponse::None)
+                }
+                _ => Disposition::Stop,
+            }
+        }
+    }
+
+    #[test]
+    fn test_vm_run_loop() {
+        // mov cx, 4; l: out 0x80, al; loop l; hlt
+        let code = [0xB9, 0x04, 0x00, 0xE6, 0x80, 0xE2, 0xFC, 0xF4];
+        let mut vm = Vm::new(real_mode_vm(&code));
+        let mut handler = CountingHandler { outs: 0 };
+        assert_eq!(vm.run_vcpu(0, &mut handler).unwrap(), VmExit::Hlt);
+        assert_eq!(handler.outs, 4);
+    }
+
+    #[test]
+    fn test_save_and_restore_state() {
+        // in al, 0x60; out 0x80, al; hlt
+        let code = [0xE4, 0x60, 0xE6, 0x80, 0xF4];
+        let mut backend = real_mode_vm(&code);
+        assert_eq!(backend.run(0).unwrap(), VmExit::Io { port: 0x60, size: 1, direction: IoDirection::In, data: 0 });
+        backend.complete(0, ExitResponse::Data(0x5A)).unwrap();
+        backend.inject_interrupt(0, 0x20).unwrap();
+        let mut state = backend.save_state(0).unwrap();
+        // The IN is completed before the state is taken.
+        assert_eq!(state.regs.rax & 0xFF, 0x5A);
+        assert_eq!(state.regs.rip, 2);
+        assert_eq!(state.events.pending.map(|event| event.vector), Some(0x20));
+
+        state.events.pending = None;
+        state.msrs.push((0x174, 0x8));
+        let state = VcpuState::decode(&state.encode()).unwrap();
+        let mut restored = real_mode_vm(&code);
+        restored.restore_state(0, &state).unwrap();
+        assert_eq!(restored.save_state(0).unwrap(), state);
+        assert_eq!(restored.run(0).unwrap(), VmExit::Io { port: 0x80, size: 1, direction: IoDirection::Out, data: 0x5A });
+        assert_eq!(restored.run(0).unwrap(), VmExit::Hlt);
+    }
+}
@@ -0,0 +1,707 @@
+// Backend-neutral VM lifecycle: a trait over "something that can run a vCPU"
+// with the VMX implementation in `vmx::backend` and a software interpreter
+// in `mock` so VM logic can be exercised on hosts without VT-x.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::vdev::irqchip::{SharedIrqChip, IA32_APIC_BASE};
+use crate::vmx::ept::{EptError, GuestMemoryLayout, MemoryRegion};
+use crate::vmx::VmxError;
+
+pub mod hypercall;
+pub mod machine;
+pub mod migration;
+pub mod mock;
+pub mod smp;
+pub mod snapshot;
+pub mod state;
+
+pub use hypercall::{Hypercall, HypercallContext, HypercallMemory, HypercallTable};
+pub use machine::Machine;
+pub use migration::{
+    ConvergencePolicy, Decision, IncomingMigration, MigrationError, MigrationReport, MigrationSender, MigrationTransport,
+    RoundStats, StopReason, TransportError,
+};
+pub use mock::MockBackend;
+pub use smp::{SharedCoordinator, VcpuCoordinator, VcpuRunState, MAX_VCPUS};
+pub use snapshot::{SnapshotError, SnapshotId, SnapshotInfo, SnapshotStore};
+pub use state::{EventKind, PendingEvent, StateError, VcpuEvents, VcpuState};
+
+pub type VcpuId = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HvError {
+    NoSuchVcpu(VcpuId),
+    VcpuExists(VcpuId),
+    Memory(EptError),
+    Vmx(VmxError),
+    /// A guest-physical access fell outside guest RAM.
+    BadGuestAddress(u64),
+    /// The hardware or backend refused the operation.
+    Backend(&'static str),
+}
+
+impl From<EptError> for HvError {
+    fn from(err: EptError) -> Self {
+        HvError::Memory(err)
+    }
+}
+
+impl From<VmxError> for HvError {
+    fn from(err: VmxError) -> Self {
+        HvError::Vmx(err)
+    }
+}
+
+/// General-purpose registers plus RIP/RFLAGS, as seen by the guest.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VcpuRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl VcpuRegisters {
+    /// Register by its x86 encoding (0 = RAX ... 15 = R15).
+    pub fn gpr(&self, index: u8) -> u64 {
+        match index & 0xF {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            4 => self.rsp,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            _ => self.r15,
+        }
+    }
+
+    pub fn set_gpr(&mut self, index: u8, value: u64) {
+        match index & 0xF {
+            0 => self.rax = value,
+            1 => self.rcx = value,
+            2 => self.rdx = value,
+            3 => self.rbx = value,
+            4 => self.rsp = value,
+            5 => self.rbp = value,
+            6 => self.rsi = value,
+            7 => self.rdi = value,
+            8 => self.r8 = value,
+            9 => self.r9 = value,
+            10 => self.r10 = value,
+            11 => self.r11 = value,
+            12 => self.r12 = value,
+            13 => self.r13 = value,
+            14 => self.r14 = value,
+            _ => self.r15 = value,
+        }
+    }
+
+    /// Read a `size`-byte register operand. Without a REX prefix, byte
+    /// registers 4-7 are AH, CH, DH and BH.
+    pub fn read_operand(&self, index: u8, size: u8, rex: bool) -> u64 {
+        if size == 1 && !rex && (4..8).contains(&index) {
+            return (self.gpr(index - 4) >> 8) & 0xFF;
+        }
+        self.gpr(index) & operand_mask(size)
+    }
+
+    /// Write a `size`-byte register operand. In 64-bit mode 32-bit writes
+    /// zero the upper half; narrower writes always preserve the rest.
+    pub fn write_operand(&mut self, index: u8, size: u8, rex: bool, long: bool, value: u64) {
+        if size == 1 && !rex && (4..8).contains(&index) {
+            let old = self.gpr(index - 4);
+            self.set_gpr(index - 4, (old & !0xFF00) | ((value & 0xFF) << 8));
+            return;
+        }
+        let new = match size {
+            8 => value,
+            4 if long => value & 0xFFFF_FFFF,
+            _ => (self.gpr(index) & !operand_mask(size)) | (value & operand_mask(size)),
+        };
+        self.set_gpr(index, new);
+    }
+}
+
+/// All ones for an operand of `size` bytes.
+pub fn operand_mask(size: u8) -> u64 {
+    if size >= 8 { u64::MAX } else { (1u64 << (size as u32 * 8)) - 1 }
+}
+
+/// Sign-extends the low `size` bytes of `value` to 64 bits.
+pub fn sign_extend(value: u64, size: u8) -> u64 {
+    let shift = 64 - size as u32 * 8;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+/// A segment register in VMX access-rights format.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub selector: u16,
+    pub base: u64,
+    pub limit: u32,
+    pub access_rights: u32,
+}
+
+impl Segment {
+    /// Access-rights bit 16: the segment is unusable.
+    pub const UNUSABLE: u32 = 1 << 16;
+
+    /// A real-mode segment: base = selector * 16, 64 KiB limit.
+    pub fn real_mode(selector: u16, code: bool) -> Self {
+        let seg_type = if code { 0xB } else { 0x3 };
+        Segment { selector, base: (selector as u64) << 4, limit: 0xFFFF, access_rights: seg_type | (1 << 4) | (1 << 7) }
+    }
+
+    /// A flat 64-bit code segment.
+    pub fn long_mode_code(selector: u16) -> Self {
+        Segment { selector, base: 0, limit: 0xFFFF_FFFF, access_rights: 0xB | (1 << 4) | (1 << 7) | (1 << 13) | (1 << 15) }
+    }
+
+    /// A flat 32-bit code segment.
+    pub fn flat_code(selector: u16) -> Self {
+        Segment { selector, base: 0, limit: 0xFFFF_FFFF, access_rights: 0xB | (1 << 4) | (1 << 7) | (1 << 14) | (1 << 15) }
+    }
+
+    /// A flat data segment.
+    pub fn flat_data(selector: u16) -> Self {
+        Segment { selector, base: 0, limit: 0xFFFF_FFFF, access_rights: 0x3 | (1 << 4) | (1 << 7) | (1 << 14) | (1 << 15) }
+    }
+
+    pub fn long_mode(&self) -> bool {
+        self.access_rights & (1 << 13) != 0
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DescriptorTable {
+    pub base: u64,
+    pub limit: u16,
+}
+
+/// Segment, descriptor-table and control registers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpecialRegisters {
+    pub cs: Segment,
+    pub ds: Segment,
+    pub es: Segment,
+    pub fs: Segment,
+    pub gs: Segment,
+    pub ss: Segment,
+    pub tr: Segment,
+    pub ldtr: Segment,
+    pub gdt: DescriptorTable,
+    pub idt: DescriptorTable,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+pub const CR0_PE: u64 = 1 << 0;
+pub const CR0_ET: u64 = 1 << 4;
+pub const CR0_NE: u64 = 1 << 5;
+pub const CR0_PG: u64 = 1 << 31;
+pub const CR4_PAE: u64 = 1 << 5;
+pub const EFER_LME: u64 = 1 << 8;
+pub const EFER_LMA: u64 = 1 << 10;
+
+impl SpecialRegisters {
+    /// State of a CPU right after reset, with CS:IP pointing at `cs` * 16.
+    pub fn real_mode(cs: u16) -> Self {
+        let data = Segment::real_mode(0, false);
+        let mut tr = Segment::real_mode(0, false);
+        tr.access_rights = 0x8B;
+        let mut ldtr = Segment::real_mode(0, false);
+        ldtr.access_rights = 0x82;
+        SpecialRegisters {
+            cs: Segment::real_mode(cs, true),
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr,
+            ldtr,
+            gdt: DescriptorTable { base: 0, limit: 0xFFFF },
+            idt: DescriptorTable { base: 0, limit: 0xFFFF },
+            cr0: CR0_ET,
+            ..Default::default()
+        }
+    }
+
+    /// Flat 64-bit mode with paging rooted at `cr3`.
+    pub fn long_mode(cr3: u64) -> Self {
+        let data = Segment::flat_data(0x10);
+        let tr = Segment { selector: 0x18, base: 0, limit: 0x67, access_rights: 0x8B };
+        let ldtr = Segment { access_rights: Segment::UNUSABLE, ..Default::default() };
+        SpecialRegisters {
+            cs: Segment::long_mode_code(0x08),
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr,
+            ldtr,
+            gdt: DescriptorTable::default(),
+            idt: DescriptorTable::default(),
+            cr0: CR0_PE | CR0_ET | CR0_NE | CR0_PG,
+            cr2: 0,
+            cr3,
+            cr4: CR4_PAE,
+            efer: EFER_LME | EFER_LMA,
+        }
+    }
+
+    pub fn is_long_mode(&self) -> bool {
+        self.efer & EFER_LMA != 0 && self.cs.long_mode()
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.cr0 & CR0_PE != 0
+    }
+}
+
+/// Translate a guest linear address to guest-physical using the guest's own
+/// paging mode. `read_entry` reads a page-table entry at a guest-physical
+/// address. Only no paging and 4-level paging are supported; returns `None`
+/// for a non-present page or an unsupported mode.
+pub fn translate_linear<F: Fn(u64) -> Option<u64>>(sregs: &SpecialRegisters, linear: u64, read_entry: F) -> Option<u64> {
+    if sregs.cr0 & CR0_PG == 0 {
+        return Some(linear & 0xFFFF_FFFF);
+    }
+    if sregs.efer & EFER_LMA == 0 {
+        return None;
+    }
+    let mut table = sregs.cr3 & 0x000F_FFFF_FFFF_F000;
+    for level in (0..4).rev() {
+        let index = (linear >> (12 + 9 * level)) & 0x1FF;
+        let entry = read_entry(table + index * 8)?;
+        if entry & 1 == 0 {
+            return None;
+        }
+        let addr = entry & 0x000F_FFFF_FFFF_F000;
+        if level == 0 || (level <= 2 && entry & (1 << 7) != 0) {
+            let page_mask = (1u64 << (12 + 9 * level)) - 1;
+            return Some((addr & !page_mask) | (linear & page_mask));
+        }
+        table = addr;
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoDirection {
+    In,
+    Out,
+}
+
+/// Why a vCPU stopped running, in backend-neutral terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExit {
+    /// Port I/O. `data` holds the value written for `Out`; the value for
+    /// `In` is supplied with [`ExitResponse::Data`].
+    Io { port: u16, size: u8, direction: IoDirection, data: u32 },
+    /// Access to guest-physical memory with no RAM behind it.
+    Mmio { gpa: u64, size: u8, write: bool, data: u64 },
+    Cpuid { leaf: u32, subleaf: u32 },
+    MsrRead { msr: u32 },
+    MsrWrite { msr: u32, value: u64 },
+    /// VMCALL; arguments are in the guest registers.
+    Hypercall,
+    Hlt,
+    /// The guest can take an interrupt again; requested with
+    /// [`HypervisorBackend::set_interrupt_window`].
+    InterruptWindow,
+    Shutdown,
+    /// The run was cut short by a host interrupt, such as a kick from
+    /// another vCPU's thread. Nothing needs completing.
+    Interrupted,
+    /// Anything the backend cannot express above, with a backend-specific
+    /// reason code (the basic exit reason on VMX).
+    Unhandled { reason: u32, qualification: u64 },
+}
+
+/// Data returned to the guest to complete the last exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitResponse {
+    /// Nothing to return; the instruction completes as-is.
+    None,
+    /// Value for IN, an MMIO read or RDMSR.
+    Data(u64),
+    Cpuid { eax: u32, ebx: u32, ecx: u32, edx: u32 },
+}
+
+/// A hypervisor implementation able to run guest vCPUs.
+///
+/// After [`run`](Self::run) returns an exit, the exiting instruction is
+/// completed on the next call to `run`, using whatever was passed to
+/// [`complete`](Self::complete) in between (the KVM `kvm_run` model).
+/// Exits that need data and get none read as all ones (I/O, MMIO) or
+/// zero (MSRs, CPUID).
+pub trait HypervisorBackend {
+    fn create_vcpu(&mut self, id: VcpuId) -> Result<(), HvError>;
+
+    fn get_registers(&self, vcpu: VcpuId) -> Result<VcpuRegisters, HvError>;
+    fn set_registers(&mut self, vcpu: VcpuId, regs: &VcpuRegisters) -> Result<(), HvError>;
+    fn get_special_registers(&self, vcpu: VcpuId) -> Result<SpecialRegisters, HvError>;
+    fn set_special_registers(&mut self, vcpu: VcpuId, sregs: &SpecialRegisters) -> Result<(), HvError>;
+
+    /// Add a region to the guest-physical address space. For RAM regions,
+    /// `host_phys` is the host address backing the region.
+    fn map_memory(&mut self, region: MemoryRegion) -> Result<(), HvError>;
+    fn memory_layout(&self) -> &GuestMemoryLayout;
+
+    fn read_guest(&self, gpa: u64, buf: &mut [u8]) -> Result<(), HvError>;
+    fn write_guest(&mut self, gpa: u64, data: &[u8]) -> Result<(), HvError>;
+
+    fn run(&mut self, vcpu: VcpuId) -> Result<VmExit, HvError>;
+    fn complete(&mut self, vcpu: VcpuId, response: ExitResponse) -> Result<(), HvError>;
+
+    /// Whether the guest would take an interrupt injected now: RFLAGS.IF is
+    /// set, no STI or MOV SS shadow is in effect once the last exit is
+    /// completed, and no other event is already queued.
+    fn interrupt_ready(&self, vcpu: VcpuId) -> Result<bool, HvError>;
+    /// Deliver external interrupt `vector` through the guest IDT on the next
+    /// `run`. Only call this when [`interrupt_ready`](Self::interrupt_ready).
+    fn inject_interrupt(&mut self, vcpu: VcpuId, vector: u8) -> Result<(), HvError>;
+    /// While enabled, `run` returns [`VmExit::InterruptWindow`] as soon as
+    /// the guest can take an interrupt.
+    fn set_interrupt_window(&mut self, vcpu: VcpuId, enabled: bool) -> Result<(), HvError>;
+
+    /// Drop cached guest translations for `vcpu`, the per-vCPU half of a
+    /// TLB shootdown.
+    fn flush_tlb(&mut self, vcpu: VcpuId) -> Result<(), HvError>;
+
+    /// Capture `vcpu`'s registers, MSRs and event state. The instruction
+    /// behind the last exit is completed first, so the state resumes
+    /// cleanly on any backend. LAPIC state belongs to the irqchip and is
+    /// left `None`.
+    fn save_state(&mut self, vcpu: VcpuId) -> Result<VcpuState, HvError>;
+    /// Load state from [`save_state`](Self::save_state), dropping any exit
+    /// still waiting to be completed.
+    fn restore_state(&mut self, vcpu: VcpuId, state: &VcpuState) -> Result<(), HvError>;
+
+    /// Start logging which pages of guest RAM are written.
+    fn enable_dirty_log(&mut self) -> Result<(), HvError>;
+    /// Guest-physical addresses of the 4 KiB RAM pages written since the
+    /// log was enabled or last taken, in ascending order; taking them
+    /// resets the log. vCPUs must be stopped and need a TLB flush before
+    /// they run again, which [`Vm::take_dirty_pages`] arranges.
+    fn take_dirty_pages(&mut self) -> Result<Vec<u64>, HvError>;
+}
+
+/// What a [`VmExitHandler`] wants done with an exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Resume(ExitResponse),
+    Stop,
+}
+
+/// Backend-neutral exit handling, used by [`Vm::run_vcpu`].
+pub trait VmExitHandler {
+    fn handle(&mut self, vcpu: VcpuId, exit: &VmExit) -> Disposition;
+}
+
+/// A VM: guest memory plus vCPUs on some backend.
+pub struct Vm<B: HypervisorBackend> {
+    backend: B,
+    vcpus: Vec<VcpuId>,
+    irqchip: Option<SharedIrqChip>,
+    hypercalls: Option<HypercallTable>,
+    coordinator: SharedCoordinator,
+}
+
+impl<B: HypervisorBackend> Vm<B> {
+    pub fn new(backend: B) -> Self {
+        Self::with_coordinator(backend, Arc::new(VcpuCoordinator::new()))
+    }
+
+    /// A VM whose vCPU threads are coordinated through `coordinator`, which
+    /// knows how to kick them on this host.
+    pub fn with_coordinator(backend: B, coordinator: SharedCoordinator) -> Self {
+        Vm { backend, vcpus: Vec::new(), irqchip: None, hypercalls: None, coordinator }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    pub fn vcpus(&self) -> &[VcpuId] {
+        &self.vcpus
+    }
+
+    pub fn add_vcpu(&mut self) -> Result<VcpuId, HvError> {
+        let id = self.vcpus.len() as VcpuId;
+        if id as usize >= MAX_VCPUS {
+            return Err(HvError::Backend("too many vCPUs"));
+        }
+        self.backend.create_vcpu(id)?;
+        self.vcpus.push(id);
+        self.coordinator.add(id);
+        Ok(id)
+    }
+
+    /// Add vCPUs until the VM has `count`.
+    pub fn add_vcpus(&mut self, count: usize) -> Result<(), HvError> {
+        while self.vcpus.len() < count {
+            self.add_vcpu()?;
+        }
+        Ok(())
+    }
+
+    /// Cross-vCPU requests: pause-all, TLB shootdown and wakeups.
+    pub fn coordinator(&self) -> &SharedCoordinator {
+        &self.coordinator
+    }
+
+    pub fn add_memory(&mut self, region: MemoryRegion) -> Result<(), HvError> {
+        self.backend.map_memory(region)
+    }
+
+    /// Attach the VM's interrupt controllers. From then on `run_vcpu`
+    /// answers LAPIC, IOAPIC and IA32_APIC_BASE exits itself and injects
+    /// pending interrupts before each entry.
+    pub fn set_irqchip(&mut self, chip: SharedIrqChip) {
+        self.irqchip = Some(chip);
+    }
+
+    pub fn irqchip(&self) -> Option<&SharedIrqChip> {
+        self.irqchip.as_ref()
+    }
+
+    /// Let the guest make hypercalls. From then on `run_vcpu` answers VMCALL
+    /// exits from `table` instead of passing them to the handler. The CPU
+    /// model should advertise the table with [`HypercallTable::cpuid`].
+    pub fn set_hypercalls(&mut self, table: HypercallTable) {
+        self.hypercalls = Some(table);
+    }
+
+    pub fn hypercalls(&self) -> Option<&HypercallTable> {
+        self.hypercalls.as_ref()
+    }
+
+    pub fn hypercalls_mut(&mut self) -> Option<&mut HypercallTable> {
+        self.hypercalls.as_mut()
+    }
+
+    /// Make the hypercall `vcpu` exited for, returning RAX to complete it with.
+    fn hypercall(&mut self, vcpu: VcpuId) -> Result<Option<ExitResponse>, HvError> {
+        let table = match &mut self.hypercalls {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let mut regs = self.backend.get_registers(vcpu)?;
+        let sregs = self.backend.get_special_registers(vcpu)?;
+        table.dispatch(vcpu, &mut regs, &sregs, &mut self.backend);
+        self.backend.set_registers(vcpu, &regs)?;
+        Ok(Some(ExitResponse::Data(regs.rax)))
+    }
+
+    /// Capture `vcpu`'s complete state, with its LAPIC and IA32_APIC_BASE
+    /// when an irqchip is attached. The vCPU must not be running, e.g.
+    /// held by [`VcpuCoordinator::pause_all`].
+    pub fn save_vcpu(&mut self, vcpu: VcpuId) -> Result<VcpuState, HvError> {
+        let mut state = self.backend.save_state(vcpu)?;
+        if let Some(chip) = &self.irqchip {
+            let (lapic, apic_base) = chip.lock().save_lapic(vcpu).ok_or(HvError::NoSuchVcpu(vcpu))?;
+            state.lapic = Some(lapic);
+            state.msrs.push((IA32_APIC_BASE, apic_base));
+        }
+        Ok(state)
+    }
+
+    /// Load state from [`save_vcpu`](Self::save_vcpu) into a stopped vCPU.
+    pub fn restore_vcpu(&mut self, vcpu: VcpuId, state: &VcpuState) -> Result<(), HvError> {
+        let apic_base = state.msr(IA32_APIC_BASE);
+        let msrs = state.msrs.iter().copied().filter(|&(msr, _)| msr != IA32_APIC_BASE).collect();
+        self.backend.restore_state(vcpu, &VcpuState { msrs, ..state.clone() })?;
+        if let (Some(chip), Some(lapic)) = (&self.irqchip, &state.lapic) {
+            let apic_base = apic_base.ok_or(HvError::Backend("LAPIC state without IA32_APIC_BASE"))?;
+            if !chip.lock().restore_lapic(vcpu, lapic, apic_base) {
+                return Err(HvError::NoSuchVcpu(vcpu));
+            }
+        }
+        Ok(())
+    }
+
+    /// Start logging guest writes to RAM, for incremental snapshots and
+    /// live migration.
+    pub fn enable_dirty_log(&mut self) -> Result<(), HvError> {
+        self.backend.enable_dirty_log()
+    }
+
+    /// Pages written since the dirty log was enabled or last taken. Every
+    /// vCPU gets a TLB flush on its next entry so later writes are logged.
+    pub fn take_dirty_pages(&mut self) -> Result<Vec<u64>, HvError> {
+        let pages = self.backend.take_dirty_pages()?;
+        self.coordinator.flush_tlb(&self.vcpus);
+        Ok(pages)
+    }
+
+    /// Start `vcpu` if it has received a startup IPI. Returns whether it may
+    /// run, i.e. it is not still waiting for one.
+    fn startup(&mut self, vcpu: VcpuId) -> Result<bool, HvError> {
+        let mut chip = match &self.irqchip {
+            Some(chip) => chip.lock(),
+            None => return Ok(true),
+        };
+        if let Some(vector) = chip.take_startup(vcpu) {
+            self.backend.set_special_registers(vcpu, &SpecialRegisters::real_mode((vector as u16) << 8))?;
+            self.backend.set_registers(vcpu, &VcpuRegisters { rflags: 1 << 1, ..Default::default() })?;
+        }
+        Ok(!chip.is_waiting_for_sipi(vcpu))
+    }
+
+    /// Inject the highest-priority pending interrupt if the guest can take
+    /// it, or ask for an interrupt-window exit if it cannot.
+    fn inject_pending(&mut self, vcpu: VcpuId) -> Result<(), HvError> {
+        let mut chip = match &self.irqchip {
+            Some(chip) => chip.lock(),
+            None => return Ok(()),
+        };
+        let vector = match chip.pending_interrupt(vcpu) {
+            Some(vector) => vector,
+            None => return self.backend.set_interrupt_window(vcpu, false),
+        };
+        if self.backend.interrupt_ready(vcpu)? {
+            chip.acknowledge(vcpu, vector);
+            self.backend.inject_interrupt(vcpu, vector)?;
+            self.backend.set_interrupt_window(vcpu, false)
+        } else {
+            self.backend.set_interrupt_window(vcpu, true)
+        }
+    }
+
+    /// Exits the interrupt controllers deal with, without the caller's handler.
+    fn irqchip_exit(&mut self, vcpu: VcpuId, exit: &VmExit) -> Result<Option<ExitResponse>, HvError> {
+        let chip = match &self.irqchip {
+            Some(chip) => chip,
+            None => return Ok(None),
+        };
+        Ok(match exit {
+            VmExit::InterruptWindow => Some(ExitResponse::None),
+            // A halted vCPU with an interrupt to take wakes up straight away.
+            VmExit::Hlt => {
+                let pending = chip.lock().pending_interrupt(vcpu).is_some();
+                if pending && self.backend.interrupt_ready(vcpu)? { Some(ExitResponse::None) } else { None }
+            }
+            exit => chip.lock().handle_exit(vcpu, exit),
+        })
+    }
+
+    /// Wake the other vCPUs an IPI or interrupt routing change from `vcpu`
+    /// left something to do.
+    fn wake_targets(&self, vcpu: VcpuId) {
+        let mut chip = match &self.irqchip {
+            Some(chip) => chip.lock(),
+            None => return,
+        };
+        for &target in self.vcpus.iter().filter(|&&target| target != vcpu) {
+            if chip.has_pending_event(target) {
+                self.coordinator.wake(target);
+            }
+        }
+    }
+
+    /// Run `vcpu` until the handler stops it, returning the final exit.
+    ///
+    /// A vCPU held by [`VcpuCoordinator::pause_all`] does not run and
+    /// reports [`VmExit::Hlt`]; so does, with an irqchip attached, an
+    /// application processor that has not yet received its startup IPI.
+    pub fn run_vcpu(&mut self, vcpu: VcpuId, handler: &mut dyn VmExitHandler) -> Result<VmExit, HvError> {
+        loop {
+            let flush_tlb = match self.coordinator.begin_entry(vcpu) {
+                smp::Entry::Paused => return Ok(VmExit::Hlt),
+                smp::Entry::Run { flush_tlb } => flush_tlb,
+            };
+            match self.enter(vcpu, flush_tlb, handler) {
+                Ok(Some(exit)) => return Ok(exit),
+                Ok(None) => {}
+                // However far it got, the vCPU is not in the guest now, and
+                // must not hold up `pause_all`.
+                Err(err) => {
+                    self.coordinator.end_entry(vcpu, false);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// One entry into the guest, which the coordinator has allowed, and
+    /// the handling of its exit. Returns the exit if `run_vcpu` should stop.
+    fn enter(
+        &mut self,
+        vcpu: VcpuId,
+        flush_tlb: bool,
+        handler: &mut dyn VmExitHandler,
+    ) -> Result<Option<VmExit>, HvError> {
+        if flush_tlb {
+            self.backend.flush_tlb(vcpu)?;
+        }
+        if !self.startup(vcpu)? {
+            self.coordinator.end_entry(vcpu, true);
+            return Ok(Some(VmExit::Hlt));
+        }
+        self.inject_pending(vcpu)?;
+        let exit = self.backend.run(vcpu)?;
+        if exit == VmExit::Interrupted {
+            self.coordinator.end_entry(vcpu, false);
+            return Ok(None);
+        }
+        if exit == VmExit::Hypercall {
+            if let Some(response) = self.hypercall(vcpu)? {
+                self.backend.complete(vcpu, response)?;
+                self.coordinator.end_entry(vcpu, false);
+                return Ok(None);
+            }
+        }
+        if let Some(response) = self.irqchip_exit(vcpu, &exit)? {
+            self.backend.complete(vcpu, response)?;
+            if matches!(exit, VmExit::Mmio { write: true, .. }) {
+                self.wake_targets(vcpu);
+            }
+            self.coordinator.end_entry(vcpu, false);
+            return Ok(None);
+        }
+        let disposition = handler.handle(vcpu, &exit);
+        self.coordinator.end_entry(vcpu, disposition == Disposition::Stop && exit == VmExit::Hlt);
+        match disposition {
+            Disposition::Resume(response) => {
+                self.backend.complete(vcpu, response)?;
+                Ok(None)
+            }
+            Disposition::Stop => Ok(Some(exit)),
+        }
+    }
+}
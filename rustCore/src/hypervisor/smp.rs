@@ -0,0 +1,357 @@
+// Coordination between the vCPUs of one VM. Each vCPU thread checks in
+// before every entry; other threads post requests (pause, TLB flush) and
+// wake or kick the target so it sees them promptly.
+//
+// A kick is whatever makes a running vCPU exit to its thread: on VMX an
+// IPI to the physical CPU it runs on, which the external-interrupt exit
+// turns into `VmExit::Interrupted`.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+use super::VcpuId;
+
+/// Most vCPUs a VM may have.
+pub const MAX_VCPUS: usize = 64;
+
+const REQUEST_FLUSH_TLB: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum VcpuRunState {
+    /// Not created, or stopped for good.
+    Stopped = 0,
+    /// Ready to enter the guest when its thread is scheduled.
+    Runnable = 1,
+    /// In the guest, or about to enter it.
+    Running = 2,
+    /// Halted in the guest, waiting for an interrupt.
+    Halted = 3,
+    /// Held outside the guest by [`VcpuCoordinator::pause_all`].
+    Paused = 4,
+}
+
+impl VcpuRunState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => VcpuRunState::Runnable,
+            2 => VcpuRunState::Running,
+            3 => VcpuRunState::Halted,
+            4 => VcpuRunState::Paused,
+            _ => VcpuRunState::Stopped,
+        }
+    }
+}
+
+/// What a vCPU thread must do before entering the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    /// Stay out of the guest until the VM is resumed.
+    Paused,
+    /// Enter, flushing guest TLB entries first if `flush_tlb`.
+    Run { flush_tlb: bool },
+}
+
+#[derive(Default)]
+struct Slot {
+    state: AtomicU8,
+    requests: AtomicU32,
+}
+
+pub type SharedCoordinator = Arc<VcpuCoordinator>;
+
+pub struct VcpuCoordinator {
+    slots: Vec<Slot>,
+    paused: AtomicBool,
+    kick: Option<Box<dyn Fn(VcpuId) + Send + Sync>>,
+}
+
+impl VcpuCoordinator {
+    /// A coordinator whose kicks do nothing, for backends that return to
+    /// their thread between instructions anyway.
+    pub fn new() -> Self {
+        VcpuCoordinator { slots: (0..MAX_VCPUS).map(|_| Slot::default()).collect(), paused: AtomicBool::new(false), kick: None }
+    }
+
+    /// A coordinator that calls `kick` to force a running vCPU out of the
+    /// guest.
+    pub fn with_kick(kick: Box<dyn Fn(VcpuId) + Send + Sync>) -> Self {
+        VcpuCoordinator { kick: Some(kick), ..Self::new() }
+    }
+
+    fn slot(&self, vcpu: VcpuId) -> Option<&Slot> {
+        self.slots.get(vcpu as usize)
+    }
+
+    pub fn state(&self, vcpu: VcpuId) -> VcpuRunState {
+        self.slot(vcpu).map_or(VcpuRunState::Stopped, |slot| VcpuRunState::from_u8(slot.state.load(Ordering::SeqCst)))
+    }
+
+    fn set_state(&self, vcpu: VcpuId, state: VcpuRunState) {
+        if let Some(slot) = self.slot(vcpu) {
+            slot.state.store(state as u8, Ordering::SeqCst);
+        }
+    }
+
+    /// `vcpu` exists and may be scheduled.
+    pub fn add(&self, vcpu: VcpuId) {
+        self.set_state(vcpu, VcpuRunState::Runnable);
+    }
+
+    /// `vcpu` will not run again.
+    pub fn stop(&self, vcpu: VcpuId) {
+        self.set_state(vcpu, VcpuRunState::Stopped);
+    }
+
+    /// Make a halted `vcpu` runnable, or kick it if it is in the guest, so
+    /// it notices a new interrupt or request.
+    pub fn wake(&self, vcpu: VcpuId) {
+        let slot = match self.slot(vcpu) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let halted = slot.state.compare_exchange(
+            VcpuRunState::Halted as u8,
+            VcpuRunState::Runnable as u8,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        if halted == Err(VcpuRunState::Running as u8) {
+            if let Some(kick) = &self.kick {
+                kick(vcpu);
+            }
+        }
+    }
+
+    /// Called by `vcpu`'s thread before each entry. Marks it running and
+    /// hands over the requests posted since the last entry; requests
+    /// posted from now on come with a kick.
+    pub fn begin_entry(&self, vcpu: VcpuId) -> Entry {
+        let slot = match self.slot(vcpu) {
+            Some(slot) => slot,
+            None => return Entry::Paused,
+        };
+        if self.paused.load(Ordering::SeqCst) {
+            slot.state.store(VcpuRunState::Paused as u8, Ordering::SeqCst);
+            return Entry::Paused;
+        }
+        slot.state.store(VcpuRunState::Running as u8, Ordering::SeqCst);
+        // A pause that raced with the store above is seen here or kicks us.
+        if self.paused.load(Ordering::SeqCst) {
+            slot.state.store(VcpuRunState::Paused as u8, Ordering::SeqCst);
+            return Entry::Paused;
+        }
+        let requests = slot.requests.swap(0, Ordering::SeqCst);
+        Entry::Run { flush_tlb: requests & REQUEST_FLUSH_TLB != 0 }
+    }
+
+    /// Called by `vcpu`'s thread when the guest exits to it. A vCPU that
+    /// stops on HLT passes `halted`, and stays off the run queue until
+    /// [`wake`](Self::wake).
+    pub fn end_entry(&self, vcpu: VcpuId, halted: bool) {
+        let state = if halted { VcpuRunState::Halted } else { VcpuRunState::Runnable };
+        if let Some(slot) = self.slot(vcpu) {
+            // Leave Paused and Stopped alone.
+            let _ = slot.state.compare_exchange(
+                VcpuRunState::Running as u8,
+                state as u8,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+        }
+    }
+
+    /// Stop every vCPU at its next exit and keep them out of the guest.
+    /// Poll [`all_paused`](Self::all_paused) to know when they are out.
+    pub fn pause_all(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+        for vcpu in 0..self.slots.len() as VcpuId {
+            self.wake(vcpu);
+        }
+    }
+
+    /// Whether no vCPU is in the guest, so VM-wide state can be changed.
+    pub fn all_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+            && (0..self.slots.len() as VcpuId).all(|vcpu| self.state(vcpu) != VcpuRunState::Running)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn resume_all(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        for slot in &self.slots {
+            let _ = slot.state.compare_exchange(
+                VcpuRunState::Paused as u8,
+                VcpuRunState::Runnable as u8,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+        }
+    }
+
+    /// TLB shootdown: have each of `vcpus` flush its guest TLB before it
+    /// next enters the guest, kicking those in it now. The old mappings
+    /// are gone once [`flush_pending`](Self::flush_pending) is false for
+    /// every target.
+    pub fn flush_tlb(&self, vcpus: &[VcpuId]) {
+        for &vcpu in vcpus {
+            if let Some(slot) = self.slot(vcpu) {
+                slot.requests.fetch_or(REQUEST_FLUSH_TLB, Ordering::SeqCst);
+                if self.state(vcpu) == VcpuRunState::Running {
+                    if let Some(kick) = &self.kick {
+                        kick(vcpu);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether `vcpu` may still be using translations from before the last
+    /// [`flush_tlb`](Self::flush_tlb). Only a vCPU in the guest can.
+    pub fn flush_pending(&self, vcpu: VcpuId) -> bool {
+        self.slot(vcpu).is_some_and(|slot| slot.requests.load(Ordering::SeqCst) & REQUEST_FLUSH_TLB != 0)
+            && self.state(vcpu) == VcpuRunState::Running
+    }
+}
+
+impl Default for VcpuCoordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::sync::atomic::AtomicU64;
+
+    use spin::Mutex;
+
+    use crate::hypervisor::{Disposition, ExitResponse, HvError, HypervisorBackend, MockBackend, SpecialRegisters, VcpuRegisters, Vm, VmExit, VmExitHandler};
+    use crate::vdev::irqchip::IrqChip;
+    use crate::vdev::lapic::LAPIC_BASE;
+
+    fn kicks() -> (VcpuCoordinator, Arc<Mutex<Vec<VcpuId>>>) {
+        let kicked = Arc::new(Mutex::new(Vec::new()));
+        let log = kicked.clone();
+        (VcpuCoordinator::with_kick(Box::new(move |vcpu| log.lock().push(vcpu))), kicked)
+    }
+
+    #[test]
+    fn test_pause_and_shootdown() {
+        let (coordinator, kicked) = kicks();
+        for vcpu in 0..4 {
+            coordinator.add(vcpu);
+        }
+        assert_eq!(coordinator.begin_entry(1), Entry::Run { flush_tlb: false });
+        assert_eq!(coordinator.begin_entry(2), Entry::Run { flush_tlb: false });
+        coordinator.end_entry(2, true);
+        assert_eq!(coordinator.state(2), VcpuRunState::Halted);
+
+        // vCPU 1 is in the guest and gets kicked; 2 flushes when it wakes.
+        coordinator.flush_tlb(&[1, 2]);
+        assert_eq!(*kicked.lock(), [1]);
+        assert!(coordinator.flush_pending(1));
+        assert!(!coordinator.flush_pending(2));
+        coordinator.end_entry(1, false);
+        assert_eq!(coordinator.begin_entry(1), Entry::Run { flush_tlb: true });
+        assert!(!coordinator.flush_pending(1));
+
+        coordinator.pause_all();
+        assert_eq!(*kicked.lock(), [1, 1]);
+        assert!(!coordinator.all_paused());
+        coordinator.end_entry(1, false);
+        assert!(coordinator.all_paused());
+        assert_eq!(coordinator.begin_entry(1), Entry::Paused);
+        // The halted vCPU was woken to park itself.
+        assert_eq!(coordinator.begin_entry(2), Entry::Paused);
+        coordinator.resume_all();
+        assert_eq!(coordinator.state(2), VcpuRunState::Runnable);
+        assert_eq!(coordinator.begin_entry(2), Entry::Run { flush_tlb: true });
+    }
+
+    #[test]
+    fn test_failed_entry_does_not_block_pause() {
+        let coordinator = Arc::new(VcpuCoordinator::new());
+        let mut vm = Vm::with_coordinator(MockBackend::new(), coordinator.clone());
+        vm.set_irqchip(IrqChip::shared(1, Box::new(|| 0)));
+        // Known to the coordinator but not the backend, so getting the vCPU
+        // ready for interrupts fails once it is marked running.
+        coordinator.add(0);
+        assert_eq!(vm.run_vcpu(0, &mut Ports(Vec::new())), Err(HvError::NoSuchVcpu(0)));
+        assert_eq!(coordinator.state(0), VcpuRunState::Runnable);
+        coordinator.pause_all();
+        assert!(coordinator.all_paused());
+    }
+
+    /// Records which vCPUs wrote to a port; stops on anything else.
+    struct Ports(Vec<VcpuId>);
+
+    impl VmExitHandler for Ports {
+        fn handle(&mut self, vcpu: VcpuId, exit: &VmExit) -> Disposition {
+            match exit {
+                VmExit::Io { .. } => {
+                    self.0.push(vcpu);
+                    Disposition::Resume(ExitResponse::None)
+                }
+                _ => Disposition::Stop,
+            }
+        }
+    }
+
+    #[test]
+    fn test_four_vcpu_vm() {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        // BSP: enable the LAPIC, send INIT and SIPI (vector 2) to all
+        // others, and halt.
+        let mut code = vec![0x66, 0xBB];
+        code.extend_from_slice(&(LAPIC_BASE as u32).to_le_bytes());
+        for (offset, value) in [(0xF0u32, 0x1FFu32), (0x300, 0x000C_4500), (0x300, 0x000C_4602)] {
+            code.extend_from_slice(&[0x67, 0x66, 0xC7, 0x83]);
+            code.extend_from_slice(&offset.to_le_bytes());
+            code.extend_from_slice(&value.to_le_bytes());
+        }
+        code.push(0xF4);
+        backend.write_guest(0x1000, &code).unwrap();
+        // APs start at 0x2000: out 0x80, al; hlt; hlt
+        backend.write_guest(0x2000, &[0xE6, 0x80, 0xF4, 0xF4]).unwrap();
+
+        let mut vm = Vm::new(backend);
+        vm.add_vcpus(4).unwrap();
+        assert_eq!(vm.vcpus(), [0, 1, 2, 3]);
+        let time = Arc::new(AtomicU64::new(0));
+        vm.set_irqchip(IrqChip::shared(4, Box::new(move || time.load(Ordering::Relaxed))));
+        vm.backend_mut().set_special_registers(0, &SpecialRegisters::real_mode(0)).unwrap();
+        vm.backend_mut().set_registers(0, &VcpuRegisters { rip: 0x1000, rflags: 2, ..Default::default() }).unwrap();
+        let coordinator = vm.coordinator().clone();
+        let mut ports = Ports(Vec::new());
+
+        // An AP that has not had its SIPI yet does not run.
+        assert_eq!(vm.run_vcpu(3, &mut ports).unwrap(), VmExit::Hlt);
+        assert_eq!(coordinator.state(3), VcpuRunState::Halted);
+        assert_eq!(vm.run_vcpu(0, &mut ports).unwrap(), VmExit::Hlt);
+        // The SIPI woke the APs.
+        for vcpu in 1..4 {
+            assert_eq!(coordinator.state(vcpu), VcpuRunState::Runnable);
+            assert_eq!(vm.run_vcpu(vcpu, &mut ports).unwrap(), VmExit::Hlt);
+        }
+        assert_eq!(ports.0, [1, 2, 3]);
+
+        coordinator.flush_tlb(&[0, 1, 2, 3]);
+        coordinator.pause_all();
+        assert!(coordinator.all_paused());
+        assert_eq!(vm.run_vcpu(2, &mut ports).unwrap(), VmExit::Hlt);
+        assert_eq!(coordinator.state(2), VcpuRunState::Paused);
+        assert_eq!(vm.backend().tlb_flushes(2), 0);
+        coordinator.resume_all();
+        vm.run_vcpu(2, &mut ports).unwrap();
+        assert_eq!(vm.backend().tlb_flushes(2), 1);
+    }
+}
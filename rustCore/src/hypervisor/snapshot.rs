@@ -0,0 +1,480 @@
+// VM snapshots on a `StorageBackend`: vCPU state plus guest RAM.
+//
+// Each snapshot stores the RAM pages that changed since its parent, found
+// through the backend's dirty log; the first snapshot taken by a store has
+// no parent and holds every page. Restoring walks from the chosen snapshot
+// back to its root and takes each page from the newest snapshot that has it.
+//
+// On-disk layout, in blocks of the store's block size:
+//
+//   block 0     superblock: magic, version, block size, next free block and
+//               the newest record
+//   page data   one 4 KiB guest page each, written before the record that
+//               names it
+//   records     vCPU state and page index of one snapshot, linked to the
+//               record written before it (for listing) and to its parent
+//               (for restoring)
+//
+// The superblock is written last, so a snapshot cut short by a failure is
+// never reachable.
+
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{HvError, HypervisorBackend, StateError, VcpuState, Vm};
+use crate::codec::{self, Writer};
+use crate::storage::StorageBackend;
+use crate::vmx::ept::RegionKind;
+
+const SUPER_MAGIC: [u8; 4] = *b"HCMS";
+const RECORD_MAGIC: [u8; 4] = *b"HCSR";
+const FORMAT_VERSION: u16 = 1;
+const PAGE_SIZE: usize = 0x1000;
+/// Magic and total length, enough to size the rest of a record.
+const RECORD_PREFIX: usize = 8;
+
+/// A snapshot, named by the block its record starts at.
+pub type SnapshotId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The storage backend failed a read or write.
+    Storage,
+    /// The block size does not divide the page size, or differs from the
+    /// one the store was formatted with.
+    BadBlockSize,
+    Corrupt(&'static str),
+    NotFound(SnapshotId),
+    /// The VM's RAM regions or vCPU count differ from the snapshot's.
+    LayoutMismatch,
+    Guest(HvError),
+    State(StateError),
+}
+
+impl From<HvError> for SnapshotError {
+    fn from(err: HvError) -> Self {
+        SnapshotError::Guest(err)
+    }
+}
+
+impl From<StateError> for SnapshotError {
+    fn from(err: StateError) -> Self {
+        SnapshotError::State(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub id: SnapshotId,
+    pub name: String,
+    /// The snapshot this one only stores differences from.
+    pub parent: Option<SnapshotId>,
+    /// Pages stored in this snapshot itself.
+    pub pages: usize,
+}
+
+struct Record {
+    prev: Option<SnapshotId>,
+    parent: Option<SnapshotId>,
+    name: String,
+    /// RAM regions as (guest-physical start, size).
+    regions: Vec<(u64, u64)>,
+    vcpus: Vec<Vec<u8>>,
+    /// Stored pages as (guest-physical address, first block).
+    pages: Vec<(u64, u64)>,
+}
+
+/// The snapshots of one VM.
+pub struct SnapshotStore<S: StorageBackend> {
+    storage: S,
+    block_size: usize,
+    next_free: u64,
+    newest: Option<SnapshotId>,
+    /// The snapshot guest RAM matched when the dirty log was last taken.
+    /// The next snapshot stores only the pages written since.
+    base: Option<SnapshotId>,
+}
+
+impl<S: StorageBackend> SnapshotStore<S> {
+    /// Start an empty store on `storage`, discarding whatever was there.
+    pub fn format(storage: S, block_size: usize) -> Result<Self, SnapshotError> {
+        if block_size < 32 || !PAGE_SIZE.is_multiple_of(block_size) {
+            return Err(SnapshotError::BadBlockSize);
+        }
+        let store = SnapshotStore { storage, block_size, next_free: 1, newest: None, base: None };
+        store.write_super()?;
+        Ok(store)
+    }
+
+    /// Open a store written by [`format`](Self::format). The first snapshot
+    /// taken afterwards is a full one, since nothing is known about how
+    /// guest RAM relates to the stored snapshots.
+    pub fn open(storage: S, block_size: usize) -> Result<Self, SnapshotError> {
+        let mut block = vec![0u8; block_size];
+        storage.read_block(0, &mut block).map_err(|_| SnapshotError::Storage)?;
+        let mut r = reader(&block);
+        if r.take(4)? != SUPER_MAGIC {
+            return Err(SnapshotError::Corrupt("not a snapshot store"));
+        }
+        if r.u16()? != FORMAT_VERSION {
+            return Err(SnapshotError::Corrupt("unsupported store version"));
+        }
+        if r.u32()? as usize != block_size {
+            return Err(SnapshotError::BadBlockSize);
+        }
+        let next_free = r.u64()?;
+        let newest = id(r.u64()?);
+        Ok(SnapshotStore { storage, block_size, next_free, newest, base: None })
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Every snapshot, newest first.
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>, SnapshotError> {
+        let mut snapshots = Vec::new();
+        let mut next = self.newest;
+        while let Some(current) = next {
+            let record = self.read_record(current)?;
+            next = record.prev;
+            snapshots.push(SnapshotInfo { id: current, name: record.name, parent: record.parent, pages: record.pages.len() });
+        }
+        Ok(snapshots)
+    }
+
+    /// The newest snapshot called `name`.
+    pub fn find(&self, name: &str) -> Result<Option<SnapshotInfo>, SnapshotError> {
+        Ok(self.list()?.into_iter().find(|snapshot| snapshot.name == name))
+    }
+
+    /// Save `vm` as a new snapshot and return its ID. Its vCPUs must be
+    /// stopped. Dirty logging is turned on if it is not already.
+    pub fn take<B: HypervisorBackend>(&mut self, vm: &mut Vm<B>, name: &str) -> Result<SnapshotId, SnapshotError> {
+        let regions = ram_regions(vm);
+        let mut vcpus = Vec::new();
+        for vcpu in vm.vcpus().to_vec() {
+            vcpus.push(vm.save_vcpu(vcpu)?.encode());
+        }
+        vm.enable_dirty_log()?;
+        // Taking the dirty log clears it, so after a failure from here on
+        // the next snapshot has nothing to store differences from.
+        let result = self.write_snapshot(vm, name, regions, vcpus);
+        self.base = result.as_ref().ok().copied();
+        result
+    }
+
+    fn write_snapshot<B: HypervisorBackend>(
+        &mut self,
+        vm: &mut Vm<B>,
+        name: &str,
+        regions: Vec<(u64, u64)>,
+        vcpus: Vec<Vec<u8>>,
+    ) -> Result<SnapshotId, SnapshotError> {
+        let dirty = vm.take_dirty_pages()?;
+        let addresses = match self.base {
+            Some(_) => dirty,
+            None => regions.iter().flat_map(|&(start, size)| (start..start + size).step_by(PAGE_SIZE)).collect(),
+        };
+
+        let mut next_free = self.next_free;
+        let mut pages = Vec::with_capacity(addresses.len());
+        let mut page = vec![0u8; PAGE_SIZE];
+        for gpa in addresses {
+            vm.backend().read_guest(gpa, &mut page)?;
+            self.write_bytes(next_free, &page)?;
+            pages.push((gpa, next_free));
+            next_free += (PAGE_SIZE / self.block_size) as u64;
+        }
+        let record = Record { prev: self.newest, parent: self.base, name: String::from(name), regions, vcpus, pages };
+        let encoded = record.encode();
+        self.write_bytes(next_free, &encoded)?;
+
+        let id = next_free;
+        self.next_free = next_free + encoded.len().div_ceil(self.block_size) as u64;
+        self.newest = Some(id);
+        self.write_super()?;
+        Ok(id)
+    }
+
+    /// Put `vm` back in the state saved as `id`. Its vCPUs must be stopped,
+    /// and it must have the RAM regions and vCPU count it had then.
+    pub fn restore<B: HypervisorBackend>(&mut self, vm: &mut Vm<B>, id: SnapshotId) -> Result<(), SnapshotError> {
+        let record = self.read_record(id)?;
+        if record.regions != ram_regions(vm) || record.vcpus.len() != vm.vcpus().len() {
+            return Err(SnapshotError::LayoutMismatch);
+        }
+        let states = record.vcpus.iter().map(|data| VcpuState::decode(data)).collect::<Result<Vec<_>, _>>()?;
+
+        let mut restored = BTreeSet::new();
+        self.restore_pages(vm, &record, &mut restored)?;
+        let (mut child, mut next) = (id, record.parent);
+        while let Some(current) = next {
+            // Parents are always written before their children.
+            if current >= child {
+                return Err(SnapshotError::Corrupt("snapshot chain loops"));
+            }
+            let parent = self.read_record(current)?;
+            self.restore_pages(vm, &parent, &mut restored)?;
+            (child, next) = (current, parent.parent);
+        }
+        let total: u64 = record.regions.iter().map(|&(_, size)| size / PAGE_SIZE as u64).sum();
+        if restored.len() as u64 != total {
+            return Err(SnapshotError::Corrupt("snapshot chain does not cover guest RAM"));
+        }
+
+        for (vcpu, state) in vm.vcpus().to_vec().into_iter().zip(&states) {
+            vm.restore_vcpu(vcpu, state)?;
+        }
+        vm.enable_dirty_log()?;
+        vm.take_dirty_pages()?;
+        self.base = Some(id);
+        Ok(())
+    }
+
+    /// Write the pages of `record` not already in `restored` to guest RAM.
+    fn restore_pages<B: HypervisorBackend>(
+        &self,
+        vm: &mut Vm<B>,
+        record: &Record,
+        restored: &mut BTreeSet<u64>,
+    ) -> Result<(), SnapshotError> {
+        for &(gpa, block) in &record.pages {
+            if restored.insert(gpa) {
+                let page = self.read_bytes(block, PAGE_SIZE)?;
+                vm.backend_mut().write_guest(gpa, &page)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_super(&self) -> Result<(), SnapshotError> {
+        let mut w = Writer::new();
+        w.bytes(&SUPER_MAGIC);
+        w.u16(FORMAT_VERSION);
+        w.u32(self.block_size as u32);
+        w.u64(self.next_free);
+        w.u64(self.newest.unwrap_or(0));
+        self.write_bytes(0, w.as_bytes())
+    }
+
+    fn read_record(&self, id: SnapshotId) -> Result<Record, SnapshotError> {
+        if id == 0 || id >= self.next_free {
+            return Err(SnapshotError::NotFound(id));
+        }
+        let prefix = self.read_bytes(id, RECORD_PREFIX)?;
+        if prefix[..4] != RECORD_MAGIC {
+            return Err(SnapshotError::NotFound(id));
+        }
+        let len = u32::from_le_bytes(prefix[4..8].try_into().unwrap()) as usize;
+        Record::decode(&self.read_bytes(id, len)?)
+    }
+
+    /// Write `data` from the start of `first_block`, padding the last block.
+    fn write_bytes(&self, first_block: u64, data: &[u8]) -> Result<(), SnapshotError> {
+        let mut block = vec![0u8; self.block_size];
+        for (i, chunk) in data.chunks(self.block_size).enumerate() {
+            block[..chunk.len()].copy_from_slice(chunk);
+            block[chunk.len()..].fill(0);
+            self.storage.write_block(first_block + i as u64, &block).map_err(|_| SnapshotError::Storage)?;
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&self, first_block: u64, len: usize) -> Result<Vec<u8>, SnapshotError> {
+        let mut data = vec![0u8; len.div_ceil(self.block_size) * self.block_size];
+        for (i, block) in data.chunks_mut(self.block_size).enumerate() {
+            self.storage.read_block(first_block + i as u64, block).map_err(|_| SnapshotError::Storage)?;
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+/// The VM's RAM regions as (guest-physical start, size).
+fn ram_regions<B: HypervisorBackend>(vm: &Vm<B>) -> Vec<(u64, u64)> {
+    let layout = vm.backend().memory_layout();
+    layout
+        .regions()
+        .iter()
+        .filter(|region| matches!(region.kind, RegionKind::Ram { .. }))
+        .map(|region| (region.guest_phys, region.size))
+        .collect()
+}
+
+fn reader(data: &[u8]) -> codec::Reader<'_, SnapshotError> {
+    codec::Reader::new(data, SnapshotError::Corrupt("truncated record"))
+}
+
+/// Block 0 is the superblock, so it doubles as "none".
+fn id(raw: u64) -> Option<SnapshotId> {
+    (raw != 0).then_some(raw)
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(&RECORD_MAGIC);
+        w.u32(0);
+        w.u64(self.prev.unwrap_or(0));
+        w.u64(self.parent.unwrap_or(0));
+        let name = &self.name.as_bytes()[..self.name.len().min(u16::MAX as usize)];
+        w.u16(name.len() as u16);
+        w.bytes(name);
+        w.u32(self.regions.len() as u32);
+        for &(start, size) in &self.regions {
+            w.u64(start);
+            w.u64(size);
+        }
+        w.u32(self.vcpus.len() as u32);
+        for state in &self.vcpus {
+            w.u32(state.len() as u32);
+            w.bytes(state);
+        }
+        w.u32(self.pages.len() as u32);
+        for &(gpa, block) in &self.pages {
+            w.u64(gpa);
+            w.u64(block);
+        }
+        let len = w.len() as u32;
+        w.patch_u32(4, len);
+        w.into_bytes()
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, SnapshotError> {
+        let mut r = reader(data);
+        r.take(RECORD_PREFIX)?;
+        let prev = id(r.u64()?);
+        let parent = id(r.u64()?);
+        let name_len = r.u16()? as usize;
+        let name = String::from_utf8(r.take(name_len)?.to_vec()).map_err(|_| SnapshotError::Corrupt("bad snapshot name"))?;
+        let regions = (0..r.u32()?).map(|_| Ok((r.u64()?, r.u64()?))).collect::<Result<_, SnapshotError>>()?;
+        let mut vcpus = Vec::new();
+        for _ in 0..r.u32()? {
+            let len = r.u32()? as usize;
+            vcpus.push(r.take(len)?.to_vec());
+        }
+        let pages = (0..r.u32()?).map(|_| Ok((r.u64()?, r.u64()?))).collect::<Result<_, SnapshotError>>()?;
+        Ok(Record { prev, parent, name, regions, vcpus, pages })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::hypervisor::{Disposition, MockBackend, SpecialRegisters, VcpuId, VmExit, VmExitHandler};
+    use crate::storage::tests::RamStorage;
+
+    /// Room for every snapshot the tests take.
+    fn disk() -> RamStorage {
+        RamStorage::new(512, 1024)
+    }
+
+    /// A copy of the store's disk in `block_size` blocks, to reopen it from.
+    fn reopen(store: &SnapshotStore<RamStorage>, block_size: usize) -> RamStorage {
+        RamStorage::from_bytes(block_size, store.storage().data().clone())
+    }
+
+    struct StopAtHlt;
+
+    impl VmExitHandler for StopAtHlt {
+        fn handle(&mut self, _vcpu: VcpuId, _exit: &VmExit) -> Disposition {
+            Disposition::Stop
+        }
+    }
+
+    fn read(vm: &Vm<MockBackend>, gpa: u64) -> u8 {
+        let mut byte = [0u8];
+        vm.backend().read_guest(gpa, &mut byte).unwrap();
+        byte[0]
+    }
+
+    #[test]
+    fn test_incremental_snapshots_and_restore() {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        // mov byte [0x5000], 0x77; hlt
+        backend.write_guest(0x1000, &[0xC6, 0x06, 0x00, 0x50, 0x77, 0xF4]).unwrap();
+        let mut vm = Vm::new(backend);
+        vm.add_vcpu().unwrap();
+        vm.backend_mut().set_special_registers(0, &SpecialRegisters::real_mode(0x100)).unwrap();
+
+        let mut store = SnapshotStore::format(disk(), 512).unwrap();
+        let base = store.take(&mut vm, "base").unwrap();
+        vm.backend_mut().write_guest(0x3000, b"two").unwrap();
+        let second = store.take(&mut vm, "second").unwrap();
+        assert_eq!(vm.run_vcpu(0, &mut StopAtHlt).unwrap(), VmExit::Hlt);
+        let third = store.take(&mut vm, "third").unwrap();
+
+        let list = store.list().unwrap();
+        let summary: Vec<_> = list.iter().map(|s| (s.name.as_str(), s.parent, s.pages)).collect();
+        assert_eq!(summary, [("third", Some(second), 1), ("second", Some(base), 1), ("base", None, 16)]);
+
+        store.restore(&mut vm, second).unwrap();
+        assert_eq!(read(&vm, 0x3000), b't');
+        assert_eq!(read(&vm, 0x5000), 0);
+        assert_eq!(vm.backend().get_registers(0).unwrap().rip, 0);
+        // The guest runs again from where the snapshot left it.
+        assert_eq!(vm.run_vcpu(0, &mut StopAtHlt).unwrap(), VmExit::Hlt);
+        assert_eq!(read(&vm, 0x5000), 0x77);
+
+        // A reopened store still rebuilds every point in time.
+        let disk = reopen(&store, 512);
+        let mut store = SnapshotStore::open(disk, 512).unwrap();
+        store.restore(&mut vm, base).unwrap();
+        assert_eq!(read(&vm, 0x3000), 0);
+        assert_eq!(read(&vm, 0x5000), 0);
+        // Snapshots after a restore branch off the restored one.
+        vm.backend_mut().write_guest(0x8000, &[1]).unwrap();
+        let branch = store.take(&mut vm, "branch").unwrap();
+        assert_eq!(store.find("branch").unwrap().map(|s| (s.parent, s.pages)), Some((Some(base), 1)));
+        store.restore(&mut vm, third).unwrap();
+        assert_eq!((read(&vm, 0x3000), read(&vm, 0x5000), read(&vm, 0x8000)), (b't', 0x77, 0));
+        store.restore(&mut vm, branch).unwrap();
+        assert_eq!((read(&vm, 0x3000), read(&vm, 0x5000), read(&vm, 0x8000)), (0, 0, 1));
+    }
+
+    #[test]
+    fn test_store_errors() {
+        assert!(matches!(SnapshotStore::format(disk(), 1000), Err(SnapshotError::BadBlockSize)));
+        let store = SnapshotStore::format(disk(), 512).unwrap();
+        let disk = reopen(&store, 256);
+        assert!(matches!(SnapshotStore::open(disk, 256), Err(SnapshotError::BadBlockSize)));
+
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x2000).unwrap();
+        let mut vm = Vm::new(backend);
+        vm.add_vcpu().unwrap();
+        let mut store = store;
+        assert!(matches!(store.restore(&mut vm, 5), Err(SnapshotError::NotFound(5))));
+        let id = store.take(&mut vm, "one").unwrap();
+        vm.add_vcpu().unwrap();
+        assert!(matches!(store.restore(&mut vm, id), Err(SnapshotError::LayoutMismatch)));
+    }
+
+    #[test]
+    fn test_failed_take_forgets_base() {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x4000).unwrap();
+        let mut vm = Vm::new(backend);
+        vm.add_vcpu().unwrap();
+        let mut store = SnapshotStore::format(disk(), 512).unwrap();
+        store.take(&mut vm, "base").unwrap();
+
+        // The failed snapshot consumed the dirty log, so the write below
+        // must end up in a full snapshot rather than be lost.
+        vm.backend_mut().write_guest(0x2000, b"lost?").unwrap();
+        store.storage().fail_writes(true);
+        assert!(matches!(store.take(&mut vm, "failed"), Err(SnapshotError::Storage)));
+        store.storage().fail_writes(false);
+        let full = store.take(&mut vm, "full").unwrap();
+        assert_eq!(store.find("full").unwrap().map(|s| (s.parent, s.pages)), Some((None, 4)));
+
+        vm.backend_mut().write_guest(0x2000, &[0]).unwrap();
+        store.restore(&mut vm, full).unwrap();
+        assert_eq!(read(&vm, 0x2000), b'l');
+    }
+}
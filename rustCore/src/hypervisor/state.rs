@@ -0,0 +1,400 @@
+// Saved vCPU state and its binary format, used by snapshots and migration.
+//
+// An encoded state is a header (magic and format version) followed by
+// tagged, length-prefixed sections, all little-endian. Readers skip
+// sections they do not know and ignore bytes past the end of the ones they
+// do, so new state can be appended without a version bump; the version
+// only changes when an existing field changes meaning.
+
+use alloc::vec::Vec;
+
+use super::{DescriptorTable, Segment, SpecialRegisters, VcpuRegisters};
+use crate::codec::{self, Writer};
+use crate::vdev::lapic::LapicState;
+
+type Reader<'a> = codec::Reader<'a, StateError>;
+
+pub const STATE_MAGIC: [u8; 4] = *b"HCVS";
+pub const STATE_VERSION: u16 = 1;
+
+const SECTION_REGS: u16 = 1;
+const SECTION_SREGS: u16 = 2;
+const SECTION_MSRS: u16 = 3;
+const SECTION_EVENTS: u16 = 4;
+const SECTION_LAPIC: u16 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    /// Written by a newer format version than this build understands.
+    UnsupportedVersion(u16),
+    Truncated,
+    MissingSection(u16),
+    /// A known section is shorter than its fields or holds invalid values.
+    BadSection(u16),
+}
+
+/// Event types, numbered as in the VMX interruption-information field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EventKind {
+    ExternalInterrupt = 0,
+    Nmi = 2,
+    HardwareException = 3,
+    SoftwareInterrupt = 4,
+    PrivilegedSoftwareException = 5,
+    SoftwareException = 6,
+}
+
+impl EventKind {
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        Some(match raw {
+            0 => EventKind::ExternalInterrupt,
+            2 => EventKind::Nmi,
+            3 => EventKind::HardwareException,
+            4 => EventKind::SoftwareInterrupt,
+            5 => EventKind::PrivilegedSoftwareException,
+            6 => EventKind::SoftwareException,
+            _ => return None,
+        })
+    }
+}
+
+/// An event queued for delivery on the next entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingEvent {
+    pub kind: EventKind,
+    pub vector: u8,
+    pub error_code: Option<u32>,
+    /// Length of the instruction behind a software interrupt or exception.
+    pub instruction_length: u32,
+}
+
+/// Event injection and interruptibility state.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VcpuEvents {
+    pub pending: Option<PendingEvent>,
+    /// Blocking by STI (bit 0) and MOV SS (bit 1).
+    pub interrupt_shadow: u8,
+    pub nmi_blocked: bool,
+    /// An interrupt-window exit was requested.
+    pub interrupt_window: bool,
+}
+
+/// Everything needed to resume a vCPU elsewhere.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VcpuState {
+    pub regs: VcpuRegisters,
+    pub sregs: SpecialRegisters,
+    /// MSRs not covered by `sregs`, as (index, value).
+    pub msrs: Vec<(u32, u64)>,
+    pub events: VcpuEvents,
+    /// Absent when the VM has no in-kernel irqchip.
+    pub lapic: Option<LapicState>,
+}
+
+impl VcpuState {
+    /// A CPU right after reset, about to fetch from the reset vector.
+    pub fn reset() -> Self {
+        VcpuState {
+            regs: VcpuRegisters { rip: 0xFFF0, rflags: 1 << 1, ..Default::default() },
+            sregs: SpecialRegisters::real_mode(0xF000),
+            ..Default::default()
+        }
+    }
+
+    pub fn msr(&self, msr: u32) -> Option<u64> {
+        self.msrs.iter().find(|&&(index, _)| index == msr).map(|&(_, value)| value)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Writer::new();
+        out.bytes(&STATE_MAGIC);
+        out.u16(STATE_VERSION);
+        section(&mut out, SECTION_REGS, |w| {
+            let r = &self.regs;
+            for value in [
+                r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rsp, r.rbp, r.r8, r.r9, r.r10, r.r11, r.r12, r.r13, r.r14,
+                r.r15, r.rip, r.rflags,
+            ] {
+                w.u64(value);
+            }
+        });
+        section(&mut out, SECTION_SREGS, |w| {
+            let s = &self.sregs;
+            for segment in [&s.cs, &s.ds, &s.es, &s.fs, &s.gs, &s.ss, &s.tr, &s.ldtr] {
+                w.u16(segment.selector);
+                w.u64(segment.base);
+                w.u32(segment.limit);
+                w.u32(segment.access_rights);
+            }
+            for table in [&s.gdt, &s.idt] {
+                w.u64(table.base);
+                w.u16(table.limit);
+            }
+            for value in [s.cr0, s.cr2, s.cr3, s.cr4, s.efer] {
+                w.u64(value);
+            }
+        });
+        section(&mut out, SECTION_MSRS, |w| {
+            w.u32(self.msrs.len() as u32);
+            for &(msr, value) in &self.msrs {
+                w.u32(msr);
+                w.u64(value);
+            }
+        });
+        section(&mut out, SECTION_EVENTS, |w| {
+            let events = &self.events;
+            match events.pending {
+                Some(event) => {
+                    w.u8(1);
+                    w.u8(event.kind as u8);
+                    w.u8(event.vector);
+                    w.u8(event.error_code.is_some() as u8);
+                    w.u32(event.error_code.unwrap_or(0));
+                    w.u32(event.instruction_length);
+                }
+                None => {
+                    w.bytes(&[0; 12]);
+                }
+            }
+            w.u8(events.interrupt_shadow);
+            w.u8(events.nmi_blocked as u8);
+            w.u8(events.interrupt_window as u8);
+        });
+        if let Some(lapic) = &self.lapic {
+            section(&mut out, SECTION_LAPIC, |w| {
+                w.u8(lapic.id);
+                for &word in [&lapic.irr, &lapic.isr, &lapic.tmr].into_iter().flatten() {
+                    w.u32(word);
+                }
+                for value in [lapic.tpr, lapic.ldr, lapic.dfr, lapic.svr, lapic.esr, lapic.icr_low, lapic.icr_high] {
+                    w.u32(value);
+                }
+                for &lvt in &lapic.lvt {
+                    w.u32(lvt);
+                }
+                w.u32(lapic.timer_initial);
+                w.u32(lapic.timer_divide);
+                w.u8(lapic.timer_remaining.is_some() as u8);
+                w.u64(lapic.timer_remaining.unwrap_or(0));
+                w.u8(lapic.wait_for_sipi as u8);
+                w.u8(lapic.sipi_vector.is_some() as u8);
+                w.u8(lapic.sipi_vector.unwrap_or(0));
+            });
+        }
+        out.into_bytes()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, StateError> {
+        let mut input = Reader::new(data, StateError::Truncated);
+        if input.take(4).map_err(|_| StateError::BadMagic)? != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = input.u16()?;
+        if version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let mut state = VcpuState::default();
+        let mut seen = Vec::new();
+        while !input.is_empty() {
+            let tag = input.u16()?;
+            let len = input.u32()? as usize;
+            let mut body = Reader::new(input.take(len)?, StateError::Truncated);
+            let parsed = match tag {
+                SECTION_REGS => decode_regs(&mut body).map(|regs| state.regs = regs),
+                SECTION_SREGS => decode_sregs(&mut body).map(|sregs| state.sregs = sregs),
+                SECTION_MSRS => decode_msrs(&mut body).map(|msrs| state.msrs = msrs),
+                SECTION_EVENTS => decode_events(&mut body).map(|events| state.events = events),
+                SECTION_LAPIC => decode_lapic(&mut body).map(|lapic| state.lapic = Some(lapic)),
+                _ => Ok(()),
+            };
+            parsed.map_err(|_| StateError::BadSection(tag))?;
+            seen.push(tag);
+        }
+        for tag in [SECTION_REGS, SECTION_SREGS, SECTION_EVENTS] {
+            if !seen.contains(&tag) {
+                return Err(StateError::MissingSection(tag));
+            }
+        }
+        Ok(state)
+    }
+}
+
+fn decode_regs(r: &mut Reader) -> Result<VcpuRegisters, StateError> {
+    Ok(VcpuRegisters {
+        rax: r.u64()?,
+        rbx: r.u64()?,
+        rcx: r.u64()?,
+        rdx: r.u64()?,
+        rsi: r.u64()?,
+        rdi: r.u64()?,
+        rsp: r.u64()?,
+        rbp: r.u64()?,
+        r8: r.u64()?,
+        r9: r.u64()?,
+        r10: r.u64()?,
+        r11: r.u64()?,
+        r12: r.u64()?,
+        r13: r.u64()?,
+        r14: r.u64()?,
+        r15: r.u64()?,
+        rip: r.u64()?,
+        rflags: r.u64()?,
+    })
+}
+
+fn decode_sregs(r: &mut Reader) -> Result<SpecialRegisters, StateError> {
+    let mut segments = [Segment::default(); 8];
+    for segment in segments.iter_mut() {
+        *segment = Segment { selector: r.u16()?, base: r.u64()?, limit: r.u32()?, access_rights: r.u32()? };
+    }
+    let [cs, ds, es, fs, gs, ss, tr, ldtr] = segments;
+    Ok(SpecialRegisters {
+        cs,
+        ds,
+        es,
+        fs,
+        gs,
+        ss,
+        tr,
+        ldtr,
+        gdt: DescriptorTable { base: r.u64()?, limit: r.u16()? },
+        idt: DescriptorTable { base: r.u64()?, limit: r.u16()? },
+        cr0: r.u64()?,
+        cr2: r.u64()?,
+        cr3: r.u64()?,
+        cr4: r.u64()?,
+        efer: r.u64()?,
+    })
+}
+
+fn decode_msrs(r: &mut Reader) -> Result<Vec<(u32, u64)>, StateError> {
+    let count = r.u32()?;
+    (0..count).map(|_| Ok((r.u32()?, r.u64()?))).collect()
+}
+
+fn decode_events(r: &mut Reader) -> Result<VcpuEvents, StateError> {
+    let valid = r.u8()? != 0;
+    let kind = r.u8()?;
+    let vector = r.u8()?;
+    let has_error_code = r.u8()? != 0;
+    let error_code = r.u32()?;
+    let instruction_length = r.u32()?;
+    let pending = if valid {
+        Some(PendingEvent {
+            kind: EventKind::from_raw(kind).ok_or(StateError::BadSection(SECTION_EVENTS))?,
+            vector,
+            error_code: has_error_code.then_some(error_code),
+            instruction_length,
+        })
+    } else {
+        None
+    };
+    Ok(VcpuEvents { pending, interrupt_shadow: r.u8()?, nmi_blocked: r.u8()? != 0, interrupt_window: r.u8()? != 0 })
+}
+
+fn decode_lapic(r: &mut Reader) -> Result<LapicState, StateError> {
+    let mut lapic = LapicState { id: r.u8()?, ..Default::default() };
+    for set in [&mut lapic.irr, &mut lapic.isr, &mut lapic.tmr] {
+        for word in set.iter_mut() {
+            *word = r.u32()?;
+        }
+    }
+    for value in [
+        &mut lapic.tpr,
+        &mut lapic.ldr,
+        &mut lapic.dfr,
+        &mut lapic.svr,
+        &mut lapic.esr,
+        &mut lapic.icr_low,
+        &mut lapic.icr_high,
+    ] {
+        *value = r.u32()?;
+    }
+    for lvt in lapic.lvt.iter_mut() {
+        *lvt = r.u32()?;
+    }
+    lapic.timer_initial = r.u32()?;
+    lapic.timer_divide = r.u32()?;
+    let timer_running = r.u8()? != 0;
+    let timer_remaining = r.u64()?;
+    lapic.timer_remaining = timer_running.then_some(timer_remaining);
+    lapic.wait_for_sipi = r.u8()? != 0;
+    let has_sipi = r.u8()? != 0;
+    let sipi_vector = r.u8()?;
+    lapic.sipi_vector = has_sipi.then_some(sipi_vector);
+    Ok(lapic)
+}
+
+/// Append a section, patching in its length once `body` has run.
+fn section(w: &mut Writer, tag: u16, body: impl FnOnce(&mut Writer)) {
+    w.u16(tag);
+    let len_at = w.len();
+    w.u32(0);
+    body(w);
+    let len = (w.len() - len_at - 4) as u32;
+    w.patch_u32(len_at, len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn sample() -> VcpuState {
+        let mut lapic = LapicState { id: 3, svr: 0x1FF, timer_remaining: Some(12_345), sipi_vector: Some(0x9A), ..Default::default() };
+        lapic.irr[1] = 1 << 4;
+        lapic.lvt = [0x1_0000, 0xEC, 0x1_0000, 0x1_0000, 0x700, 0x400, 0xFE];
+        VcpuState {
+            regs: VcpuRegisters { rax: 1, r15: u64::MAX, rip: 0xFFFF_8000_0010_0000, rflags: 0x202, ..Default::default() },
+            sregs: SpecialRegisters { cr2: 0xDEAD_B000, ..SpecialRegisters::long_mode(0x1000) },
+            msrs: vec![(0x174, 0x10), (0xC000_0082, 0xFFFF_FFFF_8100_0000)],
+            events: VcpuEvents {
+                pending: Some(PendingEvent {
+                    kind: EventKind::HardwareException,
+                    vector: 14,
+                    error_code: Some(2),
+                    instruction_length: 0,
+                }),
+                interrupt_shadow: 1,
+                nmi_blocked: true,
+                interrupt_window: false,
+            },
+            lapic: Some(lapic),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let state = sample();
+        let encoded = state.encode();
+        assert_eq!(&encoded[..4], b"HCVS");
+        assert_eq!(VcpuState::decode(&encoded), Ok(state));
+
+        let reset = VcpuState::reset();
+        assert_eq!(VcpuState::decode(&reset.encode()), Ok(reset));
+    }
+
+    #[test]
+    fn test_unknown_sections_and_errors() {
+        let state = sample();
+        let mut encoded = state.encode();
+        // A section from a later writer is skipped.
+        encoded.extend_from_slice(&[0x34, 0x12, 3, 0, 0, 0, 1, 2, 3]);
+        assert_eq!(VcpuState::decode(&encoded), Ok(state.clone()));
+
+        let encoded = state.encode();
+        assert_eq!(VcpuState::decode(&encoded[..encoded.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(VcpuState::decode(b"XXXX\x01\x00"), Err(StateError::BadMagic));
+        let mut newer = encoded.clone();
+        newer[4] = 2;
+        assert_eq!(VcpuState::decode(&newer), Err(StateError::UnsupportedVersion(2)));
+        assert_eq!(VcpuState::decode(&encoded[..6]), Err(StateError::MissingSection(SECTION_REGS)));
+        // A registers section cut short inside its own length.
+        let mut short = encoded[..6].to_vec();
+        short.extend_from_slice(&[1, 0, 8, 0, 0, 0]);
+        short.extend_from_slice(&[0; 8]);
+        assert_eq!(VcpuState::decode(&short), Err(StateError::BadSection(SECTION_REGS)));
+    }
+}
@@ -2,10 +2,16 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::info::MemoryRegionKind;
 use bootloader_api::{entry_point, BootInfo};
 
 pub mod interrupts;
+pub mod codec;
 pub mod vmx;
+pub mod hypervisor;
+pub mod loader;
+pub mod vdev;
 pub mod process;
 pub mod memory;
 pub mod graphics;
@@ -13,16 +19,51 @@ pub mod network;
 pub mod storage;
 pub mod net;
 pub mod gui;
+pub mod manager;
+pub mod services;
 
 pub use storage::{BlockDevice, StorageBackend, BlockStorage, RamDisk};
 
 extern crate alloc;
+#[cfg(feature = "host-fs")]
+extern crate std;
 
-entry_point!(kernel_main);
+/// All of physical memory is mapped, so native VMs' RAM and EPT tables can
+/// be reached.
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    // Example RAMDISK_MEMORY allocation (must be static and mutable)
+    static mut RAMDISK_MEMORY: [u8; 1024 * 1024] = [0; 1024 * 1024]; // 1 MiB RAM disk
+
+    let ramdisk = unsafe { RamDisk::new(&mut RAMDISK_MEMORY, 4096) };
+    let storage = BlockStorage::new(ramdisk, 4096);
+    *crate::STORAGE.lock() = Some(storage);
+
+    // The largest usable region is set aside for native VMs.
+    let usable = boot_info.memory_regions.iter().filter(|region| region.kind == MemoryRegionKind::Usable);
+    if let (Some(offset), Some(region)) =
+        (boot_info.physical_memory_offset.into_option(), usable.max_by_key(|region| region.end - region.start))
+    {
+        let frames = crate::memory::SimpleFrameAllocator::new(region.start, region.end);
+        *crate::memory::GUEST_FRAMES.lock() = Some((frames, offset));
+    }
 
-fn kernel_main(_boot_info: &'static BootInfo) -> ! {
     #[cfg(feature = "graphics")]
     if let Some(framebuffer) = boot_info.framebuffer.as_ref() {
+        // Volumes the VMs share and files dragged between them live on RAM
+        // disks of their own.
+        static mut VOLUME_MEMORY: [u8; 4 * 1024 * 1024] = [0; 4 * 1024 * 1024];
+        static mut STAGING_MEMORY: [u8; 4 * 1024 * 1024] = [0; 4 * 1024 * 1024];
+        let volumes = BlockStorage::new(unsafe { RamDisk::new(&mut VOLUME_MEMORY, 4096) }, 4096);
+        let staging = BlockStorage::new(unsafe { RamDisk::new(&mut STAGING_MEMORY, 4096) }, 4096);
+
         let info = framebuffer.info();
         let fb_addr = framebuffer.buffer().as_ptr() as *mut u8;
         let width = info.width;
@@ -30,12 +71,23 @@ fn kernel_main(_boot_info: &'static BootInfo) -> ! {
         let pitch = info.stride * 4; // 4 bytes per pixel for 32bpp
         let bpp = 4;
         let mut writer = crate::graphics::FramebufferWriter::new(fb_addr, width, height, pitch, bpp);
-        let mut gui = crate::gui::Gui::new(&mut writer);
+        let mut gui = crate::gui::Gui::new(
+            &mut writer,
+            crate::storage::volume::VolumeStore::shared(volumes, 4096, 1024),
+            crate::services::StagingArea::new(staging, 4096, 1024),
+        )
+        // TSC ticks, uncalibrated, until there is a timer to calibrate against.
+        .with_clock(|| unsafe { core::arch::x86_64::_rdtsc() });
+        // VMs boot the bzImage at the start of the RAM disk, if there is one.
+        let kernel = crate::STORAGE.lock().as_ref().map(|disk| crate::loader::read_bzimage(disk, 4096, 0));
+        if let Some(Ok(kernel)) = kernel {
+            gui = gui.with_kernel(kernel);
+        }
         // Simple demo input loop: cycle through menu options 1-9, then exit
         let mut demo_inputs = [b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0'];
         let mut idx = 0;
         loop {
-            gui.draw_menu();
+            gui.render();
             let input = demo_inputs[idx];
             gui.handle_input(input);
             idx += 1;
@@ -47,13 +99,6 @@ fn kernel_main(_boot_info: &'static BootInfo) -> ! {
         }
     }
 
-    // Example RAMDISK_MEMORY allocation (must be static and mutable)
-    static mut RAMDISK_MEMORY: [u8; 1024 * 1024] = [0; 1024 * 1024]; // 1 MiB RAM disk
-
-    let ramdisk = unsafe { RamDisk::new(&mut RAMDISK_MEMORY, 4096) };
-    let storage = BlockStorage::new(ramdisk, 4096);
-    *crate::STORAGE.lock() = Some(storage);
-
     loop {}
 }
 
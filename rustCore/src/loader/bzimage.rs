@@ -0,0 +1,384 @@
+// Linux bzImage loader using the 64-bit boot protocol
+// (Documentation/arch/x86/boot.rst): the protected-mode kernel is copied to
+// its preferred address and entered at offset 0x200 in long mode, with RSI
+// pointing at a `boot_params` page built here.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{enter_long_mode, is_ram, memory_map, read_storage, LoadError, BOOT_STACK, IDENTITY_MAPPED};
+use crate::hypervisor::{HypervisorBackend, VcpuId, VcpuRegisters};
+use crate::storage::StorageBackend;
+
+/// Guest-physical address of `boot_params` (the "zero page").
+pub const BOOT_PARAMS: u64 = 0x7000;
+/// Guest-physical address of the kernel command line.
+pub const CMDLINE: u64 = 0x2_0000;
+
+// Setup header fields, as offsets into the image and into `boot_params`.
+const SETUP_SECTS: usize = 0x1F1;
+const SYSSIZE: usize = 0x1F4;
+const BOOT_FLAG: usize = 0x1FE;
+const JUMP: usize = 0x200;
+const HEADER: usize = 0x202;
+const VERSION: usize = 0x206;
+const TYPE_OF_LOADER: usize = 0x210;
+const LOADFLAGS: usize = 0x211;
+const RAMDISK_IMAGE: usize = 0x218;
+const RAMDISK_SIZE: usize = 0x21C;
+const CMD_LINE_PTR: usize = 0x228;
+const INITRD_ADDR_MAX: usize = 0x22C;
+const RELOCATABLE_KERNEL: usize = 0x234;
+const XLOADFLAGS: usize = 0x236;
+const CMDLINE_SIZE: usize = 0x238;
+const PREF_ADDRESS: usize = 0x258;
+const INIT_SIZE: usize = 0x260;
+/// End of the fields this loader reads.
+const HEADER_END: usize = 0x268;
+/// End of the setup header area in `boot_params`.
+const BOOT_PARAMS_HEADER_END: usize = 0x290;
+
+// Other `boot_params` fields.
+const EXT_RAMDISK_IMAGE: usize = 0xC0;
+const EXT_RAMDISK_SIZE: usize = 0xC4;
+const EXT_CMD_LINE_PTR: usize = 0xC8;
+const E820_ENTRIES: usize = 0x1E8;
+const E820_TABLE: usize = 0x2D0;
+const E820_MAX: usize = 128;
+
+const HDRS_MAGIC: u32 = 0x5372_6448;
+const LOADED_HIGH: u8 = 1 << 0;
+const XLF_KERNEL_64: u16 = 1 << 0;
+/// "Undefined" boot loader ID.
+const LOADER_ID: u8 = 0xFF;
+/// Offset of the 64-bit entry point from the start of the kernel.
+const ENTRY_64: u64 = 0x200;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// A parsed bzImage.
+pub struct BzImage<'a> {
+    data: &'a [u8],
+    setup_len: usize,
+}
+
+impl<'a> BzImage<'a> {
+    /// Check the setup header. Only kernels with a 64-bit entry point (boot
+    /// protocol 2.12 or later) are accepted.
+    pub fn parse(data: &'a [u8]) -> Result<Self, LoadError> {
+        Self::check_magic(data)?;
+        if read_u16(data, VERSION) < 0x020C {
+            return Err(LoadError::BadImage("boot protocol older than 2.12"));
+        }
+        if data[LOADFLAGS] & LOADED_HIGH == 0 {
+            return Err(LoadError::BadImage("zImage kernels are not supported"));
+        }
+        if read_u16(data, XLOADFLAGS) & XLF_KERNEL_64 == 0 {
+            return Err(LoadError::BadImage("no 64-bit entry point"));
+        }
+        let setup_len = (Self::setup_sects(data) + 1) * 512;
+        if setup_len >= data.len() {
+            return Err(LoadError::BadImage("no protected-mode kernel"));
+        }
+        Ok(BzImage { data, setup_len })
+    }
+
+    fn check_magic(data: &[u8]) -> Result<(), LoadError> {
+        if data.len() < HEADER_END {
+            return Err(LoadError::BadImage("truncated setup header"));
+        }
+        if read_u16(data, BOOT_FLAG) != 0xAA55 || read_u32(data, HEADER) != HDRS_MAGIC {
+            return Err(LoadError::BadImage("not a bzImage"));
+        }
+        Ok(())
+    }
+
+    /// Setup sectors after the boot sector; zero means the historical four.
+    fn setup_sects(data: &[u8]) -> usize {
+        match data[SETUP_SECTS] {
+            0 => 4,
+            sects => sects as usize,
+        }
+    }
+
+    /// Length of the whole image according to its header.
+    fn image_len(data: &[u8]) -> usize {
+        (Self::setup_sects(data) + 1) * 512 + read_u32(data, SYSSIZE) as usize * 16
+    }
+
+    pub fn version(&self) -> u16 {
+        read_u16(self.data, VERSION)
+    }
+
+    /// The protected-mode kernel, loaded at [`load_address`](Self::load_address).
+    pub fn kernel(&self) -> &'a [u8] {
+        &self.data[self.setup_len..]
+    }
+
+    pub fn relocatable(&self) -> bool {
+        self.data[RELOCATABLE_KERNEL] != 0
+    }
+
+    pub fn load_address(&self) -> u64 {
+        match read_u64(self.data, PREF_ADDRESS) {
+            0 => 0x10_0000,
+            address => address,
+        }
+    }
+
+    /// Memory the kernel needs from its load address before it has
+    /// relocated itself, which is more than the image.
+    pub fn init_size(&self) -> u64 {
+        (read_u32(self.data, INIT_SIZE) as u64).max(self.kernel().len() as u64)
+    }
+
+    pub fn initrd_addr_max(&self) -> u64 {
+        read_u32(self.data, INITRD_ADDR_MAX) as u64
+    }
+
+    /// Longest command line the kernel accepts, without the terminating NUL.
+    pub fn cmdline_size(&self) -> usize {
+        read_u32(self.data, CMDLINE_SIZE) as usize
+    }
+
+    /// The setup header, as copied into `boot_params`.
+    fn setup_header(&self) -> &'a [u8] {
+        let end = (HEADER + self.data[JUMP + 1] as usize).clamp(HEADER_END, BOOT_PARAMS_HEADER_END);
+        &self.data[SETUP_SECTS..end.min(self.data.len())]
+    }
+}
+
+/// Where [`load_linux`] put things.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinuxBoot {
+    pub entry: u64,
+    pub kernel: u64,
+    pub boot_params: u64,
+    /// Address and size of the initrd.
+    pub initrd: Option<(u64, u64)>,
+}
+
+/// Highest page-aligned address below `limit` where `len` bytes of RAM
+/// above `floor` are free.
+fn place_initrd(layout: &crate::vmx::ept::GuestMemoryLayout, len: u64, floor: u64, limit: u64) -> Option<u64> {
+    layout.regions().iter().rev().find_map(|region| {
+        let end = region.end().min(limit);
+        let start = end.checked_sub(len)? & !0xFFF;
+        (start >= floor.max(region.guest_phys) && is_ram(layout, start, len)).then_some(start)
+    })
+}
+
+/// Load `image` with `cmdline` and an optional `initrd` into guest RAM and
+/// set `vcpu` to the 64-bit entry state. Guest RAM must cover the low 1 MiB
+/// and the kernel's load address.
+pub fn load_linux<B: HypervisorBackend>(
+    backend: &mut B,
+    vcpu: VcpuId,
+    image: &BzImage,
+    cmdline: &str,
+    initrd: Option<&[u8]>,
+) -> Result<LinuxBoot, LoadError> {
+    let layout = backend.memory_layout().clone();
+    let kernel = image.load_address();
+    if !is_ram(&layout, kernel, image.init_size()) {
+        return Err(LoadError::NoSpace("kernel"));
+    }
+    if cmdline.len() > image.cmdline_size() {
+        return Err(LoadError::NoSpace("command line"));
+    }
+
+    let mut params = vec![0u8; 4096];
+    let header = image.setup_header();
+    params[SETUP_SECTS..SETUP_SECTS + header.len()].copy_from_slice(header);
+    params[TYPE_OF_LOADER] = LOADER_ID;
+    write_u32(&mut params, CMD_LINE_PTR, CMDLINE as u32);
+    write_u32(&mut params, EXT_CMD_LINE_PTR, (CMDLINE >> 32) as u32);
+
+    let map = memory_map(&layout);
+    if map.len() > E820_MAX {
+        return Err(LoadError::NoSpace("e820 table"));
+    }
+    params[E820_ENTRIES] = map.len() as u8;
+    for (i, entry) in map.iter().enumerate() {
+        let offset = E820_TABLE + i * 20;
+        params[offset..offset + 8].copy_from_slice(&entry.addr.to_le_bytes());
+        params[offset + 8..offset + 16].copy_from_slice(&entry.size.to_le_bytes());
+        write_u32(&mut params, offset + 16, entry.kind as u32);
+    }
+
+    let initrd = match initrd {
+        Some(data) => {
+            let len = data.len() as u64;
+            // The boot page tables only reach the first 4 GiB.
+            let limit = (image.initrd_addr_max() + 1).min(IDENTITY_MAPPED);
+            let addr = place_initrd(&layout, len, kernel + image.init_size(), limit)
+                .ok_or(LoadError::NoSpace("initrd"))?;
+            backend.write_guest(addr, data)?;
+            write_u32(&mut params, RAMDISK_IMAGE, addr as u32);
+            write_u32(&mut params, RAMDISK_SIZE, len as u32);
+            write_u32(&mut params, EXT_RAMDISK_IMAGE, (addr >> 32) as u32);
+            write_u32(&mut params, EXT_RAMDISK_SIZE, (len >> 32) as u32);
+            Some((addr, len))
+        }
+        None => None,
+    };
+
+    backend.write_guest(kernel, image.kernel())?;
+    let mut line = Vec::with_capacity(cmdline.len() + 1);
+    line.extend_from_slice(cmdline.as_bytes());
+    line.push(0);
+    backend.write_guest(CMDLINE, &line)?;
+    backend.write_guest(BOOT_PARAMS, &params)?;
+
+    enter_long_mode(backend, vcpu)?;
+    let entry = kernel + ENTRY_64;
+    let regs = VcpuRegisters { rip: entry, rsi: BOOT_PARAMS, rsp: BOOT_STACK, rflags: 1 << 1, ..Default::default() };
+    backend.set_registers(vcpu, &regs)?;
+    Ok(LinuxBoot { entry, kernel, boot_params: BOOT_PARAMS, initrd })
+}
+
+/// Read a bzImage stored contiguously from block `first_block`, using the
+/// setup header to find its length.
+pub fn read_bzimage<S: StorageBackend + ?Sized>(
+    storage: &S,
+    block_size: usize,
+    first_block: u64,
+) -> Result<Vec<u8>, LoadError> {
+    let header = read_storage(storage, block_size, first_block, HEADER_END)?;
+    BzImage::check_magic(&header)?;
+    read_storage(storage, block_size, first_block, BzImage::image_len(&header))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hypervisor::{HypervisorBackend, IoDirection, MockBackend, VmExit};
+    use crate::loader::{BOOT_CS, BOOT_PML4};
+    use crate::storage::tests::RamStorage;
+
+    /// A bzImage with one setup sector whose 64-bit entry runs `entry`.
+    pub(crate) fn image(entry: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 1024];
+        data[SETUP_SECTS] = 1;
+        data[BOOT_FLAG..BOOT_FLAG + 2].copy_from_slice(&0xAA55u16.to_le_bytes());
+        data[JUMP] = 0xEB;
+        data[JUMP + 1] = (HEADER_END - HEADER) as u8;
+        write_u32(&mut data, HEADER, HDRS_MAGIC);
+        data[VERSION..VERSION + 2].copy_from_slice(&0x020Fu16.to_le_bytes());
+        data[LOADFLAGS] = LOADED_HIGH;
+        write_u32(&mut data, INITRD_ADDR_MAX, 0x7FFF_FFFF);
+        data[RELOCATABLE_KERNEL] = 1;
+        data[XLOADFLAGS..XLOADFLAGS + 2].copy_from_slice(&XLF_KERNEL_64.to_le_bytes());
+        write_u32(&mut data, CMDLINE_SIZE, 2047);
+        data[PREF_ADDRESS..PREF_ADDRESS + 8].copy_from_slice(&0x100_0000u64.to_le_bytes());
+        write_u32(&mut data, INIT_SIZE, 0x10_0000);
+        let mut kernel = vec![0x90u8; 0x400];
+        kernel[0x200..0x200 + entry.len()].copy_from_slice(entry);
+        write_u32(&mut data, SYSSIZE, (kernel.len() / 16) as u32);
+        data.extend_from_slice(&kernel);
+        data
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = image(&[]);
+        let bz = BzImage::parse(&data).unwrap();
+        assert_eq!(bz.version(), 0x020F);
+        assert_eq!(bz.kernel().len(), 0x400);
+        assert_eq!(bz.load_address(), 0x100_0000);
+        assert_eq!(BzImage::image_len(&data), data.len());
+
+        let mut old = data.clone();
+        old[VERSION] = 0x0B;
+        assert_eq!(BzImage::parse(&old).err(), Some(LoadError::BadImage("boot protocol older than 2.12")));
+        let mut no64 = data.clone();
+        no64[XLOADFLAGS] = 0;
+        assert_eq!(BzImage::parse(&no64).err(), Some(LoadError::BadImage("no 64-bit entry point")));
+        assert_eq!(BzImage::parse(&data[..0x100]).err(), Some(LoadError::BadImage("truncated setup header")));
+    }
+
+    #[test]
+    fn test_read_from_storage() {
+        let data = image(&[0xF4]);
+        let mut disk = vec![0u8; 8192];
+        disk[512..512 + data.len()].copy_from_slice(&data);
+        let storage = RamStorage::from_bytes(512, disk);
+        assert_eq!(read_bzimage(&storage, 512, 1).unwrap(), data);
+        assert_eq!(read_bzimage(&storage, 512, 15), Err(LoadError::Storage));
+        assert_eq!(read_bzimage(&storage, 512, 0), Err(LoadError::BadImage("not a bzImage")));
+    }
+
+    #[test]
+    fn test_boot_params_and_entry_state() {
+        // mov eax, [rsi + 0x202]; out 0x80, eax; mov eax, [rsi + 0x228]; out 0x80, eax; hlt
+        let entry = [0x8B, 0x86, 0x02, 0x02, 0x00, 0x00, 0xE7, 0x80, 0x8B, 0x86, 0x28, 0x02, 0x00, 0x00, 0xE7, 0x80, 0xF4];
+        let data = image(&entry);
+        let bz = BzImage::parse(&data).unwrap();
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x400_0000).unwrap();
+        backend.create_vcpu(0).unwrap();
+        let initrd = [0xA5u8; 0x1800];
+        let boot = load_linux(&mut backend, 0, &bz, "console=ttyS0", Some(&initrd)).unwrap();
+        assert_eq!(boot.entry, 0x100_0200);
+        // Top of RAM, page aligned.
+        assert_eq!(boot.initrd, Some((0x3FF_E000, 0x1800)));
+
+        let mut params = vec![0u8; 4096];
+        backend.read_guest(BOOT_PARAMS, &mut params).unwrap();
+        assert_eq!(params[TYPE_OF_LOADER], LOADER_ID);
+        assert_eq!(read_u32(&params, RAMDISK_IMAGE), 0x3FF_E000);
+        assert_eq!(read_u32(&params, RAMDISK_SIZE), 0x1800);
+        assert_eq!(read_u32(&params, CMDLINE_SIZE), 2047);
+        assert_eq!(params[E820_ENTRIES], 3);
+        let e820 = |i: usize| {
+            let offset = E820_TABLE + i * 20;
+            (read_u64(&params, offset), read_u64(&params, offset + 8), read_u32(&params, offset + 16))
+        };
+        assert_eq!(e820(0), (0, 0xA_0000, 1));
+        assert_eq!(e820(1), (0xA_0000, 0x6_0000, 2));
+        assert_eq!(e820(2), (0x10_0000, 0x3F0_0000, 1));
+        let mut line = [0u8; 14];
+        backend.read_guest(CMDLINE, &mut line).unwrap();
+        assert_eq!(&line, b"console=ttyS0\0");
+
+        let sregs = backend.get_special_registers(0).unwrap();
+        assert!(sregs.is_long_mode());
+        assert_eq!(sregs.cs.selector, BOOT_CS);
+        assert_eq!(sregs.cr3, BOOT_PML4);
+
+        // The entry code reads boot_params through the boot page tables.
+        let mut out = Vec::new();
+        loop {
+            match backend.run(0).unwrap() {
+                VmExit::Io { port: 0x80, direction: IoDirection::Out, data, .. } => out.push(data),
+                VmExit::Hlt => break,
+                exit => panic!("unexpected exit {:?}", exit),
+            }
+        }
+        assert_eq!(out, [HDRS_MAGIC, CMDLINE as u32]);
+    }
+
+    #[test]
+    fn test_no_room() {
+        let data = image(&[]);
+        let bz = BzImage::parse(&data).unwrap();
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x100_0000).unwrap();
+        backend.create_vcpu(0).unwrap();
+        assert_eq!(load_linux(&mut backend, 0, &bz, "", None), Err(LoadError::NoSpace("kernel")));
+    }
+}
@@ -0,0 +1,295 @@
+// ELF64 loader for unikernels and test kernels: PT_LOAD segments are
+// copied to their physical addresses and the rest of each segment (.bss)
+// is zeroed.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{enter_long_mode, is_ram, read_storage, LoadError, BOOT_STACK};
+use crate::hypervisor::{HypervisorBackend, VcpuId, VcpuRegisters};
+use crate::storage::StorageBackend;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7FELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+const PT_LOAD: u32 = 1;
+pub const EHDR_SIZE: usize = 64;
+pub const PHDR_SIZE: usize = 56;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// A PT_LOAD program header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadSegment {
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub flags: u32,
+}
+
+impl LoadSegment {
+    pub fn end(&self) -> u64 {
+        self.paddr + self.memsz
+    }
+}
+
+/// A parsed little-endian x86-64 executable.
+pub struct ElfImage<'a> {
+    data: &'a [u8],
+    entry: u64,
+    segments: Vec<LoadSegment>,
+}
+
+impl<'a> ElfImage<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, LoadError> {
+        let (phoff, phnum) = Self::check_header(data)?;
+        let table_end = phoff.checked_add(phnum * PHDR_SIZE).ok_or(LoadError::BadImage("bad program header table"))?;
+        if table_end > data.len() {
+            return Err(LoadError::BadImage("truncated program header table"));
+        }
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = &data[phoff + i * PHDR_SIZE..][..PHDR_SIZE];
+            if read_u32(ph, 0) != PT_LOAD {
+                continue;
+            }
+            let segment = LoadSegment {
+                flags: read_u32(ph, 4),
+                offset: read_u64(ph, 8),
+                vaddr: read_u64(ph, 16),
+                paddr: read_u64(ph, 24),
+                filesz: read_u64(ph, 32),
+                memsz: read_u64(ph, 40),
+            };
+            if segment.filesz > segment.memsz {
+                return Err(LoadError::BadImage("segment file size exceeds memory size"));
+            }
+            if segment.offset.checked_add(segment.filesz).is_none_or(|end| end > data.len() as u64) {
+                return Err(LoadError::BadImage("segment extends past end of file"));
+            }
+            segments.push(segment);
+        }
+        if segments.is_empty() {
+            return Err(LoadError::BadImage("no loadable segments"));
+        }
+        Ok(ElfImage { data, entry: read_u64(data, 24), segments })
+    }
+
+    /// Validate the ELF header and return the program header table offset
+    /// and entry count.
+    fn check_header(data: &[u8]) -> Result<(usize, usize), LoadError> {
+        if data.len() < EHDR_SIZE || &data[..4] != ELF_MAGIC {
+            return Err(LoadError::BadImage("not an ELF file"));
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(LoadError::BadImage("not a little-endian ELF64 file"));
+        }
+        if read_u16(data, 16) != ET_EXEC || read_u16(data, 18) != EM_X86_64 {
+            return Err(LoadError::BadImage("not an x86-64 executable"));
+        }
+        if read_u16(data, 54) as usize != PHDR_SIZE {
+            return Err(LoadError::BadImage("unexpected program header size"));
+        }
+        Ok((read_u64(data, 32) as usize, read_u16(data, 56) as usize))
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn segments(&self) -> &[LoadSegment] {
+        &self.segments
+    }
+
+    /// One past the highest physical address any segment occupies.
+    pub fn end(&self) -> u64 {
+        self.segments.iter().map(LoadSegment::end).max().unwrap_or(0)
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// Copy the segments of `image` into guest RAM. Nothing is written unless
+/// every segment fits.
+pub fn load_elf<B: HypervisorBackend>(backend: &mut B, image: &ElfImage) -> Result<(), LoadError> {
+    for segment in image.segments() {
+        if !is_ram(backend.memory_layout(), segment.paddr, segment.memsz) {
+            return Err(LoadError::NoSpace("ELF segment"));
+        }
+    }
+    for segment in image.segments() {
+        let file = &image.data[segment.offset as usize..][..segment.filesz as usize];
+        backend.write_guest(segment.paddr, file)?;
+        let bss = vec![0u8; (segment.memsz - segment.filesz) as usize];
+        backend.write_guest(segment.paddr + segment.filesz, &bss)?;
+    }
+    Ok(())
+}
+
+/// Load `image` and start `vcpu` at its entry point in 64-bit mode, with
+/// the low 4 GiB identity-mapped and RSP at [`BOOT_STACK`].
+pub fn boot_elf<B: HypervisorBackend>(backend: &mut B, vcpu: VcpuId, image: &ElfImage) -> Result<(), LoadError> {
+    load_elf(backend, image)?;
+    enter_long_mode(backend, vcpu)?;
+    let regs = VcpuRegisters { rip: image.entry(), rsp: BOOT_STACK, rflags: 1 << 1, ..Default::default() };
+    backend.set_registers(vcpu, &regs)?;
+    Ok(())
+}
+
+/// Read an ELF file stored contiguously from block `first_block`. Its
+/// length is taken from the furthest segment or program header.
+pub fn read_elf<S: StorageBackend + ?Sized>(storage: &S, block_size: usize, first_block: u64) -> Result<Vec<u8>, LoadError> {
+    let header = read_storage(storage, block_size, first_block, EHDR_SIZE)?;
+    let (phoff, phnum) = ElfImage::check_header(&header)?;
+    let table_end = phnum
+        .checked_mul(PHDR_SIZE)
+        .and_then(|size| phoff.checked_add(size))
+        .ok_or(LoadError::BadImage("bad program header table"))?;
+    let table = read_storage(storage, block_size, first_block, table_end)?;
+    let mut len = table.len();
+    for i in 0..phnum {
+        let ph = &table[phoff + i * PHDR_SIZE..][..PHDR_SIZE];
+        if read_u32(ph, 0) == PT_LOAD {
+            let end = read_u64(ph, 8)
+                .checked_add(read_u64(ph, 32))
+                .and_then(|end| usize::try_from(end).ok())
+                .ok_or(LoadError::BadImage("segment extends past end of file"))?;
+            len = len.max(end);
+        }
+    }
+    read_storage(storage, block_size, first_block, len)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hypervisor::{IoDirection, MockBackend, VmExit};
+    use crate::storage::tests::RamStorage;
+
+    /// An ELF64 executable with one PT_LOAD segment per `(paddr, bytes,
+    /// memsz)`, plus a PT_NOTE that the loader must skip.
+    pub(crate) fn elf(entry: u64, segments: &[(u64, &[u8], u64)]) -> Vec<u8> {
+        let phnum = segments.len() + 1;
+        let mut data = vec![0u8; EHDR_SIZE + phnum * PHDR_SIZE];
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[4] = ELFCLASS64;
+        data[5] = ELFDATA2LSB;
+        data[6] = 1;
+        data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        data[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        data[24..32].copy_from_slice(&entry.to_le_bytes());
+        data[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        data[52..54].copy_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        data[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        data[56..58].copy_from_slice(&(phnum as u16).to_le_bytes());
+        // PT_NOTE first.
+        data[EHDR_SIZE..EHDR_SIZE + 4].copy_from_slice(&4u32.to_le_bytes());
+        for (i, (paddr, bytes, memsz)) in segments.iter().enumerate() {
+            let offset = data.len() as u64;
+            let ph = EHDR_SIZE + (i + 1) * PHDR_SIZE;
+            let fields = [offset, *paddr, *paddr, bytes.len() as u64, *memsz];
+            data[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            data[ph + 4..ph + 8].copy_from_slice(&5u32.to_le_bytes());
+            for (j, field) in fields.iter().enumerate() {
+                data[ph + 8 + j * 8..ph + 16 + j * 8].copy_from_slice(&field.to_le_bytes());
+            }
+            data.extend_from_slice(bytes);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = elf(0x20_0000, &[(0x20_0000, &[0xF4; 16], 16), (0x30_0000, &[1, 2, 3, 4], 0x2000)]);
+        let image = ElfImage::parse(&data).unwrap();
+        assert_eq!(image.entry(), 0x20_0000);
+        assert_eq!(image.segments().len(), 2);
+        assert_eq!(image.segments()[1].filesz, 4);
+        assert_eq!(image.end(), 0x30_2000);
+
+        let mut bad = data.clone();
+        bad[4] = 1;
+        assert_eq!(ElfImage::parse(&bad).err(), Some(LoadError::BadImage("not a little-endian ELF64 file")));
+        let mut bad = data.clone();
+        bad[18] = 3;
+        assert_eq!(ElfImage::parse(&bad).err(), Some(LoadError::BadImage("not an x86-64 executable")));
+        assert_eq!(
+            ElfImage::parse(&data[..data.len() - 1]).err(),
+            Some(LoadError::BadImage("segment extends past end of file"))
+        );
+        assert_eq!(ElfImage::parse(&data[..100]).err(), Some(LoadError::BadImage("truncated program header table")));
+        assert_eq!(ElfImage::parse(b"MZ").err(), Some(LoadError::BadImage("not an ELF file")));
+    }
+
+    #[test]
+    fn test_read_elf() {
+        let data = elf(0x20_0000, &[(0x20_0000, &[0xF4; 16], 16)]);
+        let storage = RamStorage::new(64, 8);
+        let store = |data: &[u8]| {
+            for (i, block) in data.chunks(64).enumerate() {
+                let mut buf = [0u8; 64];
+                buf[..block.len()].copy_from_slice(block);
+                storage.write_block(1 + i as u64, &buf).unwrap();
+            }
+        };
+        store(&data);
+        assert_eq!(read_elf(&storage, 64, 1).unwrap(), data);
+
+        // Offsets that wrap around are refused rather than read.
+        let mut bad = data.clone();
+        bad[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        store(&bad);
+        assert_eq!(read_elf(&storage, 64, 1), Err(LoadError::BadImage("bad program header table")));
+        let mut bad = data.clone();
+        bad[EHDR_SIZE + PHDR_SIZE + 8..][..8].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        store(&bad);
+        assert_eq!(read_elf(&storage, 64, 1), Err(LoadError::BadImage("segment extends past end of file")));
+    }
+
+    #[test]
+    fn test_load_and_boot() {
+        // mov al, [0x300004]; out 0x80, al; mov al, [0x300002]; out 0x80, al; hlt
+        let code = [0x8A, 0x04, 0x25, 0x04, 0x00, 0x30, 0x00, 0xE6, 0x80, 0x8A, 0x04, 0x25, 0x02, 0x00, 0x30, 0x00, 0xE6, 0x80, 0xF4];
+        let data = elf(0x20_0000, &[(0x20_0000, &code, 0x1000), (0x30_0000, &[1, 2, 3, 4], 0x2000)]);
+        let image = ElfImage::parse(&data).unwrap();
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x40_0000).unwrap();
+        backend.create_vcpu(0).unwrap();
+        // Stale contents where the .bss goes.
+        backend.write_guest(0x30_0004, &[0xFF; 8]).unwrap();
+        boot_elf(&mut backend, 0, &image).unwrap();
+
+        let mut out = Vec::new();
+        loop {
+            match backend.run(0).unwrap() {
+                VmExit::Io { port: 0x80, direction: IoDirection::Out, data, .. } => out.push(data),
+                VmExit::Hlt => break,
+                exit => panic!("unexpected exit {:?}", exit),
+            }
+        }
+        assert_eq!(out, [0, 3]);
+
+        let mut small = MockBackend::new();
+        small.alloc_ram(0, 0x30_1000).unwrap();
+        assert_eq!(load_elf(&mut small, &image), Err(LoadError::NoSpace("ELF segment")));
+        let mut first = [0u8; 1];
+        small.read_guest(0x20_0000, &mut first).unwrap();
+        assert_eq!(first, [0]);
+    }
+}
@@ -0,0 +1,228 @@
+// Guest kernel loaders for the native VMX path: they place a kernel image
+// in guest memory and put the boot vCPU in the entry state its boot
+// protocol expects, with no firmware involved.
+//
+// The helpers here are shared between protocols: the boot GDT, identity
+// page tables and a memory map derived from the guest memory layout.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::hypervisor::{DescriptorTable, HvError, HypervisorBackend, Segment, SpecialRegisters, VcpuId, CR0_ET, CR0_PE};
+use crate::storage::StorageBackend;
+use crate::vmx::ept::{GuestMemoryLayout, RegionKind};
+
+pub mod bzimage;
+pub mod elf;
+pub mod multiboot2;
+
+pub use bzimage::{load_linux, read_bzimage, BzImage, LinuxBoot};
+pub use elf::{boot_elf, load_elf, read_elf, ElfImage, LoadSegment};
+pub use multiboot2::{load_multiboot2, Module, MultibootBoot, MultibootInfo, Multiboot2Header};
+
+/// Boot GDT, shared by every protocol.
+pub const BOOT_GDT: u64 = 0x500;
+/// Stack pointer handed to kernels that do not set up their own first.
+pub const BOOT_STACK: u64 = 0x8FF0;
+/// Identity page tables for 64-bit entry: PML4, PDPT, then four PDs.
+pub const BOOT_PML4: u64 = 0x9000;
+const BOOT_PDPT: u64 = 0xA000;
+const BOOT_PD: u64 = 0xB000;
+/// Bytes identity-mapped by the boot page tables.
+pub const IDENTITY_MAPPED: u64 = 4 << 30;
+
+/// Flat 32-bit code selector in the boot GDT, for protected-mode entry.
+pub const BOOT_CS32: u16 = 0x08;
+/// Selectors in the boot GDT, matching Linux's __BOOT_CS and __BOOT_DS.
+pub const BOOT_CS: u16 = 0x10;
+pub const BOOT_DS: u16 = 0x18;
+const BOOT_TSS: u16 = 0x20;
+
+/// Start and end of the legacy VGA/BIOS hole, which is never reported as RAM.
+const LEGACY_HOLE: (u64, u64) = (0xA_0000, 0x10_0000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The image is not in a format or protocol version the loader supports.
+    BadImage(&'static str),
+    /// A Multiboot2 kernel requires this boot information tag, which the
+    /// loader does not provide.
+    UnsupportedTag(u32),
+    /// Something does not fit in guest RAM.
+    NoSpace(&'static str),
+    /// The storage backend failed to read the image.
+    Storage,
+    Guest(HvError),
+}
+
+impl From<HvError> for LoadError {
+    fn from(err: HvError) -> Self {
+        LoadError::Guest(err)
+    }
+}
+
+/// Entry types shared by the e820 and Multiboot2 memory maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MemoryKind {
+    Ram = 1,
+    Reserved = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMapEntry {
+    pub addr: u64,
+    pub size: u64,
+    pub kind: MemoryKind,
+}
+
+/// The guest's physical memory map, sorted by address: RAM regions less
+/// the legacy hole, plus MMIO regions and the hole as reserved.
+pub fn memory_map(layout: &GuestMemoryLayout) -> Vec<MemoryMapEntry> {
+    let mut map = Vec::new();
+    let mut add = |addr: u64, end: u64, kind| {
+        if end > addr {
+            map.push(MemoryMapEntry { addr, size: end - addr, kind });
+        }
+    };
+    let mut hole_overlaps = false;
+    for region in layout.regions() {
+        match region.kind {
+            RegionKind::Ram { .. } => {
+                let (start, end) = (region.guest_phys, region.end());
+                hole_overlaps |= start < LEGACY_HOLE.1 && end > LEGACY_HOLE.0;
+                add(start, end.min(LEGACY_HOLE.0), MemoryKind::Ram);
+                add(start.max(LEGACY_HOLE.1), end, MemoryKind::Ram);
+            }
+            RegionKind::Mmio => add(region.guest_phys, region.end(), MemoryKind::Reserved),
+        }
+    }
+    if hole_overlaps {
+        add(LEGACY_HOLE.0, LEGACY_HOLE.1, MemoryKind::Reserved);
+    }
+    map.sort_by_key(|entry| entry.addr);
+    map
+}
+
+/// Whether `[addr, addr + len)` is inside a single RAM region.
+pub fn is_ram(layout: &GuestMemoryLayout, addr: u64, len: u64) -> bool {
+    match layout.find(addr) {
+        Some(region) => matches!(region.kind, RegionKind::Ram { .. }) && addr.saturating_add(len) <= region.end(),
+        None => false,
+    }
+}
+
+/// Read `len` bytes from `storage`, starting at block `first_block`.
+pub fn read_storage<S: StorageBackend + ?Sized>(
+    storage: &S,
+    block_size: usize,
+    first_block: u64,
+    len: usize,
+) -> Result<Vec<u8>, LoadError> {
+    let mut data = vec![0u8; len.div_ceil(block_size) * block_size];
+    for (i, block) in data.chunks_mut(block_size).enumerate() {
+        storage.read_block(first_block + i as u64, block).map_err(|_| LoadError::Storage)?;
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Write the boot GDT: flat 32-bit code at [`BOOT_CS32`], flat 64-bit code
+/// at [`BOOT_CS`], flat data at [`BOOT_DS`] and an empty TSS.
+fn write_boot_gdt<B: HypervisorBackend>(backend: &mut B) -> Result<DescriptorTable, LoadError> {
+    let gdt: [u64; 6] = [0, 0x00CF_9B00_0000_FFFF, 0x00AF_9B00_0000_FFFF, 0x00CF_9300_0000_FFFF, 0x0000_8B00_0000_0067, 0];
+    let mut bytes = [0u8; 48];
+    for (chunk, entry) in bytes.chunks_mut(8).zip(gdt) {
+        chunk.copy_from_slice(&entry.to_le_bytes());
+    }
+    backend.write_guest(BOOT_GDT, &bytes)?;
+    Ok(DescriptorTable { base: BOOT_GDT, limit: bytes.len() as u16 - 1 })
+}
+
+/// Identity-map the first [`IDENTITY_MAPPED`] bytes with 2 MiB pages.
+fn write_identity_tables<B: HypervisorBackend>(backend: &mut B) -> Result<(), LoadError> {
+    let pds = IDENTITY_MAPPED >> 30;
+    backend.write_guest(BOOT_PML4, &(BOOT_PDPT | 3).to_le_bytes())?;
+    let mut pdpt = vec![0u8; 4096];
+    for i in 0..pds {
+        let entry = (BOOT_PD + i * 4096) | 3;
+        pdpt[i as usize * 8..][..8].copy_from_slice(&entry.to_le_bytes());
+    }
+    backend.write_guest(BOOT_PDPT, &pdpt)?;
+    let mut pd = vec![0u8; 4096 * pds as usize];
+    for (i, entry) in pd.chunks_mut(8).enumerate() {
+        // Present, writable, 2 MiB page.
+        entry.copy_from_slice(&(((i as u64) << 21) | 0x83).to_le_bytes());
+    }
+    backend.write_guest(BOOT_PD, &pd)?;
+    Ok(())
+}
+
+/// Put `vcpu` in 64-bit mode on the boot GDT and identity page tables.
+/// Memory from [`BOOT_GDT`] up to the end of the page tables must be RAM.
+pub fn enter_long_mode<B: HypervisorBackend>(backend: &mut B, vcpu: VcpuId) -> Result<(), LoadError> {
+    let gdt = write_boot_gdt(backend)?;
+    write_identity_tables(backend)?;
+    let mut sregs = SpecialRegisters::long_mode(BOOT_PML4);
+    let data = Segment::flat_data(BOOT_DS);
+    sregs.cs = Segment::long_mode_code(BOOT_CS);
+    sregs.ds = data;
+    sregs.es = data;
+    sregs.fs = data;
+    sregs.gs = data;
+    sregs.ss = data;
+    sregs.tr.selector = BOOT_TSS;
+    sregs.gdt = gdt;
+    backend.set_special_registers(vcpu, &sregs)?;
+    Ok(())
+}
+
+/// Put `vcpu` in flat 32-bit protected mode on the boot GDT, paging off.
+pub fn enter_protected_mode<B: HypervisorBackend>(backend: &mut B, vcpu: VcpuId) -> Result<(), LoadError> {
+    let gdt = write_boot_gdt(backend)?;
+    let data = Segment::flat_data(BOOT_DS);
+    let sregs = SpecialRegisters {
+        cs: Segment::flat_code(BOOT_CS32),
+        ds: data,
+        es: data,
+        fs: data,
+        gs: data,
+        ss: data,
+        tr: Segment { selector: BOOT_TSS, base: 0, limit: 0x67, access_rights: 0x8B },
+        ldtr: Segment { access_rights: Segment::UNUSABLE, ..Default::default() },
+        gdt,
+        cr0: CR0_PE | CR0_ET,
+        ..Default::default()
+    };
+    backend.set_special_registers(vcpu, &sregs)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmx::ept::MemoryRegion;
+
+    #[test]
+    fn test_memory_map() {
+        let mut layout = GuestMemoryLayout::new();
+        layout.add(MemoryRegion::ram(0, 0x800_0000, 0x1000_0000)).unwrap();
+        layout.add(MemoryRegion::mmio(0xFEC0_0000, 0x1000)).unwrap();
+        layout.add(MemoryRegion::ram(0x1_0000_0000, 0x1000_0000, 0x2000_0000)).unwrap();
+        let map = memory_map(&layout);
+        let entry = |addr, size, kind| MemoryMapEntry { addr, size, kind };
+        assert_eq!(
+            map,
+            [
+                entry(0, 0xA_0000, MemoryKind::Ram),
+                entry(0xA_0000, 0x6_0000, MemoryKind::Reserved),
+                entry(0x10_0000, 0x7F0_0000, MemoryKind::Ram),
+                entry(0xFEC0_0000, 0x1000, MemoryKind::Reserved),
+                entry(0x1_0000_0000, 0x1000_0000, MemoryKind::Ram),
+            ]
+        );
+        assert!(is_ram(&layout, 0x10_0000, 0x100_0000));
+        assert!(!is_ram(&layout, 0x7FF_F000, 0x2000));
+        assert!(!is_ram(&layout, 0xFEC0_0000, 4));
+    }
+}
@@ -0,0 +1,455 @@
+// Multiboot2 loader: finds the Multiboot2 header, loads the kernel either
+// through its address tag or as an ELF64 file, places modules after it,
+// builds the boot information structure and starts the vCPU in 32-bit
+// protected mode as the specification requires.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::elf::{load_elf, ElfImage};
+use super::{enter_protected_mode, is_ram, memory_map, LoadError, MemoryKind, MemoryMapEntry, BOOT_STACK, IDENTITY_MAPPED};
+use crate::hypervisor::{HypervisorBackend, VcpuId, VcpuRegisters};
+
+pub const HEADER_MAGIC: u32 = 0xE852_50D6;
+/// EAX on entry.
+pub const BOOTLOADER_MAGIC: u32 = 0x36D7_6289;
+/// The header must be 8-byte aligned within this many bytes of the image.
+const HEADER_SEARCH: usize = 32 * 1024;
+const ARCH_I386: u32 = 0;
+
+// Header tags.
+const HEADER_TAG_END: u16 = 0;
+const HEADER_TAG_INFO_REQUEST: u16 = 1;
+const HEADER_TAG_ADDRESS: u16 = 2;
+const HEADER_TAG_ENTRY: u16 = 3;
+const HEADER_TAG_OPTIONAL: u16 = 1;
+
+// Boot information tags.
+const TAG_END: u32 = 0;
+pub const TAG_CMDLINE: u32 = 1;
+pub const TAG_LOADER_NAME: u32 = 2;
+pub const TAG_MODULE: u32 = 3;
+pub const TAG_BASIC_MEMINFO: u32 = 4;
+pub const TAG_MMAP: u32 = 6;
+const SUPPORTED_TAGS: [u32; 5] = [TAG_CMDLINE, TAG_LOADER_NAME, TAG_MODULE, TAG_BASIC_MEMINFO, TAG_MMAP];
+
+const LOADER_NAME: &str = "Hypercore";
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// The Multiboot2 address tag: where to load an image that is not ELF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressTag {
+    pub header_addr: u32,
+    pub load_addr: u32,
+    /// Zero means the rest of the file.
+    pub load_end_addr: u32,
+    /// Zero means no .bss.
+    pub bss_end_addr: u32,
+}
+
+/// The parts of a Multiboot2 header the loader acts on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Multiboot2Header {
+    /// Offset of the header in the image.
+    pub offset: usize,
+    pub address: Option<AddressTag>,
+    pub entry: Option<u32>,
+    /// Information tags the kernel cannot boot without.
+    pub required_tags: Vec<u32>,
+}
+
+impl Multiboot2Header {
+    /// Find and validate the header of `image`.
+    pub fn find(image: &[u8]) -> Result<Self, LoadError> {
+        let limit = image.len().min(HEADER_SEARCH);
+        let offset = (0..limit.saturating_sub(15))
+            .step_by(8)
+            .find(|&offset| {
+                let field = |i: usize| read_u32(image, offset + i * 4);
+                field(0) == HEADER_MAGIC
+                    && field(1) == ARCH_I386
+                    && field(0).wrapping_add(field(1)).wrapping_add(field(2)).wrapping_add(field(3)) == 0
+            })
+            .ok_or(LoadError::BadImage("no Multiboot2 header"))?;
+        let length = read_u32(image, offset + 8) as usize;
+        let header = image.get(offset..offset + length).ok_or(LoadError::BadImage("truncated Multiboot2 header"))?;
+
+        let mut parsed = Multiboot2Header { offset, address: None, entry: None, required_tags: Vec::new() };
+        let mut tag = 16;
+        while tag + 8 <= header.len() {
+            let kind = read_u16(header, tag);
+            let flags = read_u16(header, tag + 2);
+            let size = read_u32(header, tag + 4) as usize;
+            if size < 8 || tag + size > header.len() {
+                return Err(LoadError::BadImage("bad Multiboot2 header tag"));
+            }
+            let body = &header[tag + 8..tag + size];
+            match kind {
+                HEADER_TAG_END => break,
+                HEADER_TAG_INFO_REQUEST if flags & HEADER_TAG_OPTIONAL == 0 => {
+                    parsed.required_tags.extend(body.chunks_exact(4).map(|chunk| read_u32(chunk, 0)));
+                }
+                HEADER_TAG_ADDRESS if body.len() >= 16 => {
+                    parsed.address = Some(AddressTag {
+                        header_addr: read_u32(body, 0),
+                        load_addr: read_u32(body, 4),
+                        load_end_addr: read_u32(body, 8),
+                        bss_end_addr: read_u32(body, 12),
+                    });
+                }
+                HEADER_TAG_ENTRY if body.len() >= 4 => parsed.entry = Some(read_u32(body, 0)),
+                // Console, framebuffer, alignment and EFI tags are hints this
+                // loader does not honour; a required one it cannot satisfy
+                // is still refused below.
+                _ if flags & HEADER_TAG_OPTIONAL == 0 && kind > HEADER_TAG_ENTRY => {
+                    return Err(LoadError::BadImage("unsupported required Multiboot2 header tag"));
+                }
+                _ => {}
+            }
+            tag += align_up(size as u64, 8) as usize;
+        }
+        if let Some(&tag) = parsed.required_tags.iter().find(|tag| !SUPPORTED_TAGS.contains(tag)) {
+            return Err(LoadError::UnsupportedTag(tag));
+        }
+        Ok(parsed)
+    }
+}
+
+/// Builds a Multiboot2 boot information structure.
+pub struct MultibootInfo {
+    data: Vec<u8>,
+}
+
+impl MultibootInfo {
+    pub fn new() -> Self {
+        // total_size and reserved, filled in by `finish`.
+        MultibootInfo { data: vec![0u8; 8] }
+    }
+
+    fn add_tag(&mut self, kind: u32, body: &[u8]) {
+        self.data.extend_from_slice(&kind.to_le_bytes());
+        self.data.extend_from_slice(&(8 + body.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(body);
+        self.data.resize(align_up(self.data.len() as u64, 8) as usize, 0);
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(value.len() + 1);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(0);
+        bytes
+    }
+
+    pub fn add_cmdline(&mut self, cmdline: &str) {
+        self.add_tag(TAG_CMDLINE, &Self::string(cmdline));
+    }
+
+    pub fn add_loader_name(&mut self, name: &str) {
+        self.add_tag(TAG_LOADER_NAME, &Self::string(name));
+    }
+
+    /// A module loaded at `[start, end)`.
+    pub fn add_module(&mut self, start: u32, end: u32, cmdline: &str) {
+        let mut body = Vec::new();
+        body.extend_from_slice(&start.to_le_bytes());
+        body.extend_from_slice(&end.to_le_bytes());
+        body.extend_from_slice(&Self::string(cmdline));
+        self.add_tag(TAG_MODULE, &body);
+    }
+
+    /// KiB of lower memory (from 0) and upper memory (from 1 MiB).
+    pub fn add_basic_meminfo(&mut self, mem_lower: u32, mem_upper: u32) {
+        let mut body = [0u8; 8];
+        body[..4].copy_from_slice(&mem_lower.to_le_bytes());
+        body[4..].copy_from_slice(&mem_upper.to_le_bytes());
+        self.add_tag(TAG_BASIC_MEMINFO, &body);
+    }
+
+    pub fn add_memory_map(&mut self, map: &[MemoryMapEntry]) {
+        // entry_size and entry_version.
+        let mut body = Vec::with_capacity(8 + map.len() * 24);
+        body.extend_from_slice(&24u32.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        for entry in map {
+            body.extend_from_slice(&entry.addr.to_le_bytes());
+            body.extend_from_slice(&entry.size.to_le_bytes());
+            body.extend_from_slice(&(entry.kind as u32).to_le_bytes());
+            body.extend_from_slice(&0u32.to_le_bytes());
+        }
+        self.add_tag(TAG_MMAP, &body);
+    }
+
+    /// Append the end tag and return the finished structure.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.add_tag(TAG_END, &[]);
+        let total = self.data.len() as u32;
+        self.data[..4].copy_from_slice(&total.to_le_bytes());
+        self.data
+    }
+}
+
+impl Default for MultibootInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A boot module and its command line.
+#[derive(Debug, Clone, Copy)]
+pub struct Module<'a> {
+    pub data: &'a [u8],
+    pub cmdline: &'a str,
+}
+
+/// Where [`load_multiboot2`] put things.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultibootBoot {
+    pub entry: u64,
+    /// Address of the boot information structure, passed in EBX.
+    pub info: u64,
+    /// Start and end of each module.
+    pub modules: Vec<(u64, u64)>,
+}
+
+/// Load a kernel through its address tag. Returns the end of its image
+/// and .bss.
+fn load_address_tag<B: HypervisorBackend>(
+    backend: &mut B,
+    image: &[u8],
+    header: &Multiboot2Header,
+    tag: &AddressTag,
+) -> Result<u64, LoadError> {
+    let start = (header.offset as u64)
+        .checked_sub(tag.header_addr.wrapping_sub(tag.load_addr) as u64)
+        .ok_or(LoadError::BadImage("load address after header address"))? as usize;
+    let end = match tag.load_end_addr {
+        0 => image.len(),
+        load_end => start + load_end.wrapping_sub(tag.load_addr) as usize,
+    };
+    let data = image.get(start..end).ok_or(LoadError::BadImage("load range outside the image"))?;
+    let load = tag.load_addr as u64;
+    let kernel_end = (load + data.len() as u64).max(tag.bss_end_addr as u64);
+    if !is_ram(backend.memory_layout(), load, kernel_end - load) {
+        return Err(LoadError::NoSpace("kernel"));
+    }
+    backend.write_guest(load, data)?;
+    let bss = vec![0u8; (kernel_end - load - data.len() as u64) as usize];
+    backend.write_guest(load + data.len() as u64, &bss)?;
+    Ok(kernel_end)
+}
+
+/// Load a Multiboot2 kernel with `modules` and start `vcpu` at its entry
+/// point in 32-bit protected mode, with EAX holding [`BOOTLOADER_MAGIC`]
+/// and EBX the boot information address.
+pub fn load_multiboot2<B: HypervisorBackend>(
+    backend: &mut B,
+    vcpu: VcpuId,
+    image: &[u8],
+    cmdline: &str,
+    modules: &[Module],
+) -> Result<MultibootBoot, LoadError> {
+    let header = Multiboot2Header::find(image)?;
+    let (kernel_end, elf_entry) = match &header.address {
+        Some(tag) => (load_address_tag(backend, image, &header, tag)?, None),
+        None => {
+            let elf = ElfImage::parse(image)?;
+            load_elf(backend, &elf)?;
+            (elf.end(), Some(elf.entry()))
+        }
+    };
+    let entry = match (header.entry, elf_entry) {
+        (Some(entry), _) => entry as u64,
+        (None, Some(entry)) => entry,
+        (None, None) => return Err(LoadError::BadImage("no entry address")),
+    };
+    if entry >= IDENTITY_MAPPED {
+        return Err(LoadError::BadImage("entry point above 4 GiB"));
+    }
+
+    let map = memory_map(backend.memory_layout());
+    let mut info = MultibootInfo::new();
+    info.add_cmdline(cmdline);
+    info.add_loader_name(LOADER_NAME);
+    let ram_at = |addr: u64| {
+        map.iter().find(|e| e.kind == MemoryKind::Ram && e.addr == addr).map_or(0, |e| (e.size / 1024) as u32)
+    };
+    info.add_basic_meminfo(ram_at(0), ram_at(0x10_0000));
+    info.add_memory_map(&map);
+
+    let mut next = align_up(kernel_end, 4096);
+    let mut placed = Vec::with_capacity(modules.len());
+    for module in modules {
+        let end = next + module.data.len() as u64;
+        if end > IDENTITY_MAPPED || !is_ram(backend.memory_layout(), next, module.data.len() as u64) {
+            return Err(LoadError::NoSpace("module"));
+        }
+        backend.write_guest(next, module.data)?;
+        info.add_module(next as u32, end as u32, module.cmdline);
+        placed.push((next, end));
+        next = align_up(end, 4096);
+    }
+    let info = info.finish();
+    if next + info.len() as u64 > IDENTITY_MAPPED || !is_ram(backend.memory_layout(), next, info.len() as u64) {
+        return Err(LoadError::NoSpace("boot information"));
+    }
+    backend.write_guest(next, &info)?;
+
+    enter_protected_mode(backend, vcpu)?;
+    let regs = VcpuRegisters {
+        rax: BOOTLOADER_MAGIC as u64,
+        rbx: next,
+        rsp: BOOT_STACK,
+        rip: entry,
+        rflags: 1 << 1,
+        ..Default::default()
+    };
+    backend.set_registers(vcpu, &regs)?;
+    Ok(MultibootBoot { entry, info: next, modules: placed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::MockBackend;
+    use crate::loader::elf::tests::elf;
+    use crate::loader::BOOT_CS32;
+
+    /// A Multiboot2 header with the given tags (type, flags, body), each
+    /// padded to 8 bytes.
+    fn header(tags: &[(u16, u16, &[u8])]) -> Vec<u8> {
+        let mut data = vec![0u8; 16];
+        for (kind, flags, body) in tags.iter().chain([(HEADER_TAG_END, 0, &[][..])].iter()) {
+            data.extend_from_slice(&kind.to_le_bytes());
+            data.extend_from_slice(&flags.to_le_bytes());
+            data.extend_from_slice(&(8 + body.len() as u32).to_le_bytes());
+            data.extend_from_slice(body);
+            data.resize(align_up(data.len() as u64, 8) as usize, 0);
+        }
+        let length = data.len() as u32;
+        let checksum = 0u32.wrapping_sub(HEADER_MAGIC).wrapping_sub(ARCH_I386).wrapping_sub(length);
+        for (i, field) in [HEADER_MAGIC, ARCH_I386, length, checksum].iter().enumerate() {
+            data[i * 4..i * 4 + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        data
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    /// Walk the boot information at `addr`, returning (type, body) pairs.
+    fn tags(backend: &MockBackend, addr: u64) -> Vec<(u32, Vec<u8>)> {
+        let mut size = [0u8; 4];
+        backend.read_guest(addr, &mut size).unwrap();
+        let mut data = vec![0u8; u32::from_le_bytes(size) as usize];
+        backend.read_guest(addr, &mut data).unwrap();
+        let mut tags = Vec::new();
+        let mut offset = 8;
+        loop {
+            let kind = read_u32(&data, offset);
+            let size = read_u32(&data, offset + 4) as usize;
+            if kind == TAG_END {
+                assert_eq!(offset + size, data.len());
+                return tags;
+            }
+            tags.push((kind, data[offset + 8..offset + size].to_vec()));
+            offset += align_up(size as u64, 8) as usize;
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let request = words(&[TAG_CMDLINE, TAG_MMAP]);
+        let entry = words(&[0x10_0040]);
+        let mut image = vec![0u8; 24];
+        image.extend_from_slice(&header(&[(HEADER_TAG_INFO_REQUEST, 0, &request), (HEADER_TAG_ENTRY, 0, &entry)]));
+        let parsed = Multiboot2Header::find(&image).unwrap();
+        assert_eq!(parsed.offset, 24);
+        assert_eq!(parsed.entry, Some(0x10_0040));
+        assert_eq!(parsed.required_tags, [TAG_CMDLINE, TAG_MMAP]);
+
+        // A bad checksum is not a header.
+        let mut broken = image.clone();
+        broken[24 + 12] ^= 1;
+        assert_eq!(Multiboot2Header::find(&broken), Err(LoadError::BadImage("no Multiboot2 header")));
+        // Framebuffer info is requested but cannot be provided.
+        let image = header(&[(HEADER_TAG_INFO_REQUEST, 0, &words(&[8]))]);
+        assert_eq!(
+            Multiboot2Header::find(&image),
+            Err(LoadError::UnsupportedTag(8))
+        );
+        let optional = header(&[(HEADER_TAG_INFO_REQUEST, HEADER_TAG_OPTIONAL, &words(&[8]))]);
+        assert!(Multiboot2Header::find(&optional).is_ok());
+    }
+
+    #[test]
+    fn test_load_elf_kernel_with_modules() {
+        // The header sits in the first segment, 8-byte aligned in the file.
+        let mut text = vec![0u8; 8];
+        text.extend_from_slice(&header(&[]));
+        text.extend_from_slice(&[0xF4; 8]);
+        let image = elf(0x10_0000, &[(0x10_0000, &text, 0x3000)]);
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x100_0000).unwrap();
+        backend.create_vcpu(0).unwrap();
+        let modules = [Module { data: &[7u8; 0x1001], cmdline: "initrd" }, Module { data: b"cfg", cmdline: "" }];
+        let boot = load_multiboot2(&mut backend, 0, &image, "quiet", &modules).unwrap();
+        assert_eq!(boot.entry, 0x10_0000);
+        assert_eq!(boot.modules, [(0x10_3000, 0x10_4001), (0x10_5000, 0x10_5003)]);
+        assert_eq!(boot.info, 0x10_6000);
+
+        let regs = backend.get_registers(0).unwrap();
+        assert_eq!((regs.rax, regs.rbx, regs.rip), (BOOTLOADER_MAGIC as u64, 0x10_6000, 0x10_0000));
+        let sregs = backend.get_special_registers(0).unwrap();
+        assert!(sregs.is_protected() && !sregs.is_long_mode());
+        assert_eq!(sregs.cs.selector, BOOT_CS32);
+
+        let tags = tags(&backend, boot.info);
+        assert_eq!(tags[0], (TAG_CMDLINE, b"quiet\0".to_vec()));
+        assert_eq!(tags[1], (TAG_LOADER_NAME, b"Hypercore\0".to_vec()));
+        assert_eq!(tags[2], (TAG_BASIC_MEMINFO, words(&[640, 15 * 1024])));
+        let mmap = &tags[3].1;
+        assert_eq!(tags[3].0, TAG_MMAP);
+        assert_eq!(read_u32(mmap, 0), 24);
+        assert_eq!((mmap.len() - 8) / 24, 3);
+        assert_eq!(&mmap[8 + 48..8 + 72], &[&0x10_0000u64.to_le_bytes()[..], &0xF0_0000u64.to_le_bytes(), &words(&[1, 0])].concat()[..]);
+        assert_eq!(tags[4], (TAG_MODULE, [&words(&[0x10_3000, 0x10_4001])[..], b"initrd\0"].concat()));
+        assert_eq!(tags[5], (TAG_MODULE, [&words(&[0x10_5000, 0x10_5003])[..], b"\0"].concat()));
+        let mut module = [0u8; 3];
+        backend.read_guest(0x10_5000, &mut module).unwrap();
+        assert_eq!(&module, b"cfg");
+    }
+
+    #[test]
+    fn test_load_address_tag_kernel() {
+        // Flat binary loaded at 1 MiB: 0x40 bytes of code, then the header.
+        let mut image = vec![0x90u8; 0x40];
+        let address = words(&[0x10_0040, 0x10_0000, 0, 0x10_2000]);
+        let entry = words(&[0x10_0010]);
+        image.extend_from_slice(&header(&[(HEADER_TAG_ADDRESS, 0, &address), (HEADER_TAG_ENTRY, 0, &entry)]));
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x40_0000).unwrap();
+        backend.create_vcpu(0).unwrap();
+        backend.write_guest(0x10_1000, &[0xAA; 16]).unwrap();
+        let boot = load_multiboot2(&mut backend, 0, &image, "", &[]).unwrap();
+        assert_eq!(boot.entry, 0x10_0010);
+        assert_eq!(boot.info, 0x10_2000);
+        let mut loaded = [0u8; 4];
+        backend.read_guest(0x10_003C, &mut loaded).unwrap();
+        assert_eq!(loaded, [0x90, 0x90, 0x90, 0x90]);
+        // .bss is cleared.
+        backend.read_guest(0x10_1000, &mut loaded).unwrap();
+        assert_eq!(loaded, [0; 4]);
+    }
+}
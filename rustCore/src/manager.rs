@@ -0,0 +1,559 @@
+// The VMs Hypercore knows about, and running them on its own hypervisor.
+//
+// A VM is a record of its configuration until it is booted. Booted natively,
+// it gets a `Machine` whose vCPUs run whenever `run_native_vms` is called,
+// until it is stopped or exits for something no device handles. VMs booted
+// under QEMU are the caller's to run; the manager only keeps their records.
+//
+// Volumes are shared with VMs under QEMU over virtio-serial ports. Native VMs
+// have no virtio-9p device yet, so a VM with volumes only boots under QEMU.
+//
+// The shell and the GUI are front ends to this module: everything they do to
+// a VM goes through a `VmManager`, and they only print what it returns.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::hypervisor::{
+    HvError, HypervisorBackend, Machine, SnapshotError, SnapshotId, SnapshotInfo, SnapshotStore, VcpuId, VcpuRunState,
+    VmExit, MAX_VCPUS,
+};
+use crate::loader::bzimage::{load_linux, BzImage};
+use crate::loader::LoadError;
+use crate::memory::take_guest_frames;
+use crate::process::{ProcessManager, PROCESS_MANAGER};
+use crate::storage::volume::{Access, SharedVolumeStore, VolumeError, VolumeHandle};
+use crate::storage::StorageBackend;
+use crate::vdev::console::{ConsoleBuffer, SharedConsole, DEFAULT_CONSOLE_CAPACITY};
+use crate::vmx::backend::VmxBackend;
+use crate::vmx::cpu_model::{host_cpuid, CpuModel};
+
+/// Room for a native VM's EPT tables, on top of its RAM.
+const EPT_RESERVE: u64 = 4 << 20;
+/// Kernel command line of native VMs.
+pub const NATIVE_CMDLINE: &str = "console=ttyS0";
+/// Block size snapshot stores are formatted with.
+pub const SNAPSHOT_BLOCK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManagerError {
+    NoSuchVm,
+    VmExists,
+    /// Each CPU becomes a vCPU with its own VMCS and thread, so a VM has
+    /// between 1 and `MAX_VCPUS`.
+    BadCpuCount(usize),
+    /// The VM is already running natively.
+    AlreadyRunning,
+    /// The VM is not running natively.
+    NotRunning,
+    /// Not enough memory is set aside for native VMs.
+    NoMemory,
+    /// The kernel image is bad or does not fit the VM.
+    Kernel(LoadError),
+    Hv(HvError),
+    NoSuchSnapshot,
+    SnapshotExists,
+    Snapshot(SnapshotError),
+    /// A volume quota of no MiB, or of more than fit in a u64 of bytes.
+    BadQuota(u64),
+    Volume(VolumeError),
+    /// The VM has volumes, which only VMs under QEMU can reach.
+    VolumesNeedQemu,
+}
+
+impl From<LoadError> for ManagerError {
+    fn from(err: LoadError) -> Self {
+        ManagerError::Kernel(err)
+    }
+}
+
+impl From<HvError> for ManagerError {
+    fn from(err: HvError) -> Self {
+        ManagerError::Hv(err)
+    }
+}
+
+impl From<SnapshotError> for ManagerError {
+    fn from(err: SnapshotError) -> Self {
+        ManagerError::Snapshot(err)
+    }
+}
+
+impl From<VolumeError> for ManagerError {
+    fn from(err: VolumeError) -> Self {
+        ManagerError::Volume(err)
+    }
+}
+
+pub struct VmRecord<B: HypervisorBackend, S: StorageBackend> {
+    name: String,
+    /// Identifies the VM's vCPU threads to the scheduler.
+    id: u64,
+    ram_mb: usize,
+    cpus: usize,
+    /// Path to Ceph RBD image or other disk
+    disk_image: String,
+    /// Optional path to Ubuntu ISO
+    iso_path: Option<String>,
+    /// Snapshots of vCPU state and RAM, created with the first one. Disks
+    /// are not part of them.
+    snapshots: Option<SnapshotStore<S>>,
+    /// Output of the guest's COM1, fed by its emulated 16550.
+    console: SharedConsole,
+    /// The VM while it runs natively rather than under QEMU.
+    machine: Option<Machine<B>>,
+}
+
+impl<B: HypervisorBackend, S: StorageBackend> VmRecord<B, S> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn ram_mb(&self) -> usize {
+        self.ram_mb
+    }
+
+    pub fn cpus(&self) -> usize {
+        self.cpus
+    }
+
+    pub fn disk_image(&self) -> &str {
+        &self.disk_image
+    }
+
+    pub fn iso_path(&self) -> Option<&str> {
+        self.iso_path.as_deref()
+    }
+
+    pub fn console(&self) -> &SharedConsole {
+        &self.console
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.machine.is_some()
+    }
+
+    pub fn machine_mut(&mut self) -> Option<&mut Machine<B>> {
+        self.machine.as_mut()
+    }
+
+    /// Every snapshot of the VM, oldest first.
+    pub fn snapshots(&self) -> Result<Vec<SnapshotInfo>, ManagerError> {
+        let mut snapshots = match &self.snapshots {
+            Some(store) => store.list()?,
+            None => Vec::new(),
+        };
+        snapshots.reverse();
+        Ok(snapshots)
+    }
+}
+
+/// A volume a VM booting under QEMU has access to, attached or with why it
+/// could not be.
+pub type AttachedVolume<V> = (String, Result<VolumeHandle<V>, VolumeError>);
+
+/// A native VM that [`VmManager::run_native_vms`] stopped, with the vCPU
+/// that stopped it and the exit or failure no device handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stopped {
+    pub vm: String,
+    pub vcpu: VcpuId,
+    pub cause: Result<VmExit, HvError>,
+}
+
+pub struct VmManager<B: HypervisorBackend, S: StorageBackend, V: StorageBackend> {
+    vms: Vec<VmRecord<B, S>>,
+    next_id: u64,
+    volumes: SharedVolumeStore<V>,
+    processes: &'static Mutex<ProcessManager>,
+}
+
+impl<B: HypervisorBackend, S: StorageBackend, V: StorageBackend> VmManager<B, S, V> {
+    /// A manager of no VMs yet, sharing the volumes in `volumes` with them.
+    pub fn new(volumes: SharedVolumeStore<V>) -> Self {
+        Self::with_processes(volumes, &PROCESS_MANAGER)
+    }
+
+    /// Like `new`, with native VMs' vCPU threads created by `processes`
+    /// rather than the global process manager.
+    pub fn with_processes(volumes: SharedVolumeStore<V>, processes: &'static Mutex<ProcessManager>) -> Self {
+        VmManager { vms: Vec::new(), next_id: 1, volumes, processes }
+    }
+
+    pub fn vms(&self) -> &[VmRecord<B, S>] {
+        &self.vms
+    }
+
+    pub fn vm(&self, name: &str) -> Result<&VmRecord<B, S>, ManagerError> {
+        self.vms.iter().find(|vm| vm.name == name).ok_or(ManagerError::NoSuchVm)
+    }
+
+    pub fn vm_mut(&mut self, name: &str) -> Result<&mut VmRecord<B, S>, ManagerError> {
+        self.vms.iter_mut().find(|vm| vm.name == name).ok_or(ManagerError::NoSuchVm)
+    }
+
+    // TODO: Persist VM records to storage on every change, and load them
+    // back at start.
+
+    pub fn create_vm(
+        &mut self,
+        name: &str,
+        ram_mb: usize,
+        cpus: usize,
+        disk_image: &str,
+        iso_path: Option<&str>,
+    ) -> Result<(), ManagerError> {
+        check_cpus(cpus)?;
+        if self.vm(name).is_ok() {
+            return Err(ManagerError::VmExists);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.vms.push(VmRecord {
+            name: String::from(name),
+            id,
+            ram_mb,
+            cpus,
+            disk_image: String::from(disk_image),
+            iso_path: iso_path.map(String::from),
+            snapshots: None,
+            console: ConsoleBuffer::shared(DEFAULT_CONSOLE_CAPACITY),
+            machine: None,
+        });
+        Ok(())
+    }
+
+    /// Change a VM's RAM or CPUs. A running VM keeps what it booted with
+    /// until it boots again.
+    pub fn update_vm(&mut self, name: &str, ram_mb: Option<usize>, cpus: Option<usize>) -> Result<(), ManagerError> {
+        if let Some(cpus) = cpus {
+            check_cpus(cpus)?;
+        }
+        let vm = self.vm_mut(name)?;
+        if let Some(ram_mb) = ram_mb {
+            vm.ram_mb = ram_mb;
+        }
+        if let Some(cpus) = cpus {
+            vm.cpus = cpus;
+        }
+        Ok(())
+    }
+
+    /// Forget a VM and its snapshots, stopping it first if it runs
+    /// natively, and take it off every volume.
+    pub fn delete_vm(&mut self, name: &str) -> Result<(), ManagerError> {
+        let index = self.vms.iter().position(|vm| vm.name == name).ok_or(ManagerError::NoSuchVm)?;
+        self.vms.remove(index);
+        self.volumes.lock().remove_vm(name);
+        Ok(())
+    }
+
+    /// Boot a VM straight into the Linux kernel `image`, on the backend
+    /// `backend` makes for it with the VM's RAM mapped at 0. Its vCPUs run
+    /// from [`run_native_vms`](Self::run_native_vms).
+    pub fn boot_native(
+        &mut self,
+        name: &str,
+        image: &[u8],
+        backend: impl FnOnce(&VmRecord<B, S>) -> Result<B, ManagerError>,
+        clock: Box<dyn Fn() -> u64 + Send>,
+    ) -> Result<(), ManagerError> {
+        let processes = self.processes;
+        let has_volumes = !self.volumes.lock().volumes_for(name).is_empty();
+        let vm = self.vm_mut(name)?;
+        if vm.machine.is_some() {
+            return Err(ManagerError::AlreadyRunning);
+        }
+        if has_volumes {
+            return Err(ManagerError::VolumesNeedQemu);
+        }
+        let image = BzImage::parse(image)?;
+        let backend = backend(vm)?;
+        let mut machine = Machine::with_processes(vm.id, backend, vm.cpus, clock, vm.console.clone(), processes)?;
+        load_linux(machine.vm_mut().backend_mut(), 0, &image, NATIVE_CMDLINE, None)?;
+        vm.machine = Some(machine);
+        Ok(())
+    }
+
+    /// Give the runnable vCPUs of every native VM a turn in the guest. A VM
+    /// whose vCPU exits for something no device handles is stopped, and
+    /// returned.
+    pub fn run_native_vms(&mut self) -> Vec<Stopped> {
+        let mut stopped = Vec::new();
+        for vm in self.vms.iter_mut() {
+            let Some(machine) = vm.machine.as_mut() else { continue };
+            machine.sync_threads();
+            for vcpu in machine.vm().vcpus().to_vec() {
+                if machine.vm().coordinator().state(vcpu) != VcpuRunState::Runnable {
+                    continue;
+                }
+                match machine.run_vcpu(vcpu) {
+                    Ok(VmExit::Hlt) => {}
+                    cause => {
+                        stopped.push(Stopped { vm: vm.name.clone(), vcpu, cause });
+                        vm.machine = None;
+                        break;
+                    }
+                }
+            }
+        }
+        stopped
+    }
+
+    /// Snapshot a native VM. Its first snapshot formats the store on what
+    /// `storage` returns.
+    pub fn take_snapshot(
+        &mut self,
+        name: &str,
+        snapshot: &str,
+        storage: impl FnOnce() -> Result<S, SnapshotError>,
+    ) -> Result<SnapshotId, ManagerError> {
+        let vm = self.vm_mut(name)?;
+        if vm.snapshots()?.iter().any(|s| s.name == snapshot) {
+            return Err(ManagerError::SnapshotExists);
+        }
+        let machine = vm.machine.as_mut().ok_or(ManagerError::NotRunning)?;
+        let store = match vm.snapshots.take() {
+            Some(store) => store,
+            None => SnapshotStore::format(storage()?, SNAPSHOT_BLOCK_SIZE)?,
+        };
+        Ok(vm.snapshots.insert(store).take(machine.vm_mut(), snapshot)?)
+    }
+
+    /// Put a native VM back in the state it had at `snapshot`, with its
+    /// vCPU threads brought up to date.
+    pub fn restore_snapshot(&mut self, name: &str, snapshot: &str) -> Result<(), ManagerError> {
+        let vm = self.vm_mut(name)?;
+        let store = vm.snapshots.as_mut().ok_or(ManagerError::NoSuchSnapshot)?;
+        let id = store.find(snapshot)?.ok_or(ManagerError::NoSuchSnapshot)?.id;
+        let machine = vm.machine.as_mut().ok_or(ManagerError::NotRunning)?;
+        store.restore(machine.vm_mut(), id)?;
+        machine.sync_threads();
+        Ok(())
+    }
+
+    /// Stop a native VM at once.
+    pub fn stop_native(&mut self, name: &str) -> Result<(), ManagerError> {
+        self.vm_mut(name)?.machine.take().map(drop).ok_or(ManagerError::NotRunning)
+    }
+
+    pub fn volumes(&self) -> &SharedVolumeStore<V> {
+        &self.volumes
+    }
+
+    pub fn create_volume(&mut self, name: &str, quota_mb: u64) -> Result<(), ManagerError> {
+        let quota = quota_mb.checked_mul(1 << 20).filter(|&quota| quota > 0).ok_or(ManagerError::BadQuota(quota_mb))?;
+        Ok(self.volumes.lock().create_volume(name, quota)?)
+    }
+
+    pub fn delete_volume(&mut self, name: &str) -> Result<(), ManagerError> {
+        Ok(self.volumes.lock().delete_volume(name)?)
+    }
+
+    /// Give a VM access to a volume, or take it away with `Access::None`.
+    /// Running VMs see a new volume the next time they boot, but a change
+    /// of access applies at once.
+    pub fn set_volume_access(&mut self, volume: &str, vm: &str, access: Access) -> Result<(), ManagerError> {
+        if access != Access::None {
+            self.vm(vm)?;
+        }
+        Ok(self.volumes.lock().set_access(volume, vm, access)?)
+    }
+
+    /// Attach a VM booting under QEMU to every volume it has access to,
+    /// with why each volume that could not be attached was not.
+    pub fn attach_volumes(&self, vm: &str) -> Result<Vec<AttachedVolume<V>>, ManagerError> {
+        self.vm(vm)?;
+        let visible = self.volumes.lock().volumes_for(vm);
+        Ok(visible
+            .into_iter()
+            .map(|(volume, _)| {
+                let handle = VolumeHandle::attach(&self.volumes, &volume, vm);
+                (volume, handle)
+            })
+            .collect())
+    }
+}
+
+fn check_cpus(cpus: usize) -> Result<(), ManagerError> {
+    if cpus == 0 || cpus > MAX_VCPUS {
+        return Err(ManagerError::BadCpuCount(cpus));
+    }
+    Ok(())
+}
+
+/// A VMX backend for a native VM of `ram_mb` MiB and `cpus` vCPUs, in
+/// frames taken from those set aside for native VMs, with its RAM mapped at
+/// 0 and CPUID limited to what the host has.
+pub fn vmx_backend(ram_mb: usize, cpus: usize) -> Result<VmxBackend, ManagerError> {
+    let ram = (ram_mb as u64).checked_mul(1 << 20).ok_or(ManagerError::NoMemory)?;
+    let size = ram.checked_add(EPT_RESERVE).ok_or(ManagerError::NoMemory)?;
+    let (frames, phys_offset) = take_guest_frames(size).ok_or(ManagerError::NoMemory)?;
+    let mut backend = VmxBackend::new(frames, phys_offset)?;
+    backend.alloc_ram(0, ram)?;
+    let mut model = CpuModel::new(cpus as u32);
+    model.restrict_to(host_cpuid);
+    backend.set_cpu_model(model)?;
+    Ok(backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::mock::MOCK_INVALID_OPCODE;
+    use crate::hypervisor::MockBackend;
+    use crate::loader::bzimage::tests::image;
+    use crate::process::{MultiFeedbackQueue, ProcessState};
+    use crate::storage::tests::RamStorage;
+    use crate::storage::volume::VolumeStore;
+
+    type TestManager = VmManager<MockBackend, RamStorage, RamStorage>;
+
+    fn manager() -> (TestManager, &'static Mutex<MultiFeedbackQueue>) {
+        let scheduler: &'static Mutex<MultiFeedbackQueue> = Box::leak(Box::new(Mutex::new(MultiFeedbackQueue::new())));
+        let processes = Box::leak(Box::new(Mutex::new(ProcessManager::with_scheduler(scheduler))));
+        let volumes = VolumeStore::shared(RamStorage::new(512, 64), 512, 64);
+        (VmManager::with_processes(volumes, processes), scheduler)
+    }
+
+    fn clock() -> Box<dyn Fn() -> u64 + Send> {
+        Box::new(|| 0)
+    }
+
+    /// mov dx, 0x3F8; mov al, 'A'; out dx, al; hlt
+    fn hello() -> Vec<u8> {
+        image(&[0x66, 0xBA, 0xF8, 0x03, 0xB0, b'A', 0xEE, 0xF4])
+    }
+
+    fn mock(vm: &VmRecord<MockBackend, RamStorage>) -> Result<MockBackend, ManagerError> {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, vm.ram_mb() as u64 * (1 << 20))?;
+        Ok(backend)
+    }
+
+    #[test]
+    fn test_records() {
+        let (mut manager, _) = manager();
+        manager.create_vm("a", 32, 1, "a.img", None).unwrap();
+        manager.create_vm("b", 64, 2, "b.img", Some("b.iso")).unwrap();
+        assert_eq!(manager.create_vm("a", 32, 1, "a.img", None), Err(ManagerError::VmExists));
+        assert_eq!(manager.create_vm("c", 32, 0, "c.img", None), Err(ManagerError::BadCpuCount(0)));
+        let too_many = MAX_VCPUS + 1;
+        assert_eq!(manager.update_vm("b", None, Some(too_many)), Err(ManagerError::BadCpuCount(too_many)));
+        manager.update_vm("b", None, Some(MAX_VCPUS)).unwrap();
+        manager.update_vm("a", Some(48), None).unwrap();
+        assert_eq!(manager.update_vm("c", Some(48), None), Err(ManagerError::NoSuchVm));
+        let a = manager.vm("a").unwrap();
+        assert_eq!((a.ram_mb(), a.cpus(), a.disk_image(), a.iso_path()), (48, 1, "a.img", None));
+        assert_ne!(a.id(), manager.vm("b").unwrap().id());
+        manager.delete_vm("a").unwrap();
+        assert_eq!(manager.delete_vm("a"), Err(ManagerError::NoSuchVm));
+        assert_eq!(manager.vms().len(), 1);
+    }
+
+    #[test]
+    fn test_native_lifecycle() {
+        let (mut manager, scheduler) = manager();
+        manager.create_vm("a", 32, 2, "a.img", None).unwrap();
+        manager.create_vm("b", 32, 1, "b.img", None).unwrap();
+        let hello = hello();
+        let ud2 = image(&[0x0F, 0x0B]);
+        assert_eq!(
+            manager.boot_native("a", &hello[..0x100], mock, clock()),
+            Err(ManagerError::Kernel(LoadError::BadImage("truncated setup header")))
+        );
+        manager.boot_native("a", &hello, mock, clock()).unwrap();
+        assert_eq!(manager.boot_native("a", &hello, mock, clock()), Err(ManagerError::AlreadyRunning));
+        manager.boot_native("b", &ud2, mock, clock()).unwrap();
+        // One thread per vCPU; vCPU 1 waits for a startup IPI that never
+        // comes.
+        let id = manager.vm("a").unwrap().id();
+        assert_eq!(scheduler.lock().vcpu_threads(id).len(), 2);
+
+        // A halted vCPU leaves its VM running; an exit nothing handles stops
+        // the VM.
+        let stopped = manager.run_native_vms();
+        assert_eq!(manager.vm("a").unwrap().console().lock().text(), "A");
+        assert!(manager.vm("a").unwrap().is_running());
+        assert_eq!(stopped.len(), 1);
+        assert_eq!((stopped[0].vm.as_str(), stopped[0].vcpu), ("b", 0));
+        assert!(matches!(stopped[0].cause, Ok(VmExit::Unhandled { reason: MOCK_INVALID_OPCODE, .. })));
+        assert!(!manager.vm("b").unwrap().is_running());
+        assert_eq!(manager.run_native_vms(), []);
+
+        manager.stop_native("a").unwrap();
+        assert_eq!(manager.stop_native("a"), Err(ManagerError::NotRunning));
+        assert_eq!(scheduler.lock().vcpu_threads(id), []);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let (mut manager, scheduler) = manager();
+        manager.create_vm("a", 18, 1, "a.img", None).unwrap();
+        let storage = || Ok(RamStorage::new(SNAPSHOT_BLOCK_SIZE, 5120));
+        assert_eq!(manager.take_snapshot("b", "boot", storage), Err(ManagerError::NoSuchVm));
+        assert_eq!(manager.take_snapshot("a", "boot", storage), Err(ManagerError::NotRunning));
+        assert_eq!(manager.restore_snapshot("a", "boot"), Err(ManagerError::NoSuchSnapshot));
+        manager.boot_native("a", &hello(), mock, clock()).unwrap();
+        assert_eq!(
+            manager.take_snapshot("a", "boot", || Err(SnapshotError::Storage)),
+            Err(ManagerError::Snapshot(SnapshotError::Storage))
+        );
+        manager.take_snapshot("a", "boot", storage).unwrap();
+        assert_eq!(manager.take_snapshot("a", "boot", storage), Err(ManagerError::SnapshotExists));
+        let machine = manager.vm_mut("a").unwrap().machine_mut().unwrap();
+        let (thread, vcpu) = (machine.threads()[0], machine.vm().vcpus()[0]);
+
+        manager.run_native_vms();
+        assert_eq!(scheduler.lock().get_process_state(thread), Some(ProcessState::Blocked));
+        manager.take_snapshot("a", "halted", storage).unwrap();
+        let names: Vec<String> = manager.vm("a").unwrap().snapshots().unwrap().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, ["boot", "halted"]);
+        manager.restore_snapshot("a", "boot").unwrap();
+        // The vCPU is back at the kernel entry, and prints again once woken.
+        let machine = manager.vm_mut("a").unwrap().machine_mut().unwrap();
+        machine.vm().coordinator().wake(vcpu);
+        manager.run_native_vms();
+        assert_eq!(manager.vm("a").unwrap().console().lock().text(), "AA");
+
+        manager.stop_native("a").unwrap();
+        assert_eq!(manager.restore_snapshot("a", "boot"), Err(ManagerError::NotRunning));
+    }
+
+    #[test]
+    fn test_volumes() {
+        let (mut manager, _) = manager();
+        manager.create_vm("a", 18, 1, "a.img", None).unwrap();
+        manager.create_vm("b", 18, 1, "b.img", None).unwrap();
+        assert_eq!(manager.create_volume("v", 0), Err(ManagerError::BadQuota(0)));
+        assert_eq!(manager.create_volume("v", u64::MAX >> 10), Err(ManagerError::BadQuota(u64::MAX >> 10)));
+        manager.create_volume("v", 1).unwrap();
+        assert_eq!(manager.create_volume("v", 1), Err(ManagerError::Volume(VolumeError::Exists)));
+        assert_eq!(manager.set_volume_access("v", "c", Access::Read), Err(ManagerError::NoSuchVm));
+        assert_eq!(manager.set_volume_access("w", "a", Access::Read), Err(ManagerError::Volume(VolumeError::NotFound)));
+        manager.set_volume_access("v", "a", Access::Write).unwrap();
+        manager.set_volume_access("v", "b", Access::Read).unwrap();
+
+        let attached = manager.attach_volumes("a").unwrap();
+        assert_eq!(attached.len(), 1);
+        assert_eq!(attached[0].0, "v");
+        assert_eq!(attached[0].1.as_ref().map(|handle| handle.access()), Ok(Access::Write));
+        // Native VMs cannot reach volumes.
+        assert_eq!(manager.boot_native("a", &hello(), mock, clock()), Err(ManagerError::VolumesNeedQemu));
+        assert!(!manager.vm("a").unwrap().is_running());
+
+        manager.delete_vm("a").unwrap();
+        assert_eq!(manager.volumes().lock().access("v", "a"), Access::None);
+        manager.set_volume_access("v", "b", Access::None).unwrap();
+        assert!(manager.attach_volumes("b").unwrap().is_empty());
+        manager.boot_native("b", &hello(), mock, clock()).unwrap();
+        manager.delete_volume("v").unwrap();
+        assert_eq!(manager.delete_volume("v"), Err(ManagerError::Volume(VolumeError::NotFound)));
+    }
+}
@@ -7,19 +7,112 @@ use alloc::vec::Vec;
 //use x86_64::structures::paging::mapper::UnmapError;
 pub mod paging;
 pub struct SimpleFrameAllocator {
+    start: u64,
     next: u64,
     end: u64,
     free_list: Vec<u64>,
+    /// Ranges given back with `release_range`, as sorted, disjoint
+    /// (start, end) pairs below `next`.
+    free_ranges: Vec<(u64, u64)>,
+    /// Called with the span when the allocator is dropped.
+    on_drop: Option<fn((u64, u64))>,
 }
 
 impl SimpleFrameAllocator {
     pub fn new(start: u64, end: u64) -> Self {
-        SimpleFrameAllocator { next: start, end, free_list: Vec::new() }
+        SimpleFrameAllocator { start, next: start, end, free_list: Vec::new(), free_ranges: Vec::new(), on_drop: None }
+    }
+
+    /// Hand the span to `on_drop` once the allocator, and with it whatever
+    /// was built from its frames, goes away.
+    pub fn with_on_drop(mut self, on_drop: fn((u64, u64))) -> Self {
+        self.on_drop = Some(on_drop);
+        self
+    }
+
+    /// The frames this allocator hands out, as (start, end).
+    pub fn span(&self) -> (u64, u64) {
+        (self.start, self.end)
     }
 
     pub fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
         self.free_list.push(frame.start_address().as_u64());
     }
+
+    /// Take `size` bytes of contiguous frames and return where they start:
+    /// the first released range they fit in, or frames never allocated.
+    /// Frames freed one at a time are not reused for this.
+    pub fn allocate_range(&mut self, size: u64) -> Option<u64> {
+        if let Some(i) = self.free_ranges.iter().position(|&(start, end)| end - start >= size) {
+            let (start, end) = self.free_ranges[i];
+            if end - start == size {
+                self.free_ranges.remove(i);
+            } else {
+                self.free_ranges[i].0 = start + size;
+            }
+            return Some(start);
+        }
+        let start = self.next;
+        self.next = start.checked_add(size).filter(|&end| end <= self.end)?;
+        Some(start)
+    }
+
+    /// Give back `size` bytes at `start` from `allocate_range`, merging
+    /// them with their free neighbours. A range that ends where the never
+    /// allocated frames begin joins those.
+    pub fn release_range(&mut self, start: u64, size: u64) {
+        let mut range = (start, start + size);
+        let i = self.free_ranges.partition_point(|&(other, _)| other < start);
+        if i < self.free_ranges.len() && self.free_ranges[i].0 == range.1 {
+            range.1 = self.free_ranges.remove(i).1;
+        }
+        if i > 0 && self.free_ranges[i - 1].1 == range.0 {
+            range.0 = self.free_ranges.remove(i - 1).0;
+            self.free_ranges.insert(i - 1, range);
+        } else {
+            self.free_ranges.insert(i, range);
+        }
+        if let Some(&(start, end)) = self.free_ranges.last() {
+            if end == self.next {
+                self.next = start;
+                self.free_ranges.pop();
+            }
+        }
+    }
+
+    /// Hand `size` bytes of frames to an allocator of their own, whose
+    /// `span` may go back to `release_range` once nothing uses them.
+    pub fn split_off(&mut self, size: u64) -> Option<SimpleFrameAllocator> {
+        let start = self.allocate_range(size)?;
+        Some(SimpleFrameAllocator::new(start, start + size))
+    }
+}
+
+/// Physical memory set aside at boot for native VMs, and the offset it is
+/// mapped at in the kernel's address space.
+pub static GUEST_FRAMES: spin::Mutex<Option<(SimpleFrameAllocator, u64)>> = spin::Mutex::new(None);
+
+/// `size` bytes of `GUEST_FRAMES` as an allocator of their own, and the
+/// offset they are mapped at. The frames go back when the allocator is
+/// dropped, which for a VM's is when the VM is torn down.
+pub fn take_guest_frames(size: u64) -> Option<(SimpleFrameAllocator, u64)> {
+    let mut guest_frames = GUEST_FRAMES.lock();
+    let (frames, phys_offset) = guest_frames.as_mut()?;
+    Some((frames.split_off(size)?.with_on_drop(return_guest_frames), *phys_offset))
+}
+
+fn return_guest_frames((start, end): (u64, u64)) {
+    if let Some((frames, _)) = GUEST_FRAMES.lock().as_mut() {
+        frames.release_range(start, end - start);
+    }
+}
+
+impl Drop for SimpleFrameAllocator {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.on_drop {
+            on_drop(self.span());
+        }
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for SimpleFrameAllocator {
@@ -37,4 +130,47 @@ unsafe impl FrameAllocator<Size4KiB> for SimpleFrameAllocator {
 }
 
 pub mod lru;
-pub mod manager; 
+pub mod manager;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_released_ranges_are_reused() {
+        let mut frames = SimpleFrameAllocator::new(0x10_0000, 0x20_0000);
+        let a = frames.split_off(0x1000).unwrap();
+        let b = frames.allocate_range(0x3000).unwrap();
+        let c = frames.allocate_range(0x2000).unwrap();
+        assert_eq!((a.span(), b, c), ((0x10_0000, 0x10_1000), 0x10_1000, 0x10_4000));
+
+        // A hole is reused by whatever fits in it, and merges with its
+        // neighbours when they are released too.
+        frames.release_range(b, 0x3000);
+        assert_eq!(frames.allocate_range(0x4000), Some(0x10_6000));
+        assert_eq!(frames.allocate_range(0x1000), Some(0x10_1000));
+        frames.release_range(0x10_1000, 0x1000);
+        frames.release_range(a.span().0, 0x1000);
+        assert_eq!(frames.allocate_range(0x4000), Some(0x10_0000));
+
+        // Releasing the last range hands its frames back to the bump
+        // allocator, along with any free range it now adjoins.
+        frames.release_range(0x10_6000, 0x4000);
+        frames.release_range(c, 0x2000);
+        assert_eq!(frames.split_off(0x10_0000 - 0x4000).map(|f| f.span()), Some((0x10_4000, 0x20_0000)));
+        assert_eq!(frames.allocate_range(0x1000), None);
+    }
+
+    #[test]
+    fn test_guest_frames_return_on_drop() {
+        *GUEST_FRAMES.lock() = Some((SimpleFrameAllocator::new(0, 0x10_0000), 0x8000_0000));
+        let (vm, offset) = take_guest_frames(0x8_0000).unwrap();
+        assert_eq!((vm.span(), offset), ((0, 0x8_0000), 0x8000_0000));
+        assert!(take_guest_frames(0x10_0000).is_none());
+        drop(vm);
+        let (vm, _) = take_guest_frames(0x10_0000).unwrap();
+        assert_eq!(vm.span(), (0, 0x10_0000));
+        drop(vm);
+        *GUEST_FRAMES.lock() = None;
+    }
+}
@@ -1,16 +1,26 @@
-use super::scheduler::{Process, ProcessState, SCHEDULER};
+use super::scheduler::{MultiFeedbackQueue, Process, ProcessState, SCHEDULER};
+use crate::hypervisor::{VcpuId, VcpuRunState};
+use alloc::vec::Vec;
 use core::time::Duration;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
 pub struct ProcessManager {
     next_pid: u64,
+    scheduler: &'static Mutex<MultiFeedbackQueue>,
 }
 
 impl ProcessManager {
     pub fn new() -> Self {
+        Self::with_scheduler(&SCHEDULER)
+    }
+
+    /// A manager whose processes go to `scheduler` instead of the global
+    /// one.
+    pub fn with_scheduler(scheduler: &'static Mutex<MultiFeedbackQueue>) -> Self {
         ProcessManager {
             next_pid: 1,
+            scheduler,
         }
     }
 
@@ -24,27 +34,58 @@ impl ProcessManager {
             Duration::from_secs(0), // Current time would be passed in real implementation
         );
 
-        SCHEDULER.lock().add_process(process);
+        self.scheduler.lock().add_process(process);
         pid
     }
 
+    /// One schedulable thread per vCPU of `vm`. Returns their PIDs in vCPU
+    /// order.
+    pub fn create_vcpu_threads(&mut self, vm: u64, vcpus: usize) -> Vec<u64> {
+        let mut scheduler = self.scheduler.lock();
+        (0..vcpus as VcpuId)
+            .map(|vcpu| {
+                let pid = self.next_pid;
+                self.next_pid += 1;
+                scheduler.add_process(Process::vcpu_thread(pid, vm, vcpu, Duration::from_secs(0)));
+                pid
+            })
+            .collect()
+    }
+
+    /// Mirror a vCPU's run state in its thread, so halted and paused vCPUs
+    /// are blocked.
+    pub fn update_vcpu_state(&mut self, vm: u64, vcpu: VcpuId, state: VcpuRunState) {
+        let mut scheduler = self.scheduler.lock();
+        if let Some(pid) = scheduler.find_vcpu(vm, vcpu) {
+            scheduler.set_process_state(pid, ProcessState::from_vcpu(state));
+        }
+    }
+
+    /// End every vCPU thread of `vm`.
+    pub fn terminate_vm(&mut self, vm: u64) {
+        let mut scheduler = self.scheduler.lock();
+        for (_, pid) in scheduler.vcpu_threads(vm) {
+            scheduler.complete_process(pid);
+        }
+    }
+
     pub fn terminate_process(&mut self, pid: u64) {
-        SCHEDULER.lock().complete_process(pid);
+        self.scheduler.lock().complete_process(pid);
     }
 
     pub fn get_process_state(&self, pid: u64) -> Option<ProcessState> {
-        let scheduler = SCHEDULER.lock();
+        let scheduler = self.scheduler.lock();
         scheduler.get_process_state(pid)
     }
 
     // The scheduler now returns Option<u64> (the PID), not a reference to Process
     pub fn schedule_next(&mut self) -> Option<u64> {
-        let mut scheduler = SCHEDULER.lock();
+        let mut scheduler = self.scheduler.lock();
         scheduler.schedule()
     }
 
     pub fn tick(&mut self) {
-        SCHEDULER.lock().tick();
+        self.scheduler.lock().tick();
     }
 }
 
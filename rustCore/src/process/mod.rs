@@ -1,7 +1,7 @@
 mod scheduler;
 mod manager;
 
-pub use scheduler::{Process, ProcessState, MultiFeedbackQueue, SCHEDULER};
+pub use scheduler::{Process, ProcessKind, ProcessState, MultiFeedbackQueue, SCHEDULER};
 pub use manager::{ProcessManager, PROCESS_MANAGER};
 
 // Re-export commonly used types
@@ -2,6 +2,9 @@ use alloc::collections::VecDeque;
 use core::time::Duration;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use alloc::vec::Vec;
+
+use crate::hypervisor::{VcpuId, VcpuRunState};
 // Process states
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
@@ -11,10 +14,32 @@ pub enum ProcessState {
     Terminated,
 }
 
+// What a process runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessKind {
+    Task,
+    /// The thread running one vCPU of a VM until the VM is torn down.
+    Vcpu { vm: u64, vcpu: VcpuId },
+}
+
+impl ProcessState {
+    /// Scheduler view of a vCPU's run state: halted and paused vCPUs
+    /// wait for a wakeup.
+    pub fn from_vcpu(state: VcpuRunState) -> Self {
+        match state {
+            VcpuRunState::Runnable => ProcessState::Ready,
+            VcpuRunState::Running => ProcessState::Running,
+            VcpuRunState::Halted | VcpuRunState::Paused => ProcessState::Blocked,
+            VcpuRunState::Stopped => ProcessState::Terminated,
+        }
+    }
+}
+
 // Process structure
 #[derive(Debug)]
 pub struct Process {
     pub pid: u64,
+    pub kind: ProcessKind,
     pub state: ProcessState,
     pub priority: u8,
     pub burst_time: Duration,
@@ -29,6 +54,7 @@ impl Process {
     pub fn new(pid: u64, burst_time: Duration, arrival_time: Duration) -> Self {
         Process {
             pid,
+            kind: ProcessKind::Task,
             state: ProcessState::Ready,
             priority: 0,
             burst_time,
@@ -40,6 +66,12 @@ impl Process {
         }
     }
 
+    /// A vCPU thread. It has no burst time of its own: it runs until the VM
+    /// stops, so it settles in the round-robin levels.
+    pub fn vcpu_thread(pid: u64, vm: u64, vcpu: VcpuId, arrival_time: Duration) -> Self {
+        Process { kind: ProcessKind::Vcpu { vm, vcpu }, ..Process::new(pid, Duration::MAX, arrival_time) }
+    }
+
     pub fn update_response_ratio(&mut self, current_time: Duration) {
         let waiting_time = current_time.as_secs_f64() - self.arrival_time.as_secs_f64();
         self.response_ratio = (waiting_time + self.burst_time.as_secs_f64()) / self.burst_time.as_secs_f64();
@@ -153,6 +185,37 @@ impl MultiFeedbackQueue {
         None
     }
 
+    pub fn set_process_state(&mut self, pid: u64, state: ProcessState) -> bool {
+        for queue in self.queues.iter_mut() {
+            if let Some(process) = queue.iter_mut().find(|p| p.pid == pid) {
+                process.state = state;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The thread of `vcpu` in `vm`.
+    pub fn find_vcpu(&self, vm: u64, vcpu: VcpuId) -> Option<u64> {
+        let kind = ProcessKind::Vcpu { vm, vcpu };
+        self.queues.iter().flatten().find(|p| p.kind == kind).map(|p| p.pid)
+    }
+
+    /// PIDs of `vm`'s vCPU threads, in vCPU order.
+    pub fn vcpu_threads(&self, vm: u64) -> Vec<(VcpuId, u64)> {
+        let mut threads: Vec<(VcpuId, u64)> = self
+            .queues
+            .iter()
+            .flatten()
+            .filter_map(|p| match p.kind {
+                ProcessKind::Vcpu { vm: owner, vcpu } if owner == vm => Some((vcpu, p.pid)),
+                _ => None,
+            })
+            .collect();
+        threads.sort_unstable();
+        threads
+    }
+
     pub fn schedule_and<F>(&mut self, mut f: F)
     where
         F: FnMut(&mut Process),
@@ -247,4 +310,26 @@ mod tests {
         assert!(process.is_some());
         assert_eq!(process.unwrap(), 2);
     }
+
+    #[test]
+    fn test_vcpu_threads() {
+        let mut scheduler = MultiFeedbackQueue::new();
+        scheduler.add_process(Process::new(1, Duration::from_millis(10), Duration::from_millis(0)));
+        for vcpu in 0..4 {
+            scheduler.add_process(Process::vcpu_thread(10 + vcpu as u64, 7, vcpu, Duration::from_millis(0)));
+        }
+        scheduler.add_process(Process::vcpu_thread(20, 8, 0, Duration::from_millis(0)));
+        assert_eq!(scheduler.vcpu_threads(7), [(0, 10), (1, 11), (2, 12), (3, 13)]);
+        assert_eq!(scheduler.find_vcpu(8, 0), Some(20));
+        assert_eq!(scheduler.find_vcpu(8, 1), None);
+
+        let pid = scheduler.find_vcpu(7, 2).unwrap();
+        assert!(scheduler.set_process_state(pid, ProcessState::from_vcpu(VcpuRunState::Halted)));
+        assert_eq!(scheduler.get_process_state(pid), Some(ProcessState::Blocked));
+        // vCPU threads never finish their burst.
+        for _ in 0..8 {
+            scheduler.schedule();
+        }
+        assert_eq!(scheduler.vcpu_threads(7).len(), 4);
+    }
 }
\ No newline at end of file
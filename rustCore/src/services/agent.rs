@@ -0,0 +1,281 @@
+// The host end of every guest's agent channel: the HELLO handshake that
+// grants services, and routing of each request to the service it names.
+//
+// Services are handed to `poll` rather than owned by the hub, so whoever
+// owns a service (the clipboard broker, say) can also use it directly.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use hypercore_guest::protocol::{
+    service_of, DecodeError, ErrorCode, Frame, FrameDecoder, Hello, MAX_PAYLOAD, MSG_HELLO, MSG_WELCOME,
+    PROTOCOL_VERSION,
+};
+
+use crate::vdev::channel::SharedChannel;
+
+/// A service guests reach over the agent channel.
+pub trait AgentService {
+    /// The SERVICE_* bit of the messages it handles.
+    fn service(&self) -> u32;
+
+    /// Whether `vm` may be granted the service at all.
+    fn allows(&self, vm: &str) -> bool;
+
+    /// Handle a message from `vm`'s agent, returning the reply if it needs
+    /// one. Messages for other VMs, such as events, go in `outbox`.
+    fn handle(&mut self, vm: &str, message: &Frame, outbox: &mut Outbox) -> Option<Frame>;
+
+    /// `vm`'s agent said HELLO and was granted the service, perhaps after
+    /// restarting and forgetting what it was doing.
+    fn connected(&mut self, _vm: &str, _outbox: &mut Outbox) {}
+
+    /// `vm`'s agent channel went away.
+    fn detached(&mut self, _vm: &str, _outbox: &mut Outbox) {}
+}
+
+/// Messages a service sends to VMs other than the one it is answering.
+#[derive(Default)]
+pub struct Outbox {
+    messages: Vec<(String, Frame)>,
+}
+
+impl Outbox {
+    pub fn send(&mut self, vm: &str, frame: Frame) {
+        self.messages.push((String::from(vm), frame));
+    }
+}
+
+struct Session {
+    channel: SharedChannel,
+    decoder: FrameDecoder,
+    /// Services granted by the last HELLO, if there was one.
+    services: Option<u32>,
+}
+
+pub struct AgentHub {
+    sessions: BTreeMap<String, Session>,
+    max_payload: u32,
+}
+
+/// An ERROR reply, unless the message was an event nobody waits on.
+fn refuse(message: &Frame, code: ErrorCode) -> Option<Frame> {
+    (message.id != 0).then(|| Frame::error(message.id, code))
+}
+
+impl AgentHub {
+    pub fn new() -> Self {
+        AgentHub { sessions: BTreeMap::new(), max_payload: MAX_PAYLOAD }
+    }
+
+    /// Refuse messages from guests with payloads over `max_payload` bytes.
+    pub fn with_max_payload(mut self, max_payload: u32) -> Self {
+        self.max_payload = core::cmp::min(max_payload, MAX_PAYLOAD);
+        self
+    }
+
+    /// Terminate `vm`'s agent channel here, replacing any earlier one.
+    pub fn attach(&mut self, vm: &str, channel: SharedChannel) {
+        let session = Session { channel, decoder: FrameDecoder::new(self.max_payload), services: None };
+        self.sessions.insert(String::from(vm), session);
+    }
+
+    pub fn detach(&mut self, vm: &str, services: &mut [&mut dyn AgentService]) {
+        if self.sessions.remove(vm).is_some() {
+            let mut outbox = Outbox::default();
+            for service in services.iter_mut() {
+                service.detached(vm, &mut outbox);
+            }
+            self.deliver(outbox);
+        }
+    }
+
+    /// Services `vm`'s agent was granted, None until it says HELLO.
+    pub fn granted(&self, vm: &str) -> Option<u32> {
+        self.sessions.get(vm)?.services
+    }
+
+    /// Send `frame` to `vm`'s agent if it was granted the frame's service.
+    pub fn send(&mut self, vm: &str, frame: &Frame) -> bool {
+        let service = service_of(frame.kind);
+        match self.sessions.get(vm) {
+            Some(session) if session.services.is_some_and(|granted| granted & service == service) => {
+                session.channel.lock().send(&frame.encode());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Handle everything the agents have sent, returning how many messages
+    /// that was.
+    pub fn poll(&mut self, services: &mut [&mut dyn AgentService]) -> usize {
+        let mut outbox = Outbox::default();
+        let mut handled = 0;
+        for (vm, session) in self.sessions.iter_mut() {
+            let bytes = session.channel.lock().take();
+            session.decoder.push(&bytes);
+            loop {
+                let reply = match session.decoder.next_frame() {
+                    Ok(Some(message)) => Self::dispatch(vm, session, &message, self.max_payload, services, &mut outbox),
+                    Ok(None) => break,
+                    Err(DecodeError::TooLarge { kind, id, .. }) => {
+                        refuse(&Frame::new(kind, id, Vec::new()), ErrorCode::TooLarge)
+                    }
+                    Err(DecodeError::Malformed) => break,
+                };
+                if let Some(reply) = reply {
+                    session.channel.lock().send(&reply.encode());
+                }
+                handled += 1;
+            }
+        }
+        self.deliver(outbox);
+        handled
+    }
+
+    /// Send what a service queued outside of `poll`, such as in answer to
+    /// the user.
+    pub fn deliver(&mut self, outbox: Outbox) {
+        for (vm, frame) in outbox.messages {
+            self.send(&vm, &frame);
+        }
+    }
+
+    fn dispatch(
+        vm: &str,
+        session: &mut Session,
+        message: &Frame,
+        max_payload: u32,
+        services: &mut [&mut dyn AgentService],
+        outbox: &mut Outbox,
+    ) -> Option<Frame> {
+        if message.kind == MSG_HELLO {
+            let Ok(hello) = Hello::decode(&message.payload) else {
+                return refuse(message, ErrorCode::Malformed);
+            };
+            let granted = services
+                .iter()
+                .filter(|service| hello.services & service.service() != 0 && service.allows(vm))
+                .fold(0, |granted, service| granted | service.service());
+            session.services = Some(granted);
+            let welcome = Hello { version: PROTOCOL_VERSION, services: granted, max_payload };
+            // The WELCOME goes out first; whatever the services have to say
+            // waits in the outbox.
+            for service in services.iter_mut().filter(|service| granted & service.service() != 0) {
+                service.connected(vm, outbox);
+            }
+            return Some(Frame::new(MSG_WELCOME, message.id, welcome.encode()));
+        }
+        let Some(granted) = session.services else {
+            return refuse(message, ErrorCode::NoSession);
+        };
+        let wanted = service_of(message.kind);
+        match services.iter_mut().find(|service| wanted != 0 && service.service() == wanted) {
+            Some(service) if granted & wanted != 0 => service.handle(vm, message, outbox),
+            _ => refuse(message, ErrorCode::Unsupported),
+        }
+    }
+}
+
+impl Default for AgentHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    use hypercore_guest::protocol::{FrameDecoder, MSG_OK};
+
+    use crate::vdev::channel::ByteChannel;
+
+    const SERVICE_ECHO: u32 = 1 << 4;
+    const MSG_ECHO: u16 = 0x0501;
+    const MSG_ECHO_EVENT: u16 = 0x0502;
+
+    /// Echoes requests back and tells "b" about each one.
+    struct Echo;
+
+    impl AgentService for Echo {
+        fn service(&self) -> u32 {
+            SERVICE_ECHO
+        }
+
+        fn allows(&self, vm: &str) -> bool {
+            vm != "denied"
+        }
+
+        fn handle(&mut self, _vm: &str, message: &Frame, outbox: &mut Outbox) -> Option<Frame> {
+            outbox.send("b", Frame::new(MSG_ECHO_EVENT, 0, message.payload.clone()));
+            Some(Frame::new(message.kind, message.id, message.payload.clone()))
+        }
+    }
+
+    /// Deliver `frames` from `vm`'s agent and return what its agent got.
+    fn exchange(hub: &mut AgentHub, channels: &[(&str, SharedChannel)], vm: &str, frames: &[Frame]) -> Vec<Frame> {
+        let channel = &channels.iter().find(|(name, _)| *name == vm).unwrap().1;
+        for frame in frames {
+            channel.lock().push_from_guest(&frame.encode());
+        }
+        hub.poll(&mut [&mut Echo]);
+        received(channel)
+    }
+
+    fn received(channel: &SharedChannel) -> Vec<Frame> {
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD);
+        decoder.push(&channel.lock().pop_to_guest(usize::MAX));
+        core::iter::from_fn(|| decoder.next_frame().unwrap()).collect()
+    }
+
+    fn hello(services: u32) -> Frame {
+        Frame::new(MSG_HELLO, 1, Hello { version: PROTOCOL_VERSION, services, max_payload: 0 }.encode())
+    }
+
+    #[test]
+    fn test_handshake_and_routing() {
+        let mut hub = AgentHub::new().with_max_payload(16);
+        let channels: Vec<(&str, SharedChannel)> =
+            ["a", "b", "denied"].iter().map(|&vm| (vm, ByteChannel::shared(1024))).collect();
+        for (vm, channel) in &channels {
+            hub.attach(vm, channel.clone());
+        }
+
+        // Nothing before HELLO.
+        let early = exchange(&mut hub, &channels, "a", &[Frame::new(MSG_ECHO, 5, vec![1])]);
+        assert_eq!(early, [Frame::error(5, ErrorCode::NoSession)]);
+
+        let welcome = exchange(&mut hub, &channels, "a", &[hello(SERVICE_ECHO | 1 << 9)]);
+        assert_eq!(welcome[0].kind, MSG_WELCOME);
+        let welcome = Hello::decode(&welcome[0].payload).unwrap();
+        assert_eq!((welcome.services, welcome.max_payload), (SERVICE_ECHO, 16));
+        assert_eq!(hub.granted("a"), Some(SERVICE_ECHO));
+        exchange(&mut hub, &channels, "b", &[hello(SERVICE_ECHO)]);
+        exchange(&mut hub, &channels, "denied", &[hello(SERVICE_ECHO)]);
+        assert_eq!(hub.granted("denied"), Some(0));
+
+        // Requests reach the service, which can message other VMs.
+        let requests = [Frame::new(MSG_ECHO, 6, vec![2]), Frame::new(0x0601, 7, vec![])];
+        let replies = exchange(&mut hub, &channels, "a", &requests);
+        assert_eq!(replies, [Frame::new(MSG_ECHO, 6, vec![2]), Frame::error(7, ErrorCode::Unsupported)]);
+        assert_eq!(received(&channels[1].1), [Frame::new(MSG_ECHO_EVENT, 0, vec![2])]);
+        let refused = exchange(&mut hub, &channels, "denied", &[Frame::new(MSG_ECHO, 8, vec![])]);
+        assert_eq!(refused, [Frame::error(8, ErrorCode::Unsupported)]);
+        assert!(!hub.send("denied", &Frame::new(MSG_ECHO_EVENT, 0, vec![])));
+        assert!(hub.send("denied", &Frame::ok(0)));
+
+        // An oversized message is refused without disturbing the next one.
+        let big = Frame::new(MSG_ECHO, 9, vec![0; 17]);
+        let replies = exchange(&mut hub, &channels, "a", &[big, Frame::new(MSG_ECHO, 10, vec![3])]);
+        assert_eq!(replies, [Frame::error(9, ErrorCode::TooLarge), Frame::new(MSG_ECHO, 10, vec![3])]);
+        received(&channels[1].1);
+
+        hub.detach("a", &mut [&mut Echo]);
+        assert_eq!(hub.granted("a"), None);
+        assert!(!hub.send("a", &Frame::new(MSG_OK, 0, vec![])));
+    }
+}
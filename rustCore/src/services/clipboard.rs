@@ -0,0 +1,319 @@
+// The shared clipboard: one clipboard for every VM that opts in, set by a
+// guest agent when the user copies and read by another when they paste.
+//
+// Each VM has a policy saying whether its copies are shared, whether it may
+// paste what others copied, and the largest clipboard it may send or
+// receive. VMs without a policy take no part. A new copy is announced to the
+// VMs allowed to paste, which fetch it only when the user actually pastes.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use hypercore_guest::protocol::{
+    decode_formats, decode_items, encode_formats, encode_items, ClipboardFormat, ClipboardItem, ErrorCode, Frame,
+    Reader, Writer, MSG_CLIPBOARD_CHANGED, MSG_CLIPBOARD_DATA, MSG_CLIPBOARD_GET, MSG_CLIPBOARD_SET, SERVICE_CLIPBOARD,
+};
+
+use super::agent::{AgentService, Outbox};
+
+/// Clipboard size allowed by [`ClipboardPolicy::shared`], as encoded on the
+/// wire.
+pub const DEFAULT_MAX_CLIPBOARD: usize = 8 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClipboardPolicy {
+    /// Whether the VM's copies are shared with other VMs.
+    pub copy: bool,
+    /// Whether the VM may paste what other VMs copied.
+    pub paste: bool,
+    /// Largest clipboard, in encoded bytes, the VM may send or receive.
+    pub max_size: usize,
+}
+
+impl ClipboardPolicy {
+    pub const DISABLED: ClipboardPolicy = ClipboardPolicy { copy: false, paste: false, max_size: 0 };
+
+    /// Copy and paste both ways, up to the default size.
+    pub fn shared() -> Self {
+        ClipboardPolicy { copy: true, paste: true, max_size: DEFAULT_MAX_CLIPBOARD }
+    }
+
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+}
+
+/// What was last copied, and by whom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClipboardEntry {
+    pub owner: String,
+    /// Counts copies, so agents can tell a new copy from one they have seen.
+    pub serial: u64,
+    pub items: Vec<ClipboardItem>,
+}
+
+pub struct ClipboardBroker {
+    policies: BTreeMap<String, ClipboardPolicy>,
+    current: Option<ClipboardEntry>,
+    serial: u64,
+}
+
+impl ClipboardBroker {
+    pub fn new() -> Self {
+        ClipboardBroker { policies: BTreeMap::new(), current: None, serial: 0 }
+    }
+
+    pub fn policy(&self, vm: &str) -> ClipboardPolicy {
+        self.policies.get(vm).copied().unwrap_or(ClipboardPolicy::DISABLED)
+    }
+
+    /// Set `vm`'s policy. A VM no longer allowed to share loses what it
+    /// had on the clipboard.
+    pub fn set_policy(&mut self, vm: &str, policy: ClipboardPolicy) {
+        if !policy.copy && self.current.as_ref().is_some_and(|entry| entry.owner == vm) {
+            self.current = None;
+        }
+        if policy == ClipboardPolicy::DISABLED {
+            self.policies.remove(vm);
+        } else {
+            self.policies.insert(String::from(vm), policy);
+        }
+    }
+
+    /// Forget a VM that was deleted.
+    pub fn remove_vm(&mut self, vm: &str) {
+        self.set_policy(vm, ClipboardPolicy::DISABLED);
+    }
+
+    pub fn current(&self) -> Option<&ClipboardEntry> {
+        self.current.as_ref()
+    }
+
+    /// Put `items` on the clipboard as copied by `vm`, or clear it if there
+    /// are none, as a SET from its agent would. Returns the copy's serial.
+    pub fn copy(&mut self, vm: &str, items: Vec<ClipboardItem>, outbox: &mut Outbox) -> Result<u64, ErrorCode> {
+        let policy = self.policy(vm);
+        if !policy.copy {
+            return Err(ErrorCode::Denied);
+        }
+        if encode_items(&items).len() > policy.max_size {
+            return Err(ErrorCode::TooLarge);
+        }
+        self.publish(vm, items, outbox);
+        Ok(self.serial)
+    }
+
+    /// What `vm` would paste in `formats`, or in every format there is if
+    /// `formats` is empty, with the serial of the copy.
+    pub fn paste(&self, vm: &str, formats: &[ClipboardFormat]) -> Result<(u64, Vec<ClipboardItem>), ErrorCode> {
+        if !self.policy(vm).paste {
+            return Err(ErrorCode::Denied);
+        }
+        let entry = self.current.as_ref().ok_or(ErrorCode::Empty)?;
+        let items: Vec<ClipboardItem> = entry
+            .items
+            .iter()
+            .filter(|item| formats.is_empty() || formats.contains(&item.format()))
+            .cloned()
+            .collect();
+        if items.is_empty() {
+            return Err(ErrorCode::Empty);
+        }
+        Ok((entry.serial, items))
+    }
+
+    /// Put `items` on the clipboard as copied by `owner`, or clear it if
+    /// there are none, and tell the VMs that may paste.
+    fn publish(&mut self, owner: &str, items: Vec<ClipboardItem>, outbox: &mut Outbox) {
+        self.serial += 1;
+        let formats: Vec<ClipboardFormat> = items.iter().map(ClipboardItem::format).collect();
+        self.current = if items.is_empty() {
+            None
+        } else {
+            Some(ClipboardEntry { owner: String::from(owner), serial: self.serial, items })
+        };
+        let changed = [&Writer::new().u64(self.serial).finish()[..], &encode_formats(&formats)].concat();
+        for (vm, _) in self.policies.iter().filter(|(vm, policy)| policy.paste && *vm != owner) {
+            outbox.send(vm, Frame::new(MSG_CLIPBOARD_CHANGED, 0, changed.clone()));
+        }
+    }
+
+    fn set(&mut self, vm: &str, message: &Frame, outbox: &mut Outbox) -> Result<Frame, ErrorCode> {
+        let policy = self.policy(vm);
+        if !policy.copy {
+            return Err(ErrorCode::Denied);
+        }
+        if message.payload.len() > policy.max_size {
+            return Err(ErrorCode::TooLarge);
+        }
+        let mut reader = Reader::new(&message.payload);
+        let items = decode_items(&mut reader).map_err(|_| ErrorCode::Malformed)?;
+        reader.finish().map_err(|_| ErrorCode::Malformed)?;
+        self.publish(vm, items, outbox);
+        Ok(Frame::ok(message.id))
+    }
+
+    fn get(&mut self, vm: &str, message: &Frame) -> Result<Frame, ErrorCode> {
+        let policy = self.policy(vm);
+        if !policy.paste {
+            return Err(ErrorCode::Denied);
+        }
+        let mut reader = Reader::new(&message.payload);
+        let formats = decode_formats(&mut reader).map_err(|_| ErrorCode::Malformed)?;
+        reader.finish().map_err(|_| ErrorCode::Malformed)?;
+        let (serial, items) = self.paste(vm, &formats)?;
+        let data = [&Writer::new().u64(serial).finish()[..], &encode_items(&items)].concat();
+        if data.len() > policy.max_size {
+            return Err(ErrorCode::TooLarge);
+        }
+        Ok(Frame::new(MSG_CLIPBOARD_DATA, message.id, data))
+    }
+}
+
+impl Default for ClipboardBroker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AgentService for ClipboardBroker {
+    fn service(&self) -> u32 {
+        SERVICE_CLIPBOARD
+    }
+
+    fn allows(&self, vm: &str) -> bool {
+        let policy = self.policy(vm);
+        policy.copy || policy.paste
+    }
+
+    fn handle(&mut self, vm: &str, message: &Frame, outbox: &mut Outbox) -> Option<Frame> {
+        let reply = match message.kind {
+            MSG_CLIPBOARD_SET => self.set(vm, message, outbox),
+            MSG_CLIPBOARD_GET => self.get(vm, message),
+            _ => Err(ErrorCode::Unsupported),
+        };
+        if message.id == 0 {
+            return None;
+        }
+        Some(reply.unwrap_or_else(|code| Frame::error(message.id, code)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    use hypercore_guest::protocol::{FrameDecoder, Hello, MAX_PAYLOAD, MSG_HELLO, MSG_OK, PROTOCOL_VERSION};
+
+    use crate::services::agent::AgentHub;
+    use crate::vdev::channel::{ByteChannel, SharedChannel};
+
+    struct Setup {
+        hub: AgentHub,
+        broker: ClipboardBroker,
+        channels: BTreeMap<&'static str, SharedChannel>,
+    }
+
+    impl Setup {
+        /// VMs "a" and "b" share, "c" takes no part; all agents are up.
+        fn new() -> Self {
+            let mut broker = ClipboardBroker::new();
+            broker.set_policy("a", ClipboardPolicy::shared());
+            broker.set_policy("b", ClipboardPolicy::shared().with_max_size(64));
+            let mut setup = Setup { hub: AgentHub::new(), broker, channels: BTreeMap::new() };
+            for vm in ["a", "b", "c"] {
+                let channel = ByteChannel::shared(4096);
+                setup.hub.attach(vm, channel.clone());
+                setup.channels.insert(vm, channel);
+                let hello = Hello { version: PROTOCOL_VERSION, services: SERVICE_CLIPBOARD, max_payload: 0 };
+                setup.request(vm, MSG_HELLO, hello.encode());
+            }
+            setup
+        }
+
+        /// Send a request from `vm`'s agent and return its reply, leaving
+        /// any events to `events`.
+        fn request(&mut self, vm: &str, kind: u16, payload: Vec<u8>) -> Frame {
+            let frame = Frame::new(kind, 1, payload);
+            self.channels[vm].lock().push_from_guest(&frame.encode());
+            self.hub.poll(&mut [&mut self.broker]);
+            self.received(vm).into_iter().find(|frame| frame.id == 1).unwrap()
+        }
+
+        fn received(&self, vm: &str) -> Vec<Frame> {
+            let mut decoder = FrameDecoder::new(MAX_PAYLOAD);
+            decoder.push(&self.channels[vm].lock().pop_to_guest(usize::MAX));
+            core::iter::from_fn(|| decoder.next_frame().unwrap()).collect()
+        }
+
+        fn copy(&mut self, vm: &str, items: &[ClipboardItem]) -> Frame {
+            self.request(vm, MSG_CLIPBOARD_SET, encode_items(items))
+        }
+
+        fn paste(&mut self, vm: &str, formats: &[ClipboardFormat]) -> Result<(u64, Vec<ClipboardItem>), ErrorCode> {
+            let reply = self.request(vm, MSG_CLIPBOARD_GET, encode_formats(formats));
+            if let Some(code) = reply.error_code() {
+                return Err(code);
+            }
+            assert_eq!(reply.kind, MSG_CLIPBOARD_DATA);
+            let mut reader = Reader::new(&reply.payload);
+            Ok((reader.u64().unwrap(), decode_items(&mut reader).unwrap()))
+        }
+    }
+
+    #[test]
+    fn test_copy_and_paste_between_vms() {
+        let mut setup = Setup::new();
+        assert_eq!(setup.hub.granted("c"), Some(0));
+        assert_eq!(setup.paste("b", &[]), Err(ErrorCode::Empty));
+
+        let text = ClipboardItem::Text("from a".to_string());
+        let html = ClipboardItem::Html("<p>from a</p>".to_string());
+        assert_eq!(setup.copy("a", &[text.clone(), html.clone()]).kind, MSG_OK);
+        assert_eq!(setup.broker.current().unwrap().owner, "a");
+        // Only "b" hears about it; "c" is not in and "a" knows already.
+        let formats = encode_formats(&[ClipboardFormat::Text, ClipboardFormat::Html]);
+        let changed = [&1u64.to_le_bytes()[..], &formats].concat();
+        assert_eq!(setup.received("b"), [Frame::new(MSG_CLIPBOARD_CHANGED, 0, changed)]);
+        assert_eq!(setup.received("c"), []);
+        assert_eq!(setup.received("a"), []);
+
+        assert_eq!(setup.paste("b", &[ClipboardFormat::Html]), Ok((1, vec![html])));
+        assert_eq!(setup.paste("b", &[]), Ok((1, vec![text.clone(), ClipboardItem::Html("<p>from a</p>".to_string())])));
+        assert_eq!(setup.paste("b", &[ClipboardFormat::Image]), Err(ErrorCode::Empty));
+        assert_eq!(setup.paste("c", &[]), Err(ErrorCode::Unsupported));
+        assert_eq!(setup.copy("c", &[text]).error_code(), Some(ErrorCode::Unsupported));
+    }
+
+    #[test]
+    fn test_policy_limits() {
+        let mut setup = Setup::new();
+        // "b" may send and receive at most 64 bytes.
+        let big = ClipboardItem::Text("x".repeat(100));
+        assert_eq!(setup.copy("b", core::slice::from_ref(&big)).error_code(), Some(ErrorCode::TooLarge));
+        assert_eq!(setup.copy("a", &[big]).kind, MSG_OK);
+        assert_eq!(setup.paste("b", &[]), Err(ErrorCode::TooLarge));
+        assert_eq!(setup.paste("a", &[]).unwrap().0, 1);
+
+        // Paste-only: "b" can no longer share, and loses nothing it owned.
+        setup.broker.set_policy("b", ClipboardPolicy { copy: false, ..ClipboardPolicy::shared() });
+        assert_eq!(setup.copy("b", &[ClipboardItem::Text("hi".to_string())]).error_code(), Some(ErrorCode::Denied));
+        assert!(setup.broker.current().is_some());
+
+        // Revoking sharing from the owner clears the clipboard.
+        setup.broker.set_policy("a", ClipboardPolicy { copy: false, ..ClipboardPolicy::shared() });
+        assert_eq!(setup.broker.current(), None);
+        assert_eq!(setup.paste("a", &[]), Err(ErrorCode::Empty));
+
+        // A malformed copy changes nothing.
+        setup.broker.set_policy("a", ClipboardPolicy::shared());
+        assert_eq!(setup.request("a", MSG_CLIPBOARD_SET, vec![1, 0, 1]).error_code(), Some(ErrorCode::Malformed));
+        assert_eq!(setup.broker.current(), None);
+        setup.broker.remove_vm("a");
+        assert_eq!(setup.broker.policy("a"), ClipboardPolicy::DISABLED);
+    }
+}
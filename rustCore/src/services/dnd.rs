@@ -0,0 +1,741 @@
+// Drag-and-drop of files between VMs, mediated by the hypervisor.
+//
+// The source VM's agent offers the files when the user starts dragging them.
+// When they are dropped on another VM's window, that VM's agent is told and
+// accepts some or all of them. The hypervisor then pulls each accepted file
+// from the source agent a chunk at a time into the staging area, checks it
+// against the checksum offered, and tells the target it is ready; the target
+// reads it back at its own pace and says when it has it intact.
+//
+// Either side can pick up where it left off: pulls start at what is already
+// staged and reads at whatever offset the target asks for, and an agent that
+// says HELLO again is re-sent whatever it was waiting on.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use hypercore_guest::protocol::{
+    decode_files, encode_files, Crc32, ErrorCode, FileInfo, Frame, Reader, Writer, MSG_DND_ACCEPT, MSG_DND_CANCEL,
+    MSG_DND_CHUNK, MSG_DND_DONE, MSG_DND_DROP, MSG_DND_OFFER, MSG_DND_OFFERED, MSG_DND_PULL, MSG_DND_READ,
+    MSG_DND_READY, SERVICE_DND,
+};
+
+use super::agent::{AgentService, Outbox};
+use super::staging::{StagedId, StagingArea, StagingError};
+use crate::storage::StorageBackend;
+
+/// Most bytes pulled from a source or read by a target at a time.
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 << 10;
+/// Transfer size allowed by [`DndPolicy::shared`].
+pub const DEFAULT_MAX_TRANSFER: u64 = 4 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DndPolicy {
+    /// Whether files may be dragged out of the VM.
+    pub drag: bool,
+    /// Whether files may be dropped into the VM.
+    pub drop: bool,
+    /// Largest transfer, in bytes of file contents, the VM may send or
+    /// receive.
+    pub max_size: u64,
+}
+
+impl DndPolicy {
+    pub const DISABLED: DndPolicy = DndPolicy { drag: false, drop: false, max_size: 0 };
+
+    /// Drag and drop both ways, up to the default size.
+    pub fn shared() -> Self {
+        DndPolicy { drag: true, drop: true, max_size: DEFAULT_MAX_TRANSFER }
+    }
+
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    /// Being dragged, not dropped anywhere yet.
+    Offered,
+    /// Dropped, waiting for the target to accept.
+    Dropped,
+    /// Accepted files are being staged and delivered.
+    Copying,
+    Complete,
+    Failed(ErrorCode),
+}
+
+impl TransferState {
+    pub fn is_finished(self) -> bool {
+        matches!(self, TransferState::Complete | TransferState::Failed(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileState {
+    /// Offered but not accepted.
+    Skipped,
+    Staging,
+    /// Staged and verified; the target may read it.
+    Ready,
+    /// The target has it.
+    Done,
+}
+
+struct TransferFile {
+    info: FileInfo,
+    state: FileState,
+    staged: Option<StagedId>,
+    staged_len: u64,
+    crc: Crc32,
+    /// Furthest the target has read.
+    delivered: u64,
+}
+
+struct Transfer {
+    source: String,
+    target: Option<String>,
+    files: Vec<TransferFile>,
+    state: TransferState,
+    /// File and offset of the pull awaiting a chunk.
+    pulling: Option<(u16, u64)>,
+}
+
+impl Transfer {
+    fn involves(&self, vm: &str) -> bool {
+        self.source == vm || self.target.as_deref() == Some(vm)
+    }
+}
+
+/// Where a transfer stands, for the GUI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub id: u64,
+    pub source: String,
+    pub target: Option<String>,
+    pub state: TransferState,
+    /// Files accepted, or offered until the target has accepted.
+    pub files: usize,
+    /// Bytes in those files.
+    pub total: u64,
+    /// Bytes pulled from the source so far.
+    pub staged: u64,
+    /// Bytes the target has read so far.
+    pub delivered: u64,
+}
+
+pub struct DndBroker<S: StorageBackend> {
+    policies: BTreeMap<String, DndPolicy>,
+    staging: StagingArea<S>,
+    transfers: BTreeMap<u64, Transfer>,
+    next_transfer: u64,
+    chunk_size: u32,
+}
+
+/// `vm`'s transfer `id` as its target, if it is in `state`.
+fn target_transfer<'a>(
+    transfers: &'a mut BTreeMap<u64, Transfer>,
+    vm: &str,
+    id: u64,
+    state: TransferState,
+) -> Result<&'a mut Transfer, ErrorCode> {
+    transfers
+        .get_mut(&id)
+        .filter(|transfer| transfer.target.as_deref() == Some(vm) && transfer.state == state)
+        .ok_or(ErrorCode::NotFound)
+}
+
+fn cancel_frame(transfer: u64, code: ErrorCode) -> Frame {
+    Frame::new(MSG_DND_CANCEL, 0, Writer::new().u64(transfer).u16(code.code()).finish())
+}
+
+impl From<StagingError> for ErrorCode {
+    fn from(err: StagingError) -> Self {
+        match err {
+            StagingError::NoSpace => ErrorCode::TooLarge,
+            StagingError::NotFound => ErrorCode::NotFound,
+            StagingError::Io => ErrorCode::Other(0),
+        }
+    }
+}
+
+impl<S: StorageBackend> DndBroker<S> {
+    pub fn new(staging: StagingArea<S>) -> Self {
+        DndBroker {
+            policies: BTreeMap::new(),
+            staging,
+            transfers: BTreeMap::new(),
+            next_transfer: 1,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    /// Move at most `chunk_size` bytes per message; it must stay under
+    /// the hub's payload limit.
+    pub fn with_chunk_size(mut self, chunk_size: u32) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn policy(&self, vm: &str) -> DndPolicy {
+        self.policies.get(vm).copied().unwrap_or(DndPolicy::DISABLED)
+    }
+
+    /// Set `vm`'s policy. Transfers already under way are left to finish.
+    pub fn set_policy(&mut self, vm: &str, policy: DndPolicy) {
+        if policy == DndPolicy::DISABLED {
+            self.policies.remove(vm);
+        } else {
+            self.policies.insert(String::from(vm), policy);
+        }
+    }
+
+    /// Forget a VM that was deleted, cancelling its transfers.
+    pub fn remove_vm(&mut self, vm: &str, outbox: &mut Outbox) {
+        self.policies.remove(vm);
+        self.cancel_vm(vm, outbox);
+    }
+
+    pub fn progress(&self) -> Vec<TransferProgress> {
+        self.transfers
+            .iter()
+            .map(|(&id, transfer)| {
+                let offered = matches!(transfer.state, TransferState::Offered | TransferState::Dropped);
+                let files = || transfer.files.iter().filter(move |file| offered || file.state != FileState::Skipped);
+                TransferProgress {
+                    id,
+                    source: transfer.source.clone(),
+                    target: transfer.target.clone(),
+                    state: transfer.state,
+                    files: files().count(),
+                    total: files().map(|file| file.info.size).sum(),
+                    staged: files().map(|file| file.staged_len).sum(),
+                    delivered: files().map(|file| file.delivered).sum(),
+                }
+            })
+            .collect()
+    }
+
+    /// Stop reporting transfers that completed or failed.
+    pub fn clear_finished(&mut self) {
+        self.transfers.retain(|_, transfer| !transfer.state.is_finished());
+    }
+
+    /// The user dropped what they are dragging out of `source` onto
+    /// `target`'s window. Returns the transfer ID.
+    pub fn drop_on(&mut self, source: &str, target: &str, outbox: &mut Outbox) -> Result<u64, ErrorCode> {
+        if source == target || !self.policy(target).drop {
+            return Err(ErrorCode::Denied);
+        }
+        let (&id, transfer) = self
+            .transfers
+            .iter_mut()
+            .find(|(_, transfer)| transfer.source == source && transfer.state == TransferState::Offered)
+            .ok_or(ErrorCode::Empty)?;
+        let total: u64 = transfer.files.iter().map(|file| file.info.size).sum();
+        if total > self.policies[target].max_size {
+            return Err(ErrorCode::TooLarge);
+        }
+        transfer.target = Some(String::from(target));
+        transfer.state = TransferState::Dropped;
+        Self::send_drop(id, transfer, outbox);
+        Ok(id)
+    }
+
+    /// The user called a transfer off.
+    pub fn cancel(&mut self, id: u64, outbox: &mut Outbox) {
+        self.fail(id, ErrorCode::Cancelled, None, outbox);
+    }
+
+    fn send_drop(id: u64, transfer: &Transfer, outbox: &mut Outbox) {
+        let files: Vec<FileInfo> = transfer.files.iter().map(|file| file.info.clone()).collect();
+        let payload = [&Writer::new().u64(id).finish()[..], &encode_files(&files)].concat();
+        outbox.send(transfer.target.as_deref().unwrap(), Frame::new(MSG_DND_DROP, 0, payload));
+    }
+
+    fn send_ready(id: u64, transfer: &Transfer, file: u16, outbox: &mut Outbox) {
+        let payload = Writer::new().u64(id).u16(file).finish();
+        outbox.send(transfer.target.as_deref().unwrap(), Frame::new(MSG_DND_READY, 0, payload));
+    }
+
+    /// End a transfer that has not finished, freeing what it staged and
+    /// telling whichever agents did not call it off themselves.
+    fn fail(&mut self, id: u64, code: ErrorCode, by: Option<&str>, outbox: &mut Outbox) {
+        let Some(transfer) = self.transfers.get_mut(&id).filter(|transfer| !transfer.state.is_finished()) else {
+            return;
+        };
+        let notify = [Some(transfer.source.as_str()), transfer.target.as_deref()];
+        for vm in notify.into_iter().flatten().filter(|&vm| Some(vm) != by) {
+            outbox.send(vm, cancel_frame(id, code));
+        }
+        for file in transfer.files.iter_mut() {
+            if let Some(staged) = file.staged.take() {
+                self.staging.remove(staged);
+            }
+        }
+        if transfer.state == TransferState::Offered {
+            // Never dropped, so there is nothing to report.
+            self.transfers.remove(&id);
+        } else {
+            transfer.state = TransferState::Failed(code);
+            transfer.pulling = None;
+        }
+    }
+
+    fn cancel_vm(&mut self, vm: &str, outbox: &mut Outbox) {
+        let ids: Vec<u64> = self.transfers.iter().filter(|(_, t)| t.involves(vm)).map(|(&id, _)| id).collect();
+        for id in ids {
+            self.fail(id, ErrorCode::Cancelled, Some(vm), outbox);
+        }
+    }
+
+    /// Pull the next piece of the transfer from its source, unless a pull
+    /// is already out, telling the target about each file that is ready.
+    fn pump(&mut self, id: u64, outbox: &mut Outbox) {
+        let Some(transfer) = self.transfers.get_mut(&id) else { return };
+        if transfer.state != TransferState::Copying || transfer.pulling.is_some() {
+            return;
+        }
+        for index in 0..transfer.files.len() {
+            let file = &mut transfer.files[index];
+            if file.state != FileState::Staging {
+                continue;
+            }
+            if file.staged_len < file.info.size {
+                let len = core::cmp::min(file.info.size - file.staged_len, self.chunk_size as u64) as u32;
+                let pull = Writer::new().u64(id).u16(index as u16).u64(file.staged_len).u32(len).finish();
+                outbox.send(&transfer.source, Frame::new(MSG_DND_PULL, 0, pull));
+                transfer.pulling = Some((index as u16, file.staged_len));
+                return;
+            }
+            if file.crc.finish() != file.info.checksum {
+                return self.fail(id, ErrorCode::Corrupt, None, outbox);
+            }
+            file.state = FileState::Ready;
+            Self::send_ready(id, transfer, index as u16, outbox);
+        }
+    }
+
+    fn offer(&mut self, vm: &str, message: &Frame, outbox: &mut Outbox) -> Result<Frame, ErrorCode> {
+        let policy = self.policy(vm);
+        if !policy.drag {
+            return Err(ErrorCode::Denied);
+        }
+        let mut reader = Reader::new(&message.payload);
+        let files = decode_files(&mut reader).map_err(|_| ErrorCode::Malformed)?;
+        reader.finish().map_err(|_| ErrorCode::Malformed)?;
+        // Names must not lead the target agent out of its drop directory.
+        if files.iter().any(|file| matches!(file.name.as_str(), "" | "." | "..") || file.name.contains(['/', '\\'])) {
+            return Err(ErrorCode::Malformed);
+        }
+        if files.iter().map(|file| file.size).fold(0u64, u64::saturating_add) > policy.max_size {
+            return Err(ErrorCode::TooLarge);
+        }
+        let previous = self.transfers.iter().find(|(_, t)| t.source == vm && t.state == TransferState::Offered);
+        if let Some((&previous, _)) = previous {
+            self.fail(previous, ErrorCode::Cancelled, Some(vm), outbox);
+        }
+        let id = self.next_transfer;
+        self.next_transfer += 1;
+        let files = files
+            .into_iter()
+            .map(|info| TransferFile {
+                info,
+                state: FileState::Skipped,
+                staged: None,
+                staged_len: 0,
+                crc: Crc32::new(),
+                delivered: 0,
+            })
+            .collect();
+        let transfer =
+            Transfer { source: String::from(vm), target: None, files, state: TransferState::Offered, pulling: None };
+        self.transfers.insert(id, transfer);
+        Ok(Frame::new(MSG_DND_OFFERED, message.id, Writer::new().u64(id).finish()))
+    }
+
+    fn accept(&mut self, vm: &str, message: &Frame, outbox: &mut Outbox) -> Result<Frame, ErrorCode> {
+        let mut reader = Reader::new(&message.payload);
+        let id = reader.u64().map_err(|_| ErrorCode::Malformed)?;
+        let count = reader.u16().map_err(|_| ErrorCode::Malformed)?;
+        let accepted: Vec<u16> =
+            (0..count).map(|_| reader.u16()).collect::<Result<_, _>>().map_err(|_| ErrorCode::Malformed)?;
+        reader.finish().map_err(|_| ErrorCode::Malformed)?;
+        let transfer = target_transfer(&mut self.transfers, vm, id, TransferState::Dropped)?;
+        if accepted.iter().any(|&index| index as usize >= transfer.files.len()) {
+            return Err(ErrorCode::Malformed);
+        }
+        if accepted.is_empty() {
+            self.fail(id, ErrorCode::Cancelled, Some(vm), outbox);
+            return Ok(Frame::ok(message.id));
+        }
+        let mut accepted_files = vec![false; transfer.files.len()];
+        for &index in &accepted {
+            accepted_files[index as usize] = true;
+        }
+        let sizes = || transfer.files.iter().zip(&accepted_files).filter(|(_, &a)| a).map(|(file, _)| file.info.size);
+        let blocks: u64 = sizes().map(|size| self.staging.blocks_for(size)).sum();
+        if blocks > self.staging.free_blocks() {
+            return Err(ErrorCode::TooLarge);
+        }
+        for (file, _) in transfer.files.iter_mut().zip(&accepted_files).filter(|(_, &a)| a) {
+            file.state = FileState::Staging;
+            file.staged = Some(self.staging.create());
+        }
+        transfer.state = TransferState::Copying;
+        self.pump(id, outbox);
+        Ok(Frame::ok(message.id))
+    }
+
+    /// Stage a chunk the source sent in answer to a pull. Chunks that answer
+    /// no pull, left over from before an agent restarted, are ignored.
+    fn chunk(&mut self, vm: &str, message: &Frame, outbox: &mut Outbox) {
+        let mut reader = Reader::new(&message.payload);
+        let (Ok(id), Ok(index), Ok(offset), Ok(data)) = (reader.u64(), reader.u16(), reader.u64(), reader.bytes())
+        else {
+            return;
+        };
+        let Some(transfer) = self.transfers.get_mut(&id) else { return };
+        if transfer.source != vm || transfer.pulling != Some((index, offset)) {
+            return;
+        }
+        transfer.pulling = None;
+        let file = &mut transfer.files[index as usize];
+        if data.is_empty() || data.len() as u64 > file.info.size - file.staged_len {
+            // The file changed size since it was offered.
+            return self.fail(id, ErrorCode::Corrupt, None, outbox);
+        }
+        if let Err(err) = self.staging.append(file.staged.unwrap(), data) {
+            return self.fail(id, err.into(), None, outbox);
+        }
+        file.crc.update(data);
+        file.staged_len += data.len() as u64;
+        self.pump(id, outbox);
+    }
+
+    fn read(&mut self, vm: &str, message: &Frame) -> Result<Frame, ErrorCode> {
+        let mut reader = Reader::new(&message.payload);
+        let (Ok(id), Ok(index), Ok(offset), Ok(len), Ok(())) =
+            (reader.u64(), reader.u16(), reader.u64(), reader.u32(), reader.finish())
+        else {
+            return Err(ErrorCode::Malformed);
+        };
+        let transfer = target_transfer(&mut self.transfers, vm, id, TransferState::Copying)?;
+        let file = transfer.files.get_mut(index as usize).ok_or(ErrorCode::NotFound)?;
+        match file.state {
+            FileState::Ready => {}
+            FileState::Staging => return Err(ErrorCode::Empty),
+            FileState::Skipped | FileState::Done => return Err(ErrorCode::NotFound),
+        }
+        let mut data = vec![0u8; core::cmp::min(len, self.chunk_size) as usize];
+        let count = self.staging.read(file.staged.unwrap(), offset, &mut data)?;
+        data.truncate(count);
+        file.delivered = core::cmp::max(file.delivered, offset + count as u64);
+        let payload = Writer::new().u64(id).u16(index).u64(offset).bytes(&data).finish();
+        Ok(Frame::new(MSG_DND_CHUNK, message.id, payload))
+    }
+
+    fn done(&mut self, vm: &str, message: &Frame) -> Result<Frame, ErrorCode> {
+        let mut reader = Reader::new(&message.payload);
+        let (Ok(id), Ok(index), Ok(())) = (reader.u64(), reader.u16(), reader.finish()) else {
+            return Err(ErrorCode::Malformed);
+        };
+        let transfer = target_transfer(&mut self.transfers, vm, id, TransferState::Copying)?;
+        let file = transfer.files.get_mut(index as usize).ok_or(ErrorCode::NotFound)?;
+        if file.state != FileState::Ready {
+            return Err(ErrorCode::NotFound);
+        }
+        file.state = FileState::Done;
+        file.delivered = file.info.size;
+        self.staging.remove(file.staged.take().unwrap());
+        if transfer.files.iter().all(|file| matches!(file.state, FileState::Done | FileState::Skipped)) {
+            transfer.state = TransferState::Complete;
+        }
+        Ok(Frame::ok(message.id))
+    }
+
+    /// An agent called a transfer off.
+    fn cancelled(&mut self, vm: &str, message: &Frame, outbox: &mut Outbox) {
+        let mut reader = Reader::new(&message.payload);
+        let (Ok(id), Ok(code)) = (reader.u64(), reader.u16()) else { return };
+        if self.transfers.get(&id).is_some_and(|transfer| transfer.involves(vm)) {
+            self.fail(id, ErrorCode::from_code(code), Some(vm), outbox);
+        }
+    }
+}
+
+impl<S: StorageBackend> AgentService for DndBroker<S> {
+    fn service(&self) -> u32 {
+        SERVICE_DND
+    }
+
+    fn allows(&self, vm: &str) -> bool {
+        let policy = self.policy(vm);
+        policy.drag || policy.drop
+    }
+
+    fn handle(&mut self, vm: &str, message: &Frame, outbox: &mut Outbox) -> Option<Frame> {
+        let reply = match message.kind {
+            MSG_DND_OFFER => self.offer(vm, message, outbox),
+            MSG_DND_ACCEPT => self.accept(vm, message, outbox),
+            MSG_DND_READ => self.read(vm, message),
+            MSG_DND_DONE => self.done(vm, message),
+            // Events from agents, with nothing to reply.
+            MSG_DND_CHUNK => {
+                self.chunk(vm, message, outbox);
+                return None;
+            }
+            MSG_DND_CANCEL => {
+                self.cancelled(vm, message, outbox);
+                return None;
+            }
+            _ => Err(ErrorCode::Unsupported),
+        };
+        if message.id == 0 {
+            return None;
+        }
+        Some(reply.unwrap_or_else(|code| Frame::error(message.id, code)))
+    }
+
+    /// Send a restarted agent what it was waiting on: the source is pulled
+    /// from again at what is staged, the target is told again about the
+    /// drop or the files ready to read.
+    fn connected(&mut self, vm: &str, outbox: &mut Outbox) {
+        let ids: Vec<u64> = self.transfers.keys().copied().collect();
+        for id in ids {
+            let transfer = self.transfers.get_mut(&id).unwrap();
+            if transfer.source == vm && transfer.state == TransferState::Copying {
+                transfer.pulling = None;
+                self.pump(id, outbox);
+            } else if transfer.target.as_deref() == Some(vm) {
+                match transfer.state {
+                    TransferState::Dropped => Self::send_drop(id, transfer, outbox),
+                    TransferState::Copying => {
+                        let ready = transfer.files.iter().enumerate().filter(|(_, f)| f.state == FileState::Ready);
+                        for (index, _) in ready {
+                            Self::send_ready(id, transfer, index as u16, outbox);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn detached(&mut self, vm: &str, outbox: &mut Outbox) {
+        self.cancel_vm(vm, outbox);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::rc::Rc;
+    use alloc::string::ToString;
+    use core::cell::RefCell;
+
+    use hypercore_guest::{Agent, AgentError, ChannelError, Event};
+
+    use crate::services::agent::AgentHub;
+    use crate::storage::tests::RamStorage;
+    use crate::vdev::channel::tests::Loopback;
+    use crate::vdev::channel::ByteChannel;
+
+    const CHUNK: u32 = 16;
+
+    struct Host {
+        hub: AgentHub,
+        dnd: DndBroker<RamStorage>,
+    }
+
+    impl Host {
+        /// VMs "a" and "b" may drag and drop; "c" takes no part.
+        fn new() -> Rc<RefCell<Host>> {
+            let staging = StagingArea::new(RamStorage::new(32, 8), 32, 8);
+            let mut dnd = DndBroker::new(staging).with_chunk_size(CHUNK);
+            dnd.set_policy("a", DndPolicy::shared());
+            dnd.set_policy("b", DndPolicy::shared());
+            Rc::new(RefCell::new(Host { hub: AgentHub::new(), dnd }))
+        }
+
+        fn poll(&mut self) {
+            self.hub.poll(&mut [&mut self.dnd]);
+        }
+
+        fn drop_on(&mut self, source: &str, target: &str) -> Result<u64, ErrorCode> {
+            let mut outbox = Outbox::default();
+            let id = self.dnd.drop_on(source, target, &mut outbox);
+            self.hub.deliver(outbox);
+            id
+        }
+    }
+
+    /// An agent channel of VM `vm` whose waits run the host.
+    fn attach(host: &Rc<RefCell<Host>>, vm: &str) -> Loopback {
+        let channel = ByteChannel::shared(1 << 16);
+        host.borrow_mut().hub.attach(vm, channel.clone());
+        let host = host.clone();
+        Loopback::new(channel, move |_| host.borrow_mut().poll())
+    }
+
+    fn connect(channel: Loopback) -> Agent<Loopback> {
+        Agent::connect(channel, SERVICE_DND).unwrap()
+    }
+
+    fn file(name: &str, data: &[u8]) -> FileInfo {
+        FileInfo { name: name.to_string(), size: data.len() as u64, checksum: Crc32::checksum(data) }
+    }
+
+    /// The source agent: answer pulls from `files` until the host goes
+    /// quiet, returning any other events.
+    fn serve(agent: &mut Agent<Loopback>, files: &[&[u8]]) -> Vec<Event> {
+        let mut events = Vec::new();
+        loop {
+            match agent.next_event() {
+                Ok(Event::DndPull { transfer, file, offset, len }) => {
+                    let data = files[file as usize];
+                    let end = core::cmp::min(data.len(), (offset + len as u64) as usize);
+                    agent.send_chunk(transfer, file, offset, &data[offset as usize..end]).unwrap();
+                }
+                Ok(event) => events.push(event),
+                Err(AgentError::Channel(ChannelError::Closed)) => return events,
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+    }
+
+    /// The target agent's side of the disk: what was dropped and what of it
+    /// has arrived, which survives the agent restarting.
+    #[derive(Default)]
+    struct Dropped {
+        files: Vec<FileInfo>,
+        received: BTreeMap<u16, Vec<u8>>,
+    }
+
+    /// The target agent: accept every drop and read ready files, stopping
+    /// after `budget` bytes as though the agent crashed, until the host goes
+    /// quiet. Returns any other events.
+    fn receive(agent: &mut Agent<Loopback>, dropped: &mut Dropped, mut budget: usize) -> Vec<Event> {
+        let mut events = Vec::new();
+        loop {
+            match agent.next_event() {
+                Ok(Event::DndDrop { transfer, files }) => {
+                    let all: Vec<u16> = (0..files.len() as u16).collect();
+                    dropped.files = files;
+                    agent.accept_drop(transfer, &all).unwrap();
+                }
+                Ok(Event::DndReady { transfer, file }) => {
+                    let received = dropped.received.entry(file).or_default();
+                    loop {
+                        if budget == 0 {
+                            return events;
+                        }
+                        let chunk = agent.read_chunk(transfer, file, received.len() as u64, CHUNK).unwrap();
+                        if chunk.is_empty() {
+                            break;
+                        }
+                        budget = budget.saturating_sub(chunk.len());
+                        received.extend(chunk);
+                    }
+                    assert_eq!(Crc32::checksum(received), dropped.files[file as usize].checksum);
+                    agent.finish_file(transfer, file).unwrap();
+                }
+                Ok(event) => events.push(event),
+                Err(AgentError::Channel(ChannelError::Closed)) => return events,
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_drag_and_drop_between_vms() {
+        let host = Host::new();
+        let mut source = connect(attach(&host, "a"));
+        let mut target = connect(attach(&host, "b"));
+        let notes: &[u8] = b"Hypercore drag-and-drop notes: 50 bytes of text...";
+        let picture = [0x89u8; 40];
+        let files = [notes, &[], &picture];
+        let offered = [file("notes.txt", notes), file("empty", &[]), file("picture.png", &picture)];
+
+        let id = source.offer_files(&offered).unwrap();
+        assert_eq!(host.borrow().dnd.progress()[0].state, TransferState::Offered);
+        assert_eq!(host.borrow_mut().drop_on("a", "c"), Err(ErrorCode::Denied));
+        assert_eq!(host.borrow_mut().drop_on("b", "a"), Err(ErrorCode::Empty));
+        assert_eq!(host.borrow_mut().drop_on("a", "b"), Ok(id));
+
+        // The target accepts, the source sends one chunk and restarts.
+        let mut dropped = Dropped::default();
+        assert_eq!(receive(&mut target, &mut dropped, usize::MAX), []);
+        assert_eq!(dropped.files, offered);
+        let pull = source.next_event().unwrap();
+        assert_eq!(pull, Event::DndPull { transfer: id, file: 0, offset: 0, len: CHUNK });
+        source.send_chunk(id, 0, 0, &notes[..16]).unwrap();
+        let mut source = connect(source.into_channel());
+        // Pulls resume at the second chunk; the one from before the restart
+        // is answered too, and ignored.
+        assert_eq!(serve(&mut source, &files), []);
+        let progress = host.borrow().dnd.progress()[0].clone();
+        assert_eq!((progress.state, progress.files, progress.total), (TransferState::Copying, 3, 90));
+        assert_eq!((progress.staged, progress.delivered), (90, 0));
+
+        // The target reads part way and restarts, then picks up from there.
+        assert_eq!(receive(&mut target, &mut dropped, 20), []);
+        assert_eq!(host.borrow().dnd.progress()[0].delivered, 32);
+        let mut target = connect(target.into_channel());
+        assert_eq!(receive(&mut target, &mut dropped, usize::MAX), []);
+        assert_eq!(dropped.received[&0], notes);
+        assert_eq!(dropped.received[&1], []);
+        assert_eq!(dropped.received[&2], picture);
+
+        let progress = host.borrow().dnd.progress()[0].clone();
+        assert_eq!((progress.state, progress.delivered), (TransferState::Complete, 90));
+        assert_eq!(host.borrow().dnd.staging.free_blocks(), 8);
+        host.borrow_mut().dnd.clear_finished();
+        assert_eq!(host.borrow().dnd.progress(), []);
+    }
+
+    #[test]
+    fn test_checksum_mismatch_and_cancel() {
+        let host = Host::new();
+        let mut source = connect(attach(&host, "a"));
+        let mut target = connect(attach(&host, "b"));
+        let mut dropped = Dropped::default();
+
+        // The file changed after it was offered.
+        let id = source.offer_files(&[file("a.txt", b"original")]).unwrap();
+        host.borrow_mut().drop_on("a", "b").unwrap();
+        receive(&mut target, &mut dropped, usize::MAX);
+        let cancelled = Event::DndCancelled { transfer: id, code: ErrorCode::Corrupt };
+        assert_eq!(serve(&mut source, &[b"modified"]), core::slice::from_ref(&cancelled));
+        assert_eq!(receive(&mut target, &mut dropped, usize::MAX), [cancelled]);
+        assert_eq!(host.borrow().dnd.progress()[0].state, TransferState::Failed(ErrorCode::Corrupt));
+        assert_eq!(host.borrow().dnd.staging.free_blocks(), 8);
+
+        // Names that could escape the drop directory are refused, and so
+        // are transfers the staging area cannot hold.
+        let escape = source.offer_files(&[file("../x", b"")]);
+        assert_eq!(escape, Err(AgentError::Refused(ErrorCode::Malformed)));
+        let huge = FileInfo { name: "huge".to_string(), size: 1 << 20, checksum: 0 };
+        let id = source.offer_files(&[huge]).unwrap();
+        host.borrow_mut().drop_on("a", "b").unwrap();
+        target.next_event().unwrap();
+        assert_eq!(target.accept_drop(id, &[0]), Err(AgentError::Refused(ErrorCode::TooLarge)));
+
+        // Declining tells the source; a VM going away tells the other side.
+        target.accept_drop(id, &[]).unwrap();
+        let declined = Event::DndCancelled { transfer: id, code: ErrorCode::Cancelled };
+        assert_eq!(serve(&mut source, &[]), [declined]);
+        let id = source.offer_files(&[file("b.txt", b"data")]).unwrap();
+        host.borrow_mut().drop_on("a", "b").unwrap();
+        {
+            let Host { hub, dnd } = &mut *host.borrow_mut();
+            hub.detach("a", &mut [dnd]);
+        }
+        assert!(matches!(target.next_event(), Ok(Event::DndDrop { transfer, .. }) if transfer == id));
+        assert_eq!(target.next_event(), Ok(Event::DndCancelled { transfer: id, code: ErrorCode::Cancelled }));
+        assert_eq!(host.borrow().dnd.progress().len(), 3);
+    }
+}
@@ -0,0 +1,274 @@
+// Services guests reach by hypercall rather than through their agent: the
+// host's wall clock, the shared clipboard's text and the state of shared
+// volumes. Each is registered in a VM's `HypercallTable` under the function
+// numbers in hypercore_guest::hypercall, and answers for that one VM.
+//
+// The clipboard calls go through the same broker as agents do, so a copy
+// made by hypercall is pasted by agents and the other way round.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use hypercore_guest::hypercall::{HypercallError, HC_CLIPBOARD_GET, HC_CLIPBOARD_SET, HC_TIME, HC_VOLUME_NOTIFY};
+use hypercore_guest::protocol::{ClipboardFormat, ClipboardItem, ErrorCode};
+
+use super::agent::Outbox;
+use super::clipboard::ClipboardBroker;
+use crate::hypervisor::{Hypercall, HypercallContext, HypercallTable};
+use crate::storage::volume::{Access, SharedVolumeStore, MAX_NAME_LEN};
+use crate::storage::StorageBackend;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+fn status(code: ErrorCode) -> HypercallError {
+    match code {
+        ErrorCode::Denied => HypercallError::Denied,
+        ErrorCode::TooLarge => HypercallError::TooLarge,
+        ErrorCode::Empty => HypercallError::Empty,
+        ErrorCode::NotFound => HypercallError::NotFound,
+        _ => HypercallError::Invalid,
+    }
+}
+
+/// TIME: the host's wall clock.
+pub struct TimeSync {
+    /// Wall-clock time in nanoseconds since the Unix epoch.
+    clock: Box<dyn Fn() -> u64 + Send>,
+}
+
+impl TimeSync {
+    pub fn new(clock: Box<dyn Fn() -> u64 + Send>) -> Self {
+        TimeSync { clock }
+    }
+
+    pub fn register(self, table: &mut HypercallTable) {
+        table.register(HC_TIME, Box::new(self));
+    }
+}
+
+impl Hypercall for TimeSync {
+    fn call(&mut self, ctx: &mut HypercallContext) -> Result<(), HypercallError> {
+        let now = (self.clock)();
+        ctx.results = [now / NANOS_PER_SEC, now % NANOS_PER_SEC, 0];
+        Ok(())
+    }
+}
+
+/// CLIPBOARD_SET and CLIPBOARD_GET for one VM.
+#[derive(Clone)]
+pub struct ClipboardCalls {
+    vm: String,
+    broker: Arc<Mutex<ClipboardBroker>>,
+    outbox: Arc<Mutex<Outbox>>,
+}
+
+impl ClipboardCalls {
+    /// `vm`'s clipboard calls. Copies are announced to other VMs' agents
+    /// through `outbox`, which the caller empties into
+    /// [`AgentHub::deliver`](super::AgentHub::deliver).
+    pub fn new(vm: &str, broker: Arc<Mutex<ClipboardBroker>>, outbox: Arc<Mutex<Outbox>>) -> Self {
+        ClipboardCalls { vm: String::from(vm), broker, outbox }
+    }
+
+    pub fn register(self, table: &mut HypercallTable) {
+        table.register(HC_CLIPBOARD_SET, Box::new(self.clone()));
+        table.register(HC_CLIPBOARD_GET, Box::new(self));
+    }
+
+    fn set(&mut self, ctx: &mut HypercallContext) -> Result<(), HypercallError> {
+        let [gpa, len, ..] = ctx.args;
+        let policy = self.broker.lock().policy(&self.vm);
+        if !policy.copy {
+            return Err(HypercallError::Denied);
+        }
+        if len > policy.max_size as u64 {
+            return Err(HypercallError::TooLarge);
+        }
+        let text = String::from_utf8(ctx.read(gpa, len as usize)?).map_err(|_| HypercallError::Invalid)?;
+        let items = if text.is_empty() { Vec::new() } else { vec![ClipboardItem::Text(text)] };
+        let serial = self.broker.lock().copy(&self.vm, items, &mut self.outbox.lock()).map_err(status)?;
+        ctx.results[0] = serial;
+        Ok(())
+    }
+
+    fn get(&mut self, ctx: &mut HypercallContext) -> Result<(), HypercallError> {
+        let [gpa, size, ..] = ctx.args;
+        let broker = self.broker.lock();
+        let (serial, items) = broker.paste(&self.vm, &[ClipboardFormat::Text]).map_err(status)?;
+        let Some(ClipboardItem::Text(text)) = items.first() else { return Err(HypercallError::Empty) };
+        if text.len() > broker.policy(&self.vm).max_size {
+            return Err(HypercallError::TooLarge);
+        }
+        ctx.results = [text.len() as u64, serial, 0];
+        match size {
+            0 => Ok(()),
+            size if text.len() as u64 > size => Err(HypercallError::TooSmall),
+            _ => ctx.write(gpa, text.as_bytes()),
+        }
+    }
+}
+
+impl Hypercall for ClipboardCalls {
+    fn call(&mut self, ctx: &mut HypercallContext) -> Result<(), HypercallError> {
+        match ctx.function {
+            HC_CLIPBOARD_SET => self.set(ctx),
+            HC_CLIPBOARD_GET => self.get(ctx),
+            _ => Err(HypercallError::NotSupported),
+        }
+    }
+}
+
+/// VOLUME_NOTIFY for one VM: the generation of a volume it may use.
+pub struct VolumeNotify<S: StorageBackend> {
+    vm: String,
+    store: SharedVolumeStore<S>,
+}
+
+impl<S: StorageBackend + Send + 'static> VolumeNotify<S> {
+    pub fn new(vm: &str, store: SharedVolumeStore<S>) -> Self {
+        VolumeNotify { vm: String::from(vm), store }
+    }
+
+    pub fn register(self, table: &mut HypercallTable) {
+        table.register(HC_VOLUME_NOTIFY, Box::new(self));
+    }
+}
+
+impl<S: StorageBackend + Send> Hypercall for VolumeNotify<S> {
+    fn call(&mut self, ctx: &mut HypercallContext) -> Result<(), HypercallError> {
+        let [gpa, len, ..] = ctx.args;
+        if len > MAX_NAME_LEN as u64 {
+            return Err(HypercallError::Invalid);
+        }
+        let name = String::from_utf8(ctx.read(gpa, len as usize)?).map_err(|_| HypercallError::Invalid)?;
+        let store = self.store.lock();
+        // Volumes the VM may not use are not told apart from missing ones.
+        let access = match store.access(&name, &self.vm) {
+            Access::None => return Err(HypercallError::NotFound),
+            Access::Read => 1,
+            Access::Write => 2,
+        };
+        let generation = store.generation(&name).map_err(|_| HypercallError::NotFound)?;
+        ctx.results = [generation, access, 0];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::{HypervisorBackend, MockBackend, SpecialRegisters, VcpuRegisters};
+    use crate::services::agent::AgentHub;
+    use crate::services::clipboard::ClipboardPolicy;
+    use crate::storage::tests::RamStorage;
+    use crate::storage::volume::{VolumeHandle, VolumeStore, ROOT_INO};
+    use crate::vdev::channel::ByteChannel;
+    use hypercore_guest::hypercall::HypercallError::*;
+    use hypercore_guest::protocol::{
+        Frame, FrameDecoder, Hello, MAX_PAYLOAD, MSG_CLIPBOARD_CHANGED, MSG_HELLO, PROTOCOL_VERSION, SERVICE_CLIPBOARD,
+    };
+
+    struct Guest {
+        table: HypercallTable,
+        memory: MockBackend,
+    }
+
+    impl Guest {
+        fn new(table: HypercallTable) -> Self {
+            let mut memory = MockBackend::new();
+            memory.alloc_ram(0, 0x10000).unwrap();
+            Guest { table, memory }
+        }
+
+        fn call(&mut self, function: u32, args: [u64; 4]) -> Result<[u64; 3], HypercallError> {
+            let mut regs =
+                VcpuRegisters { rax: function as u64, rbx: args[0], rcx: args[1], rdx: args[2], ..Default::default() };
+            self.table.dispatch(0, &mut regs, &SpecialRegisters::long_mode(0), &mut self.memory);
+            match regs.rax as i64 {
+                0 => Ok([regs.rbx, regs.rcx, regs.rdx]),
+                code => Err(HypercallError::from_code(code)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_time_and_clipboard() {
+        let broker = Arc::new(Mutex::new(ClipboardBroker::new()));
+        broker.lock().set_policy("a", ClipboardPolicy::shared().with_max_size(64));
+        broker.lock().set_policy("b", ClipboardPolicy { copy: false, paste: true, max_size: 64 });
+        let outbox = Arc::new(Mutex::new(Outbox::default()));
+        let guest = |vm: &str| {
+            let mut table = HypercallTable::new();
+            TimeSync::new(Box::new(|| 1_700_000_000 * NANOS_PER_SEC + 250)).register(&mut table);
+            ClipboardCalls::new(vm, broker.clone(), outbox.clone()).register(&mut table);
+            Guest::new(table)
+        };
+        let (mut a, mut b) = (guest("a"), guest("b"));
+        assert_eq!(a.call(HC_TIME, [0; 4]), Ok([1_700_000_000, 250, 0]));
+
+        // B also runs an agent, which hears of copies made by hypercall.
+        let mut hub = AgentHub::new();
+        let channel = ByteChannel::shared(4096);
+        hub.attach("b", channel.clone());
+        let hello = Hello { version: PROTOCOL_VERSION, services: SERVICE_CLIPBOARD, max_payload: 0 };
+        channel.lock().push_from_guest(&Frame::new(MSG_HELLO, 1, hello.encode()).encode());
+        hub.poll(&mut [&mut *broker.lock()]);
+        channel.lock().pop_to_guest(usize::MAX);
+
+        assert_eq!(b.call(HC_CLIPBOARD_GET, [0; 4]), Err(Empty));
+        a.memory.write_guest(0x1000, "héllo".as_bytes()).unwrap();
+        assert_eq!(a.call(HC_CLIPBOARD_SET, [0x1000, 6, 0, 0]), Ok([1, 0, 0]));
+        assert_eq!(broker.lock().current().unwrap().items, [ClipboardItem::Text(String::from("héllo"))]);
+        hub.deliver(core::mem::take(&mut *outbox.lock()));
+        let mut decoder = FrameDecoder::new(MAX_PAYLOAD);
+        decoder.push(&channel.lock().pop_to_guest(usize::MAX));
+        assert_eq!(decoder.next_frame().unwrap().unwrap().kind, MSG_CLIPBOARD_CHANGED);
+
+        assert_eq!(b.call(HC_CLIPBOARD_GET, [0x2000, 0, 0, 0]), Ok([6, 1, 0]));
+        assert_eq!(b.call(HC_CLIPBOARD_GET, [0x2000, 5, 0, 0]), Err(TooSmall));
+        assert_eq!(b.call(HC_CLIPBOARD_GET, [0x2000, 6, 0, 0]), Ok([6, 1, 0]));
+        let mut text = [0u8; 6];
+        b.memory.read_guest(0x2000, &mut text).unwrap();
+        assert_eq!(&text, "héllo".as_bytes());
+        assert_eq!(b.call(HC_CLIPBOARD_GET, [0x10_0000, 6, 0, 0]), Err(Invalid));
+
+        assert_eq!(b.call(HC_CLIPBOARD_SET, [0x1000, 6, 0, 0]), Err(Denied));
+        assert_eq!(a.call(HC_CLIPBOARD_SET, [0x1000, 65, 0, 0]), Err(TooLarge));
+        a.memory.write_guest(0x1000, &[0xFF]).unwrap();
+        assert_eq!(a.call(HC_CLIPBOARD_SET, [0x1000, 1, 0, 0]), Err(Invalid));
+        // Setting no text clears the clipboard.
+        assert_eq!(a.call(HC_CLIPBOARD_SET, [0x1000, 0, 0, 0]), Ok([2, 0, 0]));
+        assert_eq!(b.call(HC_CLIPBOARD_GET, [0; 4]), Err(Empty));
+    }
+
+    #[test]
+    fn test_volume_notify() {
+        let store = VolumeStore::shared(RamStorage::new(512, 16), 512, 16);
+        store.lock().create_volume("shared", 1 << 20).unwrap();
+        store.lock().set_access("shared", "a", Access::Write).unwrap();
+        store.lock().set_access("shared", "b", Access::Read).unwrap();
+        let guest = |vm: &str| {
+            let mut table = HypercallTable::new();
+            VolumeNotify::new(vm, store.clone()).register(&mut table);
+            let mut guest = Guest::new(table);
+            guest.memory.write_guest(0x1000, b"shared").unwrap();
+            guest
+        };
+        let (mut b, mut c) = (guest("b"), guest("c"));
+
+        let [generation, access, _] = b.call(HC_VOLUME_NOTIFY, [0x1000, 6, 0, 0]).unwrap();
+        assert_eq!(access, 1);
+        assert_eq!(c.call(HC_VOLUME_NOTIFY, [0x1000, 6, 0, 0]), Err(NotFound));
+        assert_eq!(b.call(HC_VOLUME_NOTIFY, [0x1000, 5, 0, 0]), Err(NotFound));
+        assert_eq!(b.call(HC_VOLUME_NOTIFY, [0x1000, 256, 0, 0]), Err(Invalid));
+
+        // A write from another VM moves the generation B sees.
+        let a = VolumeHandle::attach(&store, "shared", "a").unwrap();
+        a.create(ROOT_INO, "f", 0o644, 0, 0).unwrap();
+        assert_eq!(b.call(HC_VOLUME_NOTIFY, [0x1000, 6, 0, 0]), Ok([generation + 1, 1, 0]));
+    }
+}
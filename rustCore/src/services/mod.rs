@@ -0,0 +1,19 @@
+// Paravirtual services guests reach through their agent: the hub at the host
+// end of every agent channel and the services behind it. The wire protocol
+// is in the hypercore-guest crate, shared with the guests. Guests running
+// QEMU's guest agent instead are driven through the client in `qga`, and
+// guest kernels can reach some services by hypercall through `hypercall`.
+
+pub mod agent;
+pub mod clipboard;
+pub mod dnd;
+pub mod hypercall;
+pub mod qga;
+pub mod staging;
+
+pub use agent::{AgentHub, AgentService, Outbox};
+pub use clipboard::{ClipboardBroker, ClipboardEntry, ClipboardPolicy};
+pub use dnd::{DndBroker, DndPolicy, TransferProgress, TransferState};
+pub use hypercall::{ClipboardCalls, TimeSync, VolumeNotify};
+pub use qga::{QgaChannel, QgaClient, QgaError};
+pub use staging::{StagedId, StagingArea, StagingError};
@@ -0,0 +1,276 @@
+// Arguments and replies of the agent commands the client sends, as typed
+// values. Names and fields follow the agent's schema (qga/qapi-schema.json
+// in QEMU). File contents and process input and output travel base64
+// encoded; here they are bytes.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::json::Json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    Powerdown,
+    Halt,
+    Reboot,
+}
+
+impl ShutdownMode {
+    pub(super) fn as_str(self) -> &'static str {
+        match self {
+            ShutdownMode::Powerdown => "powerdown",
+            ShutdownMode::Halt => "halt",
+            ShutdownMode::Reboot => "reboot",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreezeStatus {
+    Thawed,
+    Frozen,
+}
+
+impl FreezeStatus {
+    pub(super) fn from_json(value: &Json) -> Option<Self> {
+        match value.as_str()? {
+            "thawed" => Some(FreezeStatus::Thawed),
+            "frozen" => Some(FreezeStatus::Frozen),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentInfo {
+    pub version: String,
+    /// Commands the agent knows, and whether each is enabled.
+    pub commands: Vec<(String, bool)>,
+}
+
+impl AgentInfo {
+    /// Whether the agent knows `command` and has it enabled.
+    pub fn supports(&self, command: &str) -> bool {
+        self.commands.iter().any(|(name, enabled)| name == command && *enabled)
+    }
+
+    pub(super) fn from_json(value: &Json) -> Option<Self> {
+        let mut commands = Vec::new();
+        for command in value.get("supported_commands")?.as_array()? {
+            let name = command.get("name")?.as_str()?;
+            commands.push((name.into(), command.get("enabled")?.as_bool()?));
+        }
+        Some(AgentInfo { version: value.get("version")?.as_str()?.into(), commands })
+    }
+}
+
+/// An open file in the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHandle(pub i64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRead {
+    pub data: Vec<u8>,
+    pub eof: bool,
+}
+
+impl FileRead {
+    pub(super) fn from_json(value: &Json) -> Option<Self> {
+        let data = base64_decode(value.get("buf-b64")?.as_str()?)?;
+        if value.get("count")?.as_u64()? != data.len() as u64 {
+            return None;
+        }
+        Some(FileRead { data, eof: value.get("eof")?.as_bool()? })
+    }
+}
+
+/// A program for the agent to start.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecRequest {
+    pub path: String,
+    pub args: Vec<String>,
+    /// `NAME=value` pairs, replacing the agent's environment if any given.
+    pub env: Vec<String>,
+    /// Written to the program's standard input, which is then closed.
+    pub input: Option<Vec<u8>>,
+    /// Keep standard output and error for [`ExecStatus`].
+    pub capture_output: bool,
+}
+
+impl ExecRequest {
+    pub fn new(path: &str) -> Self {
+        ExecRequest { path: path.into(), ..Default::default() }
+    }
+
+    pub fn with_arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn with_env(mut self, var: &str) -> Self {
+        self.env.push(var.into());
+        self
+    }
+
+    pub fn with_input(mut self, input: &[u8]) -> Self {
+        self.input = Some(input.to_vec());
+        self
+    }
+
+    pub fn with_output(mut self) -> Self {
+        self.capture_output = true;
+        self
+    }
+
+    pub(super) fn to_json(&self) -> Json {
+        let strings = |list: &[String]| Json::Array(list.iter().map(|s| Json::str(s)).collect());
+        let mut members = Vec::from([("path", Json::str(&self.path)), ("arg", strings(&self.args))]);
+        if !self.env.is_empty() {
+            members.push(("env", strings(&self.env)));
+        }
+        if let Some(input) = &self.input {
+            members.push(("input-data", Json::String(base64_encode(input))));
+        }
+        members.push(("capture-output", Json::Bool(self.capture_output)));
+        Json::object(members)
+    }
+}
+
+/// Where a started program is at.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecStatus {
+    pub exited: bool,
+    pub exit_code: Option<i64>,
+    /// The signal that killed it, on guests that have them.
+    pub signal: Option<i64>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Whether the agent dropped output past its limit.
+    pub truncated: bool,
+}
+
+impl ExecStatus {
+    pub(super) fn from_json(value: &Json) -> Option<Self> {
+        let output = |key| match value.get(key) {
+            Some(data) => base64_decode(data.as_str()?),
+            None => Some(Vec::new()),
+        };
+        let flag = |key| value.get(key).and_then(Json::as_bool).unwrap_or(false);
+        Some(ExecStatus {
+            exited: value.get("exited")?.as_bool()?,
+            exit_code: value.get("exitcode").and_then(Json::as_i64),
+            signal: value.get("signal").and_then(Json::as_i64),
+            stdout: output("out-data")?,
+            stderr: output("err-data")?,
+            truncated: flag("out-truncated") || flag("err-truncated"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpKind {
+    V4,
+    V6,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpAddress {
+    pub kind: IpKind,
+    pub address: String,
+    pub prefix: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub hardware_address: Option<String>,
+    pub addresses: Vec<IpAddress>,
+}
+
+impl NetworkInterface {
+    pub(super) fn from_json(value: &Json) -> Option<Self> {
+        let mut addresses = Vec::new();
+        for address in value.get("ip-addresses").and_then(Json::as_array).unwrap_or(&[]) {
+            let kind = match address.get("ip-address-type")?.as_str()? {
+                "ipv4" => IpKind::V4,
+                "ipv6" => IpKind::V6,
+                _ => return None,
+            };
+            let prefix = u8::try_from(address.get("prefix")?.as_u64()?).ok()?;
+            addresses.push(IpAddress { kind, address: address.get("ip-address")?.as_str()?.into(), prefix });
+        }
+        Some(NetworkInterface {
+            name: value.get("name")?.as_str()?.into(),
+            hardware_address: value.get("hardware-address").and_then(Json::as_str).map(String::from),
+            addresses,
+        })
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(super) fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    let value = match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    };
+    Some(value as u32)
+}
+
+/// Decode padded base64, as the agent writes it.
+pub(super) fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for (n, chunk) in text.chunks(4).enumerate() {
+        let last = n == text.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut bits = 0u32;
+        for &c in &chunk[..4 - padding] {
+            bits = bits << 6 | base64_value(c)?;
+        }
+        bits <<= 6 * padding as u32;
+        out.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        let cases = [(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"\xff\x00\xfe", "/wD+")];
+        for (data, text) in cases {
+            assert_eq!(base64_encode(data), text);
+            assert_eq!(base64_decode(text).as_deref(), Some(data));
+        }
+        for bad in ["Zg=", "Z===", "Zg==Zg==", "Zm9v!A==", "=Zm9"] {
+            assert_eq!(base64_decode(bad), None, "{}", bad);
+        }
+    }
+}
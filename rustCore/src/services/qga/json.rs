@@ -0,0 +1,455 @@
+// Just enough JSON for the guest agent's protocol: a value type, an encoder,
+// and a parser that reads one value off the front of a stream and can tell
+// a value that is cut short from one that is malformed.
+//
+// Numbers are kept as written and converted when read, so the guest's 64-bit
+// counters come through exactly. What the parser reads comes from the guest:
+// nesting is limited, and strings must be valid UTF-8.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Deepest nesting of arrays and objects the parser accepts.
+pub const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// A number as written, checked to be valid JSON.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// Members in the order written.
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonError {
+    /// The input ends before the value does.
+    Incomplete,
+    /// Not JSON, or nested too deeply, from the given byte offset.
+    Malformed(usize),
+}
+
+impl Json {
+    pub fn int(value: i64) -> Self {
+        Json::Number(value.to_string())
+    }
+
+    pub fn uint(value: u64) -> Self {
+        Json::Number(value.to_string())
+    }
+
+    pub fn str(value: &str) -> Self {
+        Json::String(value.into())
+    }
+
+    pub fn object<'a>(members: impl IntoIterator<Item = (&'a str, Json)>) -> Self {
+        Json::Object(members.into_iter().map(|(key, value)| (key.into(), value)).collect())
+    }
+
+    /// An object's member; the first, should the key be repeated.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Take an object's member out, leaving null in its place.
+    pub fn take(&mut self, key: &str) -> Option<Json> {
+        match self {
+            Json::Object(members) => {
+                members.iter_mut().find(|(name, _)| name == key).map(|(_, value)| core::mem::replace(value, Json::Null))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The number, if it is an integer that fits.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut String) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            Json::Number(text) => out.push_str(text),
+            Json::String(value) => encode_string(value, out),
+            Json::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.encode_into(out);
+                }
+                out.push(']');
+            }
+            Json::Object(members) => {
+                out.push('{');
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    encode_string(key, out);
+                    out.push(':');
+                    value.encode_into(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+
+    /// Parse a value that makes up the whole of `input`, but for whitespace.
+    pub fn parse(input: &[u8]) -> Result<Json, JsonError> {
+        let mut parser = Parser { input, pos: 0, whole: true };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        match parser.pos == input.len() {
+            true => Ok(value),
+            false => Err(JsonError::Malformed(parser.pos)),
+        }
+    }
+
+    /// Parse the value at the front of `input`, after any whitespace, and
+    /// say how many bytes it took.
+    pub fn parse_prefix(input: &[u8]) -> Result<(Json, usize), JsonError> {
+        let mut parser = Parser { input, pos: 0, whole: false };
+        let value = parser.value(0)?;
+        Ok((value, parser.pos))
+    }
+}
+
+fn encode_string(value: &str, out: &mut String) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    /// Whether the input is all there is, rather than a stream so far.
+    whole: bool,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Result<u8, JsonError> {
+        self.input.get(self.pos).copied().ok_or(self.end())
+    }
+
+    fn end(&self) -> JsonError {
+        match self.whole {
+            true => JsonError::Malformed(self.pos),
+            false => JsonError::Incomplete,
+        }
+    }
+
+    fn next(&mut self) -> Result<u8, JsonError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn malformed<T>(&self) -> Result<T, JsonError> {
+        Err(JsonError::Malformed(self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.input.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth >= MAX_DEPTH {
+            return self.malformed();
+        }
+        self.skip_whitespace();
+        match self.peek()? {
+            b'n' => self.literal(b"null", Json::Null),
+            b't' => self.literal(b"true", Json::Bool(true)),
+            b'f' => self.literal(b"false", Json::Bool(false)),
+            b'"' => Ok(Json::String(self.string()?)),
+            b'-' | b'0'..=b'9' => self.number(),
+            b'[' => self.array(depth),
+            b'{' => self.object(depth),
+            _ => self.malformed(),
+        }
+    }
+
+    fn literal(&mut self, word: &[u8], value: Json) -> Result<Json, JsonError> {
+        let rest = &self.input[self.pos..];
+        if rest.starts_with(word) {
+            self.pos += word.len();
+            Ok(value)
+        } else if word.starts_with(rest) {
+            Err(self.end())
+        } else {
+            self.malformed()
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.input.get(self.pos) == Some(&byte);
+        self.pos += found as usize;
+        found
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.input.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        self.eat(b'-');
+        let leading_zero = self.input.get(self.pos) == Some(&b'0');
+        let mut valid = match self.digits() {
+            0 => false,
+            n => n == 1 || !leading_zero,
+        };
+        if valid && self.eat(b'.') {
+            valid = self.digits() > 0;
+        }
+        if valid && (self.eat(b'e') || self.eat(b'E')) {
+            let _ = self.eat(b'+') || self.eat(b'-');
+            valid = self.digits() > 0;
+        }
+        // A number running to the end of a stream may have more to come.
+        if self.pos == self.input.len() && !self.whole {
+            return Err(JsonError::Incomplete);
+        }
+        if !valid {
+            return self.malformed();
+        }
+        // Only ASCII digits, signs and the like were taken.
+        Ok(Json::Number(core::str::from_utf8(&self.input[start..self.pos]).unwrap().into()))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = match self.next()? {
+                c @ b'0'..=b'9' => c - b'0',
+                c @ b'a'..=b'f' => c - b'a' + 10,
+                c @ b'A'..=b'F' => c - b'A' + 10,
+                _ => return self.malformed(),
+            };
+            value = value << 4 | digit as u32;
+        }
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1;
+        let start = self.pos;
+        let mut bytes = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => break,
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.escaped_char()?,
+                        _ => return self.malformed(),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                c if c < 0x20 => return self.malformed(),
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).or(Err(JsonError::Malformed(start)))
+    }
+
+    /// The character of a `\u` escape, a surrogate pair taking two.
+    fn escaped_char(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xd800..=0xdbff => {
+                if self.next()? != b'\\' || self.next()? != b'u' {
+                    return self.malformed();
+                }
+                match self.hex4()? {
+                    low @ 0xdc00..=0xdfff => 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00),
+                    _ => return self.malformed(),
+                }
+            }
+            code => code,
+        };
+        char::from_u32(code).map_or_else(|| self.malformed(), Ok)
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek()? == b']' {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {}
+                b']' => return Ok(Json::Array(items)),
+                _ => return Err(JsonError::Malformed(self.pos - 1)),
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.pos += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek()? == b'}' {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek()? != b'"' {
+                return self.malformed();
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if self.next()? != b':' {
+                return Err(JsonError::Malformed(self.pos - 1));
+            }
+            members.push((key, self.value(depth + 1)?));
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {}
+                b'}' => return Ok(Json::Object(members)),
+                _ => return Err(JsonError::Malformed(self.pos - 1)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_parse_and_encode() {
+        let text = br#" {"return": [{"name": "eth0", "rx": 18446744073709551615, "up": true},
+            {"name": "caf\u00e9 \ud83d\ude00\n", "ip": null, "load": -1.5e3}], "id": 0} "#;
+        let value = Json::parse(text).unwrap();
+        let interfaces = value.get("return").and_then(Json::as_array).unwrap();
+        assert_eq!(interfaces[0].get("rx").and_then(Json::as_u64), Some(u64::MAX));
+        assert_eq!(interfaces[0].get("up").and_then(Json::as_bool), Some(true));
+        assert_eq!(interfaces[1].get("name").and_then(Json::as_str), Some("café 😀\n"));
+        assert_eq!(interfaces[1].get("load"), Some(&Json::Number("-1.5e3".into())));
+        assert_eq!(interfaces[1].get("load").and_then(Json::as_i64), None);
+        assert_eq!(value.get("id").and_then(Json::as_i64), Some(0));
+
+        let request = Json::object([
+            ("execute", Json::str("guest-file-write")),
+            ("arguments", Json::object([("handle", Json::int(-3)), ("buf-b64", Json::str("a\"\\\u{1}"))])),
+            ("list", Json::Array(vec![Json::Null, Json::Bool(false), Json::Array(vec![])])),
+        ]);
+        let encoded = request.encode();
+        assert_eq!(
+            encoded,
+            r#"{"execute":"guest-file-write","arguments":{"handle":-3,"buf-b64":"a\"\\\u0001"},"list":[null,false,[]]}"#
+        );
+        assert_eq!(Json::parse(encoded.as_bytes()), Ok(request));
+    }
+
+    #[test]
+    fn test_incomplete_and_malformed() {
+        // Values cut short anywhere are incomplete, and the next one in a
+        // stream is left alone.
+        let text = br#"{"return": {"count": 12, "eof": false, "buf-b64": "aGk="}}"#;
+        for len in 0..text.len() {
+            assert_eq!(Json::parse_prefix(&text[..len]), Err(JsonError::Incomplete), "{} bytes", len);
+        }
+        let mut stream = text.to_vec();
+        stream.extend_from_slice(b"\n{\"ret");
+        let (value, used) = Json::parse_prefix(&stream).unwrap();
+        let count = value.get("return").and_then(|r| r.get("count")).and_then(Json::as_u64);
+        assert_eq!((used, count), (text.len(), Some(12)));
+        assert_eq!(Json::parse_prefix(b"  12"), Err(JsonError::Incomplete));
+        assert_eq!(Json::parse(b"  12"), Ok(Json::int(12)));
+        assert_eq!(Json::parse(b"[1, tr"), Err(JsonError::Malformed(4)));
+
+        assert_eq!(Json::parse(b"{\"a\" 1}"), Err(JsonError::Malformed(5)));
+        assert_eq!(Json::parse(b"[1,]"), Err(JsonError::Malformed(3)));
+        assert_eq!(Json::parse(b"{} x"), Err(JsonError::Malformed(3)));
+        assert_eq!(Json::parse(b"012"), Err(JsonError::Malformed(3)));
+        assert_eq!(Json::parse(b"nul "), Err(JsonError::Malformed(0)));
+        assert_eq!(Json::parse(b"\"\xff\""), Err(JsonError::Malformed(1)));
+        assert_eq!(Json::parse(b"\"\\ud800x\""), Err(JsonError::Malformed(8)));
+        assert_eq!(Json::parse(b"\"\t\""), Err(JsonError::Malformed(2)));
+        let deep = [vec![b'['; MAX_DEPTH + 1], vec![b']'; MAX_DEPTH + 1]].concat();
+        assert_eq!(Json::parse(&deep), Err(JsonError::Malformed(MAX_DEPTH)));
+        assert!(Json::parse(&deep[1..deep.len() - 1]).is_ok());
+    }
+}
@@ -0,0 +1,577 @@
+// A client for QEMU's guest agent (qemu-ga), for guests that run it rather
+// than our own agent. It lets the host shut a guest down cleanly, freeze its
+// filesystems around a snapshot, read and write its files, run programs in
+// it and ask after its network interfaces.
+//
+// The agent reads JSON commands and writes a JSON reply to each, over a
+// virtio-serial port or any other byte stream: a console device's channel,
+// with the guest running `qemu-ga -m isa-serial -p /dev/hvcN`, or a socket
+// QEMU connects to the port. Replies carry no id, so after a reply goes
+// missing the two ends are out of step. The client then syncs before its
+// next command: it sends a 0xFF byte, which makes the agent drop any
+// half-read command, and a guest-sync-delimited with a fresh id. The agent
+// puts 0xFF ahead of that reply, and everything before the 0xFF is left
+// over from before and skipped.
+
+pub mod commands;
+pub mod json;
+
+pub use commands::{
+    AgentInfo, ExecRequest, ExecStatus, FileHandle, FileRead, FreezeStatus, IpAddress, IpKind, NetworkInterface,
+    ShutdownMode,
+};
+pub use json::{Json, JsonError};
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::vdev::channel::SharedChannel;
+use commands::base64_encode;
+
+/// Time a command may take, in nanoseconds.
+pub const DEFAULT_TIMEOUT: u64 = 5_000_000_000;
+/// Largest reply accepted. File reads make the largest ones.
+pub const MAX_REPLY: usize = 4 << 20;
+/// Bytes asked for per read by [`QgaClient::read_file`].
+pub const FILE_CHUNK: usize = 1 << 20;
+
+/// Sent to reset the agent's parser, and sent back ahead of a sync's reply.
+const DELIMITER: u8 = 0xff;
+
+/// A byte stream to the agent.
+pub trait QgaChannel {
+    fn send(&mut self, data: &[u8]) -> Result<(), QgaError>;
+
+    /// Append what the agent has sent since the last call to `buf`. May wait
+    /// a moment for it, but not for long: the client keeps the time.
+    fn recv(&mut self, buf: &mut Vec<u8>) -> Result<(), QgaError>;
+}
+
+impl QgaChannel for SharedChannel {
+    fn send(&mut self, data: &[u8]) -> Result<(), QgaError> {
+        self.lock().send(data);
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut Vec<u8>) -> Result<(), QgaError> {
+        buf.extend(self.lock().take());
+        Ok(())
+    }
+}
+
+/// The class of an error the agent replied with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    GenericError,
+    CommandNotFound,
+    DeviceNotActive,
+    DeviceNotFound,
+    Other,
+}
+
+impl ErrorClass {
+    fn from_name(name: &str) -> Self {
+        match name {
+            "GenericError" => ErrorClass::GenericError,
+            "CommandNotFound" => ErrorClass::CommandNotFound,
+            "DeviceNotActive" => ErrorClass::DeviceNotActive,
+            "DeviceNotFound" => ErrorClass::DeviceNotFound,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QgaError {
+    /// The channel to the agent failed.
+    Channel,
+    /// No reply in time.
+    Timeout,
+    /// The agent sent something other than the reply expected.
+    Protocol(&'static str),
+    /// The command failed; [`QgaClient::last_error`] has the agent's reason.
+    Command(ErrorClass),
+}
+
+impl From<JsonError> for QgaError {
+    fn from(_: JsonError) -> Self {
+        QgaError::Protocol("malformed JSON")
+    }
+}
+
+pub struct QgaClient<C: QgaChannel> {
+    channel: C,
+    /// Monotonic time in nanoseconds.
+    clock: Box<dyn Fn() -> u64 + Send>,
+    timeout: u64,
+    /// Bytes from the agent not yet parsed.
+    input: Vec<u8>,
+    /// Whether the next reply from the agent is the one to the next command.
+    synced: bool,
+    syncs: u64,
+    last_error: Option<String>,
+}
+
+impl<C: QgaChannel> QgaClient<C> {
+    pub fn new(channel: C, clock: Box<dyn Fn() -> u64 + Send>) -> Self {
+        QgaClient {
+            channel,
+            clock,
+            timeout: DEFAULT_TIMEOUT,
+            input: Vec::new(),
+            synced: false,
+            syncs: 0,
+            last_error: None,
+        }
+    }
+
+    /// Wait `timeout` nanoseconds for each reply instead.
+    pub fn with_timeout(mut self, timeout: u64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn channel(&mut self) -> &mut C {
+        &mut self.channel
+    }
+
+    /// The agent's description of the last command's failure.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Get in step with the agent, skipping whatever it sent before. Done
+    /// before the first command and after any that went unanswered.
+    pub fn sync(&mut self) -> Result<(), QgaError> {
+        self.synced = false;
+        self.input.clear();
+        // Different from any earlier sync whose reply may still be on its way,
+        // including those of a client before this one.
+        self.syncs += 1;
+        let id = ((self.clock)() ^ self.syncs.wrapping_mul(0x9e37_79b9_7f4a_7c15)) >> 1;
+        self.channel.send(&[DELIMITER])?;
+        self.send_command("guest-sync-delimited", Some(Json::object([("id", Json::uint(id))])))?;
+        let deadline = (self.clock)() + self.timeout;
+        loop {
+            match self.input.iter().position(|&byte| byte == DELIMITER) {
+                Some(at) => drop(self.input.drain(..=at)),
+                None => {
+                    self.input.clear();
+                    self.fill(deadline)?;
+                    continue;
+                }
+            }
+            match self.next_reply(deadline) {
+                Ok(reply) if reply.get("return").and_then(Json::as_u64) == Some(id) => break,
+                // A stale sync's reply, or the agent complaining about the
+                // 0xFF: look for the next delimiter.
+                Ok(_) | Err(QgaError::Protocol(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.synced = true;
+        Ok(())
+    }
+
+    /// Run `command` and return what it returned.
+    pub fn execute(&mut self, command: &str, arguments: Option<Json>) -> Result<Json, QgaError> {
+        if !self.synced {
+            self.sync()?;
+        }
+        self.last_error = None;
+        self.send_command(command, arguments)?;
+        let deadline = (self.clock)() + self.timeout;
+        let mut reply = self.next_reply(deadline).inspect_err(|_| self.synced = false)?;
+        if let Some(value) = reply.take("return") {
+            return Ok(value);
+        }
+        let error = reply.get("error").ok_or(QgaError::Protocol("reply without return or error"))?;
+        self.last_error = error.get("desc").and_then(Json::as_str).map(String::from);
+        Err(QgaError::Command(ErrorClass::from_name(error.get("class").and_then(Json::as_str).unwrap_or(""))))
+    }
+
+    fn send_command(&mut self, command: &str, arguments: Option<Json>) -> Result<(), QgaError> {
+        let mut request = Json::object([("execute", Json::str(command))]);
+        if let (Json::Object(members), Some(arguments)) = (&mut request, arguments) {
+            members.push(("arguments".into(), arguments));
+        }
+        let mut text = request.encode();
+        text.push('\n');
+        self.channel.send(text.as_bytes())
+    }
+
+    /// Wait for more bytes from the agent.
+    fn fill(&mut self, deadline: u64) -> Result<(), QgaError> {
+        let had = self.input.len();
+        loop {
+            self.channel.recv(&mut self.input)?;
+            if self.input.len() > MAX_REPLY {
+                return Err(QgaError::Protocol("reply too large"));
+            }
+            if self.input.len() > had {
+                return Ok(());
+            }
+            if (self.clock)() >= deadline {
+                return Err(QgaError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn next_reply(&mut self, deadline: u64) -> Result<Json, QgaError> {
+        loop {
+            match Json::parse_prefix(&self.input) {
+                Ok((reply, used)) => {
+                    self.input.drain(..used);
+                    return Ok(reply);
+                }
+                Err(JsonError::Incomplete) => {}
+                Err(err) => return Err(err.into()),
+            }
+            // More of a long base64 string, such as a file's contents, cannot
+            // finish a reply: parse again only once something else comes.
+            loop {
+                let had = self.input.len();
+                self.fill(deadline)?;
+                if !self.input[had..].iter().all(|&c| c.is_ascii_alphanumeric() || matches!(c, b'+' | b'/' | b'=')) {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn expect<T>(value: Option<T>) -> Result<T, QgaError> {
+        value.ok_or(QgaError::Protocol("unexpected reply"))
+    }
+
+    pub fn ping(&mut self) -> Result<(), QgaError> {
+        self.execute("guest-ping", None).map(drop)
+    }
+
+    pub fn info(&mut self) -> Result<AgentInfo, QgaError> {
+        Self::expect(AgentInfo::from_json(&self.execute("guest-info", None)?))
+    }
+
+    /// Ask the guest to shut down. The agent does not answer if it goes
+    /// along, so this returns once the command is sent.
+    pub fn shutdown(&mut self, mode: ShutdownMode) -> Result<(), QgaError> {
+        if !self.synced {
+            self.sync()?;
+        }
+        self.send_command("guest-shutdown", Some(Json::object([("mode", Json::str(mode.as_str()))])))?;
+        self.synced = false;
+        Ok(())
+    }
+
+    pub fn fsfreeze_status(&mut self) -> Result<FreezeStatus, QgaError> {
+        Self::expect(FreezeStatus::from_json(&self.execute("guest-fsfreeze-status", None)?))
+    }
+
+    /// Flush and freeze the guest's filesystems, returning how many were
+    /// frozen. They stay frozen until thawed, so writes in the guest stall.
+    pub fn fsfreeze_freeze(&mut self) -> Result<u32, QgaError> {
+        let count = self.execute("guest-fsfreeze-freeze", None)?;
+        Self::expect(count.as_u64().and_then(|n| u32::try_from(n).ok()))
+    }
+
+    /// Thaw the guest's filesystems, returning how many were thawed.
+    pub fn fsfreeze_thaw(&mut self) -> Result<u32, QgaError> {
+        let count = self.execute("guest-fsfreeze-thaw", None)?;
+        Self::expect(count.as_u64().and_then(|n| u32::try_from(n).ok()))
+    }
+
+    /// Open a file in the guest with an fopen() mode such as "r" or "w".
+    pub fn file_open(&mut self, path: &str, mode: &str) -> Result<FileHandle, QgaError> {
+        let arguments = Json::object([("path", Json::str(path)), ("mode", Json::str(mode))]);
+        Self::expect(self.execute("guest-file-open", Some(arguments))?.as_i64().map(FileHandle))
+    }
+
+    pub fn file_read(&mut self, file: FileHandle, count: usize) -> Result<FileRead, QgaError> {
+        let arguments = Json::object([("handle", Json::int(file.0)), ("count", Json::uint(count as u64))]);
+        Self::expect(FileRead::from_json(&self.execute("guest-file-read", Some(arguments))?))
+    }
+
+    /// Write to the file, returning how many bytes the guest took.
+    pub fn file_write(&mut self, file: FileHandle, data: &[u8]) -> Result<usize, QgaError> {
+        let arguments = Json::object([("handle", Json::int(file.0)), ("buf-b64", Json::String(base64_encode(data)))]);
+        let reply = self.execute("guest-file-write", Some(arguments))?;
+        Self::expect(reply.get("count").and_then(Json::as_u64).map(|n| n as usize))
+    }
+
+    pub fn file_close(&mut self, file: FileHandle) -> Result<(), QgaError> {
+        self.execute("guest-file-close", Some(Json::object([("handle", Json::int(file.0))]))).map(drop)
+    }
+
+    /// The whole of a file in the guest.
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, QgaError> {
+        let file = self.file_open(path, "r")?;
+        let mut data = Vec::new();
+        let result = loop {
+            match self.file_read(file, FILE_CHUNK) {
+                Ok(read) => {
+                    data.extend_from_slice(&read.data);
+                    if read.eof || read.data.is_empty() {
+                        break Ok(());
+                    }
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        let closed = self.file_close(file);
+        result.and(closed).map(|()| data)
+    }
+
+    /// Create or replace a file in the guest.
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), QgaError> {
+        let file = self.file_open(path, "w")?;
+        let mut result = Ok(());
+        for chunk in data.chunks(FILE_CHUNK) {
+            result = match self.file_write(file, chunk) {
+                Ok(written) if written == chunk.len() => Ok(()),
+                Ok(_) => Err(QgaError::Protocol("short write")),
+                Err(err) => Err(err),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        let closed = self.file_close(file);
+        result.and(closed)
+    }
+
+    /// Start a program in the guest, returning its pid.
+    pub fn exec(&mut self, request: &ExecRequest) -> Result<i64, QgaError> {
+        let reply = self.execute("guest-exec", Some(request.to_json()))?;
+        Self::expect(reply.get("pid").and_then(Json::as_i64))
+    }
+
+    /// Poll a program started by [`exec`](Self::exec). Once it has exited,
+    /// the agent forgets it and the pid is no longer valid.
+    pub fn exec_status(&mut self, pid: i64) -> Result<ExecStatus, QgaError> {
+        let reply = self.execute("guest-exec-status", Some(Json::object([("pid", Json::int(pid))])))?;
+        Self::expect(ExecStatus::from_json(&reply))
+    }
+
+    pub fn network_interfaces(&mut self) -> Result<Vec<NetworkInterface>, QgaError> {
+        let reply = self.execute("guest-network-get-interfaces", None)?;
+        Self::expect(reply.as_array().and_then(|list| list.iter().map(NetworkInterface::from_json).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use alloc::format;
+    use alloc::string::ToString;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::vdev::channel::ByteChannel;
+
+    /// A clock that moves a millisecond each time it is read.
+    fn clock() -> Box<dyn Fn() -> u64 + Send> {
+        let now = Arc::new(AtomicU64::new(0));
+        Box::new(move || now.fetch_add(1_000_000, Ordering::Relaxed))
+    }
+
+    /// Answers as the agent would, from canned replies to each command.
+    /// Commands without one go unanswered.
+    struct MockAgent {
+        replies: Vec<(&'static str, String)>,
+        /// Sent ahead of the next reply, as left over from earlier.
+        stale: Vec<u8>,
+        commands: Vec<Json>,
+        files: Vec<(i64, Vec<u8>)>,
+        pending: Vec<u8>,
+        output: Vec<u8>,
+    }
+
+    impl MockAgent {
+        fn new(replies: &[(&'static str, &str)]) -> Self {
+            MockAgent {
+                replies: replies.iter().map(|&(command, reply)| (command, reply.to_string())).collect(),
+                stale: Vec::new(),
+                commands: Vec::new(),
+                files: Vec::new(),
+                pending: Vec::new(),
+                output: Vec::new(),
+            }
+        }
+
+        fn names(&self) -> Vec<&str> {
+            self.commands.iter().map(|c| c.get("execute").and_then(Json::as_str).unwrap()).collect()
+        }
+
+        fn answer(&mut self, command: Json) {
+            let name = command.get("execute").and_then(Json::as_str).unwrap().to_string();
+            let arguments = command.get("arguments").cloned().unwrap_or(Json::Null);
+            self.commands.push(command);
+            self.output.append(&mut self.stale);
+            let reply = match name.as_str() {
+                "guest-sync-delimited" => {
+                    self.output.push(DELIMITER);
+                    format!("{{\"return\": {}}}", arguments.get("id").and_then(Json::as_u64).unwrap())
+                }
+                "guest-file-write" => {
+                    let data = commands::base64_decode(arguments.get("buf-b64").unwrap().as_str().unwrap()).unwrap();
+                    let handle = arguments.get("handle").and_then(Json::as_i64).unwrap();
+                    self.files.push((handle, data.clone()));
+                    format!("{{\"return\": {{\"count\": {}, \"eof\": false}}}}", data.len())
+                }
+                _ => match self.replies.iter().position(|(command, _)| *command == name) {
+                    Some(at) => self.replies.remove(at).1,
+                    None => return,
+                },
+            };
+            self.output.extend_from_slice(reply.as_bytes());
+            self.output.extend_from_slice(b"\r\n");
+        }
+    }
+
+    impl QgaChannel for MockAgent {
+        fn send(&mut self, data: &[u8]) -> Result<(), QgaError> {
+            self.pending.extend_from_slice(data);
+            loop {
+                // The agent's parser drops what it was reading on a 0xFF.
+                if let Some(at) = self.pending.iter().rposition(|&byte| byte == DELIMITER) {
+                    self.pending.drain(..=at);
+                }
+                match Json::parse_prefix(&self.pending) {
+                    Ok((command, used)) => {
+                        self.pending.drain(..used);
+                        self.answer(command);
+                    }
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+
+        fn recv(&mut self, buf: &mut Vec<u8>) -> Result<(), QgaError> {
+            // Replies arrive in pieces.
+            let len = self.output.len().div_ceil(3).min(4096);
+            buf.extend(self.output.drain(..len));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut agent = MockAgent::new(&[
+            ("guest-ping", "{\"return\": {}}"),
+            ("guest-fsfreeze-freeze", "{\"return\": 2}"),
+            ("guest-fsfreeze-status", "{\"return\": \"frozen\"}"),
+            ("guest-fsfreeze-thaw", r#"{"error": {"class": "GenericError", "desc": "not frozen"}}"#),
+            (
+                "guest-info",
+                r#"{"return": {"version": "8.2.0", "supported_commands": [
+                    {"name": "guest-exec", "enabled": true, "success-response": true},
+                    {"name": "guest-file-open", "enabled": false, "success-response": true}]}}"#,
+            ),
+            ("guest-exec", "{\"return\": {\"pid\": 812}}"),
+            (
+                "guest-exec-status",
+                r#"{"return": {"exited": true, "exitcode": 3, "out-data": "aGVsbG8K", "err-truncated": true}}"#,
+            ),
+            (
+                "guest-network-get-interfaces",
+                r#"{"return": [{"name": "lo", "ip-addresses": [
+                    {"ip-address-type": "ipv4", "ip-address": "127.0.0.1", "prefix": 8}]},
+                    {"name": "eth0", "hardware-address": "52:54:00:12:34:56", "ip-addresses": [
+                    {"ip-address-type": "ipv6", "ip-address": "fe80::1", "prefix": 64}],
+                    "statistics": {"rx-bytes": 18446744073709551615}}]}"#,
+            ),
+        ]);
+        // A reply meant for an earlier client comes first.
+        agent.stale = b"\xff{\"return\": 17}\n{\"return\": {}}".to_vec();
+        let mut client = QgaClient::new(agent, clock());
+
+        client.ping().unwrap();
+        assert_eq!(client.channel().names(), ["guest-sync-delimited", "guest-ping"]);
+        assert_eq!(client.fsfreeze_freeze(), Ok(2));
+        assert_eq!(client.fsfreeze_status(), Ok(FreezeStatus::Frozen));
+        assert_eq!(client.fsfreeze_thaw(), Err(QgaError::Command(ErrorClass::GenericError)));
+        assert_eq!(client.last_error(), Some("not frozen"));
+
+        let info = client.info().unwrap();
+        assert_eq!(info.version, "8.2.0");
+        assert!(info.supports("guest-exec") && !info.supports("guest-file-open") && !info.supports("guest-ping"));
+
+        let request = ExecRequest::new("/bin/sh").with_arg("-c").with_arg("echo hello; exit 3").with_output();
+        assert_eq!(client.exec(&request), Ok(812));
+        let sent = client.channel().commands.last().unwrap().get("arguments").unwrap().encode();
+        assert_eq!(sent, r#"{"path":"/bin/sh","arg":["-c","echo hello; exit 3"],"capture-output":true}"#);
+        let status = client.exec_status(812).unwrap();
+        assert_eq!((status.exited, status.exit_code, status.signal), (true, Some(3), None));
+        assert_eq!((&status.stdout[..], &status.stderr[..], status.truncated), (&b"hello\n"[..], &b""[..], true));
+
+        let interfaces = client.network_interfaces().unwrap();
+        assert_eq!(interfaces[1].name, "eth0");
+        assert_eq!(interfaces[1].hardware_address.as_deref(), Some("52:54:00:12:34:56"));
+        assert_eq!(interfaces[1].addresses, [IpAddress { kind: IpKind::V6, address: "fe80::1".into(), prefix: 64 }]);
+        assert_eq!(interfaces[0].addresses[0].prefix, 8);
+
+        client.shutdown(ShutdownMode::Powerdown).unwrap();
+        let shutdown = client.channel().commands.last().unwrap().encode();
+        assert_eq!(shutdown, r#"{"execute":"guest-shutdown","arguments":{"mode":"powerdown"}}"#);
+        assert_eq!(client.channel().names().iter().filter(|&&n| n == "guest-sync-delimited").count(), 1);
+    }
+
+    #[test]
+    fn test_timeout_and_files() {
+        let data: Vec<u8> = (0..FILE_CHUNK + 100).map(|i| i as u8).collect();
+        let read = |data: &[u8], eof| {
+            let text = commands::base64_encode(data);
+            format!(r#"{{"return": {{"count": {}, "buf-b64": "{}", "eof": {}}}}}"#, data.len(), text, eof)
+        };
+        let agent = MockAgent::new(&[
+            ("guest-file-open", "{\"return\": 1000}"),
+            ("guest-file-read", &read(&data[..FILE_CHUNK], false)),
+            ("guest-file-read", &read(&data[FILE_CHUNK..], true)),
+            ("guest-file-close", "{\"return\": {}}"),
+            ("guest-file-open", "{\"return\": 1001}"),
+            ("guest-file-close", "{\"return\": {}}"),
+        ]);
+        let mut client = QgaClient::new(agent, clock()).with_timeout(50_000_000);
+
+        // Unanswered, and answered late: the client syncs again and skips
+        // the late reply.
+        assert_eq!(client.fsfreeze_freeze(), Err(QgaError::Timeout));
+        client.channel().output.extend_from_slice(b"{\"return\": 1}\n");
+        assert_eq!(client.read_file("/etc/hostname"), Ok(data.clone()));
+        assert_eq!(client.channel().names().iter().filter(|&&n| n == "guest-sync-delimited").count(), 2);
+
+        client.write_file("/tmp/out", &data).unwrap();
+        let written: Vec<(i64, usize)> = client.channel().files.iter().map(|(h, d)| (*h, d.len())).collect();
+        assert_eq!(written, [(1001, FILE_CHUNK), (1001, 100)]);
+
+        // Nonsense from the agent is an error, and the next command syncs.
+        client.channel().replies.push(("guest-ping", "{\"return\": {}]".into()));
+        assert_eq!(client.ping(), Err(QgaError::Protocol("malformed JSON")));
+        client.channel().replies.push(("guest-ping", "{\"return\": {}}".into()));
+        assert_eq!(client.ping(), Ok(()));
+        assert_eq!(client.channel().names().iter().filter(|&&n| n == "guest-sync-delimited").count(), 3);
+    }
+
+    #[test]
+    fn test_shared_channel() {
+        let channel = ByteChannel::shared(1024);
+        let guest = channel.clone();
+        let agent = std::thread::spawn(move || {
+            let mut agent = MockAgent::new(&[("guest-ping", "{\"return\": {}}")]);
+            while !agent.names().contains(&"guest-shutdown") {
+                let request = guest.lock().pop_to_guest(usize::MAX);
+                agent.send(&request).unwrap();
+                guest.lock().push_from_guest(&core::mem::take(&mut agent.output));
+                std::thread::yield_now();
+            }
+        });
+        let start = std::time::Instant::now();
+        let mut client = QgaClient::new(channel, Box::new(move || start.elapsed().as_nanos() as u64));
+        client.ping().unwrap();
+        client.shutdown(ShutdownMode::Halt).unwrap();
+        agent.join().unwrap();
+    }
+}
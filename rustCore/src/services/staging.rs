@@ -0,0 +1,152 @@
+// The common storage space where data passing between VMs is staged, such
+// as files dragged from one VM's window to another's. It is a set of
+// scratch files on a `StorageBackend`; files only grow by appending and
+// are freed whole, so blocks are handed out from a free list and a file's
+// blocks need not be contiguous.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::storage::StorageBackend;
+
+pub type StagedId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StagingError {
+    NoSpace,
+    NotFound,
+    /// The backend failed a block read or write.
+    Io,
+}
+
+struct StagedFile {
+    blocks: Vec<u64>,
+    len: u64,
+}
+
+pub struct StagingArea<S: StorageBackend> {
+    storage: S,
+    block_size: usize,
+    /// Free blocks, lowest last.
+    free: Vec<u64>,
+    files: BTreeMap<StagedId, StagedFile>,
+    next_id: StagedId,
+}
+
+impl<S: StorageBackend> StagingArea<S> {
+    /// Stage files in the first `blocks` blocks of `storage`.
+    pub fn new(storage: S, block_size: usize, blocks: u64) -> Self {
+        StagingArea { storage, block_size, free: (0..blocks).rev().collect(), files: BTreeMap::new(), next_id: 1 }
+    }
+
+    /// Blocks needed to stage `len` bytes.
+    pub fn blocks_for(&self, len: u64) -> u64 {
+        len.div_ceil(self.block_size as u64)
+    }
+
+    pub fn free_blocks(&self) -> u64 {
+        self.free.len() as u64
+    }
+
+    /// Start an empty file.
+    pub fn create(&mut self) -> StagedId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.files.insert(id, StagedFile { blocks: Vec::new(), len: 0 });
+        id
+    }
+
+    pub fn len(&self, id: StagedId) -> Option<u64> {
+        self.files.get(&id).map(|file| file.len)
+    }
+
+    pub fn append(&mut self, id: StagedId, mut data: &[u8]) -> Result<(), StagingError> {
+        let block_size = self.block_size;
+        let file = self.files.get_mut(&id).ok_or(StagingError::NotFound)?;
+        let needed = (file.len + data.len() as u64).div_ceil(block_size as u64) - file.blocks.len() as u64;
+        if needed > self.free.len() as u64 {
+            return Err(StagingError::NoSpace);
+        }
+        let mut block = vec![0u8; block_size];
+        while !data.is_empty() {
+            let start = (file.len % block_size as u64) as usize;
+            let index = if start == 0 {
+                file.blocks.push(self.free.pop().unwrap());
+                block.fill(0);
+                file.blocks.len() - 1
+            } else {
+                let index = file.blocks.len() - 1;
+                self.storage.read_block(file.blocks[index], &mut block).map_err(|_| StagingError::Io)?;
+                index
+            };
+            let count = core::cmp::min(block_size - start, data.len());
+            block[start..start + count].copy_from_slice(&data[..count]);
+            self.storage.write_block(file.blocks[index], &block).map_err(|_| StagingError::Io)?;
+            file.len += count as u64;
+            data = &data[count..];
+        }
+        Ok(())
+    }
+
+    /// Read from `offset` into `buf`, returning how many bytes there were.
+    pub fn read(&self, id: StagedId, offset: u64, buf: &mut [u8]) -> Result<usize, StagingError> {
+        let file = self.files.get(&id).ok_or(StagingError::NotFound)?;
+        let total = core::cmp::min(buf.len() as u64, file.len.saturating_sub(offset)) as usize;
+        let mut block = vec![0u8; self.block_size];
+        let mut done = 0;
+        while done < total {
+            let position = offset + done as u64;
+            let index = (position / self.block_size as u64) as usize;
+            let start = (position % self.block_size as u64) as usize;
+            self.storage.read_block(file.blocks[index], &mut block).map_err(|_| StagingError::Io)?;
+            let count = core::cmp::min(self.block_size - start, total - done);
+            buf[done..done + count].copy_from_slice(&block[start..start + count]);
+            done += count;
+        }
+        Ok(total)
+    }
+
+    /// Delete a file, freeing its blocks.
+    pub fn remove(&mut self, id: StagedId) {
+        if let Some(file) = self.files.remove(&id) {
+            self.free.extend(file.blocks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::RamStorage;
+
+    #[test]
+    fn test_append_read_and_free() {
+        let mut staging = StagingArea::new(RamStorage::new(8, 4), 8, 4);
+        let a = staging.create();
+        let b = staging.create();
+        // Interleaved appends leave the two files' blocks mixed up.
+        staging.append(a, b"hello").unwrap();
+        staging.append(b, b"0123456789").unwrap();
+        staging.append(a, b", world").unwrap();
+        assert_eq!((staging.len(a), staging.len(b), staging.free_blocks()), (Some(12), Some(10), 0));
+        assert_eq!(staging.append(a, b"!"), Ok(()));
+        assert_eq!(staging.append(a, b"more"), Err(StagingError::NoSpace));
+
+        let mut buf = [0u8; 16];
+        assert_eq!(staging.read(a, 0, &mut buf), Ok(13));
+        assert_eq!(&buf[..13], b"hello, world!");
+        assert_eq!(staging.read(b, 6, &mut buf[..2]), Ok(2));
+        assert_eq!(&buf[..2], b"67");
+        assert_eq!(staging.read(b, 10, &mut buf), Ok(0));
+
+        staging.remove(a);
+        assert_eq!(staging.free_blocks(), 2);
+        assert_eq!(staging.read(a, 0, &mut buf), Err(StagingError::NotFound));
+        let c = staging.create();
+        staging.append(c, &[7; 16]).unwrap();
+        assert_eq!(staging.read(b, 0, &mut buf), Ok(10));
+        assert_eq!(&buf[..10], b"0123456789");
+        assert_eq!(staging.blocks_for(17), 3);
+    }
+}
@@ -1,128 +1,102 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::boxed::Box;
 use core::cell::RefCell;
-use std::process::Command;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::{Child, Command};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Clone)]
-struct VMSnapshot {
-    name: String,
-    // In a real system, this would include memory, disk, CPU state, etc.
-    // For now, just a stub.
-}
+use crate::hypervisor::{SnapshotError, SnapshotInfo, MAX_VCPUS};
+use crate::manager::{vmx_backend, AttachedVolume, ManagerError, VmManager, VmRecord};
+use crate::services::qga::{QgaChannel, QgaClient, QgaError, ShutdownMode};
+use crate::storage::volume::{Access, SharedVolumeStore, VolumeError, VolumeHandle, VolumeStore};
+use crate::storage::{BlockStorage, RamDisk, StorageBackend};
+use crate::vdev::console::SharedConsole;
+use crate::vdev::virtio::p9::P9Server;
+use crate::vmx::backend::VmxBackend;
 
-#[derive(Clone)]
-struct VM {
-    name: String,
-    ram: usize,
-    cpus: usize,
-    disk_image: String, // Path to Ceph RBD image or other disk
-    iso_path: Option<String>, // Optional path to Ubuntu ISO
-    snapshots: Vec<VMSnapshot>,
-}
+/// Where shared volumes are kept, until they get a disk of their own.
+type VolumeStorage = BlockStorage<RamDisk>;
+const VOLUME_BLOCK_SIZE: usize = 4096;
+/// 64 MiB for all volumes together.
+const VOLUME_BLOCKS: u64 = 16384;
 
-struct VMManager {
-    vms: RefCell<Vec<VM>>,
-}
+/// Name of the virtio-serial port qemu-ga looks for.
+const QGA_PORT: &str = "org.qemu.guest_agent.0";
 
-impl VMManager {
-    const fn new() -> Self {
-        VMManager { vms: RefCell::new(Vec::new()) }
-    }
+/// A file holding a VM's snapshots of vCPU state and RAM, see
+/// `hypervisor::snapshot`. Disks are not part of them.
+struct SnapshotFile(File);
 
-    fn list_vms(&self) {
-        let vms = self.vms.borrow();
-        if vms.is_empty() {
-            println!("No VMs found.");
-        } else {
-            for vm in vms.iter() {
-                println!("VM: {} (RAM: {}MB, CPUs: {})", vm.name, vm.ram, vm.cpus);
-                if !vm.snapshots.is_empty() {
-                    println!("  Snapshots: {}", vm.snapshots.iter().map(|s| s.name.as_str()).collect::<Vec<_>>().join(", "));
-                }
-            }
-        }
+impl StorageBackend for SnapshotFile {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) -> Result<(), ()> {
+        self.0.read_exact_at(buf, block_id * buf.len() as u64).map_err(|_| ())
     }
 
-    fn create_vm(&self, name: &str, ram: usize, cpus: usize, disk_image: &str, iso_path: Option<&str>) {
-        let mut vms = self.vms.borrow_mut();
-        if vms.iter().any(|vm| vm.name == name) {
-            println!("VM '{}' already exists.", name);
-            return;
-        }
-        vms.push(VM {
-            name: String::from(name),
-            ram,
-            cpus,
-            disk_image: disk_image.to_string(),
-            iso_path: iso_path.map(|s| s.to_string()),
-            snapshots: Vec::new(),
-        });
-        println!("Created VM '{}'.", name);
-        // TODO: Persist to storage
+    fn write_block(&self, block_id: u64, buf: &[u8]) -> Result<(), ()> {
+        self.0.write_all_at(buf, block_id * buf.len() as u64).map_err(|_| ())
     }
+}
 
-    fn update_vm(&self, name: &str, ram: Option<usize>, cpus: Option<usize>) {
-        let mut vms = self.vms.borrow_mut();
-        if let Some(vm) = vms.iter_mut().find(|vm| vm.name == name) {
-            if let Some(ram) = ram { vm.ram = ram; }
-            if let Some(cpus) = cpus { vm.cpus = cpus; }
-            println!("Updated VM '{}'.", name);
-            // TODO: Persist to storage
-        } else {
-            println!("VM '{}' not found.", name);
-        }
-    }
+fn snapshot_names(snapshots: &[SnapshotInfo]) -> String {
+    snapshots.iter().map(|s| s.name.as_str()).collect::<Vec<_>>().join(", ")
+}
 
-    fn delete_vm(&self, name: &str) {
-        let mut vms = self.vms.borrow_mut();
-        let len_before = vms.len();
-        vms.retain(|vm| vm.name != name);
-        if vms.len() < len_before {
-            println!("Deleted VM '{}'.", name);
-            // TODO: Persist to storage
-        } else {
-            println!("VM '{}' not found.", name);
-        }
-    }
+/// Where a VM's snapshots are kept.
+fn snapshot_file(vm: &str) -> String {
+    format!("/tmp/hypercore-{}.snapshots", vm)
+}
 
-    fn snapshot_vm(&self, name: &str, snapshot: &str) {
-        let mut vms = self.vms.borrow_mut();
-        if let Some(vm) = vms.iter_mut().find(|vm| vm.name == name) {
-            if vm.snapshots.iter().any(|s| s.name == snapshot) {
-                println!("Snapshot '{}' already exists for VM '{}'.", snapshot, name);
-                return;
-            }
-            vm.snapshots.push(VMSnapshot { name: String::from(snapshot) });
-            println!("Snapshot '{}' created for VM '{}'.", snapshot, name);
-            // TODO: Persist to storage
-        } else {
-            println!("VM '{}' not found.", name);
+/// A new, empty snapshot file for the VM.
+fn create_snapshot_file(vm: &str) -> Result<SnapshotFile, SnapshotError> {
+    match OpenOptions::new().read(true).write(true).create(true).truncate(true).open(snapshot_file(vm)) {
+        Ok(file) => Ok(SnapshotFile(file)),
+        Err(e) => {
+            println!("Could not create the snapshot file of VM '{}': {}", vm, e);
+            Err(SnapshotError::Storage)
         }
     }
+}
 
-    fn restore_vm(&self, name: &str, snapshot: &str) {
-        let vms = self.vms.borrow();
-        if let Some(vm) = vms.iter().find(|vm| vm.name == name) {
-            if vm.snapshots.iter().any(|s| s.name == snapshot) {
-                println!("Restored VM '{}' from snapshot '{}'. (stub)", name, snapshot);
-                // TODO: Restore VM state from snapshot (real implementation)
-            } else {
-                println!("Snapshot '{}' not found for VM '{}'.", snapshot, name);
-            }
-        } else {
-            println!("VM '{}' not found.", name);
+/// Tell the user why a command on VM `name` failed.
+fn report(name: &str, err: ManagerError) {
+    match err {
+        ManagerError::NoSuchVm => println!("VM '{}' not found.", name),
+        ManagerError::VmExists => println!("VM '{}' already exists.", name),
+        ManagerError::BadCpuCount(_) => println!("A VM needs between 1 and {} CPUs.", MAX_VCPUS),
+        ManagerError::AlreadyRunning => println!("VM '{}' is already running.", name),
+        ManagerError::NotRunning => println!("VM '{}' is not running natively.", name),
+        ManagerError::NoMemory => println!("Not enough memory is set aside for native VMs."),
+        ManagerError::Kernel(e) => println!("Could not load the kernel of VM '{}': {:?}", name, e),
+        ManagerError::Hv(e) => println!("VM '{}' failed: {:?}", name, e),
+        ManagerError::NoSuchSnapshot => println!("Snapshot not found for VM '{}'.", name),
+        ManagerError::SnapshotExists => println!("Snapshot already exists for VM '{}'.", name),
+        ManagerError::Snapshot(e) => println!("Snapshots of VM '{}' failed: {:?}", name, e),
+        ManagerError::BadQuota(_) => println!("A volume needs a quota of at least 1 MB."),
+        ManagerError::Volume(e) => println!("Volumes of VM '{}' failed: {:?}", name, e),
+        ManagerError::VolumesNeedQemu => {
+            println!("VM '{}' has volumes, which native VMs cannot reach yet; boot it without --kernel.", name)
         }
     }
+}
 
-    // TODO: Implement persistence for VM state (save/load to storage)
-    // fn save_to_storage(&self) { /* ... */ }
-    // fn load_from_storage(&mut self) { /* ... */ }
-    // Call these in create_vm, update_vm, delete_vm, etc.
+/// An empty store for the volumes.
+fn volume_store() -> SharedVolumeStore<VolumeStorage> {
+    let disk = vec![0u8; VOLUME_BLOCK_SIZE * VOLUME_BLOCKS as usize].leak();
+    let storage = BlockStorage::new(RamDisk::new(disk, VOLUME_BLOCK_SIZE), VOLUME_BLOCK_SIZE);
+    VolumeStore::shared(storage, VOLUME_BLOCK_SIZE, VOLUME_BLOCKS)
 }
 
 thread_local! {
-    static VM_MANAGER: VMManager = VMManager::new();
+    static VM_MANAGER: RefCell<VmManager<VmxBackend, SnapshotFile, VolumeStorage>> =
+        RefCell::new(VmManager::new(volume_store()));
+    /// The QEMU process of each VM booted under QEMU.
+    static QEMU: RefCell<BTreeMap<String, Child>> = RefCell::new(BTreeMap::new());
 }
 
 enum Command<'a> {
@@ -132,10 +106,18 @@ enum Command<'a> {
     DeleteVM { name: &'a str },
     SnapshotVM { name: &'a str, snapshot: &'a str },
     RestoreVM { name: &'a str, snapshot: &'a str },
-    BootVM { name: &'a str },
+    BootVM { name: &'a str, kernel: Option<&'a str> },
     StopVM { name: &'a str },
     ListSnapshots { name: &'a str },
+    Console { name: &'a str, clear: bool },
+    ListVolumes,
+    CreateVolume { name: &'a str, quota_mb: u64 },
+    DeleteVolume { name: &'a str },
+    VolumeAddVM { volume: &'a str, vm: &'a str, access: Access },
+    VolumeRemoveVM { volume: &'a str, vm: &'a str },
     Help,
+    /// A known command with arguments that make no sense, and why.
+    Invalid(&'static str),
     Unknown,
 }
 
@@ -175,82 +157,465 @@ fn parse_command(input: &str) -> Command {
         ["delete-vm", name] => Command::DeleteVM { name },
         ["snapshot-vm", name, snapshot] => Command::SnapshotVM { name, snapshot },
         ["restore-vm", name, snapshot] => Command::RestoreVM { name, snapshot },
-        ["boot-vm", name] => Command::BootVM { name },
+        ["boot-vm", name] => Command::BootVM { name, kernel: None },
+        ["boot-vm", name, "--kernel", kernel] => Command::BootVM { name, kernel: Some(*kernel) },
         ["stop-vm", name] => Command::StopVM { name },
         ["list-snapshots", name] => Command::ListSnapshots { name },
+        ["console", name] => Command::Console { name, clear: false },
+        ["console", name, "--clear"] => Command::Console { name, clear: true },
+        ["list-volumes"] => Command::ListVolumes,
+        ["create-volume", name, quota_mb] => match quota_mb.parse() {
+            Ok(quota_mb) => Command::CreateVolume { name, quota_mb },
+            Err(_) => Command::Invalid("The quota must be a whole number of MB."),
+        },
+        ["delete-volume", name] => Command::DeleteVolume { name },
+        ["volume-add-vm", volume, vm] => Command::VolumeAddVM { volume, vm, access: Access::Write },
+        ["volume-add-vm", volume, vm, "--read-only"] => Command::VolumeAddVM { volume, vm, access: Access::Read },
+        ["volume-remove-vm", volume, vm] => Command::VolumeRemoveVM { volume, vm },
         ["help"] => Command::Help,
         _ => Command::Unknown,
     }
 }
 
 fn list_vms() {
-    VM_MANAGER.with(|mgr| mgr.list_vms());
+    VM_MANAGER.with(|mgr| {
+        let mgr = mgr.borrow();
+        if mgr.vms().is_empty() {
+            println!("No VMs found.");
+        }
+        for vm in mgr.vms() {
+            println!("VM: {} (RAM: {}MB, CPUs: {})", vm.name(), vm.ram_mb(), vm.cpus());
+            if let Ok(snapshots) = vm.snapshots() {
+                if !snapshots.is_empty() {
+                    println!("  Snapshots: {}", snapshot_names(&snapshots));
+                }
+            }
+        }
+    });
 }
 
 fn create_vm(name: &str, ram: usize, cpus: usize, disk_image: &str, iso_path: Option<&str>) {
-    VM_MANAGER.with(|mgr| mgr.create_vm(name, ram, cpus, disk_image, iso_path));
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().create_vm(name, ram, cpus, disk_image, iso_path)) {
+        Ok(()) => println!("Created VM '{}'.", name),
+        Err(e) => report(name, e),
+    }
 }
 
 fn update_vm(name: &str, ram: Option<usize>, cpus: Option<usize>) {
-    VM_MANAGER.with(|mgr| mgr.update_vm(name, ram, cpus));
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().update_vm(name, ram, cpus)) {
+        Ok(()) => println!("Updated VM '{}'.", name),
+        Err(e) => report(name, e),
+    }
 }
 
 fn delete_vm(name: &str) {
-    VM_MANAGER.with(|mgr| mgr.delete_vm(name));
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().delete_vm(name)) {
+        Ok(()) => {
+            let _ = std::fs::remove_file(snapshot_file(name));
+            println!("Deleted VM '{}'.", name);
+        }
+        Err(e) => report(name, e),
+    }
 }
 
+/// Snapshot a VM, with its filesystems frozen if it runs the guest agent so
+/// what they hold is consistent.
 fn snapshot_vm(name: &str, snapshot: &str) {
-    VM_MANAGER.with(|mgr| mgr.snapshot_vm(name, snapshot));
+    let mut agent = connect_agent(name);
+    if let Some(client) = agent.as_mut() {
+        match client.fsfreeze_freeze() {
+            Ok(count) => println!("Froze {} filesystems in VM '{}'.", count, name),
+            Err(e) => {
+                println!("Could not freeze the filesystems of VM '{}': {}", name, describe(client, e));
+                agent = None;
+            }
+        }
+    }
+    take_snapshot(name, snapshot);
+    if let Some(mut client) = agent {
+        if let Err(e) = client.fsfreeze_thaw() {
+            println!("Could not thaw the filesystems of VM '{}': {}", name, describe(&client, e));
+        }
+    }
+}
+
+fn take_snapshot(name: &str, snapshot: &str) {
+    let storage = || create_snapshot_file(name);
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().take_snapshot(name, snapshot, storage)) {
+        Ok(_) => println!("Snapshot '{}' created for VM '{}'.", snapshot, name),
+        Err(ManagerError::SnapshotExists) => println!("Snapshot '{}' already exists for VM '{}'.", snapshot, name),
+        Err(ManagerError::NotRunning) => {
+            println!("VM '{}' is not running natively; only native VMs can be snapshotted.", name)
+        }
+        Err(ManagerError::Snapshot(e)) => println!("Could not snapshot VM '{}': {:?}", name, e),
+        Err(e) => report(name, e),
+    }
 }
 
 fn restore_vm(name: &str, snapshot: &str) {
-    VM_MANAGER.with(|mgr| mgr.restore_vm(name, snapshot));
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().restore_snapshot(name, snapshot)) {
+        Ok(()) => println!("Restored VM '{}' from snapshot '{}'.", name, snapshot),
+        Err(ManagerError::NoSuchSnapshot) => println!("Snapshot '{}' not found for VM '{}'.", snapshot, name),
+        Err(ManagerError::NotRunning) => {
+            println!("VM '{}' is not running natively; boot it with --kernel first.", name)
+        }
+        Err(ManagerError::Snapshot(e)) => {
+            println!("Could not restore VM '{}' from snapshot '{}': {:?}", name, snapshot, e)
+        }
+        Err(e) => report(name, e),
+    }
 }
 
-fn boot_vm(name: &str) {
+/// Boot a VM under QEMU, or with `kernel` on Hypercore's own hypervisor.
+fn boot_vm(name: &str, kernel: Option<&str>) {
+    if qemu_running(name) {
+        return report(name, ManagerError::AlreadyRunning);
+    }
+    if let Some(kernel) = kernel {
+        boot_native(name, kernel);
+        return;
+    }
     VM_MANAGER.with(|mgr| {
-        let vms = mgr.vms.borrow();
-        if let Some(vm) = vms.iter().find(|vm| vm.name == name) {
-            let mut cmd = Command::new("qemu-system-x86_64");
-            cmd.arg("-enable-kvm")
-                .arg("-m").arg(vm.ram.to_string())
-                .arg("-smp").arg(vm.cpus.to_string())
-                .arg("-drive").arg(format!("file={},if=virtio,format=raw", vm.disk_image));
-            if let Some(iso) = &vm.iso_path {
-                cmd.arg("-cdrom").arg(iso)
-                    .arg("-boot").arg("d");
+        let mgr = mgr.borrow();
+        let vm = match mgr.vm(name) {
+            Ok(vm) if vm.is_running() => return report(name, ManagerError::AlreadyRunning),
+            Ok(vm) => vm,
+            Err(e) => return report(name, e),
+        };
+        let mut cmd = Command::new("qemu-system-x86_64");
+        cmd.arg("-enable-kvm")
+            .arg("-m").arg(vm.ram_mb().to_string())
+            .arg("-smp").arg(vm.cpus().to_string())
+            .arg("-drive").arg(format!("file={},if=virtio,format=raw", vm.disk_image()));
+        if let Some(iso) = vm.iso_path() {
+            cmd.arg("-cdrom").arg(iso)
+                .arg("-boot").arg("d");
+        }
+        let _ = std::fs::remove_file(serial_socket(name));
+        let serial = match UnixListener::bind(serial_socket(name)) {
+            Ok(listener) => {
+                cmd.arg("-chardev").arg(format!("socket,path={},id=serial0", serial_socket(name)))
+                    .arg("-serial").arg("chardev:serial0");
+                Some(listener)
+            }
+            Err(e) => {
+                println!("COM1 of VM '{}' will not be captured: {}", name, e);
+                None
+            }
+        };
+        let _ = std::fs::remove_file(agent_socket(name));
+        cmd.arg("-chardev").arg(format!("socket,path={},server=on,wait=off,id=qga0", agent_socket(name)))
+            .arg("-device").arg("virtio-serial")
+            .arg("-device").arg(format!("virtserialport,chardev=qga0,name={}", QGA_PORT));
+        let mut volumes = Vec::new();
+        let attached = mgr.attach_volumes(name).unwrap_or_default();
+        for (i, volume) in attached_volumes(name, attached).into_iter().enumerate() {
+            let (path, port) = (volume_socket(name, volume.volume()), volume_port(volume.volume()));
+            let _ = std::fs::remove_file(&path);
+            match UnixListener::bind(&path) {
+                Ok(listener) => {
+                    cmd.arg("-chardev").arg(format!("socket,path={},id=vol{}", path, i))
+                        .arg("-device").arg(format!("virtserialport,chardev=vol{},name={}", i, port));
+                    volumes.push((listener, volume));
+                }
+                Err(e) => println!("Volume '{}' will not be shared with VM '{}': {}", volume.volume(), name, e),
             }
-            println!("Launching QEMU for VM '{}'...", name);
-            match cmd.spawn() {
-                Ok(_child) => println!("VM '{}' started.", name),
-                Err(e) => println!("Failed to start VM '{}': {}", name, e),
+        }
+        println!("Launching QEMU for VM '{}'...", name);
+        match cmd.spawn() {
+            Ok(child) => {
+                QEMU.with(|qemu| qemu.borrow_mut().insert(String::from(name), child));
+                if let Some(listener) = serial {
+                    pipe_serial(listener, vm.console().clone());
+                }
+                for (listener, volume) in volumes {
+                    let volume_name = volume.volume();
+                    println!("Mount volume '{}' in the guest with: {}", volume_name, mount_command(volume_name));
+                    serve_volume(listener, volume);
+                }
+                println!("VM '{}' started.", name);
             }
-        } else {
-            println!("VM '{}' not found.", name);
+            Err(e) => println!("Failed to start VM '{}': {}", name, e),
         }
     });
 }
 
+/// Boot a VM straight into the Linux kernel at `kernel`. Its vCPUs run
+/// from the shell loop.
+fn boot_native(name: &str, kernel: &str) {
+    let image = match std::fs::read(kernel) {
+        Ok(image) => image,
+        Err(e) => {
+            println!("Could not read kernel '{}': {}", kernel, e);
+            return;
+        }
+    };
+    let start = Instant::now();
+    let clock = Box::new(move || start.elapsed().as_nanos() as u64);
+    let backend = |vm: &VmRecord<VmxBackend, SnapshotFile>| vmx_backend(vm.ram_mb(), vm.cpus());
+    VM_MANAGER.with(|mgr| {
+        let mut mgr = mgr.borrow_mut();
+        match mgr.boot_native(name, &image, backend, clock) {
+            Ok(()) => println!("VM '{}' started natively with {} vCPUs.", name, mgr.vm(name).unwrap().cpus()),
+            Err(e) => report(name, e),
+        }
+    });
+}
+
+fn run_native_vms() {
+    for stopped in VM_MANAGER.with(|mgr| mgr.borrow_mut().run_native_vms()) {
+        match stopped.cause {
+            Ok(exit) => println!("VM '{}' stopped: vCPU {} exited with {:?}.", stopped.vm, stopped.vcpu, exit),
+            Err(e) => println!("VM '{}' stopped: vCPU {} failed: {:?}", stopped.vm, stopped.vcpu, e),
+        }
+    }
+}
+
+/// Whether the VM's QEMU process is still running.
+fn qemu_running(name: &str) -> bool {
+    QEMU.with(|qemu| qemu.borrow_mut().get_mut(name).is_some_and(|child| matches!(child.try_wait(), Ok(None))))
+}
+
+/// Stop a native VM at once, or ask a QEMU guest to power down through its
+/// agent. A QEMU guest without an agent is killed along with QEMU.
 fn stop_vm(name: &str) {
-    // TODO: Implement real VM stop logic
-    println!("Stopping VM '{}' (stub)", name);
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().stop_native(name)) {
+        Ok(()) => {
+            println!("Stopped VM '{}'.", name);
+            return;
+        }
+        Err(ManagerError::NotRunning) => {}
+        Err(e) => return report(name, e),
+    }
+    match connect_agent(name) {
+        Some(mut client) => match client.shutdown(ShutdownMode::Powerdown) {
+            Ok(()) => println!("Asked VM '{}' to power down.", name),
+            Err(e) => println!("Could not ask VM '{}' to power down: {}", name, describe(&client, e)),
+        },
+        None => match QEMU.with(|qemu| qemu.borrow_mut().remove(name)) {
+            Some(mut child) if matches!(child.try_wait(), Ok(None)) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                println!("VM '{}' has no guest agent to ask to power down; stopped QEMU.", name);
+            }
+            _ => println!("VM '{}' is not running.", name),
+        },
+    }
 }
 
-fn list_snapshots(name: &str) {
+/// Where QEMU listens for a VM's guest agent connection.
+fn agent_socket(vm: &str) -> String {
+    format!("/tmp/hypercore-{}.qga", vm)
+}
+
+/// Where QEMU connects a VM's COM1.
+fn serial_socket(vm: &str) -> String {
+    format!("/tmp/hypercore-{}.serial", vm)
+}
+
+/// Copy what QEMU's COM1 transmits into `console` until QEMU hangs up.
+fn pipe_serial(listener: UnixListener, console: SharedConsole) {
+    std::thread::spawn(move || {
+        let Ok((mut stream, _)) = listener.accept() else { return };
+        let mut chunk = [0u8; 512];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => console.lock().write(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// The QEMU end of a VM's guest agent port.
+struct AgentSocket(UnixStream);
+
+impl QgaChannel for AgentSocket {
+    fn send(&mut self, data: &[u8]) -> Result<(), QgaError> {
+        self.0.write_all(data).map_err(|_| QgaError::Channel)
+    }
+
+    fn recv(&mut self, buf: &mut Vec<u8>) -> Result<(), QgaError> {
+        let mut chunk = [0u8; 4096];
+        match self.0.read(&mut chunk) {
+            Ok(0) => Err(QgaError::Channel),
+            Ok(len) => {
+                buf.extend_from_slice(&chunk[..len]);
+                Ok(())
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(()),
+            Err(_) => Err(QgaError::Channel),
+        }
+    }
+}
+
+/// A client for the guest agent of a running VM, if it answers.
+fn connect_agent(vm: &str) -> Option<QgaClient<AgentSocket>> {
+    let stream = UnixStream::connect(agent_socket(vm)).ok()?;
+    stream.set_read_timeout(Some(Duration::from_millis(10))).ok()?;
+    let start = Instant::now();
+    let mut client = QgaClient::new(AgentSocket(stream), Box::new(move || start.elapsed().as_nanos() as u64));
+    client.sync().ok()?;
+    Some(client)
+}
+
+fn describe(client: &QgaClient<AgentSocket>, err: QgaError) -> String {
+    match client.last_error() {
+        Some(reason) => String::from(reason),
+        None => format!("{:?}", err),
+    }
+}
+
+/// Where QEMU connects the guest's port for `volume`.
+fn volume_socket(vm: &str, volume: &str) -> String {
+    format!("/tmp/hypercore-{}.{}.9p", vm, volume)
+}
+
+/// Name of the virtio-serial port `volume` is served on.
+fn volume_port(volume: &str) -> String {
+    format!("org.hypercore.volume.{}", volume)
+}
+
+/// How a Linux guest mounts `volume` from its port.
+fn mount_command(volume: &str) -> String {
+    format!(
+        "mount -t 9p -o trans=fd,rfdno=3,wfdno=3,version=9p2000.L {} <dir> 3<>/dev/virtio-ports/{}",
+        volume,
+        volume_port(volume)
+    )
+}
+
+/// Serve `volume` over 9P2000.L to the first connection on `listener`,
+/// which is QEMU relaying the guest's virtio-serial port, until it hangs up.
+fn serve_volume(listener: UnixListener, volume: VolumeHandle<VolumeStorage>) {
+    std::thread::spawn(move || {
+        let Ok((mut stream, _)) = listener.accept() else { return };
+        let clock = || SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let mut server = P9Server::new(volume).with_clock(Box::new(clock));
+        loop {
+            let mut size = [0u8; 4];
+            if stream.read_exact(&mut size).is_err() {
+                break;
+            }
+            // Messages start with their own size, which may not exceed the
+            // agreed one.
+            let size = u32::from_le_bytes(size);
+            if size < 4 || size > server.msize() {
+                break;
+            }
+            let mut request = vec![0u8; size as usize];
+            request[..4].copy_from_slice(&size.to_le_bytes());
+            if stream.read_exact(&mut request[4..]).is_err() {
+                break;
+            }
+            if let Some(reply) = server.handle(&request) {
+                if stream.write_all(&reply).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// The volumes a booting VM was attached to, after telling the user which
+/// those are and which could not be attached.
+fn attached_volumes(vm: &str, attached: Vec<AttachedVolume<VolumeStorage>>) -> Vec<VolumeHandle<VolumeStorage>> {
+    let mut handles = Vec::new();
+    for (volume, handle) in attached {
+        match handle {
+            Ok(handle) => {
+                let mode = if handle.access() == Access::Write { "read-write" } else { "read-only" };
+                println!("Attached volume '{}' to VM '{}' ({}).", volume, vm, mode);
+                handles.push(handle);
+            }
+            Err(e) => println!("Could not attach volume '{}' to VM '{}': {:?}", volume, vm, e),
+        }
+    }
+    handles
+}
+
+fn list_volumes() {
     VM_MANAGER.with(|mgr| {
-        let vms = mgr.vms.borrow();
-        if let Some(vm) = vms.iter().find(|vm| vm.name == name) {
-            if vm.snapshots.is_empty() {
-                println!("No snapshots for VM '{}'.", name);
-            } else {
-                println!("Snapshots for VM '{}': {}", name, vm.snapshots.iter().map(|s| s.name.as_str()).collect::<Vec<_>>().join(", "));
+        let mgr = mgr.borrow();
+        let store = mgr.volumes().lock();
+        let volumes = store.volumes();
+        if volumes.is_empty() {
+            println!("No volumes found.");
+        }
+        for volume in volumes {
+            println!("Volume: {} ({} of {} KB used)", volume.name, volume.used / 1024, volume.quota / 1024);
+            for (vm, access) in volume.acl {
+                println!("  {}: {}", vm, if access == Access::Write { "read-write" } else { "read-only" });
             }
-        } else {
-            println!("VM '{}' not found.", name);
         }
+        println!("{} KB free for volumes.", store.free_bytes() / 1024);
     });
 }
 
+fn create_volume(name: &str, quota_mb: u64) {
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().create_volume(name, quota_mb)) {
+        Ok(()) => println!("Created volume '{}' with a {} MB quota.", name, quota_mb),
+        Err(ManagerError::BadQuota(0)) => println!("A volume needs a quota of at least 1 MB."),
+        Err(ManagerError::BadQuota(_)) => println!("A quota of {} MB is too large.", quota_mb),
+        Err(ManagerError::Volume(VolumeError::Exists)) => println!("Volume '{}' already exists.", name),
+        Err(e) => println!("Could not create volume '{}': {:?}", name, e),
+    }
+}
+
+fn delete_volume(name: &str) {
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().delete_volume(name)) {
+        Ok(()) => println!("Deleted volume '{}'.", name),
+        Err(_) => println!("Volume '{}' not found.", name),
+    }
+}
+
+/// Give a VM access to a volume. Running VMs see a new volume the next
+/// time they boot, but a change of access applies at once.
+fn volume_add_vm(volume: &str, vm: &str, access: Access) {
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().set_volume_access(volume, vm, access)) {
+        Ok(()) => {
+            let verb = if access == Access::Write { "write to" } else { "read" };
+            println!("VM '{}' can now {} volume '{}'.", vm, verb, volume);
+        }
+        Err(ManagerError::NoSuchVm) => println!("VM '{}' not found.", vm),
+        Err(_) => println!("Volume '{}' not found.", volume),
+    }
+}
+
+/// Take a VM's access to a volume away, at once.
+fn volume_remove_vm(volume: &str, vm: &str) {
+    match VM_MANAGER.with(|mgr| mgr.borrow_mut().set_volume_access(volume, vm, Access::None)) {
+        Ok(()) => println!("VM '{}' removed from volume '{}'.", vm, volume),
+        Err(_) => println!("Volume '{}' not found.", volume),
+    }
+}
+
+fn list_snapshots(name: &str) {
+    match VM_MANAGER.with(|mgr| mgr.borrow().vm(name).and_then(|vm| vm.snapshots())) {
+        Ok(snapshots) if snapshots.is_empty() => println!("No snapshots for VM '{}'.", name),
+        Ok(snapshots) => println!("Snapshots for VM '{}': {}", name, snapshot_names(&snapshots)),
+        Err(ManagerError::Snapshot(e)) => println!("Snapshots of VM '{}' are unreadable: {:?}", name, e),
+        Err(e) => report(name, e),
+    }
+}
+
+fn show_console(name: &str, clear: bool) {
+    let console = match VM_MANAGER.with(|mgr| mgr.borrow().vm(name).map(|vm| vm.console().clone())) {
+        Ok(console) => console,
+        Err(e) => return report(name, e),
+    };
+    let mut console = console.lock();
+    if console.dropped() > 0 {
+        println!("[{} earlier bytes dropped]", console.dropped());
+    }
+    print!("{}", console.text());
+    if clear {
+        console.take_output();
+    }
+}
+
 fn print_help() {
     println!("Available commands:");
     println!("  list-vms");
@@ -259,9 +624,15 @@ fn print_help() {
     println!("  delete-vm <name>");
     println!("  snapshot-vm <name> <snapshot>");
     println!("  restore-vm <name> <snapshot>");
-    println!("  boot-vm <name>");
+    println!("  boot-vm <name> [--kernel <bzImage>]");
     println!("  stop-vm <name>");
     println!("  list-snapshots <name>");
+    println!("  console <name> [--clear]");
+    println!("  list-volumes");
+    println!("  create-volume <name> <quota_mb>");
+    println!("  delete-volume <name>");
+    println!("  volume-add-vm <volume> <vm> [--read-only]");
+    println!("  volume-remove-vm <volume> <vm>");
     println!("  help");
 }
 
@@ -273,6 +644,7 @@ fn read_line() -> String {
 
 pub fn shell_main() {
     loop {
+        run_native_vms();
         let line = read_line();
         match parse_command(&line) {
             Command::ListVMs => list_vms(),
@@ -281,10 +653,17 @@ pub fn shell_main() {
             Command::DeleteVM { name } => delete_vm(name),
             Command::SnapshotVM { name, snapshot } => snapshot_vm(name, snapshot),
             Command::RestoreVM { name, snapshot } => restore_vm(name, snapshot),
-            Command::BootVM { name } => boot_vm(name),
+            Command::BootVM { name, kernel } => boot_vm(name, kernel),
             Command::StopVM { name } => stop_vm(name),
             Command::ListSnapshots { name } => list_snapshots(name),
+            Command::Console { name, clear } => show_console(name, clear),
+            Command::ListVolumes => list_volumes(),
+            Command::CreateVolume { name, quota_mb } => create_volume(name, quota_mb),
+            Command::DeleteVolume { name } => delete_volume(name),
+            Command::VolumeAddVM { volume, vm, access } => volume_add_vm(volume, vm, access),
+            Command::VolumeRemoveVM { volume, vm } => volume_remove_vm(volume, vm),
             Command::Help => print_help(),
+            Command::Invalid(reason) => println!("{}", reason),
             Command::Unknown => println!("Unknown command"),
         }
     }
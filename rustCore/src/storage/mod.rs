@@ -1,8 +1,10 @@
 pub mod block_devices;
 pub mod ramdisk;
+pub mod volume;
 
 pub use block_devices::BlockDevice;
 pub use ramdisk::RamDisk;
+pub use volume::{Access, SharedVolumeStore, VolumeError, VolumeHandle, VolumeStore};
 
 pub trait StorageBackend {
     fn read_block(&self, block_id: u64, buf: &mut [u8]) -> Result<(), ()>;
@@ -29,3 +31,63 @@ impl<B: BlockDevice> StorageBackend for BlockStorage<B> {
         self.device.write_sector(block_id, buf)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::{Cell, Ref, RefCell};
+
+    /// Blocks of `block_size` bytes in memory. Block `bad` fails reads and
+    /// writes, and every write fails while writes are failed.
+    pub(crate) struct RamStorage {
+        data: RefCell<Vec<u8>>,
+        block_size: usize,
+        bad: Cell<Option<u64>>,
+        fail_writes: Cell<bool>,
+    }
+
+    impl RamStorage {
+        pub(crate) fn new(block_size: usize, blocks: usize) -> Self {
+            Self::from_bytes(block_size, vec![0; block_size * blocks])
+        }
+
+        /// Storage holding `data`, which is whole blocks.
+        pub(crate) fn from_bytes(block_size: usize, data: Vec<u8>) -> Self {
+            RamStorage { data: RefCell::new(data), block_size, bad: Cell::new(None), fail_writes: Cell::new(false) }
+        }
+
+        pub(crate) fn data(&self) -> Ref<'_, Vec<u8>> {
+            self.data.borrow()
+        }
+
+        pub(crate) fn set_bad_block(&self, block: Option<u64>) {
+            self.bad.set(block);
+        }
+
+        pub(crate) fn fail_writes(&self, fail: bool) {
+            self.fail_writes.set(fail);
+        }
+    }
+
+    impl StorageBackend for RamStorage {
+        fn read_block(&self, block_id: u64, buf: &mut [u8]) -> Result<(), ()> {
+            if Some(block_id) == self.bad.get() {
+                return Err(());
+            }
+            let start = block_id as usize * self.block_size;
+            buf.copy_from_slice(self.data.borrow().get(start..start + self.block_size).ok_or(())?);
+            Ok(())
+        }
+
+        fn write_block(&self, block_id: u64, buf: &[u8]) -> Result<(), ()> {
+            if Some(block_id) == self.bad.get() || self.fail_writes.get() {
+                return Err(());
+            }
+            let start = block_id as usize * self.block_size;
+            self.data.borrow_mut().get_mut(start..start + self.block_size).ok_or(())?.copy_from_slice(buf);
+            Ok(())
+        }
+    }
+}
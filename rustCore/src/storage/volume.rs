@@ -0,0 +1,776 @@
+// Shared volumes: the common storage space every VM can be given a view of.
+//
+// A volume is a named tree of files and directories whose contents live on a
+// `StorageBackend`; all volumes share the backend's blocks, handed out from
+// one free list. Each volume has a quota on the blocks it may hold and a
+// list of the VMs that may read it or write to it. A VM is attached to the
+// volumes it may see when it boots, and reaches them through a
+// `VolumeHandle`, which checks its access on every call so that taking a VM
+// off a volume applies at once.
+//
+// Only file contents are kept on the backend; the tree itself is in memory.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::StorageBackend;
+
+/// Inode number of a node within its volume.
+pub type Ino = u64;
+/// Every volume's root directory.
+pub const ROOT_INO: Ino = 1;
+/// Longest name of a file or directory.
+pub const MAX_NAME_LEN: usize = 255;
+
+pub type SharedVolumeStore<S> = Arc<Mutex<VolumeStore<S>>>;
+
+/// What a VM may do with a volume. VMs not on a volume's list have `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    None,
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeError {
+    /// No such volume, file or directory.
+    NotFound,
+    Exists,
+    /// The VM's access to the volume does not allow it.
+    Denied,
+    /// The volume holds as much as its quota allows.
+    QuotaExceeded,
+    /// The backend has no free blocks left.
+    NoSpace,
+    NotADirectory,
+    IsADirectory,
+    NotEmpty,
+    /// A bad name, or a directory moved into itself.
+    Invalid,
+    /// The backend failed a block read or write.
+    Io,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+}
+
+/// A file's or directory's attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attr {
+    pub ino: Ino,
+    pub kind: NodeKind,
+    /// Permission bits, as in `st_mode` without the file type.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// Backend blocks holding the contents; files may have holes.
+    pub blocks: u64,
+    pub nlink: u32,
+    /// Seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+/// Attributes to change; `None` leaves one as it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SetAttr {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Truncate or extend a file.
+    pub size: Option<u64>,
+    pub mtime: Option<u64>,
+}
+
+/// A volume as listed to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeInfo {
+    pub name: String,
+    /// Bytes the volume may hold.
+    pub quota: u64,
+    /// Bytes it holds, in whole blocks.
+    pub used: u64,
+    pub acl: Vec<(String, Access)>,
+}
+
+struct Node {
+    kind: NodeKind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u64,
+    parent: Ino,
+    /// A file's blocks by index in the file; missing ones are holes that
+    /// read as zeros.
+    blocks: BTreeMap<u64, u64>,
+    children: BTreeMap<String, Ino>,
+}
+
+impl Node {
+    fn new(kind: NodeKind, parent: Ino, mode: u32, uid: u32, gid: u32) -> Self {
+        Node { kind, mode, uid, gid, size: 0, mtime: 0, parent, blocks: BTreeMap::new(), children: BTreeMap::new() }
+    }
+
+    fn allocated(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    /// Copy `data` into the file at `offset`, taking blocks from the free
+    /// list for holes. `added` counts the blocks taken, even if the backend
+    /// fails part way.
+    fn write<S: StorageBackend>(
+        &mut self,
+        blocks: &mut Blocks<S>,
+        offset: u64,
+        data: &[u8],
+        added: &mut u64,
+    ) -> Result<(), VolumeError> {
+        let block_size = blocks.block_size as u64;
+        let mut block = vec![0u8; blocks.block_size];
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let index = position / block_size;
+            let start = (position % block_size) as usize;
+            let count = core::cmp::min(blocks.block_size - start, data.len() - done);
+            let id = match self.blocks.get(&index).copied() {
+                Some(id) => {
+                    if count < blocks.block_size {
+                        blocks.read(id, &mut block)?;
+                    }
+                    id
+                }
+                None => {
+                    let id = blocks.free.pop().unwrap();
+                    self.blocks.insert(index, id);
+                    *added += 1;
+                    block.fill(0);
+                    id
+                }
+            };
+            block[start..start + count].copy_from_slice(&data[done..done + count]);
+            blocks.write(id, &block)?;
+            done += count;
+            self.size = core::cmp::max(self.size, position + count as u64);
+        }
+        Ok(())
+    }
+}
+
+/// The backend and its free blocks, shared by all volumes.
+struct Blocks<S: StorageBackend> {
+    storage: S,
+    block_size: usize,
+    /// Lowest last.
+    free: Vec<u64>,
+}
+
+impl<S: StorageBackend> Blocks<S> {
+    fn read(&self, block: u64, buf: &mut [u8]) -> Result<(), VolumeError> {
+        self.storage.read_block(block, buf).map_err(|_| VolumeError::Io)
+    }
+
+    fn write(&self, block: u64, buf: &[u8]) -> Result<(), VolumeError> {
+        self.storage.write_block(block, buf).map_err(|_| VolumeError::Io)
+    }
+}
+
+struct Volume {
+    quota: u64,
+    /// Blocks held by the volume's files.
+    used: u64,
+    acl: BTreeMap<String, Access>,
+    nodes: BTreeMap<Ino, Node>,
+    next_ino: Ino,
+    /// Counts changes to the volume, its quota and its access list.
+    generation: u64,
+}
+
+fn check_name(name: &str) -> Result<(), VolumeError> {
+    if matches!(name, "" | "." | "..") || name.len() > MAX_NAME_LEN || name.contains(['/', '\0']) {
+        return Err(VolumeError::Invalid);
+    }
+    Ok(())
+}
+
+impl Volume {
+    fn new(quota: u64) -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_INO, Node::new(NodeKind::Directory, ROOT_INO, 0o777, 0, 0));
+        Volume { quota, used: 0, acl: BTreeMap::new(), nodes, next_ino: ROOT_INO + 1, generation: 1 }
+    }
+
+    fn node(&self, ino: Ino) -> Result<&Node, VolumeError> {
+        self.nodes.get(&ino).ok_or(VolumeError::NotFound)
+    }
+
+    fn node_mut(&mut self, ino: Ino) -> Result<&mut Node, VolumeError> {
+        self.nodes.get_mut(&ino).ok_or(VolumeError::NotFound)
+    }
+
+    fn dir(&self, ino: Ino) -> Result<&Node, VolumeError> {
+        match self.node(ino)? {
+            node if node.kind == NodeKind::Directory => Ok(node),
+            _ => Err(VolumeError::NotADirectory),
+        }
+    }
+
+    fn file_mut(&mut self, ino: Ino) -> Result<&mut Node, VolumeError> {
+        match self.node_mut(ino)? {
+            node if node.kind == NodeKind::File => Ok(node),
+            _ => Err(VolumeError::IsADirectory),
+        }
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, VolumeError> {
+        self.dir(dir)?.children.get(name).copied().ok_or(VolumeError::NotFound)
+    }
+
+    fn getattr(&self, ino: Ino) -> Result<Attr, VolumeError> {
+        let node = self.node(ino)?;
+        let nlink = match node.kind {
+            NodeKind::File => 1,
+            NodeKind::Directory => {
+                let subdirs = node.children.values().filter(|child| self.nodes[child].kind == NodeKind::Directory);
+                2 + subdirs.count() as u32
+            }
+        };
+        let blocks = node.allocated();
+        let Node { kind, mode, uid, gid, size, mtime, .. } = *node;
+        Ok(Attr { ino, kind, mode, uid, gid, size, blocks, nlink, mtime })
+    }
+
+    fn create(&mut self, dir: Ino, name: &str, kind: NodeKind, attr: (u32, u32, u32)) -> Result<Ino, VolumeError> {
+        check_name(name)?;
+        if self.dir(dir)?.children.contains_key(name) {
+            return Err(VolumeError::Exists);
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let (mode, uid, gid) = attr;
+        self.nodes.insert(ino, Node::new(kind, dir, mode, uid, gid));
+        self.node_mut(dir)?.children.insert(String::from(name), ino);
+        Ok(ino)
+    }
+
+    fn read<S: StorageBackend>(
+        &self,
+        blocks: &Blocks<S>,
+        ino: Ino,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, VolumeError> {
+        let node = self.node(ino)?;
+        if node.kind != NodeKind::File {
+            return Err(VolumeError::IsADirectory);
+        }
+        let block_size = blocks.block_size as u64;
+        let total = core::cmp::min(buf.len() as u64, node.size.saturating_sub(offset)) as usize;
+        let mut block = vec![0u8; blocks.block_size];
+        let mut done = 0;
+        while done < total {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let count = core::cmp::min(blocks.block_size - start, total - done);
+            match node.blocks.get(&(position / block_size)).copied() {
+                Some(id) => {
+                    blocks.read(id, &mut block)?;
+                    buf[done..done + count].copy_from_slice(&block[start..start + count]);
+                }
+                None => buf[done..done + count].fill(0),
+            }
+            done += count;
+        }
+        Ok(total)
+    }
+
+    fn write<S: StorageBackend>(
+        &mut self,
+        blocks: &mut Blocks<S>,
+        ino: Ino,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, VolumeError> {
+        let block_size = blocks.block_size as u64;
+        let (quota, used) = (self.quota, self.used);
+        let node = self.file_mut(ino)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(data.len() as u64).ok_or(VolumeError::Invalid)?;
+        let (first, last) = (offset / block_size, (end - 1) / block_size);
+        let new = (first..=last).filter(|index| !node.blocks.contains_key(index)).count();
+        if (used + new as u64) * block_size > quota {
+            return Err(VolumeError::QuotaExceeded);
+        }
+        if new > blocks.free.len() {
+            return Err(VolumeError::NoSpace);
+        }
+        let mut added = 0;
+        let result = node.write(blocks, offset, data, &mut added);
+        self.used += added;
+        result.map(|()| data.len())
+    }
+
+    /// Cut a file down to `size`, freeing the blocks past it and zeroing the
+    /// rest of the last one so that growing it again reads zeros.
+    fn truncate<S: StorageBackend>(&mut self, blocks: &mut Blocks<S>, ino: Ino, size: u64) -> Result<(), VolumeError> {
+        let block_size = blocks.block_size as u64;
+        let node = self.file_mut(ino)?;
+        if size < node.size {
+            let keep = size.div_ceil(block_size);
+            let freed: Vec<u64> = node.blocks.split_off(&keep).into_values().collect();
+            let start = (size % block_size) as usize;
+            if let Some(id) = node.blocks.get(&keep.wrapping_sub(1)).filter(|_| start != 0) {
+                let mut block = vec![0u8; blocks.block_size];
+                blocks.read(*id, &mut block)?;
+                block[start..].fill(0);
+                blocks.write(*id, &block)?;
+            }
+            self.used -= freed.len() as u64;
+            blocks.free.extend(freed);
+        }
+        self.file_mut(ino)?.size = size;
+        Ok(())
+    }
+
+    /// Remove a node that is no longer in any directory, freeing its blocks.
+    fn free<S: StorageBackend>(&mut self, blocks: &mut Blocks<S>, ino: Ino) {
+        if let Some(node) = self.nodes.remove(&ino) {
+            let freed: Vec<u64> = node.blocks.into_values().collect();
+            self.used -= freed.len() as u64;
+            blocks.free.extend(freed);
+        }
+    }
+
+    fn unlink<S: StorageBackend>(&mut self, blocks: &mut Blocks<S>, dir: Ino, name: &str) -> Result<(), VolumeError> {
+        let ino = self.lookup(dir, name)?;
+        if !self.node(ino)?.children.is_empty() {
+            return Err(VolumeError::NotEmpty);
+        }
+        self.node_mut(dir)?.children.remove(name);
+        self.free(blocks, ino);
+        Ok(())
+    }
+
+    fn rename<S: StorageBackend>(
+        &mut self,
+        blocks: &mut Blocks<S>,
+        from: (Ino, &str),
+        to: (Ino, &str),
+    ) -> Result<(), VolumeError> {
+        check_name(to.1)?;
+        let ino = self.lookup(from.0, from.1)?;
+        self.dir(to.0)?;
+        // A directory cannot go inside itself.
+        let mut ancestor = to.0;
+        loop {
+            if ancestor == ino {
+                return Err(VolumeError::Invalid);
+            }
+            if ancestor == ROOT_INO {
+                break;
+            }
+            ancestor = self.node(ancestor)?.parent;
+        }
+        if let Ok(existing) = self.lookup(to.0, to.1) {
+            if existing == ino {
+                return Ok(());
+            }
+            match (self.node(ino)?.kind, self.node(existing)?.kind) {
+                (NodeKind::File, NodeKind::Directory) => return Err(VolumeError::IsADirectory),
+                (NodeKind::Directory, NodeKind::File) => return Err(VolumeError::NotADirectory),
+                _ => self.unlink(blocks, to.0, to.1)?,
+            }
+        }
+        self.node_mut(from.0)?.children.remove(from.1);
+        self.node_mut(to.0)?.children.insert(String::from(to.1), ino);
+        self.node_mut(ino)?.parent = to.0;
+        Ok(())
+    }
+}
+
+pub struct VolumeStore<S: StorageBackend> {
+    blocks: Blocks<S>,
+    volumes: BTreeMap<String, Volume>,
+}
+
+impl<S: StorageBackend> VolumeStore<S> {
+    /// Keep volumes in the first `blocks` blocks of `storage`.
+    pub fn new(storage: S, block_size: usize, blocks: u64) -> Self {
+        VolumeStore {
+            blocks: Blocks { storage, block_size, free: (0..blocks).rev().collect() },
+            volumes: BTreeMap::new(),
+        }
+    }
+
+    pub fn shared(storage: S, block_size: usize, blocks: u64) -> SharedVolumeStore<S> {
+        Arc::new(Mutex::new(Self::new(storage, block_size, blocks)))
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.blocks.block_size
+    }
+
+    /// Bytes not yet used by any volume.
+    pub fn free_bytes(&self) -> u64 {
+        self.blocks.free.len() as u64 * self.blocks.block_size as u64
+    }
+
+    /// Create an empty volume that may hold up to `quota` bytes. Nobody
+    /// has access to it yet.
+    pub fn create_volume(&mut self, name: &str, quota: u64) -> Result<(), VolumeError> {
+        check_name(name)?;
+        if self.volumes.contains_key(name) {
+            return Err(VolumeError::Exists);
+        }
+        self.volumes.insert(String::from(name), Volume::new(quota));
+        Ok(())
+    }
+
+    /// Delete a volume and everything in it.
+    pub fn delete_volume(&mut self, name: &str) -> Result<(), VolumeError> {
+        let volume = self.volumes.remove(name).ok_or(VolumeError::NotFound)?;
+        for node in volume.nodes.into_values() {
+            self.blocks.free.extend(node.blocks.into_values());
+        }
+        Ok(())
+    }
+
+    pub fn set_quota(&mut self, name: &str, quota: u64) -> Result<(), VolumeError> {
+        let volume = self.volume_mut(name)?;
+        volume.quota = quota;
+        volume.generation += 1;
+        Ok(())
+    }
+
+    pub fn volumes(&self) -> Vec<VolumeInfo> {
+        self.volumes
+            .iter()
+            .map(|(name, volume)| VolumeInfo {
+                name: name.clone(),
+                quota: volume.quota,
+                used: volume.used * self.blocks.block_size as u64,
+                acl: volume.acl.iter().map(|(vm, &access)| (vm.clone(), access)).collect(),
+            })
+            .collect()
+    }
+
+    /// Give `vm` `access` to a volume; `Access::None` takes it off the list.
+    pub fn set_access(&mut self, name: &str, vm: &str, access: Access) -> Result<(), VolumeError> {
+        let volume = self.volume_mut(name)?;
+        if access == Access::None {
+            volume.acl.remove(vm);
+        } else {
+            volume.acl.insert(String::from(vm), access);
+        }
+        volume.generation += 1;
+        Ok(())
+    }
+
+    pub fn access(&self, name: &str, vm: &str) -> Access {
+        let volume = self.volumes.get(name);
+        volume.and_then(|volume| volume.acl.get(vm)).copied().unwrap_or(Access::None)
+    }
+
+    /// A number that goes up whenever anything in the volume, its quota or
+    /// its access list changes, so VMs can tell when what they cached is
+    /// stale.
+    pub fn generation(&self, name: &str) -> Result<u64, VolumeError> {
+        self.volumes.get(name).map(|volume| volume.generation).ok_or(VolumeError::NotFound)
+    }
+
+    /// Take a VM that was deleted off every volume.
+    pub fn remove_vm(&mut self, vm: &str) {
+        for volume in self.volumes.values_mut() {
+            if volume.acl.remove(vm).is_some() {
+                volume.generation += 1;
+            }
+        }
+    }
+
+    /// The volumes `vm` may see, and how.
+    pub fn volumes_for(&self, vm: &str) -> Vec<(String, Access)> {
+        self.volumes
+            .iter()
+            .filter_map(|(name, volume)| volume.acl.get(vm).map(|&access| (name.clone(), access)))
+            .collect()
+    }
+
+    fn volume_mut(&mut self, name: &str) -> Result<&mut Volume, VolumeError> {
+        self.volumes.get_mut(name).ok_or(VolumeError::NotFound)
+    }
+
+    /// The volume, and the backend, if `vm` has at least `needed` access.
+    fn checked(&mut self, name: &str, vm: &str, needed: Access) -> Result<(&mut Volume, &mut Blocks<S>), VolumeError> {
+        let volume = self.volumes.get_mut(name).ok_or(VolumeError::NotFound)?;
+        match volume.acl.get(vm) {
+            Some(&access) if access >= needed => Ok((volume, &mut self.blocks)),
+            _ => Err(VolumeError::Denied),
+        }
+    }
+}
+
+/// One VM's view of one volume.
+#[derive(Clone)]
+pub struct VolumeHandle<S: StorageBackend> {
+    store: SharedVolumeStore<S>,
+    volume: String,
+    vm: String,
+}
+
+impl<S: StorageBackend> VolumeHandle<S> {
+    /// Attach `vm` to a volume it has access to.
+    pub fn attach(store: &SharedVolumeStore<S>, volume: &str, vm: &str) -> Result<Self, VolumeError> {
+        if store.lock().access(volume, vm) == Access::None {
+            return Err(VolumeError::Denied);
+        }
+        Ok(VolumeHandle { store: store.clone(), volume: String::from(volume), vm: String::from(vm) })
+    }
+
+    pub fn volume(&self) -> &str {
+        &self.volume
+    }
+
+    /// The VM's access now, which may have changed since it was attached.
+    pub fn access(&self) -> Access {
+        self.store.lock().access(&self.volume, &self.vm)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.store.lock().block_size()
+    }
+
+    /// The volume's quota and the bytes it may still take, which the free
+    /// space left on the backend may cut short.
+    pub fn space(&self) -> Result<(u64, u64), VolumeError> {
+        self.with(Access::Read, |volume, blocks| {
+            let block_size = blocks.block_size as u64;
+            let left = volume.quota.saturating_sub(volume.used * block_size);
+            Ok((volume.quota, core::cmp::min(left, blocks.free.len() as u64 * block_size)))
+        })
+    }
+
+    fn with<T>(
+        &self,
+        needed: Access,
+        op: impl FnOnce(&mut Volume, &mut Blocks<S>) -> Result<T, VolumeError>,
+    ) -> Result<T, VolumeError> {
+        let mut store = self.store.lock();
+        let (volume, blocks) = store.checked(&self.volume, &self.vm, needed)?;
+        let result = op(volume, blocks);
+        // Even a failed change may have written part of its data.
+        if needed == Access::Write {
+            volume.generation += 1;
+        }
+        result
+    }
+
+    pub fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, VolumeError> {
+        self.with(Access::Read, |volume, _| volume.lookup(dir, name))
+    }
+
+    pub fn getattr(&self, ino: Ino) -> Result<Attr, VolumeError> {
+        self.with(Access::Read, |volume, _| volume.getattr(ino))
+    }
+
+    /// A directory's entries, in name order.
+    pub fn readdir(&self, dir: Ino) -> Result<Vec<(String, Ino, NodeKind)>, VolumeError> {
+        self.with(Access::Read, |volume, _| {
+            let entries = volume.dir(dir)?.children.iter();
+            Ok(entries.map(|(name, &ino)| (name.clone(), ino, volume.nodes[&ino].kind)).collect())
+        })
+    }
+
+    /// Read from `offset` into `buf`, returning how many bytes there were.
+    pub fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, VolumeError> {
+        self.with(Access::Read, |volume, blocks| volume.read(blocks, ino, offset, buf))
+    }
+
+    pub fn write(&self, ino: Ino, offset: u64, data: &[u8]) -> Result<usize, VolumeError> {
+        self.with(Access::Write, |volume, blocks| volume.write(blocks, ino, offset, data))
+    }
+
+    /// Create an empty file owned by `uid` and `gid`.
+    pub fn create(&self, dir: Ino, name: &str, mode: u32, uid: u32, gid: u32) -> Result<Ino, VolumeError> {
+        self.with(Access::Write, |volume, _| volume.create(dir, name, NodeKind::File, (mode, uid, gid)))
+    }
+
+    pub fn mkdir(&self, dir: Ino, name: &str, mode: u32, uid: u32, gid: u32) -> Result<Ino, VolumeError> {
+        self.with(Access::Write, |volume, _| volume.create(dir, name, NodeKind::Directory, (mode, uid, gid)))
+    }
+
+    /// Remove a file or an empty directory.
+    pub fn unlink(&self, dir: Ino, name: &str) -> Result<(), VolumeError> {
+        self.with(Access::Write, |volume, blocks| volume.unlink(blocks, dir, name))
+    }
+
+    /// Move `name` in `dir` to `new_name` in `new_dir`, replacing a file or
+    /// empty directory already there.
+    pub fn rename(&self, dir: Ino, name: &str, new_dir: Ino, new_name: &str) -> Result<(), VolumeError> {
+        self.with(Access::Write, |volume, blocks| volume.rename(blocks, (dir, name), (new_dir, new_name)))
+    }
+
+    pub fn setattr(&self, ino: Ino, attr: SetAttr) -> Result<(), VolumeError> {
+        self.with(Access::Write, |volume, blocks| {
+            volume.node(ino)?;
+            if let Some(size) = attr.size {
+                volume.truncate(blocks, ino, size)?;
+            }
+            let node = volume.node_mut(ino)?;
+            node.mode = attr.mode.unwrap_or(node.mode);
+            node.uid = attr.uid.unwrap_or(node.uid);
+            node.gid = attr.gid.unwrap_or(node.gid);
+            node.mtime = attr.mtime.unwrap_or(node.mtime);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::RamStorage;
+
+    const BLOCK: usize = 64;
+
+    /// Volume "shared" with "a" writing, "b" reading and "c" left out.
+    fn setup(blocks: u64, quota: u64) -> (SharedVolumeStore<RamStorage>, [VolumeHandle<RamStorage>; 2]) {
+        let store = VolumeStore::shared(RamStorage::new(BLOCK, blocks as usize), BLOCK, blocks);
+        {
+            let mut store = store.lock();
+            store.create_volume("shared", quota).unwrap();
+            store.set_access("shared", "a", Access::Write).unwrap();
+            store.set_access("shared", "b", Access::Read).unwrap();
+        }
+        let a = VolumeHandle::attach(&store, "shared", "a").unwrap();
+        let b = VolumeHandle::attach(&store, "shared", "b").unwrap();
+        (store, [a, b])
+    }
+
+    #[test]
+    fn test_files_and_directories() {
+        let (_store, [a, b]) = setup(16, 1 << 20);
+        let docs = a.mkdir(ROOT_INO, "docs", 0o755, 1000, 1000).unwrap();
+        let file = a.create(docs, "notes.txt", 0o644, 1000, 1000).unwrap();
+        assert_eq!(a.create(docs, "notes.txt", 0o644, 0, 0), Err(VolumeError::Exists));
+        assert_eq!(a.mkdir(ROOT_INO, "..", 0o755, 0, 0), Err(VolumeError::Invalid));
+
+        // Writes across block boundaries, with a hole between them.
+        let text = [b'x'; 100];
+        assert_eq!(a.write(file, 10, &text), Ok(100));
+        assert_eq!(a.write(file, 300, b"end"), Ok(3));
+        let mut buf = [0xFFu8; 400];
+        assert_eq!(b.read(file, 0, &mut buf), Ok(303));
+        assert!(buf[..10].iter().chain(&buf[110..300]).all(|&byte| byte == 0));
+        assert_eq!((&buf[10..110], &buf[300..303]), (&text[..], &b"end"[..]));
+        let attr = b.getattr(file).unwrap();
+        assert_eq!((attr.kind, attr.size, attr.blocks, attr.uid, attr.mode), (NodeKind::File, 303, 3, 1000, 0o644));
+
+        // Shrinking then growing reads zeros past the cut.
+        a.setattr(file, SetAttr { size: Some(20), mtime: Some(5), ..SetAttr::default() }).unwrap();
+        a.setattr(file, SetAttr { size: Some(40), ..SetAttr::default() }).unwrap();
+        assert_eq!(b.read(file, 0, &mut buf), Ok(40));
+        assert_eq!((&buf[10..20], &buf[20..40]), (&[b'x'; 10][..], &[0u8; 20][..]));
+        assert_eq!(b.getattr(file).unwrap().mtime, 5);
+
+        assert_eq!(a.rename(ROOT_INO, "docs", docs, "inside"), Err(VolumeError::Invalid));
+        a.rename(docs, "notes.txt", ROOT_INO, "moved.txt").unwrap();
+        assert_eq!(b.lookup(ROOT_INO, "moved.txt"), Ok(file));
+        assert_eq!(b.lookup(docs, "notes.txt"), Err(VolumeError::NotFound));
+        let names: Vec<(String, NodeKind)> = b.readdir(ROOT_INO).unwrap().into_iter().map(|(n, _, k)| (n, k)).collect();
+        assert_eq!(names, [(String::from("docs"), NodeKind::Directory), (String::from("moved.txt"), NodeKind::File)]);
+        assert_eq!(b.getattr(ROOT_INO).unwrap().nlink, 3);
+
+        a.create(docs, "other", 0o644, 0, 0).unwrap();
+        assert_eq!(a.unlink(ROOT_INO, "docs"), Err(VolumeError::NotEmpty));
+        assert_eq!(a.rename(ROOT_INO, "moved.txt", ROOT_INO, "docs"), Err(VolumeError::IsADirectory));
+        a.unlink(docs, "other").unwrap();
+        a.unlink(ROOT_INO, "docs").unwrap();
+        assert_eq!(b.read(ROOT_INO, 0, &mut buf), Err(VolumeError::IsADirectory));
+    }
+
+    #[test]
+    fn test_access_and_quota() {
+        let (store, [a, b]) = setup(8, 4 * BLOCK as u64);
+        let file = a.create(ROOT_INO, "f", 0o644, 0, 0).unwrap();
+        // Readers cannot change anything; others are not let in at all.
+        assert_eq!(b.write(file, 0, b"no"), Err(VolumeError::Denied));
+        assert_eq!(b.create(ROOT_INO, "g", 0o644, 0, 0).map(|_| ()), Err(VolumeError::Denied));
+        assert_eq!(VolumeHandle::attach(&store, "shared", "c").map(|_| ()), Err(VolumeError::Denied));
+
+        assert_eq!(a.write(file, 0, &[1; 4 * BLOCK]), Ok(4 * BLOCK));
+        assert_eq!(a.write(file, 4 * BLOCK as u64, b"!"), Err(VolumeError::QuotaExceeded));
+        // Overwriting what is there needs no more room.
+        assert_eq!(a.write(file, 0, &[2; BLOCK]), Ok(BLOCK));
+        assert_eq!(store.lock().volumes()[0].used, 4 * BLOCK as u64);
+
+        // A second volume runs out of backend blocks before its quota.
+        store.lock().create_volume("other", 1 << 20).unwrap();
+        store.lock().set_access("other", "a", Access::Write).unwrap();
+        let other = VolumeHandle::attach(&store, "other", "a").unwrap();
+        let big = other.create(ROOT_INO, "big", 0o644, 0, 0).unwrap();
+        assert_eq!(other.write(big, 0, &[3; 5 * BLOCK]), Err(VolumeError::NoSpace));
+        assert_eq!(other.write(big, 0, &[3; 4 * BLOCK]), Ok(4 * BLOCK));
+        let visible = [(String::from("other"), Access::Write), (String::from("shared"), Access::Write)];
+        assert_eq!(store.lock().volumes_for("a"), visible);
+
+        // Removing a file, or a whole volume, gives its blocks back.
+        a.unlink(ROOT_INO, "f").unwrap();
+        assert_eq!(store.lock().free_bytes(), 4 * BLOCK as u64);
+        store.lock().delete_volume("other").unwrap();
+        assert_eq!(store.lock().free_bytes(), 8 * BLOCK as u64);
+        assert_eq!(other.getattr(ROOT_INO), Err(VolumeError::NotFound));
+
+        // Reads leave the generation alone; changes and access edits move it.
+        let generation = store.lock().generation("shared").unwrap();
+        b.getattr(ROOT_INO).unwrap();
+        assert_eq!(store.lock().generation("shared"), Ok(generation));
+        // Taking a VM off the volume applies to handles already attached.
+        store.lock().set_access("shared", "b", Access::None).unwrap();
+        assert_eq!(b.getattr(ROOT_INO), Err(VolumeError::Denied));
+        assert_eq!(store.lock().generation("shared"), Ok(generation + 1));
+        store.lock().remove_vm("a");
+        assert_eq!(store.lock().generation("shared"), Ok(generation + 2));
+        assert_eq!(store.lock().generation("other"), Err(VolumeError::NotFound));
+        assert_eq!(a.access(), Access::None);
+        assert_eq!(store.lock().volumes()[0].acl, []);
+    }
+
+    #[test]
+    fn test_sparse_files_and_failed_writes() {
+        // The backend only has room for two of the four blocks handed out.
+        let store = VolumeStore::shared(RamStorage::new(BLOCK, 2), BLOCK, 4);
+        store.lock().create_volume("shared", 1 << 20).unwrap();
+        store.lock().set_access("shared", "a", Access::Write).unwrap();
+        let a = VolumeHandle::attach(&store, "shared", "a").unwrap();
+
+        // A write far into a file takes one block, however far.
+        let file = a.create(ROOT_INO, "sparse", 0o644, 0, 0).unwrap();
+        assert_eq!(a.write(file, 1 << 50, b"far"), Ok(3));
+        let mut buf = [0xFFu8; 4];
+        assert_eq!(a.read(file, (1 << 50) - 1, &mut buf), Ok(4));
+        assert_eq!(&buf, b"\0far");
+        assert_eq!(a.getattr(file).unwrap().blocks, 1);
+
+        // Blocks taken before the backend failed are still the volume's, and
+        // are given back with the file.
+        let failed = a.create(ROOT_INO, "failed", 0o644, 0, 0).unwrap();
+        assert_eq!(a.write(failed, 0, &[1; 3 * BLOCK]), Err(VolumeError::Io));
+        assert_eq!(a.getattr(failed).unwrap().blocks, 2);
+        assert_eq!(store.lock().volumes()[0].used, 3 * BLOCK as u64);
+        assert_eq!(store.lock().free_bytes(), BLOCK as u64);
+        a.unlink(ROOT_INO, "sparse").unwrap();
+        a.unlink(ROOT_INO, "failed").unwrap();
+        assert_eq!(store.lock().free_bytes(), 4 * BLOCK as u64);
+    }
+}
@@ -0,0 +1,137 @@
+// A byte pipe between a paravirtual device and the host service behind it,
+// such as a guest's agent channel.
+//
+// Bytes from the guest are held up to a capacity; past it the device stops
+// taking them from the guest until the host catches up. Bytes for the guest
+// are queued without limit, the host being trusted to pace itself.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 256 * 1024;
+
+pub type SharedChannel = Arc<Mutex<ByteChannel>>;
+
+pub struct ByteChannel {
+    to_guest: VecDeque<u8>,
+    from_guest: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl ByteChannel {
+    pub fn new(capacity: usize) -> Self {
+        ByteChannel { to_guest: VecDeque::new(), from_guest: VecDeque::new(), capacity }
+    }
+
+    pub fn shared(capacity: usize) -> SharedChannel {
+        Arc::new(Mutex::new(Self::new(capacity)))
+    }
+
+    /// Queue bytes for the guest.
+    pub fn send(&mut self, data: &[u8]) {
+        self.to_guest.extend(data);
+    }
+
+    /// Take everything the guest has sent so far.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.from_guest.drain(..).collect()
+    }
+
+    /// Bytes the device may still accept from the guest.
+    pub fn room(&self) -> usize {
+        self.capacity.saturating_sub(self.from_guest.len())
+    }
+
+    /// Device side: bytes that arrived from the guest.
+    pub fn push_from_guest(&mut self, data: &[u8]) {
+        self.from_guest.extend(data);
+    }
+
+    /// Device side: up to `max` of the bytes waiting for the guest.
+    pub fn pop_to_guest(&mut self, max: usize) -> Vec<u8> {
+        let len = core::cmp::min(max, self.to_guest.len());
+        self.to_guest.drain(..len).collect()
+    }
+
+    /// Whether bytes are waiting for the guest.
+    pub fn has_pending(&self) -> bool {
+        !self.to_guest.is_empty()
+    }
+}
+
+impl Default for ByteChannel {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHANNEL_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    use hypercore_guest::{Channel, ChannelError};
+
+    use crate::hypervisor::{MigrationTransport, TransportError};
+
+    /// The guest end of a channel, driven from the same thread as the host
+    /// end: a read that finds nothing waiting first runs `on_empty`, which
+    /// stands in for the host doing its work. A host with nothing more to
+    /// say reads as a hang-up, or as a short read by a migration receiver.
+    pub(crate) struct Loopback {
+        channel: SharedChannel,
+        on_empty: Box<dyn FnMut(&SharedChannel)>,
+    }
+
+    impl Loopback {
+        pub(crate) fn new(channel: SharedChannel, on_empty: impl FnMut(&SharedChannel) + 'static) -> Self {
+            Loopback { channel, on_empty: Box::new(on_empty) }
+        }
+
+        /// A loopback whose host sends back whatever it is sent.
+        pub(crate) fn echo() -> Self {
+            Self::new(ByteChannel::shared(DEFAULT_CHANNEL_CAPACITY), |channel| {
+                let mut channel = channel.lock();
+                let data = channel.take();
+                channel.send(&data);
+            })
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> usize {
+            if !self.channel.lock().has_pending() {
+                (self.on_empty)(&self.channel);
+            }
+            let data = self.channel.lock().pop_to_guest(buf.len());
+            buf[..data.len()].copy_from_slice(&data);
+            data.len()
+        }
+    }
+
+    impl Channel for Loopback {
+        fn send(&mut self, data: &[u8]) -> Result<(), ChannelError> {
+            self.channel.lock().push_from_guest(data);
+            Ok(())
+        }
+
+        fn recv(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+            Ok(self.read(buf))
+        }
+    }
+
+    impl MigrationTransport for Loopback {
+        fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+            self.channel.lock().push_from_guest(data);
+            Ok(())
+        }
+
+        fn recv(&mut self, buf: &mut [u8]) -> Result<(), TransportError> {
+            if self.read(buf) < buf.len() {
+                return Err(TransportError);
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Per-VM console: what the guest printed on its serial port, and what the
+// host has typed for it.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+/// Output kept per console before the oldest bytes are dropped.
+pub const DEFAULT_CONSOLE_CAPACITY: usize = 64 * 1024;
+
+pub type SharedConsole = Arc<Mutex<ConsoleBuffer>>;
+
+/// A bounded log of guest console output plus a queue of pending input.
+pub struct ConsoleBuffer {
+    output: VecDeque<u8>,
+    capacity: usize,
+    dropped: u64,
+    input: VecDeque<u8>,
+}
+
+impl ConsoleBuffer {
+    pub fn new(capacity: usize) -> Self {
+        ConsoleBuffer { output: VecDeque::new(), capacity, dropped: 0, input: VecDeque::new() }
+    }
+
+    pub fn shared(capacity: usize) -> SharedConsole {
+        Arc::new(Mutex::new(Self::new(capacity)))
+    }
+
+    /// Append guest output, dropping the oldest bytes once full.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.output.len() == self.capacity {
+                self.output.pop_front();
+                self.dropped += 1;
+            }
+            self.output.push_back(byte);
+        }
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.output.iter().copied().collect()
+    }
+
+    /// The buffered output as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.output()).into_owned()
+    }
+
+    /// Remove and return everything buffered so far.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.drain(..).collect()
+    }
+
+    /// Bytes lost to the capacity limit since the console was created.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Queue bytes for the guest to receive.
+    pub fn send_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    pub fn pop_input(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    pub fn has_input(&self) -> bool {
+        !self.input.is_empty()
+    }
+}
+
+impl Default for ConsoleBuffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONSOLE_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_drops_oldest() {
+        let mut console = ConsoleBuffer::new(4);
+        console.write(b"ab");
+        console.write(b"cdef");
+        assert_eq!(console.text(), "cdef");
+        assert_eq!(console.dropped(), 2);
+        assert_eq!(console.take_output(), b"cdef");
+        assert_eq!(console.text(), "");
+
+        console.send_input(b"x");
+        assert!(console.has_input());
+        assert_eq!(console.pop_input(), Some(b'x'));
+        assert_eq!(console.pop_input(), None);
+    }
+}
@@ -0,0 +1,353 @@
+// Decoder for the memory-access instructions guests use on MMIO.
+//
+// Only what is needed to emulate a faulting access is extracted: the
+// operation, the access size, the register or immediate involved and the
+// instruction length. The effective address is not computed; the
+// guest-physical address comes from the EPT violation.
+
+/// Default operand and address size of the code segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuMode {
+    /// Real mode or a 16-bit protected-mode code segment.
+    Bits16,
+    Bits32,
+    Long,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extend {
+    None,
+    Zero,
+    Sign,
+}
+
+/// The non-memory operand of a store or read-modify-write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Reg(u8),
+    /// Already sign-extended to 64 bits.
+    Imm(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioOp {
+    /// MOV/MOVZX/MOVSX reg, [mem]
+    Load { reg: u8, dest_size: u8, extend: Extend },
+    /// MOV [mem], reg/imm
+    Store { src: Source },
+    /// STOS: store rAX to ES:rDI, honouring REP and RFLAGS.DF.
+    Stos,
+    /// AND [mem], reg/imm
+    And { src: Source },
+    /// OR [mem], reg/imm
+    Or { src: Source },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub op: MmioOp,
+    /// Size of the memory access in bytes.
+    pub size: u8,
+    pub len: u8,
+    /// A REX prefix is present, so byte registers 4-7 are SPL..DIL.
+    pub rex: bool,
+    pub rep: bool,
+    pub addr_size: u8,
+}
+
+impl Instruction {
+    /// RIP after this instruction, wrapped to the width of the instruction
+    /// pointer in `mode`.
+    pub fn next_rip(&self, rip: u64, mode: CpuMode) -> u64 {
+        let ip_mask = match mode {
+            CpuMode::Long => u64::MAX,
+            CpuMode::Bits32 => 0xFFFF_FFFF,
+            CpuMode::Bits16 => 0xFFFF,
+        };
+        rip.wrapping_add(self.len as u64) & ip_mask
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ended before the instruction did.
+    Truncated,
+    /// Not a memory-access instruction we emulate.
+    Unsupported(u16),
+    /// A register-only form of an otherwise supported opcode.
+    NotMemory,
+}
+
+/// Architectural limit on the length of one x86 instruction.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        if self.pos >= self.bytes.len() || self.pos >= MAX_INSTRUCTION_LEN {
+            return Err(DecodeError::Truncated);
+        }
+        self.pos += 1;
+        Ok(self.bytes[self.pos - 1])
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), DecodeError> {
+        for _ in 0..n {
+            self.byte()?;
+        }
+        Ok(())
+    }
+
+    /// Little-endian immediate of `n` bytes, sign-extended to 64 bits.
+    fn imm(&mut self, n: u8) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for i in 0..n {
+            value |= (self.byte()? as u64) << (8 * i);
+        }
+        let shift = 64 - 8 * n as u32;
+        Ok((((value << shift) as i64) >> shift) as u64)
+    }
+}
+
+/// Decode the instruction at the start of `bytes`.
+pub fn decode(bytes: &[u8], mode: CpuMode) -> Result<Instruction, DecodeError> {
+    let mut cur = Cursor { bytes, pos: 0 };
+    let mut op16 = false;
+    let mut addr_override = false;
+    let mut rep = false;
+    let mut opcode = loop {
+        match cur.byte()? {
+            0x66 => op16 = true,
+            0x67 => addr_override = true,
+            0xF2 | 0xF3 => rep = true,
+            0xF0 | 0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65 => {}
+            b => break b,
+        }
+    };
+    let mut rex = 0u8;
+    if mode == CpuMode::Long && opcode & 0xF0 == 0x40 {
+        rex = opcode;
+        opcode = cur.byte()?;
+    }
+    let opsize = match mode {
+        CpuMode::Long if rex & 0x8 != 0 => 8,
+        CpuMode::Long | CpuMode::Bits32 => if op16 { 2 } else { 4 },
+        CpuMode::Bits16 => if op16 { 4 } else { 2 },
+    };
+    let addr_size = match mode {
+        CpuMode::Long => if addr_override { 4 } else { 8 },
+        CpuMode::Bits32 => if addr_override { 2 } else { 4 },
+        CpuMode::Bits16 => if addr_override { 4 } else { 2 },
+    };
+    let imm_size = core::cmp::min(opsize, 4);
+    let opcode = if opcode == 0x0F { 0x0F00 | cur.byte()? as u16 } else { opcode as u16 };
+
+    // Everything below except the string and moffs forms takes a ModRM
+    // byte with a memory operand.
+    let (op, size) = match opcode {
+        0xA0..=0xA3 => {
+            cur.skip(addr_size as usize)?;
+            let size = if opcode & 1 == 0 { 1 } else { opsize };
+            let op = if opcode < 0xA2 {
+                MmioOp::Load { reg: 0, dest_size: size, extend: Extend::None }
+            } else {
+                MmioOp::Store { src: Source::Reg(0) }
+            };
+            (op, size)
+        }
+        0xAA => (MmioOp::Stos, 1),
+        0xAB => (MmioOp::Stos, opsize),
+        0x88 | 0x89 | 0x8A | 0x8B | 0xC6 | 0xC7 | 0x08 | 0x09 | 0x20 | 0x21 | 0x80 | 0x81 | 0x83 | 0x0FB6
+        | 0x0FB7 | 0x0FBE | 0x0FBF => {
+            let reg = modrm_memory(&mut cur, rex, addr_size)?;
+            let digit = reg & 7;
+            let byte_op = matches!(opcode, 0x88 | 0x8A | 0xC6 | 0x08 | 0x20 | 0x80);
+            let size = if byte_op { 1 } else { opsize };
+            match opcode {
+                0x88 | 0x89 => (MmioOp::Store { src: Source::Reg(reg) }, size),
+                0x8A | 0x8B => (MmioOp::Load { reg, dest_size: size, extend: Extend::None }, size),
+                0xC6 if digit == 0 => (MmioOp::Store { src: Source::Imm(cur.imm(1)?) }, size),
+                0xC7 if digit == 0 => (MmioOp::Store { src: Source::Imm(cur.imm(imm_size)?) }, size),
+                0x08 | 0x09 => (MmioOp::Or { src: Source::Reg(reg) }, size),
+                0x20 | 0x21 => (MmioOp::And { src: Source::Reg(reg) }, size),
+                0x80 | 0x81 | 0x83 if digit == 1 || digit == 4 => {
+                    let n = if opcode == 0x81 { imm_size } else { 1 };
+                    let src = Source::Imm(cur.imm(n)?);
+                    let op = if digit == 1 { MmioOp::Or { src } } else { MmioOp::And { src } };
+                    (op, size)
+                }
+                0x0FB6 | 0x0FB7 | 0x0FBE | 0x0FBF => {
+                    let extend = if opcode < 0x0FBE { Extend::Zero } else { Extend::Sign };
+                    let src_size = if opcode & 1 == 0 { 1 } else { 2 };
+                    (MmioOp::Load { reg, dest_size: opsize, extend }, src_size)
+                }
+                _ => return Err(DecodeError::Unsupported(opcode)),
+            }
+        }
+        _ => return Err(DecodeError::Unsupported(opcode)),
+    };
+    Ok(Instruction { op, size, len: cur.pos as u8, rex: rex != 0, rep, addr_size })
+}
+
+/// Consume ModRM, SIB and displacement; returns ModRM.reg extended by REX.R.
+fn modrm_memory(cur: &mut Cursor, rex: u8, addr_size: u8) -> Result<u8, DecodeError> {
+    let modrm = cur.byte()?;
+    let md = modrm >> 6;
+    let rm = modrm & 7;
+    if md == 3 {
+        return Err(DecodeError::NotMemory);
+    }
+    let disp = if addr_size == 2 {
+        match md {
+            0 if rm == 6 => 2,
+            1 => 1,
+            2 => 2,
+            _ => 0,
+        }
+    } else {
+        let mut base = rm;
+        if rm == 4 {
+            base = cur.byte()? & 7;
+        }
+        match md {
+            0 if base == 5 => 4,
+            1 => 1,
+            2 => 4,
+            _ => 0,
+        }
+    };
+    cur.skip(disp)?;
+    Ok(((modrm >> 3) & 7) | ((rex & 0x4) << 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Case {
+        bytes: &'static [u8],
+        mode: CpuMode,
+        expected: Result<(MmioOp, u8, u8), DecodeError>,
+    }
+
+    const fn ok(bytes: &'static [u8], mode: CpuMode, op: MmioOp, size: u8) -> Case {
+        Case { bytes, mode, expected: Ok((op, size, bytes.len() as u8)) }
+    }
+
+    const fn err(bytes: &'static [u8], mode: CpuMode, error: DecodeError) -> Case {
+        Case { bytes, mode, expected: Err(error) }
+    }
+
+    const fn load(reg: u8, dest_size: u8, extend: Extend) -> MmioOp {
+        MmioOp::Load { reg, dest_size, extend }
+    }
+
+    const fn store_reg(reg: u8) -> MmioOp {
+        MmioOp::Store { src: Source::Reg(reg) }
+    }
+
+    const fn store_imm(imm: u64) -> MmioOp {
+        MmioOp::Store { src: Source::Imm(imm) }
+    }
+
+    use super::CpuMode::{Bits16, Bits32, Long};
+    use super::Extend::{None as NoExt, Sign, Zero};
+
+    const CASES: &[Case] = &[
+        // mov eax, [rbx]
+        ok(&[0x8B, 0x03], Long, load(0, 4, NoExt), 4),
+        // mov rax, [rbx + 0x20]
+        ok(&[0x48, 0x8B, 0x43, 0x20], Long, load(0, 8, NoExt), 8),
+        // mov r9d, [rsi + 0x300]
+        ok(&[0x44, 0x8B, 0x8E, 0x00, 0x03, 0x00, 0x00], Long, load(9, 4, NoExt), 4),
+        // mov ax, [rdi]
+        ok(&[0x66, 0x8B, 0x07], Long, load(0, 2, NoExt), 2),
+        // mov cl, [rax]
+        ok(&[0x8A, 0x08], Long, load(1, 1, NoExt), 1),
+        // mov ecx, [rip + 0x1000]
+        ok(&[0x8B, 0x0D, 0x00, 0x10, 0x00, 0x00], Long, load(1, 4, NoExt), 4),
+        // mov edx, [rax + rcx*4 + 8]
+        ok(&[0x8B, 0x54, 0x88, 0x08], Long, load(2, 4, NoExt), 4),
+        // mov eax, [0xfee000b0] (SIB, no base)
+        ok(&[0x8B, 0x04, 0x25, 0xB0, 0x00, 0xE0, 0xFE], Long, load(0, 4, NoExt), 4),
+        // mov [rbx], esi
+        ok(&[0x89, 0x33], Long, store_reg(6), 4),
+        // mov [r8], r15
+        ok(&[0x4D, 0x89, 0x38], Long, store_reg(15), 8),
+        // mov [rdx], sil
+        ok(&[0x40, 0x88, 0x32], Long, store_reg(6), 1),
+        // mov byte [rax], 0x80
+        ok(&[0xC6, 0x00, 0x80], Long, store_imm(0xFFFF_FFFF_FFFF_FF80), 1),
+        // mov dword [rax + 0xb0], 0
+        ok(&[0xC7, 0x80, 0xB0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], Long, store_imm(0), 4),
+        // mov qword [rax], -1
+        ok(&[0x48, 0xC7, 0x00, 0xFF, 0xFF, 0xFF, 0xFF], Long, store_imm(u64::MAX), 8),
+        // mov word [rax], 0x1234
+        ok(&[0x66, 0xC7, 0x00, 0x34, 0x12], Long, store_imm(0x1234), 2),
+        // movzx eax, byte [rbx]
+        ok(&[0x0F, 0xB6, 0x03], Long, load(0, 4, Zero), 1),
+        // movzx ecx, word [rbx]
+        ok(&[0x0F, 0xB7, 0x0B], Long, load(1, 4, Zero), 2),
+        // movsx rax, byte [rbx]
+        ok(&[0x48, 0x0F, 0xBE, 0x03], Long, load(0, 8, Sign), 1),
+        // movsx edx, word [rbx + 2]
+        ok(&[0x0F, 0xBF, 0x53, 0x02], Long, load(2, 4, Sign), 2),
+        // mov eax, [moffs64]
+        ok(&[0xA1, 0x00, 0x00, 0xE0, 0xFE, 0x00, 0x00, 0x00, 0x00], Long, load(0, 4, NoExt), 4),
+        // mov [moffs64], al
+        ok(&[0xA2, 0x00, 0x00, 0xE0, 0xFE, 0x00, 0x00, 0x00, 0x00], Long, store_reg(0), 1),
+        // stosd / rep stosb / stosq
+        ok(&[0xAB], Long, MmioOp::Stos, 4),
+        ok(&[0xF3, 0xAA], Long, MmioOp::Stos, 1),
+        ok(&[0x48, 0xAB], Long, MmioOp::Stos, 8),
+        // or [rbx], eax / and [rbx], cl
+        ok(&[0x09, 0x03], Long, MmioOp::Or { src: Source::Reg(0) }, 4),
+        ok(&[0x20, 0x0B], Long, MmioOp::And { src: Source::Reg(1) }, 1),
+        // or dword [rax], 0x100
+        ok(&[0x81, 0x08, 0x00, 0x01, 0x00, 0x00], Long, MmioOp::Or { src: Source::Imm(0x100) }, 4),
+        // and dword [rax], ~0x10 (imm8, sign-extended)
+        ok(&[0x83, 0x20, 0xEF], Long, MmioOp::And { src: Source::Imm(0xFFFF_FFFF_FFFF_FFEF) }, 4),
+        // lock or byte [rax], 1
+        ok(&[0xF0, 0x80, 0x08, 0x01], Long, MmioOp::Or { src: Source::Imm(1) }, 1),
+        // 32-bit: mov eax, [ebx]; mov [0xfec00000], ecx; a16 mov ax, [bx + si]
+        ok(&[0x8B, 0x03], Bits32, load(0, 4, NoExt), 4),
+        ok(&[0x89, 0x0D, 0x00, 0x00, 0xC0, 0xFE], Bits32, store_reg(1), 4),
+        ok(&[0x67, 0x66, 0x8B, 0x00], Bits32, load(0, 2, NoExt), 2),
+        // 16-bit: mov ax, [0x1234]; mov [bp + 2], dl; mov eax, [bx + 0x100]
+        ok(&[0x8B, 0x06, 0x34, 0x12], Bits16, load(0, 2, NoExt), 2),
+        ok(&[0x88, 0x56, 0x02], Bits16, store_reg(2), 1),
+        ok(&[0x66, 0x8B, 0x87, 0x00, 0x01], Bits16, load(0, 4, NoExt), 4),
+        // 0x48 is DEC AX outside long mode, not REX.W
+        err(&[0x48, 0x8B, 0x03], Bits32, DecodeError::Unsupported(0x48)),
+        // register forms, unsupported opcodes and truncation
+        err(&[0x89, 0xC3], Long, DecodeError::NotMemory),
+        err(&[0x80, 0x30, 0x01], Long, DecodeError::Unsupported(0x80)),
+        err(&[0x01, 0x03], Long, DecodeError::Unsupported(0x01)),
+        err(&[0x0F, 0x05], Long, DecodeError::Unsupported(0x0F05)),
+        err(&[0x8B, 0x80, 0x00], Long, DecodeError::Truncated),
+        err(&[0x66, 0x66], Long, DecodeError::Truncated),
+    ];
+
+    #[test]
+    fn test_decode_table() {
+        for (i, case) in CASES.iter().enumerate() {
+            let result = decode(case.bytes, case.mode).map(|insn| (insn.op, insn.size, insn.len));
+            assert_eq!(result, case.expected, "case {} ({:02x?})", i, case.bytes);
+        }
+    }
+
+    #[test]
+    fn test_prefix_flags() {
+        let insn = decode(&[0xF3, 0x67, 0xAB, 0x90], CpuMode::Long).unwrap();
+        assert!(insn.rep);
+        assert_eq!(insn.addr_size, 4);
+        assert_eq!(insn.len, 3);
+        assert!(!insn.rex);
+        assert!(decode(&[0x40, 0x8A, 0x30], CpuMode::Long).unwrap().rex);
+        assert_eq!(decode(&[0xAB], CpuMode::Bits16).unwrap().addr_size, 2);
+    }
+}
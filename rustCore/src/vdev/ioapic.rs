@@ -0,0 +1,230 @@
+// Virtual IOAPIC: a redirection table turning device interrupt pins into
+// APIC bus messages.
+//
+// Pin levels are logical: `true` means the device is asserting its
+// interrupt, whatever the programmed polarity.
+
+use alloc::vec::Vec;
+
+use super::lapic::{ApicMessage, DeliveryMode, Destination};
+
+pub const IOAPIC_BASE: u64 = 0xFEC0_0000;
+pub const IOAPIC_SIZE: u64 = 0x1000;
+pub const IOAPIC_PINS: usize = 24;
+
+// MMIO offsets.
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOEOI: u64 = 0x40;
+
+// Indirect registers.
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_ARBITRATION: u32 = 0x02;
+const REG_REDIRECTION: u32 = 0x10;
+
+/// Version 0x20 has the directed-EOI register.
+const VERSION: u32 = 0x20;
+
+const RTE_DEST_LOGICAL: u64 = 1 << 11;
+const RTE_DELIVERY_STATUS: u64 = 1 << 12;
+const RTE_REMOTE_IRR: u64 = 1 << 14;
+const RTE_LEVEL: u64 = 1 << 15;
+const RTE_MASKED: u64 = 1 << 16;
+/// Bits the guest cannot write.
+const RTE_READ_ONLY: u64 = RTE_DELIVERY_STATUS | RTE_REMOTE_IRR;
+
+pub struct IoApic {
+    id: u8,
+    select: u32,
+    redirection: [u64; IOAPIC_PINS],
+    /// Pins the attached devices are asserting.
+    asserted: u32,
+}
+
+impl IoApic {
+    pub fn new(id: u8) -> Self {
+        IoApic { id, select: 0, redirection: [RTE_MASKED; IOAPIC_PINS], asserted: 0 }
+    }
+
+    pub fn redirection_entry(&self, pin: usize) -> Option<u64> {
+        self.redirection.get(pin).copied()
+    }
+
+    fn message(&self, pin: usize) -> Option<ApicMessage> {
+        let entry = self.redirection[pin];
+        let dest = (entry >> 56) as u8;
+        Some(ApicMessage {
+            vector: entry as u8,
+            mode: DeliveryMode::from_bits((entry >> 8) as u32)?,
+            destination: if entry & RTE_DEST_LOGICAL != 0 { Destination::Logical(dest) } else { Destination::Physical(dest) },
+            level_triggered: entry & RTE_LEVEL != 0,
+        })
+    }
+
+    /// Send the interrupt for a level-triggered pin that is asserted and not
+    /// already waiting for an EOI.
+    fn service_level(&mut self, pin: usize) -> Option<ApicMessage> {
+        let entry = self.redirection[pin];
+        if entry & (RTE_LEVEL | RTE_MASKED | RTE_REMOTE_IRR) != RTE_LEVEL || self.asserted & (1 << pin) == 0 {
+            return None;
+        }
+        let message = self.message(pin)?;
+        self.redirection[pin] |= RTE_REMOTE_IRR;
+        Some(message)
+    }
+
+    /// Drive input `pin`. Edge-triggered pins send on the rising edge;
+    /// level-triggered pins send while asserted, once per EOI.
+    pub fn set_level(&mut self, pin: usize, level: bool) -> Option<ApicMessage> {
+        if pin >= IOAPIC_PINS {
+            return None;
+        }
+        let was = self.asserted & (1 << pin) != 0;
+        if level {
+            self.asserted |= 1 << pin;
+        } else {
+            self.asserted &= !(1 << pin);
+        }
+        let entry = self.redirection[pin];
+        if entry & RTE_LEVEL != 0 {
+            self.service_level(pin)
+        } else if level && !was && entry & RTE_MASKED == 0 {
+            self.message(pin)
+        } else {
+            None
+        }
+    }
+
+    /// A LAPIC EOI for `vector`: clear Remote IRR on matching level-triggered
+    /// pins and resend those still asserted.
+    pub fn end_of_interrupt(&mut self, vector: u8) -> impl Iterator<Item = ApicMessage> + '_ {
+        (0..IOAPIC_PINS).filter_map(move |pin| {
+            let entry = self.redirection[pin];
+            if entry & (RTE_LEVEL | RTE_REMOTE_IRR) != (RTE_LEVEL | RTE_REMOTE_IRR) || entry as u8 != vector {
+                return None;
+            }
+            self.redirection[pin] &= !RTE_REMOTE_IRR;
+            self.service_level(pin)
+        })
+    }
+
+    fn read_register(&self) -> u32 {
+        match self.select {
+            REG_ID | REG_ARBITRATION => (self.id as u32 & 0xF) << 24,
+            REG_VERSION => VERSION | ((IOAPIC_PINS as u32 - 1) << 16),
+            index if index >= REG_REDIRECTION && index < REG_REDIRECTION + 2 * IOAPIC_PINS as u32 => {
+                let entry = self.redirection[((index - REG_REDIRECTION) / 2) as usize];
+                if index & 1 == 0 { entry as u32 } else { (entry >> 32) as u32 }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, value: u32) -> Option<ApicMessage> {
+        match self.select {
+            REG_ID => self.id = ((value >> 24) & 0xF) as u8,
+            index if index >= REG_REDIRECTION && index < REG_REDIRECTION + 2 * IOAPIC_PINS as u32 => {
+                let pin = ((index - REG_REDIRECTION) / 2) as usize;
+                let old = self.redirection[pin];
+                let (mask, value) = if index & 1 == 0 {
+                    (0xFFFF_FFFF & !RTE_READ_ONLY, value as u64)
+                } else {
+                    (0xFFFF_FFFF << 32, (value as u64) << 32)
+                };
+                let mut entry = (old & !mask) | (value & mask);
+                if entry & RTE_LEVEL == 0 {
+                    entry &= !RTE_REMOTE_IRR;
+                }
+                self.redirection[pin] = entry;
+                // Unmasking an asserted level-triggered pin delivers it now.
+                return self.service_level(pin);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    pub fn read(&mut self, offset: u64) -> u32 {
+        match offset {
+            IOREGSEL => self.select,
+            IOWIN => self.read_register(),
+            _ => 0,
+        }
+    }
+
+    /// Write the MMIO register at `offset`. Any messages the write causes
+    /// are returned for delivery.
+    pub fn write(&mut self, offset: u64, value: u32) -> Vec<ApicMessage> {
+        match offset {
+            IOREGSEL => self.select = value & 0xFF,
+            IOWIN => return self.write_register(value).into_iter().collect(),
+            IOEOI => return self.end_of_interrupt(value as u8).collect(),
+            _ => {}
+        }
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(ioapic: &mut IoApic, pin: u32, low: u32, high: u32) {
+        ioapic.write(IOREGSEL, REG_REDIRECTION + 2 * pin + 1);
+        ioapic.write(IOWIN, high);
+        ioapic.write(IOREGSEL, REG_REDIRECTION + 2 * pin);
+        ioapic.write(IOWIN, low);
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut ioapic = IoApic::new(0);
+        ioapic.write(IOREGSEL, REG_VERSION);
+        assert_eq!(ioapic.read(IOWIN), 0x0017_0020);
+        ioapic.write(IOREGSEL, REG_ID);
+        ioapic.write(IOWIN, 5 << 24);
+        assert_eq!(ioapic.read(IOWIN), 5 << 24);
+
+        program(&mut ioapic, 2, 0x0000_0830 | RTE_REMOTE_IRR as u32, 0x0100_0000);
+        ioapic.write(IOREGSEL, REG_REDIRECTION + 4);
+        // Remote IRR is read-only.
+        assert_eq!(ioapic.read(IOWIN), 0x0830);
+        assert_eq!(ioapic.redirection_entry(2), Some(0x0100_0000_0000_0830));
+    }
+
+    #[test]
+    fn test_edge_triggered_pin() {
+        let mut ioapic = IoApic::new(0);
+        assert_eq!(ioapic.set_level(4, true), None, "masked at reset");
+        ioapic.set_level(4, false);
+        program(&mut ioapic, 4, 0x24, 0);
+        let message = ioapic.set_level(4, true).unwrap();
+        assert_eq!(message.vector, 0x24);
+        assert_eq!(message.destination, Destination::Physical(0));
+        assert!(!message.level_triggered);
+        // Staying high is not a new edge.
+        assert_eq!(ioapic.set_level(4, true), None);
+        ioapic.set_level(4, false);
+        assert!(ioapic.set_level(4, true).is_some());
+    }
+
+    #[test]
+    fn test_level_triggered_pin_and_eoi() {
+        let mut ioapic = IoApic::new(0);
+        program(&mut ioapic, 10, 0x1_8000 | 0x0851, 0x0300_0000);
+        ioapic.set_level(10, true);
+        // Unmasking while asserted delivers straight away.
+        let message = ioapic.write(IOWIN, 0x8000 | 0x0851)[0];
+        assert_eq!(message.destination, Destination::Logical(3));
+        assert!(message.level_triggered);
+        assert_ne!(ioapic.redirection_entry(10).unwrap() & RTE_REMOTE_IRR, 0);
+        // No resend until the EOI, and then only while still asserted.
+        assert_eq!(ioapic.set_level(10, true), None);
+        assert_eq!(ioapic.end_of_interrupt(0x51).count(), 1);
+        ioapic.set_level(10, false);
+        // Directed EOI through the version 0x20 register.
+        assert!(ioapic.write(IOEOI, 0x51).is_empty());
+        assert_eq!(ioapic.redirection_entry(10).unwrap() & RTE_REMOTE_IRR, 0);
+    }
+}
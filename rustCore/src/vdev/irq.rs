@@ -0,0 +1,39 @@
+// Interrupt lines from device models to whatever interrupt controller the
+// VM has wired them to.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The output pin of a device interrupt. Devices drive it as a level; the
+/// controller on the other end turns level changes into interrupts.
+pub trait IrqLine: Send {
+    fn set_level(&mut self, level: bool);
+}
+
+/// A line that is not connected to anything.
+pub struct NoIrq;
+
+impl IrqLine for NoIrq {
+    fn set_level(&mut self, _level: bool) {}
+}
+
+/// A line whose level can be watched from elsewhere, for polling
+/// controllers and tests.
+#[derive(Clone, Default)]
+pub struct SharedIrq(Arc<AtomicBool>);
+
+impl SharedIrq {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn level(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl IrqLine for SharedIrq {
+    fn set_level(&mut self, level: bool) {
+        self.0.store(level, Ordering::Release);
+    }
+}
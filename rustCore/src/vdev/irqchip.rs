@@ -0,0 +1,464 @@
+// The VM's interrupt controllers: one LAPIC per vCPU plus an IOAPIC, and
+// the APIC bus between them.
+//
+// The chip answers the guest's LAPIC/IOAPIC MMIO and IA32_APIC_BASE
+// accesses through `handle_exit`, and `Vm` asks it which vector to inject
+// before each entry.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::ioapic::{IoApic, IOAPIC_BASE, IOAPIC_SIZE};
+use super::irq::IrqLine;
+use super::lapic::{ApicMessage, DeliveryMode, Destination, LapicEvent, LapicState, LocalApic, LAPIC_BASE, LAPIC_SIZE};
+use crate::hypervisor::{Disposition, ExitResponse, VcpuId, VmExit, VmExitHandler};
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_BSP: u64 = 1 << 8;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+
+pub type SharedIrqChip = Arc<Mutex<IrqChip>>;
+
+pub struct IrqChip {
+    lapics: Vec<LocalApic>,
+    /// IA32_APIC_BASE of each vCPU.
+    apic_base: Vec<u64>,
+    ioapic: IoApic,
+    /// Monotonic time in nanoseconds, for the LAPIC timers.
+    clock: Box<dyn Fn() -> u64 + Send>,
+    /// SMI, NMI and ExtINT messages, which are not modelled.
+    dropped: u64,
+}
+
+impl IrqChip {
+    /// Controllers for `vcpus` vCPUs. vCPU `n` gets APIC ID `n`; vCPU 0 is
+    /// the bootstrap processor and the others wait for INIT-SIPI.
+    pub fn new(vcpus: usize, clock: Box<dyn Fn() -> u64 + Send>) -> Self {
+        IrqChip {
+            lapics: (0..vcpus).map(|i| LocalApic::new(i as u8, i == 0)).collect(),
+            apic_base: (0..vcpus)
+                .map(|i| LAPIC_BASE | APIC_BASE_ENABLE | if i == 0 { APIC_BASE_BSP } else { 0 })
+                .collect(),
+            ioapic: IoApic::new(vcpus as u8),
+            clock,
+            dropped: 0,
+        }
+    }
+
+    pub fn shared(vcpus: usize, clock: Box<dyn Fn() -> u64 + Send>) -> SharedIrqChip {
+        Arc::new(Mutex::new(Self::new(vcpus, clock)))
+    }
+
+    pub fn now(&self) -> u64 {
+        (self.clock)()
+    }
+
+    pub fn lapic(&self, vcpu: VcpuId) -> Option<&LocalApic> {
+        self.lapics.get(vcpu as usize)
+    }
+
+    pub fn lapic_mut(&mut self, vcpu: VcpuId) -> Option<&mut LocalApic> {
+        self.lapics.get_mut(vcpu as usize)
+    }
+
+    pub fn ioapic(&self) -> &IoApic {
+        &self.ioapic
+    }
+
+    pub fn ioapic_mut(&mut self) -> &mut IoApic {
+        &mut self.ioapic
+    }
+
+    /// Messages dropped for a delivery mode the chip does not model.
+    pub fn dropped_deliveries(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Drive IOAPIC input `pin`.
+    pub fn set_irq(&mut self, pin: usize, level: bool) {
+        if let Some(message) = self.ioapic.set_level(pin, level) {
+            self.deliver(None, message);
+        }
+    }
+
+    /// Put `message` on the APIC bus. `source` is the sending vCPU for IPIs,
+    /// which the ICR shorthands are relative to.
+    pub fn deliver(&mut self, source: Option<usize>, message: ApicMessage) {
+        let targets: Vec<usize> = match message.destination {
+            Destination::ToSelf => source.into_iter().collect(),
+            Destination::AllIncludingSelf => (0..self.lapics.len()).collect(),
+            Destination::AllExcludingSelf => (0..self.lapics.len()).filter(|&i| Some(i) != source).collect(),
+            destination => (0..self.lapics.len()).filter(|&i| self.lapics[i].matches(destination)).collect(),
+        };
+        match message.mode {
+            DeliveryMode::Fixed => {
+                for i in targets {
+                    self.lapics[i].accept(message.vector, message.level_triggered);
+                }
+            }
+            // Arbitration by priority is not modelled; the first match wins.
+            DeliveryMode::LowestPriority => {
+                if let Some(&i) = targets.first() {
+                    self.lapics[i].accept(message.vector, message.level_triggered);
+                }
+            }
+            DeliveryMode::Init | DeliveryMode::Startup => {
+                for i in targets {
+                    self.lapics[i].accept_startup(message.mode, message.vector);
+                }
+            }
+            DeliveryMode::Smi | DeliveryMode::Nmi | DeliveryMode::ExtInt => self.dropped += 1,
+        }
+    }
+
+    fn handle_lapic_event(&mut self, source: usize, event: LapicEvent) {
+        match event {
+            LapicEvent::Ipi(message) => self.deliver(Some(source), message),
+            LapicEvent::Eoi(vector) => {
+                let resent: Vec<ApicMessage> = self.ioapic.end_of_interrupt(vector).collect();
+                for message in resent {
+                    self.deliver(None, message);
+                }
+            }
+        }
+    }
+
+    /// The vector `vcpu` should take next, after firing any expired timer.
+    pub fn pending_interrupt(&mut self, vcpu: VcpuId) -> Option<u8> {
+        let now = self.now();
+        let lapic = self.lapics.get_mut(vcpu as usize)?;
+        lapic.tick(now);
+        lapic.pending_interrupt()
+    }
+
+    /// `vector` was injected into `vcpu`.
+    pub fn acknowledge(&mut self, vcpu: VcpuId, vector: u8) {
+        if let Some(lapic) = self.lapics.get_mut(vcpu as usize) {
+            lapic.acknowledge(vector);
+        }
+    }
+
+    /// The earliest running LAPIC timer, so a host with every vCPU halted
+    /// knows how long it may sleep.
+    pub fn next_timer_deadline(&self) -> Option<u64> {
+        self.lapics.iter().filter_map(|lapic| lapic.timer_deadline()).min()
+    }
+
+    pub fn is_waiting_for_sipi(&self, vcpu: VcpuId) -> bool {
+        self.lapic(vcpu).is_some_and(|lapic| lapic.is_waiting_for_sipi())
+    }
+
+    pub fn take_startup(&mut self, vcpu: VcpuId) -> Option<u8> {
+        self.lapic_mut(vcpu)?.take_startup()
+    }
+
+    /// Whether `vcpu` has an interrupt or startup IPI waiting, so a halted
+    /// vCPU should be woken to look at it.
+    pub fn has_pending_event(&mut self, vcpu: VcpuId) -> bool {
+        self.pending_interrupt(vcpu).is_some() || self.lapic(vcpu).is_some_and(LocalApic::has_startup)
+    }
+
+    /// `vcpu`'s LAPIC registers and IA32_APIC_BASE.
+    pub fn save_lapic(&self, vcpu: VcpuId) -> Option<(LapicState, u64)> {
+        let now = self.now();
+        Some((self.lapic(vcpu)?.save(now), *self.apic_base.get(vcpu as usize)?))
+    }
+
+    /// Load state from [`save_lapic`](Self::save_lapic). Returns false if
+    /// `vcpu` has no LAPIC.
+    pub fn restore_lapic(&mut self, vcpu: VcpuId, state: &LapicState, apic_base: u64) -> bool {
+        let now = self.now();
+        match (self.lapics.get_mut(vcpu as usize), self.apic_base.get_mut(vcpu as usize)) {
+            (Some(lapic), Some(base)) => {
+                lapic.restore(state, now);
+                *base = apic_base;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Offset into `vcpu`'s LAPIC page, if `gpa` falls in it and the APIC is
+    /// enabled in IA32_APIC_BASE.
+    fn lapic_offset(&self, vcpu: VcpuId, gpa: u64) -> Option<u64> {
+        let base = *self.apic_base.get(vcpu as usize)?;
+        let start = base & APIC_BASE_ADDRESS;
+        if base & APIC_BASE_ENABLE == 0 || gpa < start || gpa >= start + LAPIC_SIZE {
+            return None;
+        }
+        Some(gpa - start)
+    }
+
+    fn mmio(&mut self, vcpu: VcpuId, gpa: u64, write: bool, data: u64) -> Option<ExitResponse> {
+        let now = self.now();
+        if let Some(offset) = self.lapic_offset(vcpu, gpa) {
+            // Registers are 32 bits wide on 16-byte boundaries.
+            let register = offset & !0xF;
+            let lapic = &mut self.lapics[vcpu as usize];
+            if !write {
+                let value = if offset & 0xF == 0 { lapic.read(register, now) } else { 0 };
+                return Some(ExitResponse::Data(value as u64));
+            }
+            if offset & 0xF == 0 {
+                if let Some(event) = lapic.write(register, data as u32, now) {
+                    self.handle_lapic_event(vcpu as usize, event);
+                }
+            }
+            return Some(ExitResponse::None);
+        }
+        if (IOAPIC_BASE..IOAPIC_BASE + IOAPIC_SIZE).contains(&gpa) {
+            let offset = gpa - IOAPIC_BASE;
+            if !write {
+                return Some(ExitResponse::Data(self.ioapic.read(offset) as u64));
+            }
+            for message in self.ioapic.write(offset, data as u32) {
+                self.deliver(None, message);
+            }
+            return Some(ExitResponse::None);
+        }
+        None
+    }
+
+    /// Handle LAPIC and IOAPIC MMIO and IA32_APIC_BASE accesses by `vcpu`.
+    pub fn handle_exit(&mut self, vcpu: VcpuId, exit: &VmExit) -> Option<ExitResponse> {
+        match *exit {
+            VmExit::Mmio { gpa, write, data, .. } => self.mmio(vcpu, gpa, write, data),
+            VmExit::MsrRead { msr: IA32_APIC_BASE } => {
+                self.apic_base.get(vcpu as usize).map(|&base| ExitResponse::Data(base))
+            }
+            VmExit::MsrWrite { msr: IA32_APIC_BASE, value } => {
+                let base = self.apic_base.get_mut(vcpu as usize)?;
+                // The BSP flag is read-only; x2APIC mode is not offered.
+                *base = (value & (APIC_BASE_ADDRESS | APIC_BASE_ENABLE)) | (*base & APIC_BASE_BSP);
+                Some(ExitResponse::None)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl VmExitHandler for IrqChip {
+    fn handle(&mut self, vcpu: VcpuId, exit: &VmExit) -> Disposition {
+        match self.handle_exit(vcpu, exit) {
+            Some(response) => Disposition::Resume(response),
+            None => Disposition::Stop,
+        }
+    }
+}
+
+/// An IOAPIC input, for wiring device models to the chip.
+pub struct IoApicPin {
+    chip: SharedIrqChip,
+    pin: usize,
+}
+
+impl IoApicPin {
+    pub fn new(chip: SharedIrqChip, pin: usize) -> Self {
+        IoApicPin { chip, pin }
+    }
+}
+
+impl IrqLine for IoApicPin {
+    fn set_level(&mut self, level: bool) {
+        self.chip.lock().set_irq(self.pin, level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::hypervisor::{HypervisorBackend, IoDirection, MockBackend, SpecialRegisters, VcpuRegisters, Vm};
+
+    const SVR: u64 = 0xF0;
+    const EOI: u64 = 0xB0;
+    const ICR_LOW: u64 = 0x300;
+    const ICR_HIGH: u64 = 0x310;
+
+    fn chip(vcpus: usize) -> (IrqChip, Arc<AtomicU64>) {
+        let time = Arc::new(AtomicU64::new(0));
+        let clock = time.clone();
+        (IrqChip::new(vcpus, Box::new(move || clock.load(Ordering::Relaxed))), time)
+    }
+
+    fn mmio_write(chip: &mut IrqChip, vcpu: VcpuId, gpa: u64, data: u64) {
+        let exit = VmExit::Mmio { gpa, size: 4, write: true, data };
+        assert_eq!(chip.handle_exit(vcpu, &exit), Some(ExitResponse::None));
+    }
+
+    fn mmio_read(chip: &mut IrqChip, vcpu: VcpuId, gpa: u64) -> u64 {
+        match chip.handle_exit(vcpu, &VmExit::Mmio { gpa, size: 4, write: false, data: 0 }) {
+            Some(ExitResponse::Data(value)) => value,
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ipis_and_startup() {
+        let (mut chip, _) = chip(2);
+        for vcpu in 0..2 {
+            mmio_write(&mut chip, vcpu, LAPIC_BASE + SVR, 0x1FF);
+        }
+        // Each vCPU sees its own LAPIC at the same address.
+        assert_eq!(mmio_read(&mut chip, 1, LAPIC_BASE + 0x20), 1 << 24);
+        assert_eq!(chip.handle_exit(0, &VmExit::MsrRead { msr: IA32_APIC_BASE }), Some(ExitResponse::Data(0xFEE0_0900)));
+        assert_eq!(chip.handle_exit(1, &VmExit::MsrRead { msr: IA32_APIC_BASE }), Some(ExitResponse::Data(0xFEE0_0800)));
+
+        mmio_write(&mut chip, 0, LAPIC_BASE + ICR_HIGH, 1 << 24);
+        mmio_write(&mut chip, 0, LAPIC_BASE + ICR_LOW, 0x4040);
+        assert_eq!(chip.pending_interrupt(1), Some(0x40));
+        assert_eq!(chip.pending_interrupt(0), None);
+        // Self IPI via the shorthand.
+        mmio_write(&mut chip, 0, LAPIC_BASE + ICR_LOW, 0x0004_4041);
+        assert_eq!(chip.pending_interrupt(0), Some(0x41));
+        // NMIs are not modelled, only counted.
+        mmio_write(&mut chip, 0, LAPIC_BASE + ICR_LOW, 0x0004_4400);
+        assert_eq!(chip.dropped_deliveries(), 1);
+
+        assert!(chip.is_waiting_for_sipi(1));
+        mmio_write(&mut chip, 0, LAPIC_BASE + ICR_LOW, 0x000C_4500);
+        mmio_write(&mut chip, 0, LAPIC_BASE + ICR_LOW, 0x000C_4610);
+        assert_eq!(chip.take_startup(1), Some(0x10));
+        assert!(!chip.is_waiting_for_sipi(1));
+        assert_eq!(chip.take_startup(0), None);
+        // INIT reset vCPU 1's LAPIC, dropping the earlier IPI.
+        assert_eq!(chip.pending_interrupt(1), None);
+    }
+
+    #[test]
+    fn test_ioapic_level_interrupt_and_eoi() {
+        let (mut chip, _) = chip(1);
+        mmio_write(&mut chip, 0, LAPIC_BASE + SVR, 0x1FF);
+        // Pin 9, level-triggered, vector 0x59, to APIC 0.
+        mmio_write(&mut chip, 0, IOAPIC_BASE, 0x10 + 18);
+        mmio_write(&mut chip, 0, IOAPIC_BASE + 0x10, 0x8059);
+        chip.set_irq(9, true);
+        assert_eq!(chip.pending_interrupt(0), Some(0x59));
+        chip.acknowledge(0, 0x59);
+        // Still asserted at EOI, so it comes straight back.
+        mmio_write(&mut chip, 0, LAPIC_BASE + EOI, 0);
+        assert_eq!(chip.pending_interrupt(0), Some(0x59));
+        chip.acknowledge(0, 0x59);
+        chip.set_irq(9, false);
+        mmio_write(&mut chip, 0, LAPIC_BASE + EOI, 0);
+        assert_eq!(chip.pending_interrupt(0), None);
+    }
+
+    /// Stops on the second HLT and records port writes; the first HLT moves
+    /// the clock past the LAPIC timer deadline.
+    struct Host {
+        time: Arc<AtomicU64>,
+        chip: SharedIrqChip,
+        halts: u32,
+        ports: Vec<(u16, u32)>,
+    }
+
+    impl VmExitHandler for Host {
+        fn handle(&mut self, _vcpu: VcpuId, exit: &VmExit) -> Disposition {
+            match *exit {
+                VmExit::Io { port, direction: IoDirection::Out, data, .. } => {
+                    self.ports.push((port, data));
+                    if port == 0x80 {
+                        // Edge on IOAPIC pin 4.
+                        self.chip.lock().set_irq(4, true);
+                    }
+                    Disposition::Resume(ExitResponse::None)
+                }
+                VmExit::Hlt if self.halts == 0 => {
+                    self.halts += 1;
+                    self.time.store(1_000, Ordering::Relaxed);
+                    Disposition::Resume(ExitResponse::None)
+                }
+                _ => Disposition::Stop,
+            }
+        }
+    }
+
+    /// A real-mode guest at 0x1000 with the interrupt handler for vector
+    /// 0x30 at 0x200. EBX holds the LAPIC base throughout.
+    fn guest(main: &[u8], handler: &[u8]) -> (Vm<MockBackend>, SharedIrqChip, Host) {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        backend.write_guest(0x30 * 4, &[0x00, 0x02, 0x00, 0x00]).unwrap();
+        backend.write_guest(0x31 * 4, &[0x00, 0x02, 0x00, 0x00]).unwrap();
+        backend.write_guest(0x200, handler).unwrap();
+        backend.write_guest(0x1000, main).unwrap();
+        let mut vm = Vm::new(backend);
+        let vcpu = vm.add_vcpu().unwrap();
+        let backend = vm.backend_mut();
+        backend.set_special_registers(vcpu, &SpecialRegisters::real_mode(0)).unwrap();
+        let regs = VcpuRegisters { rip: 0x1000, rsp: 0x8000, rbx: LAPIC_BASE, rflags: 0x2, ..Default::default() };
+        backend.set_registers(vcpu, &regs).unwrap();
+
+        let time = Arc::new(AtomicU64::new(0));
+        let clock = time.clone();
+        let chip = IrqChip::shared(1, Box::new(move || clock.load(Ordering::Relaxed)));
+        vm.set_irqchip(chip.clone());
+        let host = Host { time, chip: chip.clone(), halts: 0, ports: Vec::new() };
+        (vm, chip, host)
+    }
+
+    /// `mov dword [ebx + offset], value`
+    fn apic_store(code: &mut Vec<u8>, offset: u32, value: u32) {
+        code.extend_from_slice(&[0x67, 0x66, 0xC7, 0x83]);
+        code.extend_from_slice(&offset.to_le_bytes());
+        code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn handler(marker: u8) -> Vec<u8> {
+        let mut code = vec![0xB0, marker, 0xE6, 0x82]; // mov al, marker; out 0x82, al
+        apic_store(&mut code, EOI as u32, 0);
+        code.push(0xCF); // iret
+        code
+    }
+
+    #[test]
+    fn test_timer_interrupt_wakes_halted_guest() {
+        let mut main = Vec::new();
+        apic_store(&mut main, SVR as u32, 0x1FF);
+        apic_store(&mut main, 0x320, 0x30); // LVT timer: vector 0x30, one-shot
+        apic_store(&mut main, 0x3E0, 0xB); // divide by 1
+        apic_store(&mut main, 0x380, 100); // initial count
+        main.extend_from_slice(&[0xFB, 0xF4]); // sti; hlt
+        main.extend_from_slice(&[0xE6, 0x81, 0xF4]); // out 0x81, al; hlt
+
+        let (mut vm, chip, mut host) = guest(&main, &handler(0x42));
+        let exit = vm.run_vcpu(0, &mut host).unwrap();
+        assert_eq!(exit, VmExit::Hlt);
+        assert_eq!(host.ports, [(0x82, 0x42), (0x81, 0x42)]);
+        let mut chip = chip.lock();
+        assert_eq!(chip.pending_interrupt(0), None);
+        // The handler's EOI emptied the ISR.
+        assert_eq!(chip.lapic_mut(0).unwrap().read(0xA0, 1_000), 0);
+    }
+
+    #[test]
+    fn test_interrupt_window() {
+        let mut main = Vec::new();
+        apic_store(&mut main, SVR as u32, 0x1FF);
+        main.extend_from_slice(&[0xE6, 0x80]); // out 0x80, al: host raises pin 4
+        main.extend_from_slice(&[0xB0, 0x01, 0xFB, 0xB0, 0x02]); // mov al, 1; sti; mov al, 2
+        main.extend_from_slice(&[0xE6, 0x81, 0xF4, 0xF4]); // out 0x81, al; hlt; hlt
+
+        let (mut vm, chip, mut host) = guest(&main, &handler(0x55));
+        {
+            let mut chip = chip.lock();
+            let ioapic = chip.ioapic_mut();
+            ioapic.write(0x00, 0x10 + 8);
+            ioapic.write(0x10, 0x31);
+        }
+        host.halts = 1;
+        let exit = vm.run_vcpu(0, &mut host).unwrap();
+        assert_eq!(exit, VmExit::Hlt);
+        // Taken only after the instruction following STI, with AL already 2.
+        assert_eq!(host.ports, [(0x80, 0), (0x82, 0x55), (0x81, 0x55)]);
+        let regs = vm.backend().get_registers(0).unwrap();
+        assert_eq!(regs.rsp, 0x8000);
+    }
+}
@@ -0,0 +1,638 @@
+// Virtual local APIC in xAPIC (MMIO) mode, one per vCPU.
+//
+// Interrupt delivery to other APICs and the IOAPIC is not done here: writes
+// that send something return an [`LapicEvent`] for the `IrqChip` to route.
+// Time is supplied by the caller in nanoseconds; the timer counts at
+// `LAPIC_TIMER_HZ` before the divide configuration is applied.
+
+/// Architectural default for IA32_APIC_BASE.
+pub const LAPIC_BASE: u64 = 0xFEE0_0000;
+pub const LAPIC_SIZE: u64 = 0x1000;
+/// Timer input clock. At 1 GHz one tick is one nanosecond.
+pub const LAPIC_TIMER_HZ: u64 = 1_000_000_000;
+
+// Register offsets.
+const ID: u64 = 0x20;
+const VERSION: u64 = 0x30;
+const TPR: u64 = 0x80;
+const APR: u64 = 0x90;
+const PPR: u64 = 0xA0;
+const EOI: u64 = 0xB0;
+const LDR: u64 = 0xD0;
+const DFR: u64 = 0xE0;
+const SVR: u64 = 0xF0;
+const ISR: u64 = 0x100;
+const TMR: u64 = 0x180;
+const IRR: u64 = 0x200;
+const ESR: u64 = 0x280;
+const LVT_CMCI: u64 = 0x2F0;
+const ICR_LOW: u64 = 0x300;
+const ICR_HIGH: u64 = 0x310;
+const LVT_TIMER: u64 = 0x320;
+const LVT_ERROR: u64 = 0x370;
+const TIMER_INITIAL: u64 = 0x380;
+const TIMER_CURRENT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3E0;
+
+/// Version 0x14 (integrated APIC) with seven LVT entries.
+const VERSION_VALUE: u32 = 0x14 | (6 << 16);
+
+const SVR_ENABLE: u32 = 1 << 8;
+const SVR_SUPPRESS_EOI_BROADCAST: u32 = 1 << 12;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_TIMER_MODE: u32 = 3 << 17;
+
+/// How an interrupt message is delivered at its destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    Fixed,
+    LowestPriority,
+    Smi,
+    Nmi,
+    Init,
+    Startup,
+    ExtInt,
+}
+
+impl DeliveryMode {
+    /// Decode the 3-bit delivery-mode field shared by the ICR, LVTs and
+    /// IOAPIC redirection entries. Reserved encodings return `None`.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        Some(match bits & 7 {
+            0 => DeliveryMode::Fixed,
+            1 => DeliveryMode::LowestPriority,
+            2 => DeliveryMode::Smi,
+            4 => DeliveryMode::Nmi,
+            5 => DeliveryMode::Init,
+            6 => DeliveryMode::Startup,
+            7 => DeliveryMode::ExtInt,
+            _ => return None,
+        })
+    }
+}
+
+/// Which local APICs an interrupt message is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// APIC ID; 0xFF is a broadcast.
+    Physical(u8),
+    /// Message destination address, matched against each LDR/DFR.
+    Logical(u8),
+    /// ICR shorthands, relative to the sending APIC.
+    ToSelf,
+    AllIncludingSelf,
+    AllExcludingSelf,
+}
+
+/// An interrupt on the APIC bus, from an IPI or the IOAPIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicMessage {
+    pub vector: u8,
+    pub mode: DeliveryMode,
+    pub destination: Destination,
+    pub level_triggered: bool,
+}
+
+/// Something a LAPIC register write asks the rest of the system to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LapicEvent {
+    /// The guest wrote the ICR.
+    Ipi(ApicMessage),
+    /// EOI of a level-triggered vector, to be broadcast to the IOAPIC.
+    Eoi(u8),
+}
+
+/// A 256-bit vector register (IRR, ISR, TMR).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct VectorSet([u32; 8]);
+
+impl VectorSet {
+    fn set(&mut self, vector: u8) {
+        self.0[vector as usize / 32] |= 1 << (vector % 32);
+    }
+
+    fn clear(&mut self, vector: u8) {
+        self.0[vector as usize / 32] &= !(1 << (vector % 32));
+    }
+
+    fn contains(&self, vector: u8) -> bool {
+        self.0[vector as usize / 32] & (1 << (vector % 32)) != 0
+    }
+
+    fn highest(&self) -> Option<u8> {
+        self.0
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, &word)| word != 0)
+            .map(|(i, &word)| (i * 32 + 31 - word.leading_zeros() as usize) as u8)
+    }
+}
+
+/// Saved LAPIC registers. The timer is kept as the time left rather than
+/// a deadline, since the clock it was measured against does not carry
+/// over to a restored or migrated VM.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LapicState {
+    pub id: u8,
+    pub irr: [u32; 8],
+    pub isr: [u32; 8],
+    pub tmr: [u32; 8],
+    pub tpr: u32,
+    pub ldr: u32,
+    pub dfr: u32,
+    pub svr: u32,
+    pub esr: u32,
+    pub icr_low: u32,
+    pub icr_high: u32,
+    pub lvt: [u32; 7],
+    pub timer_initial: u32,
+    pub timer_divide: u32,
+    /// Nanoseconds until the running timer next fires.
+    pub timer_remaining: Option<u64>,
+    pub wait_for_sipi: bool,
+    pub sipi_vector: Option<u8>,
+}
+
+pub struct LocalApic {
+    id: u8,
+    irr: VectorSet,
+    isr: VectorSet,
+    tmr: VectorSet,
+    tpr: u32,
+    ldr: u32,
+    dfr: u32,
+    svr: u32,
+    esr: u32,
+    icr_low: u32,
+    icr_high: u32,
+    /// CMCI, timer, thermal, performance, LINT0, LINT1 and error, in
+    /// register order.
+    lvt: [u32; 7],
+    timer_initial: u32,
+    timer_divide: u32,
+    /// When the running timer next reaches zero, in caller nanoseconds.
+    timer_deadline: Option<u64>,
+    /// Set by INIT: the vCPU must not run until a startup IPI arrives.
+    wait_for_sipi: bool,
+    sipi_vector: Option<u8>,
+}
+
+impl LocalApic {
+    /// A LAPIC in its power-on state. Application processors start waiting
+    /// for INIT-SIPI; the bootstrap processor runs straight away.
+    pub fn new(id: u8, bootstrap: bool) -> Self {
+        let mut apic = LocalApic {
+            id,
+            irr: VectorSet::default(),
+            isr: VectorSet::default(),
+            tmr: VectorSet::default(),
+            tpr: 0,
+            ldr: 0,
+            dfr: 0,
+            svr: 0,
+            esr: 0,
+            icr_low: 0,
+            icr_high: 0,
+            lvt: [0; 7],
+            timer_initial: 0,
+            timer_divide: 0,
+            timer_deadline: None,
+            wait_for_sipi: !bootstrap,
+            sipi_vector: None,
+        };
+        apic.reset();
+        apic
+    }
+
+    /// The register state after INIT. The APIC ID is preserved.
+    fn reset(&mut self) {
+        self.irr = VectorSet::default();
+        self.isr = VectorSet::default();
+        self.tmr = VectorSet::default();
+        self.tpr = 0;
+        self.ldr = 0;
+        self.dfr = 0xFFFF_FFFF;
+        self.svr = 0xFF;
+        self.esr = 0;
+        self.icr_low = 0;
+        self.icr_high = 0;
+        self.lvt = [LVT_MASKED; 7];
+        self.timer_initial = 0;
+        self.timer_divide = 0;
+        self.timer_deadline = None;
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// The registers as of `now`.
+    pub fn save(&self, now: u64) -> LapicState {
+        LapicState {
+            id: self.id,
+            irr: self.irr.0,
+            isr: self.isr.0,
+            tmr: self.tmr.0,
+            tpr: self.tpr,
+            ldr: self.ldr,
+            dfr: self.dfr,
+            svr: self.svr,
+            esr: self.esr,
+            icr_low: self.icr_low,
+            icr_high: self.icr_high,
+            lvt: self.lvt,
+            timer_initial: self.timer_initial,
+            timer_divide: self.timer_divide,
+            timer_remaining: self.timer_deadline.map(|deadline| deadline.saturating_sub(now)),
+            wait_for_sipi: self.wait_for_sipi,
+            sipi_vector: self.sipi_vector,
+        }
+    }
+
+    /// Load registers saved by [`save`](Self::save), with the timer
+    /// resuming from `now`.
+    pub fn restore(&mut self, state: &LapicState, now: u64) {
+        *self = LocalApic {
+            id: state.id,
+            irr: VectorSet(state.irr),
+            isr: VectorSet(state.isr),
+            tmr: VectorSet(state.tmr),
+            tpr: state.tpr,
+            ldr: state.ldr,
+            dfr: state.dfr,
+            svr: state.svr,
+            esr: state.esr,
+            icr_low: state.icr_low,
+            icr_high: state.icr_high,
+            lvt: state.lvt,
+            timer_initial: state.timer_initial,
+            timer_divide: state.timer_divide,
+            timer_deadline: state.timer_remaining.map(|remaining| now + remaining),
+            wait_for_sipi: state.wait_for_sipi,
+            sipi_vector: state.sipi_vector,
+        };
+    }
+
+    pub fn software_enabled(&self) -> bool {
+        self.svr & SVR_ENABLE != 0
+    }
+
+    /// Whether `destination` selects this APIC. Shorthands are resolved by
+    /// the sender and never match here.
+    pub fn matches(&self, destination: Destination) -> bool {
+        match destination {
+            Destination::Physical(id) => id == 0xFF || id == self.id,
+            Destination::Logical(mda) => {
+                let logical_id = (self.ldr >> 24) as u8;
+                if self.dfr >> 28 == 0xF {
+                    // Flat model: one bit per APIC.
+                    logical_id & mda != 0
+                } else {
+                    // Cluster model: cluster in the high nibble, members in the low.
+                    (mda >> 4 == 0xF || mda >> 4 == logical_id >> 4) && logical_id & mda & 0xF != 0
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn timer_divisor(&self) -> u64 {
+        let value = (self.timer_divide & 3) | ((self.timer_divide & 8) >> 1);
+        1 << ((value + 1) & 7)
+    }
+
+    fn timer_period(&self) -> u64 {
+        self.timer_initial as u64 * self.timer_divisor() * 1_000_000_000 / LAPIC_TIMER_HZ
+    }
+
+    /// When the timer next fires, if it is running.
+    pub fn timer_deadline(&self) -> Option<u64> {
+        self.timer_deadline
+    }
+
+    /// Fire the timer if its deadline has passed by `now`. A periodic
+    /// timer that fell several periods behind fires once and catches up.
+    pub fn tick(&mut self, now: u64) {
+        let deadline = match self.timer_deadline {
+            Some(deadline) if now >= deadline => deadline,
+            _ => return,
+        };
+        let lvt = self.lvt[1];
+        if lvt & LVT_MASKED == 0 {
+            self.accept(lvt as u8, false);
+        }
+        self.timer_deadline = if lvt & LVT_TIMER_MODE == LVT_TIMER_PERIODIC {
+            let period = core::cmp::max(self.timer_period(), 1);
+            Some(deadline + ((now - deadline) / period + 1) * period)
+        } else {
+            None
+        };
+    }
+
+    fn timer_current(&self, now: u64) -> u32 {
+        match self.timer_deadline {
+            Some(deadline) if deadline > now => {
+                let ns_per_tick = self.timer_divisor() * 1_000_000_000 / LAPIC_TIMER_HZ;
+                ((deadline - now).div_ceil(core::cmp::max(ns_per_tick, 1))) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    /// Latch a fixed interrupt into the IRR. Dropped while the APIC is
+    /// software-disabled.
+    pub fn accept(&mut self, vector: u8, level_triggered: bool) {
+        if !self.software_enabled() || vector < 16 {
+            return;
+        }
+        self.irr.set(vector);
+        if level_triggered {
+            self.tmr.set(vector);
+        } else {
+            self.tmr.clear(vector);
+        }
+    }
+
+    /// Handle an INIT or SIPI message.
+    pub fn accept_startup(&mut self, mode: DeliveryMode, vector: u8) {
+        match mode {
+            DeliveryMode::Init => {
+                self.reset();
+                self.wait_for_sipi = true;
+                self.sipi_vector = None;
+            }
+            DeliveryMode::Startup if self.wait_for_sipi => {
+                self.wait_for_sipi = false;
+                self.sipi_vector = Some(vector);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_waiting_for_sipi(&self) -> bool {
+        self.wait_for_sipi
+    }
+
+    /// Whether a startup IPI is waiting to be taken.
+    pub fn has_startup(&self) -> bool {
+        self.sipi_vector.is_some()
+    }
+
+    /// The vector of a startup IPI received since the last call. The vCPU
+    /// should begin in real mode at `vector << 12`.
+    pub fn take_startup(&mut self) -> Option<u8> {
+        self.sipi_vector.take()
+    }
+
+    fn processor_priority(&self) -> u32 {
+        let isrv = self.isr.highest().unwrap_or(0) as u32;
+        if self.tpr & 0xF0 >= isrv & 0xF0 { self.tpr & 0xFF } else { isrv & 0xF0 }
+    }
+
+    /// Highest pending vector the processor priority allows through.
+    pub fn pending_interrupt(&self) -> Option<u8> {
+        let vector = self.irr.highest()?;
+        if vector as u32 & 0xF0 > self.processor_priority() & 0xF0 { Some(vector) } else { None }
+    }
+
+    /// The CPU took `vector`: move it from the IRR to the ISR.
+    pub fn acknowledge(&mut self, vector: u8) {
+        self.irr.clear(vector);
+        self.isr.set(vector);
+    }
+
+    fn eoi(&mut self) -> Option<LapicEvent> {
+        let vector = self.isr.highest()?;
+        self.isr.clear(vector);
+        if self.tmr.contains(vector) && self.svr & SVR_SUPPRESS_EOI_BROADCAST == 0 {
+            Some(LapicEvent::Eoi(vector))
+        } else {
+            None
+        }
+    }
+
+    fn send_ipi(&mut self) -> Option<LapicEvent> {
+        let low = self.icr_low;
+        let mode = DeliveryMode::from_bits(low >> 8)?;
+        let level_asserted = low & (1 << 14) != 0;
+        let level_triggered = low & (1 << 15) != 0;
+        if mode == DeliveryMode::Init && level_triggered && !level_asserted {
+            // INIT level de-assert, only meaningful to 82489DX APICs.
+            return None;
+        }
+        let destination = match (low >> 18) & 3 {
+            1 => Destination::ToSelf,
+            2 => Destination::AllIncludingSelf,
+            3 => Destination::AllExcludingSelf,
+            _ if low & (1 << 11) != 0 => Destination::Logical((self.icr_high >> 24) as u8),
+            _ => Destination::Physical((self.icr_high >> 24) as u8),
+        };
+        Some(LapicEvent::Ipi(ApicMessage { vector: low as u8, mode, destination, level_triggered }))
+    }
+
+    /// Read the 32-bit register at `offset` in the APIC page.
+    pub fn read(&mut self, offset: u64, now: u64) -> u32 {
+        self.tick(now);
+        match offset {
+            ID => (self.id as u32) << 24,
+            VERSION => VERSION_VALUE,
+            TPR => self.tpr,
+            APR => 0,
+            PPR => self.processor_priority(),
+            LDR => self.ldr,
+            DFR => self.dfr,
+            SVR => self.svr,
+            o @ ISR..=0x170 => self.isr.0[((o - ISR) / 0x10) as usize],
+            o @ TMR..=0x1F0 => self.tmr.0[((o - TMR) / 0x10) as usize],
+            o @ IRR..=0x270 => self.irr.0[((o - IRR) / 0x10) as usize],
+            ESR => self.esr,
+            ICR_LOW => self.icr_low,
+            ICR_HIGH => self.icr_high,
+            LVT_CMCI => self.lvt[0],
+            o @ LVT_TIMER..=LVT_ERROR => self.lvt[((o - LVT_TIMER) / 0x10) as usize + 1],
+            TIMER_INITIAL => self.timer_initial,
+            TIMER_CURRENT => self.timer_current(now),
+            TIMER_DIVIDE => self.timer_divide,
+            _ => 0,
+        }
+    }
+
+    /// Write the 32-bit register at `offset` in the APIC page.
+    pub fn write(&mut self, offset: u64, value: u32, now: u64) -> Option<LapicEvent> {
+        self.tick(now);
+        match offset {
+            TPR => self.tpr = value & 0xFF,
+            EOI => return self.eoi(),
+            LDR => self.ldr = value & 0xFF00_0000,
+            DFR => self.dfr = value | 0x0FFF_FFFF,
+            SVR => {
+                self.svr = value & 0x13FF;
+                if !self.software_enabled() {
+                    for lvt in self.lvt.iter_mut() {
+                        *lvt |= LVT_MASKED;
+                    }
+                }
+            }
+            // Writes arm the ESR; we never record errors.
+            ESR => self.esr = 0,
+            ICR_LOW => {
+                self.icr_low = value & !(1 << 12);
+                return self.send_ipi();
+            }
+            ICR_HIGH => self.icr_high = value & 0xFF00_0000,
+            LVT_CMCI => self.lvt[0] = self.lvt_value(value),
+            o @ LVT_TIMER..=LVT_ERROR => {
+                let index = ((o - LVT_TIMER) / 0x10) as usize + 1;
+                self.lvt[index] = self.lvt_value(value);
+            }
+            TIMER_INITIAL => {
+                self.timer_initial = value;
+                self.timer_deadline = if value == 0 { None } else { Some(now + self.timer_period()) };
+            }
+            TIMER_DIVIDE => self.timer_divide = value & 0xB,
+            _ => {}
+        }
+        None
+    }
+
+    fn lvt_value(&self, value: u32) -> u32 {
+        if self.software_enabled() { value } else { value | LVT_MASKED }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_apic() -> LocalApic {
+        let mut apic = LocalApic::new(0, true);
+        apic.write(SVR, SVR_ENABLE | 0xFF, 0);
+        apic
+    }
+
+    #[test]
+    fn test_priority_and_eoi() {
+        let mut apic = enabled_apic();
+        apic.accept(0x31, false);
+        apic.accept(0x52, true);
+        assert_eq!(apic.pending_interrupt(), Some(0x52));
+        apic.acknowledge(0x52);
+        // 0x31 is in a lower priority class than the one in service.
+        assert_eq!(apic.pending_interrupt(), None);
+        assert_eq!(apic.read(PPR, 0), 0x50);
+        assert_eq!(apic.write(EOI, 0, 0), Some(LapicEvent::Eoi(0x52)));
+        assert_eq!(apic.pending_interrupt(), Some(0x31));
+
+        apic.write(TPR, 0x40, 0);
+        assert_eq!(apic.pending_interrupt(), None);
+        apic.write(TPR, 0x20, 0);
+        apic.acknowledge(0x31);
+        assert_eq!(apic.read(ISR + 0x10, 0), 1 << (0x31 - 32));
+        // Edge-triggered EOIs are not broadcast.
+        assert_eq!(apic.write(EOI, 0, 0), None);
+    }
+
+    #[test]
+    fn test_disabled_apic_drops_interrupts() {
+        let mut apic = LocalApic::new(1, true);
+        apic.accept(0x40, false);
+        assert_eq!(apic.pending_interrupt(), None);
+        apic.write(LVT_TIMER, 0x20, 0);
+        assert_ne!(apic.read(LVT_TIMER, 0) & LVT_MASKED, 0);
+    }
+
+    #[test]
+    fn test_timer_one_shot_and_periodic() {
+        let mut apic = enabled_apic();
+        apic.write(TIMER_DIVIDE, 0x3, 0); // divide by 16
+        apic.write(LVT_TIMER, 0xEC, 0);
+        apic.write(TIMER_INITIAL, 100, 1000);
+        assert_eq!(apic.timer_deadline(), Some(1000 + 1600));
+        assert_eq!(apic.read(TIMER_CURRENT, 1800), 50);
+        apic.tick(2599);
+        assert_eq!(apic.pending_interrupt(), None);
+        apic.tick(2600);
+        assert_eq!(apic.pending_interrupt(), Some(0xEC));
+        assert_eq!(apic.timer_deadline(), None);
+        assert_eq!(apic.read(TIMER_CURRENT, 3000), 0);
+
+        apic.acknowledge(0xEC);
+        apic.write(EOI, 0, 3000);
+        apic.write(TIMER_DIVIDE, 0xB, 3000); // divide by 1
+        apic.write(LVT_TIMER, 0xEC | LVT_TIMER_PERIODIC, 3000);
+        apic.write(TIMER_INITIAL, 10, 3000);
+        apic.tick(3035);
+        assert_eq!(apic.pending_interrupt(), Some(0xEC));
+        assert_eq!(apic.timer_deadline(), Some(3040));
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let mut apic = enabled_apic();
+        apic.write(TIMER_DIVIDE, 0xB, 0);
+        apic.write(LVT_TIMER, 0xEC, 0);
+        apic.write(TIMER_INITIAL, 500, 1000);
+        apic.accept(0x41, false);
+        let state = apic.save(1200);
+        assert_eq!(state.timer_remaining, Some(300));
+
+        // The timer resumes against the new clock.
+        let mut restored = LocalApic::new(0, false);
+        restored.restore(&state, 50_000);
+        assert_eq!(restored.save(50_000), state);
+        assert_eq!(restored.timer_deadline(), Some(50_300));
+        assert_eq!(restored.pending_interrupt(), Some(0x41));
+        assert!(!restored.is_waiting_for_sipi());
+    }
+
+    #[test]
+    fn test_icr_ipis() {
+        let mut apic = enabled_apic();
+        apic.write(ICR_HIGH, 3 << 24, 0);
+        let event = apic.write(ICR_LOW, 0x4041, 0);
+        assert_eq!(
+            event,
+            Some(LapicEvent::Ipi(ApicMessage {
+                vector: 0x41,
+                mode: DeliveryMode::Fixed,
+                destination: Destination::Physical(3),
+                level_triggered: false,
+            }))
+        );
+        // Delivery completes instantly, so the busy bit never reads as set.
+        assert_eq!(apic.read(ICR_LOW, 0) & (1 << 12), 0);
+
+        // INIT to all others, then the matching de-assert, which is dropped.
+        let init = apic.write(ICR_LOW, 0x000C_4500, 0);
+        assert!(matches!(
+            init,
+            Some(LapicEvent::Ipi(ApicMessage { mode: DeliveryMode::Init, destination: Destination::AllExcludingSelf, .. }))
+        ));
+        assert_eq!(apic.write(ICR_LOW, 0x000C_8500, 0), None);
+    }
+
+    #[test]
+    fn test_init_sipi_and_logical_destinations() {
+        let mut ap = LocalApic::new(2, false);
+        assert!(ap.is_waiting_for_sipi());
+        ap.accept_startup(DeliveryMode::Startup, 0x9A);
+        assert!(!ap.is_waiting_for_sipi());
+        assert_eq!(ap.take_startup(), Some(0x9A));
+        assert_eq!(ap.take_startup(), None);
+        // A second SIPI after start-up is ignored.
+        ap.accept_startup(DeliveryMode::Startup, 0x10);
+        assert_eq!(ap.take_startup(), None);
+
+        ap.write(LDR, 0x04 << 24, 0);
+        assert!(ap.matches(Destination::Logical(0x0C)));
+        assert!(!ap.matches(Destination::Logical(0x01)));
+        ap.write(DFR, 0x0FFF_FFFF, 0);
+        ap.write(LDR, 0x21 << 24, 0);
+        assert!(ap.matches(Destination::Logical(0x23)));
+        assert!(!ap.matches(Destination::Logical(0x13)));
+        assert!(ap.matches(Destination::Physical(2)));
+        assert!(ap.matches(Destination::Physical(0xFF)));
+    }
+}
@@ -0,0 +1,353 @@
+// Guest MMIO bus: routes accesses to unbacked guest-physical ranges to
+// device models, and emulates the faulting instruction against it.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+
+use super::decoder::{CpuMode, Extend, Instruction, MmioOp, Source};
+use crate::hypervisor::{operand_mask, sign_extend, Disposition, ExitResponse, VcpuId, VcpuRegisters, VmExit, VmExitHandler};
+
+const FLAG_CF: u64 = 1 << 0;
+const FLAG_PF: u64 = 1 << 2;
+const FLAG_ZF: u64 = 1 << 6;
+const FLAG_SF: u64 = 1 << 7;
+const FLAG_DF: u64 = 1 << 10;
+const FLAG_OF: u64 = 1 << 11;
+
+/// A device model mapped into guest-physical address space.
+///
+/// `offset` is relative to the base the device was registered at, and
+/// `size` is 1, 2, 4 or 8 bytes.
+pub trait MmioDevice: Send {
+    fn read(&mut self, offset: u64, size: u8) -> u64;
+    fn write(&mut self, offset: u64, size: u8, value: u64);
+
+    fn name(&self) -> &'static str {
+        "mmio-device"
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioError {
+    InvalidRange { base: u64, len: u64 },
+    Overlap { base: u64, existing: u64 },
+    NotRegistered(u64),
+    /// No device claims the guest-physical address being emulated.
+    Unclaimed(u64),
+}
+
+struct Mapping {
+    len: u64,
+    device: Box<dyn MmioDevice>,
+}
+
+pub struct MmioBus {
+    mappings: BTreeMap<u64, Mapping>,
+}
+
+impl MmioBus {
+    pub fn new() -> Self {
+        MmioBus { mappings: BTreeMap::new() }
+    }
+
+    /// Claim guest-physical `base .. base + len` for `device`. The range must
+    /// also be left unbacked (an MMIO region) in the guest memory layout.
+    pub fn register(&mut self, base: u64, len: u64, device: Box<dyn MmioDevice>) -> Result<(), MmioError> {
+        let end = base.checked_add(len).ok_or(MmioError::InvalidRange { base, len })?;
+        if len == 0 {
+            return Err(MmioError::InvalidRange { base, len });
+        }
+        if let Some((&existing, mapping)) = self.mappings.range(..=base).next_back() {
+            if existing + mapping.len > base {
+                return Err(MmioError::Overlap { base, existing });
+            }
+        }
+        if let Some((&existing, _)) = self.mappings.range(base..).next() {
+            if existing < end {
+                return Err(MmioError::Overlap { base, existing });
+            }
+        }
+        self.mappings.insert(base, Mapping { len, device });
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, base: u64) -> Result<Box<dyn MmioDevice>, MmioError> {
+        self.mappings.remove(&base).map(|m| m.device).ok_or(MmioError::NotRegistered(base))
+    }
+
+    /// Base and length of the device range containing `gpa`.
+    pub fn range_of(&self, gpa: u64) -> Option<(u64, u64)> {
+        self.mappings
+            .range(..=gpa)
+            .next_back()
+            .filter(|(&base, m)| gpa < base + m.len)
+            .map(|(&base, m)| (base, m.len))
+    }
+
+    pub fn is_claimed(&self, gpa: u64) -> bool {
+        self.range_of(gpa).is_some()
+    }
+
+    fn lookup(&mut self, gpa: u64) -> Option<(u64, &mut Mapping)> {
+        let (&base, mapping) = self.mappings.range_mut(..=gpa).next_back()?;
+        if gpa < base + mapping.len {
+            Some((gpa - base, mapping))
+        } else {
+            None
+        }
+    }
+
+    /// Read from a device. Unclaimed addresses read as all ones.
+    pub fn read(&mut self, gpa: u64, size: u8) -> u64 {
+        match self.lookup(gpa) {
+            Some((offset, mapping)) => mapping.device.read(offset, size) & operand_mask(size),
+            None => operand_mask(size),
+        }
+    }
+
+    /// Write to a device. Writes to unclaimed addresses are dropped.
+    pub fn write(&mut self, gpa: u64, size: u8, value: u64) {
+        if let Some((offset, mapping)) = self.lookup(gpa) {
+            mapping.device.write(offset, size, value & operand_mask(size));
+        }
+    }
+
+    /// Serve a backend-neutral MMIO exit. Returns `None` for other exits.
+    pub fn handle_exit(&mut self, exit: &VmExit) -> Option<ExitResponse> {
+        match *exit {
+            VmExit::Mmio { gpa, size, write: false, .. } => Some(ExitResponse::Data(self.read(gpa, size))),
+            VmExit::Mmio { gpa, size, write: true, data } => {
+                self.write(gpa, size, data);
+                Some(ExitResponse::None)
+            }
+            _ => None,
+        }
+    }
+
+    /// Emulate `insn`, which faulted on `gpa`, against the bus.
+    ///
+    /// Updates the destination register, RFLAGS for AND/OR, RDI/RCX for
+    /// STOS, and moves RIP past the instruction. A REP STOS that runs off the
+    /// end of the device is stopped there with RIP unchanged, so the rest of
+    /// the string is executed by the guest normally.
+    pub fn emulate(&mut self, insn: &Instruction, gpa: u64, regs: &mut VcpuRegisters, mode: CpuMode) -> Result<(), MmioError> {
+        let long = mode == CpuMode::Long;
+        let (base, len) = self.range_of(gpa).ok_or(MmioError::Unclaimed(gpa))?;
+        let size = insn.size;
+        let source = |regs: &VcpuRegisters, src: Source| match src {
+            Source::Reg(reg) => regs.read_operand(reg, size, insn.rex),
+            Source::Imm(imm) => imm & operand_mask(size),
+        };
+        match insn.op {
+            MmioOp::Load { reg, dest_size, extend } => {
+                let mut value = self.read(gpa, size);
+                if extend == Extend::Sign {
+                    value = sign_extend(value, size);
+                }
+                regs.write_operand(reg, dest_size, insn.rex, long, value);
+            }
+            MmioOp::Store { src } => {
+                let value = source(regs, src);
+                self.write(gpa, size, value);
+            }
+            MmioOp::And { src } | MmioOp::Or { src } => {
+                let old = self.read(gpa, size);
+                let operand = source(regs, src);
+                let result = if matches!(insn.op, MmioOp::And { .. }) { old & operand } else { old | operand };
+                self.write(gpa, size, result);
+                set_logic_flags(regs, result, size);
+            }
+            MmioOp::Stos => {
+                let amask = operand_mask(insn.addr_size);
+                let mut count = if insn.rep { regs.rcx & amask } else { 1 };
+                let step = if regs.rflags & FLAG_DF != 0 { (size as u64).wrapping_neg() } else { size as u64 };
+                let value = regs.rax & operand_mask(size);
+                let mut addr = gpa;
+                while count != 0 {
+                    if addr < base || addr + size as u64 > base + len {
+                        return Ok(());
+                    }
+                    self.write(addr, size, value);
+                    addr = addr.wrapping_add(step);
+                    regs.rdi = (regs.rdi & !amask) | (regs.rdi.wrapping_add(step) & amask);
+                    count -= 1;
+                    if insn.rep {
+                        regs.rcx = (regs.rcx & !amask) | count;
+                    }
+                }
+            }
+        }
+        regs.rip = insn.next_rip(regs.rip, mode);
+        Ok(())
+    }
+}
+
+fn set_logic_flags(regs: &mut VcpuRegisters, result: u64, size: u8) {
+    regs.rflags &= !(FLAG_CF | FLAG_OF | FLAG_PF | FLAG_ZF | FLAG_SF);
+    if result == 0 {
+        regs.rflags |= FLAG_ZF;
+    }
+    if result & (1 << (size as u32 * 8 - 1)) != 0 {
+        regs.rflags |= FLAG_SF;
+    }
+    if (result as u8).count_ones() & 1 == 0 {
+        regs.rflags |= FLAG_PF;
+    }
+}
+
+impl Default for MmioBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs a vCPU with only MMIO emulated; any other exit stops it.
+impl VmExitHandler for MmioBus {
+    fn handle(&mut self, _vcpu: VcpuId, exit: &VmExit) -> Disposition {
+        match self.handle_exit(exit) {
+            Some(response) => Disposition::Resume(response),
+            None => Disposition::Stop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vdev::decoder::{decode, CpuMode};
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    /// 256 bytes of little-endian registers.
+    struct Scratch {
+        mem: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl MmioDevice for Scratch {
+        fn read(&mut self, offset: u64, size: u8) -> u64 {
+            let mem = self.mem.lock();
+            let mut bytes = [0u8; 8];
+            bytes[..size as usize].copy_from_slice(&mem[offset as usize..offset as usize + size as usize]);
+            u64::from_le_bytes(bytes)
+        }
+
+        fn write(&mut self, offset: u64, size: u8, value: u64) {
+            let mut mem = self.mem.lock();
+            mem[offset as usize..offset as usize + size as usize].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        }
+    }
+
+    const BASE: u64 = 0xFEE0_0000;
+
+    fn bus() -> (MmioBus, Arc<Mutex<Vec<u8>>>) {
+        let mem = Arc::new(Mutex::new(vec![0u8; 0x100]));
+        let mut bus = MmioBus::new();
+        bus.register(BASE, 0x100, Box::new(Scratch { mem: mem.clone() })).unwrap();
+        (bus, mem)
+    }
+
+    fn run(bus: &mut MmioBus, bytes: &[u8], gpa: u64, regs: &mut VcpuRegisters) {
+        let insn = decode(bytes, CpuMode::Long).unwrap();
+        bus.emulate(&insn, gpa, regs, CpuMode::Long).unwrap();
+    }
+
+    #[test]
+    fn test_register_and_route() {
+        let (mut bus, mem) = bus();
+        assert_eq!(
+            bus.register(BASE + 0x80, 0x1000, Box::new(Scratch { mem: mem.clone() })).err(),
+            Some(MmioError::Overlap { base: BASE + 0x80, existing: BASE })
+        );
+        bus.write(BASE + 0x10, 4, 0xDEAD_BEEF);
+        assert_eq!(bus.read(BASE + 0x10, 2), 0xBEEF);
+        assert_eq!(bus.read(0x1000, 4), 0xFFFF_FFFF);
+        assert_eq!(bus.range_of(BASE + 0xFF), Some((BASE, 0x100)));
+        assert!(!bus.is_claimed(BASE + 0x100));
+    }
+
+    #[test]
+    fn test_emulate_loads_and_stores() {
+        let (mut bus, mem) = bus();
+        let mut regs = VcpuRegisters { rip: 0x1000, rax: u64::MAX, rcx: 0xAAAA_AAAA_1234_5678, ..Default::default() };
+
+        // mov [rbx], ecx
+        run(&mut bus, &[0x89, 0x0B], BASE + 0x20, &mut regs);
+        assert_eq!(&mem.lock()[0x20..0x24], &[0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(regs.rip, 0x1002);
+
+        // mov eax, [rbx] zero-extends into RAX
+        run(&mut bus, &[0x8B, 0x03], BASE + 0x20, &mut regs);
+        assert_eq!(regs.rax, 0x1234_5678);
+
+        // mov ah, [rbx] only touches bits 15:8
+        run(&mut bus, &[0x8A, 0x23], BASE + 0x21, &mut regs);
+        assert_eq!(regs.rax, 0x1234_5678 & !0xFF00 | 0x5600);
+
+        // movsx rdx, byte [rbx]
+        mem.lock()[0x30] = 0x80;
+        run(&mut bus, &[0x48, 0x0F, 0xBE, 0x13], BASE + 0x30, &mut regs);
+        assert_eq!(regs.rdx, 0xFFFF_FFFF_FFFF_FF80);
+
+        // movzx esi, word [rbx]
+        regs.rsi = u64::MAX;
+        run(&mut bus, &[0x0F, 0xB7, 0x33], BASE + 0x22, &mut regs);
+        assert_eq!(regs.rsi, 0x1234);
+
+        // mov qword [rax], -2
+        run(&mut bus, &[0x48, 0xC7, 0x00, 0xFE, 0xFF, 0xFF, 0xFF], BASE + 0x40, &mut regs);
+        assert_eq!(bus.read(BASE + 0x40, 8), 0xFFFF_FFFF_FFFF_FFFE);
+        assert_eq!(regs.rip, 0x1000 + 2 + 2 + 2 + 4 + 3 + 7);
+    }
+
+    #[test]
+    fn test_emulate_and_or_flags() {
+        let (mut bus, _mem) = bus();
+        let mut regs = VcpuRegisters::default();
+        bus.write(BASE, 4, 0x8000_00F0);
+
+        // or dword [rax], 0x100
+        run(&mut bus, &[0x81, 0x08, 0x00, 0x01, 0x00, 0x00], BASE, &mut regs);
+        assert_eq!(bus.read(BASE, 4), 0x8000_01F0);
+        assert_ne!(regs.rflags & FLAG_SF, 0);
+        assert_eq!(regs.rflags & FLAG_ZF, 0);
+
+        // and dword [rax], 0 clears and sets ZF
+        regs.rcx = 0;
+        run(&mut bus, &[0x21, 0x08], BASE, &mut regs);
+        assert_eq!(bus.read(BASE, 4), 0);
+        assert_ne!(regs.rflags & FLAG_ZF, 0);
+        assert_eq!(regs.rflags & FLAG_SF, 0);
+    }
+
+    #[test]
+    fn test_emulate_rep_stos() {
+        let (mut bus, mem) = bus();
+        let mut regs = VcpuRegisters { rax: 0x1122_3344, rcx: 4, rdi: 0x5000, rip: 0x10, ..Default::default() };
+
+        // rep stosd into the last 8 bytes: two elements fit, two are left
+        run(&mut bus, &[0xF3, 0xAB], BASE + 0xF8, &mut regs);
+        assert_eq!(&mem.lock()[0xF8..0x100], &[0x44, 0x33, 0x22, 0x11, 0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(regs.rcx, 2);
+        assert_eq!(regs.rdi, 0x5008);
+        assert_eq!(regs.rip, 0x10);
+
+        // backwards with DF set, completes
+        regs.rflags |= FLAG_DF;
+        run(&mut bus, &[0xF3, 0xAB], BASE + 0x10, &mut regs);
+        assert_eq!(regs.rcx, 0);
+        assert_eq!(regs.rdi, 0x5000);
+        assert_eq!(bus.read(BASE + 0x0C, 4), 0x1122_3344);
+        assert_eq!(regs.rip, 0x12);
+    }
+
+    #[test]
+    fn test_unclaimed_emulation_fails() {
+        let (mut bus, _mem) = bus();
+        let insn = decode(&[0x8B, 0x03], CpuMode::Long).unwrap();
+        let mut regs = VcpuRegisters::default();
+        assert_eq!(bus.emulate(&insn, 0x2000, &mut regs, CpuMode::Long), Err(MmioError::Unclaimed(0x2000)));
+        assert_eq!(regs.rip, 0);
+    }
+}
@@ -0,0 +1,27 @@
+// Emulated devices for guests and the buses that route accesses to them.
+
+pub mod pio;
+pub mod mmio;
+pub mod decoder;
+pub mod irq;
+pub mod console;
+pub mod serial;
+pub mod lapic;
+pub mod ioapic;
+pub mod irqchip;
+pub mod pci;
+pub mod virtio;
+pub mod switch;
+pub mod channel;
+
+pub use pio::{PortIoBus, PortIoDevice, PortIoError};
+pub use mmio::{MmioBus, MmioDevice, MmioError};
+pub use irq::{IrqLine, NoIrq, SharedIrq};
+pub use console::{ConsoleBuffer, SharedConsole};
+pub use serial::Serial16550;
+pub use lapic::LocalApic;
+pub use ioapic::IoApic;
+pub use irqchip::{IoApicPin, IrqChip, SharedIrqChip};
+pub use pci::{PciBus, PciConfig, PciFunction, SharedPciBus};
+pub use channel::{ByteChannel, SharedChannel};
+pub use switch::{SharedSwitch, SwitchPort, UplinkBridge, VirtualSwitch};
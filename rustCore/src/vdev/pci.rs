@@ -0,0 +1,648 @@
+// Emulated PCI bus 0: configuration mechanism #1 on ports 0xCF8/0xCFC, a
+// host bridge at 00:00.0, and routing of guest accesses to the memory BARs
+// the guest has programmed.
+//
+// The bus is shared between two bus registrations: `PciConfigPorts` on the
+// port I/O bus and `PciMmioWindow` over the MMIO hole BARs are placed in,
+// so functions can be moved around by the guest without re-registering
+// anything.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::mmio::{MmioBus, MmioDevice, MmioError};
+use super::pio::{size_mask, PortIoBus, PortIoDevice, PortIoError};
+
+pub const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+/// CONFIG_ADDRESS and the four CONFIG_DATA ports.
+pub const CONFIG_PORTS: u32 = 8;
+pub const CONFIG_SPACE_SIZE: usize = 256;
+
+// Configuration header offsets (type 0).
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION_ID: u16 = 0x08;
+pub const CLASS_CODE: u16 = 0x09;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const BAR0: u16 = 0x10;
+pub const SUBSYSTEM_VENDOR_ID: u16 = 0x2C;
+pub const SUBSYSTEM_ID: u16 = 0x2E;
+pub const CAPABILITIES_POINTER: u16 = 0x34;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+pub const INTERRUPT_PIN: u16 = 0x3D;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+pub const STATUS_INTERRUPT: u16 = 1 << 3;
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+pub const CAP_ID_VENDOR: u8 = 0x09;
+
+const BAR_MEMORY_64: u32 = 0b100;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+/// Capabilities start after the type 0 header.
+const FIRST_CAPABILITY: usize = 0x40;
+
+/// Bus, device and function number of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress { bus, device, function }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    Memory32,
+    /// Takes this BAR slot and the next.
+    Memory64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciError {
+    /// Device number above 31 or function number above 7.
+    InvalidAddress(PciAddress),
+    Occupied(PciAddress),
+    NotRegistered(PciAddress),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bar {
+    size: u64,
+    kind: BarKind,
+}
+
+/// A type 0 configuration space: the 256 bytes the guest sees, which of
+/// their bits it may change, and the BARs and capabilities behind them.
+pub struct PciConfig {
+    data: [u8; CONFIG_SPACE_SIZE],
+    writable: [u8; CONFIG_SPACE_SIZE],
+    bars: [Option<Bar>; 6],
+    last_capability: Option<usize>,
+    next_capability: usize,
+}
+
+impl PciConfig {
+    /// `class` is the 24-bit class code: base class, subclass and
+    /// programming interface.
+    pub fn new(vendor: u16, device: u16, class: u32, revision: u8) -> Self {
+        let mut config = PciConfig {
+            data: [0; CONFIG_SPACE_SIZE],
+            writable: [0; CONFIG_SPACE_SIZE],
+            bars: [None; 6],
+            last_capability: None,
+            next_capability: FIRST_CAPABILITY,
+        };
+        config.set(VENDOR_ID, &vendor.to_le_bytes());
+        config.set(DEVICE_ID, &device.to_le_bytes());
+        config.set(REVISION_ID, &[revision]);
+        config.set(CLASS_CODE, &class.to_le_bytes()[..3]);
+        let command = COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE;
+        config.set_writable(COMMAND, &command.to_le_bytes());
+        config.set_writable(INTERRUPT_LINE, &[0xFF]);
+        config
+    }
+
+    pub fn set_subsystem(&mut self, vendor: u16, id: u16) {
+        self.set(SUBSYSTEM_VENDOR_ID, &vendor.to_le_bytes());
+        self.set(SUBSYSTEM_ID, &id.to_le_bytes());
+    }
+
+    /// Use INTx pin `pin` (1 = INTA# .. 4 = INTD#).
+    pub fn set_interrupt_pin(&mut self, pin: u8) {
+        self.set(INTERRUPT_PIN, &[pin]);
+    }
+
+    /// Declare memory BAR `index` of `size` bytes, a power of two of at
+    /// least 16. The guest chooses where it goes.
+    pub fn add_bar(&mut self, index: usize, size: u64, kind: BarKind) {
+        assert!(size.is_power_of_two() && size >= 16, "bad BAR size {:#x}", size);
+        let offset = BAR0 + 4 * index as u16;
+        let mask = !(size - 1);
+        match kind {
+            BarKind::Memory32 => {
+                self.set_writable(offset, &(mask as u32).to_le_bytes());
+            }
+            BarKind::Memory64 => {
+                assert!(index < 5, "64-bit BAR {} has no upper half", index);
+                self.set(offset, &(BAR_MEMORY_64 | BAR_PREFETCHABLE).to_le_bytes());
+                self.set_writable(offset, &(mask as u32 & !0xF).to_le_bytes());
+                self.set_writable(offset + 4, &((mask >> 32) as u32).to_le_bytes());
+            }
+        }
+        self.bars[index] = Some(Bar { size, kind });
+    }
+
+    /// Append a capability with `body` following its ID and next pointer,
+    /// returning its offset in configuration space.
+    pub fn add_capability(&mut self, id: u8, body: &[u8]) -> u16 {
+        let offset = self.next_capability;
+        assert!(offset + 2 + body.len() <= CONFIG_SPACE_SIZE, "configuration space full");
+        self.data[offset] = id;
+        self.data[offset + 2..offset + 2 + body.len()].copy_from_slice(body);
+        match self.last_capability {
+            Some(last) => self.data[last + 1] = offset as u8,
+            None => {
+                self.data[CAPABILITIES_POINTER as usize] = offset as u8;
+                let status = self.read(STATUS, 2) as u16 | STATUS_CAPABILITIES;
+                self.set(STATUS, &status.to_le_bytes());
+            }
+        }
+        self.last_capability = Some(offset);
+        self.next_capability = (offset + 2 + body.len() + 3) & !3;
+        offset as u16
+    }
+
+    /// Overwrite bytes regardless of what the guest may write.
+    pub fn set(&mut self, offset: u16, bytes: &[u8]) {
+        let offset = offset as usize;
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Let the guest change the bits of `mask`, starting at `offset`.
+    pub fn set_writable(&mut self, offset: u16, mask: &[u8]) {
+        let offset = offset as usize;
+        self.writable[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    /// Read `size` bytes at `offset`; bytes past the end read as ones.
+    pub fn read(&self, offset: u16, size: u8) -> u32 {
+        let mut bytes = [0xFF; 4];
+        for (i, byte) in bytes.iter_mut().take(size as usize).enumerate() {
+            if let Some(&value) = self.data.get(offset as usize + i) {
+                *byte = value;
+            }
+        }
+        u32::from_le_bytes(bytes) & size_mask(size)
+    }
+
+    /// A guest write: only writable bits change.
+    pub fn write(&mut self, offset: u16, size: u8, value: u32) {
+        for (i, byte) in value.to_le_bytes().iter().take(size as usize).enumerate() {
+            let at = offset as usize + i;
+            if at < CONFIG_SPACE_SIZE {
+                let mask = self.writable[at];
+                self.data[at] = (self.data[at] & !mask) | (byte & mask);
+            }
+        }
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read(COMMAND, 2) as u16
+    }
+
+    /// Mirror a pending interrupt in the status register and return the
+    /// level the function's INTx pin should have: low while disabled.
+    pub fn update_interrupt(&mut self, pending: bool) -> bool {
+        let mut status = self.read(STATUS, 2) as u16 & !STATUS_INTERRUPT;
+        if pending {
+            status |= STATUS_INTERRUPT;
+        }
+        self.set(STATUS, &status.to_le_bytes());
+        pending && self.command() & COMMAND_INTX_DISABLE == 0
+    }
+
+    /// Where BAR `index` currently decodes, if it exists and memory
+    /// decoding is on.
+    pub fn bar_address(&self, index: usize) -> Option<u64> {
+        let bar = self.bars.get(index).copied().flatten()?;
+        if self.command() & COMMAND_MEMORY == 0 {
+            return None;
+        }
+        let offset = BAR0 + 4 * index as u16;
+        let low = (self.read(offset, 4) & !0xF) as u64;
+        Some(match bar.kind {
+            BarKind::Memory32 => low,
+            BarKind::Memory64 => low | (self.read(offset + 4, 4) as u64) << 32,
+        })
+    }
+
+    pub fn bar_size(&self, index: usize) -> Option<u64> {
+        self.bars.get(index).copied().flatten().map(|bar| bar.size)
+    }
+}
+
+/// A device function on the bus.
+///
+/// Configuration accesses go through [`PciConfig`] unless a function needs
+/// to see them; BAR accesses are given as an offset into the BAR.
+pub trait PciFunction: Send {
+    fn config(&self) -> &PciConfig;
+    fn config_mut(&mut self) -> &mut PciConfig;
+
+    fn config_read(&mut self, offset: u16, size: u8) -> u32 {
+        self.config().read(offset, size)
+    }
+
+    fn config_write(&mut self, offset: u16, size: u8, value: u32) {
+        self.config_mut().write(offset, size, value);
+    }
+
+    fn bar_read(&mut self, bar: usize, offset: u64, size: u8) -> u64;
+    fn bar_write(&mut self, bar: usize, offset: u64, size: u8, value: u64);
+
+    fn name(&self) -> &'static str {
+        "pci-function"
+    }
+}
+
+/// The host bridge Linux and firmware look for at 00:00.0 before trusting
+/// configuration mechanism #1.
+pub struct HostBridge {
+    config: PciConfig,
+}
+
+impl HostBridge {
+    pub fn new() -> Self {
+        // Red Hat, Inc. QEMU PCIe host bridge.
+        HostBridge { config: PciConfig::new(0x1B36, 0x0008, 0x06_00_00, 0) }
+    }
+}
+
+impl Default for HostBridge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PciFunction for HostBridge {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn bar_read(&mut self, _bar: usize, _offset: u64, size: u8) -> u64 {
+        crate::hypervisor::operand_mask(size)
+    }
+
+    fn bar_write(&mut self, _bar: usize, _offset: u64, _size: u8, _value: u64) {}
+
+    fn name(&self) -> &'static str {
+        "host-bridge"
+    }
+}
+
+pub type SharedPciBus = Arc<Mutex<PciBus>>;
+
+pub struct PciBus {
+    functions: Vec<(PciAddress, Box<dyn PciFunction>)>,
+    /// The last value written to CONFIG_ADDRESS.
+    address: u32,
+}
+
+impl PciBus {
+    /// A bus with only the host bridge on it.
+    pub fn new() -> Self {
+        let mut bus = PciBus { functions: Vec::new(), address: 0 };
+        bus.add(PciAddress::new(0, 0, 0), Box::new(HostBridge::new())).unwrap();
+        bus
+    }
+
+    pub fn shared() -> SharedPciBus {
+        Arc::new(Mutex::new(Self::new()))
+    }
+
+    pub fn add(&mut self, address: PciAddress, function: Box<dyn PciFunction>) -> Result<(), PciError> {
+        if address.device > 31 || address.function > 7 {
+            return Err(PciError::InvalidAddress(address));
+        }
+        if self.functions.iter().any(|(existing, _)| *existing == address) {
+            return Err(PciError::Occupied(address));
+        }
+        self.functions.push((address, function));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, address: PciAddress) -> Result<Box<dyn PciFunction>, PciError> {
+        let index = self.functions.iter().position(|(existing, _)| *existing == address);
+        let index = index.ok_or(PciError::NotRegistered(address))?;
+        Ok(self.functions.remove(index).1)
+    }
+
+    /// The lowest free device number on bus 0.
+    pub fn free_slot(&self) -> Option<PciAddress> {
+        (0..32).map(|device| PciAddress::new(0, device, 0)).find(|a| !self.functions.iter().any(|(b, _)| b == a))
+    }
+
+    pub fn function_mut(&mut self, address: PciAddress) -> Option<&mut dyn PciFunction> {
+        let (_, function) = self.functions.iter_mut().find(|(existing, _)| *existing == address)?;
+        Some(function.as_mut())
+    }
+
+    /// Configuration read; absent functions read as all ones.
+    pub fn config_read(&mut self, address: PciAddress, offset: u16, size: u8) -> u32 {
+        match self.function_mut(address) {
+            Some(function) => function.config_read(offset, size) & size_mask(size),
+            None => size_mask(size),
+        }
+    }
+
+    pub fn config_write(&mut self, address: PciAddress, offset: u16, size: u8, value: u32) {
+        if let Some(function) = self.function_mut(address) {
+            function.config_write(offset, size, value & size_mask(size));
+        }
+    }
+
+    /// The function, BAR and offset decoding guest-physical `gpa`.
+    fn decode(&mut self, gpa: u64) -> Option<(&mut dyn PciFunction, usize, u64)> {
+        for (_, function) in self.functions.iter_mut() {
+            for bar in 0..6 {
+                let config = function.config();
+                if let (Some(base), Some(size)) = (config.bar_address(bar), config.bar_size(bar)) {
+                    if base != 0 && gpa >= base && gpa - base < size {
+                        return Some((function.as_mut(), bar, gpa - base));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Read from whatever BAR decodes `gpa`; nothing there reads as ones.
+    pub fn mmio_read(&mut self, gpa: u64, size: u8) -> u64 {
+        match self.decode(gpa) {
+            Some((function, bar, offset)) => function.bar_read(bar, offset, size),
+            None => crate::hypervisor::operand_mask(size),
+        }
+    }
+
+    pub fn mmio_write(&mut self, gpa: u64, size: u8, value: u64) {
+        if let Some((function, bar, offset)) = self.decode(gpa) {
+            function.bar_write(bar, offset, size, value);
+        }
+    }
+
+    /// The function and register CONFIG_ADDRESS selects for a data access
+    /// at byte `offset` of CONFIG_DATA, if enabled.
+    fn selected(&self, offset: u16) -> Option<(PciAddress, u16)> {
+        if self.address & (1 << 31) == 0 {
+            return None;
+        }
+        let bus = (self.address >> 16) as u8;
+        let device = (self.address >> 11) as u8 & 0x1F;
+        let function = (self.address >> 8) as u8 & 0x7;
+        let address = PciAddress::new(bus, device, function);
+        Some((address, (self.address & 0xFC) as u16 + offset))
+    }
+}
+
+impl Default for PciBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CONFIG_ADDRESS at 0xCF8 and CONFIG_DATA at 0xCFC.
+pub struct PciConfigPorts(pub SharedPciBus);
+
+impl PortIoDevice for PciConfigPorts {
+    fn read(&mut self, offset: u16, size: u8) -> u32 {
+        let mut bus = self.0.lock();
+        match offset {
+            0 if size == 4 => bus.address,
+            4..=7 => match bus.selected(offset - 4) {
+                Some((address, register)) => bus.config_read(address, register, size),
+                None => size_mask(size),
+            },
+            _ => size_mask(size),
+        }
+    }
+
+    fn write(&mut self, offset: u16, size: u8, value: u32) {
+        let mut bus = self.0.lock();
+        match offset {
+            0 if size == 4 => bus.address = value & 0x80FF_FFFC,
+            4..=7 => {
+                if let Some((address, register)) = bus.selected(offset - 4) {
+                    bus.config_write(address, register, size, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "pci-config"
+    }
+}
+
+/// The MMIO hole BARs are assigned from, registered at `base`.
+pub struct PciMmioWindow {
+    bus: SharedPciBus,
+    base: u64,
+}
+
+impl MmioDevice for PciMmioWindow {
+    fn read(&mut self, offset: u64, size: u8) -> u64 {
+        self.bus.lock().mmio_read(self.base + offset, size)
+    }
+
+    fn write(&mut self, offset: u64, size: u8, value: u64) {
+        self.bus.lock().mmio_write(self.base + offset, size, value);
+    }
+
+    fn name(&self) -> &'static str {
+        "pci-mmio"
+    }
+}
+
+/// Register the configuration ports and an MMIO window of `len` bytes at
+/// `base` for `bus`. The window must also be left unbacked in the guest's
+/// memory layout.
+pub fn register_pci(
+    pio: &mut PortIoBus,
+    mmio: &mut MmioBus,
+    bus: &SharedPciBus,
+    base: u64,
+    len: u64,
+) -> Result<(), PciRegisterError> {
+    pio.register(CONFIG_ADDRESS_PORT, CONFIG_PORTS, Box::new(PciConfigPorts(bus.clone())))?;
+    mmio.register(base, len, Box::new(PciMmioWindow { bus: bus.clone(), base }))?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciRegisterError {
+    Pio(PortIoError),
+    Mmio(MmioError),
+}
+
+impl From<PortIoError> for PciRegisterError {
+    fn from(err: PortIoError) -> Self {
+        PciRegisterError::Pio(err)
+    }
+}
+
+impl From<MmioError> for PciRegisterError {
+    fn from(err: MmioError) -> Self {
+        PciRegisterError::Mmio(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Writes = Arc<Mutex<Vec<(usize, u64, u64)>>>;
+
+    /// One 32-bit and one 64-bit BAR whose registers read back the offset.
+    struct Function {
+        config: PciConfig,
+        writes: Writes,
+    }
+
+    impl PciFunction for Function {
+        fn config(&self) -> &PciConfig {
+            &self.config
+        }
+
+        fn config_mut(&mut self) -> &mut PciConfig {
+            &mut self.config
+        }
+
+        fn bar_read(&mut self, bar: usize, offset: u64, _size: u8) -> u64 {
+            (bar as u64) << 16 | offset
+        }
+
+        fn bar_write(&mut self, bar: usize, offset: u64, _size: u8, value: u64) {
+            self.writes.lock().push((bar, offset, value));
+        }
+    }
+
+    fn function(writes: &Writes) -> Box<Function> {
+        let mut config = PciConfig::new(0x1234, 0x5678, 0x02_00_00, 3);
+        config.add_bar(0, 0x1000, BarKind::Memory32);
+        config.add_bar(2, 0x4000, BarKind::Memory64);
+        config.set_interrupt_pin(1);
+        Box::new(Function { config, writes: writes.clone() })
+    }
+
+    fn config_address(device: u8, register: u8) -> u32 {
+        1 << 31 | (device as u32) << 11 | register as u32
+    }
+
+    fn setup() -> (PortIoBus, MmioBus, SharedPciBus, Writes) {
+        let writes = Writes::default();
+        let bus = PciBus::shared();
+        bus.lock().add(PciAddress::new(0, 3, 0), function(&writes)).unwrap();
+        let mut pio = PortIoBus::new();
+        let mut mmio = MmioBus::new();
+        register_pci(&mut pio, &mut mmio, &bus, 0xE000_0000, 0x1000_0000).unwrap();
+        (pio, mmio, bus, writes)
+    }
+
+    fn cfg_read(pio: &mut PortIoBus, device: u8, register: u8, size: u8) -> u32 {
+        pio.write(0xCF8, 4, config_address(device, register & !3));
+        pio.read(0xCFC + (register & 3) as u16, size)
+    }
+
+    fn cfg_write(pio: &mut PortIoBus, device: u8, register: u8, size: u8, value: u32) {
+        pio.write(0xCF8, 4, config_address(device, register & !3));
+        pio.write(0xCFC + (register & 3) as u16, size, value);
+    }
+
+    #[test]
+    fn test_enumeration() {
+        let (mut pio, ..) = setup();
+        assert_eq!(cfg_read(&mut pio, 0, 0, 4), 0x0008_1B36);
+        assert_eq!(cfg_read(&mut pio, 0, 0x0B, 1), 0x06);
+        assert_eq!(cfg_read(&mut pio, 3, 0, 4), 0x5678_1234);
+        assert_eq!(cfg_read(&mut pio, 3, 0x08, 4), 0x0200_0003);
+        assert_eq!(cfg_read(&mut pio, 3, 0x3D, 1), 1);
+        assert_eq!(cfg_read(&mut pio, 4, 0, 4), 0xFFFF_FFFF);
+        assert_eq!(cfg_read(&mut pio, 3, 0x02, 2), 0x5678);
+        // Disabled CONFIG_ADDRESS reads nothing.
+        pio.write(0xCF8, 4, 0);
+        assert_eq!(pio.read(0xCFC, 4), 0xFFFF_FFFF);
+        pio.write(0xCF8, 4, 0x8000_1803);
+        assert_eq!(pio.read(0xCF8, 4), 0x8000_1800);
+    }
+
+    #[test]
+    fn test_bar_sizing_and_routing() {
+        let (mut pio, mut mmio, bus, writes) = setup();
+        cfg_write(&mut pio, 3, 0x10, 4, 0xFFFF_FFFF);
+        assert_eq!(cfg_read(&mut pio, 3, 0x10, 4), 0xFFFF_F000);
+        cfg_write(&mut pio, 3, 0x18, 4, 0xFFFF_FFFF);
+        cfg_write(&mut pio, 3, 0x1C, 4, 0xFFFF_FFFF);
+        assert_eq!(cfg_read(&mut pio, 3, 0x18, 4), 0xFFFF_C00C);
+        assert_eq!(cfg_read(&mut pio, 3, 0x1C, 4), 0xFFFF_FFFF);
+
+        cfg_write(&mut pio, 3, 0x10, 4, 0xE000_0000);
+        cfg_write(&mut pio, 3, 0x18, 4, 0xE001_0000);
+        cfg_write(&mut pio, 3, 0x1C, 4, 0);
+        // Nothing decodes until memory space is enabled.
+        assert_eq!(mmio.read(0xE000_0010, 4), 0xFFFF_FFFF);
+        cfg_write(&mut pio, 3, 0x04, 2, COMMAND_MEMORY as u32);
+        assert_eq!(mmio.read(0xE000_0010, 4), 0x10);
+        assert_eq!(mmio.read(0xE001_3FF8, 8), 0x2_3FF8);
+        assert_eq!(mmio.read(0xE000_1000, 4), 0xFFFF_FFFF);
+        mmio.write(0xE001_0004, 4, 7);
+        assert_eq!(*writes.lock(), [(2, 4, 7)]);
+
+        // Moving a BAR moves what decodes.
+        cfg_write(&mut pio, 3, 0x10, 4, 0xE000_8000);
+        assert_eq!(mmio.read(0xE000_0010, 4), 0xFFFF_FFFF);
+        assert_eq!(mmio.read(0xE000_8010, 4), 0x10);
+        let mut bus = bus.lock();
+        let function = bus.function_mut(PciAddress::new(0, 3, 0)).unwrap();
+        assert_eq!(function.config().bar_address(2), Some(0xE001_0000));
+    }
+
+    #[test]
+    fn test_read_only_fields_and_capabilities() {
+        let mut config = PciConfig::new(0x1AF4, 0x1041, 0x02_00_00, 1);
+        config.write(VENDOR_ID, 4, 0);
+        assert_eq!(config.read(VENDOR_ID, 4), 0x1041_1AF4);
+        config.write(COMMAND, 2, 0xFFFF);
+        assert_eq!(config.command(), 0x0407);
+        config.write(INTERRUPT_LINE, 1, 11);
+        assert_eq!(config.read(INTERRUPT_LINE, 1), 11);
+
+        let first = config.add_capability(CAP_ID_VENDOR, &[14, 1, 2, 3]);
+        let second = config.add_capability(CAP_ID_VENDOR, &[3]);
+        assert_eq!((first, second), (0x40, 0x48));
+        assert_eq!(config.read(CAPABILITIES_POINTER, 1), 0x40);
+        assert_eq!(config.read(STATUS, 2) as u16 & STATUS_CAPABILITIES, STATUS_CAPABILITIES);
+        assert_eq!(config.read(0x40, 4), 0x010E_4809);
+        assert_eq!(config.read(0x48, 4), 0x0003_0009);
+
+        assert!(!config.update_interrupt(true));
+        assert_eq!(config.read(STATUS, 2) as u16 & STATUS_INTERRUPT, STATUS_INTERRUPT);
+        config.write(COMMAND, 2, 0);
+        assert!(config.update_interrupt(true));
+        assert!(!config.update_interrupt(false));
+    }
+
+    #[test]
+    fn test_bus_slots() {
+        let writes = Writes::default();
+        let mut bus = PciBus::new();
+        assert_eq!(bus.free_slot(), Some(PciAddress::new(0, 1, 0)));
+        bus.add(PciAddress::new(0, 1, 0), function(&writes)).unwrap();
+        let occupied = bus.add(PciAddress::new(0, 1, 0), function(&writes));
+        assert_eq!(occupied.err(), Some(PciError::Occupied(PciAddress::new(0, 1, 0))));
+        let invalid = bus.add(PciAddress::new(0, 32, 0), function(&writes));
+        assert_eq!(invalid.err(), Some(PciError::InvalidAddress(PciAddress::new(0, 32, 0))));
+        assert_eq!(bus.free_slot(), Some(PciAddress::new(0, 2, 0)));
+        assert!(bus.remove(PciAddress::new(0, 1, 0)).is_ok());
+        assert!(bus.remove(PciAddress::new(0, 1, 0)).is_err());
+    }
+}
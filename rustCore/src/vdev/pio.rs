@@ -0,0 +1,332 @@
+// Guest port I/O bus: routes IN/OUT to emulated devices by port range.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+
+use crate::hypervisor::{Disposition, ExitResponse, IoDirection, VcpuId, VmExit, VmExitHandler};
+
+/// A device model reachable through guest port I/O.
+///
+/// `offset` is relative to the base port the device was registered at, and
+/// `size` is 1, 2 or 4 bytes.
+pub trait PortIoDevice: Send {
+    fn read(&mut self, offset: u16, size: u8) -> u32;
+    fn write(&mut self, offset: u16, size: u8, value: u32);
+
+    fn name(&self) -> &'static str {
+        "port-io-device"
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortIoError {
+    /// Zero-length range, or one running past port 0xFFFF.
+    InvalidRange { base: u16, len: u32 },
+    /// The range overlaps the device registered at `existing`.
+    Overlap { base: u16, existing: u16 },
+    NotRegistered(u16),
+}
+
+/// Accesses seen on a port that no device claims.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UnclaimedPort {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+struct Mapping {
+    len: u32,
+    device: Box<dyn PortIoDevice>,
+}
+
+/// All ones for an access of `size` bytes.
+pub fn size_mask(size: u8) -> u32 {
+    match size {
+        1 => 0xFF,
+        2 => 0xFFFF,
+        _ => 0xFFFF_FFFF,
+    }
+}
+
+pub struct PortIoBus {
+    mappings: BTreeMap<u16, Mapping>,
+    unclaimed: BTreeMap<u16, UnclaimedPort>,
+    log_unclaimed: bool,
+}
+
+impl PortIoBus {
+    pub fn new() -> Self {
+        PortIoBus { mappings: BTreeMap::new(), unclaimed: BTreeMap::new(), log_unclaimed: true }
+    }
+
+    /// Print the first access to every unclaimed port (on by default).
+    pub fn set_log_unclaimed(&mut self, enabled: bool) {
+        self.log_unclaimed = enabled;
+    }
+
+    /// Claim ports `base .. base + len` for `device`.
+    pub fn register(&mut self, base: u16, len: u32, device: Box<dyn PortIoDevice>) -> Result<(), PortIoError> {
+        if len == 0 || base as u32 + len > 0x1_0000 {
+            return Err(PortIoError::InvalidRange { base, len });
+        }
+        let end = base as u32 + len;
+        let previous = self.mappings.range(..=base).next_back();
+        if let Some((&existing, mapping)) = previous {
+            if existing as u32 + mapping.len > base as u32 {
+                return Err(PortIoError::Overlap { base, existing });
+            }
+        }
+        if let Some((&existing, _)) = self.mappings.range(base..).next() {
+            if (existing as u32) < end {
+                return Err(PortIoError::Overlap { base, existing });
+            }
+        }
+        self.mappings.insert(base, Mapping { len, device });
+        Ok(())
+    }
+
+    /// Remove the device registered at `base` and hand it back.
+    pub fn unregister(&mut self, base: u16) -> Result<Box<dyn PortIoDevice>, PortIoError> {
+        self.mappings.remove(&base).map(|m| m.device).ok_or(PortIoError::NotRegistered(base))
+    }
+
+    /// Name of the device claiming `port`, if any.
+    pub fn device_at(&self, port: u16) -> Option<&'static str> {
+        self.mappings
+            .range(..=port)
+            .next_back()
+            .filter(|(&base, m)| (port as u32) < base as u32 + m.len)
+            .map(|(_, m)| m.device.name())
+    }
+
+    pub fn is_claimed(&self, port: u16) -> bool {
+        self.device_at(port).is_some()
+    }
+
+    /// Ports that were accessed with no device behind them.
+    pub fn unclaimed_ports(&self) -> impl Iterator<Item = (u16, &UnclaimedPort)> {
+        self.unclaimed.iter().map(|(&port, stats)| (port, stats))
+    }
+
+    pub fn clear_unclaimed(&mut self) {
+        self.unclaimed.clear();
+    }
+
+    fn lookup(&mut self, port: u16) -> Option<(u16, &mut Mapping)> {
+        let (&base, mapping) = self.mappings.range_mut(..=port).next_back()?;
+        if (port as u32) < base as u32 + mapping.len {
+            Some((port - base, mapping))
+        } else {
+            None
+        }
+    }
+
+    fn note_unclaimed(&mut self, port: u16, direction: IoDirection) {
+        let log = self.log_unclaimed;
+        let stats = self.unclaimed.entry(port).or_default();
+        if log && stats.reads == 0 && stats.writes == 0 {
+            crate::println!("pio: unclaimed {:?} on port {:#06x}", direction, port);
+        }
+        match direction {
+            IoDirection::In => stats.reads += 1,
+            IoDirection::Out => stats.writes += 1,
+        }
+    }
+
+    /// IN from `port`. Unclaimed ports read as all ones.
+    pub fn read(&mut self, port: u16, size: u8) -> u32 {
+        match self.lookup(port) {
+            Some((offset, mapping)) => mapping.device.read(offset, size) & size_mask(size),
+            None => {
+                self.note_unclaimed(port, IoDirection::In);
+                size_mask(size)
+            }
+        }
+    }
+
+    /// OUT to `port`. Writes to unclaimed ports are dropped.
+    pub fn write(&mut self, port: u16, size: u8, value: u32) {
+        match self.lookup(port) {
+            Some((offset, mapping)) => mapping.device.write(offset, size, value & size_mask(size)),
+            None => self.note_unclaimed(port, IoDirection::Out),
+        }
+    }
+
+    /// INS: fill `buf` with `buf.len() / size` consecutive reads of `port`.
+    pub fn read_string(&mut self, port: u16, size: u8, buf: &mut [u8]) {
+        for chunk in buf.chunks_exact_mut(size as usize) {
+            let value = self.read(port, size);
+            chunk.copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        }
+    }
+
+    /// OUTS: write every `size`-byte element of `data` to `port`.
+    pub fn write_string(&mut self, port: u16, size: u8, data: &[u8]) {
+        for chunk in data.chunks_exact(size as usize) {
+            let mut bytes = [0u8; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            self.write(port, size, u32::from_le_bytes(bytes));
+        }
+    }
+
+    /// Serve a backend-neutral I/O exit. Returns `None` for other exits.
+    pub fn handle_exit(&mut self, exit: &VmExit) -> Option<ExitResponse> {
+        match *exit {
+            VmExit::Io { port, size, direction: IoDirection::In, .. } => {
+                Some(ExitResponse::Data(self.read(port, size) as u64))
+            }
+            VmExit::Io { port, size, direction: IoDirection::Out, data } => {
+                self.write(port, size, data);
+                Some(ExitResponse::None)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for PortIoBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs a vCPU with only port I/O emulated; any other exit stops it.
+impl VmExitHandler for PortIoBus {
+    fn handle(&mut self, _vcpu: VcpuId, exit: &VmExit) -> Disposition {
+        match self.handle_exit(exit) {
+            Some(response) => Disposition::Resume(response),
+            None => Disposition::Stop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    use crate::hypervisor::{HypervisorBackend, MockBackend, SpecialRegisters, Segment, Vm};
+
+    type Log = Arc<Mutex<Vec<(IoDirection, u16, u8, u32)>>>;
+
+    /// Records every access and reads back `0x10 + offset`.
+    struct FakeDevice {
+        log: Log,
+    }
+
+    impl PortIoDevice for FakeDevice {
+        fn read(&mut self, offset: u16, size: u8) -> u32 {
+            self.log.lock().push((IoDirection::In, offset, size, 0));
+            0x1234_5610 + offset as u32
+        }
+
+        fn write(&mut self, offset: u16, size: u8, value: u32) {
+            self.log.lock().push((IoDirection::Out, offset, size, value));
+        }
+
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+    }
+
+    fn fake() -> (Box<dyn PortIoDevice>, Log) {
+        let log: Log = Arc::new(Mutex::new(Vec::new()));
+        (Box::new(FakeDevice { log: log.clone() }), log)
+    }
+
+    #[test]
+    fn test_routing_and_access_sizes() {
+        let mut bus = PortIoBus::new();
+        let (dev, log) = fake();
+        bus.register(0x3F8, 8, dev).unwrap();
+
+        assert_eq!(bus.read(0x3F8, 1), 0x10);
+        assert_eq!(bus.read(0x3FA, 2), 0x5612);
+        assert_eq!(bus.read(0x3FC, 4), 0x1234_5614);
+        bus.write(0x3F9, 1, 0x1FF);
+        assert_eq!(
+            *log.lock(),
+            [
+                (IoDirection::In, 0, 1, 0),
+                (IoDirection::In, 2, 2, 0),
+                (IoDirection::In, 4, 4, 0),
+                (IoDirection::Out, 1, 1, 0xFF),
+            ]
+        );
+        assert_eq!(bus.device_at(0x3FF), Some("fake"));
+        assert!(!bus.is_claimed(0x400));
+    }
+
+    #[test]
+    fn test_overlapping_ranges_rejected() {
+        let mut bus = PortIoBus::new();
+        bus.register(0x60, 1, fake().0).unwrap();
+        bus.register(0x64, 1, fake().0).unwrap();
+        assert_eq!(bus.register(0x5E, 4, fake().0), Err(PortIoError::Overlap { base: 0x5E, existing: 0x60 }));
+        assert_eq!(bus.register(0x64, 1, fake().0), Err(PortIoError::Overlap { base: 0x64, existing: 0x64 }));
+        assert_eq!(bus.register(0xFFFF, 2, fake().0), Err(PortIoError::InvalidRange { base: 0xFFFF, len: 2 }));
+        assert_eq!(bus.register(0x70, 0, fake().0), Err(PortIoError::InvalidRange { base: 0x70, len: 0 }));
+        bus.register(0x61, 3, fake().0).unwrap();
+        assert!(bus.unregister(0x61).is_ok());
+        assert_eq!(bus.unregister(0x61).err(), Some(PortIoError::NotRegistered(0x61)));
+    }
+
+    #[test]
+    fn test_unclaimed_ports() {
+        let mut bus = PortIoBus::new();
+        bus.set_log_unclaimed(false);
+        assert_eq!(bus.read(0x80, 1), 0xFF);
+        assert_eq!(bus.read(0x80, 2), 0xFFFF);
+        assert_eq!(bus.read(0xCFC, 4), 0xFFFF_FFFF);
+        bus.write(0x80, 1, 0x42);
+        let unclaimed: Vec<_> = bus.unclaimed_ports().map(|(port, stats)| (port, *stats)).collect();
+        assert_eq!(
+            unclaimed,
+            [(0x80, UnclaimedPort { reads: 2, writes: 1 }), (0xCFC, UnclaimedPort { reads: 1, writes: 0 })]
+        );
+        bus.clear_unclaimed();
+        assert_eq!(bus.unclaimed_ports().count(), 0);
+    }
+
+    #[test]
+    fn test_string_io() {
+        let mut bus = PortIoBus::new();
+        let (dev, log) = fake();
+        bus.register(0x1F0, 8, dev).unwrap();
+
+        let mut buf = [0u8; 6];
+        bus.read_string(0x1F0, 2, &mut buf);
+        assert_eq!(buf, [0x10, 0x56, 0x10, 0x56, 0x10, 0x56]);
+        bus.write_string(0x1F0, 2, &[0x01, 0x02, 0x03, 0x04]);
+        let writes: Vec<_> = log.lock().iter().filter(|e| e.0 == IoDirection::Out).map(|e| e.3).collect();
+        assert_eq!(writes, [0x0201, 0x0403]);
+    }
+
+    #[test]
+    fn test_guest_rep_outsb_through_bus() {
+        // mov dx, 0x3f8; mov si, 0x20; mov cx, 3; rep outsb; in al, dx; hlt
+        let code = [0xBA, 0xF8, 0x03, 0xBE, 0x20, 0x00, 0xB9, 0x03, 0x00, 0xF3, 0x6E, 0xEC, 0xF4];
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        backend.create_vcpu(0).unwrap();
+        backend.write_guest(0x1000, &code).unwrap();
+        backend.write_guest(0x1020, b"abc").unwrap();
+        let mut sregs = SpecialRegisters::real_mode(0x100);
+        sregs.ds = Segment::real_mode(0x100, false);
+        backend.set_special_registers(0, &sregs).unwrap();
+
+        let mut bus = PortIoBus::new();
+        let (dev, log) = fake();
+        bus.register(0x3F8, 8, dev).unwrap();
+        let mut vm = Vm::new(backend);
+        assert_eq!(vm.run_vcpu(0, &mut bus).unwrap(), VmExit::Hlt);
+
+        let log = log.lock();
+        let bytes: Vec<u8> = log.iter().filter(|e| e.0 == IoDirection::Out).map(|e| e.3 as u8).collect();
+        assert_eq!(bytes, b"abc");
+        assert_eq!(log.last(), Some(&(IoDirection::In, 0, 1, 0)));
+        assert_eq!(vm.backend().get_registers(0).unwrap().rax & 0xFF, 0x10);
+    }
+}
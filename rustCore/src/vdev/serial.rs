@@ -0,0 +1,426 @@
+// 16550A UART model, normally registered at COM1 so a guest booted with
+// `console=ttyS0` writes to the VM's console buffer.
+//
+// Transmission is instantaneous: a byte written to THR lands in the console
+// (or the receive FIFO in loopback mode) at once, so THR and the shift
+// register are always empty when read back.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use super::console::SharedConsole;
+use super::irq::IrqLine;
+use super::pio::{PortIoBus, PortIoDevice, PortIoError};
+
+pub const COM1_BASE: u16 = 0x3F8;
+pub const COM1_IRQ: u8 = 4;
+/// Number of ports a UART occupies.
+pub const UART_PORTS: u32 = 8;
+
+const FIFO_SIZE: usize = 16;
+
+// Register offsets.
+const RBR_THR: u16 = 0;
+const IER: u16 = 1;
+const IIR_FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const MSR: u16 = 6;
+const SCR: u16 = 7;
+
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_RLS: u8 = 1 << 2;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_RLS: u8 = 0x06;
+const IIR_RDA: u8 = 0x04;
+const IIR_TIMEOUT: u8 = 0x0C;
+const IIR_THRE: u8 = 0x02;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOP: u8 = 1 << 4;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_OE: u8 = 1 << 1;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+
+pub struct Serial16550 {
+    console: SharedConsole,
+    irq: Box<dyn IrqLine>,
+    irq_level: bool,
+    rx: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    /// Error bits of LSR, cleared when LSR is read.
+    lsr_errors: u8,
+    scr: u8,
+    divisor: u16,
+    /// THR became empty and the guest has not yet seen it through IIR.
+    thre_pending: bool,
+}
+
+impl Serial16550 {
+    pub fn new(console: SharedConsole, irq: Box<dyn IrqLine>) -> Self {
+        Serial16550 {
+            console,
+            irq,
+            irq_level: false,
+            rx: VecDeque::new(),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: MCR_OUT2,
+            lsr_errors: 0,
+            scr: 0,
+            // 115200 baud from the 1.8432 MHz reference clock.
+            divisor: 1,
+            thre_pending: false,
+        }
+    }
+
+    /// Divisor latch value programmed by the guest.
+    pub fn divisor(&self) -> u16 {
+        self.divisor
+    }
+
+    /// Whether the interrupt output is currently asserted.
+    pub fn irq_level(&self) -> bool {
+        self.irq_level
+    }
+
+    /// Deliver bytes to the receiver, as if they came down the wire.
+    /// Bytes that do not fit in the FIFO are lost and flag an overrun.
+    pub fn receive(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push_rx(byte);
+        }
+        self.update_irq();
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_ENABLE != 0
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() { FIFO_SIZE } else { 1 }
+    }
+
+    fn rx_trigger(&self) -> usize {
+        match self.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn push_rx(&mut self, byte: u8) {
+        if self.rx.len() >= self.rx_capacity() {
+            self.lsr_errors |= LSR_OE;
+        } else {
+            self.rx.push_back(byte);
+        }
+    }
+
+    /// Move queued console input into the receive FIFO without overrunning it.
+    fn pull_input(&mut self) {
+        if self.mcr & MCR_LOOP != 0 {
+            return;
+        }
+        let mut console = self.console.lock();
+        while self.rx.len() < self.rx_capacity() {
+            match console.pop_input() {
+                Some(byte) => self.rx.push_back(byte),
+                None => break,
+            }
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            self.push_rx(byte);
+        } else {
+            self.console.lock().write(&[byte]);
+        }
+        self.thre_pending = true;
+    }
+
+    /// Highest-priority pending interrupt, in IIR encoding without the
+    /// FIFO bits.
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RLS != 0 && self.lsr_errors != 0 {
+            IIR_RLS
+        } else if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            // Data below the trigger level is reported as a character
+            // timeout straight away; there is no clock to wait four
+            // character times on.
+            if self.fifo_enabled() && self.rx.len() < self.rx_trigger() { IIR_TIMEOUT } else { IIR_RDA }
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn update_irq(&mut self) {
+        let level = self.interrupt_id() != IIR_NONE;
+        if level != self.irq_level {
+            self.irq_level = level;
+            self.irq.set_level(level);
+        }
+    }
+
+    fn modem_status(&self) -> u8 {
+        if self.mcr & MCR_LOOP != 0 {
+            let mut msr = 0;
+            if self.mcr & MCR_RTS != 0 {
+                msr |= MSR_CTS;
+            }
+            if self.mcr & MCR_DTR != 0 {
+                msr |= MSR_DSR;
+            }
+            if self.mcr & MCR_OUT1 != 0 {
+                msr |= MSR_RI;
+            }
+            if self.mcr & MCR_OUT2 != 0 {
+                msr |= MSR_DCD;
+            }
+            msr
+        } else {
+            // A terminal is always attached.
+            MSR_CTS | MSR_DSR | MSR_DCD
+        }
+    }
+
+    fn read_register(&mut self, offset: u16) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor as u8,
+            RBR_THR => self.rx.pop_front().unwrap_or(0),
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                if self.fifo_enabled() { id | IIR_FIFO_ENABLED } else { id }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let mut lsr = LSR_THRE | LSR_TEMT | self.lsr_errors;
+                if !self.rx.is_empty() {
+                    lsr |= LSR_DR;
+                }
+                self.lsr_errors = 0;
+                lsr
+            }
+            MSR => self.modem_status(),
+            SCR => self.scr,
+            _ => 0xFF,
+        }
+    }
+
+    fn write_register(&mut self, offset: u16, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            RBR_THR => self.transmit(value),
+            IER if dlab => self.divisor = (self.divisor & 0x00FF) | ((value as u16) << 8),
+            IER => {
+                let value = value & 0x0F;
+                // Enabling the THRE interrupt with THR empty raises it at once.
+                if value & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value;
+            }
+            IIR_FCR => {
+                if (value ^ self.fcr) & FCR_ENABLE != 0 || value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                // The clear bits are self-resetting; keep enable and trigger.
+                self.fcr = value & 0xC1;
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1F,
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+}
+
+/// Register a UART at COM1 on `bus`, with its output going to `console`.
+pub fn register_com1(bus: &mut PortIoBus, console: SharedConsole, irq: Box<dyn IrqLine>) -> Result<(), PortIoError> {
+    bus.register(COM1_BASE, UART_PORTS, Box::new(Serial16550::new(console, irq)))
+}
+
+impl PortIoDevice for Serial16550 {
+    fn read(&mut self, offset: u16, _size: u8) -> u32 {
+        self.pull_input();
+        let value = self.read_register(offset);
+        self.update_irq();
+        value as u32
+    }
+
+    fn write(&mut self, offset: u16, _size: u8, value: u32) {
+        self.pull_input();
+        self.write_register(offset, value as u8);
+        self.update_irq();
+    }
+
+    fn name(&self) -> &'static str {
+        "serial-16550"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vdev::console::ConsoleBuffer;
+    use crate::vdev::irq::SharedIrq;
+
+    fn com1() -> (PortIoBus, SharedConsole, SharedIrq) {
+        let console = ConsoleBuffer::shared(1024);
+        let irq = SharedIrq::new();
+        let mut bus = PortIoBus::new();
+        register_com1(&mut bus, console.clone(), Box::new(irq.clone())).unwrap();
+        (bus, console, irq)
+    }
+
+    fn outb(bus: &mut PortIoBus, reg: u16, value: u8) {
+        bus.write(COM1_BASE + reg, 1, value as u32);
+    }
+
+    fn inb(bus: &mut PortIoBus, reg: u16) -> u8 {
+        bus.read(COM1_BASE + reg, 1) as u8
+    }
+
+    #[test]
+    fn test_output_reaches_console() {
+        let (mut bus, console, _) = com1();
+        // The usual early-console setup: 8N1 at 115200.
+        outb(&mut bus, LCR, 0x80);
+        outb(&mut bus, RBR_THR, 1);
+        outb(&mut bus, IER, 0);
+        outb(&mut bus, LCR, 0x03);
+        for &byte in b"Linux version" {
+            assert_ne!(inb(&mut bus, LSR) & LSR_THRE, 0);
+            outb(&mut bus, RBR_THR, byte);
+        }
+        assert_eq!(console.lock().text(), "Linux version");
+        assert_eq!(inb(&mut bus, LCR), 0x03);
+    }
+
+    #[test]
+    fn test_divisor_latch() {
+        let console = ConsoleBuffer::shared(16);
+        let mut uart = Serial16550::new(console, Box::new(SharedIrq::new()));
+        uart.write(LCR, 1, 0x83);
+        uart.write(RBR_THR, 1, 0x0C);
+        uart.write(IER, 1, 0x00);
+        assert_eq!(uart.divisor(), 12);
+        assert_eq!(uart.read(RBR_THR, 1), 0x0C);
+        uart.write(LCR, 1, 0x03);
+        // With DLAB clear, offset 1 is IER again.
+        assert_eq!(uart.read(IER, 1), 0);
+    }
+
+    #[test]
+    fn test_loopback_probe() {
+        // Linux's 8250 autoconfig: loopback with RTS and OUT2 must read back
+        // as CTS and DCD.
+        let (mut bus, console, _) = com1();
+        outb(&mut bus, MCR, 0x1A);
+        assert_eq!(inb(&mut bus, MSR) & 0xF0, 0x90);
+        outb(&mut bus, SCR, 0xA5);
+        assert_eq!(inb(&mut bus, SCR), 0xA5);
+        outb(&mut bus, RBR_THR, b'x');
+        assert_ne!(inb(&mut bus, LSR) & LSR_DR, 0);
+        assert_eq!(inb(&mut bus, RBR_THR), b'x');
+        assert!(console.lock().output().is_empty());
+        // FIFO detection.
+        outb(&mut bus, IIR_FCR, 0x01);
+        assert_eq!(inb(&mut bus, IIR_FCR) & 0xC0, 0xC0);
+    }
+
+    #[test]
+    fn test_thre_interrupt() {
+        let (mut bus, _, irq) = com1();
+        assert_eq!(inb(&mut bus, IIR_FCR), IIR_NONE);
+        outb(&mut bus, IER, IER_THRE);
+        assert!(irq.level());
+        // Reading IIR acknowledges it.
+        assert_eq!(inb(&mut bus, IIR_FCR), IIR_THRE);
+        assert!(!irq.level());
+        assert_eq!(inb(&mut bus, IIR_FCR), IIR_NONE);
+        // Each transmitted byte empties THR again.
+        outb(&mut bus, RBR_THR, b'a');
+        assert!(irq.level());
+        outb(&mut bus, IER, 0);
+        assert!(!irq.level());
+    }
+
+    #[test]
+    fn test_receive_fifo_and_interrupts() {
+        let (mut bus, console, irq) = com1();
+        // FIFO on, trigger level 4, RX and line-status interrupts.
+        outb(&mut bus, IIR_FCR, 0x47);
+        outb(&mut bus, IER, IER_RDA | IER_RLS);
+        assert!(!irq.level());
+
+        console.lock().send_input(b"ab");
+        assert_eq!(inb(&mut bus, IIR_FCR), IIR_FIFO_ENABLED | IIR_TIMEOUT);
+        assert!(irq.level());
+        console.lock().send_input(b"cd");
+        assert_eq!(inb(&mut bus, IIR_FCR), IIR_FIFO_ENABLED | IIR_RDA);
+        for &expected in b"abcd" {
+            assert_ne!(inb(&mut bus, LSR) & LSR_DR, 0);
+            assert_eq!(inb(&mut bus, RBR_THR), expected);
+        }
+        assert_eq!(inb(&mut bus, LSR) & LSR_DR, 0);
+        assert!(!irq.level());
+
+        // Host input waits in the console rather than overrunning the FIFO.
+        console.lock().send_input(&[b'z'; 20]);
+        assert_eq!(inb(&mut bus, LSR) & LSR_OE, 0);
+        assert!(console.lock().has_input());
+    }
+
+    #[test]
+    fn test_overrun() {
+        let console = ConsoleBuffer::shared(16);
+        let irq = SharedIrq::new();
+        let mut uart = Serial16550::new(console, Box::new(irq.clone()));
+        uart.write(IER, 1, (IER_RDA | IER_RLS) as u32);
+        // Without the FIFO the receiver holds a single byte.
+        uart.receive(b"12");
+        assert!(irq.level());
+        assert_eq!(uart.read(IIR_FCR, 1) as u8, IIR_RLS);
+        let lsr = uart.read(LSR, 1) as u8;
+        assert_eq!(lsr & (LSR_OE | LSR_DR), LSR_OE | LSR_DR);
+        assert_eq!(uart.read(IIR_FCR, 1) as u8, IIR_RDA);
+        assert_eq!(uart.read(RBR_THR, 1) as u8, b'1');
+        assert!(!uart.irq_level());
+    }
+}
@@ -0,0 +1,318 @@
+// A learning Ethernet switch connecting guest NICs to each other and, through
+// an uplink, to the host's physical network.
+//
+// Every NIC attached gets a `SwitchPort`. A frame sent on a port goes to the
+// port its destination MAC was last seen sending from, or to every other port
+// when the destination is broadcast, multicast or not yet known. Frames wait
+// in a bounded queue per port until its owner takes them; a full queue drops
+// new frames, as a congested switch would.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::net::ethernet::EthernetDevice;
+
+pub const ETH_HEADER_LEN: usize = 14;
+/// Largest frame switched: a 1500-byte payload behind an 802.1Q tag.
+pub const MAX_FRAME_LEN: usize = 1518;
+/// Frames queued per port before new ones are dropped.
+pub const PORT_QUEUE_LEN: usize = 256;
+
+pub type MacAddress = [u8; 6];
+pub type PortId = u32;
+
+pub type SharedSwitch = Arc<Mutex<VirtualSwitch>>;
+
+fn is_multicast(mac: &MacAddress) -> bool {
+    mac[0] & 1 != 0
+}
+
+struct Port {
+    queue: VecDeque<Vec<u8>>,
+    dropped: u64,
+}
+
+pub struct VirtualSwitch {
+    ports: BTreeMap<PortId, Port>,
+    /// Port each unicast source MAC was last seen on.
+    macs: BTreeMap<MacAddress, PortId>,
+    next_port: PortId,
+}
+
+impl VirtualSwitch {
+    pub fn new() -> Self {
+        VirtualSwitch { ports: BTreeMap::new(), macs: BTreeMap::new(), next_port: 0 }
+    }
+
+    pub fn shared() -> SharedSwitch {
+        Arc::new(Mutex::new(Self::new()))
+    }
+
+    pub fn port_count(&self) -> usize {
+        self.ports.len()
+    }
+
+    /// The port frames for `mac` are sent to, once it has been learned.
+    pub fn lookup(&self, mac: &MacAddress) -> Option<PortId> {
+        self.macs.get(mac).copied()
+    }
+
+    fn add_port(&mut self) -> PortId {
+        let id = self.next_port;
+        self.next_port += 1;
+        self.ports.insert(id, Port { queue: VecDeque::new(), dropped: 0 });
+        id
+    }
+
+    fn remove_port(&mut self, id: PortId) {
+        self.ports.remove(&id);
+        self.macs.retain(|_, port| *port != id);
+    }
+
+    /// Switch `frame` sent on port `from`. Returns false for a frame too
+    /// short or too long to be Ethernet.
+    fn forward(&mut self, from: PortId, frame: &[u8]) -> bool {
+        if frame.len() < ETH_HEADER_LEN || frame.len() > MAX_FRAME_LEN {
+            return false;
+        }
+        let dest: MacAddress = frame[0..6].try_into().unwrap();
+        let source: MacAddress = frame[6..12].try_into().unwrap();
+        if !is_multicast(&source) {
+            self.macs.insert(source, from);
+        }
+        let known = if is_multicast(&dest) { None } else { self.lookup(&dest) };
+        match known {
+            // Never reflect a frame back where it came from.
+            Some(to) if to == from => {}
+            Some(to) => Self::enqueue(self.ports.get_mut(&to).unwrap(), frame),
+            None => {
+                for (_, port) in self.ports.iter_mut().filter(|(&id, _)| id != from) {
+                    Self::enqueue(port, frame);
+                }
+            }
+        }
+        true
+    }
+
+    fn enqueue(port: &mut Port, frame: &[u8]) {
+        if port.queue.len() == PORT_QUEUE_LEN {
+            port.dropped += 1;
+        } else {
+            port.queue.push_back(frame.to_vec());
+        }
+    }
+}
+
+impl Default for VirtualSwitch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One NIC's connection to a switch. Dropping it unplugs the NIC.
+pub struct SwitchPort {
+    switch: SharedSwitch,
+    id: PortId,
+}
+
+impl SwitchPort {
+    pub fn attach(switch: &SharedSwitch) -> Self {
+        let id = switch.lock().add_port();
+        SwitchPort { switch: switch.clone(), id }
+    }
+
+    pub fn id(&self) -> PortId {
+        self.id
+    }
+
+    /// Send an Ethernet frame, without FCS. Returns false if it was not a
+    /// frame the switch could carry.
+    pub fn send(&self, frame: &[u8]) -> bool {
+        self.switch.lock().forward(self.id, frame)
+    }
+
+    /// Take the oldest frame switched to this port.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.switch.lock().ports.get_mut(&self.id)?.queue.pop_front()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.switch.lock().ports.get(&self.id).is_some_and(|port| !port.queue.is_empty())
+    }
+
+    /// Frames lost because this port's queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.switch.lock().ports.get(&self.id).map_or(0, |port| port.dropped)
+    }
+}
+
+impl Drop for SwitchPort {
+    fn drop(&mut self) {
+        self.switch.lock().remove_port(self.id);
+    }
+}
+
+/// The host side of a physical NIC.
+pub trait Uplink {
+    /// Put a frame on the wire; false if the NIC would not take it.
+    fn transmit(&mut self, frame: &[u8]) -> bool;
+
+    /// Take a frame off the wire, if one has arrived, returning its length.
+    fn receive(&mut self, buf: &mut [u8]) -> Option<usize>;
+}
+
+impl Uplink for EthernetDevice {
+    fn transmit(&mut self, frame: &[u8]) -> bool {
+        self.send(frame).is_ok()
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> Option<usize> {
+        match EthernetDevice::receive(self, buf) {
+            Ok(len) if len > 0 => Some(len),
+            _ => None,
+        }
+    }
+}
+
+/// A physical NIC plugged into a switch port, so guests on the switch share
+/// the host's network segment.
+pub struct UplinkBridge<U: Uplink> {
+    port: SwitchPort,
+    nic: U,
+}
+
+impl<U: Uplink> UplinkBridge<U> {
+    pub fn new(switch: &SharedSwitch, nic: U) -> Self {
+        UplinkBridge { port: SwitchPort::attach(switch), nic }
+    }
+
+    pub fn port(&self) -> &SwitchPort {
+        &self.port
+    }
+
+    pub fn nic(&self) -> &U {
+        &self.nic
+    }
+
+    /// Move the frames waiting in each direction, at most a port queue's
+    /// worth from the wire. Returns how many went out and how many came in.
+    pub fn pump(&mut self) -> (usize, usize) {
+        let mut sent = 0;
+        while let Some(frame) = self.port.recv() {
+            if self.nic.transmit(&frame) {
+                sent += 1;
+            }
+        }
+        let mut received = 0;
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        while received < PORT_QUEUE_LEN {
+            let Some(len) = self.nic.receive(&mut buf) else { break };
+            if self.port.send(&buf[..len]) {
+                received += 1;
+            }
+        }
+        (sent, received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BROADCAST: MacAddress = [0xFF; 6];
+
+    fn mac(n: u8) -> MacAddress {
+        [0x52, 0x54, 0, 0, 0, n]
+    }
+
+    fn frame(dest: MacAddress, source: MacAddress, tag: u8) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&dest);
+        frame.extend_from_slice(&source);
+        frame.extend_from_slice(&[0x08, 0x00, tag]);
+        frame
+    }
+
+    fn drain(port: &SwitchPort) -> Vec<u8> {
+        core::iter::from_fn(|| port.recv()).map(|frame| frame[14]).collect()
+    }
+
+    #[test]
+    fn test_learning_and_flooding() {
+        let switch = VirtualSwitch::shared();
+        let ports: Vec<SwitchPort> = (0..3).map(|_| SwitchPort::attach(&switch)).collect();
+        let [a, b, c] = &ports[..] else { unreachable!() };
+
+        // Broadcast and unknown unicast reach every other port.
+        assert!(a.send(&frame(BROADCAST, mac(1), 1)));
+        assert!(a.send(&frame(mac(2), mac(1), 2)));
+        assert_eq!((drain(a), drain(b), drain(c)), (vec![], vec![1, 2], vec![1, 2]));
+
+        // Once B has spoken, frames for it go only to B.
+        assert!(b.send(&frame(mac(1), mac(2), 3)));
+        assert!(a.send(&frame(mac(2), mac(1), 4)));
+        assert_eq!((drain(a), drain(b), drain(c)), (vec![3], vec![4], vec![]));
+        assert_eq!(switch.lock().lookup(&mac(2)), Some(b.id()));
+
+        // A frame for a station on the sending port goes nowhere.
+        assert!(a.send(&frame(mac(1), mac(9), 5)));
+        assert!(!a.has_pending());
+
+        // Runts and giants are refused.
+        assert!(!a.send(&[0; 13]));
+        assert!(!a.send(&[0; MAX_FRAME_LEN + 1]));
+    }
+
+    #[test]
+    fn test_full_queue_and_unplug() {
+        let switch = VirtualSwitch::shared();
+        let a = SwitchPort::attach(&switch);
+        let b = SwitchPort::attach(&switch);
+        for i in 0..PORT_QUEUE_LEN + 3 {
+            a.send(&frame(BROADCAST, mac(1), i as u8));
+        }
+        assert_eq!(b.dropped(), 3);
+        assert_eq!(drain(&b).len(), PORT_QUEUE_LEN);
+
+        b.send(&frame(mac(1), mac(2), 0));
+        drop(b);
+        assert_eq!(switch.lock().port_count(), 1);
+        assert_eq!(switch.lock().lookup(&mac(2)), None);
+    }
+
+    struct Wire {
+        outgoing: Vec<Vec<u8>>,
+        incoming: VecDeque<Vec<u8>>,
+    }
+
+    impl Uplink for Wire {
+        fn transmit(&mut self, frame: &[u8]) -> bool {
+            self.outgoing.push(frame.to_vec());
+            true
+        }
+
+        fn receive(&mut self, buf: &mut [u8]) -> Option<usize> {
+            let frame = self.incoming.pop_front()?;
+            buf[..frame.len()].copy_from_slice(&frame);
+            Some(frame.len())
+        }
+    }
+
+    #[test]
+    fn test_uplink_bridge() {
+        let switch = VirtualSwitch::shared();
+        let guest = SwitchPort::attach(&switch);
+        let wire = Wire { outgoing: Vec::new(), incoming: VecDeque::from([frame(mac(1), mac(7), 1)]) };
+        let mut bridge = UplinkBridge::new(&switch, wire);
+        guest.send(&frame(mac(7), mac(1), 2));
+        assert_eq!(bridge.pump(), (1, 1));
+        assert_eq!(bridge.nic().outgoing, vec![frame(mac(7), mac(1), 2)]);
+        assert_eq!(drain(&guest), vec![1]);
+        // The host's stations are learned on the uplink port.
+        assert_eq!(switch.lock().lookup(&mac(7)), Some(bridge.port().id()));
+    }
+}
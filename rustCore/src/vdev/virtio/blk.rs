@@ -0,0 +1,446 @@
+// virtio-blk: a guest disk backed by any `StorageBackend`.
+//
+// Requests address 512-byte sectors whatever the backend's block size.
+// Accesses that cover part of a backend block read the whole block and,
+// for writes, write it back, so any multiple of 512 works as a block size.
+// The backend writes synchronously, which makes flushes trivial; discards
+// are checked and then ignored, which the spec allows.
+
+use alloc::vec;
+
+use super::queue::{DescriptorChain, QueueError, Virtqueue};
+use super::{GuestMemory, VirtioDevice, VIRTIO_ID_BLOCK};
+use crate::storage::StorageBackend;
+
+pub const SECTOR_SIZE: u64 = 512;
+/// Length of the string returned by a GET_ID request.
+pub const ID_LEN: usize = 20;
+
+const QUEUE_SIZE: u16 = 128;
+/// Discard ranges accepted per request.
+const MAX_DISCARD_SEGMENTS: u32 = 16;
+
+// Feature bits.
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+pub const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+
+// Request types.
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+// Request status.
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Request header: type, reserved, sector.
+const HEADER_LEN: u64 = 16;
+/// Discard segment: sector, number of sectors, flags.
+const DISCARD_SEGMENT_LEN: u64 = 16;
+
+// Configuration space fields.
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SEG_MAX: usize = 12;
+const CONFIG_BLK_SIZE: usize = 20;
+const CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+const CONFIG_MAX_DISCARD_SEG: usize = 40;
+const CONFIG_DISCARD_SECTOR_ALIGNMENT: usize = 44;
+const CONFIG_LEN: usize = 60;
+
+/// A request's status and how many bytes of data went to the driver.
+type Served = Result<(u8, u64), QueueError>;
+
+pub struct VirtioBlk<S: StorageBackend> {
+    storage: S,
+    block_size: usize,
+    /// Disk size in 512-byte sectors.
+    sectors: u64,
+    read_only: bool,
+    id: [u8; ID_LEN],
+}
+
+impl<S: StorageBackend> VirtioBlk<S> {
+    /// A disk of `blocks` blocks of `block_size` bytes, a multiple of 512.
+    pub fn new(storage: S, block_size: usize, blocks: u64) -> Self {
+        assert!(block_size > 0 && (block_size as u64).is_multiple_of(SECTOR_SIZE), "bad block size {}", block_size);
+        let sectors = blocks * (block_size as u64 / SECTOR_SIZE);
+        VirtioBlk { storage, block_size, sectors, read_only: false, id: [0; ID_LEN] }
+    }
+
+    /// Refuse guest writes and discards.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// The serial number the guest reads with GET_ID, cut to 20 bytes.
+    pub fn with_id(mut self, id: &str) -> Self {
+        let len = core::cmp::min(id.len(), ID_LEN);
+        self.id = [0; ID_LEN];
+        self.id[..len].copy_from_slice(&id.as_bytes()[..len]);
+        self
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Capacity in 512-byte sectors.
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    /// Check that `len` bytes from `sector` on are whole sectors on the disk.
+    fn in_range(&self, sector: u64, len: u64) -> bool {
+        len.is_multiple_of(SECTOR_SIZE) && sector.checked_add(len / SECTOR_SIZE).is_some_and(|end| end <= self.sectors)
+    }
+
+    /// Serve one request.
+    fn serve(&mut self, chain: &DescriptorChain, mem: &dyn GuestMemory) -> Served {
+        let mut header = [0u8; HEADER_LEN as usize];
+        if chain.read_at(mem, 0, &mut header)? < header.len() {
+            return Ok((VIRTIO_BLK_S_IOERR, 0));
+        }
+        let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        // Everything writable but the status byte.
+        let data_in = chain.writable_len() - 1;
+        let data_out = chain.readable_len() - HEADER_LEN;
+        match kind {
+            VIRTIO_BLK_T_IN => {
+                if !self.in_range(sector, data_in) {
+                    return Ok((VIRTIO_BLK_S_IOERR, 0));
+                }
+                self.read(chain, mem, sector * SECTOR_SIZE, data_in)
+            }
+            VIRTIO_BLK_T_OUT => {
+                if self.read_only || !self.in_range(sector, data_out) {
+                    return Ok((VIRTIO_BLK_S_IOERR, 0));
+                }
+                self.write(chain, mem, sector * SECTOR_SIZE, data_out)
+            }
+            VIRTIO_BLK_T_FLUSH => Ok((VIRTIO_BLK_S_OK, 0)),
+            VIRTIO_BLK_T_GET_ID => {
+                let written = chain.write_at(mem, 0, &self.id[..core::cmp::min(ID_LEN as u64, data_in) as usize])?;
+                Ok((VIRTIO_BLK_S_OK, written as u64))
+            }
+            VIRTIO_BLK_T_DISCARD => self.discard(chain, mem, data_out),
+            _ => Ok((VIRTIO_BLK_S_UNSUPP, 0)),
+        }
+    }
+
+    /// Copy `len` bytes of the disk from byte `offset` into the chain.
+    fn read(&mut self, chain: &DescriptorChain, mem: &dyn GuestMemory, offset: u64, len: u64) -> Served {
+        let mut block = vec![0u8; self.block_size];
+        let mut done = 0;
+        while done < len {
+            let (index, start, n) = self.block_span(offset + done, len - done);
+            if self.storage.read_block(index, &mut block).is_err() {
+                return Ok((VIRTIO_BLK_S_IOERR, done));
+            }
+            chain.write_at(mem, done, &block[start..start + n])?;
+            done += n as u64;
+        }
+        Ok((VIRTIO_BLK_S_OK, done))
+    }
+
+    /// Copy `len` bytes after the request header to the disk at byte
+    /// `offset`.
+    fn write(&mut self, chain: &DescriptorChain, mem: &dyn GuestMemory, offset: u64, len: u64) -> Served {
+        let mut block = vec![0u8; self.block_size];
+        let mut done = 0;
+        while done < len {
+            let (index, start, n) = self.block_span(offset + done, len - done);
+            if n < self.block_size && self.storage.read_block(index, &mut block).is_err() {
+                return Ok((VIRTIO_BLK_S_IOERR, 0));
+            }
+            chain.read_at(mem, HEADER_LEN + done, &mut block[start..start + n])?;
+            if self.storage.write_block(index, &block).is_err() {
+                return Ok((VIRTIO_BLK_S_IOERR, 0));
+            }
+            done += n as u64;
+        }
+        Ok((VIRTIO_BLK_S_OK, 0))
+    }
+
+    /// Check the discard segments of a request; the backend keeps the data.
+    fn discard(&mut self, chain: &DescriptorChain, mem: &dyn GuestMemory, len: u64) -> Served {
+        if self.read_only {
+            return Ok((VIRTIO_BLK_S_IOERR, 0));
+        }
+        let segments = len / DISCARD_SEGMENT_LEN;
+        if !len.is_multiple_of(DISCARD_SEGMENT_LEN) || segments == 0 || segments > MAX_DISCARD_SEGMENTS as u64 {
+            return Ok((VIRTIO_BLK_S_UNSUPP, 0));
+        }
+        for i in 0..segments {
+            let mut segment = [0u8; DISCARD_SEGMENT_LEN as usize];
+            chain.read_at(mem, HEADER_LEN + i * DISCARD_SEGMENT_LEN, &mut segment)?;
+            let sector = u64::from_le_bytes(segment[0..8].try_into().unwrap());
+            let count = u32::from_le_bytes(segment[8..12].try_into().unwrap()) as u64;
+            let flags = u32::from_le_bytes(segment[12..16].try_into().unwrap());
+            // Bit 0 (unmap) is only meaningful for write-zeroes.
+            if flags != 0 {
+                return Ok((VIRTIO_BLK_S_UNSUPP, 0));
+            }
+            if !self.in_range(sector, count * SECTOR_SIZE) {
+                return Ok((VIRTIO_BLK_S_IOERR, 0));
+            }
+        }
+        Ok((VIRTIO_BLK_S_OK, 0))
+    }
+
+    /// The backend block holding byte `offset`, where in it `offset` falls,
+    /// and how many of the `len` bytes from there it holds.
+    fn block_span(&self, offset: u64, len: u64) -> (u64, usize, usize) {
+        let block_size = self.block_size as u64;
+        let start = (offset % block_size) as usize;
+        let n = core::cmp::min(len, block_size - start as u64) as usize;
+        (offset / block_size, start, n)
+    }
+
+    fn config(&self) -> [u8; CONFIG_LEN] {
+        let mut config = [0u8; CONFIG_LEN];
+        let mut put = |at: usize, bytes: &[u8]| config[at..at + bytes.len()].copy_from_slice(bytes);
+        put(CONFIG_CAPACITY, &self.sectors.to_le_bytes());
+        put(CONFIG_SEG_MAX, &(QUEUE_SIZE as u32 - 2).to_le_bytes());
+        put(CONFIG_BLK_SIZE, &(self.block_size as u32).to_le_bytes());
+        put(CONFIG_MAX_DISCARD_SECTORS, &u32::MAX.to_le_bytes());
+        put(CONFIG_MAX_DISCARD_SEG, &MAX_DISCARD_SEGMENTS.to_le_bytes());
+        put(CONFIG_DISCARD_SECTOR_ALIGNMENT, &(self.block_size as u64 / SECTOR_SIZE).to_le_bytes()[..4]);
+        config
+    }
+}
+
+impl<S: StorageBackend + Send> VirtioDevice for VirtioBlk<S> {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+        if self.read_only {
+            features |= VIRTIO_BLK_F_RO;
+        } else {
+            features |= VIRTIO_BLK_F_DISCARD;
+        }
+        features
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.config();
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn notify(&mut self, index: usize, queues: &mut [Virtqueue], mem: &dyn GuestMemory) -> Result<(), QueueError> {
+        let queue = &mut queues[index];
+        while let Some(chain) = queue.pop(mem)? {
+            // A request with nowhere to put the status cannot be answered.
+            if chain.writable_len() == 0 || chain.readable_len() < HEADER_LEN {
+                queue.add_used(mem, chain.head, 0)?;
+                continue;
+            }
+            let (status, written) = self.serve(&chain, mem)?;
+            chain.write_at(mem, chain.writable_len() - 1, &[status])?;
+            queue.add_used(mem, chain.head, written as u32 + 1)?;
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    use crate::hypervisor::{HypervisorBackend, MockBackend};
+    use crate::storage::tests::RamStorage;
+    use crate::vdev::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use crate::vdev::virtio::LayoutMemory;
+
+    const HEADER: u64 = 0x8000;
+    const DATA: u64 = 0x9000;
+    const STATUS: u64 = 0xF000;
+
+    /// A 16-block disk of distinct bytes, and the driver's view of a
+    /// queue to it.
+    struct Harness {
+        blk: VirtioBlk<RamStorage>,
+        queues: [Virtqueue; 1],
+        mem: LayoutMemory,
+        _backend: MockBackend,
+    }
+
+    fn harness(block_size: usize) -> Harness {
+        let data = (0..16 * block_size).map(|i| (i / SECTOR_SIZE as usize) as u8 ^ i as u8).collect();
+        let disk = RamStorage::from_bytes(block_size, data);
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        let mem = unsafe { LayoutMemory::new(backend.memory_layout().clone(), 0) };
+        let mut queue = Virtqueue::new(QUEUE_SIZE);
+        queue.size = 8;
+        queue.desc_table = 0x1000;
+        queue.avail_ring = 0x2000;
+        queue.used_ring = 0x3000;
+        assert!(queue.enable(false));
+        Harness { blk: VirtioBlk::new(disk, block_size, 16).with_id("disk-0"), queues: [queue], mem, _backend: backend }
+    }
+
+    impl Harness {
+        fn disk(&self) -> core::cell::Ref<'_, Vec<u8>> {
+            self.blk.storage().data()
+        }
+
+        /// Submit a request of `kind` at `sector` with `out` as its payload
+        /// and room for `data_in` bytes of reply, returning the status, the
+        /// reply and the used length.
+        fn request(&mut self, kind: u32, sector: u64, out: &[u8], data_in: u32) -> (u8, Vec<u8>, u32) {
+            let mut header = [0u8; 16];
+            header[0..4].copy_from_slice(&kind.to_le_bytes());
+            header[8..16].copy_from_slice(&sector.to_le_bytes());
+            self.mem.write(HEADER, &header).unwrap();
+            self.mem.write(DATA, out).unwrap();
+            self.mem.write(STATUS, &[0xFF]).unwrap();
+            let mut descs = vec![(HEADER, 16, 0)];
+            if !out.is_empty() {
+                descs.push((DATA, out.len() as u32, 0));
+            }
+            if data_in > 0 {
+                descs.push((DATA, data_in, VIRTQ_DESC_F_WRITE));
+            }
+            descs.push((STATUS, 1, VIRTQ_DESC_F_WRITE));
+            for (i, &(addr, len, flags)) in descs.iter().enumerate() {
+                let next = if i + 1 < descs.len() { VIRTQ_DESC_F_NEXT } else { 0 };
+                let mut raw = [0u8; 16];
+                raw[0..8].copy_from_slice(&addr.to_le_bytes());
+                raw[8..12].copy_from_slice(&len.to_le_bytes());
+                raw[12..14].copy_from_slice(&(flags | next).to_le_bytes());
+                raw[14..16].copy_from_slice(&(i as u16 + 1).to_le_bytes());
+                self.mem.write(0x1000 + 16 * i as u64, &raw).unwrap();
+            }
+            let mut idx = [0u8; 2];
+            self.mem.read(0x2002, &mut idx).unwrap();
+            let idx = u16::from_le_bytes(idx);
+            self.mem.write(0x2004 + 2 * (idx % 8) as u64, &0u16.to_le_bytes()).unwrap();
+            self.mem.write(0x2002, &idx.wrapping_add(1).to_le_bytes()).unwrap();
+
+            self.blk.notify(0, &mut self.queues, &self.mem).unwrap();
+            let mut status = [0u8; 1];
+            self.mem.read(STATUS, &mut status).unwrap();
+            let mut reply = vec![0u8; data_in as usize];
+            self.mem.read(DATA, &mut reply).unwrap();
+            let mut used = [0u8; 4];
+            self.mem.read(0x3004 + 8 * (idx % 8) as u64 + 4, &mut used).unwrap();
+            (status[0], reply, u32::from_le_bytes(used))
+        }
+    }
+
+    #[test]
+    fn test_config_and_features() {
+        let h = harness(4096);
+        assert_eq!(h.blk.sectors(), 128);
+        let mut capacity = [0u8; 8];
+        h.blk.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 128);
+        let mut blk_size = [0u8; 4];
+        h.blk.read_config(20, &mut blk_size);
+        assert_eq!(u32::from_le_bytes(blk_size), 4096);
+        assert_eq!(h.blk.features() & (VIRTIO_BLK_F_RO | VIRTIO_BLK_F_DISCARD), VIRTIO_BLK_F_DISCARD);
+        let ro = VirtioBlk::new(RamStorage::new(512, 0), 512, 0).read_only();
+        assert_eq!(ro.features() & (VIRTIO_BLK_F_RO | VIRTIO_BLK_F_DISCARD), VIRTIO_BLK_F_RO);
+    }
+
+    #[test]
+    fn test_read_and_write_across_blocks() {
+        for block_size in [512, 4096] {
+            let mut h = harness(block_size);
+            // Sectors 7..10 straddle a 4K block boundary.
+            let (status, reply, used) = h.request(VIRTIO_BLK_T_IN, 7, &[], 1536);
+            assert_eq!((status, used), (VIRTIO_BLK_S_OK, 1537));
+            assert_eq!(reply, h.disk()[7 * 512..10 * 512]);
+
+            let payload: Vec<u8> = (0..1024).map(|i| (i * 7) as u8).collect();
+            let (status, _, used) = h.request(VIRTIO_BLK_T_OUT, 7, &payload, 0);
+            assert_eq!((status, used), (VIRTIO_BLK_S_OK, 1));
+            assert_eq!(h.disk()[7 * 512..9 * 512], payload[..]);
+            // The rest of the blocks written around is untouched.
+            let expected = |i: usize| (i / 512) as u8 ^ i as u8;
+            assert_eq!(h.disk()[6 * 512], expected(6 * 512));
+            assert_eq!(h.disk()[9 * 512 + 3], expected(9 * 512 + 3));
+        }
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let mut h = harness(4096);
+        // Past the end, and not a whole number of sectors.
+        assert_eq!(h.request(VIRTIO_BLK_T_IN, 127, &[], 1024).0, VIRTIO_BLK_S_IOERR);
+        assert_eq!(h.request(VIRTIO_BLK_T_IN, u64::MAX, &[], 512).0, VIRTIO_BLK_S_IOERR);
+        assert_eq!(h.request(VIRTIO_BLK_T_OUT, 0, &[1; 100], 0).0, VIRTIO_BLK_S_IOERR);
+        assert_eq!(h.request(0x99, 0, &[], 0).0, VIRTIO_BLK_S_UNSUPP);
+
+        // A failing backend block fails the request.
+        h.blk.storage().set_bad_block(Some(1));
+        assert_eq!(h.request(VIRTIO_BLK_T_IN, 8, &[], 512).0, VIRTIO_BLK_S_IOERR);
+        assert_eq!(h.request(VIRTIO_BLK_T_OUT, 7, &[0; 1024], 0).0, VIRTIO_BLK_S_IOERR);
+        assert_eq!(h.request(VIRTIO_BLK_T_IN, 0, &[], 512).0, VIRTIO_BLK_S_OK);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mut h = harness(512);
+        let disk = RamStorage::from_bytes(512, vec![7; 4096]);
+        h.blk = VirtioBlk::new(disk, 512, 8).read_only();
+        assert!(h.blk.is_read_only());
+        assert_eq!(h.request(VIRTIO_BLK_T_OUT, 0, &[0; 512], 0).0, VIRTIO_BLK_S_IOERR);
+        assert_eq!(h.disk()[0], 7);
+        let discard = [0u8; 16];
+        assert_eq!(h.request(VIRTIO_BLK_T_DISCARD, 0, &discard, 0).0, VIRTIO_BLK_S_IOERR);
+        let (status, reply, _) = h.request(VIRTIO_BLK_T_IN, 0, &[], 512);
+        assert_eq!((status, reply), (VIRTIO_BLK_S_OK, vec![7; 512]));
+    }
+
+    #[test]
+    fn test_flush_get_id_and_discard() {
+        let mut h = harness(4096);
+        assert_eq!(h.request(VIRTIO_BLK_T_FLUSH, 0, &[], 0), (VIRTIO_BLK_S_OK, vec![], 1));
+        let (status, reply, used) = h.request(VIRTIO_BLK_T_GET_ID, 0, &[], 20);
+        assert_eq!((status, used), (VIRTIO_BLK_S_OK, 21));
+        assert_eq!(&reply[..7], b"disk-0\0");
+
+        let segment = |sector: u64, count: u32, flags: u32| {
+            let mut raw = [0u8; 16];
+            raw[0..8].copy_from_slice(&sector.to_le_bytes());
+            raw[8..12].copy_from_slice(&count.to_le_bytes());
+            raw[12..16].copy_from_slice(&flags.to_le_bytes());
+            raw
+        };
+        let before = h.disk().clone();
+        let mut two = segment(0, 8, 0).to_vec();
+        two.extend_from_slice(&segment(120, 8, 0));
+        assert_eq!(h.request(VIRTIO_BLK_T_DISCARD, 0, &two, 0).0, VIRTIO_BLK_S_OK);
+        assert_eq!(*h.disk(), before);
+        assert_eq!(h.request(VIRTIO_BLK_T_DISCARD, 0, &segment(124, 8, 0), 0).0, VIRTIO_BLK_S_IOERR);
+        assert_eq!(h.request(VIRTIO_BLK_T_DISCARD, 0, &segment(0, 8, 1), 0).0, VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(h.request(VIRTIO_BLK_T_DISCARD, 0, &[0; 8], 0).0, VIRTIO_BLK_S_UNSUPP);
+    }
+}
@@ -0,0 +1,201 @@
+// virtio-console with a single port, carrying a byte channel between the
+// guest and a host service. Linux guests see the port as /dev/hvcN.
+//
+// No features are offered: no console size, no multiport, no emergency
+// writes. Transmitted buffers are copied only as far as the channel has
+// room and finished later, so a guest that outpaces the host is slowed
+// down, not truncated.
+
+use alloc::vec;
+
+use super::queue::{DescriptorChain, QueueError, Virtqueue};
+use super::{GuestMemory, VirtioDevice, VIRTIO_ID_CONSOLE};
+use crate::vdev::channel::SharedChannel;
+
+pub const RX_QUEUE: usize = 0;
+pub const TX_QUEUE: usize = 1;
+const QUEUE_SIZE: u16 = 64;
+
+pub struct VirtioConsole {
+    channel: SharedChannel,
+    /// A transmitted chain the channel had no room for all of, and how much
+    /// of it was passed on.
+    partial: Option<(DescriptorChain, u64)>,
+}
+
+impl VirtioConsole {
+    pub fn new(channel: SharedChannel) -> Self {
+        VirtioConsole { channel, partial: None }
+    }
+
+    pub fn channel(&self) -> &SharedChannel {
+        &self.channel
+    }
+
+    /// Fill the driver's receive buffers with what the host has sent.
+    fn receive(&mut self, queue: &mut Virtqueue, mem: &dyn GuestMemory) -> Result<(), QueueError> {
+        while queue.is_ready() && self.channel.lock().has_pending() {
+            let Some(chain) = queue.pop(mem)? else { break };
+            let data = self.channel.lock().pop_to_guest(chain.writable_len() as usize);
+            let written = chain.write_at(mem, 0, &data)?;
+            queue.add_used(mem, chain.head, written as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Pass on what the driver transmitted while the channel has room.
+    fn transmit(&mut self, queue: &mut Virtqueue, mem: &dyn GuestMemory) -> Result<(), QueueError> {
+        while queue.is_ready() {
+            let room = self.channel.lock().room();
+            if room == 0 {
+                break;
+            }
+            let (chain, done) = match self.partial.take() {
+                Some(partial) => partial,
+                None => match queue.pop(mem)? {
+                    Some(chain) => (chain, 0),
+                    None => break,
+                },
+            };
+            let len = core::cmp::min(chain.readable_len() - done, room as u64);
+            let mut data = vec![0u8; len as usize];
+            chain.read_at(mem, done, &mut data)?;
+            self.channel.lock().push_from_guest(&data);
+            if done + len < chain.readable_len() {
+                self.partial = Some((chain, done + len));
+            } else {
+                queue.add_used(mem, chain.head, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    /// cols, rows, max_nr_ports and emerg_wr, all unused without features.
+    fn read_config(&self, _offset: u64, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn reset(&mut self) {
+        self.partial = None;
+    }
+
+    fn notify(&mut self, index: usize, queues: &mut [Virtqueue], mem: &dyn GuestMemory) -> Result<(), QueueError> {
+        match index {
+            RX_QUEUE => self.receive(&mut queues[RX_QUEUE], mem),
+            TX_QUEUE => self.transmit(&mut queues[TX_QUEUE], mem),
+            _ => Ok(()),
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], mem: &dyn GuestMemory) -> Result<(), QueueError> {
+        self.receive(&mut queues[RX_QUEUE], mem)?;
+        self.transmit(&mut queues[TX_QUEUE], mem)
+    }
+
+    fn name(&self) -> &'static str {
+        "virtio-console"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    use crate::hypervisor::{HypervisorBackend, MockBackend};
+    use crate::vdev::channel::ByteChannel;
+    use crate::vdev::virtio::queue::VIRTQ_DESC_F_WRITE;
+    use crate::vdev::virtio::LayoutMemory;
+
+    const SIZE: u16 = 4;
+
+    /// Rings of queue `i` at `i * 0x4000`, one single-buffer chain per
+    /// descriptor.
+    fn queues() -> [Virtqueue; 2] {
+        [0u64, 1].map(|i| {
+            let mut queue = Virtqueue::new(QUEUE_SIZE);
+            queue.size = SIZE;
+            queue.desc_table = i * 0x4000;
+            queue.avail_ring = i * 0x4000 + 0x1000;
+            queue.used_ring = i * 0x4000 + 0x2000;
+            assert!(queue.enable(false));
+            queue
+        })
+    }
+
+    fn post(mem: &dyn GuestMemory, queue: usize, desc: u16, addr: u64, len: u32, flags: u16) {
+        let base = queue as u64 * 0x4000;
+        let mut raw = [0u8; 16];
+        raw[0..8].copy_from_slice(&addr.to_le_bytes());
+        raw[8..12].copy_from_slice(&len.to_le_bytes());
+        raw[12..14].copy_from_slice(&flags.to_le_bytes());
+        mem.write(base + 16 * desc as u64, &raw).unwrap();
+        let mut idx = [0u8; 2];
+        mem.read(base + 0x1002, &mut idx).unwrap();
+        let idx = u16::from_le_bytes(idx);
+        mem.write(base + 0x1004 + 2 * (idx % SIZE) as u64, &desc.to_le_bytes()).unwrap();
+        mem.write(base + 0x1002, &idx.wrapping_add(1).to_le_bytes()).unwrap();
+    }
+
+    fn used_lens(mem: &dyn GuestMemory, queue: usize) -> Vec<u32> {
+        let used = queue as u64 * 0x4000 + 0x2000;
+        let mut idx = [0u8; 2];
+        mem.read(used + 2, &mut idx).unwrap();
+        (0..u16::from_le_bytes(idx) as u64)
+            .map(|slot| {
+                let mut len = [0u8; 4];
+                mem.read(used + 8 + 8 * slot, &mut len).unwrap();
+                u32::from_le_bytes(len)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bytes_both_ways() {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        let mem = unsafe { LayoutMemory::new(backend.memory_layout().clone(), 0) };
+        let mut queues = queues();
+        let channel = ByteChannel::shared(8);
+        let mut console = VirtioConsole::new(channel.clone());
+
+        // Host to guest, split across two 4-byte buffers.
+        channel.lock().send(b"hello");
+        post(&mem, RX_QUEUE, 0, 0x8000, 4, VIRTQ_DESC_F_WRITE);
+        console.notify(RX_QUEUE, &mut queues, &mem).unwrap();
+        assert!(channel.lock().has_pending());
+        post(&mem, RX_QUEUE, 1, 0x8004, 4, VIRTQ_DESC_F_WRITE);
+        console.poll(&mut queues, &mem).unwrap();
+        assert_eq!(used_lens(&mem, RX_QUEUE), [4, 1]);
+        let mut received = [0u8; 5];
+        mem.read(0x8000, &mut received).unwrap();
+        assert_eq!(&received, b"hello");
+
+        // Guest to host, held back while the channel is full.
+        mem.write(0x9000, b"0123456789").unwrap();
+        post(&mem, TX_QUEUE, 0, 0x9000, 10, 0);
+        post(&mem, TX_QUEUE, 1, 0x9000, 2, 0);
+        console.notify(TX_QUEUE, &mut queues, &mem).unwrap();
+        assert_eq!(used_lens(&mem, TX_QUEUE), []);
+        assert_eq!(channel.lock().take(), b"01234567");
+        console.poll(&mut queues, &mem).unwrap();
+        assert_eq!(used_lens(&mem, TX_QUEUE), [0, 0]);
+        assert_eq!(channel.lock().take(), b"8901");
+        console.poll(&mut queues, &mem).unwrap();
+        assert_eq!(channel.lock().take(), vec![]);
+    }
+}
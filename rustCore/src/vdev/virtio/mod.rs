@@ -0,0 +1,161 @@
+// Virtio 1.x devices: split virtqueues, the device model interface, the
+// virtio-pci transport that exposes a device to the guest, and the devices.
+
+pub mod queue;
+pub mod pci;
+pub mod blk;
+pub mod net;
+pub mod console;
+pub mod p9;
+
+pub use blk::VirtioBlk;
+pub use console::VirtioConsole;
+pub use net::VirtioNet;
+pub use p9::VirtioP9;
+pub use pci::VirtioPci;
+pub use queue::{DescriptorChain, GuestBuffer, QueueError, Virtqueue};
+
+use alloc::sync::Arc;
+
+use crate::vmx::ept::{GuestMemoryLayout, RegionKind};
+
+// Device IDs.
+pub const VIRTIO_ID_NET: u16 = 1;
+pub const VIRTIO_ID_BLOCK: u16 = 2;
+pub const VIRTIO_ID_CONSOLE: u16 = 3;
+pub const VIRTIO_ID_9P: u16 = 9;
+
+// Device-independent feature bits.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Device status bits.
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_NEEDS_RESET: u8 = 0x40;
+pub const STATUS_FAILED: u8 = 0x80;
+
+/// A guest-physical address outside guest RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadGuestAddress(pub u64);
+
+/// Guest RAM as devices reach it for DMA, from whichever thread handles the
+/// exit that kicked them.
+pub trait GuestMemory: Send + Sync {
+    fn read(&self, gpa: u64, buf: &mut [u8]) -> Result<(), BadGuestAddress>;
+    fn write(&self, gpa: u64, data: &[u8]) -> Result<(), BadGuestAddress>;
+}
+
+pub type SharedMemory = Arc<dyn GuestMemory>;
+
+/// Guest RAM reached through the host mapping of the memory backing it.
+///
+/// Aligned 2-, 4- and 8-byte accesses are single loads and stores, so ring
+/// indices the guest updates concurrently are never seen half-written.
+pub struct LayoutMemory {
+    layout: GuestMemoryLayout,
+    phys_offset: u64,
+}
+
+impl LayoutMemory {
+    /// # Safety
+    ///
+    /// The host memory behind every RAM region of `layout` must stay mapped
+    /// at `host_phys + phys_offset` for as long as this exists.
+    pub unsafe fn new(layout: GuestMemoryLayout, phys_offset: u64) -> Self {
+        LayoutMemory { layout, phys_offset }
+    }
+
+    /// Host pointer for `gpa` and how many bytes of RAM follow it.
+    fn host_ptr(&self, gpa: u64) -> Result<(*mut u8, usize), BadGuestAddress> {
+        let region = self.layout.find(gpa).ok_or(BadGuestAddress(gpa))?;
+        match region.kind {
+            RegionKind::Ram { host_phys } => {
+                let ptr = (host_phys + self.phys_offset + (gpa - region.guest_phys)) as *mut u8;
+                Ok((ptr, (region.end() - gpa) as usize))
+            }
+            RegionKind::Mmio => Err(BadGuestAddress(gpa)),
+        }
+    }
+}
+
+impl GuestMemory for LayoutMemory {
+    fn read(&self, gpa: u64, buf: &mut [u8]) -> Result<(), BadGuestAddress> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (ptr, avail) = self.host_ptr(gpa + done as u64)?;
+            let n = core::cmp::min(avail, buf.len() - done);
+            let whole = n == buf.len() && (ptr as usize).is_multiple_of(n);
+            unsafe {
+                match n {
+                    2 if whole => buf.copy_from_slice(&(ptr as *const u16).read_volatile().to_ne_bytes()),
+                    4 if whole => buf.copy_from_slice(&(ptr as *const u32).read_volatile().to_ne_bytes()),
+                    8 if whole => buf.copy_from_slice(&(ptr as *const u64).read_volatile().to_ne_bytes()),
+                    _ => core::ptr::copy_nonoverlapping(ptr, buf[done..].as_mut_ptr(), n),
+                }
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write(&self, gpa: u64, data: &[u8]) -> Result<(), BadGuestAddress> {
+        let mut done = 0;
+        while done < data.len() {
+            let (ptr, avail) = self.host_ptr(gpa + done as u64)?;
+            let n = core::cmp::min(avail, data.len() - done);
+            let whole = n == data.len() && (ptr as usize).is_multiple_of(n);
+            unsafe {
+                match n {
+                    2 if whole => (ptr as *mut u16).write_volatile(u16::from_ne_bytes(data.try_into().unwrap())),
+                    4 if whole => (ptr as *mut u32).write_volatile(u32::from_ne_bytes(data.try_into().unwrap())),
+                    8 if whole => (ptr as *mut u64).write_volatile(u64::from_ne_bytes(data.try_into().unwrap())),
+                    _ => core::ptr::copy_nonoverlapping(data[done..].as_ptr(), ptr, n),
+                }
+            }
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+/// A virtio device model, independent of the transport exposing it.
+pub trait VirtioDevice: Send {
+    /// Virtio device ID, such as [`VIRTIO_ID_BLOCK`].
+    fn device_type(&self) -> u16;
+
+    /// Device-specific feature bits offered. The transport adds the
+    /// device-independent ones it implements.
+    fn features(&self) -> u64;
+
+    /// Maximum size of each of the device's queues.
+    fn queue_sizes(&self) -> &[u16];
+
+    /// Read from the device-specific configuration space.
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// The driver accepted `features` and set DRIVER_OK.
+    fn activate(&mut self, _features: u64) {}
+
+    /// The driver reset the device; queues are reset by the transport.
+    fn reset(&mut self) {}
+
+    /// The driver made buffers available on queue `index`. An error means
+    /// the driver broke the ring and the device needs a reset.
+    fn notify(&mut self, index: usize, queues: &mut [Virtqueue], mem: &dyn GuestMemory) -> Result<(), QueueError>;
+
+    /// Do work that does not wait for the driver, such as handing over
+    /// data that arrived from outside. Queues may not be ready yet.
+    fn poll(&mut self, _queues: &mut [Virtqueue], _mem: &dyn GuestMemory) -> Result<(), QueueError> {
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "virtio-device"
+    }
+}
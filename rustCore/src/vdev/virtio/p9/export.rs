@@ -0,0 +1,131 @@
+// What a 9P server exports: a tree of files and directories named by inode
+// number. Shared volumes are exported through their `VolumeHandle`, so a
+// VM's access to the volume is checked on every call; a host directory can
+// be exported with `HostDir` when the hypervisor runs on a host with a
+// filesystem of its own.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::proto::Errno;
+use crate::storage::volume::{Access, Attr, Ino, NodeKind, SetAttr, VolumeError, VolumeHandle};
+use crate::storage::StorageBackend;
+
+/// Space on an export, in its own blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Space {
+    pub block_size: u32,
+    pub blocks: u64,
+    pub free: u64,
+}
+
+/// A tree exported to a guest. Names are single path components; the server
+/// rejects `.`, `..` and names with a `/` before calling in.
+pub trait Export: Send {
+    fn root(&self) -> Ino;
+
+    /// Whether writes will be refused, so opening for writing can fail early.
+    fn read_only(&self) -> bool;
+
+    fn lookup(&mut self, dir: Ino, name: &str) -> Result<Ino, Errno>;
+
+    /// Attributes, with `blocks` counted in blocks of [`Space::block_size`].
+    fn getattr(&mut self, ino: Ino) -> Result<Attr, Errno>;
+
+    fn setattr(&mut self, ino: Ino, attr: SetAttr) -> Result<(), Errno>;
+
+    /// A directory's entries, without `.` and `..`.
+    fn readdir(&mut self, dir: Ino) -> Result<Vec<(String, Ino, NodeKind)>, Errno>;
+
+    /// Read from `offset` into `buf`, returning how many bytes there were.
+    fn read(&mut self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, Errno>;
+
+    fn write(&mut self, ino: Ino, offset: u64, data: &[u8]) -> Result<usize, Errno>;
+
+    /// Create an empty file.
+    fn create(&mut self, dir: Ino, name: &str, mode: u32, uid: u32, gid: u32) -> Result<Ino, Errno>;
+
+    fn mkdir(&mut self, dir: Ino, name: &str, mode: u32, uid: u32, gid: u32) -> Result<Ino, Errno>;
+
+    /// Remove a file or an empty directory.
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), Errno>;
+
+    /// Move `name` in `dir` to `new_name` in `new_dir`, replacing a file or
+    /// empty directory already there.
+    fn rename(&mut self, dir: Ino, name: &str, new_dir: Ino, new_name: &str) -> Result<(), Errno>;
+
+    fn space(&mut self) -> Result<Space, Errno>;
+}
+
+impl From<VolumeError> for Errno {
+    fn from(err: VolumeError) -> Self {
+        match err {
+            VolumeError::NotFound => Errno::ENOENT,
+            VolumeError::Exists => Errno::EEXIST,
+            VolumeError::Denied => Errno::EACCES,
+            VolumeError::QuotaExceeded => Errno::EDQUOT,
+            VolumeError::NoSpace => Errno::ENOSPC,
+            VolumeError::NotADirectory => Errno::ENOTDIR,
+            VolumeError::IsADirectory => Errno::EISDIR,
+            VolumeError::NotEmpty => Errno::ENOTEMPTY,
+            VolumeError::Invalid => Errno::EINVAL,
+            VolumeError::Io => Errno::EIO,
+        }
+    }
+}
+
+impl<S: StorageBackend + Send> Export for VolumeHandle<S> {
+    fn root(&self) -> Ino {
+        crate::storage::volume::ROOT_INO
+    }
+
+    fn read_only(&self) -> bool {
+        self.access() < Access::Write
+    }
+
+    fn lookup(&mut self, dir: Ino, name: &str) -> Result<Ino, Errno> {
+        Ok(VolumeHandle::lookup(self, dir, name)?)
+    }
+
+    fn getattr(&mut self, ino: Ino) -> Result<Attr, Errno> {
+        Ok(VolumeHandle::getattr(self, ino)?)
+    }
+
+    fn setattr(&mut self, ino: Ino, attr: SetAttr) -> Result<(), Errno> {
+        Ok(VolumeHandle::setattr(self, ino, attr)?)
+    }
+
+    fn readdir(&mut self, dir: Ino) -> Result<Vec<(String, Ino, NodeKind)>, Errno> {
+        Ok(VolumeHandle::readdir(self, dir)?)
+    }
+
+    fn read(&mut self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        Ok(VolumeHandle::read(self, ino, offset, buf)?)
+    }
+
+    fn write(&mut self, ino: Ino, offset: u64, data: &[u8]) -> Result<usize, Errno> {
+        Ok(VolumeHandle::write(self, ino, offset, data)?)
+    }
+
+    fn create(&mut self, dir: Ino, name: &str, mode: u32, uid: u32, gid: u32) -> Result<Ino, Errno> {
+        Ok(VolumeHandle::create(self, dir, name, mode, uid, gid)?)
+    }
+
+    fn mkdir(&mut self, dir: Ino, name: &str, mode: u32, uid: u32, gid: u32) -> Result<Ino, Errno> {
+        Ok(VolumeHandle::mkdir(self, dir, name, mode, uid, gid)?)
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), Errno> {
+        Ok(VolumeHandle::unlink(self, dir, name)?)
+    }
+
+    fn rename(&mut self, dir: Ino, name: &str, new_dir: Ino, new_name: &str) -> Result<(), Errno> {
+        Ok(VolumeHandle::rename(self, dir, name, new_dir, new_name)?)
+    }
+
+    fn space(&mut self) -> Result<Space, Errno> {
+        let block_size = VolumeHandle::block_size(self) as u64;
+        let (quota, available) = VolumeHandle::space(self)?;
+        Ok(Space { block_size: block_size as u32, blocks: quota / block_size, free: available / block_size })
+    }
+}
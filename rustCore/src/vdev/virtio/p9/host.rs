@@ -0,0 +1,299 @@
+// A directory of the host's own filesystem exported to a guest, for builds
+// with the `host-fs` feature that run on a host that has one.
+//
+// Inode numbers are handed out here as paths are first seen, rather than
+// taken from the host, so files on different host filesystems never share
+// one. Symbolic links, devices and other special files are left out: the
+// guest sees only regular files and directories, and cannot follow a link
+// out of the exported directory.
+//
+// Ownership is applied when the host allows it. A hypervisor running as an
+// unprivileged user creates files as that user whatever the guest asks.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::export::{Export, Space};
+use super::proto::Errno;
+use crate::storage::volume::{Attr, Ino, NodeKind, SetAttr, ROOT_INO};
+
+/// Blocks in which `st_blocks` counts.
+const BLOCK_SIZE: u32 = 512;
+
+pub struct HostDir {
+    /// Paths relative to `root`, which is "" itself.
+    paths: BTreeMap<Ino, PathBuf>,
+    inos: BTreeMap<PathBuf, Ino>,
+    root: PathBuf,
+    read_only: bool,
+    next_ino: Ino,
+}
+
+impl HostDir {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        if !fs::metadata(&root)?.is_dir() {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory));
+        }
+        let mut dir =
+            HostDir { paths: BTreeMap::new(), inos: BTreeMap::new(), root, read_only: false, next_ino: ROOT_INO };
+        dir.ino_for(PathBuf::new());
+        Ok(dir)
+    }
+
+    /// Refuse every change the guest asks for.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn root_path(&self) -> &Path {
+        &self.root
+    }
+
+    fn ino_for(&mut self, path: PathBuf) -> Ino {
+        if let Some(&ino) = self.inos.get(&path) {
+            return ino;
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.paths.insert(ino, path.clone());
+        self.inos.insert(path, ino);
+        ino
+    }
+
+    fn relative(&self, ino: Ino) -> Result<&Path, Errno> {
+        self.paths.get(&ino).map(PathBuf::as_path).ok_or(Errno::ENOENT)
+    }
+
+    fn host_path(&self, ino: Ino) -> Result<PathBuf, Errno> {
+        Ok(self.root.join(self.relative(ino)?))
+    }
+
+    fn child(&self, dir: Ino, name: &str) -> Result<PathBuf, Errno> {
+        Ok(self.relative(dir)?.join(name))
+    }
+
+    fn writable(&self) -> Result<(), Errno> {
+        match self.read_only {
+            true => Err(Errno::EROFS),
+            false => Ok(()),
+        }
+    }
+
+    /// Forget `path` and everything below it.
+    fn forget(&mut self, path: &Path) {
+        let gone: Vec<PathBuf> = self.inos.keys().filter(|known| known.starts_with(path)).cloned().collect();
+        for known in gone {
+            if let Some(ino) = self.inos.remove(&known) {
+                self.paths.remove(&ino);
+            }
+        }
+    }
+
+    fn kind(metadata: &fs::Metadata) -> Option<NodeKind> {
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            Some(NodeKind::Directory)
+        } else if file_type.is_file() {
+            Some(NodeKind::File)
+        } else {
+            None
+        }
+    }
+}
+
+fn errno(err: io::Error) -> Errno {
+    err.raw_os_error().map_or(Errno::EIO, |code| Errno(code as u32))
+}
+
+fn set_owner(path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    std::os::unix::fs::chown(path, uid, gid)
+}
+
+impl Export for HostDir {
+    fn root(&self) -> Ino {
+        ROOT_INO
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn lookup(&mut self, dir: Ino, name: &str) -> Result<Ino, Errno> {
+        let path = self.child(dir, name)?;
+        let metadata = fs::symlink_metadata(self.root.join(&path)).map_err(errno)?;
+        Self::kind(&metadata).ok_or(Errno::ENOENT)?;
+        Ok(self.ino_for(path))
+    }
+
+    fn getattr(&mut self, ino: Ino) -> Result<Attr, Errno> {
+        let metadata = fs::symlink_metadata(self.host_path(ino)?).map_err(errno)?;
+        Ok(Attr {
+            ino,
+            kind: Self::kind(&metadata).ok_or(Errno::ENOENT)?,
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size(),
+            blocks: metadata.blocks(),
+            nlink: metadata.nlink() as u32,
+            mtime: metadata.mtime().max(0) as u64,
+        })
+    }
+
+    fn setattr(&mut self, ino: Ino, attr: SetAttr) -> Result<(), Errno> {
+        self.writable()?;
+        let path = self.host_path(ino)?;
+        if let Some(size) = attr.size {
+            OpenOptions::new().write(true).open(&path).and_then(|file| file.set_len(size)).map_err(errno)?;
+        }
+        if let Some(mode) = attr.mode {
+            fs::set_permissions(&path, Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+        }
+        if attr.uid.is_some() || attr.gid.is_some() {
+            set_owner(&path, attr.uid, attr.gid).map_err(errno)?;
+        }
+        if let Some(mtime) = attr.mtime {
+            let time = UNIX_EPOCH + Duration::from_secs(mtime);
+            File::open(&path).and_then(|file| file.set_modified(time)).map_err(errno)?;
+        }
+        Ok(())
+    }
+
+    fn readdir(&mut self, dir: Ino) -> Result<Vec<(String, Ino, NodeKind)>, Errno> {
+        let relative = self.relative(dir)?.to_path_buf();
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.root.join(&relative)).map_err(errno)? {
+            let entry = entry.map_err(errno)?;
+            let Some(kind) = entry.metadata().ok().as_ref().and_then(Self::kind) else { continue };
+            let Ok(name) = entry.file_name().into_string() else { continue };
+            let ino = self.ino_for(relative.join(&name));
+            entries.push((name, ino, kind));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    fn read(&mut self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let file = File::open(self.host_path(ino)?).map_err(errno)?;
+        let mut done = 0;
+        while done < buf.len() {
+            match file.read_at(&mut buf[done..], offset + done as u64).map_err(errno)? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        Ok(done)
+    }
+
+    fn write(&mut self, ino: Ino, offset: u64, data: &[u8]) -> Result<usize, Errno> {
+        self.writable()?;
+        let file = OpenOptions::new().write(true).open(self.host_path(ino)?).map_err(errno)?;
+        file.write_all_at(data, offset).map_err(errno)?;
+        Ok(data.len())
+    }
+
+    fn create(&mut self, dir: Ino, name: &str, mode: u32, uid: u32, gid: u32) -> Result<Ino, Errno> {
+        self.writable()?;
+        let path = self.child(dir, name)?;
+        let host = self.root.join(&path);
+        OpenOptions::new().write(true).create_new(true).mode(mode & 0o7777).open(&host).map_err(errno)?;
+        let _ = set_owner(&host, Some(uid), Some(gid));
+        Ok(self.ino_for(path))
+    }
+
+    fn mkdir(&mut self, dir: Ino, name: &str, mode: u32, uid: u32, gid: u32) -> Result<Ino, Errno> {
+        self.writable()?;
+        let path = self.child(dir, name)?;
+        let host = self.root.join(&path);
+        fs::create_dir(&host).map_err(errno)?;
+        let _ = fs::set_permissions(&host, Permissions::from_mode(mode & 0o7777));
+        let _ = set_owner(&host, Some(uid), Some(gid));
+        Ok(self.ino_for(path))
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), Errno> {
+        self.writable()?;
+        let path = self.child(dir, name)?;
+        let host = self.root.join(&path);
+        let metadata = fs::symlink_metadata(&host).map_err(errno)?;
+        match Self::kind(&metadata) {
+            Some(NodeKind::Directory) => fs::remove_dir(&host),
+            Some(NodeKind::File) => fs::remove_file(&host),
+            None => return Err(Errno::ENOENT),
+        }
+        .map_err(errno)?;
+        self.forget(&path);
+        Ok(())
+    }
+
+    fn rename(&mut self, dir: Ino, name: &str, new_dir: Ino, new_name: &str) -> Result<(), Errno> {
+        self.writable()?;
+        let (from, to) = (self.child(dir, name)?, self.child(new_dir, new_name)?);
+        fs::rename(self.root.join(&from), self.root.join(&to)).map_err(errno)?;
+        self.forget(&to);
+        // Known paths at or below the old name keep their inodes.
+        let moved: Vec<(PathBuf, Ino)> =
+            self.inos.iter().filter(|(known, _)| known.starts_with(&from)).map(|(k, &i)| (k.clone(), i)).collect();
+        for (old, ino) in moved {
+            let new = to.join(old.strip_prefix(&from).unwrap());
+            self.inos.remove(&old);
+            self.inos.insert(new.clone(), ino);
+            self.paths.insert(ino, new);
+        }
+        Ok(())
+    }
+
+    /// The host offers no portable way to ask a filesystem's size, so an
+    /// export reports none; guests write until the host says it is full.
+    fn space(&mut self) -> Result<Space, Errno> {
+        Ok(Space { block_size: BLOCK_SIZE, blocks: 0, free: 0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_directory() {
+        let root = std::env::temp_dir().join(std::format!("hostdir-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/a.txt"), b"hello").unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("escape")).unwrap();
+
+        let mut dir = HostDir::new(&root).unwrap();
+        let names: Vec<String> = dir.readdir(ROOT_INO).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, ["sub"]);
+        assert_eq!(dir.lookup(ROOT_INO, "escape"), Err(Errno::ENOENT));
+
+        let sub = dir.lookup(ROOT_INO, "sub").unwrap();
+        let file = dir.lookup(sub, "a.txt").unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(dir.read(file, 1, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"ello");
+        assert_eq!(dir.write(file, 5, b", world"), Ok(7));
+        assert_eq!(dir.getattr(file).unwrap().size, 12);
+
+        // Renaming the directory keeps the file's inode working.
+        dir.rename(ROOT_INO, "sub", ROOT_INO, "moved").unwrap();
+        assert_eq!(dir.lookup(ROOT_INO, "moved"), Ok(sub));
+        assert_eq!(fs::read(root.join("moved/a.txt")).unwrap(), b"hello, world");
+        assert_eq!(dir.read(file, 0, &mut buf), Ok(8));
+        let created = dir.create(sub, "b", 0o600, 0, 0).unwrap();
+        assert_eq!(dir.getattr(created).unwrap().mode, 0o600);
+        assert_eq!(dir.unlink(ROOT_INO, "moved"), Err(Errno::ENOTEMPTY));
+
+        let mut read_only = HostDir::new(&root).unwrap().read_only();
+        assert_eq!(read_only.mkdir(ROOT_INO, "new", 0o755, 0, 0), Err(Errno::EROFS));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
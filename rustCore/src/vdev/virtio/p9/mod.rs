@@ -0,0 +1,206 @@
+// virtio-9p: a file tree shared with the guest over 9P2000.L. Linux guests
+// mount it with `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <dir>`,
+// the tag being the one set here and shown in the configuration space.
+//
+// The device has a single request queue. Each chain carries a request in
+// its readable buffers and room for the reply in its writable ones; the
+// request is served at once and the chain returned with the reply.
+
+pub mod export;
+#[cfg(feature = "host-fs")]
+pub mod host;
+pub mod proto;
+pub mod server;
+
+pub use export::{Export, Space};
+#[cfg(feature = "host-fs")]
+pub use host::HostDir;
+pub use proto::Errno;
+pub use server::P9Server;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::queue::{QueueError, Virtqueue};
+use super::{GuestMemory, VirtioDevice, VIRTIO_ID_9P};
+use proto::Rmessage;
+
+/// The configuration space holds the mount tag.
+pub const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+/// Longest mount tag; older Linux guests take no more.
+pub const MAX_TAG_LEN: usize = 31;
+
+const QUEUE_SIZE: u16 = 128;
+
+pub struct VirtioP9<E: Export> {
+    tag: Vec<u8>,
+    server: P9Server<E>,
+}
+
+impl<E: Export> VirtioP9<E> {
+    pub fn new(tag: &str, server: P9Server<E>) -> Self {
+        assert!(!tag.is_empty() && tag.len() <= MAX_TAG_LEN, "bad mount tag {:?}", tag);
+        VirtioP9 { tag: tag.as_bytes().to_vec(), server }
+    }
+
+    pub fn server(&self) -> &P9Server<E> {
+        &self.server
+    }
+
+    /// tag_len, then the tag without a terminating NUL.
+    fn config(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(&self.tag);
+        config
+    }
+}
+
+impl<E: Export> VirtioDevice for VirtioP9<E> {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_9P
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.config();
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.server.reset();
+    }
+
+    fn notify(&mut self, index: usize, queues: &mut [Virtqueue], mem: &dyn GuestMemory) -> Result<(), QueueError> {
+        let queue = &mut queues[index];
+        while let Some(chain) = queue.pop(mem)? {
+            // No request may be longer than the agreed message size, so
+            // reading more than that is only ever the driver's padding or
+            // a guest trying to make the host allocate.
+            let len = core::cmp::min(chain.readable_len(), self.server.msize() as u64);
+            let mut request = vec![0u8; len as usize];
+            chain.read_at(mem, 0, &mut request)?;
+            let Some(mut reply) = self.server.handle(&request) else {
+                queue.add_used(mem, chain.head, 0)?;
+                continue;
+            };
+            // Replies stay within the agreed message size, so only a driver
+            // that gave less room than that gets an error instead.
+            if reply.len() as u64 > chain.writable_len() {
+                let (_, _, tag) = proto::decode_header(&reply).unwrap();
+                reply = Rmessage::Lerror(Errno::EIO).encode(tag);
+            }
+            let written = chain.write_at(mem, 0, &reply)?;
+            queue.add_used(mem, chain.head, written as u32)?;
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "virtio-9p"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::hypervisor::{HypervisorBackend, MockBackend};
+    use crate::storage::tests::RamStorage;
+    use crate::storage::volume::{Access, VolumeHandle, VolumeStore};
+    use crate::vdev::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use crate::vdev::virtio::LayoutMemory;
+
+    const REQUEST: u64 = 0x8000;
+    const REPLY: u64 = 0x9000;
+
+    /// Post a chain holding `request`, with `room` bytes for the reply, and
+    /// return the reply.
+    fn exchange<E: Export>(
+        p9: &mut VirtioP9<E>,
+        queues: &mut [Virtqueue],
+        mem: &dyn GuestMemory,
+        request: &[u8],
+        room: u32,
+    ) -> Vec<u8> {
+        mem.write(REQUEST, request).unwrap();
+        let descs = [(REQUEST, request.len() as u32, VIRTQ_DESC_F_NEXT), (REPLY, room, VIRTQ_DESC_F_WRITE)];
+        for (i, &(addr, len, flags)) in descs.iter().enumerate() {
+            let mut raw = [0u8; 16];
+            raw[0..8].copy_from_slice(&addr.to_le_bytes());
+            raw[8..12].copy_from_slice(&len.to_le_bytes());
+            raw[12..14].copy_from_slice(&flags.to_le_bytes());
+            raw[14..16].copy_from_slice(&(i as u16 + 1).to_le_bytes());
+            mem.write(0x1000 + 16 * i as u64, &raw).unwrap();
+        }
+        let mut idx = [0u8; 2];
+        mem.read(0x2002, &mut idx).unwrap();
+        let idx = u16::from_le_bytes(idx);
+        mem.write(0x2004 + 2 * (idx % 8) as u64, &0u16.to_le_bytes()).unwrap();
+        mem.write(0x2002, &idx.wrapping_add(1).to_le_bytes()).unwrap();
+
+        p9.notify(0, queues, mem).unwrap();
+        let mut used = [0u8; 4];
+        mem.read(0x3004 + 8 * (idx % 8) as u64 + 4, &mut used).unwrap();
+        let mut reply = vec![0u8; u32::from_le_bytes(used) as usize];
+        mem.read(REPLY, &mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn test_mount_tag_and_requests() {
+        let store = VolumeStore::shared(RamStorage::new(64, 16), 64, 16);
+        store.lock().create_volume("shared", 1024).unwrap();
+        store.lock().set_access("shared", "vm", Access::Write).unwrap();
+        let export = VolumeHandle::attach(&store, "shared", "vm").unwrap();
+        let mut p9 = VirtioP9::new("shared", P9Server::new(export));
+        assert_eq!((p9.device_type(), p9.features()), (VIRTIO_ID_9P, VIRTIO_9P_MOUNT_TAG));
+        let mut config = [0xffu8; 10];
+        p9.read_config(0, &mut config);
+        assert_eq!(&config, b"\x06\x00shared\x00\x00");
+
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        let mem = unsafe { LayoutMemory::new(backend.memory_layout().clone(), 0) };
+        let mut queue = Virtqueue::new(QUEUE_SIZE);
+        queue.size = 8;
+        queue.desc_table = 0x1000;
+        queue.avail_ring = 0x2000;
+        queue.used_ring = 0x3000;
+        assert!(queue.enable(false));
+        let mut queues = [queue];
+
+        let mut tversion = vec![21, 0, 0, 0, proto::TVERSION, 0xff, 0xff, 0, 0x10, 0, 0, 8, 0];
+        tversion.extend_from_slice(proto::VERSION.as_bytes());
+        let rversion = Rmessage::Version { msize: 0x1000, version: proto::VERSION.into() }.encode(proto::NOTAG);
+        assert_eq!(exchange(&mut p9, &mut queues, &mem, &tversion, 0x1000), rversion);
+        // A reply that does not fit is swapped for an error.
+        assert_eq!(
+            exchange(&mut p9, &mut queues, &mem, &tversion, 16),
+            Rmessage::Lerror(Errno::EIO).encode(proto::NOTAG)
+        );
+        assert_eq!(exchange(&mut p9, &mut queues, &mem, &tversion[..4], 16), []);
+        // Only the first msize bytes of a request are read: padding past them
+        // is ignored and a request claiming more is never complete.
+        let mut padded = tversion.clone();
+        padded.resize(0x1001, 0);
+        assert_eq!(exchange(&mut p9, &mut queues, &mem, &padded, 0x100), rversion);
+        padded[0..4].copy_from_slice(&0x1001u32.to_le_bytes());
+        assert_eq!(exchange(&mut p9, &mut queues, &mem, &padded, 0x100), []);
+
+        // Fid 0, no afid, empty uname and aname, n_uname 0.
+        let tattach = [23, 0, 0, 0, proto::TATTACH, 1, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(exchange(&mut p9, &mut queues, &mem, &tattach, 64)[4], proto::RATTACH);
+        assert_eq!(p9.server().fid_count(), 1);
+        p9.reset();
+        assert_eq!(p9.server().fid_count(), 0);
+    }
+}
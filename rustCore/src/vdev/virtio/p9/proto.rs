@@ -0,0 +1,773 @@
+// The 9P2000.L wire format: the messages a Linux v9fs client sends and the
+// replies it expects. Every message starts with a `u32` size covering the
+// whole message, a `u8` type and a `u16` tag the reply echoes. Integers are
+// little-endian and strings are a `u16` length followed by UTF-8.
+//
+// Only requests are decoded and only replies encoded; requests of types not
+// listed here decode as `Tmessage::Unsupported` so they can be refused with
+// an error reply rather than dropped.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::codec::{self, Writer};
+
+pub const VERSION: &str = "9P2000.L";
+/// The tag of a Tversion, which is sent outside any session.
+pub const NOTAG: u16 = 0xffff;
+/// No fid, as in the afid of an attach without authentication.
+pub const NOFID: u32 = 0xffff_ffff;
+/// Bytes before the body of every message.
+pub const HEADER_LEN: usize = 7;
+/// Most names a single walk may take.
+pub const MAX_WALK: usize = 16;
+
+// Message types.
+pub const RLERROR: u8 = 7;
+pub const TSTATFS: u8 = 8;
+pub const RSTATFS: u8 = 9;
+pub const TLOPEN: u8 = 12;
+pub const RLOPEN: u8 = 13;
+pub const TLCREATE: u8 = 14;
+pub const RLCREATE: u8 = 15;
+pub const TRENAME: u8 = 20;
+pub const RRENAME: u8 = 21;
+pub const TGETATTR: u8 = 24;
+pub const RGETATTR: u8 = 25;
+pub const TSETATTR: u8 = 26;
+pub const RSETATTR: u8 = 27;
+pub const TREADDIR: u8 = 40;
+pub const RREADDIR: u8 = 41;
+pub const TFSYNC: u8 = 50;
+pub const RFSYNC: u8 = 51;
+pub const TLOCK: u8 = 52;
+pub const RLOCK: u8 = 53;
+pub const TGETLOCK: u8 = 54;
+pub const RGETLOCK: u8 = 55;
+pub const TMKDIR: u8 = 72;
+pub const RMKDIR: u8 = 73;
+pub const TRENAMEAT: u8 = 74;
+pub const RRENAMEAT: u8 = 75;
+pub const TUNLINKAT: u8 = 76;
+pub const RUNLINKAT: u8 = 77;
+pub const TVERSION: u8 = 100;
+pub const RVERSION: u8 = 101;
+pub const TAUTH: u8 = 102;
+pub const TATTACH: u8 = 104;
+pub const RATTACH: u8 = 105;
+pub const TFLUSH: u8 = 108;
+pub const RFLUSH: u8 = 109;
+pub const TWALK: u8 = 110;
+pub const RWALK: u8 = 111;
+pub const TREAD: u8 = 116;
+pub const RREAD: u8 = 117;
+pub const TWRITE: u8 = 118;
+pub const RWRITE: u8 = 119;
+pub const TCLUNK: u8 = 120;
+pub const RCLUNK: u8 = 121;
+pub const TREMOVE: u8 = 122;
+pub const RREMOVE: u8 = 123;
+
+// Qid types.
+pub const QID_FILE: u8 = 0;
+pub const QID_DIR: u8 = 0x80;
+
+// Tlopen and Tlcreate flags, as Linux open(2) flags.
+pub const O_ACCMODE: u32 = 0o3;
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_TRUNC: u32 = 0o1000;
+
+// Tsetattr valid bits.
+pub const SETATTR_MODE: u32 = 1 << 0;
+pub const SETATTR_UID: u32 = 1 << 1;
+pub const SETATTR_GID: u32 = 1 << 2;
+pub const SETATTR_SIZE: u32 = 1 << 3;
+pub const SETATTR_ATIME: u32 = 1 << 4;
+pub const SETATTR_MTIME: u32 = 1 << 5;
+pub const SETATTR_CTIME: u32 = 1 << 6;
+pub const SETATTR_ATIME_SET: u32 = 1 << 7;
+pub const SETATTR_MTIME_SET: u32 = 1 << 8;
+
+/// Tgetattr mask of the fields in struct stat, which is all a reply fills.
+pub const GETATTR_BASIC: u64 = 0x7ff;
+
+/// Tunlinkat flag to remove a directory.
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+// Lock types, flags and Rlock status.
+pub const LOCK_RDLCK: u8 = 0;
+pub const LOCK_WRLCK: u8 = 1;
+pub const LOCK_UNLCK: u8 = 2;
+pub const LOCK_FLAGS_BLOCK: u32 = 1;
+pub const LOCK_SUCCESS: u8 = 0;
+pub const LOCK_BLOCKED: u8 = 1;
+pub const LOCK_ERROR: u8 = 2;
+
+/// Filesystem type reported by Tstatfs, the v9fs magic number.
+pub const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// Linux error numbers, as carried by Rlerror.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u32);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const EIO: Errno = Errno(5);
+    pub const EBADF: Errno = Errno(9);
+    pub const EACCES: Errno = Errno(13);
+    pub const EBUSY: Errno = Errno(16);
+    pub const EEXIST: Errno = Errno(17);
+    pub const ENOTDIR: Errno = Errno(20);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const ENOSPC: Errno = Errno(28);
+    pub const EROFS: Errno = Errno(30);
+    pub const ENOTEMPTY: Errno = Errno(39);
+    pub const EPROTO: Errno = Errno(71);
+    pub const EOPNOTSUPP: Errno = Errno(95);
+    pub const EDQUOT: Errno = Errno(122);
+}
+
+/// The server's unique identification of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub sec: u64,
+    pub nsec: u64,
+}
+
+/// The body of a Tsetattr; `valid` says which fields apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetattrRequest {
+    pub valid: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+}
+
+/// A POSIX byte-range lock as Tlock, Tgetlock and Rgetlock carry it. A
+/// length of 0 runs to the end of the file. Rgetlock has no flags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flock {
+    pub kind: u8,
+    pub flags: u32,
+    pub start: u64,
+    pub length: u64,
+    pub proc_id: u32,
+    pub client_id: String,
+}
+
+/// The body of an Rgetattr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub qid: Qid,
+    /// File type and permission bits, as in `st_mode`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub rdev: u64,
+    pub size: u64,
+    pub blksize: u64,
+    /// 512-byte blocks allocated.
+    pub blocks: u64,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+    pub btime: Timespec,
+    pub gen: u64,
+    pub data_version: u64,
+}
+
+/// The body of an Rstatfs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    pub kind: u32,
+    pub bsize: u32,
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub fsid: u64,
+    pub namelen: u32,
+}
+
+/// One entry of an Rreaddir. `offset` is the cookie the client passes back
+/// to continue after this entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub qid: Qid,
+    pub offset: u64,
+    /// `d_type`, as in Linux `DT_DIR` or `DT_REG`.
+    pub kind: u8,
+    pub name: String,
+}
+
+impl DirEntry {
+    pub fn encoded_len(&self) -> usize {
+        13 + 8 + 1 + 2 + self.name.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tmessage {
+    Version { msize: u32, version: String },
+    Auth { afid: u32, uname: String, aname: String, n_uname: u32 },
+    Attach { fid: u32, afid: u32, uname: String, aname: String, n_uname: u32 },
+    Flush { oldtag: u16 },
+    Walk { fid: u32, newfid: u32, names: Vec<String> },
+    Lopen { fid: u32, flags: u32 },
+    Lcreate { fid: u32, name: String, flags: u32, mode: u32, gid: u32 },
+    Read { fid: u32, offset: u64, count: u32 },
+    Write { fid: u32, offset: u64, data: Vec<u8> },
+    Clunk { fid: u32 },
+    Remove { fid: u32 },
+    Readdir { fid: u32, offset: u64, count: u32 },
+    Getattr { fid: u32, mask: u64 },
+    Setattr { fid: u32, attr: SetattrRequest },
+    Mkdir { dfid: u32, name: String, mode: u32, gid: u32 },
+    Unlinkat { dfid: u32, name: String, flags: u32 },
+    Rename { fid: u32, dfid: u32, name: String },
+    Renameat { olddfid: u32, oldname: String, newdfid: u32, newname: String },
+    Lock { fid: u32, lock: Flock },
+    Getlock { fid: u32, lock: Flock },
+    Statfs { fid: u32 },
+    Fsync { fid: u32, datasync: u32 },
+    /// A request of a type this server does not implement.
+    Unsupported(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rmessage {
+    Lerror(Errno),
+    Version { msize: u32, version: String },
+    Attach(Qid),
+    Flush,
+    Walk(Vec<Qid>),
+    Lopen { qid: Qid, iounit: u32 },
+    Lcreate { qid: Qid, iounit: u32 },
+    Read(Vec<u8>),
+    Write(u32),
+    Clunk,
+    Remove,
+    Readdir(Vec<DirEntry>),
+    Getattr { valid: u64, stat: Stat },
+    Setattr,
+    Mkdir(Qid),
+    Unlinkat,
+    Rename,
+    Renameat,
+    Lock(u8),
+    Getlock(Flock),
+    Statfs(StatFs),
+    Fsync,
+}
+
+/// A request and the tag its reply must carry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub tag: u16,
+    pub message: Tmessage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Too short for a header, or shorter than its size field says.
+    Truncated,
+    /// The header was sound but the body was not; the reply goes to `tag`.
+    Malformed { tag: u16 },
+}
+
+type Reader<'a> = codec::Reader<'a, DecodeError>;
+
+/// The 9P compound fields of requests.
+trait ReaderExt {
+    fn string(&mut self) -> Result<String, DecodeError>;
+    fn timespec(&mut self) -> Result<Timespec, DecodeError>;
+    fn flock(&mut self, with_flags: bool) -> Result<Flock, DecodeError>;
+}
+
+impl ReaderExt for Reader<'_> {
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).map(String::from).map_err(|_| self.error())
+    }
+
+    fn timespec(&mut self) -> Result<Timespec, DecodeError> {
+        Ok(Timespec { sec: self.u64()?, nsec: self.u64()? })
+    }
+
+    fn flock(&mut self, with_flags: bool) -> Result<Flock, DecodeError> {
+        let kind = self.u8()?;
+        let flags = if with_flags { self.u32()? } else { 0 };
+        let (start, length, proc_id) = (self.u64()?, self.u64()?, self.u32()?);
+        Ok(Flock { kind, flags, start, length, proc_id, client_id: self.string()? })
+    }
+}
+
+/// The size, type and tag at the start of `buf`.
+pub fn decode_header(buf: &[u8]) -> Result<(u32, u8, u16), DecodeError> {
+    let mut r = Reader::new(buf, DecodeError::Truncated);
+    Ok((r.u32()?, r.u8()?, r.u16()?))
+}
+
+/// Decode the request at the start of `buf`, which may run on past it.
+pub fn decode(buf: &[u8]) -> Result<Request, DecodeError> {
+    let (size, kind, tag) = decode_header(buf)?;
+    if (size as usize) < HEADER_LEN || size as usize > buf.len() {
+        return Err(DecodeError::Truncated);
+    }
+    let mut r = Reader::new(&buf[HEADER_LEN..size as usize], DecodeError::Malformed { tag });
+    let message = decode_body(kind, &mut r)?;
+    if !r.is_empty() && !matches!(message, Tmessage::Unsupported(_)) {
+        return Err(DecodeError::Malformed { tag });
+    }
+    Ok(Request { tag, message })
+}
+
+fn decode_body(kind: u8, r: &mut Reader) -> Result<Tmessage, DecodeError> {
+    Ok(match kind {
+        TVERSION => Tmessage::Version { msize: r.u32()?, version: r.string()? },
+        TAUTH => Tmessage::Auth { afid: r.u32()?, uname: r.string()?, aname: r.string()?, n_uname: r.u32()? },
+        TATTACH => {
+            let (fid, afid) = (r.u32()?, r.u32()?);
+            Tmessage::Attach { fid, afid, uname: r.string()?, aname: r.string()?, n_uname: r.u32()? }
+        }
+        TFLUSH => Tmessage::Flush { oldtag: r.u16()? },
+        TWALK => {
+            let (fid, newfid, count) = (r.u32()?, r.u32()?, r.u16()? as usize);
+            if count > MAX_WALK {
+                return Err(r.error());
+            }
+            let names = (0..count).map(|_| r.string()).collect::<Result<Vec<_>, _>>()?;
+            Tmessage::Walk { fid, newfid, names }
+        }
+        TLOPEN => Tmessage::Lopen { fid: r.u32()?, flags: r.u32()? },
+        TLCREATE => {
+            Tmessage::Lcreate { fid: r.u32()?, name: r.string()?, flags: r.u32()?, mode: r.u32()?, gid: r.u32()? }
+        }
+        TREAD => Tmessage::Read { fid: r.u32()?, offset: r.u64()?, count: r.u32()? },
+        TWRITE => {
+            let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+            Tmessage::Write { fid, offset, data: r.take(count as usize)?.to_vec() }
+        }
+        TCLUNK => Tmessage::Clunk { fid: r.u32()? },
+        TREMOVE => Tmessage::Remove { fid: r.u32()? },
+        TREADDIR => Tmessage::Readdir { fid: r.u32()?, offset: r.u64()?, count: r.u32()? },
+        TGETATTR => Tmessage::Getattr { fid: r.u32()?, mask: r.u64()? },
+        TSETATTR => {
+            let fid = r.u32()?;
+            let (valid, mode, uid, gid, size) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?, r.u64()?);
+            let attr = SetattrRequest { valid, mode, uid, gid, size, atime: r.timespec()?, mtime: r.timespec()? };
+            Tmessage::Setattr { fid, attr }
+        }
+        TMKDIR => Tmessage::Mkdir { dfid: r.u32()?, name: r.string()?, mode: r.u32()?, gid: r.u32()? },
+        TUNLINKAT => Tmessage::Unlinkat { dfid: r.u32()?, name: r.string()?, flags: r.u32()? },
+        TRENAME => Tmessage::Rename { fid: r.u32()?, dfid: r.u32()?, name: r.string()? },
+        TRENAMEAT => {
+            Tmessage::Renameat { olddfid: r.u32()?, oldname: r.string()?, newdfid: r.u32()?, newname: r.string()? }
+        }
+        TLOCK => Tmessage::Lock { fid: r.u32()?, lock: r.flock(true)? },
+        TGETLOCK => Tmessage::Getlock { fid: r.u32()?, lock: r.flock(false)? },
+        TSTATFS => Tmessage::Statfs { fid: r.u32()? },
+        TFSYNC => Tmessage::Fsync { fid: r.u32()?, datasync: r.u32()? },
+        other => Tmessage::Unsupported(other),
+    })
+}
+
+/// The 9P compound fields of replies.
+trait WriterExt {
+    fn string(&mut self, value: &str) -> &mut Self;
+    fn qid(&mut self, qid: &Qid) -> &mut Self;
+    fn timespec(&mut self, time: &Timespec) -> &mut Self;
+}
+
+impl WriterExt for Writer {
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16).bytes(value.as_bytes())
+    }
+
+    fn qid(&mut self, qid: &Qid) -> &mut Self {
+        self.u8(qid.kind).u32(qid.version).u64(qid.path)
+    }
+
+    fn timespec(&mut self, time: &Timespec) -> &mut Self {
+        self.u64(time.sec).u64(time.nsec)
+    }
+}
+
+impl Rmessage {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Rmessage::Lerror(_) => RLERROR,
+            Rmessage::Version { .. } => RVERSION,
+            Rmessage::Attach(_) => RATTACH,
+            Rmessage::Flush => RFLUSH,
+            Rmessage::Walk(_) => RWALK,
+            Rmessage::Lopen { .. } => RLOPEN,
+            Rmessage::Lcreate { .. } => RLCREATE,
+            Rmessage::Read(_) => RREAD,
+            Rmessage::Write(_) => RWRITE,
+            Rmessage::Clunk => RCLUNK,
+            Rmessage::Remove => RREMOVE,
+            Rmessage::Readdir(_) => RREADDIR,
+            Rmessage::Getattr { .. } => RGETATTR,
+            Rmessage::Setattr => RSETATTR,
+            Rmessage::Mkdir(_) => RMKDIR,
+            Rmessage::Unlinkat => RUNLINKAT,
+            Rmessage::Rename => RRENAME,
+            Rmessage::Renameat => RRENAMEAT,
+            Rmessage::Lock(_) => RLOCK,
+            Rmessage::Getlock(_) => RGETLOCK,
+            Rmessage::Statfs(_) => RSTATFS,
+            Rmessage::Fsync => RFSYNC,
+        }
+    }
+
+    /// The whole reply, header included.
+    pub fn encode(&self, tag: u16) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.u32(0).u8(self.kind()).u16(tag);
+        match self {
+            Rmessage::Lerror(errno) => {
+                w.u32(errno.0);
+            }
+            Rmessage::Version { msize, version } => {
+                w.u32(*msize).string(version);
+            }
+            Rmessage::Attach(qid) | Rmessage::Mkdir(qid) => {
+                w.qid(qid);
+            }
+            Rmessage::Walk(qids) => {
+                w.u16(qids.len() as u16);
+                for qid in qids {
+                    w.qid(qid);
+                }
+            }
+            Rmessage::Lopen { qid, iounit } | Rmessage::Lcreate { qid, iounit } => {
+                w.qid(qid).u32(*iounit);
+            }
+            Rmessage::Read(data) => {
+                w.u32(data.len() as u32).bytes(data);
+            }
+            Rmessage::Write(count) => {
+                w.u32(*count);
+            }
+            Rmessage::Readdir(entries) => {
+                w.u32(entries.iter().map(DirEntry::encoded_len).sum::<usize>() as u32);
+                for entry in entries {
+                    w.qid(&entry.qid).u64(entry.offset).u8(entry.kind).string(&entry.name);
+                }
+            }
+            Rmessage::Getattr { valid, stat } => {
+                w.u64(*valid).qid(&stat.qid).u32(stat.mode).u32(stat.uid).u32(stat.gid);
+                w.u64(stat.nlink).u64(stat.rdev).u64(stat.size).u64(stat.blksize).u64(stat.blocks);
+                w.timespec(&stat.atime).timespec(&stat.mtime).timespec(&stat.ctime).timespec(&stat.btime);
+                w.u64(stat.gen).u64(stat.data_version);
+            }
+            Rmessage::Lock(status) => {
+                w.u8(*status);
+            }
+            Rmessage::Getlock(lock) => {
+                w.u8(lock.kind).u64(lock.start).u64(lock.length).u32(lock.proc_id).string(&lock.client_id);
+            }
+            Rmessage::Statfs(fs) => {
+                w.u32(fs.kind).u32(fs.bsize).u64(fs.blocks).u64(fs.bfree).u64(fs.bavail);
+                w.u64(fs.files).u64(fs.ffree).u64(fs.fsid).u32(fs.namelen);
+            }
+            Rmessage::Flush
+            | Rmessage::Clunk
+            | Rmessage::Remove
+            | Rmessage::Setattr
+            | Rmessage::Unlinkat
+            | Rmessage::Rename
+            | Rmessage::Renameat
+            | Rmessage::Fsync => {}
+        }
+        let size = w.len() as u32;
+        w.patch_u32(0, size);
+        w.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // Messages as a Linux v9fs client and its server put them on the wire,
+    // one field per line.
+
+    const TVERSION_MSG: &[u8] = &[
+        0x15, 0x00, 0x00, 0x00, 0x64, 0xff, 0xff, // Tversion, NOTAG
+        0x00, 0x00, 0x08, 0x00, // msize 512 KiB
+        0x08, 0x00, 0x39, 0x50, 0x32, 0x30, 0x30, 0x30, 0x2e, 0x4c, // "9P2000.L"
+    ];
+
+    const TATTACH_MSG: &[u8] = &[
+        0x1d, 0x00, 0x00, 0x00, 0x68, 0x01, 0x00, // Tattach, tag 1
+        0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // fid 0, no afid
+        0x06, 0x00, 0x6e, 0x6f, 0x62, 0x6f, 0x64, 0x79, // uname "nobody"
+        0x00, 0x00, // aname ""
+        0x00, 0x00, 0x00, 0x00, // n_uname 0
+    ];
+
+    const TWALK_MSG: &[u8] = &[
+        0x1d, 0x00, 0x00, 0x00, 0x6e, 0x01, 0x00, // Twalk, tag 1
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // fid 0, newfid 1
+        0x02, 0x00, // two names
+        0x03, 0x00, 0x73, 0x75, 0x62, // "sub"
+        0x05, 0x00, 0x61, 0x2e, 0x74, 0x78, 0x74, // "a.txt"
+    ];
+
+    const TLOPEN_MSG: &[u8] = &[
+        0x0f, 0x00, 0x00, 0x00, 0x0c, 0x01, 0x00, // Tlopen, tag 1
+        0x01, 0x00, 0x00, 0x00, // fid 1
+        0x02, 0x80, 0x00, 0x00, // O_RDWR | O_LARGEFILE
+    ];
+
+    const TWRITE_MSG: &[u8] = &[
+        0x1a, 0x00, 0x00, 0x00, 0x76, 0x02, 0x00, // Twrite, tag 2
+        0x01, 0x00, 0x00, 0x00, // fid 1
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset 5
+        0x03, 0x00, 0x00, 0x00, // count 3
+        0x61, 0x62, 0x63, // "abc"
+    ];
+
+    const TSETATTR_MSG: &[u8] = &[
+        0x43, 0x00, 0x00, 0x00, 0x1a, 0x01, 0x00, // Tsetattr, tag 1
+        0x01, 0x00, 0x00, 0x00, // fid 1
+        0x21, 0x01, 0x00, 0x00, // MODE | MTIME | MTIME_SET
+        0xa0, 0x81, 0x00, 0x00, // mode
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // uid, gid
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // size
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // atime
+        0x00, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mtime
+    ];
+
+    const TLOCK_MSG: &[u8] = &[
+        0x2b, 0x00, 0x00, 0x00, 0x34, 0x03, 0x00, // Tlock, tag 3
+        0x01, 0x00, 0x00, 0x00, // fid 1
+        0x01, 0x01, 0x00, 0x00, 0x00, // F_WRLCK, BLOCK
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // start 0
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // length 0: to the end
+        0xd2, 0x04, 0x00, 0x00, // proc_id
+        0x05, 0x00, 0x67, 0x75, 0x65, 0x73, 0x74, // client_id "guest"
+    ];
+
+    const TRENAMEAT_MSG: &[u8] = &[
+        0x15, 0x00, 0x00, 0x00, 0x4a, 0x01, 0x00, // Trenameat, tag 1
+        0x00, 0x00, 0x00, 0x00, // olddirfid 0
+        0x01, 0x00, 0x61, // "a"
+        0x02, 0x00, 0x00, 0x00, // newdirfid 2
+        0x01, 0x00, 0x62, // "b"
+    ];
+
+    const TXATTRWALK_MSG: &[u8] = &[
+        0x17, 0x00, 0x00, 0x00, 0x1e, 0x04, 0x00, // Txattrwalk, tag 4
+        0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // fid 1, newfid 2
+        0x06, 0x00, 0x75, 0x73, 0x65, 0x72, 0x2e, 0x61, // "user.a"
+    ];
+
+    const RVERSION_MSG: &[u8] = &[
+        0x15, 0x00, 0x00, 0x00, 0x65, 0xff, 0xff, // Rversion, NOTAG
+        0x00, 0x00, 0x02, 0x00, // msize 128 KiB
+        0x08, 0x00, 0x39, 0x50, 0x32, 0x30, 0x30, 0x30, 0x2e, 0x4c, // "9P2000.L"
+    ];
+
+    const RWALK_MSG: &[u8] = &[
+        0x23, 0x00, 0x00, 0x00, 0x6f, 0x01, 0x00, // Rwalk, tag 1
+        0x02, 0x00, // two qids
+        0x80, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // directory 2
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // file 3
+    ];
+
+    const RREAD_MSG: &[u8] = &[
+        0x10, 0x00, 0x00, 0x00, 0x75, 0x00, 0x00, // Rread, tag 0
+        0x05, 0x00, 0x00, 0x00, // count 5
+        0x68, 0x65, 0x6c, 0x6c, 0x6f, // "hello"
+    ];
+
+    const RLERROR_MSG: &[u8] = &[
+        0x0b, 0x00, 0x00, 0x00, 0x07, 0x05, 0x00, // Rlerror, tag 5
+        0x02, 0x00, 0x00, 0x00, // ENOENT
+    ];
+
+    const RREADDIR_MSG: &[u8] = &[
+        0x3e, 0x00, 0x00, 0x00, 0x29, 0x02, 0x00, // Rreaddir, tag 2
+        0x33, 0x00, 0x00, 0x00, // count
+        0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // qid: directory 1
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset 1
+        0x04, // DT_DIR
+        0x01, 0x00, 0x2e, // "."
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // qid: file 7
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset 2
+        0x08, // DT_REG
+        0x02, 0x00, 0x61, 0x62, // "ab"
+    ];
+
+    const RGETATTR_MSG: &[u8] = &[
+        0xa0, 0x00, 0x00, 0x00, 0x19, 0x01, 0x00, // Rgetattr, tag 1
+        0xff, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // valid
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // qid: file 3
+        0xa4, 0x81, 0x00, 0x00, 0xe8, 0x03, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, // mode, uid, gid
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nlink
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // rdev
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // size
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // blksize
+        0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // blocks
+        0x00, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // atime
+        0x00, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mtime
+        0x00, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ctime
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // btime
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // gen
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // data_version
+    ];
+
+    const RGETLOCK_MSG: &[u8] = &[
+        0x1f, 0x00, 0x00, 0x00, 0x37, 0x03, 0x00, // Rgetlock, tag 3
+        0x00, // F_RDLCK
+        0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // start
+        0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // length
+        0x63, 0x00, 0x00, 0x00, // proc_id
+        0x01, 0x00, 0x68, // client_id "h"
+    ];
+
+    const RSTATFS_MSG: &[u8] = &[
+        0x43, 0x00, 0x00, 0x00, 0x09, 0x01, 0x00, // Rstatfs, tag 1
+        0x97, 0x19, 0x02, 0x01, 0x00, 0x10, 0x00, 0x00, // V9FS_MAGIC, bsize
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // blocks
+        0xc8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // bfree
+        0xc8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // bavail
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // files
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ffree
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // fsid
+        0xff, 0x00, 0x00, 0x00, // namelen
+    ];
+
+    const RCLUNK_MSG: &[u8] = &[
+        0x07, 0x00, 0x00, 0x00, 0x79, 0x06, 0x00, // Rclunk, tag 6
+    ];
+
+    #[test]
+    fn test_decode_requests() {
+        let decoded = |buf| decode(buf).unwrap();
+        assert_eq!(
+            decoded(TVERSION_MSG),
+            Request { tag: NOTAG, message: Tmessage::Version { msize: 512 * 1024, version: String::from(VERSION) } }
+        );
+        let attach =
+            Tmessage::Attach { fid: 0, afid: NOFID, uname: String::from("nobody"), aname: String::new(), n_uname: 0 };
+        assert_eq!(decoded(TATTACH_MSG), Request { tag: 1, message: attach });
+        let names = vec![String::from("sub"), String::from("a.txt")];
+        assert_eq!(decoded(TWALK_MSG).message, Tmessage::Walk { fid: 0, newfid: 1, names });
+        assert_eq!(decoded(TLOPEN_MSG).message, Tmessage::Lopen { fid: 1, flags: 0o100002 });
+        let write = Tmessage::Write { fid: 1, offset: 5, data: b"abc".to_vec() };
+        assert_eq!(decoded(TWRITE_MSG), Request { tag: 2, message: write });
+        let attr = SetattrRequest {
+            valid: SETATTR_MODE | SETATTR_MTIME | SETATTR_MTIME_SET,
+            mode: 0o100640,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: Timespec::default(),
+            mtime: Timespec { sec: 1_700_000_000, nsec: 0 },
+        };
+        assert_eq!(decoded(TSETATTR_MSG).message, Tmessage::Setattr { fid: 1, attr });
+        let client_id = String::from("guest");
+        let lock = Flock { kind: LOCK_WRLCK, flags: LOCK_FLAGS_BLOCK, start: 0, length: 0, proc_id: 1234, client_id };
+        assert_eq!(decoded(TLOCK_MSG), Request { tag: 3, message: Tmessage::Lock { fid: 1, lock } });
+        let renameat =
+            Tmessage::Renameat { olddfid: 0, oldname: String::from("a"), newdfid: 2, newname: String::from("b") };
+        assert_eq!(decoded(TRENAMEAT_MSG).message, renameat);
+        assert_eq!(decoded(TXATTRWALK_MSG), Request { tag: 4, message: Tmessage::Unsupported(30) });
+    }
+
+    #[test]
+    fn test_encode_replies() {
+        let version = Rmessage::Version { msize: 128 * 1024, version: String::from(VERSION) };
+        assert_eq!(version.encode(NOTAG), RVERSION_MSG);
+        let qids = vec![Qid { kind: QID_DIR, version: 0, path: 2 }, Qid { kind: QID_FILE, version: 0, path: 3 }];
+        assert_eq!(Rmessage::Walk(qids).encode(1), RWALK_MSG);
+        assert_eq!(Rmessage::Read(b"hello".to_vec()).encode(0), RREAD_MSG);
+        assert_eq!(Rmessage::Lerror(Errno::ENOENT).encode(5), RLERROR_MSG);
+        let entries = vec![
+            DirEntry { qid: Qid { kind: QID_DIR, version: 0, path: 1 }, offset: 1, kind: 4, name: String::from(".") },
+            DirEntry { qid: Qid { kind: QID_FILE, version: 0, path: 7 }, offset: 2, kind: 8, name: String::from("ab") },
+        ];
+        assert_eq!(Rmessage::Readdir(entries).encode(2), RREADDIR_MSG);
+        let time = Timespec { sec: 1_700_000_000, nsec: 0 };
+        let stat = Stat {
+            qid: Qid { kind: QID_FILE, version: 0, path: 3 },
+            mode: 0o100644,
+            uid: 1000,
+            gid: 100,
+            nlink: 1,
+            rdev: 0,
+            size: 5,
+            blksize: 4096,
+            blocks: 8,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            btime: Timespec::default(),
+            gen: 0,
+            data_version: 0,
+        };
+        assert_eq!(Rmessage::Getattr { valid: GETATTR_BASIC, stat }.encode(1), RGETATTR_MSG);
+        let lock =
+            Flock { kind: LOCK_RDLCK, flags: 0, start: 10, length: 20, proc_id: 99, client_id: String::from("h") };
+        assert_eq!(Rmessage::Getlock(lock).encode(3), RGETLOCK_MSG);
+        let fs = StatFs {
+            kind: V9FS_MAGIC,
+            bsize: 4096,
+            blocks: 256,
+            bfree: 200,
+            bavail: 200,
+            files: 0,
+            ffree: 0,
+            fsid: 0,
+            namelen: 255,
+        };
+        assert_eq!(Rmessage::Statfs(fs).encode(1), RSTATFS_MSG);
+        assert_eq!(Rmessage::Clunk.encode(6), RCLUNK_MSG);
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        assert_eq!(decode(&TVERSION_MSG[..5]), Err(DecodeError::Truncated));
+        assert_eq!(decode(&TVERSION_MSG[..20]), Err(DecodeError::Truncated));
+        // Trailing bytes past the size are not part of the message.
+        let mut padded = TLOPEN_MSG.to_vec();
+        padded.extend_from_slice(&[0xaa; 8]);
+        assert_eq!(decode(&padded).unwrap().message, Tmessage::Lopen { fid: 1, flags: 0o100002 });
+
+        // A size that cuts the body short, or leaves bytes over.
+        let mut short = TWALK_MSG.to_vec();
+        short[0] -= 1;
+        assert_eq!(decode(&short), Err(DecodeError::Malformed { tag: 1 }));
+        let mut long = TLOPEN_MSG.to_vec();
+        long.push(0);
+        long[0] += 1;
+        assert_eq!(decode(&long), Err(DecodeError::Malformed { tag: 1 }));
+        let mut bad_utf8 = TRENAMEAT_MSG.to_vec();
+        bad_utf8[13] = 0xff;
+        assert_eq!(decode(&bad_utf8), Err(DecodeError::Malformed { tag: 1 }));
+        let mut walk = TWALK_MSG.to_vec();
+        walk[15] = MAX_WALK as u8 + 1;
+        assert_eq!(decode(&walk), Err(DecodeError::Malformed { tag: 1 }));
+    }
+}
@@ -0,0 +1,750 @@
+// A 9P2000.L file server for one export and one client.
+//
+// Each fid the client holds remembers the inodes from the export's root
+// down to its file, with the names they were reached by, so `..` is walked
+// without help from the export and never leads out of it, and a fid can be
+// renamed or removed by name. Directory listings are taken whole when a
+// client starts reading a directory and handed out from that snapshot, with
+// each entry's position as its cookie.
+//
+// Requests are served one at a time to completion, so there is never a
+// request in flight for Tflush to cancel. Byte-range locks are advisory and
+// kept here, per file and owner; a lock that conflicts is refused with
+// BLOCKED and the client retries it.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::export::Export;
+use super::proto::{
+    self, DecodeError, DirEntry, Errno, Flock, Qid, Request, Rmessage, SetattrRequest, Stat, StatFs, Timespec, Tmessage,
+};
+use crate::storage::volume::{Attr, Ino, NodeKind, SetAttr, MAX_NAME_LEN};
+
+/// Largest message size the server agrees to.
+pub const MAX_MSIZE: u32 = 512 * 1024;
+/// Smallest message size a client may ask for.
+pub const MIN_MSIZE: u32 = 4096;
+/// Bytes of an Rread or Rreaddir before its data.
+const READ_HEADER_LEN: u32 = 11;
+
+// Linux d_type values and st_mode file types.
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+struct Fid {
+    /// Inodes from the root down to this fid's file, with their names.
+    path: Vec<(Ino, String)>,
+    /// The user named when attaching, who owns what the fid creates.
+    uid: u32,
+    /// Access mode once opened.
+    open: Option<u32>,
+    /// A directory's entries, listed when reading started.
+    listing: Vec<DirEntry>,
+}
+
+impl Fid {
+    fn ino(&self) -> Ino {
+        self.path.last().unwrap().0
+    }
+
+    /// The directory holding this fid's file and the file's name in it.
+    fn parent(&self) -> Result<(Ino, &str), Errno> {
+        match self.path.len() {
+            0 | 1 => Err(Errno::EBUSY),
+            len => Ok((self.path[len - 2].0, &self.path[len - 1].1)),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RangeLock {
+    ino: Ino,
+    proc_id: u32,
+    client_id: String,
+    exclusive: bool,
+    start: u64,
+    /// One past the last byte; `u64::MAX` runs to the end of the file.
+    end: u64,
+}
+
+impl RangeLock {
+    fn new(ino: Ino, lock: &Flock) -> Self {
+        let end = if lock.length == 0 { u64::MAX } else { lock.start.saturating_add(lock.length) };
+        let exclusive = lock.kind == proto::LOCK_WRLCK;
+        RangeLock { ino, proc_id: lock.proc_id, client_id: lock.client_id.clone(), exclusive, start: lock.start, end }
+    }
+
+    fn same_owner(&self, other: &RangeLock) -> bool {
+        self.proc_id == other.proc_id && self.client_id == other.client_id
+    }
+
+    fn overlaps(&self, other: &RangeLock) -> bool {
+        self.ino == other.ino && self.start < other.end && other.start < self.end
+    }
+
+    fn conflicts(&self, other: &RangeLock) -> bool {
+        self.overlaps(other) && !self.same_owner(other) && (self.exclusive || other.exclusive)
+    }
+}
+
+pub struct P9Server<E: Export> {
+    export: E,
+    msize: u32,
+    fids: BTreeMap<u32, Fid>,
+    locks: Vec<RangeLock>,
+    /// Seconds since the Unix epoch, to stamp writes with.
+    clock: Option<Box<dyn Fn() -> u64 + Send>>,
+}
+
+impl<E: Export> P9Server<E> {
+    pub fn new(export: E) -> Self {
+        P9Server { export, msize: MAX_MSIZE, fids: BTreeMap::new(), locks: Vec::new(), clock: None }
+    }
+
+    /// Set modification times on writes, and to now when a client asks.
+    /// Without a clock they only change when a client gives the time.
+    pub fn with_clock(mut self, clock: Box<dyn Fn() -> u64 + Send>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn export(&self) -> &E {
+        &self.export
+    }
+
+    /// The message size agreed with the client.
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    /// Fids the client holds.
+    pub fn fid_count(&self) -> usize {
+        self.fids.len()
+    }
+
+    /// Forget the session, as when the device is reset.
+    pub fn reset(&mut self) {
+        self.msize = MAX_MSIZE;
+        self.fids.clear();
+        self.locks.clear();
+    }
+
+    /// Serve one request and return the reply, or `None` when the request
+    /// is too broken to say which tag to answer.
+    pub fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let (tag, reply) = match proto::decode(request) {
+            Ok(Request { tag, message }) => (tag, self.serve(message).unwrap_or_else(Rmessage::Lerror)),
+            Err(DecodeError::Malformed { tag }) => (tag, Rmessage::Lerror(Errno::EPROTO)),
+            Err(DecodeError::Truncated) => return None,
+        };
+        Some(reply.encode(tag))
+    }
+
+    fn serve(&mut self, message: Tmessage) -> Result<Rmessage, Errno> {
+        match message {
+            Tmessage::Version { msize, version } => {
+                self.reset();
+                if version != proto::VERSION {
+                    return Ok(Rmessage::Version { msize: self.msize, version: String::from("unknown") });
+                }
+                if msize < MIN_MSIZE {
+                    return Err(Errno::EINVAL);
+                }
+                self.msize = core::cmp::min(msize, MAX_MSIZE);
+                Ok(Rmessage::Version { msize: self.msize, version: String::from(proto::VERSION) })
+            }
+            Tmessage::Auth { .. } => Err(Errno::EOPNOTSUPP),
+            Tmessage::Attach { fid, afid, n_uname, .. } => {
+                if afid != proto::NOFID {
+                    return Err(Errno::EINVAL);
+                }
+                if self.fids.contains_key(&fid) {
+                    return Err(Errno::EBADF);
+                }
+                let root = self.export.root();
+                let qid = self.qid(root)?;
+                self.fids.insert(
+                    fid,
+                    Fid { path: vec![(root, String::new())], uid: n_uname, open: None, listing: Vec::new() },
+                );
+                Ok(Rmessage::Attach(qid))
+            }
+            Tmessage::Flush { .. } => Ok(Rmessage::Flush),
+            Tmessage::Walk { fid, newfid, names } => self.walk(fid, newfid, &names),
+            Tmessage::Lopen { fid, flags } => {
+                let ino = self.closed_fid(fid)?.ino();
+                let attr = self.export.getattr(ino)?;
+                let mode = flags & proto::O_ACCMODE;
+                if mode != proto::O_RDONLY {
+                    if attr.kind == NodeKind::Directory {
+                        return Err(Errno::EISDIR);
+                    }
+                    if self.export.read_only() {
+                        return Err(Errno::EROFS);
+                    }
+                    if flags & proto::O_TRUNC != 0 {
+                        self.export.setattr(ino, SetAttr { size: Some(0), mtime: self.now(), ..SetAttr::default() })?;
+                    }
+                }
+                self.fid(fid)?.open = Some(mode);
+                Ok(Rmessage::Lopen { qid: qid_of(&attr), iounit: 0 })
+            }
+            Tmessage::Lcreate { fid, name, flags, mode, gid } => {
+                check_name(&name)?;
+                let (dir, uid) = {
+                    let fid = self.closed_fid(fid)?;
+                    (fid.ino(), fid.uid)
+                };
+                if self.export.read_only() {
+                    return Err(Errno::EROFS);
+                }
+                let ino = self.export.create(dir, &name, mode & 0o7777, uid, gid)?;
+                let qid = self.qid(ino)?;
+                let fid = self.fid(fid)?;
+                fid.path.push((ino, name));
+                fid.open = Some(flags & proto::O_ACCMODE);
+                Ok(Rmessage::Lcreate { qid, iounit: 0 })
+            }
+            Tmessage::Read { fid, offset, count } => {
+                let ino = self.open_fid(fid, proto::O_WRONLY)?;
+                let mut buf = vec![0u8; core::cmp::min(count, self.msize - READ_HEADER_LEN) as usize];
+                let len = self.export.read(ino, offset, &mut buf)?;
+                buf.truncate(len);
+                Ok(Rmessage::Read(buf))
+            }
+            Tmessage::Write { fid, offset, data } => {
+                let ino = self.open_fid(fid, proto::O_RDONLY)?;
+                let written = self.export.write(ino, offset, &data)?;
+                if let Some(now) = self.now() {
+                    self.export.setattr(ino, SetAttr { mtime: Some(now), ..SetAttr::default() })?;
+                }
+                Ok(Rmessage::Write(written as u32))
+            }
+            Tmessage::Clunk { fid } => {
+                self.fids.remove(&fid).ok_or(Errno::EBADF)?;
+                Ok(Rmessage::Clunk)
+            }
+            Tmessage::Remove { fid } => {
+                // The fid goes whether or not the file does.
+                let fid = self.fids.remove(&fid).ok_or(Errno::EBADF)?;
+                let (dir, name) = fid.parent()?;
+                self.export.unlink(dir, name)?;
+                self.forget(fid.ino());
+                Ok(Rmessage::Remove)
+            }
+            Tmessage::Readdir { fid, offset, count } => self.readdir(fid, offset, count),
+            Tmessage::Getattr { fid, .. } => {
+                let ino = self.fid(fid)?.ino();
+                let attr = self.export.getattr(ino)?;
+                Ok(Rmessage::Getattr { valid: proto::GETATTR_BASIC, stat: self.stat(&attr)? })
+            }
+            Tmessage::Setattr { fid, attr } => {
+                let ino = self.fid(fid)?.ino();
+                let changes = self.changes(&attr);
+                if changes != SetAttr::default() {
+                    self.export.setattr(ino, changes)?;
+                }
+                Ok(Rmessage::Setattr)
+            }
+            Tmessage::Mkdir { dfid, name, mode, gid } => {
+                check_name(&name)?;
+                let (dir, uid) = {
+                    let fid = self.fid(dfid)?;
+                    (fid.ino(), fid.uid)
+                };
+                let ino = self.export.mkdir(dir, &name, mode & 0o7777, uid, gid)?;
+                Ok(Rmessage::Mkdir(self.qid(ino)?))
+            }
+            Tmessage::Unlinkat { dfid, name, flags } => {
+                check_name(&name)?;
+                let dir = self.fid(dfid)?.ino();
+                let ino = self.export.lookup(dir, &name)?;
+                match (self.export.getattr(ino)?.kind, flags & proto::AT_REMOVEDIR != 0) {
+                    (NodeKind::File, true) => return Err(Errno::ENOTDIR),
+                    (NodeKind::Directory, false) => return Err(Errno::EISDIR),
+                    _ => self.export.unlink(dir, &name)?,
+                }
+                self.forget(ino);
+                Ok(Rmessage::Unlinkat)
+            }
+            Tmessage::Rename { fid, dfid, name } => {
+                let (dir, old_name) = {
+                    let (dir, old_name) = self.fid(fid)?.parent()?;
+                    (dir, String::from(old_name))
+                };
+                self.rename(dir, &old_name, dfid, &name)?;
+                Ok(Rmessage::Rename)
+            }
+            Tmessage::Renameat { olddfid, oldname, newdfid, newname } => {
+                check_name(&oldname)?;
+                let dir = self.fid(olddfid)?.ino();
+                self.rename(dir, &oldname, newdfid, &newname)?;
+                Ok(Rmessage::Renameat)
+            }
+            Tmessage::Lock { fid, lock } => {
+                let ino = self.fid(fid)?.ino();
+                Ok(Rmessage::Lock(self.lock(ino, &lock)))
+            }
+            Tmessage::Getlock { fid, lock } => {
+                let ino = self.fid(fid)?.ino();
+                let wanted = RangeLock::new(ino, &lock);
+                let reply =
+                    match self.locks.iter().find(|held| lock.kind != proto::LOCK_UNLCK && held.conflicts(&wanted)) {
+                        Some(held) => Flock {
+                            kind: if held.exclusive { proto::LOCK_WRLCK } else { proto::LOCK_RDLCK },
+                            flags: 0,
+                            start: held.start,
+                            length: if held.end == u64::MAX { 0 } else { held.end - held.start },
+                            proc_id: held.proc_id,
+                            client_id: held.client_id.clone(),
+                        },
+                        None => Flock { kind: proto::LOCK_UNLCK, ..lock },
+                    };
+                Ok(Rmessage::Getlock(reply))
+            }
+            Tmessage::Statfs { fid } => {
+                self.fid(fid)?;
+                let space = self.export.space()?;
+                Ok(Rmessage::Statfs(StatFs {
+                    kind: proto::V9FS_MAGIC,
+                    bsize: space.block_size,
+                    blocks: space.blocks,
+                    bfree: space.free,
+                    bavail: space.free,
+                    files: 0,
+                    ffree: 0,
+                    fsid: 0,
+                    namelen: MAX_NAME_LEN as u32,
+                }))
+            }
+            Tmessage::Fsync { fid, .. } => {
+                self.fid(fid)?;
+                Ok(Rmessage::Fsync)
+            }
+            Tmessage::Unsupported(_) => Err(Errno::EOPNOTSUPP),
+        }
+    }
+
+    fn fid(&mut self, fid: u32) -> Result<&mut Fid, Errno> {
+        self.fids.get_mut(&fid).ok_or(Errno::EBADF)
+    }
+
+    /// A fid that has not been opened yet.
+    fn closed_fid(&mut self, fid: u32) -> Result<&mut Fid, Errno> {
+        match self.fid(fid)? {
+            fid if fid.open.is_none() => Ok(fid),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    /// The file behind an open fid not opened with the access mode `not`.
+    fn open_fid(&mut self, fid: u32, not: u32) -> Result<Ino, Errno> {
+        let fid = self.fid(fid)?;
+        match fid.open {
+            Some(mode) if mode != not => Ok(fid.ino()),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    fn now(&self) -> Option<u64> {
+        self.clock.as_ref().map(|clock| clock())
+    }
+
+    fn qid(&mut self, ino: Ino) -> Result<Qid, Errno> {
+        Ok(qid_of(&self.export.getattr(ino)?))
+    }
+
+    fn stat(&mut self, attr: &Attr) -> Result<Stat, Errno> {
+        let block_size = self.export.space()?.block_size as u64;
+        let file_type = match attr.kind {
+            NodeKind::File => S_IFREG,
+            NodeKind::Directory => S_IFDIR,
+        };
+        let time = Timespec { sec: attr.mtime, nsec: 0 };
+        Ok(Stat {
+            qid: qid_of(attr),
+            mode: file_type | (attr.mode & 0o7777),
+            uid: attr.uid,
+            gid: attr.gid,
+            nlink: attr.nlink as u64,
+            rdev: 0,
+            size: attr.size,
+            blksize: block_size,
+            blocks: attr.blocks * block_size / 512,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            btime: Timespec::default(),
+            gen: 0,
+            data_version: 0,
+        })
+    }
+
+    /// The changes a Tsetattr asks for that the export keeps. There are no
+    /// access or change times, so those are let go.
+    fn changes(&self, attr: &SetattrRequest) -> SetAttr {
+        let wants = |bit| attr.valid & bit != 0;
+        let mtime = match (wants(proto::SETATTR_MTIME), wants(proto::SETATTR_MTIME_SET)) {
+            (true, true) => Some(attr.mtime.sec),
+            (true, false) => self.now(),
+            _ => None,
+        };
+        SetAttr {
+            mode: wants(proto::SETATTR_MODE).then_some(attr.mode & 0o7777),
+            uid: wants(proto::SETATTR_UID).then_some(attr.uid),
+            gid: wants(proto::SETATTR_GID).then_some(attr.gid),
+            size: wants(proto::SETATTR_SIZE).then_some(attr.size),
+            mtime,
+        }
+    }
+
+    fn walk(&mut self, fid: u32, newfid: u32, names: &[String]) -> Result<Rmessage, Errno> {
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(Errno::EBADF);
+        }
+        let (mut path, uid) = {
+            let fid = self.fid(fid)?;
+            (fid.path.clone(), fid.uid)
+        };
+        let mut qids = Vec::new();
+        for name in names {
+            let step = if name == ".." {
+                if path.len() > 1 {
+                    path.pop();
+                }
+                Ok(path.last().unwrap().0)
+            } else {
+                check_name(name).and_then(|_| self.export.lookup(path.last().unwrap().0, name))
+            };
+            let ino = match step.and_then(|ino| self.qid(ino).map(|qid| (ino, qid))) {
+                Ok((ino, qid)) => {
+                    qids.push(qid);
+                    ino
+                }
+                // Only a walk that fails at its first name is an error.
+                Err(err) if qids.is_empty() => return Err(err),
+                Err(_) => break,
+            };
+            if name != ".." {
+                path.push((ino, name.clone()));
+            }
+        }
+        if qids.len() == names.len() {
+            self.fids.insert(newfid, Fid { path, uid, open: None, listing: Vec::new() });
+        }
+        Ok(Rmessage::Walk(qids))
+    }
+
+    fn readdir(&mut self, fid: u32, offset: u64, count: u32) -> Result<Rmessage, Errno> {
+        let ino = self.open_fid(fid, proto::O_WRONLY)?;
+        let fid = self.fids.get_mut(&fid).unwrap();
+        if offset == 0 || fid.listing.is_empty() {
+            let parent = fid.path.len().checked_sub(2).map_or(ino, |index| fid.path[index].0);
+            let mut entries = vec![(String::from("."), ino, NodeKind::Directory)];
+            entries.push((String::from(".."), parent, NodeKind::Directory));
+            entries.extend(self.export.readdir(ino)?);
+            fid.listing = entries
+                .into_iter()
+                .enumerate()
+                .map(|(index, (name, ino, kind))| {
+                    let (qid_kind, kind) = match kind {
+                        NodeKind::File => (proto::QID_FILE, DT_REG),
+                        NodeKind::Directory => (proto::QID_DIR, DT_DIR),
+                    };
+                    DirEntry {
+                        qid: Qid { kind: qid_kind, version: 0, path: ino },
+                        offset: index as u64 + 1,
+                        kind,
+                        name,
+                    }
+                })
+                .collect();
+        }
+        let room = core::cmp::min(count, self.msize - READ_HEADER_LEN) as usize;
+        let mut used = 0;
+        let entries = fid.listing.iter().skip(offset as usize).take_while(|entry| {
+            used += entry.encoded_len();
+            used <= room
+        });
+        Ok(Rmessage::Readdir(entries.cloned().collect()))
+    }
+
+    /// Rename `name` in `dir` into the directory of `new_dfid`, then move
+    /// every fid at or below the file along with it.
+    fn rename(&mut self, dir: Ino, name: &str, new_dfid: u32, new_name: &str) -> Result<(), Errno> {
+        check_name(new_name)?;
+        let mut new_path = self.fid(new_dfid)?.path.clone();
+        let ino = self.export.lookup(dir, name)?;
+        let replaced = self.export.lookup(new_path.last().unwrap().0, new_name).ok();
+        self.export.rename(dir, name, new_path.last().unwrap().0, new_name)?;
+        if let Some(replaced) = replaced.filter(|&replaced| replaced != ino) {
+            self.forget(replaced);
+        }
+        new_path.push((ino, String::from(new_name)));
+        for fid in self.fids.values_mut() {
+            if let Some(index) = fid.path.iter().position(|&(step, _)| step == ino) {
+                let below = fid.path.split_off(index + 1);
+                fid.path = new_path.clone();
+                fid.path.extend(below);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the locks on a file that is gone.
+    fn forget(&mut self, ino: Ino) {
+        self.locks.retain(|lock| lock.ino != ino);
+    }
+
+    fn lock(&mut self, ino: Ino, lock: &Flock) -> u8 {
+        let wanted = RangeLock::new(ino, lock);
+        match lock.kind {
+            proto::LOCK_UNLCK => self.unlock(&wanted),
+            proto::LOCK_RDLCK | proto::LOCK_WRLCK => {
+                if self.locks.iter().any(|held| held.conflicts(&wanted)) {
+                    return proto::LOCK_BLOCKED;
+                }
+                // A new lock replaces the owner's own over the same range.
+                self.unlock(&wanted);
+                self.locks.push(wanted);
+            }
+            _ => return proto::LOCK_ERROR,
+        }
+        proto::LOCK_SUCCESS
+    }
+
+    /// Release the owner's locks over the range of `range`, splitting any
+    /// that reach outside it.
+    fn unlock(&mut self, range: &RangeLock) {
+        let mut kept = Vec::new();
+        for held in self.locks.drain(..) {
+            if !held.same_owner(range) || !held.overlaps(range) {
+                kept.push(held);
+                continue;
+            }
+            if held.start < range.start {
+                kept.push(RangeLock { end: range.start, ..held.clone() });
+            }
+            if held.end > range.end {
+                kept.push(RangeLock { start: range.end, ..held });
+            }
+        }
+        self.locks = kept;
+    }
+}
+
+fn qid_of(attr: &Attr) -> Qid {
+    let kind = match attr.kind {
+        NodeKind::File => proto::QID_FILE,
+        NodeKind::Directory => proto::QID_DIR,
+    };
+    Qid { kind, version: 0, path: attr.ino }
+}
+
+/// A single path component other than `.` and `..`.
+fn check_name(name: &str) -> Result<(), Errno> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::RamStorage;
+    use crate::storage::volume::{Access, VolumeHandle, VolumeStore};
+
+    type Server = P9Server<VolumeHandle<RamStorage>>;
+
+    /// A server for VM "a" on volume "shared", with fid 0 attached as uid
+    /// 1000, and one for VM "b", which may only read.
+    fn servers() -> (Server, Server) {
+        let store = VolumeStore::shared(RamStorage::new(64, 256), 64, 256);
+        store.lock().create_volume("shared", 64 * 128).unwrap();
+        store.lock().set_access("shared", "a", Access::Write).unwrap();
+        store.lock().set_access("shared", "b", Access::Read).unwrap();
+        let attach = |vm| {
+            let mut server =
+                P9Server::new(VolumeHandle::attach(&store, "shared", vm).unwrap()).with_clock(Box::new(|| 42));
+            let attach = Tmessage::Attach {
+                fid: 0,
+                afid: proto::NOFID,
+                uname: String::new(),
+                aname: String::new(),
+                n_uname: 1000,
+            };
+            assert!(matches!(server.serve(attach), Ok(Rmessage::Attach(Qid { kind: proto::QID_DIR, .. }))));
+            server
+        };
+        (attach("a"), attach("b"))
+    }
+
+    fn walk(server: &mut Server, fid: u32, newfid: u32, names: &[&str]) -> Result<Rmessage, Errno> {
+        server.serve(Tmessage::Walk { fid, newfid, names: names.iter().map(|&name| String::from(name)).collect() })
+    }
+
+    fn names(reply: Result<Rmessage, Errno>) -> Vec<String> {
+        match reply {
+            Ok(Rmessage::Readdir(entries)) => entries.into_iter().map(|entry| entry.name).collect(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_files_and_directories() {
+        let (mut server, mut reader) = servers();
+        let version = Tmessage::Version { msize: 1 << 20, version: String::from(proto::VERSION) };
+        assert!(matches!(server.serve(version), Ok(Rmessage::Version { msize: MAX_MSIZE, .. })));
+        assert_eq!(server.fid_count(), 0);
+        let attach =
+            Tmessage::Attach { fid: 0, afid: proto::NOFID, uname: String::new(), aname: String::new(), n_uname: 1000 };
+        server.serve(attach).unwrap();
+
+        let mkdir = Tmessage::Mkdir { dfid: 0, name: String::from("docs"), mode: 0o40755, gid: 100 };
+        assert!(matches!(server.serve(mkdir), Ok(Rmessage::Mkdir(Qid { kind: proto::QID_DIR, path: 2, .. }))));
+        walk(&mut server, 0, 1, &["docs"]).unwrap();
+        let create =
+            Tmessage::Lcreate { fid: 1, name: String::from("a.txt"), flags: proto::O_RDWR, mode: 0o644, gid: 100 };
+        assert!(matches!(server.serve(create), Ok(Rmessage::Lcreate { qid: Qid { path: 3, .. }, .. })));
+        let write = Tmessage::Write { fid: 1, offset: 0, data: b"hello".to_vec() };
+        assert_eq!(server.serve(write), Ok(Rmessage::Write(5)));
+        assert_eq!(server.serve(Tmessage::Read { fid: 1, offset: 1, count: 3 }), Ok(Rmessage::Read(b"ell".to_vec())));
+        assert_eq!(server.serve(Tmessage::Clunk { fid: 1 }), Ok(Rmessage::Clunk));
+        assert_eq!(server.serve(Tmessage::Clunk { fid: 1 }), Err(Errno::EBADF));
+
+        // Walks may go up, but not above the root, and fail only at the start.
+        let Ok(Rmessage::Walk(qids)) = walk(&mut server, 0, 2, &["..", "docs", "..", "docs", "a.txt"]) else {
+            panic!()
+        };
+        assert_eq!(qids.iter().map(|qid| qid.path).collect::<Vec<_>>(), [1, 2, 1, 2, 3]);
+        let Ok(Rmessage::Getattr { stat, .. }) = server.serve(Tmessage::Getattr { fid: 2, mask: 0x3fff }) else {
+            panic!()
+        };
+        assert_eq!((stat.mode, stat.uid, stat.gid, stat.size, stat.mtime.sec), (S_IFREG | 0o644, 1000, 100, 5, 42));
+        assert!(matches!(walk(&mut server, 0, 3, &["docs", "missing"]), Ok(Rmessage::Walk(qids)) if qids.len() == 1));
+        assert_eq!(walk(&mut server, 0, 3, &["missing"]), Err(Errno::ENOENT));
+        assert_eq!(walk(&mut server, 0, 3, &["a/b"]), Err(Errno::EINVAL));
+        assert_eq!(server.fid_count(), 2);
+
+        // Listings continue from the cookie of the last entry read.
+        walk(&mut server, 0, 4, &["docs"]).unwrap();
+        server.serve(Tmessage::Lopen { fid: 4, flags: proto::O_RDONLY }).unwrap();
+        assert_eq!(names(server.serve(Tmessage::Readdir { fid: 4, offset: 0, count: 51 })), [".", ".."]);
+        assert_eq!(names(server.serve(Tmessage::Readdir { fid: 4, offset: 2, count: 51 })), ["a.txt"]);
+        assert_eq!(names(server.serve(Tmessage::Readdir { fid: 4, offset: 3, count: 51 })), Vec::<String>::new());
+        assert_eq!(server.serve(Tmessage::Lopen { fid: 4, flags: proto::O_RDONLY }), Err(Errno::EBADF));
+
+        // Renaming moves the fids along, so fid 2 still names the file.
+        let renameat = Tmessage::Renameat {
+            olddfid: 4,
+            oldname: String::from("a.txt"),
+            newdfid: 0,
+            newname: String::from("b.txt"),
+        };
+        assert_eq!(server.serve(renameat), Ok(Rmessage::Renameat));
+        assert_eq!(server.fids[&2].path.iter().map(|(ino, _)| *ino).collect::<Vec<_>>(), [1, 3]);
+        let unlink = |flags| Tmessage::Unlinkat { dfid: 0, name: String::from("docs"), flags };
+        assert_eq!(server.serve(unlink(0)), Err(Errno::EISDIR));
+        assert_eq!(server.serve(unlink(proto::AT_REMOVEDIR)), Ok(Rmessage::Unlinkat));
+        let lopen = Tmessage::Lopen { fid: 2, flags: proto::O_WRONLY | proto::O_TRUNC };
+        assert!(matches!(server.serve(lopen), Ok(Rmessage::Lopen { .. })));
+        assert_eq!(server.serve(Tmessage::Read { fid: 2, offset: 0, count: 8 }), Err(Errno::EBADF));
+        assert_eq!(server.serve(Tmessage::Remove { fid: 2 }), Ok(Rmessage::Remove));
+        assert_eq!(walk(&mut server, 0, 5, &["b.txt"]), Err(Errno::ENOENT));
+
+        // The other VM may look but not change anything.
+        walk(&mut server, 0, 6, &[]).unwrap();
+        let create = Tmessage::Lcreate { fid: 6, name: String::from("c"), flags: proto::O_RDWR, mode: 0o644, gid: 0 };
+        server.serve(create).unwrap();
+        walk(&mut reader, 0, 1, &["c"]).unwrap();
+        assert_eq!(reader.serve(Tmessage::Lopen { fid: 1, flags: proto::O_RDWR }), Err(Errno::EROFS));
+        let mkdir = Tmessage::Mkdir { dfid: 0, name: String::from("d"), mode: 0o755, gid: 0 };
+        assert_eq!(reader.serve(mkdir), Err(Errno::EACCES));
+        let Ok(Rmessage::Statfs(fs)) = reader.serve(Tmessage::Statfs { fid: 0 }) else { panic!() };
+        assert_eq!((fs.bsize, fs.blocks, fs.bfree), (64, 128, 128));
+    }
+
+    #[test]
+    fn test_locks() {
+        let (mut server, _) = servers();
+        walk(&mut server, 0, 1, &[]).unwrap();
+        let create = Tmessage::Lcreate { fid: 1, name: String::from("f"), flags: proto::O_RDWR, mode: 0o644, gid: 0 };
+        server.serve(create).unwrap();
+        let flock = |kind, start, length, owner: &str| Flock {
+            kind,
+            flags: proto::LOCK_FLAGS_BLOCK,
+            start,
+            length,
+            proc_id: 1,
+            client_id: String::from(owner),
+        };
+        let mut lock = |lock| server.serve(Tmessage::Lock { fid: 1, lock });
+        assert_eq!(lock(flock(proto::LOCK_WRLCK, 0, 10, "a")), Ok(Rmessage::Lock(proto::LOCK_SUCCESS)));
+        assert_eq!(lock(flock(proto::LOCK_RDLCK, 5, 0, "b")), Ok(Rmessage::Lock(proto::LOCK_BLOCKED)));
+        assert_eq!(lock(flock(proto::LOCK_RDLCK, 10, 0, "b")), Ok(Rmessage::Lock(proto::LOCK_SUCCESS)));
+        // Unlocking the middle of a lock leaves both ends held.
+        assert_eq!(lock(flock(proto::LOCK_UNLCK, 2, 3, "a")), Ok(Rmessage::Lock(proto::LOCK_SUCCESS)));
+        assert_eq!(lock(flock(proto::LOCK_WRLCK, 2, 3, "b")), Ok(Rmessage::Lock(proto::LOCK_SUCCESS)));
+        assert_eq!(lock(flock(7, 0, 0, "b")), Ok(Rmessage::Lock(proto::LOCK_ERROR)));
+
+        let Ok(Rmessage::Getlock(held)) =
+            server.serve(Tmessage::Getlock { fid: 1, lock: flock(proto::LOCK_RDLCK, 0, 0, "b") })
+        else {
+            panic!()
+        };
+        assert_eq!((held.kind, held.start, held.length, held.client_id.as_str()), (proto::LOCK_WRLCK, 0, 2, "a"));
+        let Ok(Rmessage::Getlock(free)) =
+            server.serve(Tmessage::Getlock { fid: 1, lock: flock(proto::LOCK_RDLCK, 20, 5, "a") })
+        else {
+            panic!()
+        };
+        assert_eq!((free.kind, free.start, free.length), (proto::LOCK_UNLCK, 20, 5));
+
+        // Locks go with the file.
+        server.serve(Tmessage::Unlinkat { dfid: 0, name: String::from("f"), flags: 0 }).unwrap();
+        assert!(server.locks.is_empty());
+    }
+
+    #[test]
+    fn test_raw_messages() {
+        let (mut server, _) = servers();
+        let mut tversion = vec![21, 0, 0, 0, proto::TVERSION, 0xff, 0xff, 0, 0x20, 0, 0, 8, 0];
+        tversion.extend_from_slice(proto::VERSION.as_bytes());
+        let reply = Rmessage::Version { msize: 0x2000, version: String::from(proto::VERSION) };
+        assert_eq!(server.handle(&tversion), Some(reply.encode(proto::NOTAG)));
+        assert_eq!(server.msize(), 0x2000);
+
+        // Malformed requests are refused at their tag; unreadable ones are not answered.
+        assert_eq!(
+            server.handle(&[9, 0, 0, 0, proto::TCLUNK, 3, 0, 1, 0]),
+            Some(Rmessage::Lerror(Errno::EPROTO).encode(3))
+        );
+        assert_eq!(server.handle(&[11, 0, 0, 0, proto::TCLUNK]), None);
+        assert_eq!(server.handle(&[7, 0, 0, 0, 30, 4, 0]), Some(Rmessage::Lerror(Errno::EOPNOTSUPP).encode(4)));
+
+        // Reads are cut to what fits in a message.
+        let create = Tmessage::Lcreate { fid: 0, name: String::from("f"), flags: proto::O_RDWR, mode: 0o644, gid: 0 };
+        server
+            .serve(Tmessage::Attach {
+                fid: 0,
+                afid: proto::NOFID,
+                uname: String::new(),
+                aname: String::new(),
+                n_uname: 0,
+            })
+            .unwrap();
+        server.serve(create).unwrap();
+        server.serve(Tmessage::Write { fid: 0, offset: 0, data: vec![1; 0x2000] }).unwrap();
+        let Ok(Rmessage::Read(data)) = server.serve(Tmessage::Read { fid: 0, offset: 0, count: 0x4000 }) else {
+            panic!()
+        };
+        assert_eq!(data.len(), 0x2000 - 11);
+    }
+}
@@ -0,0 +1,817 @@
+// Virtio over PCI (virtio 1.x "modern" interface): a PCI function whose
+// vendor capabilities point the driver at the common configuration, ISR,
+// notification and device configuration structures, all packed into BAR 0.
+//
+// Interrupts are INTx only. Without an MSI-X capability the driver reads the
+// ISR to find out why it was interrupted, which also lowers the line.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::queue::Virtqueue;
+use super::{
+    SharedMemory, VirtioDevice, STATUS_DRIVER_OK, STATUS_FEATURES_OK, STATUS_NEEDS_RESET, VIRTIO_F_EVENT_IDX,
+    VIRTIO_F_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_ID_9P, VIRTIO_ID_BLOCK, VIRTIO_ID_CONSOLE, VIRTIO_ID_NET,
+};
+use crate::hypervisor::operand_mask;
+use crate::vdev::irq::IrqLine;
+use crate::vdev::pci::{BarKind, PciConfig, PciFunction, CAP_ID_VENDOR};
+
+pub const VIRTIO_PCI_VENDOR: u16 = 0x1AF4;
+/// Modern device IDs are this plus the virtio device ID.
+pub const VIRTIO_PCI_DEVICE_BASE: u16 = 0x1040;
+/// Subsystem ID for non-transitional devices.
+const SUBSYSTEM_ID: u16 = 0x40;
+
+// Capability types.
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+const CAP_PCI_CFG: u8 = 5;
+
+// Layout of BAR 0.
+pub const BAR_SIZE: u64 = 0x4000;
+pub const COMMON_CFG: u64 = 0x0000;
+pub const COMMON_CFG_LEN: u64 = 0x38;
+pub const ISR_CFG: u64 = 0x1000;
+pub const DEVICE_CFG: u64 = 0x2000;
+pub const DEVICE_CFG_LEN: u64 = 0x1000;
+pub const NOTIFY_CFG: u64 = 0x3000;
+/// Queue N is notified by writing at `NOTIFY_CFG + N * NOTIFY_MULTIPLIER`.
+pub const NOTIFY_MULTIPLIER: u32 = 4;
+
+// Common configuration registers.
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0C;
+const CONFIG_MSIX_VECTOR: u64 = 0x10;
+const NUM_QUEUES: u64 = 0x12;
+const DEVICE_STATUS: u64 = 0x14;
+const CONFIG_GENERATION: u64 = 0x15;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const QUEUE_ENABLE: u64 = 0x1C;
+const QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const QUEUE_DESC: u64 = 0x20;
+const QUEUE_DRIVER: u64 = 0x28;
+const QUEUE_DEVICE: u64 = 0x30;
+
+const NO_VECTOR: u16 = 0xFFFF;
+
+pub const ISR_QUEUE: u8 = 1;
+pub const ISR_CONFIG: u8 = 2;
+
+/// Feature bits the transport and queues implement for every device.
+const TRANSPORT_FEATURES: u64 = VIRTIO_F_VERSION_1 | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX;
+
+/// PCI class code to present for a virtio device type.
+fn class_code(device_type: u16) -> u32 {
+    match device_type {
+        VIRTIO_ID_NET => 0x02_00_00,
+        VIRTIO_ID_BLOCK => 0x01_00_00,
+        VIRTIO_ID_CONSOLE => 0x07_80_00,
+        VIRTIO_ID_9P => 0x02_80_00,
+        _ => 0xFF_00_00,
+    }
+}
+
+/// A virtio device exposed as a PCI function.
+pub struct VirtioPci<D: VirtioDevice> {
+    config: PciConfig,
+    device: D,
+    queues: Vec<Virtqueue>,
+    mem: SharedMemory,
+    irq: Box<dyn IrqLine>,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: u64,
+    status: u8,
+    config_generation: u8,
+    queue_select: u16,
+    isr: u8,
+    /// Offset of the VIRTIO_PCI_CAP_PCI_CFG capability in config space.
+    pci_cfg_cap: u16,
+}
+
+impl<D: VirtioDevice> VirtioPci<D> {
+    /// Wrap `device`, giving it `mem` for DMA and `irq` as its INTA# line.
+    pub fn new(device: D, mem: SharedMemory, irq: Box<dyn IrqLine>) -> Self {
+        let device_type = device.device_type();
+        let mut config =
+            PciConfig::new(VIRTIO_PCI_VENDOR, VIRTIO_PCI_DEVICE_BASE + device_type, class_code(device_type), 1);
+        config.set_subsystem(VIRTIO_PCI_VENDOR, SUBSYSTEM_ID);
+        config.set_interrupt_pin(1);
+        config.add_bar(0, BAR_SIZE, BarKind::Memory64);
+        config.add_capability(CAP_ID_VENDOR, &virtio_cap(CAP_COMMON_CFG, COMMON_CFG, COMMON_CFG_LEN, &[]));
+        let queues = device.queue_sizes().len() as u64;
+        let notify_len = core::cmp::max(queues, 1) * NOTIFY_MULTIPLIER as u64;
+        config.add_capability(
+            CAP_ID_VENDOR,
+            &virtio_cap(CAP_NOTIFY_CFG, NOTIFY_CFG, notify_len, &NOTIFY_MULTIPLIER.to_le_bytes()),
+        );
+        config.add_capability(CAP_ID_VENDOR, &virtio_cap(CAP_ISR_CFG, ISR_CFG, 1, &[]));
+        config.add_capability(CAP_ID_VENDOR, &virtio_cap(CAP_DEVICE_CFG, DEVICE_CFG, DEVICE_CFG_LEN, &[]));
+        let pci_cfg_cap = config.add_capability(CAP_ID_VENDOR, &virtio_cap(CAP_PCI_CFG, 0, 0, &[0; 4]));
+        // The window's BAR, offset, length and data fields are the driver's.
+        config.set_writable(pci_cfg_cap + 4, &[0xFF]);
+        config.set_writable(pci_cfg_cap + 8, &[0xFF; 8]);
+
+        let queues = device.queue_sizes().iter().map(|&size| Virtqueue::new(size)).collect();
+        VirtioPci {
+            config,
+            device,
+            queues,
+            mem,
+            irq,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            status: 0,
+            config_generation: 0,
+            queue_select: 0,
+            isr: 0,
+            pci_cfg_cap,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    /// Features the driver accepted; meaningful once FEATURES_OK is set.
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.device.features() | TRANSPORT_FEATURES
+    }
+
+    /// Let the driver know the device configuration changed.
+    pub fn config_changed(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.isr |= ISR_CONFIG;
+        self.update_irq();
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+        for queue in &mut self.queues {
+            queue.reset();
+        }
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.queue_select = 0;
+        self.isr = 0;
+        self.update_irq();
+    }
+
+    fn set_status(&mut self, value: u8) {
+        if value == 0 {
+            self.reset();
+            return;
+        }
+        let mut status = value;
+        let newly = value & !self.status;
+        if newly & STATUS_FEATURES_OK != 0 {
+            let acceptable = self.driver_features & !self.offered_features() == 0
+                && self.driver_features & VIRTIO_F_VERSION_1 != 0;
+            if !acceptable {
+                status &= !STATUS_FEATURES_OK;
+            }
+        }
+        if newly & STATUS_DRIVER_OK != 0 && status & STATUS_FEATURES_OK != 0 {
+            self.device.activate(self.driver_features);
+        }
+        self.status = status | (self.status & STATUS_NEEDS_RESET);
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    fn read_common(&mut self, offset: u64, size: u8) -> u64 {
+        let mut image = [0u8; COMMON_CFG_LEN as usize];
+        let features = self.offered_features();
+        let half = |value: u64, select: u32| match select {
+            0 => value as u32,
+            1 => (value >> 32) as u32,
+            _ => 0,
+        };
+        let mut put = |at: u64, bytes: &[u8]| image[at as usize..at as usize + bytes.len()].copy_from_slice(bytes);
+        put(DEVICE_FEATURE_SELECT, &self.device_feature_select.to_le_bytes());
+        put(DEVICE_FEATURE, &half(features, self.device_feature_select).to_le_bytes());
+        put(DRIVER_FEATURE_SELECT, &self.driver_feature_select.to_le_bytes());
+        put(DRIVER_FEATURE, &half(self.driver_features, self.driver_feature_select).to_le_bytes());
+        put(CONFIG_MSIX_VECTOR, &NO_VECTOR.to_le_bytes());
+        put(NUM_QUEUES, &(self.queues.len() as u16).to_le_bytes());
+        put(DEVICE_STATUS, &[self.status]);
+        put(CONFIG_GENERATION, &[self.config_generation]);
+        put(QUEUE_SELECT, &self.queue_select.to_le_bytes());
+        put(QUEUE_MSIX_VECTOR, &NO_VECTOR.to_le_bytes());
+        if let Some(queue) = self.queues.get(self.queue_select as usize) {
+            put(QUEUE_SIZE, &queue.size.to_le_bytes());
+            put(QUEUE_ENABLE, &(queue.is_ready() as u16).to_le_bytes());
+            put(QUEUE_NOTIFY_OFF, &self.queue_select.to_le_bytes());
+            put(QUEUE_DESC, &queue.desc_table.to_le_bytes());
+            put(QUEUE_DRIVER, &queue.avail_ring.to_le_bytes());
+            put(QUEUE_DEVICE, &queue.used_ring.to_le_bytes());
+        }
+        let mut bytes = [0u8; 8];
+        let end = core::cmp::min(offset as usize + size as usize, image.len());
+        if let Some(src) = image.get(offset as usize..end) {
+            bytes[..src.len()].copy_from_slice(src);
+        }
+        u64::from_le_bytes(bytes)
+    }
+
+    fn write_common(&mut self, offset: u64, size: u8, value: u64) {
+        match (offset, size) {
+            (DEVICE_FEATURE_SELECT, 4) => self.device_feature_select = value as u32,
+            (DRIVER_FEATURE_SELECT, 4) => self.driver_feature_select = value as u32,
+            (DRIVER_FEATURE, 4) if self.status & STATUS_FEATURES_OK == 0 => {
+                let shift = match self.driver_feature_select {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                self.driver_features = (self.driver_features & !(0xFFFF_FFFF << shift)) | (value & 0xFFFF_FFFF) << shift;
+            }
+            (DEVICE_STATUS, 1) => self.set_status(value as u8),
+            (QUEUE_SELECT, 2) => self.queue_select = value as u16,
+            (QUEUE_SIZE, 2) => {
+                if let Some(queue) = self.selected_queue().filter(|queue| !queue.is_ready()) {
+                    queue.size = value as u16;
+                }
+            }
+            (QUEUE_ENABLE, 2) if value == 1 => {
+                let event_idx = self.driver_features & VIRTIO_F_EVENT_IDX != 0;
+                let enabled = self.selected_queue().map(|queue| queue.is_ready() || queue.enable(event_idx));
+                if enabled == Some(false) {
+                    self.needs_reset();
+                }
+            }
+            (QUEUE_DESC..=0x37, 4 | 8) => {
+                let Some(queue) = self.selected_queue().filter(|queue| !queue.is_ready()) else { return };
+                let field = match offset & !7 {
+                    QUEUE_DESC => &mut queue.desc_table,
+                    QUEUE_DRIVER => &mut queue.avail_ring,
+                    _ => &mut queue.used_ring,
+                };
+                let shift = (offset & 7) * 8;
+                let mask = operand_mask(size) << shift;
+                *field = (*field & !mask) | (value << shift & mask);
+            }
+            _ => {}
+        }
+    }
+
+    /// The driver broke the protocol: stop and tell it to reset the device.
+    fn needs_reset(&mut self) {
+        if self.status & STATUS_DRIVER_OK != 0 {
+            self.status |= STATUS_NEEDS_RESET;
+            self.config_changed();
+        }
+    }
+
+    /// Let the device process queue `index` and raise an interrupt for
+    /// whatever the driver wants to hear about.
+    fn notify(&mut self, index: usize) {
+        if !self.running() {
+            return;
+        }
+        if !self.queues.get(index).is_some_and(|queue| queue.is_ready()) {
+            return;
+        }
+        if self.device.notify(index, &mut self.queues, &*self.mem).is_err() {
+            self.needs_reset();
+        }
+        self.signal_used();
+    }
+
+    /// Let the device do work of its own, like delivering what arrived
+    /// from outside, and interrupt the driver as for a notification. The
+    /// owner calls this whenever the device may have something to do.
+    pub fn poll(&mut self) {
+        if !self.running() {
+            return;
+        }
+        if self.device.poll(&mut self.queues, &*self.mem).is_err() {
+            self.needs_reset();
+        }
+        self.signal_used();
+    }
+
+    /// Whether the driver has the device running and has not broken it.
+    fn running(&self) -> bool {
+        self.status & STATUS_DRIVER_OK != 0 && self.status & STATUS_NEEDS_RESET == 0
+    }
+
+    /// Raise the queue interrupt if any queue has used buffers the driver
+    /// wants to be told about. Devices completing requests on their own
+    /// time call this after adding to a used ring.
+    pub fn signal_used(&mut self) {
+        let mut wanted = false;
+        for queue in &mut self.queues {
+            wanted |= queue.needs_interrupt(&*self.mem).unwrap_or(false);
+        }
+        if wanted {
+            self.isr |= ISR_QUEUE;
+        }
+        self.update_irq();
+    }
+
+    fn update_irq(&mut self) {
+        let level = self.config.update_interrupt(self.isr != 0);
+        self.irq.set_level(level);
+    }
+
+    /// BAR, offset and length the VIRTIO_PCI_CAP_PCI_CFG window points at.
+    fn pci_cfg_window(&self) -> (usize, u64, u8) {
+        let cap = self.pci_cfg_cap;
+        let bar = self.config.read(cap + 4, 1) as usize;
+        let offset = self.config.read(cap + 8, 4) as u64;
+        let len = self.config.read(cap + 12, 4) as u8;
+        (bar, offset, len)
+    }
+}
+
+/// A `virtio_pci_cap` body, after the capability ID and next pointer.
+fn virtio_cap(cfg_type: u8, offset: u64, length: u64, extra: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.push(16 + extra.len() as u8);
+    body.push(cfg_type);
+    // BAR, id and padding.
+    body.extend_from_slice(&[0, 0, 0, 0]);
+    body.extend_from_slice(&(offset as u32).to_le_bytes());
+    body.extend_from_slice(&(length as u32).to_le_bytes());
+    body.extend_from_slice(extra);
+    body
+}
+
+impl<D: VirtioDevice> PciFunction for VirtioPci<D> {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn config_read(&mut self, offset: u16, size: u8) -> u32 {
+        let data = self.pci_cfg_cap + 16;
+        if offset == data {
+            let (bar, window, len) = self.pci_cfg_window();
+            if matches!(len, 1 | 2 | 4) && size >= len {
+                return self.bar_read(bar, window, len) as u32;
+            }
+        }
+        self.config.read(offset, size)
+    }
+
+    fn config_write(&mut self, offset: u16, size: u8, value: u32) {
+        let data = self.pci_cfg_cap + 16;
+        if offset == data {
+            let (bar, window, len) = self.pci_cfg_window();
+            if matches!(len, 1 | 2 | 4) && size >= len {
+                self.bar_write(bar, window, len, value as u64 & operand_mask(len));
+            }
+            return;
+        }
+        self.config.write(offset, size, value);
+        // The driver may have disabled or re-enabled INTx.
+        self.update_irq();
+    }
+
+    fn bar_read(&mut self, bar: usize, offset: u64, size: u8) -> u64 {
+        if bar != 0 {
+            return operand_mask(size);
+        }
+        match offset {
+            COMMON_CFG..ISR_CFG => self.read_common(offset - COMMON_CFG, size),
+            ISR_CFG => {
+                let isr = core::mem::take(&mut self.isr);
+                self.update_irq();
+                isr as u64
+            }
+            DEVICE_CFG..NOTIFY_CFG => {
+                let mut bytes = [0u8; 8];
+                self.device.read_config(offset - DEVICE_CFG, &mut bytes[..size as usize]);
+                u64::from_le_bytes(bytes)
+            }
+            _ => 0,
+        }
+    }
+
+    fn bar_write(&mut self, bar: usize, offset: u64, size: u8, value: u64) {
+        if bar != 0 {
+            return;
+        }
+        match offset {
+            COMMON_CFG..ISR_CFG => self.write_common(offset - COMMON_CFG, size, value),
+            DEVICE_CFG..NOTIFY_CFG => {
+                self.device.write_config(offset - DEVICE_CFG, &value.to_le_bytes()[..size as usize]);
+            }
+            NOTIFY_CFG..BAR_SIZE => self.notify(((offset - NOTIFY_CFG) / NOTIFY_MULTIPLIER as u64) as usize),
+            _ => {}
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.device.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::hypervisor::{HypervisorBackend, MockBackend};
+    use crate::vdev::irq::SharedIrq;
+    use crate::vdev::mmio::MmioBus;
+    use crate::vdev::pci::{register_pci, PciAddress, PciBus, SharedPciBus, CAPABILITIES_POINTER, COMMAND, COMMAND_MEMORY};
+    use crate::vdev::pio::PortIoBus;
+    use crate::vdev::virtio::queue::{QueueError, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use crate::vdev::virtio::{
+        GuestMemory, LayoutMemory, STATUS_ACKNOWLEDGE, STATUS_DRIVER, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC,
+    };
+
+    const BAR: u64 = 0xE000_0000;
+    const DEVICE_FEATURE_BIT: u64 = 1 << 5;
+
+    /// Reverses whatever it reads into the writable buffers: one queue of
+    /// eight, and four bytes of configuration the driver may write.
+    struct Reverser {
+        config: [u8; 4],
+        activated: Arc<AtomicU64>,
+    }
+
+    impl VirtioDevice for Reverser {
+        fn device_type(&self) -> u16 {
+            0x2A
+        }
+
+        fn features(&self) -> u64 {
+            DEVICE_FEATURE_BIT
+        }
+
+        fn queue_sizes(&self) -> &[u16] {
+            &[8]
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = self.config.get(offset as usize + i).copied().unwrap_or(0);
+            }
+        }
+
+        fn write_config(&mut self, offset: u64, data: &[u8]) {
+            for (i, &byte) in data.iter().enumerate() {
+                if let Some(slot) = self.config.get_mut(offset as usize + i) {
+                    *slot = byte;
+                }
+            }
+        }
+
+        fn activate(&mut self, features: u64) {
+            self.activated.store(features, Ordering::Relaxed);
+        }
+
+        fn notify(&mut self, index: usize, queues: &mut [Virtqueue], mem: &dyn GuestMemory) -> Result<(), QueueError> {
+            let queue = &mut queues[index];
+            while let Some(chain) = queue.pop(mem)? {
+                let mut data = chain.read_all(mem)?;
+                data.reverse();
+                let written = chain.write_at(mem, 0, &data)?;
+                queue.add_used(mem, chain.head, written as u32)?;
+            }
+            Ok(())
+        }
+    }
+
+    struct Guest {
+        pio: PortIoBus,
+        mmio: MmioBus,
+        bus: SharedPciBus,
+        mem: Arc<LayoutMemory>,
+        irq: SharedIrq,
+        activated: Arc<AtomicU64>,
+        _backend: MockBackend,
+    }
+
+    impl Guest {
+        fn cfg_read(&mut self, register: u16, size: u8) -> u32 {
+            self.pio.write(0xCF8, 4, 0x8000_0000 | 1 << 11 | (register & !3) as u32);
+            self.pio.read(0xCFC + (register & 3), size)
+        }
+
+        fn cfg_write(&mut self, register: u16, size: u8, value: u32) {
+            self.pio.write(0xCF8, 4, 0x8000_0000 | 1 << 11 | (register & !3) as u32);
+            self.pio.write(0xCFC + (register & 3), size, value);
+        }
+
+        /// Walk the capability list for the virtio capability of `cfg_type`,
+        /// returning its config space offset and the BAR offset it names.
+        fn find_cap(&mut self, cfg_type: u8) -> Option<(u16, u64)> {
+            let mut cap = self.cfg_read(CAPABILITIES_POINTER, 1) as u16;
+            while cap != 0 {
+                if self.cfg_read(cap, 1) as u8 == CAP_ID_VENDOR && self.cfg_read(cap + 3, 1) as u8 == cfg_type {
+                    return Some((cap, self.cfg_read(cap + 8, 4) as u64));
+                }
+                cap = self.cfg_read(cap + 1, 1) as u16;
+            }
+            None
+        }
+
+        fn common_read(&mut self, register: u64, size: u8) -> u64 {
+            self.mmio.read(BAR + COMMON_CFG + register, size)
+        }
+
+        fn common_write(&mut self, register: u64, size: u8, value: u64) {
+            self.mmio.write(BAR + COMMON_CFG + register, size, value);
+        }
+
+        fn desc(&self, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+            let mut raw = [0u8; 16];
+            raw[0..8].copy_from_slice(&addr.to_le_bytes());
+            raw[8..12].copy_from_slice(&len.to_le_bytes());
+            raw[12..14].copy_from_slice(&flags.to_le_bytes());
+            raw[14..16].copy_from_slice(&next.to_le_bytes());
+            self.mem.write(0x1000 + index as u64 * 16, &raw).unwrap();
+        }
+
+        /// Make the chain at `head` available on the queue set up by `setup_queue`.
+        fn publish(&self, head: u16) {
+            let mut idx = [0u8; 2];
+            self.mem.read(0x2002, &mut idx).unwrap();
+            let idx = u16::from_le_bytes(idx);
+            self.mem.write(0x2004 + 2 * (idx % 8) as u64, &head.to_le_bytes()).unwrap();
+            self.mem.write(0x2002, &idx.wrapping_add(1).to_le_bytes()).unwrap();
+        }
+
+        fn used_idx(&self) -> u16 {
+            let mut idx = [0u8; 2];
+            self.mem.read(0x3002, &mut idx).unwrap();
+            u16::from_le_bytes(idx)
+        }
+
+        /// Negotiate `features` the way a driver does, returning the status
+        /// read back after setting FEATURES_OK.
+        fn negotiate(&mut self, features: u64) -> u8 {
+            self.common_write(0x14, 1, 0);
+            self.common_write(0x14, 1, STATUS_ACKNOWLEDGE as u64);
+            self.common_write(0x14, 1, (STATUS_ACKNOWLEDGE | STATUS_DRIVER) as u64);
+            for select in 0..2 {
+                self.common_write(0x08, 4, select);
+                self.common_write(0x0C, 4, features >> (32 * select) & 0xFFFF_FFFF);
+            }
+            self.common_write(0x14, 1, (STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK) as u64);
+            self.common_read(0x14, 1) as u8
+        }
+
+        /// Queue 0 with its rings at 0x1000, 0x2000 and 0x3000.
+        fn setup_queue(&mut self) {
+            self.common_write(0x16, 2, 0);
+            self.common_write(0x18, 2, 8);
+            self.common_write(0x20, 4, 0x1000);
+            self.common_write(0x24, 4, 0);
+            self.common_write(0x28, 8, 0x2000);
+            self.common_write(0x30, 8, 0x3000);
+            self.common_write(0x1C, 2, 1);
+        }
+
+        fn driver_ok(&mut self) {
+            let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK;
+            self.common_write(0x14, 1, status as u64);
+        }
+    }
+
+    /// A reverser at 00:01.0 with BAR 0 programmed and memory decoding on.
+    fn guest() -> Guest {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        let mem = Arc::new(unsafe { LayoutMemory::new(backend.memory_layout().clone(), 0) });
+        let irq = SharedIrq::new();
+        let activated = Arc::new(AtomicU64::new(0));
+        let device = Reverser { config: [1, 2, 3, 4], activated: activated.clone() };
+        let function = VirtioPci::new(device, mem.clone(), Box::new(irq.clone()));
+        let bus = PciBus::shared();
+        bus.lock().add(PciAddress::new(0, 1, 0), Box::new(function)).unwrap();
+        let mut pio = PortIoBus::new();
+        let mut mmio = MmioBus::new();
+        register_pci(&mut pio, &mut mmio, &bus, BAR, 0x1000_0000).unwrap();
+        let mut guest = Guest { pio, mmio, bus, mem, irq, activated, _backend: backend };
+        guest.cfg_write(0x10, 4, BAR as u32);
+        guest.cfg_write(0x14, 4, 0);
+        guest.cfg_write(COMMAND, 2, COMMAND_MEMORY as u32);
+        guest
+    }
+
+    #[test]
+    fn test_pci_identity_and_capabilities() {
+        let mut guest = guest();
+        assert_eq!(guest.cfg_read(0, 4), 0x106A_1AF4);
+        assert_eq!(guest.cfg_read(0x2C, 4), 0x0040_1AF4);
+        assert_eq!(guest.cfg_read(0x08, 4) >> 8, 0xFF_00_00);
+        assert_eq!(guest.cfg_read(0x3D, 1), 1);
+        assert_eq!(guest.find_cap(CAP_COMMON_CFG).unwrap().1, COMMON_CFG);
+        assert_eq!(guest.find_cap(CAP_ISR_CFG).unwrap().1, ISR_CFG);
+        assert_eq!(guest.find_cap(CAP_DEVICE_CFG).unwrap().1, DEVICE_CFG);
+        let (notify, offset) = guest.find_cap(CAP_NOTIFY_CFG).unwrap();
+        assert_eq!(offset, NOTIFY_CFG);
+        assert_eq!(guest.cfg_read(notify + 2, 1), 20);
+        assert_eq!(guest.cfg_read(notify + 16, 4), NOTIFY_MULTIPLIER);
+        assert!(guest.find_cap(CAP_PCI_CFG).is_some());
+        // BAR 0 is a 64-bit BAR of BAR_SIZE.
+        guest.cfg_write(0x10, 4, 0xFFFF_FFFF);
+        assert_eq!(guest.cfg_read(0x10, 4), !(BAR_SIZE as u32 - 1) | 0xC);
+        guest.cfg_write(0x10, 4, BAR as u32);
+    }
+
+    #[test]
+    fn test_feature_negotiation() {
+        let mut guest = guest();
+        assert_eq!(guest.common_read(0x12, 2), 1);
+        guest.common_write(0x00, 4, 0);
+        assert_eq!(guest.common_read(0x04, 4), DEVICE_FEATURE_BIT | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX);
+        guest.common_write(0x00, 4, 1);
+        assert_eq!(guest.common_read(0x04, 4), 1);
+        guest.common_write(0x00, 4, 2);
+        assert_eq!(guest.common_read(0x04, 4), 0);
+
+        // Legacy drivers (no VERSION_1) and unknown features are refused.
+        assert_eq!(guest.negotiate(DEVICE_FEATURE_BIT), STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        assert_eq!(guest.negotiate(VIRTIO_F_VERSION_1 | 1 << 7), STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let accepted = VIRTIO_F_VERSION_1 | DEVICE_FEATURE_BIT;
+        assert_eq!(guest.negotiate(accepted), STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        // Features are fixed once accepted.
+        guest.common_write(0x08, 4, 0);
+        guest.common_write(0x0C, 4, 0);
+        assert_eq!(guest.common_read(0x0C, 4), DEVICE_FEATURE_BIT);
+        assert_eq!(guest.activated.load(Ordering::Relaxed), 0);
+        guest.driver_ok();
+        assert_eq!(guest.common_read(0x14, 1) as u8 & STATUS_DRIVER_OK, STATUS_DRIVER_OK);
+        assert_eq!(guest.activated.load(Ordering::Relaxed), accepted);
+        assert_eq!(guest.bus.lock().function_mut(PciAddress::new(0, 1, 0)).unwrap().name(), "virtio-device");
+    }
+
+    #[test]
+    fn test_queue_configuration() {
+        let mut guest = guest();
+        guest.negotiate(VIRTIO_F_VERSION_1);
+        guest.common_write(0x16, 2, 0);
+        assert_eq!(guest.common_read(0x18, 2), 8);
+        assert_eq!(guest.common_read(0x1A, 2), NO_VECTOR as u64);
+        assert_eq!(guest.common_read(0x1E, 2), 0);
+        guest.common_write(0x18, 2, 4);
+        guest.common_write(0x20, 8, 0x1_0000_1000);
+        guest.common_write(0x24, 4, 0);
+        assert_eq!(guest.common_read(0x20, 8), 0x1000);
+        guest.common_write(0x1C, 2, 1);
+        assert_eq!(guest.common_read(0x1C, 2), 1);
+        // An enabled queue can no longer be moved.
+        guest.common_write(0x18, 2, 8);
+        guest.common_write(0x20, 4, 0x5000);
+        assert_eq!((guest.common_read(0x18, 2), guest.common_read(0x20, 4)), (4, 0x1000));
+        // Queues past the last read as absent.
+        guest.common_write(0x16, 2, 1);
+        assert_eq!((guest.common_read(0x18, 2), guest.common_read(0x1C, 2)), (0, 0));
+        // Reset disables the queue again.
+        guest.common_write(0x14, 1, 0);
+        guest.common_write(0x16, 2, 0);
+        assert_eq!((guest.common_read(0x18, 2), guest.common_read(0x1C, 2)), (8, 0));
+    }
+
+    #[test]
+    fn test_request_and_interrupt() {
+        let mut guest = guest();
+        guest.negotiate(VIRTIO_F_VERSION_1);
+        guest.setup_queue();
+        guest.driver_ok();
+        assert_eq!(guest.cfg_read(0x06, 2) & 1 << 3, 0);
+
+        guest.mem.write(0x8000, b"virtio").unwrap();
+        guest.desc(0, 0x8000, 6, VIRTQ_DESC_F_NEXT, 1);
+        guest.desc(1, 0x9000, 16, VIRTQ_DESC_F_WRITE, 0);
+        guest.publish(0);
+        guest.mmio.write(BAR + NOTIFY_CFG, 2, 0);
+        assert_eq!(guest.used_idx(), 1);
+        let mut out = [0u8; 6];
+        guest.mem.read(0x9000, &mut out).unwrap();
+        assert_eq!(&out, b"oitriv");
+
+        // INTA# is raised until the driver reads the ISR.
+        assert!(guest.irq.level());
+        assert_eq!(guest.cfg_read(0x06, 2) & 1 << 3, 1 << 3);
+        assert_eq!(guest.mmio.read(BAR + ISR_CFG, 1), ISR_QUEUE as u64);
+        assert!(!guest.irq.level());
+        assert_eq!(guest.mmio.read(BAR + ISR_CFG, 1), 0);
+
+        // With INTx disabled the status bit still shows the interrupt.
+        guest.cfg_write(COMMAND, 2, (COMMAND_MEMORY | crate::vdev::pci::COMMAND_INTX_DISABLE) as u32);
+        guest.publish(0);
+        guest.mmio.write(BAR + NOTIFY_CFG, 2, 0);
+        assert!(!guest.irq.level());
+        assert_eq!(guest.cfg_read(0x06, 2) & 1 << 3, 1 << 3);
+        guest.cfg_write(COMMAND, 2, COMMAND_MEMORY as u32);
+        assert!(guest.irq.level());
+    }
+
+    #[test]
+    fn test_notify_before_driver_ok_is_ignored() {
+        let mut guest = guest();
+        guest.negotiate(VIRTIO_F_VERSION_1);
+        guest.setup_queue();
+        guest.desc(0, 0x9000, 16, VIRTQ_DESC_F_WRITE, 0);
+        guest.publish(0);
+        guest.mmio.write(BAR + NOTIFY_CFG, 2, 0);
+        assert_eq!(guest.used_idx(), 0);
+        guest.driver_ok();
+        guest.mmio.write(BAR + NOTIFY_CFG, 2, 0);
+        assert_eq!(guest.used_idx(), 1);
+    }
+
+    #[test]
+    fn test_broken_ring_needs_reset() {
+        let mut guest = guest();
+        guest.negotiate(VIRTIO_F_VERSION_1);
+        guest.setup_queue();
+        guest.driver_ok();
+        // A descriptor that links to itself.
+        guest.desc(0, 0x9000, 16, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 0);
+        guest.publish(0);
+        guest.mmio.write(BAR + NOTIFY_CFG, 2, 0);
+        assert_eq!(guest.common_read(0x14, 1) as u8 & STATUS_NEEDS_RESET, STATUS_NEEDS_RESET);
+        assert_eq!(guest.common_read(0x15, 1), 1);
+        assert_eq!(guest.mmio.read(BAR + ISR_CFG, 1), ISR_CONFIG as u64);
+        // Nothing more is processed until the driver resets the device.
+        guest.desc(0, 0x9000, 16, VIRTQ_DESC_F_WRITE, 0);
+        guest.mmio.write(BAR + NOTIFY_CFG, 2, 0);
+        assert_eq!(guest.used_idx(), 0);
+        guest.common_write(0x14, 1, 0);
+        assert_eq!(guest.common_read(0x14, 1), 0);
+    }
+
+    #[test]
+    fn test_device_config_and_pci_cfg_window() {
+        let mut guest = guest();
+        assert_eq!(guest.mmio.read(BAR + DEVICE_CFG, 4), 0x0403_0201);
+        assert_eq!(guest.mmio.read(BAR + DEVICE_CFG + 2, 2), 0x0403);
+        guest.mmio.write(BAR + DEVICE_CFG + 1, 1, 0xAA);
+        assert_eq!(guest.mmio.read(BAR + DEVICE_CFG, 4), 0x0403_AA01);
+
+        // The same registers through configuration space alone.
+        let (cap, _) = guest.find_cap(CAP_PCI_CFG).unwrap();
+        guest.cfg_write(cap + 4, 1, 0);
+        guest.cfg_write(cap + 8, 4, DEVICE_CFG as u32);
+        guest.cfg_write(cap + 12, 4, 4);
+        assert_eq!(guest.cfg_read(cap + 16, 4), 0x0403_AA01);
+        guest.cfg_write(cap + 12, 4, 1);
+        guest.cfg_write(cap + 16, 4, 0x55);
+        assert_eq!(guest.mmio.read(BAR + DEVICE_CFG, 1), 0x55);
+        // Works with memory decoding off, as the window is meant to.
+        guest.cfg_write(COMMAND, 2, 0);
+        guest.cfg_write(cap + 8, 4, (COMMON_CFG + 0x12) as u32);
+        guest.cfg_write(cap + 12, 4, 2);
+        assert_eq!(guest.cfg_read(cap + 16, 4), 1);
+    }
+
+    #[test]
+    fn test_event_idx_negotiated() {
+        let mut guest = guest();
+        guest.negotiate(VIRTIO_F_VERSION_1 | VIRTIO_F_EVENT_IDX);
+        guest.setup_queue();
+        guest.driver_ok();
+        // used_event = 1: no interrupt for the first completion.
+        guest.mem.write(0x2004 + 2 * 8, &1u16.to_le_bytes()).unwrap();
+        guest.desc(0, 0x9000, 16, VIRTQ_DESC_F_WRITE, 0);
+        let mut levels = vec![];
+        for _ in 0..2 {
+            guest.publish(0);
+            guest.mmio.write(BAR + NOTIFY_CFG, 2, 0);
+            levels.push(guest.irq.level());
+            guest.mmio.read(BAR + ISR_CFG, 1);
+        }
+        assert_eq!(levels, [false, true]);
+        // The device asked to be notified about the next buffer.
+        let mut avail_event = [0u8; 2];
+        guest.mem.read(0x3004 + 8 * 8, &mut avail_event).unwrap();
+        assert_eq!(u16::from_le_bytes(avail_event), 2);
+    }
+}
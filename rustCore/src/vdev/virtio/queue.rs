@@ -0,0 +1,738 @@
+// Device side of a virtio 1.x split virtqueue.
+//
+// The driver owns the descriptor table and the available ring; the device
+// owns the used ring. All three live in guest memory and are only ever
+// reached through a `GuestMemory`, so a malformed ring can fail a request
+// but never touch host memory outside the guest.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use super::{BadGuestAddress, GuestMemory};
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+pub const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+pub const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+const USED_ELEMENT_SIZE: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    NotReady,
+    /// A ring or buffer address outside guest RAM.
+    Memory(u64),
+    /// The available index claims more buffers than the queue holds.
+    BadAvailIndex(u16),
+    /// A chain head or next index past the end of its descriptor table.
+    BadIndex(u16),
+    /// A chain longer than its table, so it must loop.
+    ChainTooLong,
+    IndirectInIndirect,
+    /// An indirect descriptor with NEXT set or a length that is not a
+    /// whole, non-empty table.
+    BadIndirectTable,
+    /// A device-readable descriptor after a device-writable one.
+    ReadableAfterWritable,
+}
+
+impl From<BadGuestAddress> for QueueError {
+    fn from(err: BadGuestAddress) -> Self {
+        QueueError::Memory(err.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+impl Descriptor {
+    fn read(mem: &dyn GuestMemory, gpa: u64) -> Result<Self, QueueError> {
+        let mut raw = [0u8; DESCRIPTOR_SIZE as usize];
+        mem.read(gpa, &mut raw)?;
+        Ok(Descriptor {
+            addr: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+            len: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+            flags: u16::from_le_bytes([raw[12], raw[13]]),
+            next: u16::from_le_bytes([raw[14], raw[15]]),
+        })
+    }
+}
+
+/// A guest buffer named by one descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestBuffer {
+    pub addr: u64,
+    pub len: u32,
+}
+
+/// A request taken off the available ring: the buffers the device reads,
+/// then the buffers it writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorChain {
+    /// Descriptor index to hand back through the used ring.
+    pub head: u16,
+    pub readable: Vec<GuestBuffer>,
+    pub writable: Vec<GuestBuffer>,
+}
+
+impl DescriptorChain {
+    pub fn readable_len(&self) -> u64 {
+        self.readable.iter().map(|buf| buf.len as u64).sum()
+    }
+
+    pub fn writable_len(&self) -> u64 {
+        self.writable.iter().map(|buf| buf.len as u64).sum()
+    }
+
+    /// Everything the driver gave the device to read.
+    pub fn read_all(&self, mem: &dyn GuestMemory) -> Result<Vec<u8>, QueueError> {
+        let mut data = vec![0u8; self.readable_len() as usize];
+        self.read_at(mem, 0, &mut data)?;
+        Ok(data)
+    }
+
+    /// Copy readable bytes from `offset` on into `buf`, returning how many
+    /// there were.
+    pub fn read_at(&self, mem: &dyn GuestMemory, offset: u64, buf: &mut [u8]) -> Result<usize, QueueError> {
+        let mut done = 0;
+        for (gpa, len) in spans(&self.readable, offset, buf.len()) {
+            mem.read(gpa, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(done)
+    }
+
+    /// Scatter `data` into the writable buffers from `offset` on, returning
+    /// how much of it fit.
+    pub fn write_at(&self, mem: &dyn GuestMemory, offset: u64, data: &[u8]) -> Result<usize, QueueError> {
+        let mut done = 0;
+        for (gpa, len) in spans(&self.writable, offset, data.len()) {
+            mem.write(gpa, &data[done..done + len])?;
+            done += len;
+        }
+        Ok(done)
+    }
+}
+
+/// The guest ranges covering up to `len` bytes from `offset` into `buffers`.
+fn spans(buffers: &[GuestBuffer], mut offset: u64, mut len: usize) -> Vec<(u64, usize)> {
+    let mut spans = Vec::new();
+    for buf in buffers {
+        if len == 0 {
+            break;
+        }
+        if offset >= buf.len as u64 {
+            offset -= buf.len as u64;
+            continue;
+        }
+        let n = core::cmp::min(buf.len as u64 - offset, len as u64) as usize;
+        spans.push((buf.addr + offset, n));
+        offset = 0;
+        len -= n;
+    }
+    spans
+}
+
+/// `vring_need_event`: whether moving the used index from `old` to `new`
+/// passed the index the driver asked to be interrupted at.
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// One split virtqueue, configured by the driver through the transport.
+pub struct Virtqueue {
+    max_size: u16,
+    /// Number of descriptors, a power of two no larger than the maximum.
+    pub size: u16,
+    /// Guest-physical addresses of the descriptor table, the available
+    /// ("driver") ring and the used ("device") ring.
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    ready: bool,
+    event_idx: bool,
+    /// Whether the driver should notify the device of new buffers.
+    notify: bool,
+    /// Next available ring entry the device will take.
+    last_avail: u16,
+    used_idx: u16,
+    /// The used index when an interrupt was last considered.
+    signalled_used: u16,
+}
+
+impl Virtqueue {
+    pub fn new(max_size: u16) -> Self {
+        Virtqueue {
+            max_size,
+            size: max_size,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            ready: false,
+            event_idx: false,
+            notify: true,
+            last_avail: 0,
+            used_idx: 0,
+            signalled_used: 0,
+        }
+    }
+
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Start using the queue as configured, with event index notification
+    /// suppression if `event_idx` was negotiated. Fails, leaving the queue
+    /// disabled, if the size or ring alignment is invalid.
+    pub fn enable(&mut self, event_idx: bool) -> bool {
+        let valid = self.size.is_power_of_two()
+            && self.size <= self.max_size
+            && self.desc_table.is_multiple_of(16)
+            && self.avail_ring.is_multiple_of(2)
+            && self.used_ring.is_multiple_of(4);
+        if valid {
+            self.ready = true;
+            self.event_idx = event_idx;
+        }
+        valid
+    }
+
+    /// Back to the state after device reset.
+    pub fn reset(&mut self) {
+        *self = Virtqueue::new(self.max_size);
+    }
+
+    /// Index of the next available ring entry the device will take.
+    pub fn next_avail(&self) -> u16 {
+        self.last_avail
+    }
+
+    pub fn used_idx(&self) -> u16 {
+        self.used_idx
+    }
+
+    /// Whether the driver has made buffers available that the device has
+    /// not taken.
+    pub fn has_available(&self, mem: &dyn GuestMemory) -> Result<bool, QueueError> {
+        if !self.ready {
+            return Err(QueueError::NotReady);
+        }
+        Ok(read_u16(mem, self.avail_ring + 2)? != self.last_avail)
+    }
+
+    /// Take the next available descriptor chain, if any.
+    pub fn pop(&mut self, mem: &dyn GuestMemory) -> Result<Option<DescriptorChain>, QueueError> {
+        if !self.ready {
+            return Err(QueueError::NotReady);
+        }
+        let avail_idx = read_u16(mem, self.avail_ring + 2)?;
+        // Ring entries are read only after the index that published them.
+        fence(Ordering::Acquire);
+        let pending = avail_idx.wrapping_sub(self.last_avail);
+        if pending == 0 {
+            return Ok(None);
+        }
+        if pending > self.size {
+            return Err(QueueError::BadAvailIndex(avail_idx));
+        }
+        let slot = (self.last_avail & (self.size - 1)) as u64;
+        let head = read_u16(mem, self.avail_ring + 4 + 2 * slot)?;
+        let chain = self.walk(mem, head)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        if self.event_idx && self.notify {
+            write_u16(mem, self.avail_event_addr(), self.last_avail)?;
+        }
+        Ok(Some(chain))
+    }
+
+    fn walk(&self, mem: &dyn GuestMemory, head: u16) -> Result<DescriptorChain, QueueError> {
+        let mut chain = DescriptorChain { head, readable: Vec::new(), writable: Vec::new() };
+        let mut table = self.desc_table;
+        let mut table_len = self.size as u32;
+        let mut index = head;
+        let mut seen = 0;
+        let mut indirect = false;
+        if index >= self.size {
+            return Err(QueueError::BadIndex(index));
+        }
+        loop {
+            let desc = Descriptor::read(mem, table + index as u64 * DESCRIPTOR_SIZE)?;
+            seen += 1;
+            if seen > table_len {
+                return Err(QueueError::ChainTooLong);
+            }
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                if indirect {
+                    return Err(QueueError::IndirectInIndirect);
+                }
+                let entries = desc.len as u64 / DESCRIPTOR_SIZE;
+                if desc.flags & VIRTQ_DESC_F_NEXT != 0
+                    || !(desc.len as u64).is_multiple_of(DESCRIPTOR_SIZE)
+                    || entries == 0
+                    || entries > MAX_QUEUE_SIZE as u64
+                {
+                    return Err(QueueError::BadIndirectTable);
+                }
+                table = desc.addr;
+                table_len = entries as u32;
+                index = 0;
+                seen = 0;
+                indirect = true;
+                continue;
+            }
+            let buf = GuestBuffer { addr: desc.addr, len: desc.len };
+            if desc.flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push(buf);
+            } else if chain.writable.is_empty() {
+                chain.readable.push(buf);
+            } else {
+                return Err(QueueError::ReadableAfterWritable);
+            }
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(chain);
+            }
+            if desc.next as u32 >= table_len {
+                return Err(QueueError::BadIndex(desc.next));
+            }
+            index = desc.next;
+        }
+    }
+
+    /// Return the chain starting at `head` to the driver, with `written`
+    /// bytes written to its writable buffers.
+    pub fn add_used(&mut self, mem: &dyn GuestMemory, head: u16, written: u32) -> Result<(), QueueError> {
+        self.add_used_batch(mem, &[(head, written)])
+    }
+
+    /// Return several chains, each with the number of bytes written to it,
+    /// under a single index update so the driver sees all or none of them.
+    pub fn add_used_batch(&mut self, mem: &dyn GuestMemory, used: &[(u16, u32)]) -> Result<(), QueueError> {
+        if !self.ready {
+            return Err(QueueError::NotReady);
+        }
+        for (i, &(head, written)) in used.iter().enumerate() {
+            let slot = (self.used_idx.wrapping_add(i as u16) & (self.size - 1)) as u64;
+            let mut element = [0u8; USED_ELEMENT_SIZE as usize];
+            element[..4].copy_from_slice(&(head as u32).to_le_bytes());
+            element[4..].copy_from_slice(&written.to_le_bytes());
+            mem.write(self.used_ring + 4 + USED_ELEMENT_SIZE * slot, &element)?;
+        }
+        // The elements must be visible before the index that publishes them.
+        fence(Ordering::Release);
+        self.used_idx = self.used_idx.wrapping_add(used.len() as u16);
+        write_u16(mem, self.used_ring + 2, self.used_idx)?;
+        Ok(())
+    }
+
+    /// Put back the last `count` chains taken, for a device that cannot use
+    /// them yet. With event indices the driver is asked to notify as soon as
+    /// it makes more buffers available.
+    pub fn unpop(&mut self, mem: &dyn GuestMemory, count: u16) -> Result<(), QueueError> {
+        if !self.ready {
+            return Err(QueueError::NotReady);
+        }
+        self.last_avail = self.last_avail.wrapping_sub(count);
+        if self.event_idx && self.notify {
+            let avail_idx = read_u16(mem, self.avail_ring + 2)?;
+            write_u16(mem, self.avail_event_addr(), avail_idx)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the driver wants an interrupt for the buffers used since
+    /// this was last asked.
+    pub fn needs_interrupt(&mut self, mem: &dyn GuestMemory) -> Result<bool, QueueError> {
+        if !self.ready {
+            return Ok(false);
+        }
+        // Order the used index update against reading the driver's wishes.
+        fence(Ordering::SeqCst);
+        let (old, new) = (self.signalled_used, self.used_idx);
+        self.signalled_used = new;
+        if self.event_idx {
+            let used_event = read_u16(mem, self.avail_ring + 4 + 2 * self.size as u64)?;
+            Ok(need_event(used_event, new, old))
+        } else {
+            Ok(new != old && read_u16(mem, self.avail_ring)? & VIRTQ_AVAIL_F_NO_INTERRUPT == 0)
+        }
+    }
+
+    /// Ask the driver to notify, or not to bother notifying, when it makes
+    /// buffers available. Devices turn notifications off while draining the
+    /// queue, then back on and check [`has_available`](Self::has_available)
+    /// once more to close the race.
+    pub fn set_notification(&mut self, mem: &dyn GuestMemory, enabled: bool) -> Result<(), QueueError> {
+        if !self.ready {
+            return Err(QueueError::NotReady);
+        }
+        self.notify = enabled;
+        if self.event_idx {
+            // There is no way to refuse notifications outright; leaving
+            // avail_event behind only makes them unlikely.
+            if enabled {
+                write_u16(mem, self.avail_event_addr(), self.last_avail)?;
+            }
+        } else {
+            let flags = if enabled { 0 } else { VIRTQ_USED_F_NO_NOTIFY };
+            write_u16(mem, self.used_ring, flags)?;
+        }
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn avail_event_addr(&self) -> u64 {
+        self.used_ring + 4 + USED_ELEMENT_SIZE * self.size as u64
+    }
+}
+
+fn read_u16(mem: &dyn GuestMemory, gpa: u64) -> Result<u16, QueueError> {
+    let mut raw = [0u8; 2];
+    mem.read(gpa, &mut raw)?;
+    Ok(u16::from_le_bytes(raw))
+}
+
+fn write_u16(mem: &dyn GuestMemory, gpa: u64, value: u16) -> Result<(), QueueError> {
+    mem.write(gpa, &value.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::{HypervisorBackend, MockBackend};
+    use crate::vdev::virtio::LayoutMemory;
+
+    const DESC: u64 = 0x1000;
+    const AVAIL: u64 = 0x2000;
+    const USED: u64 = 0x3000;
+    const INDIRECT: u64 = 0x4000;
+    const SIZE: u16 = 8;
+
+    /// 64 KiB of guest RAM, as a device sees it, and the backend owning it.
+    fn memory() -> (MockBackend, LayoutMemory) {
+        let mut backend = MockBackend::new();
+        backend.alloc_ram(0, 0x10000).unwrap();
+        let mem = unsafe { LayoutMemory::new(backend.memory_layout().clone(), 0) };
+        (backend, mem)
+    }
+
+    fn ready_queue(event_idx: bool) -> Virtqueue {
+        let mut queue = Virtqueue::new(16);
+        queue.size = SIZE;
+        queue.desc_table = DESC;
+        queue.avail_ring = AVAIL;
+        queue.used_ring = USED;
+        assert!(queue.enable(event_idx));
+        queue
+    }
+
+    /// Driver side: write descriptor `index` of the table at `table`.
+    fn desc(mem: &dyn GuestMemory, table: u64, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let mut raw = [0u8; 16];
+        raw[0..8].copy_from_slice(&addr.to_le_bytes());
+        raw[8..12].copy_from_slice(&len.to_le_bytes());
+        raw[12..14].copy_from_slice(&flags.to_le_bytes());
+        raw[14..16].copy_from_slice(&next.to_le_bytes());
+        mem.write(table + index as u64 * 16, &raw).unwrap();
+    }
+
+    /// Driver side: make the chains at `heads` available.
+    fn publish(mem: &dyn GuestMemory, heads: &[u16]) {
+        let idx = read_u16(mem, AVAIL + 2).unwrap();
+        for (i, &head) in heads.iter().enumerate() {
+            let slot = idx.wrapping_add(i as u16) % SIZE;
+            write_u16(mem, AVAIL + 4 + 2 * slot as u64, head).unwrap();
+        }
+        write_u16(mem, AVAIL + 2, idx.wrapping_add(heads.len() as u16)).unwrap();
+    }
+
+    fn used(mem: &dyn GuestMemory, slot: u16) -> (u32, u32) {
+        let mut raw = [0u8; 8];
+        mem.read(USED + 4 + 8 * slot as u64, &mut raw).unwrap();
+        (u32::from_le_bytes(raw[..4].try_into().unwrap()), u32::from_le_bytes(raw[4..].try_into().unwrap()))
+    }
+
+    #[test]
+    fn test_enable_validates_configuration() {
+        let mut queue = Virtqueue::new(16);
+        assert_eq!(queue.pop(&memory().1), Err(QueueError::NotReady));
+        queue.size = 12;
+        assert!(!queue.enable(false));
+        queue.size = 32;
+        assert!(!queue.enable(false));
+        queue.size = 8;
+        queue.desc_table = 0x1008;
+        assert!(!queue.enable(false));
+        queue.desc_table = 0x1000;
+        queue.used_ring = 0x3002;
+        assert!(!queue.enable(false));
+        queue.used_ring = 0x3000;
+        assert!(queue.enable(false));
+        queue.reset();
+        assert!(!queue.is_ready());
+        assert_eq!((queue.size, queue.desc_table), (16, 0));
+    }
+
+    #[test]
+    fn test_chain_walk_and_used_ring() {
+        let (_backend, mem) = memory();
+        let mut queue = ready_queue(false);
+        assert_eq!(queue.pop(&mem).unwrap(), None);
+
+        // A request header and payload to read, then a status byte to write.
+        mem.write(0x8000, b"head").unwrap();
+        mem.write(0x8100, b"payload").unwrap();
+        desc(&mem, DESC, 3, 0x8000, 4, VIRTQ_DESC_F_NEXT, 5);
+        desc(&mem, DESC, 5, 0x8100, 7, VIRTQ_DESC_F_NEXT, 0);
+        desc(&mem, DESC, 0, 0x8200, 1, VIRTQ_DESC_F_WRITE, 0);
+        desc(&mem, DESC, 1, 0x8300, 16, VIRTQ_DESC_F_WRITE, 0);
+        publish(&mem, &[3, 1]);
+        assert!(queue.has_available(&mem).unwrap());
+
+        let chain = queue.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.head, 3);
+        assert_eq!((chain.readable_len(), chain.writable_len()), (11, 1));
+        assert_eq!(chain.read_all(&mem).unwrap(), b"headpayload");
+        let mut middle = [0u8; 5];
+        assert_eq!(chain.read_at(&mem, 2, &mut middle).unwrap(), 5);
+        assert_eq!(&middle, b"adpay");
+        assert_eq!(chain.write_at(&mem, 0, &[0, 9]).unwrap(), 1);
+        queue.add_used(&mem, chain.head, 1).unwrap();
+
+        let chain = queue.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.readable, []);
+        assert_eq!(chain.writable, [GuestBuffer { addr: 0x8300, len: 16 }]);
+        assert_eq!(chain.write_at(&mem, 14, b"xyz").unwrap(), 2);
+        queue.add_used(&mem, chain.head, 16).unwrap();
+        assert!(!queue.has_available(&mem).unwrap());
+        assert_eq!(queue.pop(&mem).unwrap(), None);
+
+        assert_eq!(read_u16(&mem, USED + 2).unwrap(), 2);
+        assert_eq!(used(&mem, 0), (3, 1));
+        assert_eq!(used(&mem, 1), (1, 16));
+        let mut status = [0u8; 2];
+        mem.read(0x8200, &mut status).unwrap();
+        assert_eq!(status, [0, 0]);
+        mem.read(0x830E, &mut status).unwrap();
+        assert_eq!(&status, b"xy");
+    }
+
+    #[test]
+    fn test_ring_indices_wrap() {
+        let (_backend, mem) = memory();
+        let mut queue = ready_queue(false);
+        desc(&mem, DESC, 2, 0x8000, 8, VIRTQ_DESC_F_WRITE, 0);
+        write_u16(&mem, AVAIL + 2, 0xFFFE).unwrap();
+        // Start both sides just short of the wrap.
+        queue.last_avail = 0xFFFE;
+        queue.used_idx = 0xFFFE;
+        queue.signalled_used = 0xFFFE;
+        for _ in 0..4 {
+            publish(&mem, &[2]);
+            let chain = queue.pop(&mem).unwrap().unwrap();
+            queue.add_used(&mem, chain.head, 8).unwrap();
+        }
+        assert_eq!(queue.next_avail(), 2);
+        assert_eq!(read_u16(&mem, USED + 2).unwrap(), 2);
+        assert_eq!(used(&mem, 1), (2, 8));
+
+        // A driver claiming more than a ring's worth is broken.
+        write_u16(&mem, AVAIL + 2, 2 + SIZE + 1).unwrap();
+        assert_eq!(queue.pop(&mem), Err(QueueError::BadAvailIndex(2 + SIZE + 1)));
+    }
+
+    #[test]
+    fn test_indirect_descriptors() {
+        let (_backend, mem) = memory();
+        let mut queue = ready_queue(false);
+        mem.write(0x8000, b"abc").unwrap();
+        desc(&mem, INDIRECT, 0, 0x8000, 3, VIRTQ_DESC_F_NEXT, 2);
+        desc(&mem, INDIRECT, 2, 0x8100, 4, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 1);
+        desc(&mem, INDIRECT, 1, 0x8200, 4, VIRTQ_DESC_F_WRITE, 0);
+        // The write flag on the indirect descriptor itself is ignored.
+        desc(&mem, DESC, 4, INDIRECT, 3 * 16, VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_WRITE, 0);
+        publish(&mem, &[4]);
+        let chain = queue.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.head, 4);
+        assert_eq!(chain.read_all(&mem).unwrap(), b"abc");
+        assert_eq!(chain.writable, [GuestBuffer { addr: 0x8100, len: 4 }, GuestBuffer { addr: 0x8200, len: 4 }]);
+        assert_eq!(chain.write_at(&mem, 2, b"123456").unwrap(), 6);
+        let mut out = [0u8; 4];
+        mem.read(0x8200, &mut out).unwrap();
+        assert_eq!(&out, b"3456");
+
+        // A direct descriptor may lead into an indirect table.
+        desc(&mem, DESC, 6, 0x8000, 3, VIRTQ_DESC_F_NEXT, 7);
+        desc(&mem, DESC, 7, INDIRECT + 16, 16, VIRTQ_DESC_F_INDIRECT, 0);
+        publish(&mem, &[6]);
+        let chain = queue.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.readable.len(), 1);
+        assert_eq!(chain.writable, [GuestBuffer { addr: 0x8200, len: 4 }]);
+    }
+
+    #[test]
+    fn test_malformed_chains() {
+        let (_backend, mem) = memory();
+        // (index, addr, len, flags, next) of each descriptor, the head and
+        // the error.
+        type Case = (&'static [(u16, u64, u32, u16, u16)], u16, QueueError);
+        let cases: [Case; 8] = [
+            // Head past the table.
+            (&[], 9, QueueError::BadIndex(9)),
+            // Next past the table.
+            (&[(0, 0x8000, 4, VIRTQ_DESC_F_NEXT, 8)], 0, QueueError::BadIndex(8)),
+            // A loop.
+            (&[(0, 0x8000, 4, VIRTQ_DESC_F_NEXT, 1), (1, 0x8000, 4, VIRTQ_DESC_F_NEXT, 0)], 0, QueueError::ChainTooLong),
+            // Read after write.
+            (
+                &[(0, 0x8000, 4, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 1), (1, 0x8000, 4, 0, 0)],
+                0,
+                QueueError::ReadableAfterWritable,
+            ),
+            // Indirect with NEXT.
+            (&[(0, INDIRECT, 16, VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_NEXT, 1)], 0, QueueError::BadIndirectTable),
+            // Indirect table of a partial descriptor.
+            (&[(0, INDIRECT, 20, VIRTQ_DESC_F_INDIRECT, 0)], 0, QueueError::BadIndirectTable),
+            // Indirect table outside guest RAM.
+            (&[(0, 0x20_0000, 16, VIRTQ_DESC_F_INDIRECT, 0)], 0, QueueError::Memory(0x20_0000)),
+            // Indirect within indirect.
+            (&[(0, INDIRECT + 0x100, 16, VIRTQ_DESC_F_INDIRECT, 0)], 0, QueueError::IndirectInIndirect),
+        ];
+        desc(&mem, INDIRECT + 0x100, 0, INDIRECT, 16, VIRTQ_DESC_F_INDIRECT, 0);
+        for (descs, head, err) in cases {
+            let mut queue = ready_queue(false);
+            write_u16(&mem, AVAIL + 2, 0).unwrap();
+            for &(index, addr, len, flags, next) in descs {
+                desc(&mem, DESC, index, addr, len, flags, next);
+            }
+            publish(&mem, &[head]);
+            assert_eq!(queue.pop(&mem), Err(err));
+        }
+
+        // A loop inside an indirect table.
+        let mut queue = ready_queue(false);
+        write_u16(&mem, AVAIL + 2, 0).unwrap();
+        desc(&mem, INDIRECT, 0, 0x8000, 4, VIRTQ_DESC_F_NEXT, 1);
+        desc(&mem, INDIRECT, 1, 0x8000, 4, VIRTQ_DESC_F_NEXT, 0);
+        desc(&mem, DESC, 0, INDIRECT, 32, VIRTQ_DESC_F_INDIRECT, 0);
+        publish(&mem, &[0]);
+        assert_eq!(queue.pop(&mem), Err(QueueError::ChainTooLong));
+
+        // A buffer outside guest RAM fails the access, not the walk.
+        let mut queue = ready_queue(false);
+        write_u16(&mem, AVAIL + 2, 0).unwrap();
+        desc(&mem, DESC, 0, 0xFFFE, 4, 0, 0);
+        publish(&mem, &[0]);
+        let chain = queue.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.read_all(&mem), Err(QueueError::Memory(0x10000)));
+    }
+
+    #[test]
+    fn test_interrupt_suppression_flags() {
+        let (_backend, mem) = memory();
+        let mut queue = ready_queue(false);
+        desc(&mem, DESC, 0, 0x8000, 4, VIRTQ_DESC_F_WRITE, 0);
+        assert!(!queue.needs_interrupt(&mem).unwrap());
+        publish(&mem, &[0]);
+        queue.pop(&mem).unwrap();
+        queue.add_used(&mem, 0, 0).unwrap();
+        assert!(queue.needs_interrupt(&mem).unwrap());
+        assert!(!queue.needs_interrupt(&mem).unwrap());
+
+        write_u16(&mem, AVAIL, VIRTQ_AVAIL_F_NO_INTERRUPT).unwrap();
+        publish(&mem, &[0]);
+        queue.pop(&mem).unwrap();
+        queue.add_used(&mem, 0, 0).unwrap();
+        assert!(!queue.needs_interrupt(&mem).unwrap());
+
+        queue.set_notification(&mem, false).unwrap();
+        assert_eq!(read_u16(&mem, USED).unwrap(), VIRTQ_USED_F_NO_NOTIFY);
+        queue.set_notification(&mem, true).unwrap();
+        assert_eq!(read_u16(&mem, USED).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_event_idx() {
+        let (_backend, mem) = memory();
+        let mut queue = ready_queue(true);
+        let used_event = AVAIL + 4 + 2 * SIZE as u64;
+        let avail_event = USED + 4 + 8 * SIZE as u64;
+        desc(&mem, DESC, 0, 0x8000, 4, VIRTQ_DESC_F_WRITE, 0);
+
+        // The driver wants an interrupt once the used index passes 1.
+        write_u16(&mem, used_event, 1).unwrap();
+        for expected in [false, true, false] {
+            publish(&mem, &[0]);
+            queue.pop(&mem).unwrap();
+            queue.add_used(&mem, 0, 0).unwrap();
+            assert_eq!(queue.needs_interrupt(&mem).unwrap(), expected);
+        }
+        // Batched completions crossing the event still interrupt once.
+        write_u16(&mem, used_event, 4).unwrap();
+        for _ in 0..3 {
+            publish(&mem, &[0]);
+            queue.pop(&mem).unwrap();
+            queue.add_used(&mem, 0, 0).unwrap();
+        }
+        assert!(queue.needs_interrupt(&mem).unwrap());
+        assert!(!queue.needs_interrupt(&mem).unwrap());
+
+        // Each pop asks to be told about the next buffer, unless the device
+        // has turned notifications off.
+        assert_eq!(read_u16(&mem, avail_event).unwrap(), 6);
+        queue.set_notification(&mem, false).unwrap();
+        publish(&mem, &[0]);
+        queue.pop(&mem).unwrap();
+        assert_eq!(read_u16(&mem, avail_event).unwrap(), 6);
+        queue.set_notification(&mem, true).unwrap();
+        assert_eq!(read_u16(&mem, avail_event).unwrap(), 7);
+        // The used ring flags are left alone.
+        assert_eq!(read_u16(&mem, USED).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_unpop_and_batched_used() {
+        let (_backend, mem) = memory();
+        let mut queue = ready_queue(true);
+        let avail_event = USED + 4 + 8 * SIZE as u64;
+        desc(&mem, DESC, 0, 0x8000, 4, VIRTQ_DESC_F_WRITE, 0);
+        desc(&mem, DESC, 1, 0x8100, 4, VIRTQ_DESC_F_WRITE, 0);
+        publish(&mem, &[0, 1]);
+        queue.pop(&mem).unwrap();
+        queue.pop(&mem).unwrap();
+        queue.unpop(&mem, 2).unwrap();
+        assert_eq!(queue.next_avail(), 0);
+        assert_eq!(read_u16(&mem, avail_event).unwrap(), 2);
+
+        let heads: Vec<u16> = core::iter::from_fn(|| queue.pop(&mem).unwrap()).map(|chain| chain.head).collect();
+        assert_eq!(heads, [0, 1]);
+        queue.add_used_batch(&mem, &[(1, 4), (0, 2)]).unwrap();
+        assert_eq!(read_u16(&mem, USED + 2).unwrap(), 2);
+        assert_eq!((used(&mem, 0), used(&mem, 1)), ((1, 4), (0, 2)));
+    }
+
+    #[test]
+    fn test_need_event() {
+        assert!(need_event(0, 1, 0));
+        assert!(!need_event(1, 1, 0));
+        assert!(need_event(5, 8, 3));
+        assert!(!need_event(8, 8, 3));
+        assert!(need_event(0xFFFF, 2, 0xFFFE));
+        assert!(!need_event(2, 2, 0xFFFE));
+    }
+}
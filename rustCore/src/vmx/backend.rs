@@ -0,0 +1,876 @@
+// `HypervisorBackend` on top of VT-x: one VMCS per vCPU, guest memory in an
+// EPT, and VM exits translated into backend-neutral `VmExit`s.
+
+use core::arch::asm;
+use core::cell::Cell;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use x86_64::instructions::tables::{sgdt, sidt};
+use x86_64::registers::model_specific::Msr;
+
+use super::capabilities::{entry, exit, pin_based, proc_based, proc_based2, VmxCapabilities};
+use super::cpu_model::{
+    CpuModel, ModelMsrHandler, MsrBitmap, MsrSwitchArea, IA32_SYSENTER_CS, IA32_SYSENTER_EIP, IA32_SYSENTER_ESP,
+    IA32_TSC,
+};
+use super::ept::{invept, Ept, GuestMemoryLayout, InveptType, MemoryRegion, MemoryType, RegionKind};
+use super::exit::{
+    fetch_guest_instruction, guest_cpu_mode, ExitAction, ExitContext, ExitDispatcher, ExitInfo, ExitReason,
+};
+use super::percpu;
+use super::vcpu::Vcpu;
+use super::vmcs::{ControlField, ExitInfoField, GuestField, HostField, Vmcs};
+use crate::hypervisor::{
+    operand_mask, sign_extend, DescriptorTable, EventKind, ExitResponse, HvError, HypervisorBackend, IoDirection,
+    PendingEvent, Segment, SpecialRegisters, VcpuEvents, VcpuId, VcpuRegisters, VcpuState, VmExit, CR0_PE, CR0_PG,
+    EFER_LMA,
+};
+use crate::memory::SimpleFrameAllocator;
+use crate::vdev::decoder::{decode, CpuMode, Extend, Instruction, MmioOp, Source, MAX_INSTRUCTION_LEN};
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_FS_BASE: u32 = 0xC000_0100;
+const IA32_GS_BASE: u32 = 0xC000_0101;
+const CR4_VMXE: u64 = 1 << 13;
+const RFLAGS_IF: u64 = 1 << 9;
+/// Valid bit of the VM-entry interruption-information field.
+const INTERRUPTION_VALID: u64 = 1 << 31;
+const INTERRUPTION_ERROR_CODE: u64 = 1 << 11;
+/// Interruptibility-state bits: blocking by STI and MOV SS, and NMI blocking.
+const INTERRUPTIBILITY_SHADOW: u64 = 3;
+const INTERRUPTIBILITY_NMI: u64 = 1 << 3;
+/// MSRs held in guest-state fields of the VMCS rather than the switch area.
+const VMCS_MSRS: [(u32, GuestField); 3] = [
+    (IA32_SYSENTER_CS, GuestField::Ia32SysenterCs),
+    (IA32_SYSENTER_ESP, GuestField::Ia32SysenterEsp),
+    (IA32_SYSENTER_EIP, GuestField::Ia32SysenterEip),
+];
+
+/// Selector, base, limit and access-rights fields of one guest segment.
+type SegmentFields = (GuestField, GuestField, GuestField, GuestField);
+
+const ES: SegmentFields = (GuestField::EsSelector, GuestField::EsBase, GuestField::EsLimit, GuestField::EsAccessRights);
+const CS: SegmentFields = (GuestField::CsSelector, GuestField::CsBase, GuestField::CsLimit, GuestField::CsAccessRights);
+const SS: SegmentFields = (GuestField::SsSelector, GuestField::SsBase, GuestField::SsLimit, GuestField::SsAccessRights);
+const DS: SegmentFields = (GuestField::DsSelector, GuestField::DsBase, GuestField::DsLimit, GuestField::DsAccessRights);
+const FS: SegmentFields = (GuestField::FsSelector, GuestField::FsBase, GuestField::FsLimit, GuestField::FsAccessRights);
+const GS: SegmentFields = (GuestField::GsSelector, GuestField::GsBase, GuestField::GsLimit, GuestField::GsAccessRights);
+const TR: SegmentFields = (GuestField::TrSelector, GuestField::TrBase, GuestField::TrLimit, GuestField::TrAccessRights);
+const LDTR: SegmentFields =
+    (GuestField::LdtrSelector, GuestField::LdtrBase, GuestField::LdtrLimit, GuestField::LdtrAccessRights);
+
+struct BackendVcpu {
+    vcpu: Vcpu,
+    /// The exit handed to the caller, completed on the next `run`.
+    pending: Option<ExitInfo>,
+    /// Decoded instruction behind a pending MMIO exit.
+    mmio: Option<(Instruction, CpuMode)>,
+    response: ExitResponse,
+    /// Guest and host values of the CPU model's switched MSRs.
+    msr_area: Option<MsrSwitchArea>,
+    /// The physical CPU this vCPU's VMCS is active on, if any.
+    cpu: Cell<Option<u32>>,
+}
+
+/// VT-x implementation of [`HypervisorBackend`].
+///
+/// VMX must already be on (see [`super::init`]) on every CPU the backend is
+/// used from. A vCPU's VMCS stays active on the CPU that first loaded it
+/// until [`release_vcpu`](Self::release_vcpu), so vCPU threads are pinned
+/// between releases. Handlers registered on the dispatcher run first; exits
+/// they leave unhandled are returned from `run`.
+pub struct VmxBackend {
+    caps: VmxCapabilities,
+    vcpus: BTreeMap<VcpuId, BackendVcpu>,
+    layout: GuestMemoryLayout,
+    ept: Ept,
+    allocator: SimpleFrameAllocator,
+    phys_offset: u64,
+    dispatcher: ExitDispatcher,
+    cpu_model: Option<CpuModelState>,
+}
+
+/// An installed CPU model: its MSR bitmap and the handler holding the
+/// values of its emulated MSRs.
+type CpuModelState = (Arc<CpuModel>, MsrBitmap, ModelMsrHandler);
+
+impl VmxBackend {
+    /// `allocator` provides frames for the EPT paging structures, which are
+    /// accessed through `phys_offset`.
+    pub fn new(mut allocator: SimpleFrameAllocator, phys_offset: u64) -> Result<Self, HvError> {
+        let caps = VmxCapabilities::read();
+        if !caps.supports_ept() {
+            return Err(HvError::Backend("EPT is not supported"));
+        }
+        let mut ept = Ept::new(&mut allocator, phys_offset)?;
+        ept.set_large_pages_1g(caps.ept_vpid.pages_1g());
+        Ok(VmxBackend {
+            caps,
+            vcpus: BTreeMap::new(),
+            layout: GuestMemoryLayout::new(),
+            ept,
+            allocator,
+            phys_offset,
+            dispatcher: ExitDispatcher::new(),
+            cpu_model: None,
+        })
+    }
+
+    pub fn capabilities(&self) -> &VmxCapabilities {
+        &self.caps
+    }
+
+    pub fn ept(&self) -> &Ept {
+        &self.ept
+    }
+
+    /// Exit handlers that run inside `run` before exits reach the caller.
+    pub fn dispatcher_mut(&mut self) -> &mut ExitDispatcher {
+        &mut self.dispatcher
+    }
+
+    /// Give the guest `model`'s CPUID and MSRs instead of whatever reaches
+    /// the caller. Applies to existing and future vCPUs, and replaces the
+    /// dispatcher's CPUID and RDMSR/WRMSR handlers.
+    pub fn set_cpu_model(&mut self, model: CpuModel) -> Result<(), HvError> {
+        if !self.caps.proc_based.supports(proc_based::USE_MSR_BITMAPS) {
+            return Err(HvError::Backend("MSR bitmaps are not supported"));
+        }
+        let model = Arc::new(model);
+        let bitmap = model.msr_bitmap(&mut self.allocator, self.phys_offset)?;
+        let msrs = model.install(&mut self.dispatcher);
+        self.cpu_model = Some((model, bitmap, msrs));
+        let ids: Vec<VcpuId> = self.vcpus.keys().copied().collect();
+        for id in ids {
+            self.load(id)?;
+            let vcpu = self.vcpus.get_mut(&id).ok_or(HvError::NoSuchVcpu(id))?;
+            Self::apply_cpu_model(self.cpu_model.as_ref(), &mut self.allocator, self.phys_offset, vcpu)?;
+        }
+        Ok(())
+    }
+
+    pub fn cpu_model(&self) -> Option<&Arc<CpuModel>> {
+        self.cpu_model.as_ref().map(|(model, _, _)| model)
+    }
+
+    /// Take `size` bytes of zeroed, contiguous frames from the allocator and
+    /// map them as guest RAM at `guest_phys`.
+    pub fn alloc_ram(&mut self, guest_phys: u64, size: u64) -> Result<(), HvError> {
+        let host_phys = self.allocator.allocate_range(size).ok_or(HvError::Backend("out of memory for guest RAM"))?;
+        unsafe { core::ptr::write_bytes((host_phys + self.phys_offset) as *mut u8, 0, size as usize) };
+        self.map_memory(MemoryRegion::ram(guest_phys, size, host_phys))
+    }
+
+    /// Point the current VMCS, which must be `vcpu`'s, at the MSR bitmap and
+    /// a fresh switch area in frames from `frames`.
+    fn apply_cpu_model(
+        model: Option<&CpuModelState>,
+        frames: &mut SimpleFrameAllocator,
+        phys_offset: u64,
+        vcpu: &mut BackendVcpu,
+    ) -> Result<(), HvError> {
+        let (model, bitmap, _) = match model {
+            Some(model) => model,
+            None => return Ok(()),
+        };
+        let vmcs = &vcpu.vcpu.vmcs;
+        vmcs.write_control(ControlField::MsrBitmap, bitmap.phys_addr())?;
+        vmcs.set_control_bits(ControlField::PrimaryProcBasedControls, proc_based::USE_MSR_BITMAPS as u64)?;
+        let msrs: Vec<u32> = model.switched_msrs().collect();
+        let area = MsrSwitchArea::new(frames, phys_offset, &msrs, |msr| unsafe { Msr::new(msr).read() })?;
+        area.install(vmcs)?;
+        vcpu.msr_area = Some(area);
+        Ok(())
+    }
+
+    /// Load the VMCS of `id` and return it. A VMCS arriving on a new CPU
+    /// picks up that CPU's host state.
+    fn load(&self, id: VcpuId) -> Result<&BackendVcpu, HvError> {
+        let vcpu = self.vcpus.get(&id).ok_or(HvError::NoSuchVcpu(id))?;
+        let cpu = percpu::current_cpu();
+        match vcpu.cpu.get() {
+            Some(active) if active != cpu => return Err(HvError::Backend("vCPU is active on another CPU")),
+            Some(_) => {
+                if !vcpu.vcpu.vmcs.is_current() {
+                    vcpu.vcpu.vmcs.load()?;
+                }
+            }
+            None => {
+                vcpu.vcpu.vmcs.load()?;
+                self.setup_host_state(&vcpu.vcpu.vmcs)?;
+                vcpu.cpu.set(Some(cpu));
+            }
+        }
+        Ok(vcpu)
+    }
+
+    /// VMCLEAR the VMCS of `id` on the CPU it is active on, which must be
+    /// the executing one, so the vCPU can next run on any CPU.
+    pub fn release_vcpu(&mut self, id: VcpuId) -> Result<(), HvError> {
+        let vcpu = self.vcpus.get_mut(&id).ok_or(HvError::NoSuchVcpu(id))?;
+        match vcpu.cpu.get() {
+            None => return Ok(()),
+            Some(cpu) if cpu != percpu::current_cpu() => return Err(HvError::Backend("vCPU is active on another CPU")),
+            Some(_) => {}
+        }
+        vcpu.vcpu.vmcs.clear()?;
+        vcpu.cpu.set(None);
+        Ok(())
+    }
+
+    /// The physical CPU `id` is active on.
+    pub fn vcpu_cpu(&self, id: VcpuId) -> Option<u32> {
+        self.vcpus.get(&id).and_then(|vcpu| vcpu.cpu.get())
+    }
+
+    fn load_mut(&mut self, id: VcpuId) -> Result<&mut BackendVcpu, HvError> {
+        self.load(id)?;
+        self.vcpus.get_mut(&id).ok_or(HvError::NoSuchVcpu(id))
+    }
+
+    fn setup_controls(&self, vmcs: &Vmcs) -> Result<(), HvError> {
+        let caps = &self.caps;
+        let pin = caps.pin_based.adjust(pin_based::EXTERNAL_INTERRUPT_EXITING | pin_based::NMI_EXITING);
+        let mut proc =
+            proc_based::HLT_EXITING | proc_based::UNCONDITIONAL_IO_EXITING | proc_based::ACTIVATE_SECONDARY_CONTROLS;
+        // Lets a restored guest carry on from the TSC value it was saved with.
+        if caps.proc_based.supports(proc_based::USE_TSC_OFFSETTING) {
+            proc |= proc_based::USE_TSC_OFFSETTING;
+        }
+        let proc = caps.proc_based.adjust(proc);
+        let mut proc2 = proc_based2::ENABLE_EPT;
+        if caps.supports_unrestricted_guest() {
+            proc2 |= proc_based2::UNRESTRICTED_GUEST;
+        }
+        let proc2 = caps.proc_based2.adjust(proc2);
+        let exit_controls = caps.exit.adjust(
+            exit::HOST_ADDRESS_SPACE_SIZE | exit::SAVE_EFER | exit::LOAD_EFER,
+        );
+        let entry_controls = caps.entry.adjust(entry::LOAD_EFER);
+
+        vmcs.write_control(ControlField::PinBasedControls, pin as u64)?;
+        vmcs.write_control(ControlField::PrimaryProcBasedControls, proc as u64)?;
+        vmcs.write_control(ControlField::SecondaryProcBasedControls, proc2 as u64)?;
+        vmcs.write_control(ControlField::VmExitControls, exit_controls as u64)?;
+        vmcs.write_control(ControlField::VmEntryControls, entry_controls as u64)?;
+        vmcs.write_control(ControlField::ExceptionBitmap, 0)?;
+        vmcs.write_control(ControlField::Cr3TargetCount, 0)?;
+        vmcs.write_control(ControlField::Cr0GuestHostMask, 0)?;
+        vmcs.write_control(ControlField::Cr4GuestHostMask, CR4_VMXE)?;
+        vmcs.write_control(ControlField::Cr4ReadShadow, 0)?;
+        vmcs.write_control(ControlField::EptPointer, self.ept.eptp())?;
+        vmcs.write_control(ControlField::TscOffset, 0)?;
+        vmcs.write_guest(GuestField::VmcsLinkPointer, u64::MAX)?;
+        vmcs.write_guest(GuestField::ActivityState, 0)?;
+        vmcs.write_guest(GuestField::InterruptibilityState, 0)?;
+        vmcs.write_guest(GuestField::Dr7, 0x400)?;
+        Ok(())
+    }
+
+    /// Whether the guest's TSC runs at an offset from the host's.
+    fn tsc_offsetting(vmcs: &Vmcs) -> Result<bool, HvError> {
+        let proc = vmcs.read_control(ControlField::PrimaryProcBasedControls)?;
+        Ok(proc & proc_based::USE_TSC_OFFSETTING as u64 != 0)
+    }
+
+    /// Host state restored on every exit: the current CPU as it is now.
+    fn setup_host_state(&self, vmcs: &Vmcs) -> Result<(), HvError> {
+        let (cr0, cr3, cr4): (u64, u64, u64);
+        let (cs, ss, ds, es, fs, gs, tr): (u16, u16, u16, u16, u16, u16, u16);
+        unsafe {
+            asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack));
+            asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+            asm!("mov {:x}, cs", out(reg) cs, options(nomem, nostack));
+            asm!("mov {:x}, ss", out(reg) ss, options(nomem, nostack));
+            asm!("mov {:x}, ds", out(reg) ds, options(nomem, nostack));
+            asm!("mov {:x}, es", out(reg) es, options(nomem, nostack));
+            asm!("mov {:x}, fs", out(reg) fs, options(nomem, nostack));
+            asm!("mov {:x}, gs", out(reg) gs, options(nomem, nostack));
+            asm!("str {:x}", out(reg) tr, options(nomem, nostack));
+        }
+        let gdt = sgdt();
+        let idt = sidt();
+        let gdt_base = gdt.base.as_u64();
+
+        vmcs.write_host(HostField::Cr0, cr0)?;
+        vmcs.write_host(HostField::Cr3, cr3)?;
+        vmcs.write_host(HostField::Cr4, cr4)?;
+        // Host selectors must have RPL and TI clear.
+        vmcs.write_host(HostField::CsSelector, (cs & !7) as u64)?;
+        vmcs.write_host(HostField::SsSelector, (ss & !7) as u64)?;
+        vmcs.write_host(HostField::DsSelector, (ds & !7) as u64)?;
+        vmcs.write_host(HostField::EsSelector, (es & !7) as u64)?;
+        vmcs.write_host(HostField::FsSelector, (fs & !7) as u64)?;
+        vmcs.write_host(HostField::GsSelector, (gs & !7) as u64)?;
+        vmcs.write_host(HostField::TrSelector, (tr & !7) as u64)?;
+        vmcs.write_host(HostField::FsBase, unsafe { Msr::new(IA32_FS_BASE).read() })?;
+        vmcs.write_host(HostField::GsBase, unsafe { Msr::new(IA32_GS_BASE).read() })?;
+        vmcs.write_host(HostField::TrBase, tss_base(gdt_base, tr))?;
+        vmcs.write_host(HostField::GdtrBase, gdt_base)?;
+        vmcs.write_host(HostField::IdtrBase, idt.base.as_u64())?;
+        vmcs.write_host(HostField::Ia32Efer, unsafe { Msr::new(IA32_EFER).read() })?;
+        Ok(())
+    }
+
+    fn write_segment(vmcs: &Vmcs, fields: SegmentFields, segment: &Segment) -> Result<(), HvError> {
+        vmcs.write_guest(fields.0, segment.selector as u64)?;
+        vmcs.write_guest(fields.1, segment.base)?;
+        vmcs.write_guest(fields.2, segment.limit as u64)?;
+        vmcs.write_guest(fields.3, segment.access_rights as u64)?;
+        Ok(())
+    }
+
+    fn read_segment(vmcs: &Vmcs, fields: SegmentFields) -> Result<Segment, HvError> {
+        Ok(Segment {
+            selector: vmcs.read_guest(fields.0)? as u16,
+            base: vmcs.read_guest(fields.1)?,
+            limit: vmcs.read_guest(fields.2)? as u32,
+            access_rights: vmcs.read_guest(fields.3)? as u32,
+        })
+    }
+
+    /// Finish the instruction behind the exit returned by the last `run`.
+    fn complete_pending(vcpu: &mut BackendVcpu) -> Result<(), HvError> {
+        let info = match vcpu.pending.take() {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        let response = core::mem::replace(&mut vcpu.response, ExitResponse::None);
+        let data = match response {
+            ExitResponse::Data(value) => Some(value),
+            _ => None,
+        };
+        if info.reason == Some(ExitReason::EptViolation) {
+            return Self::complete_mmio(vcpu, data);
+        }
+        let regs = &mut vcpu.vcpu.regs;
+        match info.reason {
+            Some(ExitReason::IoInstruction) => {
+                let io = info.io();
+                if io.is_in {
+                    let mask = (1u64 << (io.size as u32 * 8)) - 1;
+                    regs.rax = (regs.rax & !mask) | (data.unwrap_or(u64::MAX) & mask);
+                }
+            }
+            Some(ExitReason::Cpuid) => {
+                let (a, b, c, d) = match response {
+                    ExitResponse::Cpuid { eax, ebx, ecx, edx } => (eax, ebx, ecx, edx),
+                    _ => (0, 0, 0, 0),
+                };
+                regs.rax = a as u64;
+                regs.rbx = b as u64;
+                regs.rcx = c as u64;
+                regs.rdx = d as u64;
+            }
+            Some(ExitReason::Rdmsr) => {
+                let value = data.unwrap_or(0);
+                regs.rax = value & 0xFFFF_FFFF;
+                regs.rdx = value >> 32;
+            }
+            Some(ExitReason::Vmcall) => {
+                if let Some(value) = data {
+                    regs.rax = value;
+                }
+            }
+            Some(ExitReason::Wrmsr) | Some(ExitReason::Hlt) => {}
+            // Unhandled exits are retried as-is; the caller fixed up the guest or gave up.
+            _ => return Ok(()),
+        }
+        vcpu.vcpu.advance_rip(&info)?;
+        Ok(())
+    }
+
+    /// Write back the result of an MMIO load and step over the instruction.
+    fn complete_mmio(vcpu: &mut BackendVcpu, data: Option<u64>) -> Result<(), HvError> {
+        let (insn, mode) = match vcpu.mmio.take() {
+            Some(mmio) => mmio,
+            None => return Ok(()),
+        };
+        let vmcs = &vcpu.vcpu.vmcs;
+        let mut regs = vcpu.vcpu.regs.to_vcpu_registers(vmcs)?;
+        if let MmioOp::Load { reg, dest_size, extend } = insn.op {
+            let mut value = data.unwrap_or(u64::MAX) & operand_mask(insn.size);
+            if extend == Extend::Sign {
+                value = sign_extend(value, insn.size);
+            }
+            regs.write_operand(reg, dest_size, insn.rex, mode == CpuMode::Long, value);
+        }
+        regs.rip = insn.next_rip(regs.rip, mode);
+        vcpu.vcpu.regs.load_vcpu_registers(vmcs, &regs)?;
+        Ok(())
+    }
+
+    /// Whether completing the pending exit moves RIP past the exiting
+    /// instruction, ending any interrupt shadow.
+    fn pending_advances(vcpu: &BackendVcpu) -> bool {
+        match vcpu.pending.as_ref().and_then(|info| info.reason) {
+            Some(ExitReason::EptViolation) => vcpu.mmio.is_some(),
+            Some(ExitReason::IoInstruction)
+            | Some(ExitReason::Cpuid)
+            | Some(ExitReason::Rdmsr)
+            | Some(ExitReason::Wrmsr)
+            | Some(ExitReason::Vmcall)
+            | Some(ExitReason::Hlt) => true,
+            _ => false,
+        }
+    }
+
+    /// Re-queue an event whose delivery through the guest IDT was cut short
+    /// by the exit, such as an interrupt whose stack push hit an EPT violation.
+    fn reinject_vectored_event(vmcs: &Vmcs, info: &ExitInfo) -> Result<(), HvError> {
+        let vectoring = info.idt_vectoring_info as u64;
+        if vectoring & INTERRUPTION_VALID == 0 {
+            return Ok(());
+        }
+        if vectoring & INTERRUPTION_ERROR_CODE != 0 {
+            let code = vmcs.read_exit_info(ExitInfoField::IdtVectoringErrorCode)?;
+            vmcs.write_control(ControlField::VmEntryExceptionErrorCode, code)?;
+        }
+        // Software interrupts and exceptions need the instruction length again.
+        if matches!((vectoring >> 8) & 7, 4..=6) {
+            vmcs.write_control(ControlField::VmEntryInstructionLength, info.instruction_length)?;
+        }
+        // Bit 12 is undefined in the IDT-vectoring field and reserved on entry.
+        vmcs.write_control(ControlField::VmEntryInterruptionInfo, vectoring & !(1 << 12))?;
+        Ok(())
+    }
+
+    /// Decode the access behind an EPT violation on memory with no RAM
+    /// behind it. Only plain loads and stores are reported; read-modify-write
+    /// and string forms need an [`super::MmioHandler`] on the dispatcher.
+    fn translate_mmio(
+        info: &ExitInfo,
+        vcpu: &mut BackendVcpu,
+        layout: &GuestMemoryLayout,
+        phys_offset: u64,
+    ) -> Result<Option<VmExit>, HvError> {
+        let gpa = info.guest_physical_address;
+        if layout.gpa_to_hpa(gpa).is_some() {
+            return Ok(None);
+        }
+        let vmcs = &vcpu.vcpu.vmcs;
+        let mode = guest_cpu_mode(vmcs)?;
+        let mut bytes = [0u8; MAX_INSTRUCTION_LEN];
+        let len = fetch_guest_instruction(vmcs, layout, phys_offset, &mut bytes)?;
+        let insn = match decode(&bytes[..len], mode) {
+            Ok(insn) => insn,
+            Err(_) => return Ok(None),
+        };
+        let exit = match insn.op {
+            MmioOp::Load { .. } => VmExit::Mmio { gpa, size: insn.size, write: false, data: 0 },
+            MmioOp::Store { src: Source::Reg(reg) } => {
+                let data = vcpu.vcpu.regs.to_vcpu_registers(vmcs)?.read_operand(reg, insn.size, insn.rex);
+                VmExit::Mmio { gpa, size: insn.size, write: true, data }
+            }
+            MmioOp::Store { src: Source::Imm(imm) } => {
+                VmExit::Mmio { gpa, size: insn.size, write: true, data: imm & operand_mask(insn.size) }
+            }
+            _ => return Ok(None),
+        };
+        vcpu.mmio = Some((insn, mode));
+        Ok(Some(exit))
+    }
+
+    /// Translate an exit the dispatcher did not handle. External interrupts
+    /// are left unacknowledged, so the host takes them through its own IDT
+    /// once the exit has restored its RFLAGS; they surface as
+    /// [`VmExit::Interrupted`] so the caller sees kicks from other CPUs.
+    fn translate_exit(
+        info: &ExitInfo,
+        vcpu: &mut BackendVcpu,
+        layout: &GuestMemoryLayout,
+        phys_offset: u64,
+    ) -> Result<Option<VmExit>, HvError> {
+        let unhandled = VmExit::Unhandled { reason: info.raw_reason, qualification: info.qualification };
+        let regs = &vcpu.vcpu.regs;
+        let exit = match info.reason {
+            Some(ExitReason::ExternalInterrupt) => VmExit::Interrupted,
+            Some(ExitReason::EptViolation) => {
+                return Ok(Some(Self::translate_mmio(info, vcpu, layout, phys_offset)?.unwrap_or(unhandled)));
+            }
+            Some(ExitReason::IoInstruction) => {
+                let io = info.io();
+                if io.is_string {
+                    return Ok(Some(unhandled));
+                }
+                let (direction, data) = if io.is_in {
+                    (IoDirection::In, 0)
+                } else {
+                    (IoDirection::Out, (regs.rax & ((1u64 << (io.size as u32 * 8)) - 1)) as u32)
+                };
+                VmExit::Io { port: io.port, size: io.size, direction, data }
+            }
+            Some(ExitReason::Cpuid) => VmExit::Cpuid { leaf: regs.rax as u32, subleaf: regs.rcx as u32 },
+            Some(ExitReason::Rdmsr) => VmExit::MsrRead { msr: regs.rcx as u32 },
+            Some(ExitReason::Wrmsr) => VmExit::MsrWrite {
+                msr: regs.rcx as u32,
+                value: (regs.rdx << 32) | (regs.rax & 0xFFFF_FFFF),
+            },
+            Some(ExitReason::Vmcall) => VmExit::Hypercall,
+            Some(ExitReason::Hlt) => VmExit::Hlt,
+            Some(ExitReason::InterruptWindow) => VmExit::InterruptWindow,
+            Some(ExitReason::TripleFault) => VmExit::Shutdown,
+            _ => unhandled,
+        };
+        Ok(Some(exit))
+    }
+}
+
+/// The host's time-stamp counter.
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Base address of the TSS selected by `tr` in the GDT at `gdt_base`.
+fn tss_base(gdt_base: u64, tr: u16) -> u64 {
+    if tr & !7 == 0 {
+        return 0;
+    }
+    let desc = (gdt_base + (tr & !7) as u64) as *const u64;
+    let (low, high) = unsafe { (*desc, *desc.add(1)) };
+    ((low >> 16) & 0xFF_FFFF) | (((low >> 56) & 0xFF) << 24) | ((high & 0xFFFF_FFFF) << 32)
+}
+
+impl HypervisorBackend for VmxBackend {
+    fn create_vcpu(&mut self, id: VcpuId) -> Result<(), HvError> {
+        if self.vcpus.contains_key(&id) {
+            return Err(HvError::VcpuExists(id));
+        }
+        let vmcs = Vmcs::new(&mut self.allocator, self.phys_offset, self.caps.revision_id())?;
+        vmcs.load()?;
+        self.setup_controls(&vmcs)?;
+        self.setup_host_state(&vmcs)?;
+        let mut vcpu = BackendVcpu {
+            vcpu: Vcpu::new(id, vmcs),
+            pending: None,
+            mmio: None,
+            response: ExitResponse::None,
+            msr_area: None,
+            cpu: Cell::new(Some(percpu::current_cpu())),
+        };
+        Self::apply_cpu_model(self.cpu_model.as_ref(), &mut self.allocator, self.phys_offset, &mut vcpu)?;
+        self.vcpus.insert(id, vcpu);
+        self.set_special_registers(id, &SpecialRegisters::real_mode(0))?;
+        self.set_registers(id, &VcpuRegisters { rflags: 1 << 1, ..Default::default() })
+    }
+
+    fn get_registers(&self, id: VcpuId) -> Result<VcpuRegisters, HvError> {
+        let vcpu = &self.load(id)?.vcpu;
+        Ok(vcpu.regs.to_vcpu_registers(&vcpu.vmcs)?)
+    }
+
+    fn set_registers(&mut self, id: VcpuId, regs: &VcpuRegisters) -> Result<(), HvError> {
+        let vcpu = &mut self.load_mut(id)?.vcpu;
+        vcpu.regs.load_vcpu_registers(&vcpu.vmcs, regs)?;
+        Ok(())
+    }
+
+    fn get_special_registers(&self, id: VcpuId) -> Result<SpecialRegisters, HvError> {
+        let vmcs = &self.load(id)?.vcpu.vmcs;
+        Ok(SpecialRegisters {
+            cs: Self::read_segment(vmcs, CS)?,
+            ds: Self::read_segment(vmcs, DS)?,
+            es: Self::read_segment(vmcs, ES)?,
+            fs: Self::read_segment(vmcs, FS)?,
+            gs: Self::read_segment(vmcs, GS)?,
+            ss: Self::read_segment(vmcs, SS)?,
+            tr: Self::read_segment(vmcs, TR)?,
+            ldtr: Self::read_segment(vmcs, LDTR)?,
+            gdt: DescriptorTable {
+                base: vmcs.read_guest(GuestField::GdtrBase)?,
+                limit: vmcs.read_guest(GuestField::GdtrLimit)? as u16,
+            },
+            idt: DescriptorTable {
+                base: vmcs.read_guest(GuestField::IdtrBase)?,
+                limit: vmcs.read_guest(GuestField::IdtrLimit)? as u16,
+            },
+            cr0: vmcs.read_guest(GuestField::Cr0)?,
+            // CR2 is not part of the VMCS and is left to the guest.
+            cr2: 0,
+            cr3: vmcs.read_guest(GuestField::Cr3)?,
+            cr4: vmcs.read_control(ControlField::Cr4ReadShadow)?,
+            efer: vmcs.read_guest(GuestField::Ia32Efer)?,
+        })
+    }
+
+    fn set_special_registers(&mut self, id: VcpuId, sregs: &SpecialRegisters) -> Result<(), HvError> {
+        let caps = self.caps;
+        let vmcs = &self.load(id)?.vcpu.vmcs;
+        Self::write_segment(vmcs, CS, &sregs.cs)?;
+        Self::write_segment(vmcs, DS, &sregs.ds)?;
+        Self::write_segment(vmcs, ES, &sregs.es)?;
+        Self::write_segment(vmcs, FS, &sregs.fs)?;
+        Self::write_segment(vmcs, GS, &sregs.gs)?;
+        Self::write_segment(vmcs, SS, &sregs.ss)?;
+        Self::write_segment(vmcs, TR, &sregs.tr)?;
+        Self::write_segment(vmcs, LDTR, &sregs.ldtr)?;
+        vmcs.write_guest(GuestField::GdtrBase, sregs.gdt.base)?;
+        vmcs.write_guest(GuestField::GdtrLimit, sregs.gdt.limit as u64)?;
+        vmcs.write_guest(GuestField::IdtrBase, sregs.idt.base)?;
+        vmcs.write_guest(GuestField::IdtrLimit, sregs.idt.limit as u64)?;
+
+        // An unrestricted guest may run with paging or protection off.
+        let mut cr0 = caps.adjust_cr0(sregs.cr0);
+        if caps.supports_unrestricted_guest() {
+            cr0 = (cr0 & !(CR0_PE | CR0_PG)) | (sregs.cr0 & (CR0_PE | CR0_PG));
+        }
+        vmcs.write_guest(GuestField::Cr0, cr0)?;
+        vmcs.write_control(ControlField::Cr0ReadShadow, sregs.cr0)?;
+        vmcs.write_guest(GuestField::Cr3, sregs.cr3)?;
+        vmcs.write_guest(GuestField::Cr4, caps.adjust_cr4(sregs.cr4 | CR4_VMXE))?;
+        vmcs.write_control(ControlField::Cr4ReadShadow, sregs.cr4)?;
+        vmcs.write_guest(GuestField::Ia32Efer, sregs.efer)?;
+        if sregs.efer & EFER_LMA != 0 {
+            vmcs.set_control_bits(ControlField::VmEntryControls, entry::IA32E_MODE_GUEST as u64)?;
+        } else {
+            vmcs.clear_control_bits(ControlField::VmEntryControls, entry::IA32E_MODE_GUEST as u64)?;
+        }
+        Ok(())
+    }
+
+    fn map_memory(&mut self, region: MemoryRegion) -> Result<(), HvError> {
+        self.layout.add(region)?;
+        if let RegionKind::Ram { host_phys } = region.kind {
+            self.ept.map_range(
+                region.guest_phys,
+                host_phys,
+                region.size,
+                region.permissions,
+                MemoryType::WriteBack,
+                &mut self.allocator,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn memory_layout(&self) -> &GuestMemoryLayout {
+        &self.layout
+    }
+
+    fn read_guest(&self, gpa: u64, buf: &mut [u8]) -> Result<(), HvError> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let addr = gpa + i as u64;
+            let hpa = self.layout.gpa_to_hpa(addr).ok_or(HvError::BadGuestAddress(addr))?;
+            *byte = unsafe { *((hpa + self.phys_offset) as *const u8) };
+        }
+        Ok(())
+    }
+
+    fn write_guest(&mut self, gpa: u64, data: &[u8]) -> Result<(), HvError> {
+        for (i, byte) in data.iter().enumerate() {
+            let addr = gpa + i as u64;
+            let hpa = self.layout.gpa_to_hpa(addr).ok_or(HvError::BadGuestAddress(addr))?;
+            unsafe { *((hpa + self.phys_offset) as *mut u8) = *byte };
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, id: VcpuId) -> Result<VmExit, HvError> {
+        self.load(id)?;
+        let vcpu = self.vcpus.get_mut(&id).ok_or(HvError::NoSuchVcpu(id))?;
+        Self::complete_pending(vcpu)?;
+        loop {
+            let info = vcpu.vcpu.enter()?;
+            if info.is_entry_failure() {
+                return Ok(VmExit::Unhandled { reason: info.raw_reason, qualification: info.qualification });
+            }
+            Self::reinject_vectored_event(&vcpu.vcpu.vmcs, &info)?;
+            let action = {
+                let mut ctx = ExitContext { vcpu: id, vmcs: &vcpu.vcpu.vmcs, regs: &mut vcpu.vcpu.regs, info: &info };
+                self.dispatcher.dispatch(&mut ctx)?
+            };
+            match action {
+                ExitAction::Resume => continue,
+                ExitAction::AdvanceRip => {
+                    vcpu.vcpu.advance_rip(&info)?;
+                    continue;
+                }
+                ExitAction::Halt => {
+                    vcpu.vcpu.advance_rip(&info)?;
+                    return Ok(VmExit::Hlt);
+                }
+                ExitAction::Shutdown => return Ok(VmExit::Shutdown),
+                ExitAction::Unhandled => {}
+            }
+            if let Some(exit) = Self::translate_exit(&info, vcpu, &self.layout, self.phys_offset)? {
+                vcpu.pending = Some(info);
+                return Ok(exit);
+            }
+        }
+    }
+
+    fn complete(&mut self, id: VcpuId, response: ExitResponse) -> Result<(), HvError> {
+        self.vcpus.get_mut(&id).ok_or(HvError::NoSuchVcpu(id))?.response = response;
+        Ok(())
+    }
+
+    fn interrupt_ready(&self, id: VcpuId) -> Result<bool, HvError> {
+        let vcpu = self.load(id)?;
+        let vmcs = &vcpu.vcpu.vmcs;
+        let shadow = if Self::pending_advances(vcpu) { 0 } else { vmcs.read_guest(GuestField::InterruptibilityState)? & 3 };
+        let queued = vmcs.read_control(ControlField::VmEntryInterruptionInfo)? & INTERRUPTION_VALID != 0;
+        Ok(vmcs.read_guest(GuestField::Rflags)? & RFLAGS_IF != 0 && shadow == 0 && !queued)
+    }
+
+    fn inject_interrupt(&mut self, id: VcpuId, vector: u8) -> Result<(), HvError> {
+        let vmcs = &self.load(id)?.vcpu.vmcs;
+        // Type 0: external interrupt.
+        vmcs.write_control(ControlField::VmEntryInterruptionInfo, INTERRUPTION_VALID | vector as u64)?;
+        Ok(())
+    }
+
+    fn flush_tlb(&mut self, id: VcpuId) -> Result<(), HvError> {
+        self.load(id)?;
+        // Without VPIDs, INVEPT drops the combined mappings as well.
+        let ept = self.caps.ept_vpid;
+        if ept.invept_single_context() {
+            invept(InveptType::SingleContext, self.ept.eptp())?;
+        } else if ept.invept_all_context() {
+            invept(InveptType::Global, 0)?;
+        } else {
+            return Err(HvError::Backend("INVEPT is not supported"));
+        }
+        Ok(())
+    }
+
+    fn set_interrupt_window(&mut self, id: VcpuId, enabled: bool) -> Result<(), HvError> {
+        if enabled && !self.caps.proc_based.supports(proc_based::INTERRUPT_WINDOW_EXITING) {
+            return Err(HvError::Backend("interrupt-window exiting not supported"));
+        }
+        let vmcs = &self.load(id)?.vcpu.vmcs;
+        if enabled {
+            vmcs.set_control_bits(ControlField::PrimaryProcBasedControls, proc_based::INTERRUPT_WINDOW_EXITING as u64)?;
+        } else {
+            vmcs.clear_control_bits(ControlField::PrimaryProcBasedControls, proc_based::INTERRUPT_WINDOW_EXITING as u64)?;
+        }
+        Ok(())
+    }
+
+    fn save_state(&mut self, id: VcpuId) -> Result<VcpuState, HvError> {
+        Self::complete_pending(self.load_mut(id)?)?;
+        let regs = self.get_registers(id)?;
+        let sregs = self.get_special_registers(id)?;
+        let vcpu = self.load(id)?;
+        let vmcs = &vcpu.vcpu.vmcs;
+
+        let info = vmcs.read_control(ControlField::VmEntryInterruptionInfo)?;
+        let pending = if info & INTERRUPTION_VALID != 0 {
+            let kind = EventKind::from_raw(((info >> 8) & 7) as u8).ok_or(HvError::Backend("unknown event type"))?;
+            let error_code = match info & INTERRUPTION_ERROR_CODE {
+                0 => None,
+                _ => Some(vmcs.read_control(ControlField::VmEntryExceptionErrorCode)? as u32),
+            };
+            let instruction_length = vmcs.read_control(ControlField::VmEntryInstructionLength)? as u32;
+            Some(PendingEvent { kind, vector: info as u8, error_code, instruction_length })
+        } else {
+            None
+        };
+        let interruptibility = vmcs.read_guest(GuestField::InterruptibilityState)?;
+        let window = vmcs.read_control(ControlField::PrimaryProcBasedControls)?
+            & proc_based::INTERRUPT_WINDOW_EXITING as u64
+            != 0;
+
+        let mut msrs = Vec::new();
+        for (msr, field) in VMCS_MSRS {
+            msrs.push((msr, vmcs.read_guest(field)?));
+        }
+        if let (Some((model, _, _)), Some(area)) = (&self.cpu_model, &vcpu.msr_area) {
+            msrs.extend(model.switched_msrs().filter_map(|msr| Some((msr, area.guest_value(msr)?))));
+        }
+        if let Some((_, _, emulated)) = &self.cpu_model {
+            msrs.extend(emulated.values(id));
+        }
+        if Self::tsc_offsetting(vmcs)? {
+            let offset = vmcs.read_control(ControlField::TscOffset)?;
+            msrs.push((IA32_TSC, rdtsc().wrapping_add(offset)));
+        }
+        Ok(VcpuState {
+            regs,
+            sregs,
+            msrs,
+            events: VcpuEvents {
+                pending,
+                interrupt_shadow: (interruptibility & INTERRUPTIBILITY_SHADOW) as u8,
+                nmi_blocked: interruptibility & INTERRUPTIBILITY_NMI != 0,
+                interrupt_window: window,
+            },
+            lapic: None,
+        })
+    }
+
+    fn restore_state(&mut self, id: VcpuId, state: &VcpuState) -> Result<(), HvError> {
+        let vcpu = self.load_mut(id)?;
+        vcpu.pending = None;
+        vcpu.mmio = None;
+        vcpu.response = ExitResponse::None;
+        self.set_special_registers(id, &state.sregs)?;
+        self.set_registers(id, &state.regs)?;
+        self.set_interrupt_window(id, state.events.interrupt_window)?;
+        let vcpu = self.vcpus.get_mut(&id).ok_or(HvError::NoSuchVcpu(id))?;
+        let vmcs = &vcpu.vcpu.vmcs;
+
+        let events = &state.events;
+        let info = match events.pending {
+            Some(event) => {
+                if let Some(code) = event.error_code {
+                    vmcs.write_control(ControlField::VmEntryExceptionErrorCode, code as u64)?;
+                }
+                vmcs.write_control(ControlField::VmEntryInstructionLength, event.instruction_length as u64)?;
+                let error_code = if event.error_code.is_some() { INTERRUPTION_ERROR_CODE } else { 0 };
+                INTERRUPTION_VALID | error_code | ((event.kind as u64) << 8) | event.vector as u64
+            }
+            None => 0,
+        };
+        vmcs.write_control(ControlField::VmEntryInterruptionInfo, info)?;
+        let nmi = if events.nmi_blocked { INTERRUPTIBILITY_NMI } else { 0 };
+        vmcs.write_guest(
+            GuestField::InterruptibilityState,
+            (events.interrupt_shadow as u64 & INTERRUPTIBILITY_SHADOW) | nmi,
+        )?;
+
+        let emulated = self.cpu_model.as_ref().map(|(_, _, emulated)| emulated);
+        for &(msr, value) in &state.msrs {
+            if let Some(&(_, field)) = VMCS_MSRS.iter().find(|(index, _)| *index == msr) {
+                vmcs.write_guest(field, value)?;
+            } else if msr == IA32_TSC {
+                if !Self::tsc_offsetting(vmcs)? {
+                    return Err(HvError::Backend("TSC offsetting is not supported"));
+                }
+                vmcs.write_control(ControlField::TscOffset, value.wrapping_sub(rdtsc()))?;
+            } else if let Some(emulated) = emulated.filter(|emulated| emulated.value(id, msr).is_some()) {
+                if !emulated.write(id, msr, value) {
+                    return Err(HvError::Backend("MSR value is not valid for the CPU model"));
+                }
+            } else if !vcpu.msr_area.as_mut().is_some_and(|area| area.set_guest_value(msr, value)) {
+                return Err(HvError::Backend("MSR is not switched by the CPU model"));
+            }
+        }
+        Ok(())
+    }
+
+    fn enable_dirty_log(&mut self) -> Result<(), HvError> {
+        if !self.caps.ept_vpid.accessed_dirty() {
+            return Err(HvError::Backend("EPT accessed/dirty flags are not supported"));
+        }
+        self.ept.set_accessed_dirty(true);
+        let ids: Vec<VcpuId> = self.vcpus.keys().copied().collect();
+        for id in ids {
+            self.load(id)?.vcpu.vmcs.write_control(ControlField::EptPointer, self.ept.eptp())?;
+        }
+        Ok(())
+    }
+
+    fn take_dirty_pages(&mut self) -> Result<Vec<u64>, HvError> {
+        let mut pages = Vec::new();
+        for region in self.layout.regions() {
+            if let RegionKind::Ram { .. } = region.kind {
+                pages.extend(self.ept.take_dirty(region.guest_phys, region.size));
+            }
+        }
+        Ok(pages)
+    }
+}
@@ -0,0 +1,327 @@
+use x86_64::registers::model_specific::Msr;
+
+pub const IA32_FEATURE_CONTROL: u32 = 0x3A;
+pub const IA32_VMX_BASIC: u32 = 0x480;
+pub const IA32_VMX_PINBASED_CTLS: u32 = 0x481;
+pub const IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
+pub const IA32_VMX_EXIT_CTLS: u32 = 0x483;
+pub const IA32_VMX_ENTRY_CTLS: u32 = 0x484;
+pub const IA32_VMX_MISC: u32 = 0x485;
+pub const IA32_VMX_CR0_FIXED0: u32 = 0x486;
+pub const IA32_VMX_CR0_FIXED1: u32 = 0x487;
+pub const IA32_VMX_CR4_FIXED0: u32 = 0x488;
+pub const IA32_VMX_CR4_FIXED1: u32 = 0x489;
+pub const IA32_VMX_PROCBASED_CTLS2: u32 = 0x48B;
+pub const IA32_VMX_EPT_VPID_CAP: u32 = 0x48C;
+pub const IA32_VMX_TRUE_PINBASED_CTLS: u32 = 0x48D;
+pub const IA32_VMX_TRUE_PROCBASED_CTLS: u32 = 0x48E;
+pub const IA32_VMX_TRUE_EXIT_CTLS: u32 = 0x48F;
+pub const IA32_VMX_TRUE_ENTRY_CTLS: u32 = 0x490;
+
+pub const FEATURE_CONTROL_LOCKED: u64 = 1 << 0;
+pub const FEATURE_CONTROL_VMX_IN_SMX: u64 = 1 << 1;
+pub const FEATURE_CONTROL_VMX_OUTSIDE_SMX: u64 = 1 << 2;
+
+/// Pin-based VM-execution controls.
+pub mod pin_based {
+    pub const EXTERNAL_INTERRUPT_EXITING: u32 = 1 << 0;
+    pub const NMI_EXITING: u32 = 1 << 3;
+    pub const VIRTUAL_NMIS: u32 = 1 << 5;
+    pub const PREEMPTION_TIMER: u32 = 1 << 6;
+    pub const POSTED_INTERRUPTS: u32 = 1 << 7;
+}
+
+/// Primary processor-based VM-execution controls.
+pub mod proc_based {
+    pub const INTERRUPT_WINDOW_EXITING: u32 = 1 << 2;
+    pub const USE_TSC_OFFSETTING: u32 = 1 << 3;
+    pub const HLT_EXITING: u32 = 1 << 7;
+    pub const INVLPG_EXITING: u32 = 1 << 9;
+    pub const MWAIT_EXITING: u32 = 1 << 10;
+    pub const RDPMC_EXITING: u32 = 1 << 11;
+    pub const RDTSC_EXITING: u32 = 1 << 12;
+    pub const CR3_LOAD_EXITING: u32 = 1 << 15;
+    pub const CR3_STORE_EXITING: u32 = 1 << 16;
+    pub const CR8_LOAD_EXITING: u32 = 1 << 19;
+    pub const CR8_STORE_EXITING: u32 = 1 << 20;
+    pub const USE_TPR_SHADOW: u32 = 1 << 21;
+    pub const NMI_WINDOW_EXITING: u32 = 1 << 22;
+    pub const MOV_DR_EXITING: u32 = 1 << 23;
+    pub const UNCONDITIONAL_IO_EXITING: u32 = 1 << 24;
+    pub const USE_IO_BITMAPS: u32 = 1 << 25;
+    pub const MONITOR_TRAP_FLAG: u32 = 1 << 27;
+    pub const USE_MSR_BITMAPS: u32 = 1 << 28;
+    pub const MONITOR_EXITING: u32 = 1 << 29;
+    pub const PAUSE_EXITING: u32 = 1 << 30;
+    pub const ACTIVATE_SECONDARY_CONTROLS: u32 = 1 << 31;
+}
+
+/// Secondary processor-based VM-execution controls.
+pub mod proc_based2 {
+    pub const VIRTUALIZE_APIC_ACCESSES: u32 = 1 << 0;
+    pub const ENABLE_EPT: u32 = 1 << 1;
+    pub const DESCRIPTOR_TABLE_EXITING: u32 = 1 << 2;
+    pub const ENABLE_RDTSCP: u32 = 1 << 3;
+    pub const VIRTUALIZE_X2APIC_MODE: u32 = 1 << 4;
+    pub const ENABLE_VPID: u32 = 1 << 5;
+    pub const WBINVD_EXITING: u32 = 1 << 6;
+    pub const UNRESTRICTED_GUEST: u32 = 1 << 7;
+    pub const APIC_REGISTER_VIRTUALIZATION: u32 = 1 << 8;
+    pub const VIRTUAL_INTERRUPT_DELIVERY: u32 = 1 << 9;
+    pub const PAUSE_LOOP_EXITING: u32 = 1 << 10;
+    pub const RDRAND_EXITING: u32 = 1 << 11;
+    pub const ENABLE_INVPCID: u32 = 1 << 12;
+    pub const ENABLE_VMFUNC: u32 = 1 << 13;
+    pub const VMCS_SHADOWING: u32 = 1 << 14;
+    pub const RDSEED_EXITING: u32 = 1 << 16;
+    pub const ENABLE_PML: u32 = 1 << 17;
+    pub const EPT_VIOLATION_VE: u32 = 1 << 18;
+    pub const ENABLE_XSAVES: u32 = 1 << 20;
+}
+
+/// VM-exit controls.
+pub mod exit {
+    pub const SAVE_DEBUG_CONTROLS: u32 = 1 << 2;
+    pub const HOST_ADDRESS_SPACE_SIZE: u32 = 1 << 9;
+    pub const LOAD_PERF_GLOBAL_CTRL: u32 = 1 << 12;
+    pub const ACK_INTERRUPT_ON_EXIT: u32 = 1 << 15;
+    pub const SAVE_PAT: u32 = 1 << 18;
+    pub const LOAD_PAT: u32 = 1 << 19;
+    pub const SAVE_EFER: u32 = 1 << 20;
+    pub const LOAD_EFER: u32 = 1 << 21;
+    pub const SAVE_PREEMPTION_TIMER: u32 = 1 << 22;
+}
+
+/// VM-entry controls.
+pub mod entry {
+    pub const LOAD_DEBUG_CONTROLS: u32 = 1 << 2;
+    pub const IA32E_MODE_GUEST: u32 = 1 << 9;
+    pub const ENTRY_TO_SMM: u32 = 1 << 10;
+    pub const LOAD_PERF_GLOBAL_CTRL: u32 = 1 << 13;
+    pub const LOAD_PAT: u32 = 1 << 14;
+    pub const LOAD_EFER: u32 = 1 << 15;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityError {
+    /// The desired control bits contain settings the CPU does not allow.
+    UnsupportedControls { requested: u32, unsupported: u32 },
+    VmxUnsupported,
+    /// IA32_FEATURE_CONTROL is locked with VMX disabled (firmware setting).
+    VmxDisabledByFirmware,
+}
+
+/// Allowed settings of a VMX control field, as reported by its capability
+/// MSR: bits set in `allowed0` must be 1, bits clear in `allowed1` must be 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlCapability {
+    pub allowed0: u32,
+    pub allowed1: u32,
+}
+
+impl ControlCapability {
+    pub fn from_msr(value: u64) -> Self {
+        ControlCapability { allowed0: value as u32, allowed1: (value >> 32) as u32 }
+    }
+
+    /// Force the mandatory bits on and the unsupported bits off.
+    pub fn adjust(&self, desired: u32) -> u32 {
+        (desired | self.allowed0) & self.allowed1
+    }
+
+    /// Like [`adjust`](Self::adjust), but fail if any desired bit cannot be set.
+    pub fn require(&self, desired: u32) -> Result<u32, CapabilityError> {
+        let unsupported = desired & !self.allowed1;
+        if unsupported != 0 {
+            return Err(CapabilityError::UnsupportedControls { requested: desired, unsupported });
+        }
+        Ok(self.adjust(desired))
+    }
+
+    pub fn supports(&self, bits: u32) -> bool {
+        bits & !self.allowed1 == 0
+    }
+}
+
+/// IA32_VMX_EPT_VPID_CAP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptVpidCapabilities(pub u64);
+
+impl EptVpidCapabilities {
+    pub fn execute_only(&self) -> bool { self.0 & (1 << 0) != 0 }
+    pub fn page_walk_4(&self) -> bool { self.0 & (1 << 6) != 0 }
+    pub fn memory_type_uc(&self) -> bool { self.0 & (1 << 8) != 0 }
+    pub fn memory_type_wb(&self) -> bool { self.0 & (1 << 14) != 0 }
+    pub fn pages_2m(&self) -> bool { self.0 & (1 << 16) != 0 }
+    pub fn pages_1g(&self) -> bool { self.0 & (1 << 17) != 0 }
+    pub fn invept(&self) -> bool { self.0 & (1 << 20) != 0 }
+    pub fn accessed_dirty(&self) -> bool { self.0 & (1 << 21) != 0 }
+    pub fn invept_single_context(&self) -> bool { self.0 & (1 << 25) != 0 }
+    pub fn invept_all_context(&self) -> bool { self.0 & (1 << 26) != 0 }
+    pub fn invvpid(&self) -> bool { self.0 & (1 << 32) != 0 }
+    pub fn invvpid_single_context(&self) -> bool { self.0 & (1 << 41) != 0 }
+    pub fn invvpid_all_context(&self) -> bool { self.0 & (1 << 42) != 0 }
+}
+
+/// Everything the IA32_VMX_* MSRs report about this processor's VMX support.
+#[derive(Debug, Clone, Copy)]
+pub struct VmxCapabilities {
+    pub basic: u64,
+    pub pin_based: ControlCapability,
+    pub proc_based: ControlCapability,
+    pub proc_based2: ControlCapability,
+    pub exit: ControlCapability,
+    pub entry: ControlCapability,
+    pub misc: u64,
+    pub cr0_fixed0: u64,
+    pub cr0_fixed1: u64,
+    pub cr4_fixed0: u64,
+    pub cr4_fixed1: u64,
+    pub ept_vpid: EptVpidCapabilities,
+    pub feature_control: u64,
+}
+
+impl VmxCapabilities {
+    /// Read the capability MSRs of the current processor.
+    ///
+    /// Must only be called when CPUID reports VMX, otherwise the RDMSRs fault.
+    pub fn read() -> Self {
+        Self::from_msrs(|msr| unsafe { Msr::new(msr).read() })
+    }
+
+    /// Build the capabilities from an MSR reader. The TRUE_* control MSRs are
+    /// used when IA32_VMX_BASIC[55] says they exist, and the secondary
+    /// controls and EPT/VPID MSRs only when the CPU can activate them.
+    pub fn from_msrs<F: Fn(u32) -> u64>(read_msr: F) -> Self {
+        let basic = read_msr(IA32_VMX_BASIC);
+        let true_controls = basic & (1 << 55) != 0;
+        let (pin, proc, exit, entry) = if true_controls {
+            (IA32_VMX_TRUE_PINBASED_CTLS, IA32_VMX_TRUE_PROCBASED_CTLS, IA32_VMX_TRUE_EXIT_CTLS, IA32_VMX_TRUE_ENTRY_CTLS)
+        } else {
+            (IA32_VMX_PINBASED_CTLS, IA32_VMX_PROCBASED_CTLS, IA32_VMX_EXIT_CTLS, IA32_VMX_ENTRY_CTLS)
+        };
+        let proc_based = ControlCapability::from_msr(read_msr(proc));
+        let has_secondary = proc_based.supports(proc_based::ACTIVATE_SECONDARY_CONTROLS);
+        let proc_based2 = if has_secondary {
+            ControlCapability::from_msr(read_msr(IA32_VMX_PROCBASED_CTLS2))
+        } else {
+            ControlCapability { allowed0: 0, allowed1: 0 }
+        };
+        let has_ept_or_vpid = proc_based2.supports(proc_based2::ENABLE_EPT) || proc_based2.supports(proc_based2::ENABLE_VPID);
+        let ept_vpid = if has_secondary && has_ept_or_vpid {
+            EptVpidCapabilities(read_msr(IA32_VMX_EPT_VPID_CAP))
+        } else {
+            EptVpidCapabilities(0)
+        };
+        VmxCapabilities {
+            basic,
+            pin_based: ControlCapability::from_msr(read_msr(pin)),
+            proc_based,
+            proc_based2,
+            exit: ControlCapability::from_msr(read_msr(exit)),
+            entry: ControlCapability::from_msr(read_msr(entry)),
+            misc: read_msr(IA32_VMX_MISC),
+            cr0_fixed0: read_msr(IA32_VMX_CR0_FIXED0),
+            cr0_fixed1: read_msr(IA32_VMX_CR0_FIXED1),
+            cr4_fixed0: read_msr(IA32_VMX_CR4_FIXED0),
+            cr4_fixed1: read_msr(IA32_VMX_CR4_FIXED1),
+            ept_vpid,
+            feature_control: read_msr(IA32_FEATURE_CONTROL),
+        }
+    }
+
+    /// VMCS revision identifier, written to the VMXON region and every VMCS.
+    pub fn revision_id(&self) -> u32 {
+        (self.basic & 0x7FFF_FFFF) as u32
+    }
+
+    /// Bytes to allocate for the VMXON region and VMCS.
+    pub fn vmcs_size(&self) -> usize {
+        ((self.basic >> 32) & 0x1FFF) as usize
+    }
+
+    pub fn true_controls(&self) -> bool {
+        self.basic & (1 << 55) != 0
+    }
+
+    /// Whether VM exits on INS/OUTS report instruction information.
+    pub fn ins_outs_info(&self) -> bool {
+        self.basic & (1 << 54) != 0
+    }
+
+    pub fn has_secondary_controls(&self) -> bool {
+        self.proc_based.supports(proc_based::ACTIVATE_SECONDARY_CONTROLS)
+    }
+
+    pub fn supports_ept(&self) -> bool {
+        self.has_secondary_controls() && self.proc_based2.supports(proc_based2::ENABLE_EPT)
+    }
+
+    pub fn supports_vpid(&self) -> bool {
+        self.has_secondary_controls() && self.proc_based2.supports(proc_based2::ENABLE_VPID)
+    }
+
+    pub fn supports_unrestricted_guest(&self) -> bool {
+        self.has_secondary_controls() && self.proc_based2.supports(proc_based2::UNRESTRICTED_GUEST)
+    }
+
+    pub fn feature_control_locked(&self) -> bool {
+        self.feature_control & FEATURE_CONTROL_LOCKED != 0
+    }
+
+    /// VMX may be turned on outside SMX: either firmware enabled it, or the
+    /// MSR is still unlocked and we can enable it ourselves.
+    pub fn vmx_allowed_by_firmware(&self) -> bool {
+        !self.feature_control_locked() || self.feature_control & FEATURE_CONTROL_VMX_OUTSIDE_SMX != 0
+    }
+
+    /// Apply the IA32_VMX_CR0_FIXED0/1 constraints to a CR0 value.
+    pub fn adjust_cr0(&self, cr0: u64) -> u64 {
+        (cr0 | self.cr0_fixed0) & self.cr0_fixed1
+    }
+
+    /// Apply the IA32_VMX_CR4_FIXED0/1 constraints to a CR4 value.
+    pub fn adjust_cr4(&self, cr4: u64) -> u64 {
+        (cr4 | self.cr4_fixed0) & self.cr4_fixed1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adjust_controls() {
+        let cap = ControlCapability::from_msr(0x0000_00ff_0000_0016);
+        assert_eq!(cap.allowed0, 0x16);
+        assert_eq!(cap.allowed1, 0xff);
+        assert_eq!(cap.adjust(0x101), 0x17);
+        assert!(cap.supports(0x80));
+        assert!(!cap.supports(0x100));
+        assert_eq!(
+            cap.require(0x301),
+            Err(CapabilityError::UnsupportedControls { requested: 0x301, unsupported: 0x300 })
+        );
+        assert_eq!(cap.require(0x81), Ok(0x97));
+    }
+
+    #[test]
+    fn test_true_controls_selected_from_basic() {
+        let caps = VmxCapabilities::from_msrs(|msr| match msr {
+            IA32_VMX_BASIC => (1 << 55) | (0x1000 << 32) | 0x12,
+            IA32_VMX_PINBASED_CTLS => 0xffff_ffff_0000_0016,
+            IA32_VMX_TRUE_PINBASED_CTLS => 0xffff_ffff_0000_0000,
+            IA32_VMX_TRUE_PROCBASED_CTLS => 0x8000_0000_0000_0000,
+            IA32_VMX_PROCBASED_CTLS2 => 0x0000_0002_0000_0000,
+            IA32_VMX_EPT_VPID_CAP => 1 << 17,
+            _ => 0,
+        });
+        assert_eq!(caps.revision_id(), 0x12);
+        assert_eq!(caps.vmcs_size(), 0x1000);
+        assert!(caps.true_controls());
+        assert_eq!(caps.pin_based.allowed0, 0);
+        assert!(caps.supports_ept());
+        assert!(!caps.supports_vpid());
+        assert!(caps.ept_vpid.pages_1g());
+    }
+}
@@ -0,0 +1,614 @@
+// Guest CPU model: the CPUID leaves and MSRs a VM sees.
+//
+// The model is fixed when the VM is configured rather than passed through
+// from the host, so a guest keeps the same CPU identity on every host it
+// runs on or migrates to. `restrict_to` trims it to what a set of hosts
+// can actually provide.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid_count;
+
+use spin::Mutex;
+
+use super::exit::{inject_exception, ExitAction, ExitContext, ExitDispatcher, ExitHandler, ExitReason};
+use super::vmcs::{ControlField, Vmcs, VmxError, VmxRegion};
+use crate::memory::SimpleFrameAllocator;
+use crate::vdev::irqchip::IA32_APIC_BASE;
+
+pub const HYPERVISOR_LEAF: u32 = 0x4000_0000;
+/// Hypervisor signature in EBX, ECX and EDX of leaf 0x4000_0000.
+pub const HYPERVISOR_SIGNATURE: &[u8; 12] = b"Hypercore\0\0\0";
+const VENDOR: &[u8; 12] = b"GenuineIntel";
+const BRAND: &[u8] = b"Hypercore Virtual CPU";
+
+const MAX_BASIC_LEAF: u32 = 0xB;
+const MAX_HYPERVISOR_LEAF: u32 = 0x4000_0001;
+const MAX_EXTENDED_LEAF: u32 = 0x8000_0008;
+
+// Leaf 1 feature bits.
+const ECX_VMX: u32 = 1 << 5;
+const ECX_HYPERVISOR: u32 = 1 << 31;
+const EDX_HTT: u32 = 1 << 28;
+
+pub const IA32_TSC: u32 = 0x10;
+pub const IA32_PLATFORM_ID: u32 = 0x17;
+pub const IA32_FEATURE_CONTROL: u32 = 0x3A;
+pub const IA32_BIOS_SIGN_ID: u32 = 0x8B;
+pub const IA32_MTRRCAP: u32 = 0xFE;
+pub const IA32_SYSENTER_CS: u32 = 0x174;
+pub const IA32_SYSENTER_ESP: u32 = 0x175;
+pub const IA32_SYSENTER_EIP: u32 = 0x176;
+pub const IA32_MISC_ENABLE: u32 = 0x1A0;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_MTRR_DEF_TYPE: u32 = 0x2FF;
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_STAR: u32 = 0xC000_0081;
+pub const IA32_LSTAR: u32 = 0xC000_0082;
+pub const IA32_CSTAR: u32 = 0xC000_0083;
+pub const IA32_FMASK: u32 = 0xC000_0084;
+pub const IA32_FS_BASE: u32 = 0xC000_0100;
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuidEntry {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+impl CpuidEntry {
+    pub const fn new(eax: u32, ebx: u32, ecx: u32, edx: u32) -> Self {
+        CpuidEntry { eax, ebx, ecx, edx }
+    }
+
+    /// EBX, ECX and EDX holding a 12-byte signature, in that order.
+    fn signature(eax: u32, signature: &[u8; 12]) -> Self {
+        let word = |i: usize| u32::from_le_bytes([signature[i], signature[i + 1], signature[i + 2], signature[i + 3]]);
+        CpuidEntry { eax, ebx: word(0), ecx: word(4), edx: word(8) }
+    }
+}
+
+/// CPUID as executed on the host.
+pub fn host_cpuid(leaf: u32, subleaf: u32) -> CpuidEntry {
+    let result = __cpuid_count(leaf, subleaf);
+    CpuidEntry::new(result.eax, result.ebx, result.ecx, result.edx)
+}
+
+/// How guest accesses to one MSR are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrAccess {
+    /// Reads and writes go to hardware; the MSR is part of the VMCS guest
+    /// state, so the guest value never leaks into the host.
+    Passthrough,
+    /// Reads and writes go to hardware, with the guest and host values
+    /// swapped on every entry and exit.
+    Switched,
+    /// Reads go to hardware; writes are dropped.
+    ReadOnly,
+    /// Owned by a device model, such as the APIC base by the irqchip; the
+    /// exit is left for the caller.
+    Forward,
+    /// Emulated per vCPU. Writes may only change the `writable` bits; any
+    /// other change raises #GP like it would on hardware.
+    Emulated { reset: u64, writable: u64 },
+}
+
+/// Whether `leaf` uses ECX as a subleaf index.
+fn is_indexed(leaf: u32) -> bool {
+    matches!(leaf, 4 | 7 | 0xB)
+}
+
+/// The CPU a VM's vCPUs report. vCPU `n` has APIC ID `n`, as in
+/// [`crate::vdev::IrqChip`]; all vCPUs are cores of a single package.
+pub struct CpuModel {
+    vcpus: u32,
+    leaves: BTreeMap<(u32, u32), CpuidEntry>,
+    msrs: BTreeMap<u32, MsrAccess>,
+}
+
+impl CpuModel {
+    /// The baseline model for a VM with `vcpus` vCPUs: a 64-bit Intel CPU
+    /// with SSE4.2, no VMX, no XSAVE and no x2APIC.
+    pub fn new(vcpus: u32) -> Self {
+        let vcpus = vcpus.max(1);
+        let mut leaves = BTreeMap::new();
+        // The vendor string runs EBX, EDX, ECX.
+        let vendor = CpuidEntry::signature(MAX_BASIC_LEAF, VENDOR);
+        leaves.insert((0, 0), CpuidEntry { ecx: vendor.edx, edx: vendor.ecx, ..vendor });
+        // Family 6, model 0x3A, stepping 9. EBX: CLFLUSH line size 64.
+        leaves.insert((1, 0), CpuidEntry::new(0x0003_06A9, 8 << 8, 0x0098_2201 | ECX_HYPERVISOR, 0x078B_BB7F));
+        // Deterministic cache parameters: L1d, L1i, L2, L3, then no more.
+        leaves.insert((4, 0), CpuidEntry::new(0x0000_0121, 0x01C0_003F, 63, 0));
+        leaves.insert((4, 1), CpuidEntry::new(0x0000_0122, 0x01C0_003F, 63, 0));
+        leaves.insert((4, 2), CpuidEntry::new(0x0000_0143, 0x01C0_003F, 511, 0));
+        leaves.insert((4, 3), CpuidEntry::new(0x0000_0163, 0x03C0_003F, 8191, 6));
+        leaves.insert((4, 4), CpuidEntry::default());
+        // FSGSBASE, BMI1, SMEP, BMI2, ERMS.
+        leaves.insert((7, 0), CpuidEntry::new(0, 0x0000_0389, 0, 0));
+        leaves.insert((HYPERVISOR_LEAF, 0), CpuidEntry::signature(MAX_HYPERVISOR_LEAF, HYPERVISOR_SIGNATURE));
+        leaves.insert((HYPERVISOR_LEAF + 1, 0), CpuidEntry::default());
+        leaves.insert((0x8000_0000, 0), CpuidEntry::new(MAX_EXTENDED_LEAF, 0, 0, 0));
+        // LAHF/SAHF, LZCNT, PREFETCHW; SYSCALL, NX, long mode.
+        leaves.insert((0x8000_0001, 0), CpuidEntry::new(0, 0, 0x0000_0121, 0x2010_0800));
+        let mut brand = [0u8; 48];
+        brand[..BRAND.len()].copy_from_slice(BRAND);
+        for (i, chunk) in brand.chunks(16).enumerate() {
+            let word = |j: usize| u32::from_le_bytes([chunk[j], chunk[j + 1], chunk[j + 2], chunk[j + 3]]);
+            leaves.insert((0x8000_0002 + i as u32, 0), CpuidEntry::new(word(0), word(4), word(8), word(12)));
+        }
+        // 256 KiB 8-way L2 with 64-byte lines.
+        leaves.insert((0x8000_0006, 0), CpuidEntry::new(0, 0, 0x0100_6040, 0));
+        // 39-bit physical and 48-bit linear addresses.
+        leaves.insert((0x8000_0008, 0), CpuidEntry::new(0x3027, 0, 0, 0));
+
+        let mut msrs = BTreeMap::new();
+        msrs.insert(IA32_TSC, MsrAccess::ReadOnly);
+        msrs.insert(IA32_PLATFORM_ID, MsrAccess::Emulated { reset: 0, writable: 0 });
+        msrs.insert(IA32_APIC_BASE, MsrAccess::Forward);
+        // Locked with VMX disabled, so guests do not try to enable it.
+        msrs.insert(IA32_FEATURE_CONTROL, MsrAccess::Emulated { reset: 1, writable: 0 });
+        msrs.insert(IA32_BIOS_SIGN_ID, MsrAccess::Emulated { reset: 0, writable: u64::MAX });
+        // No fixed or variable ranges, write-combining supported.
+        msrs.insert(IA32_MTRRCAP, MsrAccess::Emulated { reset: 0x400, writable: 0 });
+        msrs.insert(IA32_MTRR_DEF_TYPE, MsrAccess::Emulated { reset: 0x806, writable: 0xCFF });
+        msrs.insert(IA32_PAT, MsrAccess::Emulated { reset: 0x0007_0406_0007_0406, writable: u64::MAX });
+        // Fast strings on, BTS and PEBS unavailable.
+        msrs.insert(IA32_MISC_ENABLE, MsrAccess::Emulated { reset: 0x1801, writable: 0x0040_0001 });
+        for msr in [IA32_SYSENTER_CS, IA32_SYSENTER_ESP, IA32_SYSENTER_EIP, IA32_EFER, IA32_FS_BASE, IA32_GS_BASE] {
+            msrs.insert(msr, MsrAccess::Passthrough);
+        }
+        for msr in [IA32_STAR, IA32_LSTAR, IA32_CSTAR, IA32_FMASK, IA32_KERNEL_GS_BASE] {
+            msrs.insert(msr, MsrAccess::Switched);
+        }
+
+        CpuModel { vcpus, leaves, msrs }
+    }
+
+    pub fn vcpus(&self) -> u32 {
+        self.vcpus
+    }
+
+    /// Replace or add a CPUID leaf. `subleaf` is ignored for leaves that do
+    /// not take one.
+    pub fn set_leaf(&mut self, leaf: u32, subleaf: u32, entry: CpuidEntry) {
+        let subleaf = if is_indexed(leaf) { subleaf } else { 0 };
+        self.leaves.insert((leaf, subleaf), entry);
+    }
+
+    pub fn set_msr(&mut self, msr: u32, access: MsrAccess) {
+        self.msrs.insert(msr, access);
+    }
+
+    /// How accesses to `msr` are handled. `None` means the MSR does not
+    /// exist on this model and accesses raise #GP.
+    pub fn msr(&self, msr: u32) -> Option<MsrAccess> {
+        self.msrs.get(&msr).copied()
+    }
+
+    /// MSRs swapped between guest and host values on entry and exit.
+    pub fn switched_msrs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.msrs.iter().filter(|(_, access)| **access == MsrAccess::Switched).map(|(msr, _)| *msr)
+    }
+
+    /// MSRs whose values live in a [`ModelMsrHandler`] rather than the CPU.
+    pub fn emulated_msrs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.msrs.iter().filter(|(_, access)| matches!(access, MsrAccess::Emulated { .. })).map(|(msr, _)| *msr)
+    }
+
+    /// Bits of the x2APIC ID taken by the core number.
+    fn core_shift(&self) -> u32 {
+        32 - (self.vcpus - 1).leading_zeros()
+    }
+
+    /// CPUID as seen by `vcpu`. Leaves the model does not define read as
+    /// zero.
+    pub fn cpuid(&self, vcpu: u32, leaf: u32, subleaf: u32) -> CpuidEntry {
+        let subleaf = if is_indexed(leaf) { subleaf } else { 0 };
+        let mut entry = self.leaves.get(&(leaf, subleaf)).copied().unwrap_or_default();
+        let shift = self.core_shift();
+        match leaf {
+            1 => {
+                entry.ebx = (entry.ebx & 0xFFFF) | ((1u32 << shift).min(0xFF) << 16) | (vcpu << 24);
+                entry.ecx &= !ECX_VMX;
+                if self.vcpus > 1 {
+                    entry.edx |= EDX_HTT;
+                } else {
+                    entry.edx &= !EDX_HTT;
+                }
+            }
+            4 if entry.eax & 0x1F != 0 => {
+                // Cores per package; the L3 is shared by all of them.
+                entry.eax = (entry.eax & 0x3FFF) | (((1 << shift) - 1) << 26);
+                if (entry.eax >> 5) & 7 == 3 {
+                    entry.eax |= ((1 << shift) - 1) << 14;
+                }
+            }
+            0xB => {
+                entry = match subleaf {
+                    // SMT level: one thread per core.
+                    0 => CpuidEntry::new(0, 1, 1 << 8, 0),
+                    1 => CpuidEntry::new(shift, self.vcpus, (2 << 8) | 1, 0),
+                    _ => CpuidEntry::new(0, 0, subleaf & 0xFF, 0),
+                };
+                entry.edx = vcpu;
+            }
+            _ => {}
+        }
+        entry
+    }
+
+    /// Drop the features `host` lacks and cap the physical address width at
+    /// the host's. For a VM that may migrate, apply this once per candidate
+    /// host so the model is their common subset.
+    pub fn restrict_to(&mut self, host: impl Fn(u32, u32) -> CpuidEntry) {
+        let mut mask = |leaf: u32, ecx_keep: u32, edx_keep: u32| {
+            let host = host(leaf, 0);
+            if let Some(entry) = self.leaves.get_mut(&(leaf, 0)) {
+                if leaf == 7 {
+                    entry.ebx &= host.ebx;
+                }
+                entry.ecx &= host.ecx | ecx_keep;
+                entry.edx &= host.edx | edx_keep;
+            }
+        };
+        // The hypervisor and HTT bits describe the VM, not the host.
+        mask(1, ECX_HYPERVISOR, EDX_HTT);
+        mask(7, 0, 0);
+        mask(0x8000_0001, 0, 0);
+        let host_bits = host(0x8000_0008, 0).eax & 0xFF;
+        if let Some(entry) = self.leaves.get_mut(&(0x8000_0008, 0)) {
+            if host_bits != 0 && host_bits < entry.eax & 0xFF {
+                entry.eax = (entry.eax & !0xFF) | host_bits;
+            }
+        }
+    }
+
+    /// The VMX MSR bitmap for this model, in a frame from `frames`: every
+    /// MSR exits except the passthrough ones.
+    pub fn msr_bitmap(&self, frames: &mut SimpleFrameAllocator, phys_offset: u64) -> Result<MsrBitmap, VmxError> {
+        let mut bitmap = MsrBitmap::new(frames, phys_offset)?;
+        for (&msr, access) in &self.msrs {
+            match access {
+                MsrAccess::Passthrough | MsrAccess::Switched => bitmap.set_intercept(msr, false, false),
+                MsrAccess::ReadOnly => bitmap.set_intercept(msr, false, true),
+                MsrAccess::Forward | MsrAccess::Emulated { .. } => {}
+            }
+        }
+        Ok(bitmap)
+    }
+
+    /// Register CPUID and RDMSR/WRMSR handlers implementing this model.
+    /// Returns the MSR handler, which shares its emulated values with the
+    /// registered ones.
+    pub fn install(self: &Arc<Self>, dispatcher: &mut ExitDispatcher) -> ModelMsrHandler {
+        let msrs = ModelMsrHandler::new(self.clone());
+        dispatcher.register(ExitReason::Cpuid, Box::new(ModelCpuidHandler { model: self.clone() }));
+        dispatcher.register(ExitReason::Rdmsr, Box::new(msrs.clone()));
+        dispatcher.register(ExitReason::Wrmsr, Box::new(msrs.clone()));
+        msrs
+    }
+}
+
+/// The VMX MSR bitmap (Intel SDM Vol. 3C, 24.6.9): one read and one write
+/// bit for each MSR in 0..0x2000 and 0xC000_0000..0xC000_2000. A set bit
+/// makes the access exit. MSRs outside both ranges always exit.
+pub struct MsrBitmap {
+    region: VmxRegion,
+}
+
+impl MsrBitmap {
+    /// A bitmap intercepting every access, in a frame from `frames`.
+    pub fn new(frames: &mut SimpleFrameAllocator, phys_offset: u64) -> Result<Self, VmxError> {
+        let region = VmxRegion::new(frames, phys_offset)?;
+        unsafe { core::ptr::write_bytes(region.as_ptr(), 0xFF, 4096) };
+        Ok(MsrBitmap { region })
+    }
+
+    /// Bit index of `msr` in the read half; the write half follows 16 Ki
+    /// bits later.
+    fn bit(msr: u32) -> Option<usize> {
+        match msr {
+            0..=0x1FFF => Some(msr as usize),
+            0xC000_0000..=0xC000_1FFF => Some(0x2000 + (msr - 0xC000_0000) as usize),
+            _ => None,
+        }
+    }
+
+    fn get(&self, bit: usize) -> bool {
+        unsafe { *self.region.as_ptr().add(bit / 8) & (1 << (bit % 8)) != 0 }
+    }
+
+    fn set(&mut self, bit: usize, value: bool) {
+        let byte = unsafe { &mut *self.region.as_ptr().add(bit / 8) };
+        if value {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+    }
+
+    /// Choose whether reads and writes of `msr` exit. Has no effect on MSRs
+    /// the bitmap does not cover.
+    pub fn set_intercept(&mut self, msr: u32, read: bool, write: bool) {
+        if let Some(bit) = Self::bit(msr) {
+            self.set(bit, read);
+            self.set(bit + 0x4000, write);
+        }
+    }
+
+    pub fn intercepts_read(&self, msr: u32) -> bool {
+        Self::bit(msr).is_none_or(|bit| self.get(bit))
+    }
+
+    pub fn intercepts_write(&self, msr: u32) -> bool {
+        Self::bit(msr).is_none_or(|bit| self.get(bit + 0x4000))
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.region.phys_addr()
+    }
+}
+
+/// Per-vCPU VM-entry MSR-load and VM-exit MSR-store/load areas for
+/// [`MsrAccess::Switched`] MSRs. The guest area is loaded on entry and
+/// refreshed on exit, after which the host values are reloaded.
+pub struct MsrSwitchArea {
+    guest: VmxRegion,
+    host: VmxRegion,
+    count: usize,
+}
+
+impl MsrSwitchArea {
+    /// At most 256 entries fit the one-page areas.
+    const MAX_ENTRIES: usize = 4096 / 16;
+
+    /// Areas for `msrs` in frames from `frames`, with guest values starting
+    /// at zero and the host values taken from `host`.
+    pub fn new(
+        frames: &mut SimpleFrameAllocator,
+        phys_offset: u64,
+        msrs: &[u32],
+        host: impl Fn(u32) -> u64,
+    ) -> Result<Self, VmxError> {
+        let guest = VmxRegion::new(frames, phys_offset)?;
+        let host_area = VmxRegion::new(frames, phys_offset)?;
+        let area = MsrSwitchArea { guest, host: host_area, count: msrs.len().min(Self::MAX_ENTRIES) };
+        for (i, &msr) in msrs.iter().take(area.count).enumerate() {
+            unsafe {
+                let guest = area.guest.as_ptr().add(i * 16);
+                let host_entry = area.host.as_ptr().add(i * 16);
+                *(guest as *mut u32) = msr;
+                *(host_entry as *mut u32) = msr;
+                *(host_entry.add(8) as *mut u64) = host(msr);
+            }
+        }
+        Ok(area)
+    }
+
+    fn entry(&self, msr: u32) -> Option<*mut u64> {
+        (0..self.count)
+            .map(|i| unsafe { self.guest.as_ptr().add(i * 16) })
+            .find(|&entry| unsafe { *(entry as *const u32) } == msr)
+            .map(|entry| unsafe { entry.add(8) as *mut u64 })
+    }
+
+    /// The guest's value of `msr` as of the last exit.
+    pub fn guest_value(&self, msr: u32) -> Option<u64> {
+        self.entry(msr).map(|value| unsafe { *value })
+    }
+
+    pub fn set_guest_value(&mut self, msr: u32, value: u64) -> bool {
+        match self.entry(msr) {
+            Some(entry) => {
+                unsafe { *entry = value };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Point the current VMCS at the areas.
+    pub fn install(&self, vmcs: &Vmcs) -> Result<(), VmxError> {
+        vmcs.write_control(ControlField::VmEntryMsrLoadAddr, self.guest.phys_addr())?;
+        vmcs.write_control(ControlField::VmEntryMsrLoadCount, self.count as u64)?;
+        vmcs.write_control(ControlField::VmExitMsrStoreAddr, self.guest.phys_addr())?;
+        vmcs.write_control(ControlField::VmExitMsrStoreCount, self.count as u64)?;
+        vmcs.write_control(ControlField::VmExitMsrLoadAddr, self.host.phys_addr())?;
+        vmcs.write_control(ControlField::VmExitMsrLoadCount, self.count as u64)?;
+        Ok(())
+    }
+}
+
+/// CPUID from a [`CpuModel`].
+pub struct ModelCpuidHandler {
+    model: Arc<CpuModel>,
+}
+
+impl ExitHandler for ModelCpuidHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        let entry = self.model.cpuid(ctx.vcpu, ctx.regs.rax as u32, ctx.regs.rcx as u32);
+        ctx.regs.rax = entry.eax as u64;
+        ctx.regs.rbx = entry.ebx as u64;
+        ctx.regs.rcx = entry.ecx as u64;
+        ctx.regs.rdx = entry.edx as u64;
+        Ok(ExitAction::AdvanceRip)
+    }
+}
+
+/// RDMSR/WRMSR of intercepted MSRs under a [`CpuModel`]. Clones share the
+/// emulated values, so one handler can be registered for both exits.
+#[derive(Clone)]
+pub struct ModelMsrHandler {
+    model: Arc<CpuModel>,
+    /// Emulated MSR values written by each vCPU, keyed by (vCPU, MSR).
+    values: Arc<Mutex<BTreeMap<(u32, u32), u64>>>,
+}
+
+impl ModelMsrHandler {
+    pub fn new(model: Arc<CpuModel>) -> Self {
+        ModelMsrHandler { model, values: Arc::new(Mutex::new(BTreeMap::new())) }
+    }
+
+    /// The current value of an emulated MSR.
+    pub fn value(&self, vcpu: u32, msr: u32) -> Option<u64> {
+        match self.model.msr(msr)? {
+            MsrAccess::Emulated { reset, .. } => Some(self.values.lock().get(&(vcpu, msr)).copied().unwrap_or(reset)),
+            _ => None,
+        }
+    }
+
+    /// Write an emulated MSR. Returns false where the guest's WRMSR would
+    /// raise #GP.
+    pub fn write(&self, vcpu: u32, msr: u32, value: u64) -> bool {
+        let current = match self.value(vcpu, msr) {
+            Some(current) => current,
+            None => return false,
+        };
+        match self.model.msr(msr) {
+            Some(MsrAccess::Emulated { writable, .. }) if (value ^ current) & !writable == 0 => {
+                self.values.lock().insert((vcpu, msr), value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Every emulated MSR of `vcpu` with its current value.
+    pub fn values(&self, vcpu: u32) -> Vec<(u32, u64)> {
+        self.model.emulated_msrs().filter_map(|msr| Some((msr, self.value(vcpu, msr)?))).collect()
+    }
+}
+
+impl ExitHandler for ModelMsrHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        let msr = ctx.regs.rcx as u32;
+        let access = match self.model.msr(msr) {
+            Some(access) => access,
+            None => {
+                inject_exception(ctx.vmcs, 13, Some(0))?;
+                return Ok(ExitAction::Resume);
+            }
+        };
+        match (ctx.info.reason, access) {
+            (_, MsrAccess::Forward) => Ok(ExitAction::Unhandled),
+            (Some(ExitReason::Wrmsr), MsrAccess::ReadOnly) => Ok(ExitAction::AdvanceRip),
+            (Some(ExitReason::Rdmsr), MsrAccess::Emulated { .. }) => {
+                let value = self.value(ctx.vcpu, msr).unwrap_or(0);
+                ctx.regs.rax = value & 0xFFFF_FFFF;
+                ctx.regs.rdx = value >> 32;
+                Ok(ExitAction::AdvanceRip)
+            }
+            (Some(ExitReason::Wrmsr), MsrAccess::Emulated { .. }) => {
+                let value = (ctx.regs.rdx << 32) | (ctx.regs.rax & 0xFFFF_FFFF);
+                if !self.write(ctx.vcpu, msr, value) {
+                    inject_exception(ctx.vmcs, 13, Some(0))?;
+                    return Ok(ExitAction::Resume);
+                }
+                Ok(ExitAction::AdvanceRip)
+            }
+            // Hardware-backed MSRs only exit if the bitmap is not in use.
+            _ => Ok(ExitAction::Unhandled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmx::ept::tests::FakePhysMemory;
+
+    #[test]
+    fn test_identity_and_topology() {
+        let model = CpuModel::new(3);
+        let vendor = model.cpuid(0, 0, 0);
+        assert_eq!(vendor.eax, MAX_BASIC_LEAF);
+        assert_eq!(&[vendor.ebx.to_le_bytes(), vendor.edx.to_le_bytes(), vendor.ecx.to_le_bytes()].concat(), VENDOR);
+        let hv = model.cpuid(1, HYPERVISOR_LEAF, 0);
+        assert_eq!(&[hv.ebx.to_le_bytes(), hv.ecx.to_le_bytes(), hv.edx.to_le_bytes()].concat(), HYPERVISOR_SIGNATURE);
+
+        let features = model.cpuid(2, 1, 0);
+        assert_eq!(features.ecx & ECX_VMX, 0);
+        assert_ne!(features.ecx & ECX_HYPERVISOR, 0);
+        assert_ne!(features.edx & EDX_HTT, 0);
+        assert_eq!(features.ebx >> 24, 2);
+        assert_eq!((features.ebx >> 16) & 0xFF, 4);
+
+        // Three cores need two bits of APIC ID.
+        assert_eq!(model.cpuid(1, 0xB, 1), CpuidEntry::new(2, 3, 0x201, 1));
+        assert_eq!(model.cpuid(1, 0xB, 0).edx, 1);
+        assert_eq!(model.cpuid(0, 4, 0).eax >> 26, 3);
+        assert_eq!((model.cpuid(0, 4, 3).eax >> 14) & 0xFFF, 3);
+        assert_eq!(model.cpuid(0, 4, 4), CpuidEntry::new(0, 0, 0, 0));
+        // Undefined leaves and subleaves of unindexed leaves.
+        assert_eq!(model.cpuid(0, 0x8000_0000, 5).eax, MAX_EXTENDED_LEAF);
+        assert_eq!(model.cpuid(0, 0x2000_0000, 0), CpuidEntry::default());
+
+        let single = CpuModel::new(1);
+        assert_eq!(single.cpuid(0, 1, 0).edx & EDX_HTT, 0);
+        assert_eq!(single.cpuid(0, 0xB, 1).eax, 0);
+    }
+
+    #[test]
+    fn test_restrict_to_host() {
+        let mut model = CpuModel::new(2);
+        model.restrict_to(|leaf, _| match leaf {
+            1 => CpuidEntry::new(0, 0, ECX_VMX | 1, 0x0789_BBFF),
+            7 => CpuidEntry::new(0, 0x8, 0, 0),
+            0x8000_0008 => CpuidEntry::new(0x3024, 0, 0, 0),
+            _ => CpuidEntry::new(0, 0, u32::MAX, u32::MAX),
+        });
+        let features = model.cpuid(0, 1, 0);
+        assert_eq!(features.ecx, 1 | ECX_HYPERVISOR);
+        assert_ne!(features.edx & EDX_HTT, 0);
+        assert_eq!(model.cpuid(0, 7, 0).ebx, 0x8);
+        assert_eq!(model.cpuid(0, 0x8000_0008, 0).eax, 0x3024);
+    }
+
+    #[test]
+    fn test_msr_policy() {
+        let model = Arc::new(CpuModel::new(2));
+        let memory = FakePhysMemory::new(1);
+        let bitmap = model.msr_bitmap(&mut memory.allocator(), memory.offset()).unwrap();
+        assert!(!bitmap.intercepts_read(IA32_LSTAR) && !bitmap.intercepts_write(IA32_LSTAR));
+        assert!(!bitmap.intercepts_read(IA32_TSC) && bitmap.intercepts_write(IA32_TSC));
+        assert!(bitmap.intercepts_read(IA32_APIC_BASE) && bitmap.intercepts_write(IA32_PAT));
+        assert!(bitmap.intercepts_read(0x4000_0000));
+        let syscall: Vec<u32> = model.switched_msrs().collect();
+        assert_eq!(syscall, [IA32_STAR, IA32_LSTAR, IA32_CSTAR, IA32_FMASK, IA32_KERNEL_GS_BASE]);
+
+        let msrs = ModelMsrHandler::new(model.clone());
+        assert_eq!(msrs.value(0, IA32_FEATURE_CONTROL), Some(1));
+        assert!(!msrs.write(0, IA32_FEATURE_CONTROL, 5));
+        // Rewriting a read-only value is allowed.
+        assert!(msrs.write(0, IA32_MTRRCAP, 0x400));
+        assert!(msrs.write(1, IA32_PAT, 0x0606));
+        assert_eq!(msrs.value(1, IA32_PAT), Some(0x0606));
+        assert_eq!(msrs.value(0, IA32_PAT), Some(0x0007_0406_0007_0406));
+        assert!(msrs.write(0, IA32_MISC_ENABLE, 0x1800));
+        assert!(!msrs.write(0, IA32_MISC_ENABLE, 0x1808));
+        assert_eq!(msrs.value(0, 0xDEAD), None);
+        let values = msrs.values(1);
+        assert_eq!(values.len(), model.emulated_msrs().count());
+        assert!(values.contains(&(IA32_PAT, 0x0606)) && values.contains(&(IA32_MISC_ENABLE, 0x1801)));
+    }
+
+    #[test]
+    fn test_switch_area() {
+        let memory = FakePhysMemory::new(2);
+        let mut frames = memory.allocator();
+        let msrs = [IA32_STAR, IA32_LSTAR];
+        let mut area = MsrSwitchArea::new(&mut frames, memory.offset(), &msrs, |msr| msr as u64 * 2).unwrap();
+        assert_eq!((area.guest.phys_addr(), area.host.phys_addr()), (0, 0x1000));
+        assert_eq!(area.guest_value(IA32_LSTAR), Some(0));
+        assert!(area.set_guest_value(IA32_LSTAR, 0xFFFF_8000_0000_1000));
+        assert_eq!(area.guest_value(IA32_LSTAR), Some(0xFFFF_8000_0000_1000));
+        assert!(!area.set_guest_value(IA32_CSTAR, 1));
+        let host = unsafe { *(area.host.as_ptr().add(24) as *const u64) };
+        assert_eq!(host, IA32_LSTAR as u64 * 2);
+    }
+}
@@ -0,0 +1,637 @@
+use core::arch::asm;
+use alloc::vec::Vec;
+use x86_64::structures::paging::FrameAllocator;
+
+use crate::memory::SimpleFrameAllocator;
+use super::vmcs::{InstructionError, VmxError};
+
+const ENTRIES: usize = 512;
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const EPT_READ: u64 = 1 << 0;
+const EPT_WRITE: u64 = 1 << 1;
+const EPT_EXECUTE: u64 = 1 << 2;
+const EPT_MEMTYPE_SHIFT: u64 = 3;
+const EPT_IGNORE_PAT: u64 = 1 << 6;
+const EPT_LARGE_PAGE: u64 = 1 << 7;
+const EPT_ACCESSED: u64 = 1 << 8;
+const EPT_DIRTY: u64 = 1 << 9;
+
+pub const SIZE_4K: u64 = 0x1000;
+pub const SIZE_2M: u64 = 0x20_0000;
+pub const SIZE_1G: u64 = 0x4000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptError {
+    FrameAllocationFailed,
+    AlreadyMapped,
+    Misaligned,
+    NotMapped,
+    OverlappingRegions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> u64 {
+        match self {
+            PageSize::Size4K => SIZE_4K,
+            PageSize::Size2M => SIZE_2M,
+            PageSize::Size1G => SIZE_1G,
+        }
+    }
+
+    /// Paging-structure level holding a leaf of this size (PML4 = 3, PT = 0).
+    fn level(&self) -> usize {
+        match self {
+            PageSize::Size4K => 0,
+            PageSize::Size2M => 1,
+            PageSize::Size1G => 2,
+        }
+    }
+}
+
+/// EPT memory type for leaf entries (and for the EPTP paging structures).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+}
+
+impl MemoryType {
+    fn from_bits(bits: u64) -> Self {
+        match bits & 0b111 {
+            1 => MemoryType::WriteCombining,
+            4 => MemoryType::WriteThrough,
+            5 => MemoryType::WriteProtected,
+            6 => MemoryType::WriteBack,
+            _ => MemoryType::Uncacheable,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptPermissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl EptPermissions {
+    pub const NONE: Self = EptPermissions { read: false, write: false, execute: false };
+    pub const READ_ONLY: Self = EptPermissions { read: true, write: false, execute: false };
+    pub const READ_WRITE: Self = EptPermissions { read: true, write: true, execute: false };
+    pub const READ_EXECUTE: Self = EptPermissions { read: true, write: false, execute: true };
+    pub const ALL: Self = EptPermissions { read: true, write: true, execute: true };
+
+    fn bits(&self) -> u64 {
+        let mut bits = 0;
+        if self.read { bits |= EPT_READ; }
+        if self.write { bits |= EPT_WRITE; }
+        if self.execute { bits |= EPT_EXECUTE; }
+        bits
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        EptPermissions {
+            read: bits & EPT_READ != 0,
+            write: bits & EPT_WRITE != 0,
+            execute: bits & EPT_EXECUTE != 0,
+        }
+    }
+}
+
+/// What backs a range of guest-physical address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Guest RAM backed by host-physical memory starting at `host_phys`.
+    Ram { host_phys: u64 },
+    /// A hole left unmapped so accesses exit with an EPT violation and can
+    /// be emulated by a device model.
+    Mmio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub guest_phys: u64,
+    pub size: u64,
+    pub kind: RegionKind,
+    pub permissions: EptPermissions,
+}
+
+impl MemoryRegion {
+    pub fn ram(guest_phys: u64, size: u64, host_phys: u64) -> Self {
+        MemoryRegion { guest_phys, size, kind: RegionKind::Ram { host_phys }, permissions: EptPermissions::ALL }
+    }
+
+    pub fn mmio(guest_phys: u64, size: u64) -> Self {
+        MemoryRegion { guest_phys, size, kind: RegionKind::Mmio, permissions: EptPermissions::NONE }
+    }
+
+    pub fn with_permissions(mut self, permissions: EptPermissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    pub fn end(&self) -> u64 {
+        self.guest_phys + self.size
+    }
+
+    pub fn contains(&self, gpa: u64) -> bool {
+        gpa >= self.guest_phys && gpa < self.end()
+    }
+}
+
+/// The guest-physical address map of a VM.
+#[derive(Debug, Clone, Default)]
+pub struct GuestMemoryLayout {
+    regions: Vec<MemoryRegion>,
+}
+
+impl GuestMemoryLayout {
+    pub fn new() -> Self {
+        GuestMemoryLayout { regions: Vec::new() }
+    }
+
+    pub fn add(&mut self, region: MemoryRegion) -> Result<(), EptError> {
+        if region.guest_phys % SIZE_4K != 0 || region.size % SIZE_4K != 0 {
+            return Err(EptError::Misaligned);
+        }
+        if self.regions.iter().any(|r| region.guest_phys < r.end() && r.guest_phys < region.end()) {
+            return Err(EptError::OverlappingRegions);
+        }
+        let pos = self.regions.iter().position(|r| r.guest_phys > region.guest_phys).unwrap_or(self.regions.len());
+        self.regions.insert(pos, region);
+        Ok(())
+    }
+
+    /// Regions sorted by guest-physical address.
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    pub fn find(&self, gpa: u64) -> Option<&MemoryRegion> {
+        self.regions.iter().find(|r| r.contains(gpa))
+    }
+
+    pub fn ram_size(&self) -> u64 {
+        self.regions.iter().filter(|r| matches!(r.kind, RegionKind::Ram { .. })).map(|r| r.size).sum()
+    }
+
+    /// Translate a guest-physical address inside a RAM region to host-physical.
+    pub fn gpa_to_hpa(&self, gpa: u64) -> Option<u64> {
+        match self.find(gpa)? {
+            MemoryRegion { kind: RegionKind::Ram { host_phys }, guest_phys, .. } => Some(host_phys + (gpa - guest_phys)),
+            _ => None,
+        }
+    }
+}
+
+/// Result of walking the EPT for a guest-physical address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptTranslation {
+    pub host_phys: u64,
+    pub page_size: PageSize,
+    pub permissions: EptPermissions,
+    pub memory_type: MemoryType,
+    pub accessed: bool,
+    pub dirty: bool,
+}
+
+/// A 4-level extended page table.
+///
+/// Paging structures are allocated from a [`SimpleFrameAllocator`] and
+/// accessed through `phys_offset`, the virtual address at which host-physical
+/// memory is mapped (0 when identity-mapped).
+pub struct Ept {
+    pml4: u64,
+    phys_offset: u64,
+    large_pages_1g: bool,
+    accessed_dirty: bool,
+}
+
+impl Ept {
+    pub fn new(allocator: &mut SimpleFrameAllocator, phys_offset: u64) -> Result<Self, EptError> {
+        let mut ept = Ept { pml4: 0, phys_offset, large_pages_1g: false, accessed_dirty: false };
+        ept.pml4 = ept.alloc_table(allocator)?;
+        Ok(ept)
+    }
+
+    /// Build an EPT mapping every RAM region of `layout`, using the largest
+    /// page size that fits each piece. MMIO regions are left unmapped.
+    pub fn from_layout(
+        layout: &GuestMemoryLayout,
+        allocator: &mut SimpleFrameAllocator,
+        phys_offset: u64,
+        allow_1g: bool,
+    ) -> Result<Self, EptError> {
+        let mut ept = Self::new(allocator, phys_offset)?;
+        ept.large_pages_1g = allow_1g;
+        for region in layout.regions() {
+            if let RegionKind::Ram { host_phys } = region.kind {
+                ept.map_range(region.guest_phys, host_phys, region.size, region.permissions, MemoryType::WriteBack, allocator)?;
+            }
+        }
+        Ok(ept)
+    }
+
+    /// Allow 1 GiB leaves (requires IA32_VMX_EPT_VPID_CAP bit 17).
+    pub fn set_large_pages_1g(&mut self, enabled: bool) {
+        self.large_pages_1g = enabled;
+    }
+
+    /// Enable accessed/dirty flags in the EPTP (requires IA32_VMX_EPT_VPID_CAP bit 21).
+    pub fn set_accessed_dirty(&mut self, enabled: bool) {
+        self.accessed_dirty = enabled;
+    }
+
+    pub fn pml4_phys(&self) -> u64 {
+        self.pml4
+    }
+
+    /// EPT pointer for the VMCS: write-back paging structures, 4-level walk.
+    pub fn eptp(&self) -> u64 {
+        let mut eptp = self.pml4 | (MemoryType::WriteBack as u64) | (3 << 3);
+        if self.accessed_dirty {
+            eptp |= 1 << 6;
+        }
+        eptp
+    }
+
+    fn alloc_table(&self, allocator: &mut SimpleFrameAllocator) -> Result<u64, EptError> {
+        let frame = allocator.allocate_frame().ok_or(EptError::FrameAllocationFailed)?;
+        let phys = frame.start_address().as_u64();
+        unsafe { core::ptr::write_bytes((phys + self.phys_offset) as *mut u8, 0, SIZE_4K as usize) };
+        Ok(phys)
+    }
+
+    fn table(&self, phys: u64) -> *mut [u64; ENTRIES] {
+        (phys + self.phys_offset) as *mut [u64; ENTRIES]
+    }
+
+    fn entry(&self, table: u64, index: usize) -> &'static mut u64 {
+        unsafe { &mut (*self.table(table))[index] }
+    }
+
+    fn index(gpa: u64, level: usize) -> usize {
+        ((gpa >> (12 + 9 * level)) & 0x1FF) as usize
+    }
+
+    /// Map one page of the given size.
+    pub fn map(
+        &mut self,
+        gpa: u64,
+        hpa: u64,
+        size: PageSize,
+        permissions: EptPermissions,
+        memory_type: MemoryType,
+        allocator: &mut SimpleFrameAllocator,
+    ) -> Result<(), EptError> {
+        let bytes = size.bytes();
+        if gpa % bytes != 0 || hpa % bytes != 0 {
+            return Err(EptError::Misaligned);
+        }
+        let mut table = self.pml4;
+        for level in (size.level() + 1..=3).rev() {
+            let entry = self.entry(table, Self::index(gpa, level));
+            if *entry & (EPT_READ | EPT_WRITE | EPT_EXECUTE) == 0 {
+                let next = self.alloc_table(allocator)?;
+                *entry = next | EPT_READ | EPT_WRITE | EPT_EXECUTE;
+            } else if *entry & EPT_LARGE_PAGE != 0 {
+                return Err(EptError::AlreadyMapped);
+            }
+            table = *entry & ADDR_MASK;
+        }
+        let entry = self.entry(table, Self::index(gpa, size.level()));
+        if *entry & (EPT_READ | EPT_WRITE | EPT_EXECUTE) != 0 {
+            return Err(EptError::AlreadyMapped);
+        }
+        let mut value = hpa | permissions.bits() | ((memory_type as u64) << EPT_MEMTYPE_SHIFT) | EPT_IGNORE_PAT;
+        if size != PageSize::Size4K {
+            value |= EPT_LARGE_PAGE;
+        }
+        *entry = value;
+        Ok(())
+    }
+
+    /// Map `len` bytes starting at `gpa` to `hpa`, picking the largest page
+    /// size allowed by the alignment of both addresses at each step.
+    pub fn map_range(
+        &mut self,
+        gpa: u64,
+        hpa: u64,
+        len: u64,
+        permissions: EptPermissions,
+        memory_type: MemoryType,
+        allocator: &mut SimpleFrameAllocator,
+    ) -> Result<(), EptError> {
+        if gpa % SIZE_4K != 0 || hpa % SIZE_4K != 0 || len % SIZE_4K != 0 {
+            return Err(EptError::Misaligned);
+        }
+        let mut offset = 0;
+        while offset < len {
+            let size = self.best_page_size(gpa + offset, hpa + offset, len - offset);
+            self.map(gpa + offset, hpa + offset, size, permissions, memory_type, allocator)?;
+            offset += size.bytes();
+        }
+        Ok(())
+    }
+
+    fn best_page_size(&self, gpa: u64, hpa: u64, remaining: u64) -> PageSize {
+        let fits = |bytes: u64| gpa % bytes == 0 && hpa % bytes == 0 && remaining >= bytes;
+        if self.large_pages_1g && fits(SIZE_1G) {
+            PageSize::Size1G
+        } else if fits(SIZE_2M) {
+            PageSize::Size2M
+        } else {
+            PageSize::Size4K
+        }
+    }
+
+    /// Find the leaf entry for `gpa`, returning a pointer to it and its size.
+    fn leaf(&self, gpa: u64) -> Option<(*mut u64, PageSize)> {
+        let mut table = self.pml4;
+        for level in (0..=3).rev() {
+            let entry = self.entry(table, Self::index(gpa, level));
+            if *entry & (EPT_READ | EPT_WRITE | EPT_EXECUTE) == 0 {
+                return None;
+            }
+            let size = match level {
+                0 => Some(PageSize::Size4K),
+                1 if *entry & EPT_LARGE_PAGE != 0 => Some(PageSize::Size2M),
+                2 if *entry & EPT_LARGE_PAGE != 0 => Some(PageSize::Size1G),
+                _ => None,
+            };
+            if let Some(size) = size {
+                return Some((entry as *mut u64, size));
+            }
+            table = *entry & ADDR_MASK;
+        }
+        None
+    }
+
+    /// Walk the tables for `gpa` the way the processor would.
+    pub fn translate(&self, gpa: u64) -> Option<EptTranslation> {
+        let (entry, page_size) = self.leaf(gpa)?;
+        let value = unsafe { *entry };
+        let offset_mask = page_size.bytes() - 1;
+        Some(EptTranslation {
+            host_phys: (value & ADDR_MASK & !offset_mask) | (gpa & offset_mask),
+            page_size,
+            permissions: EptPermissions::from_bits(value),
+            memory_type: MemoryType::from_bits(value >> EPT_MEMTYPE_SHIFT),
+            accessed: value & EPT_ACCESSED != 0,
+            dirty: value & EPT_DIRTY != 0,
+        })
+    }
+
+    /// The 4 KiB pages in `[gpa, gpa + len)` the processor marked dirty,
+    /// clearing the marks so the next call only reports newer writes. A
+    /// dirty large page reports all of its 4 KiB pages. Needs accessed/dirty
+    /// flags enabled, and an INVEPT before the guest runs again so cached
+    /// translations stop skipping the dirty-bit update.
+    pub fn take_dirty(&mut self, gpa: u64, len: u64) -> Vec<u64> {
+        let mut pages = Vec::new();
+        let end = gpa.saturating_add(len);
+        let mut addr = gpa & !(SIZE_4K - 1);
+        while addr < end {
+            let (entry, size) = match self.leaf(addr) {
+                Some(leaf) => leaf,
+                None => {
+                    addr += SIZE_4K;
+                    continue;
+                }
+            };
+            let leaf_end = (addr & !(size.bytes() - 1)) + size.bytes();
+            unsafe {
+                if *entry & EPT_DIRTY != 0 {
+                    *entry &= !EPT_DIRTY;
+                    pages.extend((addr..leaf_end.min(end)).step_by(SIZE_4K as usize));
+                }
+            }
+            addr = leaf_end;
+        }
+        pages
+    }
+
+    /// Change the permissions of the page containing `gpa`.
+    pub fn protect(&mut self, gpa: u64, permissions: EptPermissions) -> Result<(), EptError> {
+        let (entry, _) = self.leaf(gpa).ok_or(EptError::NotMapped)?;
+        unsafe { *entry = (*entry & !(EPT_READ | EPT_WRITE | EPT_EXECUTE)) | permissions.bits() };
+        Ok(())
+    }
+
+    /// Remove the mapping of the page containing `gpa`. Intermediate tables
+    /// are kept; the caller must INVEPT afterwards.
+    pub fn unmap(&mut self, gpa: u64) -> Result<PageSize, EptError> {
+        let (entry, size) = self.leaf(gpa).ok_or(EptError::NotMapped)?;
+        unsafe { *entry = 0 };
+        Ok(size)
+    }
+}
+
+/// INVEPT invalidation types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum InveptType {
+    SingleContext = 1,
+    Global = 2,
+}
+
+/// Invalidate cached EPT translations for `eptp` (ignored for `Global`).
+pub fn invept(kind: InveptType, eptp: u64) -> Result<(), VmxError> {
+    let descriptor: [u64; 2] = [eptp, 0];
+    let cf: u8;
+    let zf: u8;
+    unsafe {
+        asm!(
+            "invept {0}, [{1}]",
+            "setc {2}",
+            "setz {3}",
+            in(reg) kind as u64,
+            in(reg) &descriptor,
+            out(reg_byte) cf,
+            out(reg_byte) zf,
+            options(nostack),
+        );
+    }
+    if cf != 0 {
+        Err(VmxError::VmFailInvalid)
+    } else if zf != 0 {
+        Err(VmxError::VmFailValid(InstructionError::InvalidInveptInvvpidOperand))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+    use alloc::vec;
+
+    /// A block of host memory standing in for physical memory, with frames
+    /// handed out from guest-physical 0 upwards.
+    pub(crate) struct FakePhysMemory {
+        ptr: *mut u8,
+        layout: Layout,
+    }
+
+    impl FakePhysMemory {
+        pub(crate) fn new(frames: usize) -> Self {
+            let layout = Layout::from_size_align(frames * 4096, 4096).unwrap();
+            let ptr = unsafe { alloc_zeroed(layout) };
+            FakePhysMemory { ptr, layout }
+        }
+
+        pub(crate) fn allocator(&self) -> SimpleFrameAllocator {
+            SimpleFrameAllocator::new(0, self.layout.size() as u64)
+        }
+
+        pub(crate) fn offset(&self) -> u64 {
+            self.ptr as u64
+        }
+    }
+
+    impl Drop for FakePhysMemory {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.ptr, self.layout) };
+        }
+    }
+
+    #[test]
+    fn test_map_4k_and_translate() {
+        let mem = FakePhysMemory::new(16);
+        let mut alloc = mem.allocator();
+        let mut ept = Ept::new(&mut alloc, mem.offset()).unwrap();
+        ept.map(0x5000, 0x12_3000, PageSize::Size4K, EptPermissions::READ_WRITE, MemoryType::WriteBack, &mut alloc).unwrap();
+
+        let t = ept.translate(0x5abc).unwrap();
+        assert_eq!(t.host_phys, 0x12_3abc);
+        assert_eq!(t.page_size, PageSize::Size4K);
+        assert_eq!(t.permissions, EptPermissions::READ_WRITE);
+        assert_eq!(t.memory_type, MemoryType::WriteBack);
+        assert!(ept.translate(0x6000).is_none());
+        assert!(ept.translate(0x4fff).is_none());
+    }
+
+    #[test]
+    fn test_layout_uses_large_pages_and_skips_mmio() {
+        let mem = FakePhysMemory::new(16);
+        let mut alloc = mem.allocator();
+        let mut layout = GuestMemoryLayout::new();
+        // 1 GiB + 2 MiB + 4 KiB of RAM, then an MMIO hole.
+        layout.add(MemoryRegion::ram(0, SIZE_1G + SIZE_2M + SIZE_4K, 0x1_0000_0000)).unwrap();
+        layout.add(MemoryRegion::mmio(0xFEE0_0000, SIZE_4K)).unwrap();
+        layout.add(MemoryRegion::ram(0x1_0000_0000, SIZE_2M, 0x2_0000_0000).with_permissions(EptPermissions::READ_EXECUTE)).unwrap();
+
+        let ept = Ept::from_layout(&layout, &mut alloc, mem.offset(), true).unwrap();
+
+        let t = ept.translate(0x1234_5678).unwrap();
+        assert_eq!(t.page_size, PageSize::Size1G);
+        assert_eq!(t.host_phys, 0x1_1234_5678);
+
+        let t = ept.translate(SIZE_1G + 0x10).unwrap();
+        assert_eq!(t.page_size, PageSize::Size2M);
+        assert_eq!(t.host_phys, 0x1_0000_0000 + SIZE_1G + 0x10);
+
+        let t = ept.translate(SIZE_1G + SIZE_2M + 0xff).unwrap();
+        assert_eq!(t.page_size, PageSize::Size4K);
+        assert_eq!(t.host_phys, 0x1_0000_0000 + SIZE_1G + SIZE_2M + 0xff);
+        assert!(ept.translate(SIZE_1G + SIZE_2M + SIZE_4K).is_none());
+
+        assert!(ept.translate(0xFEE0_0000).is_none());
+
+        let t = ept.translate(0x1_0000_1000).unwrap();
+        assert_eq!(t.permissions, EptPermissions::READ_EXECUTE);
+        assert_eq!(t.host_phys, 0x2_0000_1000);
+    }
+
+    #[test]
+    fn test_no_1g_pages_unless_enabled() {
+        let mem = FakePhysMemory::new(16);
+        let mut alloc = mem.allocator();
+        let mut layout = GuestMemoryLayout::new();
+        layout.add(MemoryRegion::ram(0, SIZE_1G, 0)).unwrap();
+        let ept = Ept::from_layout(&layout, &mut alloc, mem.offset(), false).unwrap();
+        assert_eq!(ept.translate(0x3000_0000).unwrap().page_size, PageSize::Size2M);
+    }
+
+    #[test]
+    fn test_misaligned_host_falls_back_to_4k() {
+        let mem = FakePhysMemory::new(16);
+        let mut alloc = mem.allocator();
+        let mut ept = Ept::new(&mut alloc, mem.offset()).unwrap();
+        ept.map_range(0, 0x1000, SIZE_2M, EptPermissions::ALL, MemoryType::WriteBack, &mut alloc).unwrap();
+        let t = ept.translate(0x1f_f000).unwrap();
+        assert_eq!(t.page_size, PageSize::Size4K);
+        assert_eq!(t.host_phys, 0x20_0000);
+    }
+
+    #[test]
+    fn test_double_map_and_unmap() {
+        let mem = FakePhysMemory::new(16);
+        let mut alloc = mem.allocator();
+        let mut ept = Ept::new(&mut alloc, mem.offset()).unwrap();
+        ept.map(0, 0, PageSize::Size2M, EptPermissions::ALL, MemoryType::WriteBack, &mut alloc).unwrap();
+        assert_eq!(
+            ept.map(0x1000, 0, PageSize::Size4K, EptPermissions::ALL, MemoryType::WriteBack, &mut alloc),
+            Err(EptError::AlreadyMapped)
+        );
+        assert_eq!(ept.unmap(0x1000), Ok(PageSize::Size2M));
+        assert!(ept.translate(0).is_none());
+        assert_eq!(ept.unmap(0), Err(EptError::NotMapped));
+    }
+
+    #[test]
+    fn test_protect_and_eptp() {
+        let mem = FakePhysMemory::new(16);
+        let mut alloc = mem.allocator();
+        let mut ept = Ept::new(&mut alloc, mem.offset()).unwrap();
+        ept.map(0x2000, 0x2000, PageSize::Size4K, EptPermissions::ALL, MemoryType::WriteBack, &mut alloc).unwrap();
+        ept.protect(0x2000, EptPermissions::READ_ONLY).unwrap();
+        assert_eq!(ept.translate(0x2000).unwrap().permissions, EptPermissions::READ_ONLY);
+
+        assert_eq!(ept.eptp() & 0xfff, 0x1e);
+        assert_eq!(ept.eptp() & ADDR_MASK, ept.pml4_phys());
+        ept.set_accessed_dirty(true);
+        assert_eq!(ept.eptp() & 0xfff, 0x5e);
+    }
+
+    #[test]
+    fn test_take_dirty() {
+        let mem = FakePhysMemory::new(16);
+        let mut alloc = mem.allocator();
+        let mut ept = Ept::new(&mut alloc, mem.offset()).unwrap();
+        ept.set_accessed_dirty(true);
+        ept.map_range(0, 0, 0x4000, EptPermissions::ALL, MemoryType::WriteBack, &mut alloc).unwrap();
+        ept.map(SIZE_2M, SIZE_2M, PageSize::Size2M, EptPermissions::ALL, MemoryType::WriteBack, &mut alloc).unwrap();
+        // What the processor does on a guest write.
+        for gpa in [0x1000, 0x3000, SIZE_2M] {
+            unsafe { *ept.leaf(gpa).unwrap().0 |= EPT_ACCESSED | EPT_DIRTY };
+        }
+        assert_eq!(ept.take_dirty(0, 0x4000), vec![0x1000, 0x3000]);
+        assert_eq!(ept.take_dirty(0, 0x4000), Vec::<u64>::new());
+        assert!(ept.translate(0x1000).unwrap().accessed);
+        let large = ept.take_dirty(SIZE_2M + 0x1000, 0x3000);
+        assert_eq!(large, vec![SIZE_2M + 0x1000, SIZE_2M + 0x2000, SIZE_2M + 0x3000]);
+    }
+
+    #[test]
+    fn test_layout_rejects_overlap() {
+        let mut layout = GuestMemoryLayout::new();
+        layout.add(MemoryRegion::ram(0, 0x10000, 0)).unwrap();
+        assert_eq!(layout.add(MemoryRegion::mmio(0x8000, 0x1000)), Err(EptError::OverlappingRegions));
+        assert_eq!(layout.add(MemoryRegion::mmio(0x10800, 0x1000)), Err(EptError::Misaligned));
+        assert_eq!(layout.gpa_to_hpa(0x1234), Some(0x1234));
+    }
+}
@@ -0,0 +1,773 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::fmt;
+
+use spin::Mutex;
+
+use super::cpu_model::host_cpuid;
+use super::ept::GuestMemoryLayout;
+use super::vmcs::{ControlField, ExitInfoField, GuestField, Vmcs, VmxError};
+use crate::hypervisor::{translate_linear, SpecialRegisters, VcpuRegisters};
+use crate::vdev::decoder::{decode, CpuMode, MAX_INSTRUCTION_LEN};
+use crate::vdev::mmio::MmioBus;
+use crate::vdev::pio::{size_mask, PortIoBus};
+
+/// General-purpose registers of a guest, saved on VM exit and restored on
+/// VM entry. RSP and RIP live in the VMCS guest-state area instead.
+///
+/// The layout is shared with the entry/exit trampoline in `vcpu.rs`; do not
+/// reorder the fields.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct GuestRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl GuestRegisters {
+    /// The full register file, with RSP, RIP and RFLAGS read from `vmcs`.
+    pub fn to_vcpu_registers(&self, vmcs: &Vmcs) -> Result<VcpuRegisters, VmxError> {
+        Ok(VcpuRegisters {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rsp: vmcs.read_guest(GuestField::Rsp)?,
+            rbp: self.rbp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: vmcs.read_guest(GuestField::Rip)?,
+            rflags: vmcs.read_guest(GuestField::Rflags)?,
+        })
+    }
+
+    /// Load `regs`, writing RSP, RIP and RFLAGS to `vmcs`.
+    pub fn load_vcpu_registers(&mut self, vmcs: &Vmcs, regs: &VcpuRegisters) -> Result<(), VmxError> {
+        *self = GuestRegisters {
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rbp: regs.rbp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+        };
+        vmcs.write_guest(GuestField::Rsp, regs.rsp)?;
+        vmcs.write_guest(GuestField::Rip, regs.rip)?;
+        // RFLAGS bit 1 is reserved and must be set for VM entry.
+        vmcs.write_guest(GuestField::Rflags, regs.rflags | (1 << 1))?;
+        Ok(())
+    }
+}
+
+/// Basic VM-exit reasons (Intel SDM Vol. 3D, Appendix C).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u16)]
+pub enum ExitReason {
+    ExceptionOrNmi = 0,
+    ExternalInterrupt = 1,
+    TripleFault = 2,
+    InitSignal = 3,
+    StartupIpi = 4,
+    IoSmi = 5,
+    OtherSmi = 6,
+    InterruptWindow = 7,
+    NmiWindow = 8,
+    TaskSwitch = 9,
+    Cpuid = 10,
+    Getsec = 11,
+    Hlt = 12,
+    Invd = 13,
+    Invlpg = 14,
+    Rdpmc = 15,
+    Rdtsc = 16,
+    Rsm = 17,
+    Vmcall = 18,
+    Vmclear = 19,
+    Vmlaunch = 20,
+    Vmptrld = 21,
+    Vmptrst = 22,
+    Vmread = 23,
+    Vmresume = 24,
+    Vmwrite = 25,
+    Vmxoff = 26,
+    Vmxon = 27,
+    CrAccess = 28,
+    DrAccess = 29,
+    IoInstruction = 30,
+    Rdmsr = 31,
+    Wrmsr = 32,
+    EntryFailureGuestState = 33,
+    EntryFailureMsrLoading = 34,
+    Mwait = 36,
+    MonitorTrapFlag = 37,
+    Monitor = 39,
+    Pause = 40,
+    EntryFailureMachineCheck = 41,
+    TprBelowThreshold = 43,
+    ApicAccess = 44,
+    VirtualizedEoi = 45,
+    GdtrIdtrAccess = 46,
+    LdtrTrAccess = 47,
+    EptViolation = 48,
+    EptMisconfig = 49,
+    Invept = 50,
+    Rdtscp = 51,
+    PreemptionTimer = 52,
+    Invvpid = 53,
+    Wbinvd = 54,
+    Xsetbv = 55,
+    ApicWrite = 56,
+    Rdrand = 57,
+    Invpcid = 58,
+    Vmfunc = 59,
+    Encls = 60,
+    Rdseed = 61,
+    PmlFull = 62,
+    Xsaves = 63,
+    Xrstors = 64,
+}
+
+impl ExitReason {
+    pub fn from_basic(basic: u16) -> Option<Self> {
+        use ExitReason::*;
+        let reason = match basic {
+            0 => ExceptionOrNmi,
+            1 => ExternalInterrupt,
+            2 => TripleFault,
+            3 => InitSignal,
+            4 => StartupIpi,
+            5 => IoSmi,
+            6 => OtherSmi,
+            7 => InterruptWindow,
+            8 => NmiWindow,
+            9 => TaskSwitch,
+            10 => Cpuid,
+            11 => Getsec,
+            12 => Hlt,
+            13 => Invd,
+            14 => Invlpg,
+            15 => Rdpmc,
+            16 => Rdtsc,
+            17 => Rsm,
+            18 => Vmcall,
+            19 => Vmclear,
+            20 => Vmlaunch,
+            21 => Vmptrld,
+            22 => Vmptrst,
+            23 => Vmread,
+            24 => Vmresume,
+            25 => Vmwrite,
+            26 => Vmxoff,
+            27 => Vmxon,
+            28 => CrAccess,
+            29 => DrAccess,
+            30 => IoInstruction,
+            31 => Rdmsr,
+            32 => Wrmsr,
+            33 => EntryFailureGuestState,
+            34 => EntryFailureMsrLoading,
+            36 => Mwait,
+            37 => MonitorTrapFlag,
+            39 => Monitor,
+            40 => Pause,
+            41 => EntryFailureMachineCheck,
+            43 => TprBelowThreshold,
+            44 => ApicAccess,
+            45 => VirtualizedEoi,
+            46 => GdtrIdtrAccess,
+            47 => LdtrTrAccess,
+            48 => EptViolation,
+            49 => EptMisconfig,
+            50 => Invept,
+            51 => Rdtscp,
+            52 => PreemptionTimer,
+            53 => Invvpid,
+            54 => Wbinvd,
+            55 => Xsetbv,
+            56 => ApicWrite,
+            57 => Rdrand,
+            58 => Invpcid,
+            59 => Vmfunc,
+            60 => Encls,
+            61 => Rdseed,
+            62 => PmlFull,
+            63 => Xsaves,
+            64 => Xrstors,
+            _ => return None,
+        };
+        Some(reason)
+    }
+}
+
+/// Decoded exit qualification for I/O instruction exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoQualification {
+    pub port: u16,
+    /// Access size in bytes: 1, 2 or 4.
+    pub size: u8,
+    pub is_in: bool,
+    pub is_string: bool,
+    pub is_rep: bool,
+}
+
+impl IoQualification {
+    pub fn decode(qualification: u64) -> Self {
+        IoQualification {
+            port: (qualification >> 16) as u16,
+            size: ((qualification & 0b111) + 1) as u8,
+            is_in: qualification & (1 << 3) != 0,
+            is_string: qualification & (1 << 4) != 0,
+            is_rep: qualification & (1 << 5) != 0,
+        }
+    }
+}
+
+/// Decoded exit qualification for EPT violations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptViolationQualification {
+    pub read: bool,
+    pub write: bool,
+    pub fetch: bool,
+    /// Permissions of the guest-physical address in the EPT paging structures.
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+    pub linear_address_valid: bool,
+}
+
+impl EptViolationQualification {
+    pub fn decode(qualification: u64) -> Self {
+        EptViolationQualification {
+            read: qualification & (1 << 0) != 0,
+            write: qualification & (1 << 1) != 0,
+            fetch: qualification & (1 << 2) != 0,
+            readable: qualification & (1 << 3) != 0,
+            writable: qualification & (1 << 4) != 0,
+            executable: qualification & (1 << 5) != 0,
+            linear_address_valid: qualification & (1 << 7) != 0,
+        }
+    }
+}
+
+/// Everything read out of the VMCS about a single VM exit.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitInfo {
+    /// Raw exit-reason field, including the entry-failure bit.
+    pub raw_reason: u32,
+    pub reason: Option<ExitReason>,
+    pub qualification: u64,
+    pub instruction_length: u64,
+    pub guest_rip: u64,
+    pub guest_rsp: u64,
+    pub guest_rflags: u64,
+    pub guest_physical_address: u64,
+    pub guest_linear_address: u64,
+    pub interruption_info: u32,
+    pub idt_vectoring_info: u32,
+}
+
+impl ExitInfo {
+    pub fn read(vmcs: &Vmcs) -> Result<Self, VmxError> {
+        let raw_reason = vmcs.read(ExitInfoField::ExitReason)? as u32;
+        Ok(ExitInfo {
+            raw_reason,
+            reason: ExitReason::from_basic(raw_reason as u16),
+            qualification: vmcs.read(ExitInfoField::ExitQualification)?,
+            instruction_length: vmcs.read(ExitInfoField::ExitInstructionLength)?,
+            guest_rip: vmcs.read(GuestField::Rip)?,
+            guest_rsp: vmcs.read(GuestField::Rsp)?,
+            guest_rflags: vmcs.read(GuestField::Rflags)?,
+            guest_physical_address: vmcs.read(ExitInfoField::GuestPhysicalAddress)?,
+            guest_linear_address: vmcs.read(ExitInfoField::GuestLinearAddress)?,
+            interruption_info: vmcs.read(ExitInfoField::ExitInterruptionInfo)? as u32,
+            idt_vectoring_info: vmcs.read(ExitInfoField::IdtVectoringInfo)? as u32,
+        })
+    }
+
+    pub fn basic_reason(&self) -> u16 {
+        self.raw_reason as u16
+    }
+
+    /// Bit 31 of the exit reason: the VM entry itself failed.
+    pub fn is_entry_failure(&self) -> bool {
+        self.raw_reason & (1 << 31) != 0
+    }
+
+    pub fn io(&self) -> IoQualification {
+        IoQualification::decode(self.qualification)
+    }
+
+    pub fn ept_violation(&self) -> EptViolationQualification {
+        EptViolationQualification::decode(self.qualification)
+    }
+}
+
+impl fmt::Display for ExitInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            Some(reason) => write!(f, "{:?} ({})", reason, self.basic_reason())?,
+            None => write!(f, "unknown exit reason {}", self.basic_reason())?,
+        }
+        if self.is_entry_failure() {
+            write!(f, " [entry failure]")?;
+        }
+        write!(
+            f,
+            " qual={:#x} rip={:#x} rsp={:#x} rflags={:#x} len={} gpa={:#x} gla={:#x} intr={:#x} idt={:#x}",
+            self.qualification,
+            self.guest_rip,
+            self.guest_rsp,
+            self.guest_rflags,
+            self.instruction_length,
+            self.guest_physical_address,
+            self.guest_linear_address,
+            self.interruption_info,
+            self.idt_vectoring_info,
+        )
+    }
+}
+
+/// What the run loop should do after a handler has processed an exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitAction {
+    /// Re-enter the guest at the current RIP (the handler moved it if needed).
+    Resume,
+    /// Skip the exiting instruction and re-enter the guest.
+    AdvanceRip,
+    /// The guest halted with nothing to wake it.
+    Halt,
+    /// The guest is gone (triple fault, power off).
+    Shutdown,
+    /// The handler declined the exit; the run loop logs it and stops.
+    Unhandled,
+}
+
+/// State a handler may inspect and modify while servicing an exit.
+pub struct ExitContext<'a> {
+    /// ID of the exiting vCPU.
+    pub vcpu: u32,
+    pub vmcs: &'a Vmcs,
+    pub regs: &'a mut GuestRegisters,
+    pub info: &'a ExitInfo,
+}
+
+/// A handler for one or more VM-exit reasons.
+///
+/// Device models and CPU policies implement this and register themselves
+/// with an [`ExitDispatcher`]; the run loop never needs to know about them.
+pub trait ExitHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError>;
+}
+
+pub struct ExitDispatcher {
+    handlers: BTreeMap<u16, Box<dyn ExitHandler>>,
+}
+
+impl ExitDispatcher {
+    pub fn new() -> Self {
+        ExitDispatcher { handlers: BTreeMap::new() }
+    }
+
+    /// A dispatcher with the built-in handlers for CPUID, HLT, I/O
+    /// instructions, RDMSR/WRMSR, EPT violations and triple faults.
+    pub fn with_defaults() -> Self {
+        let mut dispatcher = Self::new();
+        dispatcher.register(ExitReason::Cpuid, Box::new(CpuidHandler));
+        dispatcher.register(ExitReason::Hlt, Box::new(HltHandler));
+        dispatcher.register(ExitReason::IoInstruction, Box::new(UnclaimedIoHandler));
+        dispatcher.register(ExitReason::Rdmsr, Box::new(MsrHandler::new()));
+        dispatcher.register(ExitReason::Wrmsr, Box::new(MsrHandler::new()));
+        dispatcher.register(ExitReason::EptViolation, Box::new(EptViolationHandler));
+        dispatcher.register(ExitReason::TripleFault, Box::new(TripleFaultHandler));
+        dispatcher
+    }
+
+    /// Register a handler, replacing any previous handler for `reason`.
+    pub fn register(&mut self, reason: ExitReason, handler: Box<dyn ExitHandler>) -> Option<Box<dyn ExitHandler>> {
+        self.handlers.insert(reason as u16, handler)
+    }
+
+    pub fn unregister(&mut self, reason: ExitReason) -> Option<Box<dyn ExitHandler>> {
+        self.handlers.remove(&(reason as u16))
+    }
+
+    pub fn dispatch(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        match self.handlers.get_mut(&ctx.info.basic_reason()) {
+            Some(handler) => handler.handle(ctx),
+            None => Ok(ExitAction::Unhandled),
+        }
+    }
+}
+
+impl Default for ExitDispatcher {
+    fn default() -> Self {
+        Self::with_defaults()
+    }
+}
+
+/// Log an exit nobody handled, with enough context to debug the guest.
+pub fn log_unhandled_exit(info: &ExitInfo, regs: &GuestRegisters) {
+    crate::println!("VMX: unhandled exit: {}", info);
+    crate::println!(
+        "  rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}",
+        regs.rax, regs.rbx, regs.rcx, regs.rdx
+    );
+    crate::println!(
+        "  rsi={:#018x} rdi={:#018x} rbp={:#018x} r8={:#018x}",
+        regs.rsi, regs.rdi, regs.rbp, regs.r8
+    );
+    crate::println!(
+        "  r9={:#018x} r10={:#018x} r11={:#018x} r12={:#018x}",
+        regs.r9, regs.r10, regs.r11, regs.r12
+    );
+    crate::println!("  r13={:#018x} r14={:#018x} r15={:#018x}", regs.r13, regs.r14, regs.r15);
+}
+
+/// Pass host CPUID through, hiding VMX and advertising a hypervisor.
+pub struct CpuidHandler;
+
+impl ExitHandler for CpuidHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        let leaf = ctx.regs.rax as u32;
+        let subleaf = ctx.regs.rcx as u32;
+        let mut result = host_cpuid(leaf, subleaf);
+        if leaf == 1 {
+            result.ecx &= !(1 << 5); // VMX
+            result.ecx |= 1 << 31; // hypervisor present
+        }
+        ctx.regs.rax = result.eax as u64;
+        ctx.regs.rbx = result.ebx as u64;
+        ctx.regs.rcx = result.ecx as u64;
+        ctx.regs.rdx = result.edx as u64;
+        Ok(ExitAction::AdvanceRip)
+    }
+}
+
+pub struct HltHandler;
+
+impl ExitHandler for HltHandler {
+    fn handle(&mut self, _ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        Ok(ExitAction::Halt)
+    }
+}
+
+/// Fallback for port I/O with no device behind it: reads return all ones
+/// and writes are dropped, like an empty ISA bus.
+pub struct UnclaimedIoHandler;
+
+impl ExitHandler for UnclaimedIoHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        let io = ctx.info.io();
+        if io.is_string {
+            return Ok(ExitAction::Unhandled);
+        }
+        if io.is_in {
+            let mask = size_mask(io.size) as u64;
+            ctx.regs.rax = (ctx.regs.rax & !mask) | mask;
+        }
+        Ok(ExitAction::AdvanceRip)
+    }
+}
+
+/// Forwards IN/OUT, including INS/OUTS with REP, to a [`PortIoBus`].
+///
+/// String I/O copies guest memory through `memory` (guest-physical to
+/// host-physical) and `phys_offset` (host-physical to host-virtual), and
+/// stops at page boundaries so a single exit never needs more than one
+/// guest page-table walk.
+pub struct PortIoHandler {
+    bus: Arc<Mutex<PortIoBus>>,
+    memory: GuestMemoryLayout,
+    phys_offset: u64,
+}
+
+impl PortIoHandler {
+    pub fn new(bus: Arc<Mutex<PortIoBus>>, memory: GuestMemoryLayout, phys_offset: u64) -> Self {
+        PortIoHandler { bus, memory, phys_offset }
+    }
+
+    fn host_ptr(&self, gpa: u64) -> Option<*mut u8> {
+        self.memory.gpa_to_hpa(gpa).map(|hpa| (hpa + self.phys_offset) as *mut u8)
+    }
+
+    fn string_io(&mut self, ctx: &mut ExitContext, io: IoQualification) -> Result<ExitAction, VmxError> {
+        let instruction_info = ctx.vmcs.read(ExitInfoField::ExitInstructionInfo)?;
+        let addr_mask = match (instruction_info >> 7) & 7 {
+            0 => 0xFFFF,
+            1 => 0xFFFF_FFFF,
+            _ => u64::MAX,
+        };
+        let count = if io.is_rep { ctx.regs.rcx & addr_mask } else { 1 };
+        if count == 0 {
+            return Ok(ExitAction::AdvanceRip);
+        }
+
+        let size = io.size as u64;
+        let linear = ctx.info.guest_linear_address;
+        let backwards = ctx.info.guest_rflags & (1 << 10) != 0;
+        let room = if backwards { (linear & 0xFFF) / size + 1 } else { (0x1000 - (linear & 0xFFF)) / size };
+        let n = core::cmp::min(count, core::cmp::max(room, 1));
+
+        let sregs = guest_paging_state(ctx.vmcs)?;
+        let read_entry = |gpa: u64| self.host_ptr(gpa).map(|ptr| unsafe { *(ptr as *const u64) });
+        let gpa = match translate_linear(&sregs, linear, read_entry) {
+            Some(gpa) => gpa,
+            // Let the guest take the fault on its own once we inject exceptions.
+            None => return Ok(ExitAction::Unhandled),
+        };
+
+        let mut bus = self.bus.lock();
+        for i in 0..n {
+            let element = if backwards { gpa - i * size } else { gpa + i * size };
+            let ptr = match self.host_ptr(element) {
+                Some(ptr) => ptr,
+                None => return Ok(ExitAction::Unhandled),
+            };
+            unsafe {
+                if io.is_in {
+                    let value = bus.read(io.port, io.size);
+                    core::ptr::copy_nonoverlapping(value.to_le_bytes().as_ptr(), ptr, io.size as usize);
+                } else {
+                    let mut bytes = [0u8; 4];
+                    core::ptr::copy_nonoverlapping(ptr, bytes.as_mut_ptr(), io.size as usize);
+                    bus.write(io.port, io.size, u32::from_le_bytes(bytes));
+                }
+            }
+        }
+
+        let step = n * size;
+        let advance = |reg: u64| {
+            let moved = if backwards { reg.wrapping_sub(step) } else { reg.wrapping_add(step) };
+            (reg & !addr_mask) | (moved & addr_mask)
+        };
+        if io.is_in {
+            ctx.regs.rdi = advance(ctx.regs.rdi);
+        } else {
+            ctx.regs.rsi = advance(ctx.regs.rsi);
+        }
+        if io.is_rep {
+            let remaining = count - n;
+            ctx.regs.rcx = (ctx.regs.rcx & !addr_mask) | remaining;
+            if remaining != 0 {
+                // Re-execute the instruction for the rest of the count.
+                return Ok(ExitAction::Resume);
+            }
+        }
+        Ok(ExitAction::AdvanceRip)
+    }
+}
+
+impl ExitHandler for PortIoHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        let io = ctx.info.io();
+        if io.is_string {
+            return self.string_io(ctx, io);
+        }
+        let mut bus = self.bus.lock();
+        if io.is_in {
+            let mask = size_mask(io.size) as u64;
+            ctx.regs.rax = (ctx.regs.rax & !mask) | bus.read(io.port, io.size) as u64;
+        } else {
+            bus.write(io.port, io.size, ctx.regs.rax as u32);
+        }
+        Ok(ExitAction::AdvanceRip)
+    }
+}
+
+/// The parts of the guest's special registers that control paging, enough
+/// for [`translate_linear`].
+pub fn guest_paging_state(vmcs: &Vmcs) -> Result<SpecialRegisters, VmxError> {
+    Ok(SpecialRegisters {
+        cr0: vmcs.read(GuestField::Cr0)?,
+        cr3: vmcs.read(GuestField::Cr3)?,
+        efer: vmcs.read(GuestField::Ia32Efer)?,
+        ..Default::default()
+    })
+}
+
+/// Operating mode of the guest's current code segment, from the L and D/B
+/// bits of its access rights.
+pub fn guest_cpu_mode(vmcs: &Vmcs) -> Result<CpuMode, VmxError> {
+    let access_rights = vmcs.read(GuestField::CsAccessRights)?;
+    Ok(if access_rights & (1 << 13) != 0 {
+        CpuMode::Long
+    } else if access_rights & (1 << 14) != 0 {
+        CpuMode::Bits32
+    } else {
+        CpuMode::Bits16
+    })
+}
+
+/// Queue hardware exception `vector` for delivery on the next VM entry.
+/// The faulting instruction is not skipped, so resume without advancing RIP.
+pub fn inject_exception(vmcs: &Vmcs, vector: u8, error_code: Option<u32>) -> Result<(), VmxError> {
+    // Valid, type 3 (hardware exception).
+    let mut info = (1 << 31) | (3 << 8) | vector as u64;
+    if let Some(code) = error_code {
+        info |= 1 << 11;
+        vmcs.write(ControlField::VmEntryExceptionErrorCode, code as u64)?;
+    }
+    vmcs.write(ControlField::VmEntryInterruptionInfo, info)
+}
+
+/// Copy the instruction at the guest's CS:RIP into `buf`, stopping early at
+/// an untranslatable page. Returns the number of bytes fetched.
+pub fn fetch_guest_instruction(
+    vmcs: &Vmcs,
+    memory: &GuestMemoryLayout,
+    phys_offset: u64,
+    buf: &mut [u8; MAX_INSTRUCTION_LEN],
+) -> Result<usize, VmxError> {
+    let sregs = guest_paging_state(vmcs)?;
+    let linear = vmcs.read(GuestField::CsBase)?.wrapping_add(vmcs.read(GuestField::Rip)?);
+    let host_ptr = |gpa: u64| memory.gpa_to_hpa(gpa).map(|hpa| hpa + phys_offset);
+    let read_entry = |gpa: u64| host_ptr(gpa).map(|ptr| unsafe { *(ptr as *const u64) });
+    for (i, byte) in buf.iter_mut().enumerate() {
+        let addr = linear.wrapping_add(i as u64);
+        let ptr = match translate_linear(&sregs, addr, read_entry).and_then(host_ptr) {
+            Some(ptr) => ptr,
+            None => return Ok(i),
+        };
+        *byte = unsafe { *(ptr as *const u8) };
+    }
+    Ok(MAX_INSTRUCTION_LEN)
+}
+
+/// Emulates guest accesses to MMIO regions claimed on an [`MmioBus`].
+///
+/// Register it for `EptViolation` in place of [`EptViolationHandler`]: the
+/// faulting instruction is fetched, decoded and carried out against the
+/// bus. Violations outside the bus are left unhandled.
+pub struct MmioHandler {
+    bus: Arc<Mutex<MmioBus>>,
+    memory: GuestMemoryLayout,
+    phys_offset: u64,
+}
+
+impl MmioHandler {
+    pub fn new(bus: Arc<Mutex<MmioBus>>, memory: GuestMemoryLayout, phys_offset: u64) -> Self {
+        MmioHandler { bus, memory, phys_offset }
+    }
+}
+
+impl ExitHandler for MmioHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        let gpa = ctx.info.guest_physical_address;
+        let mut bus = self.bus.lock();
+        if !bus.is_claimed(gpa) {
+            return Ok(ExitAction::Unhandled);
+        }
+
+        let mode = guest_cpu_mode(ctx.vmcs)?;
+        let mut bytes = [0u8; MAX_INSTRUCTION_LEN];
+        let len = fetch_guest_instruction(ctx.vmcs, &self.memory, self.phys_offset, &mut bytes)?;
+        let insn = match decode(&bytes[..len], mode) {
+            Ok(insn) => insn,
+            Err(err) => {
+                crate::println!("VMX: cannot emulate MMIO access at gpa {:#x}: {:?}", gpa, err);
+                return Ok(ExitAction::Unhandled);
+            }
+        };
+
+        // The exit's instruction length is undefined for EPT violations, so
+        // `emulate` moves RIP itself.
+        let mut regs = ctx.regs.to_vcpu_registers(ctx.vmcs)?;
+        if bus.emulate(&insn, gpa, &mut regs, mode).is_err() {
+            return Ok(ExitAction::Unhandled);
+        }
+        ctx.regs.load_vcpu_registers(ctx.vmcs, &regs)?;
+        Ok(ExitAction::Resume)
+    }
+}
+
+/// Minimal MSR emulation: writes are remembered and read back, unknown MSRs
+/// read as zero.
+pub struct MsrHandler {
+    values: BTreeMap<u32, u64>,
+}
+
+impl MsrHandler {
+    pub fn new() -> Self {
+        MsrHandler { values: BTreeMap::new() }
+    }
+}
+
+impl Default for MsrHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExitHandler for MsrHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        let msr = ctx.regs.rcx as u32;
+        match ctx.info.reason {
+            Some(ExitReason::Rdmsr) => {
+                let value = self.values.get(&msr).copied().unwrap_or(0);
+                ctx.regs.rax = value & 0xFFFF_FFFF;
+                ctx.regs.rdx = value >> 32;
+            }
+            Some(ExitReason::Wrmsr) => {
+                let value = (ctx.regs.rdx << 32) | (ctx.regs.rax & 0xFFFF_FFFF);
+                self.values.insert(msr, value);
+            }
+            _ => return Ok(ExitAction::Unhandled),
+        }
+        Ok(ExitAction::AdvanceRip)
+    }
+}
+
+/// Default EPT-violation handler: a guest touching unmapped memory is a
+/// bug. See [`MmioHandler`] for emulating device ranges.
+pub struct EptViolationHandler;
+
+impl ExitHandler for EptViolationHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        let q = ctx.info.ept_violation();
+        crate::println!(
+            "VMX: EPT violation at gpa {:#x} (r={} w={} x={})",
+            ctx.info.guest_physical_address, q.read, q.write, q.fetch
+        );
+        Ok(ExitAction::Unhandled)
+    }
+}
+
+pub struct TripleFaultHandler;
+
+impl ExitHandler for TripleFaultHandler {
+    fn handle(&mut self, ctx: &mut ExitContext) -> Result<ExitAction, VmxError> {
+        crate::println!("VMX: guest triple fault at rip {:#x}", ctx.info.guest_rip);
+        Ok(ExitAction::Shutdown)
+    }
+}
@@ -0,0 +1,80 @@
+use core::arch::asm;
+
+pub mod vmcs;
+pub mod exit;
+pub mod vcpu;
+pub mod ept;
+pub mod capabilities;
+pub mod backend;
+pub mod cpu_model;
+pub mod percpu;
+
+pub use vmcs::{Vmcs, VmxError};
+pub use exit::{ExitAction, ExitDispatcher, ExitHandler, ExitReason, GuestRegisters, MmioHandler, PortIoHandler};
+pub use vcpu::{RunOutcome, Vcpu};
+pub use capabilities::{ControlCapability, VmxCapabilities};
+pub use backend::VmxBackend;
+pub use cpu_model::{CpuModel, CpuidEntry, MsrAccess, MsrBitmap};
+
+use capabilities::{FEATURE_CONTROL_LOCKED, FEATURE_CONTROL_VMX_OUTSIDE_SMX, IA32_FEATURE_CONTROL};
+use x86_64::registers::model_specific::Msr;
+
+/// Turn VMX on for the executing CPU. Every CPU that will run vCPUs calls
+/// this, the bootstrap processor and each application processor alike.
+pub fn init() {
+    if !is_vtx_supported() {
+        panic!("VT-x is not supported on this CPU");
+    }
+    setup_vmx();
+}
+
+pub fn is_vtx_supported() -> bool {
+    let mut ecx: u32;
+    unsafe {
+        asm!(
+            "cpuid",
+            in("eax") 1,
+            out("ecx") ecx,
+            out("edx") _,
+            options(nostack, preserves_flags),
+        );
+    }
+    if (ecx & (1 << 5)) == 0 {
+        return false;
+    }
+    // Firmware may have locked IA32_FEATURE_CONTROL with VMX turned off.
+    VmxCapabilities::read().vmx_allowed_by_firmware()
+}
+
+pub fn enable_vmx() {
+    let caps = VmxCapabilities::read();
+    if !caps.feature_control_locked() {
+        // Enable VMX outside SMX and lock the MSR; VMXON #GPs while it is unlocked.
+        let value = caps.feature_control | FEATURE_CONTROL_VMX_OUTSIDE_SMX | FEATURE_CONTROL_LOCKED;
+        unsafe { Msr::new(IA32_FEATURE_CONTROL).write(value) };
+    }
+    let mut cr0: u64;
+    let mut cr4: u64;
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0);
+        cr0 = caps.adjust_cr0(cr0);
+        asm!("mov cr0, {}", in(reg) cr0);
+        asm!("mov {}, cr4", out(reg) cr4);
+        cr4 |= 1 << 13; // Set VMXE bit
+        cr4 = caps.adjust_cr4(cr4);
+        asm!("mov cr4, {}", in(reg) cr4);
+    }
+}
+
+/// Enter VMX root operation on the executing CPU with a VMXON region of
+/// its own, taken from `memory::GUEST_FRAMES`. See [`percpu`].
+pub fn setup_vmx() {
+    if let Err(err) = percpu::enable_this_cpu() {
+        panic!("VMXON failed: {}", err);
+    }
+}
+
+/// VMCS revision identifier from IA32_VMX_BASIC[30:0].
+pub fn get_vmx_revision_id() -> u32 {
+    VmxCapabilities::read().revision_id()
+}
@@ -0,0 +1,73 @@
+// Per-physical-CPU VMX state. VMXON is a per-CPU operation: every CPU that
+// runs vCPUs enables VMX itself and owns a VMXON region, and a VMCS can
+// only be current on one CPU at a time. VMXON regions are frames of the
+// memory set aside for native VMs.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use super::cpu_model::host_cpuid;
+use super::vmcs::{vmxoff, vmxon, VmxError, VmxRegion};
+use super::{enable_vmx, get_vmx_revision_id};
+use crate::memory::GUEST_FRAMES;
+
+/// VMXON regions of the CPUs in VMX operation, by initial APIC ID.
+static VMXON_REGIONS: Mutex<BTreeMap<u32, VmxRegion>> = Mutex::new(BTreeMap::new());
+
+/// Initial APIC ID of the executing CPU (CPUID.1:EBX[31:24]).
+pub fn current_cpu() -> u32 {
+    host_cpuid(1, 0).ebx >> 24
+}
+
+/// Enable VMX on the executing CPU and enter VMX root operation. Returns
+/// the CPU's APIC ID; calling it again on the same CPU does nothing.
+pub fn enable_this_cpu() -> Result<u32, VmxError> {
+    let cpu = current_cpu();
+    let mut regions = VMXON_REGIONS.lock();
+    if regions.contains_key(&cpu) {
+        return Ok(cpu);
+    }
+    let region = {
+        let mut guest_frames = GUEST_FRAMES.lock();
+        let (frames, phys_offset) = guest_frames.as_mut().ok_or(VmxError::RegionAllocationFailed)?;
+        VmxRegion::with_revision(frames, *phys_offset, get_vmx_revision_id())?
+    };
+    enable_vmx();
+    if let Err(err) = unsafe { vmxon(region.phys_addr()) } {
+        free_region(region);
+        return Err(err);
+    }
+    regions.insert(cpu, region);
+    Ok(cpu)
+}
+
+/// Leave VMX operation on the executing CPU and free its VMXON region.
+/// Every VMCS loaded on this CPU must have been cleared first.
+pub fn disable_this_cpu() -> Result<(), VmxError> {
+    let cpu = current_cpu();
+    let mut regions = VMXON_REGIONS.lock();
+    if regions.contains_key(&cpu) {
+        unsafe { vmxoff()? };
+        free_region(regions.remove(&cpu).unwrap());
+    }
+    Ok(())
+}
+
+fn free_region(region: VmxRegion) {
+    if let Some((frames, _)) = GUEST_FRAMES.lock().as_mut() {
+        frames.deallocate_frame(PhysFrame::containing_address(PhysAddr::new(region.phys_addr())));
+    }
+}
+
+pub fn is_enabled(cpu: u32) -> bool {
+    VMXON_REGIONS.lock().contains_key(&cpu)
+}
+
+/// APIC IDs of the CPUs in VMX operation.
+pub fn enabled_cpus() -> Vec<u32> {
+    VMXON_REGIONS.lock().keys().copied().collect()
+}
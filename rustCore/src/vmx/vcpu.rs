@@ -0,0 +1,207 @@
+use core::arch::global_asm;
+
+use super::exit::{log_unhandled_exit, ExitAction, ExitContext, ExitDispatcher, ExitInfo, GuestRegisters};
+use super::vmcs::{ExitInfoField, GuestField, InstructionError, LaunchState, Vmcs, VmxError};
+
+// VM entry/exit trampoline.
+//
+// `hypercore_vmx_enter(regs, launched)` saves the host RFLAGS and
+// callee-saved registers, points HOST_RSP/HOST_RIP at its own stack frame
+// and at `hypercore_vmx_exit`, loads the guest GPRs from `regs` and executes
+// VMLAUNCH (launched == 0) or VMRESUME. On a VM exit the processor jumps to
+// `hypercore_vmx_exit` with RSP = HOST_RSP, which stores the guest GPRs back
+// into `regs` and returns 0 to the caller of `hypercore_vmx_enter`. If the
+// entry instruction itself fails, it returns 1 for VMfailInvalid and 2 for
+// VMfailValid.
+//
+// A VM exit clears RFLAGS.IF. Restoring the saved RFLAGS on the way out lets
+// a host interrupt that caused the exit, still pending in the host LAPIC
+// because exits do not acknowledge it, be taken as soon as the host allows.
+global_asm!(
+    ".global hypercore_vmx_enter",
+    "hypercore_vmx_enter:",
+    "    pushfq",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    push rdi",
+    "    mov rax, 0x6C14",
+    "    vmwrite rax, rsp",
+    "    jbe 2f",
+    "    lea rdx, [rip + hypercore_vmx_exit]",
+    "    mov rax, 0x6C16",
+    "    vmwrite rax, rdx",
+    "    jbe 2f",
+    "    test rsi, rsi",
+    "    mov rax, [rdi + 0]",
+    "    mov rbx, [rdi + 8]",
+    "    mov rcx, [rdi + 16]",
+    "    mov rdx, [rdi + 24]",
+    "    mov rsi, [rdi + 32]",
+    "    mov rbp, [rdi + 48]",
+    "    mov r8, [rdi + 56]",
+    "    mov r9, [rdi + 64]",
+    "    mov r10, [rdi + 72]",
+    "    mov r11, [rdi + 80]",
+    "    mov r12, [rdi + 88]",
+    "    mov r13, [rdi + 96]",
+    "    mov r14, [rdi + 104]",
+    "    mov r15, [rdi + 112]",
+    "    mov rdi, [rdi + 40]",
+    "    jnz 3f",
+    "    vmlaunch",
+    "    jmp 2f",
+    "3:",
+    "    vmresume",
+    "2:",
+    "    mov eax, 1",
+    "    jc 4f",
+    "    mov eax, 2",
+    "4:",
+    "    pop rdi",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    popfq",
+    "    ret",
+    "",
+    ".global hypercore_vmx_exit",
+    "hypercore_vmx_exit:",
+    "    push rdi",
+    "    mov rdi, [rsp + 8]",
+    "    mov [rdi + 0], rax",
+    "    mov [rdi + 8], rbx",
+    "    mov [rdi + 16], rcx",
+    "    mov [rdi + 24], rdx",
+    "    mov [rdi + 32], rsi",
+    "    mov [rdi + 48], rbp",
+    "    mov [rdi + 56], r8",
+    "    mov [rdi + 64], r9",
+    "    mov [rdi + 72], r10",
+    "    mov [rdi + 80], r11",
+    "    mov [rdi + 88], r12",
+    "    mov [rdi + 96], r13",
+    "    mov [rdi + 104], r14",
+    "    mov [rdi + 112], r15",
+    "    pop rax",
+    "    mov [rdi + 40], rax",
+    "    pop rdi",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    popfq",
+    "    xor eax, eax",
+    "    ret",
+);
+
+extern "C" {
+    fn hypercore_vmx_enter(regs: *mut GuestRegisters, launched: u64) -> u64;
+}
+
+/// Why the run loop returned control to its caller.
+#[derive(Debug, Clone, Copy)]
+pub enum RunOutcome {
+    /// The guest executed HLT and no handler resumed it.
+    Halted,
+    /// The guest shut down (triple fault or an explicit power-off).
+    Shutdown,
+    /// No handler accepted the exit; it has already been logged.
+    Unhandled(ExitInfo),
+    /// VM entry failed, either at the instruction or during guest-state checks.
+    EntryFailed(ExitInfo),
+    Error(VmxError),
+}
+
+/// A virtual CPU backed by its own VMCS.
+pub struct Vcpu {
+    pub id: u32,
+    pub vmcs: Vmcs,
+    pub regs: GuestRegisters,
+}
+
+impl Vcpu {
+    pub fn new(id: u32, vmcs: Vmcs) -> Self {
+        Vcpu { id, vmcs, regs: GuestRegisters::default() }
+    }
+
+    /// Enter the guest once and return the information about the next exit.
+    pub fn enter(&mut self) -> Result<ExitInfo, VmxError> {
+        let launched = match self.vmcs.launch_state() {
+            LaunchState::Clear => 0,
+            LaunchState::Launched => 1,
+        };
+        let status = unsafe { hypercore_vmx_enter(&mut self.regs, launched) };
+        match status {
+            0 => {
+                self.vmcs.set_launch_state(LaunchState::Launched);
+                ExitInfo::read(&self.vmcs)
+            }
+            1 => Err(VmxError::VmFailInvalid),
+            _ => {
+                let code = self.vmcs.read(ExitInfoField::VmInstructionError)?;
+                Err(VmxError::VmFailValid(InstructionError::from_code(code as u32)))
+            }
+        }
+    }
+
+    /// Skip the instruction that caused the last exit. Any STI or MOV SS
+    /// interrupt shadow ends with it.
+    pub fn advance_rip(&self, info: &ExitInfo) -> Result<(), VmxError> {
+        self.vmcs.write(GuestField::Rip, info.guest_rip.wrapping_add(info.instruction_length))?;
+        let interruptibility = self.vmcs.read(GuestField::InterruptibilityState)?;
+        if interruptibility & 3 != 0 {
+            self.vmcs.write(GuestField::InterruptibilityState, interruptibility & !3)?;
+        }
+        Ok(())
+    }
+
+    /// Run the guest until an exit that the dispatcher cannot resume from.
+    ///
+    /// The VMCS must already be current on this CPU and contain a complete
+    /// guest, host and control state.
+    pub fn run(&mut self, dispatcher: &mut ExitDispatcher) -> RunOutcome {
+        loop {
+            let info = match self.enter() {
+                Ok(info) => info,
+                Err(err) => return RunOutcome::Error(err),
+            };
+            if info.is_entry_failure() {
+                log_unhandled_exit(&info, &self.regs);
+                return RunOutcome::EntryFailed(info);
+            }
+            let action = {
+                let mut ctx = ExitContext { vcpu: self.id, vmcs: &self.vmcs, regs: &mut self.regs, info: &info };
+                dispatcher.dispatch(&mut ctx)
+            };
+            match action {
+                Ok(ExitAction::Resume) => {}
+                Ok(ExitAction::AdvanceRip) => {
+                    if let Err(err) = self.advance_rip(&info) {
+                        return RunOutcome::Error(err);
+                    }
+                }
+                Ok(ExitAction::Halt) => {
+                    if let Err(err) = self.advance_rip(&info) {
+                        return RunOutcome::Error(err);
+                    }
+                    return RunOutcome::Halted;
+                }
+                Ok(ExitAction::Shutdown) => return RunOutcome::Shutdown,
+                Ok(ExitAction::Unhandled) => {
+                    log_unhandled_exit(&info, &self.regs);
+                    return RunOutcome::Unhandled(info);
+                }
+                Err(err) => return RunOutcome::Error(err),
+            }
+        }
+    }
+}
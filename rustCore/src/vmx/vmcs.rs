@@ -0,0 +1,706 @@
+use core::arch::asm;
+use core::fmt;
+use x86_64::structures::paging::FrameAllocator;
+
+use crate::memory::SimpleFrameAllocator;
+
+pub const VMCS_SIZE: usize = 4096;
+
+/// Errors reported by VMX instructions.
+///
+/// VMX instructions signal failure through RFLAGS: CF=1 is VMfailInvalid
+/// (no current VMCS, or a bad pointer), ZF=1 is VMfailValid and the reason
+/// is stored in the VM-instruction error field of the current VMCS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxError {
+    VmFailInvalid,
+    VmFailValid(InstructionError),
+    RegionAllocationFailed,
+    NotLoaded,
+}
+
+impl fmt::Display for VmxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmxError::VmFailInvalid => write!(f, "VMfailInvalid"),
+            VmxError::VmFailValid(err) => write!(f, "VMfailValid: {} ({})", err.description(), err.code()),
+            VmxError::RegionAllocationFailed => write!(f, "failed to allocate VMX region"),
+            VmxError::NotLoaded => write!(f, "VMCS is not the current VMCS"),
+        }
+    }
+}
+
+/// VM-instruction error numbers (Intel SDM Vol. 3C, 31.4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionError {
+    VmcallInVmxRoot,
+    VmclearInvalidAddress,
+    VmclearVmxonPointer,
+    VmlaunchNonClear,
+    VmresumeNonLaunched,
+    VmresumeAfterVmxoff,
+    EntryInvalidControlFields,
+    EntryInvalidHostState,
+    VmptrldInvalidAddress,
+    VmptrldVmxonPointer,
+    VmptrldBadRevision,
+    UnsupportedField,
+    WriteReadOnlyField,
+    VmxonInVmxRoot,
+    EntryInvalidExecutiveVmcs,
+    EntryNonLaunchedExecutiveVmcs,
+    EntryExecutiveVmcsNotVmxon,
+    VmcallNonClear,
+    VmcallInvalidExitControls,
+    VmcallIncorrectMsegRevision,
+    VmxoffUnderDualMonitor,
+    VmcallInvalidSmmFeatures,
+    EntryInvalidExecutionControls,
+    EntryEventsBlockedByMovSs,
+    InvalidInveptInvvpidOperand,
+    Unknown(u32),
+}
+
+impl InstructionError {
+    pub fn from_code(code: u32) -> Self {
+        match code {
+            1 => InstructionError::VmcallInVmxRoot,
+            2 => InstructionError::VmclearInvalidAddress,
+            3 => InstructionError::VmclearVmxonPointer,
+            4 => InstructionError::VmlaunchNonClear,
+            5 => InstructionError::VmresumeNonLaunched,
+            6 => InstructionError::VmresumeAfterVmxoff,
+            7 => InstructionError::EntryInvalidControlFields,
+            8 => InstructionError::EntryInvalidHostState,
+            9 => InstructionError::VmptrldInvalidAddress,
+            10 => InstructionError::VmptrldVmxonPointer,
+            11 => InstructionError::VmptrldBadRevision,
+            12 => InstructionError::UnsupportedField,
+            13 => InstructionError::WriteReadOnlyField,
+            15 => InstructionError::VmxonInVmxRoot,
+            16 => InstructionError::EntryInvalidExecutiveVmcs,
+            17 => InstructionError::EntryNonLaunchedExecutiveVmcs,
+            18 => InstructionError::EntryExecutiveVmcsNotVmxon,
+            19 => InstructionError::VmcallNonClear,
+            20 => InstructionError::VmcallInvalidExitControls,
+            22 => InstructionError::VmcallIncorrectMsegRevision,
+            23 => InstructionError::VmxoffUnderDualMonitor,
+            24 => InstructionError::VmcallInvalidSmmFeatures,
+            25 => InstructionError::EntryInvalidExecutionControls,
+            26 => InstructionError::EntryEventsBlockedByMovSs,
+            28 => InstructionError::InvalidInveptInvvpidOperand,
+            other => InstructionError::Unknown(other),
+        }
+    }
+
+    pub fn code(&self) -> u32 {
+        match self {
+            InstructionError::VmcallInVmxRoot => 1,
+            InstructionError::VmclearInvalidAddress => 2,
+            InstructionError::VmclearVmxonPointer => 3,
+            InstructionError::VmlaunchNonClear => 4,
+            InstructionError::VmresumeNonLaunched => 5,
+            InstructionError::VmresumeAfterVmxoff => 6,
+            InstructionError::EntryInvalidControlFields => 7,
+            InstructionError::EntryInvalidHostState => 8,
+            InstructionError::VmptrldInvalidAddress => 9,
+            InstructionError::VmptrldVmxonPointer => 10,
+            InstructionError::VmptrldBadRevision => 11,
+            InstructionError::UnsupportedField => 12,
+            InstructionError::WriteReadOnlyField => 13,
+            InstructionError::VmxonInVmxRoot => 15,
+            InstructionError::EntryInvalidExecutiveVmcs => 16,
+            InstructionError::EntryNonLaunchedExecutiveVmcs => 17,
+            InstructionError::EntryExecutiveVmcsNotVmxon => 18,
+            InstructionError::VmcallNonClear => 19,
+            InstructionError::VmcallInvalidExitControls => 20,
+            InstructionError::VmcallIncorrectMsegRevision => 22,
+            InstructionError::VmxoffUnderDualMonitor => 23,
+            InstructionError::VmcallInvalidSmmFeatures => 24,
+            InstructionError::EntryInvalidExecutionControls => 25,
+            InstructionError::EntryEventsBlockedByMovSs => 26,
+            InstructionError::InvalidInveptInvvpidOperand => 28,
+            InstructionError::Unknown(code) => *code,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            InstructionError::VmcallInVmxRoot => "VMCALL executed in VMX root operation",
+            InstructionError::VmclearInvalidAddress => "VMCLEAR with invalid physical address",
+            InstructionError::VmclearVmxonPointer => "VMCLEAR with VMXON pointer",
+            InstructionError::VmlaunchNonClear => "VMLAUNCH with non-clear VMCS",
+            InstructionError::VmresumeNonLaunched => "VMRESUME with non-launched VMCS",
+            InstructionError::VmresumeAfterVmxoff => "VMRESUME after VMXOFF",
+            InstructionError::EntryInvalidControlFields => "VM entry with invalid control field(s)",
+            InstructionError::EntryInvalidHostState => "VM entry with invalid host-state field(s)",
+            InstructionError::VmptrldInvalidAddress => "VMPTRLD with invalid physical address",
+            InstructionError::VmptrldVmxonPointer => "VMPTRLD with VMXON pointer",
+            InstructionError::VmptrldBadRevision => "VMPTRLD with incorrect VMCS revision identifier",
+            InstructionError::UnsupportedField => "VMREAD/VMWRITE from/to unsupported VMCS component",
+            InstructionError::WriteReadOnlyField => "VMWRITE to read-only VMCS component",
+            InstructionError::VmxonInVmxRoot => "VMXON executed in VMX root operation",
+            InstructionError::EntryInvalidExecutiveVmcs => "VM entry with invalid executive-VMCS pointer",
+            InstructionError::EntryNonLaunchedExecutiveVmcs => "VM entry with non-launched executive VMCS",
+            InstructionError::EntryExecutiveVmcsNotVmxon => "VM entry with executive-VMCS pointer not VMXON pointer",
+            InstructionError::VmcallNonClear => "VMCALL with non-clear VMCS",
+            InstructionError::VmcallInvalidExitControls => "VMCALL with invalid VM-exit control fields",
+            InstructionError::VmcallIncorrectMsegRevision => "VMCALL with incorrect MSEG revision identifier",
+            InstructionError::VmxoffUnderDualMonitor => "VMXOFF under dual-monitor treatment of SMIs and SMM",
+            InstructionError::VmcallInvalidSmmFeatures => "VMCALL with invalid SMM-monitor features",
+            InstructionError::EntryInvalidExecutionControls => "VM entry with invalid VM-execution control fields in executive VMCS",
+            InstructionError::EntryEventsBlockedByMovSs => "VM entry with events blocked by MOV SS",
+            InstructionError::InvalidInveptInvvpidOperand => "invalid operand to INVEPT/INVVPID",
+            InstructionError::Unknown(_) => "unknown VM-instruction error",
+        }
+    }
+}
+
+/// Width of a VMCS component, taken from bits 14:13 of its encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldWidth {
+    Bits16,
+    Bits64,
+    Bits32,
+    Natural,
+}
+
+impl FieldWidth {
+    pub fn from_encoding(encoding: u32) -> Self {
+        match (encoding >> 13) & 0b11 {
+            0 => FieldWidth::Bits16,
+            1 => FieldWidth::Bits64,
+            2 => FieldWidth::Bits32,
+            _ => FieldWidth::Natural,
+        }
+    }
+
+    pub fn mask(&self) -> u64 {
+        match self {
+            FieldWidth::Bits16 => 0xFFFF,
+            FieldWidth::Bits32 => 0xFFFF_FFFF,
+            FieldWidth::Bits64 | FieldWidth::Natural => u64::MAX,
+        }
+    }
+}
+
+/// A VMCS component that can be read with VMREAD.
+pub trait VmcsField: Copy {
+    fn encoding(self) -> u32;
+
+    fn width(self) -> FieldWidth {
+        FieldWidth::from_encoding(self.encoding())
+    }
+}
+
+/// A VMCS component that can also be written with VMWRITE.
+pub trait WritableField: VmcsField {}
+
+macro_rules! vmcs_fields {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $enc:expr,)* }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u32)]
+        pub enum $name {
+            $($variant = $enc,)*
+        }
+
+        impl VmcsField for $name {
+            fn encoding(self) -> u32 {
+                self as u32
+            }
+        }
+    };
+}
+
+vmcs_fields! {
+    /// Guest-state area.
+    GuestField {
+        EsSelector = 0x0800,
+        CsSelector = 0x0802,
+        SsSelector = 0x0804,
+        DsSelector = 0x0806,
+        FsSelector = 0x0808,
+        GsSelector = 0x080A,
+        LdtrSelector = 0x080C,
+        TrSelector = 0x080E,
+        InterruptStatus = 0x0810,
+        PmlIndex = 0x0812,
+        VmcsLinkPointer = 0x2800,
+        Ia32Debugctl = 0x2802,
+        Ia32Pat = 0x2804,
+        Ia32Efer = 0x2806,
+        Ia32PerfGlobalCtrl = 0x2808,
+        Pdpte0 = 0x280A,
+        Pdpte1 = 0x280C,
+        Pdpte2 = 0x280E,
+        Pdpte3 = 0x2810,
+        Ia32Bndcfgs = 0x2812,
+        EsLimit = 0x4800,
+        CsLimit = 0x4802,
+        SsLimit = 0x4804,
+        DsLimit = 0x4806,
+        FsLimit = 0x4808,
+        GsLimit = 0x480A,
+        LdtrLimit = 0x480C,
+        TrLimit = 0x480E,
+        GdtrLimit = 0x4810,
+        IdtrLimit = 0x4812,
+        EsAccessRights = 0x4814,
+        CsAccessRights = 0x4816,
+        SsAccessRights = 0x4818,
+        DsAccessRights = 0x481A,
+        FsAccessRights = 0x481C,
+        GsAccessRights = 0x481E,
+        LdtrAccessRights = 0x4820,
+        TrAccessRights = 0x4822,
+        InterruptibilityState = 0x4824,
+        ActivityState = 0x4826,
+        Smbase = 0x4828,
+        Ia32SysenterCs = 0x482A,
+        PreemptionTimerValue = 0x482E,
+        Cr0 = 0x6800,
+        Cr3 = 0x6802,
+        Cr4 = 0x6804,
+        EsBase = 0x6806,
+        CsBase = 0x6808,
+        SsBase = 0x680A,
+        DsBase = 0x680C,
+        FsBase = 0x680E,
+        GsBase = 0x6810,
+        LdtrBase = 0x6812,
+        TrBase = 0x6814,
+        GdtrBase = 0x6816,
+        IdtrBase = 0x6818,
+        Dr7 = 0x681A,
+        Rsp = 0x681C,
+        Rip = 0x681E,
+        Rflags = 0x6820,
+        PendingDebugExceptions = 0x6822,
+        Ia32SysenterEsp = 0x6824,
+        Ia32SysenterEip = 0x6826,
+    }
+}
+
+vmcs_fields! {
+    /// Host-state area, loaded on every VM exit.
+    HostField {
+        EsSelector = 0x0C00,
+        CsSelector = 0x0C02,
+        SsSelector = 0x0C04,
+        DsSelector = 0x0C06,
+        FsSelector = 0x0C08,
+        GsSelector = 0x0C0A,
+        TrSelector = 0x0C0C,
+        Ia32Pat = 0x2C00,
+        Ia32Efer = 0x2C02,
+        Ia32PerfGlobalCtrl = 0x2C04,
+        Ia32SysenterCs = 0x4C00,
+        Cr0 = 0x6C00,
+        Cr3 = 0x6C02,
+        Cr4 = 0x6C04,
+        FsBase = 0x6C06,
+        GsBase = 0x6C08,
+        TrBase = 0x6C0A,
+        GdtrBase = 0x6C0C,
+        IdtrBase = 0x6C0E,
+        Ia32SysenterEsp = 0x6C10,
+        Ia32SysenterEip = 0x6C12,
+        Rsp = 0x6C14,
+        Rip = 0x6C16,
+    }
+}
+
+vmcs_fields! {
+    /// VM-execution, VM-exit and VM-entry control fields.
+    ControlField {
+        Vpid = 0x0000,
+        PostedInterruptNotificationVector = 0x0002,
+        EptpIndex = 0x0004,
+        IoBitmapA = 0x2000,
+        IoBitmapB = 0x2002,
+        MsrBitmap = 0x2004,
+        VmExitMsrStoreAddr = 0x2006,
+        VmExitMsrLoadAddr = 0x2008,
+        VmEntryMsrLoadAddr = 0x200A,
+        ExecutiveVmcsPointer = 0x200C,
+        PmlAddress = 0x200E,
+        TscOffset = 0x2010,
+        VirtualApicAddr = 0x2012,
+        ApicAccessAddr = 0x2014,
+        PostedInterruptDescAddr = 0x2016,
+        VmFunctionControls = 0x2018,
+        EptPointer = 0x201A,
+        EoiExitBitmap0 = 0x201C,
+        EoiExitBitmap1 = 0x201E,
+        EoiExitBitmap2 = 0x2020,
+        EoiExitBitmap3 = 0x2022,
+        EptpListAddr = 0x2024,
+        VmreadBitmap = 0x2026,
+        VmwriteBitmap = 0x2028,
+        VirtualizationExceptionInfo = 0x202A,
+        XssExitingBitmap = 0x202C,
+        TscMultiplier = 0x2032,
+        PinBasedControls = 0x4000,
+        PrimaryProcBasedControls = 0x4002,
+        ExceptionBitmap = 0x4004,
+        PageFaultErrorCodeMask = 0x4006,
+        PageFaultErrorCodeMatch = 0x4008,
+        Cr3TargetCount = 0x400A,
+        VmExitControls = 0x400C,
+        VmExitMsrStoreCount = 0x400E,
+        VmExitMsrLoadCount = 0x4010,
+        VmEntryControls = 0x4012,
+        VmEntryMsrLoadCount = 0x4014,
+        VmEntryInterruptionInfo = 0x4016,
+        VmEntryExceptionErrorCode = 0x4018,
+        VmEntryInstructionLength = 0x401A,
+        TprThreshold = 0x401C,
+        SecondaryProcBasedControls = 0x401E,
+        PleGap = 0x4020,
+        PleWindow = 0x4022,
+        Cr0GuestHostMask = 0x6000,
+        Cr4GuestHostMask = 0x6002,
+        Cr0ReadShadow = 0x6004,
+        Cr4ReadShadow = 0x6006,
+        Cr3TargetValue0 = 0x6008,
+        Cr3TargetValue1 = 0x600A,
+        Cr3TargetValue2 = 0x600C,
+        Cr3TargetValue3 = 0x600E,
+    }
+}
+
+vmcs_fields! {
+    /// Read-only VM-exit information fields.
+    ExitInfoField {
+        GuestPhysicalAddress = 0x2400,
+        VmInstructionError = 0x4400,
+        ExitReason = 0x4402,
+        ExitInterruptionInfo = 0x4404,
+        ExitInterruptionErrorCode = 0x4406,
+        IdtVectoringInfo = 0x4408,
+        IdtVectoringErrorCode = 0x440A,
+        ExitInstructionLength = 0x440C,
+        ExitInstructionInfo = 0x440E,
+        ExitQualification = 0x6400,
+        IoRcx = 0x6402,
+        IoRsi = 0x6404,
+        IoRdi = 0x6406,
+        IoRip = 0x6408,
+        GuestLinearAddress = 0x640A,
+    }
+}
+
+impl WritableField for GuestField {}
+impl WritableField for HostField {}
+impl WritableField for ControlField {}
+
+/// Turn the CF/ZF outcome of a VMX instruction into a `Result`.
+fn vm_result(cf: u8, zf: u8) -> Result<(), VmxError> {
+    if cf != 0 {
+        Err(VmxError::VmFailInvalid)
+    } else if zf != 0 {
+        let code = unsafe { vmread_raw(ExitInfoField::VmInstructionError.encoding()) };
+        Err(VmxError::VmFailValid(InstructionError::from_code(code as u32)))
+    } else {
+        Ok(())
+    }
+}
+
+unsafe fn vmread_raw(encoding: u32) -> u64 {
+    let value: u64;
+    asm!(
+        "vmread {0}, {1}",
+        out(reg) value,
+        in(reg) encoding as u64,
+        options(nostack),
+    );
+    value
+}
+
+/// Read a component of the current VMCS.
+///
+/// # Safety
+/// The CPU must be in VMX operation.
+pub unsafe fn vmread(encoding: u32) -> Result<u64, VmxError> {
+    let value: u64;
+    let cf: u8;
+    let zf: u8;
+    asm!(
+        "vmread {0}, {1}",
+        "setc {2}",
+        "setz {3}",
+        out(reg) value,
+        in(reg) encoding as u64,
+        out(reg_byte) cf,
+        out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)?;
+    Ok(value)
+}
+
+/// Write a component of the current VMCS.
+///
+/// # Safety
+/// The CPU must be in VMX operation, and the write must leave the VMCS in a
+/// state the rest of the hypervisor expects.
+pub unsafe fn vmwrite(encoding: u32, value: u64) -> Result<(), VmxError> {
+    let cf: u8;
+    let zf: u8;
+    asm!(
+        "vmwrite {0}, {1}",
+        "setc {2}",
+        "setz {3}",
+        in(reg) encoding as u64,
+        in(reg) value,
+        out(reg_byte) cf,
+        out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+/// VMXON: enter VMX root operation on this CPU. The operand is the
+/// physical address of the VMXON region, passed in memory like the
+/// VMCS pointer of VMPTRLD.
+///
+/// # Safety
+/// CR4.VMXE must be set, CR0/CR4 must satisfy the VMX fixed bits and the
+/// region must stay allocated until VMXOFF.
+pub unsafe fn vmxon(phys_addr: u64) -> Result<(), VmxError> {
+    let cf: u8;
+    let zf: u8;
+    asm!(
+        "vmxon [{0}]",
+        "setc {1}",
+        "setz {2}",
+        in(reg) &phys_addr,
+        out(reg_byte) cf,
+        out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+/// VMXOFF: leave VMX operation on this CPU.
+///
+/// # Safety
+/// No VMCS may be in use on this CPU afterwards without another VMXON.
+pub unsafe fn vmxoff() -> Result<(), VmxError> {
+    let cf: u8;
+    let zf: u8;
+    asm!(
+        "vmxoff",
+        "setc {0}",
+        "setz {1}",
+        out(reg_byte) cf,
+        out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+unsafe fn vmclear(phys_addr: u64) -> Result<(), VmxError> {
+    let cf: u8;
+    let zf: u8;
+    asm!(
+        "vmclear [{0}]",
+        "setc {1}",
+        "setz {2}",
+        in(reg) &phys_addr,
+        out(reg_byte) cf,
+        out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+unsafe fn vmptrld(phys_addr: u64) -> Result<(), VmxError> {
+    let cf: u8;
+    let zf: u8;
+    asm!(
+        "vmptrld [{0}]",
+        "setc {1}",
+        "setz {2}",
+        in(reg) &phys_addr,
+        out(reg_byte) cf,
+        out(reg_byte) zf,
+        options(nostack),
+    );
+    vm_result(cf, zf)
+}
+
+unsafe fn vmptrst() -> u64 {
+    let mut phys_addr: u64 = 0;
+    asm!(
+        "vmptrst [{0}]",
+        in(reg) &mut phys_addr,
+        options(nostack),
+    );
+    phys_addr
+}
+
+/// A 4 KiB, page-aligned region handed to the processor (VMXON region,
+/// VMCS, bitmaps). It is a frame from a frame allocator: the processor is
+/// given its physical address and Hypercore reaches it through the
+/// physical-memory offset, as with EPT tables. The frame goes back with
+/// the allocator.
+pub struct VmxRegion {
+    ptr: *mut u8,
+    phys: u64,
+}
+
+impl VmxRegion {
+    /// A zeroed region in a frame from `frames`, whose frames are mapped at
+    /// `phys_offset`.
+    pub fn new(frames: &mut SimpleFrameAllocator, phys_offset: u64) -> Result<Self, VmxError> {
+        let frame = frames.allocate_frame().ok_or(VmxError::RegionAllocationFailed)?;
+        let phys = frame.start_address().as_u64();
+        let ptr = (phys + phys_offset) as *mut u8;
+        unsafe { core::ptr::write_bytes(ptr, 0, VMCS_SIZE) };
+        Ok(VmxRegion { ptr, phys })
+    }
+
+    /// Allocate a region and stamp the VMCS revision identifier into its
+    /// first dword, as required for VMXON regions and VMCSs.
+    pub fn with_revision(
+        frames: &mut SimpleFrameAllocator,
+        phys_offset: u64,
+        revision_id: u32,
+    ) -> Result<Self, VmxError> {
+        let region = Self::new(frames, phys_offset)?;
+        unsafe { *(region.ptr as *mut u32) = revision_id & 0x7FFF_FFFF };
+        Ok(region)
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.phys
+    }
+}
+
+// The region is uniquely owned; only the processor shares it, through its
+// physical address.
+unsafe impl Send for VmxRegion {}
+
+/// Launch state of a VMCS, which decides between VMLAUNCH and VMRESUME.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaunchState {
+    Clear,
+    Launched,
+}
+
+/// An owned virtual-machine control structure.
+///
+/// Field accessors operate on the processor's *current* VMCS, so the VMCS
+/// must be made current with [`Vmcs::load`] on this CPU first.
+pub struct Vmcs {
+    region: VmxRegion,
+    launch_state: LaunchState,
+}
+
+impl Vmcs {
+    /// Allocate a VMCS from `frames`, mapped at `phys_offset`, for the given
+    /// revision identifier (IA32_VMX_BASIC[30:0]) and VMCLEAR it so it is
+    /// ready to be loaded.
+    pub fn new(frames: &mut SimpleFrameAllocator, phys_offset: u64, revision_id: u32) -> Result<Self, VmxError> {
+        let region = VmxRegion::with_revision(frames, phys_offset, revision_id)?;
+        let mut vmcs = Vmcs { region, launch_state: LaunchState::Clear };
+        vmcs.clear()?;
+        Ok(vmcs)
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.region.phys_addr()
+    }
+
+    pub fn launch_state(&self) -> LaunchState {
+        self.launch_state
+    }
+
+    pub(crate) fn set_launch_state(&mut self, state: LaunchState) {
+        self.launch_state = state;
+    }
+
+    /// VMCLEAR: flush cached VMCS data to memory and reset the launch state.
+    pub fn clear(&mut self) -> Result<(), VmxError> {
+        unsafe { vmclear(self.phys_addr())? };
+        self.launch_state = LaunchState::Clear;
+        Ok(())
+    }
+
+    /// VMPTRLD: make this VMCS current and active on this CPU.
+    pub fn load(&self) -> Result<(), VmxError> {
+        unsafe { vmptrld(self.phys_addr()) }
+    }
+
+    /// Whether this VMCS is the current VMCS on this CPU.
+    pub fn is_current(&self) -> bool {
+        unsafe { vmptrst() == self.phys_addr() }
+    }
+
+    pub fn read<F: VmcsField>(&self, field: F) -> Result<u64, VmxError> {
+        unsafe { vmread(field.encoding()) }
+    }
+
+    pub fn write<F: WritableField>(&self, field: F, value: u64) -> Result<(), VmxError> {
+        unsafe { vmwrite(field.encoding(), value & field.width().mask()) }
+    }
+
+    pub fn read_guest(&self, field: GuestField) -> Result<u64, VmxError> {
+        self.read(field)
+    }
+
+    pub fn write_guest(&self, field: GuestField, value: u64) -> Result<(), VmxError> {
+        self.write(field, value)
+    }
+
+    pub fn read_host(&self, field: HostField) -> Result<u64, VmxError> {
+        self.read(field)
+    }
+
+    pub fn write_host(&self, field: HostField, value: u64) -> Result<(), VmxError> {
+        self.write(field, value)
+    }
+
+    pub fn read_control(&self, field: ControlField) -> Result<u64, VmxError> {
+        self.read(field)
+    }
+
+    pub fn write_control(&self, field: ControlField, value: u64) -> Result<(), VmxError> {
+        self.write(field, value)
+    }
+
+    pub fn read_exit_info(&self, field: ExitInfoField) -> Result<u64, VmxError> {
+        self.read(field)
+    }
+
+    /// Set bits in a control field, preserving the others.
+    pub fn set_control_bits(&self, field: ControlField, bits: u64) -> Result<(), VmxError> {
+        let value = self.read(field)?;
+        self.write(field, value | bits)
+    }
+
+    /// Clear bits in a control field, preserving the others.
+    pub fn clear_control_bits(&self, field: ControlField, bits: u64) -> Result<(), VmxError> {
+        let value = self.read(field)?;
+        self.write(field, value & !bits)
+    }
+
+    /// The VM-instruction error of the last failed VMX instruction.
+    pub fn instruction_error(&self) -> Result<InstructionError, VmxError> {
+        let code = self.read(ExitInfoField::VmInstructionError)?;
+        Ok(InstructionError::from_code(code as u32))
+    }
+}
+
+impl Drop for Vmcs {
+    fn drop(&mut self) {
+        // The processor may still cache this VMCS; flush it before freeing.
+        let _ = self.clear();
+    }
+}